@@ -0,0 +1,129 @@
+//! What happens in short clips. One frame shows a cake and a child; a few
+//! frames in a row show her blowing out the candles. With
+//! `video.action_frames` set, frames spread over each short clip are shown
+//! to the model together to name the actions, which are kept as tags of
+//! their own (`blowing-out-candles`, `diving`), apart from the keywords for
+//! what's in the picture, and found with `search --action`.
+
+use std::path::Path;
+use anyhow::Error;
+use crate::analyzer::{AnalyzerPool, Stage};
+use crate::cancel::CancelToken;
+use crate::config::VideoConfig;
+use crate::{video, ExternalMetadata};
+
+/// The `external_metadata` source action tags are kept under.
+pub const SOURCE: &str = "action";
+
+fn prompt(frames: usize) -> String {
+    format!(
+        "These {} frames are from one video clip, in order. What are the people or animals in it doing? \
+        Answer with the actions only, as short verb phrases separated by commas, such as: \
+        blowing out candles, diving, skiing. If nothing is happening, answer with nothing at all.",
+        frames,
+    )
+}
+
+/// An action as a tag: "Blowing out candles." is `blowing-out-candles`.
+fn tag(action: &str) -> String {
+    action.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// The distinct tags in the model's answer.
+fn parse(answer: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in answer.split([',', '\n']).map(tag) {
+        if !tag.is_empty() && tag != "none" && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+/// The actions the model sees in `frames`, in order, with the keywords
+/// stage's model.
+fn describe(frames: &[Vec<u8>], analyzer: &AnalyzerPool, cancel: &CancelToken) -> Result<Vec<String>, Error> {
+    let images: Vec<&[u8]> = frames.iter().map(Vec::as_slice).collect();
+    let answer = analyzer.ask_blocking(&images, Stage::Keywords, &prompt(frames.len()), cancel)?;
+    Ok(parse(&answer))
+}
+
+/// Action tags for the video at `path`, if it's short enough to have one
+/// thing going on and `config.action_frames` asks for them.
+pub fn tag_clip(path: &Path, duration: Option<f64>, config: &VideoConfig, analyzer: &AnalyzerPool, cancel: &CancelToken) -> Result<Vec<ExternalMetadata>, Error> {
+    let Some(duration) = duration.filter(|duration| *duration > 0.0 && *duration <= config.action_max_secs) else {
+        return Ok(Vec::new());
+    };
+    if config.action_frames == 0 {
+        return Ok(Vec::new());
+    }
+    // From the middle of equal parts, so no frame is a fade in or out
+    let count = config.action_frames;
+    let frames = (0..count)
+        .map(|i| video::frame_at(path, duration * (i as f64 + 0.5) / count as f64, config))
+        .collect::<Result<Vec<_>, _>>()?;
+    let tags = describe(&frames, analyzer, cancel)?;
+    tracing::debug!("Actions: {}", tags.join(", "));
+    Ok(tags.into_iter().map(|tag| ExternalMetadata { source: SOURCE.to_string(), field: String::from("tag"), value: tag }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use mockito::{Matcher, Server};
+    use crate::config::{AnalyzerConfig, HostConfig};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("Blowing out candles, diving.\nskiing, Diving"), vec!["blowing-out-candles", "diving", "skiing"]);
+        assert!(parse("None").is_empty());
+        assert!(parse("").is_empty());
+    }
+
+    #[test]
+    fn test_describe() -> Result<(), Error> {
+        let mut server = Server::new();
+        let images = [[1u8], [2], [3]].map(|image| STANDARD.encode(image));
+        let mock = server.mock("POST", "/api/generate")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::PartialJson(serde_json::json!({ "images": images })),
+                Matcher::Regex(String::from("These 3 frames are from one video clip")),
+            ]))
+            .with_body(r#"{"response": "jumping into a pool, swimming"}"#)
+            .create();
+        let config = AnalyzerConfig { hosts: vec![HostConfig::ollama(&server.url())], ..AnalyzerConfig::default() };
+        let pool = AnalyzerPool::new(&config)?;
+        assert_eq!(describe(&[vec![1], vec![2], vec![3]], &pool, &CancelToken::new())?, vec!["jumping-into-a-pool", "swimming"]);
+        mock.assert();
+
+        // Long videos have more than one thing going on
+        let long = tag_clip(Path::new("/long.mp4"), Some(600.0), &VideoConfig { action_frames: 3, ..VideoConfig::default() }, &pool, &CancelToken::new())?;
+        assert!(long.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_actions() -> Result<(), Error> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute_batch(
+            "INSERT INTO images (path, file_name, file_size) VALUES ('/dive.mp4', 'dive.mp4', 1), ('/pool.jpg', 'pool.jpg', 1);
+             INSERT INTO external_metadata (image_id, source, field, value) VALUES (1, 'action', 'tag', 'diving'), (1, 'action', 'tag', 'blowing-out-candles');",
+        )?;
+        crate::test_support::set_keywords(&conn, &[("/dive.mp4", "pool"), ("/pool.jpg", "diving board")]);
+        let search = |action: &str| -> Result<Vec<String>, Error> {
+            let filter = crate::SearchFilter { action: Some(action.to_string()), ..crate::SearchFilter::default() };
+            Ok(crate::search_images(&conn, &filter)?)
+        };
+        // Not the keyword
+        assert_eq!(search("diving")?, vec!["/dive.mp4"]);
+        assert_eq!(search("Blowing out candles")?, vec!["/dive.mp4"]);
+        Ok(())
+    }
+}
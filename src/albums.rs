@@ -0,0 +1,129 @@
+//! Albums kept in the catalog: named sets of photos, filled by hand or
+//! with what a query matches (`album add Lisbon --query place:Lisbon`).
+//! Searches, exports and galleries are kept to one with `album:Lisbon`.
+//! Photos a query added stay when they stop matching it, and photos
+//! cataloged later aren't added by themselves. Names match whatever their
+//! case.
+
+use anyhow::Error;
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::error::{CliError, ErrorKind};
+
+/// An album and how many photos it has.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Album {
+    pub name: String,
+    pub images: i64,
+}
+
+pub fn create(conn: &Connection, name: &str) -> Result<(), Error> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(CliError::new(ErrorKind::Config, "an album needs a name").into());
+    }
+    let created = conn.execute(
+        "INSERT INTO albums (name, created_at) VALUES (?1, ?2) ON CONFLICT (name) DO NOTHING",
+        params![name, chrono::Utc::now().to_rfc3339()],
+    )?;
+    if created == 0 {
+        return Err(CliError::new(ErrorKind::NothingToDo, format!("there's already an album named {}", name)).into());
+    }
+    Ok(())
+}
+
+/// Delete album `name`, leaving its photos in the catalog. Gives how many
+/// it had.
+pub fn delete(conn: &Connection, name: &str) -> Result<usize, Error> {
+    let tx = conn.unchecked_transaction()?;
+    let id = find(&tx, name)?;
+    let removed = tx.execute("DELETE FROM album_images WHERE album_id = ?1", [id])?;
+    tx.execute("DELETE FROM albums WHERE id = ?1", [id])?;
+    tx.commit()?;
+    Ok(removed)
+}
+
+/// Add the images with `ids` to album `name`. Gives how many weren't in it
+/// already.
+pub fn add(conn: &Connection, name: &str, ids: &[i64]) -> Result<usize, Error> {
+    let tx = conn.unchecked_transaction()?;
+    let album = find(&tx, name)?;
+    let added_at = chrono::Utc::now().to_rfc3339();
+    let mut stmt = tx.prepare("INSERT OR IGNORE INTO album_images (album_id, image_id, added_at) VALUES (?1, ?2, ?3)")?;
+    let mut added = 0;
+    for id in ids {
+        added += stmt.execute(params![album, id, added_at])?;
+    }
+    drop(stmt);
+    tx.commit()?;
+    Ok(added)
+}
+
+/// Take the images with `ids` out of album `name`. Gives how many were in
+/// it.
+pub fn remove(conn: &Connection, name: &str, ids: &[i64]) -> Result<usize, Error> {
+    let tx = conn.unchecked_transaction()?;
+    let album = find(&tx, name)?;
+    let mut stmt = tx.prepare("DELETE FROM album_images WHERE album_id = ?1 AND image_id = ?2")?;
+    let mut removed = 0;
+    for id in ids {
+        removed += stmt.execute(params![album, id])?;
+    }
+    drop(stmt);
+    tx.commit()?;
+    Ok(removed)
+}
+
+/// Every album, by name.
+pub fn list(conn: &Connection) -> Result<Vec<Album>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT name, COUNT(album_images.image_id) FROM albums LEFT JOIN album_images ON album_images.album_id = albums.id
+         GROUP BY albums.id ORDER BY name",
+    )?;
+    let albums = stmt.query_map([], |row| Ok(Album { name: row.get(0)?, images: row.get(1)? }))?.collect::<Result<_, _>>()?;
+    Ok(albums)
+}
+
+/// Album `name`'s id, or an error saying there's none.
+pub fn find(conn: &Connection, name: &str) -> Result<i64, Error> {
+    conn.query_row("SELECT id FROM albums WHERE name = ?1", [name.trim()], |row| row.get(0))
+        .optional()?
+        .ok_or_else(|| CliError::new(ErrorKind::NothingToDo, format!("no album named {}; `album create` makes one", name)).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SearchFilter;
+
+    #[test]
+    fn test_albums() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute_batch(
+            "INSERT INTO images (path, file_name, file_size, city, creation_date) VALUES
+                 ('/a.jpg', 'a.jpg', 1, 'Lisbon', '2023-07-01 10:00:00'),
+                 ('/b.jpg', 'b.jpg', 1, 'Lisbon', '2023-07-02 10:00:00'),
+                 ('/c.jpg', 'c.jpg', 1, 'Porto', '2023-07-03 10:00:00');",
+        )?;
+        create(&conn, "Summer")?;
+        assert!(create(&conn, "summer").is_err());
+        let lisbon = SearchFilter { place: Some(String::from("Lisbon")), ..SearchFilter::default() };
+        let ids = crate::selected_ids(&conn, &[String::from("/a.jpg")], Some(&lisbon))?;
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(add(&conn, "Summer", &ids)?, 2);
+        assert_eq!(add(&conn, "SUMMER", &[3])?, 1);
+        assert!(crate::selected_ids(&conn, &[String::from("/d.jpg")], None).is_err());
+        assert!(add(&conn, "Winter", &[1]).is_err());
+        create(&conn, "Empty")?;
+        assert_eq!(list(&conn)?, vec![Album { name: String::from("Empty"), images: 0 }, Album { name: String::from("Summer"), images: 3 }]);
+
+        let summer = SearchFilter { album: Some(String::from("summer")), ..SearchFilter::default() };
+        assert_eq!(crate::search_images(&conn, &summer)?.len(), 3);
+
+        assert_eq!(remove(&conn, "Summer", &[2, 2])?, 1);
+        assert_eq!(crate::search_images(&conn, &summer)?, vec!["/a.jpg", "/c.jpg"]);
+        assert_eq!(delete(&conn, "Summer")?, 2);
+        assert!(find(&conn, "Summer").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,632 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Error};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde_json::Value;
+use tokio::sync::Notify;
+use crate::cancel::CancelToken;
+use crate::config::{AnalyzerConfig, HostConfig, HostKind, StageModels};
+use crate::corrections::{Example, Examples};
+use crate::error::{CliError, ErrorKind};
+use crate::ExternalMetadata;
+
+pub const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+
+const PROMPT: &str = "Analyze this image and provide: \
+    1. A concise description of what you see \
+    2. A list of relevant keywords separated by commas";
+
+const DESCRIPTION_PROMPT: &str = "Describe what you see in this image, concisely.";
+
+const KEYWORDS_PROMPT: &str = "List relevant keywords for this image, separated by commas. \
+    Answer with the keywords only.";
+
+const TEXT_PROMPT: &str = "Transcribe any text that appears in this image, exactly as written. \
+    If there is none, answer with nothing at all.";
+
+/// The `external_metadata` source text read from images is kept under.
+pub const TEXT_SOURCE: &str = "ocr";
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The parts of an analysis that can each have their own model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Description,
+    Keywords,
+    Text,
+}
+
+impl StageModels {
+    fn get(&self, stage: Stage) -> Option<&str> {
+        match stage {
+            Stage::Description => self.description.as_deref(),
+            Stage::Keywords => self.keywords.as_deref(),
+            Stage::Text => self.text.as_deref(),
+        }
+    }
+}
+
+/// What the analyzer made of an image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    pub description: String,
+    pub keywords: String,
+    /// Text found in the image, if the text stage ran and found any
+    pub text: Option<String>,
+}
+
+impl Analysis {
+    /// The text found, as metadata kept alongside the embedded kind.
+    pub fn external(&self) -> Vec<ExternalMetadata> {
+        self.text.iter().map(|text| ExternalMetadata {
+            source: TEXT_SOURCE.to_string(),
+            field: String::from("text"),
+            value: text.clone(),
+        }).collect()
+    }
+}
+
+/// Photos taken just before and after the one analyzed, oldest first, for
+/// the model to see it in context.
+#[derive(Debug, Default, PartialEq)]
+pub struct Neighbors {
+    pub before: Vec<Vec<u8>>,
+    pub after: Vec<Vec<u8>>,
+}
+
+impl Neighbors {
+    /// The whole sequence in order, with the analyzed photo in its place.
+    fn around<'a>(&'a self, image_data: &'a [u8]) -> Vec<&'a [u8]> {
+        self.before.iter().map(Vec::as_slice)
+            .chain([image_data])
+            .chain(self.after.iter().map(Vec::as_slice))
+            .collect()
+    }
+}
+
+/// Ask Ollama's `/api/generate` about the images.
+async fn generate(images: &[&[u8]], ollama_url: &str, model: &str, prompt: &str) -> Result<String, Error> {
+    // Encode the images as base64
+    let base64_images: Vec<String> = images.iter().map(|image| STANDARD.encode(image)).collect();
+
+    // Create the client
+    let client = reqwest::Client::new();
+
+    // Make request to the Ollama server
+    let response = client
+        .post(format!("{}/api/generate", ollama_url))
+        .header("Content-Type", "application/json")
+        .body(serde_json::json!({
+            "model": model,
+            "prompt": prompt,
+            "images": base64_images,
+            "stream": false
+        }).to_string())
+        .send()
+        .await?
+        .error_for_status()?;
+
+    // Get the response text as a String
+    let response_text = response.text().await?;
+
+    // Parse the JSON response
+    let response_json: Value = serde_json::from_str(&response_text)?;
+
+    // Extract the response field and convert to owned String
+    let full_response = response_json["response"]
+        .as_str()
+        .unwrap_or("No response");
+
+    Ok(full_response.to_string())
+}
+
+/// Same against an OpenAI-compatible chat completions endpoint, with the
+/// images inlined as data URLs.
+async fn chat(images: &[&[u8]], host: &HostConfig, model: &str, prompt: &str) -> Result<String, Error> {
+    let mut content = vec![serde_json::json!({ "type": "text", "text": prompt })];
+    for image in images {
+        let mime = match image::guess_format(image) {
+            Ok(image::ImageFormat::Png) => "image/png",
+            _ => "image/jpeg",
+        };
+        let data_url = format!("data:{};base64,{}", mime, STANDARD.encode(image));
+        content.push(serde_json::json!({ "type": "image_url", "image_url": { "url": data_url } }));
+    }
+
+    let mut request = reqwest::Client::new()
+        .post(format!("{}/v1/chat/completions", host.url.trim_end_matches('/')))
+        .json(&serde_json::json!({
+            "model": model,
+            "messages": [{ "role": "user", "content": content }]
+        }));
+    if let Some(key) = api_key(host)? {
+        request = request.bearer_auth(key);
+    }
+
+    let response_json: Value = request.send().await?.error_for_status()?.json().await?;
+    let full_response = response_json["choices"][0]["message"]["content"]
+        .as_str()
+        .unwrap_or("No response");
+
+    Ok(full_response.to_string())
+}
+
+fn api_key(host: &HostConfig) -> Result<Option<String>, Error> {
+    host.api_key_env
+        .as_ref()
+        .map(|var| std::env::var(var).map_err(|_| anyhow!("environment variable {} is not set", var)))
+        .transpose()
+}
+
+/// `prompt`, preceded by answers the user wrote themselves (as `answer`
+/// puts them) for the model to take its style from.
+fn with_examples(prompt: &str, examples: &[&Example], answer: impl Fn(&Example) -> String) -> String {
+    if examples.is_empty() {
+        return prompt.to_string();
+    }
+    let mut text = String::from("Here is how the owner of these photos answered for some others. \
+        Match their style and level of detail.\n\n");
+    for example in examples {
+        text.push_str(&format!("Example answer:\n{}\n\n", answer(example)));
+    }
+    text.push_str(prompt);
+    text
+}
+
+/// `prompt`, told which of the photos of a sequence it's about.
+fn in_sequence(prompt: &str, neighbors: &Neighbors) -> String {
+    if neighbors.before.is_empty() && neighbors.after.is_empty() {
+        return prompt.to_string();
+    }
+    format!(
+        "These {} photos were taken one after another, in this order. Answer about photo {} only; \
+        the others are there to show what is happening around it.\n\n{}",
+        neighbors.before.len() + neighbors.after.len() + 1,
+        neighbors.before.len() + 1,
+        prompt,
+    )
+}
+
+/// Split the model's answer into description and keywords
+fn parse_analysis(full_response: &str) -> (String, String) {
+    let parts: Vec<&str> = full_response.split("\n\n").collect();
+    let description = parts.first().unwrap_or(&"").to_string();
+    let keywords = parts
+        .get(1)
+        .unwrap_or(&"")
+        .trim_start_matches("Keywords: ")
+        .to_string();
+    (description, keywords)
+}
+
+/// Transport-level failures and server errors say something about the host,
+/// not the image, so they trigger failover to another host.
+fn is_host_failure(error: &Error) -> bool {
+    error.downcast_ref::<reqwest::Error>().is_some_and(|e| {
+        e.is_connect() || e.is_timeout() || e.is_request()
+            || e.status().is_some_and(|s| s.is_server_error())
+    })
+}
+
+#[derive(Debug, Default)]
+struct HostState {
+    /// Smooth weighted round-robin counter
+    current_weight: i64,
+    in_flight: usize,
+    /// Set while the host is sitting out after a failure
+    down_until: Option<Instant>,
+}
+
+/// A set of analysis endpoints shared by the scan workers. Requests are
+/// spread over healthy hosts by weight, limited by each host's concurrency,
+/// and fall over to other hosts (then fallback hosts) when one fails.
+pub struct AnalyzerPool {
+    hosts: Vec<HostConfig>,
+    state: Mutex<Vec<HostState>>,
+    slot_freed: Notify,
+    retry_after: Duration,
+    stages: StageModels,
+    examples: Examples,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl AnalyzerPool {
+    pub fn new(config: &AnalyzerConfig) -> Result<AnalyzerPool, Error> {
+        let mut hosts = config.hosts.clone();
+        if hosts.is_empty() {
+            hosts.push(HostConfig::ollama(DEFAULT_OLLAMA_URL));
+        }
+        if let Some(host) = hosts.iter().find(|h| h.weight == 0 || h.concurrency == 0) {
+            return Err(anyhow!("analyzer host {} needs a weight and concurrency of at least 1", host.url));
+        }
+
+        let state = hosts.iter().map(|_| HostState::default()).collect();
+        Ok(AnalyzerPool {
+            hosts,
+            state: Mutex::new(state),
+            slot_freed: Notify::new(),
+            retry_after: Duration::from_secs(config.retry_after_secs),
+            stages: config.stages.clone(),
+            examples: Examples::default(),
+            runtime: tokio::runtime::Runtime::new()?,
+        })
+    }
+
+    /// Show the model these corrected captions as examples.
+    pub fn with_examples(self, examples: Examples) -> AnalyzerPool {
+        AnalyzerPool { examples, ..self }
+    }
+
+    /// How many images can usefully be analyzed at once across primary hosts.
+    pub fn concurrency(&self) -> usize {
+        let primary: usize = self.hosts.iter().filter(|h| !h.fallback).map(|h| h.concurrency).sum();
+        primary.max(1)
+    }
+
+    fn label(&self, index: usize) -> &str {
+        let host = &self.hosts[index];
+        host.name.as_deref().unwrap_or(&host.url)
+    }
+
+    /// Probe every host once, marking unreachable ones as down, and print
+    /// the result. Returns the number of healthy hosts.
+    pub fn check_health(&self) -> usize {
+        let client = reqwest::Client::new();
+        let results: Vec<bool> = self.runtime.block_on(async {
+            let mut results = Vec::new();
+            for host in &self.hosts {
+                let url = match host.kind {
+                    HostKind::Ollama => format!("{}/api/tags", host.url),
+                    HostKind::OpenAi => format!("{}/v1/models", host.url.trim_end_matches('/')),
+                };
+                let mut request = client.get(url).timeout(HEALTH_CHECK_TIMEOUT);
+                if let Ok(Some(key)) = api_key(host) {
+                    request = request.bearer_auth(key);
+                }
+                let ok = matches!(request.send().await, Ok(r) if r.status().is_success());
+                results.push(ok);
+            }
+            results
+        });
+
+        let mut state = self.state.lock().unwrap();
+        for (index, ok) in results.iter().enumerate() {
+            tracing::info!("Analyzer {}: {}", self.label(index), if *ok { "ok" } else { "unreachable" });
+            state[index].down_until = (!ok).then(|| Instant::now() + self.retry_after);
+        }
+        results.iter().filter(|ok| **ok).count()
+    }
+
+    /// Analyze on the pool's runtime, abandoning the request (and freeing
+    /// its slot) if `cancel` trips first.
+    pub fn analyze_blocking(&self, image_data: &[u8], tier: Option<&str>, cancel: &CancelToken) -> Result<Analysis, Error> {
+        self.analyze_in_sequence(image_data, &Neighbors::default(), tier, cancel)
+    }
+
+    /// `analyze_blocking`, showing the model the photo's neighbors too.
+    pub fn analyze_in_sequence(&self, image_data: &[u8], neighbors: &Neighbors, tier: Option<&str>, cancel: &CancelToken) -> Result<Analysis, Error> {
+        self.runtime.block_on(cancel.run(self.analyze(image_data, neighbors, tier)))
+    }
+
+    /// The answer to `prompt` about `images`, from the model for `stage`,
+    /// for questions other than the usual analysis.
+    pub fn ask_blocking(&self, images: &[&[u8]], stage: Stage, prompt: &str, cancel: &CancelToken) -> Result<String, Error> {
+        self.runtime.block_on(cancel.run(self.request(images, stage, prompt)))
+    }
+
+    /// The model `host` uses for `stage`.
+    fn model<'a>(&'a self, host: &'a HostConfig, stage: Stage) -> &'a str {
+        host.stages.get(stage).or(self.stages.get(stage)).unwrap_or(&host.model)
+    }
+
+    /// Run each stage, as a request of its own unless description and
+    /// keywords share a model on every host. The prompts show the corrected
+    /// captions for `tier` as examples, and the description and keywords
+    /// are asked for with the photo's neighbors alongside.
+    pub async fn analyze(&self, image_data: &[u8], neighbors: &Neighbors, tier: Option<&str>) -> Result<Analysis, Error> {
+        let examples = self.examples.for_tier(tier);
+        let sequence = neighbors.around(image_data);
+        let split = self.hosts.iter().any(|host| self.model(host, Stage::Description) != self.model(host, Stage::Keywords));
+        let (description, keywords) = if split {
+            let prompt = with_examples(&in_sequence(DESCRIPTION_PROMPT, neighbors), &examples, |example| example.description.clone());
+            let description = self.request(&sequence, Stage::Description, &prompt).await?;
+            let prompt = with_examples(&in_sequence(KEYWORDS_PROMPT, neighbors), &examples, |example| example.keywords.clone());
+            let keywords = self.request(&sequence, Stage::Keywords, &prompt).await?;
+            (description.trim().to_string(), keywords.trim().trim_start_matches("Keywords: ").to_string())
+        } else {
+            // Written the way the answer is parsed
+            let prompt = with_examples(&in_sequence(PROMPT, neighbors), &examples, |example| {
+                format!("{}\n\nKeywords: {}", example.description, example.keywords)
+            });
+            parse_analysis(&self.request(&sequence, Stage::Description, &prompt).await?)
+        };
+        let reads_text = self.stages.text.is_some() || self.hosts.iter().any(|host| host.stages.text.is_some());
+        let text = match reads_text {
+            true => Some(self.request(&[image_data], Stage::Text, TEXT_PROMPT).await?.trim().to_string()).filter(|text| !text.is_empty()),
+            false => None,
+        };
+
+        tracing::debug!("Keywords: {}", keywords);
+        tracing::debug!("Description: {}", description);
+        Ok(Analysis { description, keywords, text })
+    }
+
+    /// One prompt to whichever host is free, with its model for `stage`.
+    async fn request(&self, images: &[&[u8]], stage: Stage, prompt: &str) -> Result<String, Error> {
+        let mut failovers = 0;
+        loop {
+            let mut slot = self.acquire().await?;
+            let index = slot.index;
+            let host = &self.hosts[index];
+            let model = self.model(host, stage);
+            let result = match host.kind {
+                HostKind::Ollama => generate(images, &host.url, model, prompt).await,
+                HostKind::OpenAi => chat(images, host, model, prompt).await,
+            };
+
+            let failed = matches!(&result, Err(e) if is_host_failure(e));
+            slot.failed = failed;
+            drop(slot);
+            match result {
+                Err(e) if failed && failovers < self.hosts.len() => {
+                    tracing::warn!("Analyzer {} failed ({}), trying another host", self.label(index), e);
+                    failovers += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn acquire(&self) -> Result<Slot<'_>, Error> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                if !state.iter().any(|s| is_up(s, now)) {
+                    return Err(CliError::new(ErrorKind::BackendUnreachable, "no analyzer host is reachable").into());
+                }
+                if let Some(index) = pick_host(&self.hosts, &mut state, now) {
+                    state[index].in_flight += 1;
+                    return Ok(Slot { pool: self, index, failed: false });
+                }
+            }
+            self.slot_freed.notified().await;
+        }
+    }
+
+    fn release(&self, index: usize, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        state[index].in_flight -= 1;
+        state[index].down_until = failed.then(|| Instant::now() + self.retry_after);
+        drop(state);
+        self.slot_freed.notify_one();
+    }
+}
+
+/// A request slot on one host, given back when dropped so that abandoned
+/// (cancelled) requests don't leak it.
+struct Slot<'a> {
+    pool: &'a AnalyzerPool,
+    index: usize,
+    failed: bool,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.pool.release(self.index, self.failed);
+    }
+}
+
+fn is_up(state: &HostState, now: Instant) -> bool {
+    state.down_until.is_none_or(|until| until <= now)
+}
+
+/// Smooth weighted round-robin over the hosts that are up and have a free
+/// slot. Fallback hosts are only considered while no primary host is up;
+/// a busy primary means waiting rather than spilling over.
+fn pick_host(hosts: &[HostConfig], state: &mut [HostState], now: Instant) -> Option<usize> {
+    let primary_up = hosts.iter().zip(state.iter()).any(|(h, s)| !h.fallback && is_up(s, now));
+    let candidates: Vec<usize> = (0..hosts.len())
+        .filter(|&i| hosts[i].fallback != primary_up)
+        .filter(|&i| is_up(&state[i], now) && state[i].in_flight < hosts[i].concurrency)
+        .collect();
+
+    let total: i64 = candidates.iter().map(|&i| hosts[i].weight as i64).sum();
+    let mut best = None;
+    for &i in &candidates {
+        state[i].current_weight += hosts[i].weight as i64;
+        if best.is_none_or(|b: usize| state[i].current_weight > state[b].current_weight) {
+            best = Some(i);
+        }
+    }
+    if let Some(b) = best {
+        state[b].current_weight -= total;
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use tempfile::tempdir;
+    use mockito::Server;
+
+    fn host(url: &str, weight: u32, fallback: bool) -> HostConfig {
+        HostConfig { weight, fallback, ..HostConfig::ollama(url) }
+    }
+
+    #[tokio::test]
+    async fn test_get_image_analysis() -> Result<(), Error> {
+        // Create a mock server
+        let mut server = Server::new_async().await;
+
+        // Create a mock response
+        let mock_response = r#"{
+            "model": "llava",
+            "response": "A colorful sunset over mountains\n\nKeywords: sunset, mountains, nature, landscape, evening, colorful"
+        }"#;
+
+        // Set up the mock endpoint
+        let _m = server.mock("POST", "/api/generate")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(mock_response)
+            .create();
+
+        // Create a temporary test image
+        let dir = tempdir()?;
+        let test_image_path = dir.path().join("test.jpg");
+        let mut test_image = File::create(&test_image_path)?;
+
+        // Write some dummy image data
+        test_image.write_all(&[0xFF, 0xD8, 0xFF, 0xE0])?; // JPEG header
+        test_image.sync_all()?;
+
+        // Test the analysis function
+        let image_data = fs::read(&test_image_path)?;
+        let (description, keywords) = parse_analysis(&generate(&[&image_data], &server.url(), "llava", PROMPT).await?);
+
+        assert_eq!(description, "A colorful sunset over mountains");
+        assert_eq!(
+            keywords,
+            "sunset, mountains, nature, landscape, evening, colorful"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prompt_with_examples() {
+        assert_eq!(with_examples(PROMPT, &[], |example| example.description.clone()), PROMPT);
+        let example = Example { tier: None, description: String::from("Ana's first steps"), keywords: String::from("Ana, baby") };
+        let prompt = with_examples(KEYWORDS_PROMPT, &[&example], |example| example.keywords.clone());
+        assert!(prompt.contains("Example answer:\nAna, baby\n\n"));
+        assert!(!prompt.contains("first steps"));
+        assert!(prompt.ends_with(KEYWORDS_PROMPT));
+    }
+
+    #[test]
+    fn test_pick_host_weighted() {
+        let mut hosts = vec![host("a", 2, false), host("b", 1, false)];
+        for h in &mut hosts {
+            h.concurrency = 10;
+        }
+        let mut state: Vec<HostState> = hosts.iter().map(|_| HostState::default()).collect();
+        let now = Instant::now();
+        let picks: Vec<usize> = (0..6).map(|_| pick_host(&hosts, &mut state, now).unwrap()).collect();
+        assert_eq!(picks.iter().filter(|&&i| i == 0).count(), 4);
+        assert_eq!(picks.iter().filter(|&&i| i == 1).count(), 2);
+    }
+
+    #[test]
+    fn test_pick_host_failover_and_fallback() {
+        let hosts = vec![host("a", 1, false), host("b", 1, false), host("cloud", 1, true)];
+        let mut state: Vec<HostState> = hosts.iter().map(|_| HostState::default()).collect();
+        let now = Instant::now();
+
+        // Busy primary: wait for it rather than use the fallback
+        state[0].in_flight = 1;
+        state[1].in_flight = 1;
+        assert_eq!(pick_host(&hosts, &mut state, now), None);
+
+        state[0].in_flight = 0;
+        state[1].down_until = Some(now + Duration::from_secs(60));
+        assert_eq!(pick_host(&hosts, &mut state, now), Some(0));
+
+        state[0].down_until = Some(now + Duration::from_secs(60));
+        assert_eq!(pick_host(&hosts, &mut state, now), Some(2));
+
+        // Cooldown over: primaries are back in rotation
+        let later = now + Duration::from_secs(61);
+        state[1].in_flight = 0;
+        assert_ne!(pick_host(&hosts, &mut state, later), Some(2));
+    }
+
+    #[test]
+    fn test_analyze_fails_over() -> Result<(), Error> {
+        let mut server = Server::new();
+        server.mock("POST", "/api/generate")
+            .with_status(200)
+            .with_body(r#"{"response": "A cat\n\nKeywords: cat, sofa"}"#)
+            .create();
+        let mut broken = Server::new();
+        broken.mock("POST", "/api/generate").with_status(503).create();
+
+        let image = [0xFF, 0xD8, 0xFF, 0xE0];
+        let pool = AnalyzerPool::new(&AnalyzerConfig {
+            hosts: vec![host(&broken.url(), 100, false), host(&server.url(), 1, false)],
+            ..AnalyzerConfig::default()
+        })?;
+        let analysis = pool.analyze_blocking(&image, None, &CancelToken::new())?;
+        assert_eq!(analysis.description, "A cat");
+        assert_eq!(analysis.keywords, "cat, sofa");
+        assert_eq!(analysis.text, None);
+
+        // The broken host now sits out, so the next request goes straight through
+        assert_eq!(pool.analyze_blocking(&image, None, &CancelToken::new())?.description, "A cat");
+        Ok(())
+    }
+
+    #[test]
+    fn test_analyze_per_stage_models() -> Result<(), Error> {
+        let mut server = Server::new();
+        let mut answer = |model: &str, response: &str| {
+            server.mock("POST", "/api/generate")
+                .match_body(mockito::Matcher::PartialJson(serde_json::json!({ "model": model })))
+                .with_body(serde_json::json!({ "response": response }).to_string())
+                .create()
+        };
+        let description = answer("llava:13b", "A receipt on a table\n");
+        let keywords = answer("moondream", "Keywords: receipt, table");
+        let text = answer("llava", "TOTAL 12.50\n");
+
+        let image = [0xFF, 0xD8, 0xFF, 0xE0];
+        let pool = AnalyzerPool::new(&AnalyzerConfig {
+            hosts: vec![host(&server.url(), 1, false)],
+            stages: StageModels {
+                description: Some(String::from("llava:13b")),
+                keywords: Some(String::from("moondream")),
+                // The host's own model
+                text: Some(String::from("llava")),
+            },
+            ..AnalyzerConfig::default()
+        })?;
+        let analysis = pool.analyze_blocking(&image, None, &CancelToken::new())?;
+        assert_eq!(analysis, Analysis {
+            description: String::from("A receipt on a table"),
+            keywords: String::from("receipt, table"),
+            text: Some(String::from("TOTAL 12.50")),
+        });
+        description.assert();
+        keywords.assert();
+        text.assert();
+        assert_eq!(analysis.external()[0].source, TEXT_SOURCE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_analyze_in_sequence() -> Result<(), Error> {
+        let mut server = Server::new();
+        let images = [[1u8], [2], [3]].map(|image| STANDARD.encode(image));
+        let mock = server.mock("POST", "/api/generate")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::PartialJson(serde_json::json!({ "images": images })),
+                mockito::Matcher::Regex(String::from("These 3 photos .* Answer about photo 2 only")),
+            ]))
+            .with_body(r#"{"response": "Blowing out the candles\n\nKeywords: birthday, cake"}"#)
+            .create();
+
+        let pool = AnalyzerPool::new(&AnalyzerConfig { hosts: vec![host(&server.url(), 1, false)], ..AnalyzerConfig::default() })?;
+        let neighbors = Neighbors { before: vec![vec![1]], after: vec![vec![3]] };
+        let analysis = pool.analyze_in_sequence(&[2], &neighbors, None, &CancelToken::new())?;
+        assert_eq!(analysis.description, "Blowing out the candles");
+        mock.assert();
+        assert_eq!(in_sequence(PROMPT, &Neighbors::default()), PROMPT);
+        Ok(())
+    }
+}
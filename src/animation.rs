@@ -0,0 +1,164 @@
+//! Animated GIF and PNG (APNG) images. Frame count, running time and how
+//! often they play are read from the GIF blocks and APNG chunks directly;
+//! decoding is left to the image crate, which composites whole frames, and
+//! the middle one is what gets analyzed (the first is often blank or a
+//! title card).
+
+use std::io::Cursor;
+use anyhow::{anyhow, bail, Error};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::{AnimationDecoder, DynamicImage, ImageFormat};
+
+/// GIF delays below this many hundredths of a second are shown as
+/// `DEFAULT_GIF_DELAY` by browsers, and counted that way here.
+const MIN_GIF_DELAY: u32 = 2;
+const DEFAULT_GIF_DELAY: u32 = 10;
+
+#[derive(Debug, PartialEq)]
+pub struct Animation {
+    pub dimensions: (u32, u32),
+    pub frame_count: u32,
+    /// One play through
+    pub duration_secs: f64,
+    /// How many times it plays, 0 for forever
+    pub play_count: u32,
+}
+
+/// The animation in `data`, or `None` if it isn't an animated GIF or PNG.
+/// Single-frame files count as still images.
+pub fn read(data: &[u8]) -> Option<Animation> {
+    let animation = match data.get(..6)? {
+        b"GIF87a" | b"GIF89a" => read_gif(data),
+        _ if data.starts_with(b"\x89PNG\r\n\x1a\n") => read_apng(data),
+        _ => None,
+    }?;
+    (animation.frame_count > 1).then_some(animation)
+}
+
+fn le16(bytes: &[u8]) -> u32 {
+    u16::from_le_bytes([bytes[0], bytes[1]]) as u32
+}
+
+fn read_gif(data: &[u8]) -> Option<Animation> {
+    let dimensions = (le16(data.get(6..8)?), le16(data.get(8..10)?));
+    let color_table = |flags: u8| if flags & 0x80 != 0 { 3 << ((flags & 7) + 1) } else { 0 };
+    let mut pos = 13 + color_table(*data.get(10)?);
+    let (mut frame_count, mut delay) = (0, 0);
+    // Without a NETSCAPE2.0 block a GIF plays once
+    let mut play_count = 1;
+    loop {
+        match *data.get(pos)? {
+            0x21 => {
+                let label = *data.get(pos + 1)?;
+                pos += 2;
+                let mut first = true;
+                let mut netscape = false;
+                while let Some(&len) = data.get(pos).filter(|&&len| len > 0) {
+                    let block = data.get(pos + 1..pos + 1 + len as usize)?;
+                    match label {
+                        // Graphic control: the delay before the next frame
+                        0xF9 if first && block.len() >= 3 => {
+                            let frame_delay = le16(&block[1..3]);
+                            delay += if frame_delay < MIN_GIF_DELAY { DEFAULT_GIF_DELAY } else { frame_delay };
+                        }
+                        0xFF if first => netscape = block == b"NETSCAPE2.0",
+                        // The loop count repeats the first play; 0 repeats forever
+                        0xFF if netscape && block.len() >= 3 && block[0] == 1 => {
+                            play_count = match le16(&block[1..3]) {
+                                0 => 0,
+                                repeats => repeats + 1,
+                            };
+                        }
+                        _ => {}
+                    }
+                    first = false;
+                    pos += 1 + len as usize;
+                }
+                pos += 1;
+            }
+            0x2C => {
+                frame_count += 1;
+                // Descriptor, local color table, LZW code size, then data
+                pos += 10 + color_table(*data.get(pos + 9)?) + 1;
+                while let Some(&len) = data.get(pos).filter(|&&len| len > 0) {
+                    pos += 1 + len as usize;
+                }
+                pos += 1;
+            }
+            _ => break,
+        }
+    }
+    Some(Animation { dimensions, frame_count, duration_secs: delay as f64 / 100.0, play_count })
+}
+
+fn read_apng(data: &[u8]) -> Option<Animation> {
+    let be = |bytes: &[u8]| u32::from_be_bytes(bytes.try_into().unwrap());
+    let mut rest = data.get(8..)?;
+    let (mut dimensions, mut control, mut duration_secs) = (None, None, 0.0);
+    while rest.len() >= 12 {
+        let len = be(&rest[..4]) as usize;
+        let contents = rest.get(8..8 + len)?;
+        match &rest[4..8] {
+            b"IHDR" if len >= 8 => dimensions = Some((be(&contents[..4]), be(&contents[4..8]))),
+            // Frame count and play count
+            b"acTL" if len >= 8 => control = Some((be(&contents[..4]), be(&contents[4..8]))),
+            b"fcTL" if len >= 24 => {
+                let (num, den) = (u16::from_be_bytes([contents[20], contents[21]]), u16::from_be_bytes([contents[22], contents[23]]));
+                duration_secs += num as f64 / if den == 0 { 100.0 } else { den as f64 };
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        rest = rest.get(12 + len..)?;
+    }
+    let (frame_count, play_count) = control?;
+    Some(Animation { dimensions: dimensions?, frame_count, duration_secs, play_count })
+}
+
+/// Decode the middle frame of an animation with `frame_count` frames.
+pub fn middle_frame(data: &[u8], frame_count: u32) -> Result<DynamicImage, Error> {
+    let frames = match image::guess_format(data)? {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(data))?.into_frames(),
+        ImageFormat::Png => PngDecoder::new(Cursor::new(data))?.apng().into_frames(),
+        format => bail!("{:?} images aren't animated", format),
+    };
+    let middle = frame_count as usize / 2;
+    let frame = frames.into_iter().nth(middle).ok_or_else(|| anyhow!("animation has no frame {}", middle))??;
+    Ok(DynamicImage::ImageRgba8(frame.into_buffer()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_gif() -> Result<(), Error> {
+        let gif = animated_gif(&[[255, 0, 0], [0, 255, 0], [0, 0, 255]], 20, Some(0));
+        assert_eq!(read(&gif), Some(Animation { dimensions: (4, 3), frame_count: 3, duration_secs: 0.6, play_count: 0 }));
+        let frame = middle_frame(&gif, 3)?;
+        assert_eq!(frame.dimensions(), (4, 3));
+        assert_eq!(frame.get_pixel(0, 0).0, [0, 255, 0, 255]);
+
+        // No loop block: plays once; zero delays count as browsers show them
+        let once = animated_gif(&[[0, 0, 0], [255, 255, 255]], 0, None);
+        assert_eq!(read(&once).map(|a| (a.play_count, a.duration_secs)), Some((1, 0.2)));
+        // A still GIF isn't an animation
+        assert_eq!(read(&animated_gif(&[[0, 0, 0]], 0, None)), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apng() -> Result<(), Error> {
+        let apng = animated_png(&[[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]], 3);
+        assert_eq!(read(&apng), Some(Animation { dimensions: (4, 3), frame_count: 4, duration_secs: 2.0, play_count: 3 }));
+        assert_eq!(middle_frame(&apng, 4)?.get_pixel(0, 0).0, [0, 0, 255, 255]);
+
+        let mut still = Vec::new();
+        DynamicImage::new_rgb8(4, 3).write_to(&mut Cursor::new(&mut still), ImageFormat::Png)?;
+        assert_eq!(read(&still), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+//! Apple Photos libraries (`.photoslibrary` bundles, Photos 5 and later).
+//! Inside, the originals sit in folders named after UUIDs next to
+//! thumbnails and edited renders, so instead of walking the bundle a scan
+//! takes the list of photos from the library's own database,
+//! `database/Photos.sqlite`, and merges what Photos knows about each:
+//! its albums, whether it's a favorite or hidden, its caption and the name
+//! it was imported under, as metadata from the source `apple_photos`, and
+//! its date and place when the file has none.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context, Error};
+use chrono::{DateTime, Local};
+use rusqlite::{Connection, OpenFlags};
+use crate::{geocode, ExternalMetadata, ImageMetadata};
+
+const SOURCE: &str = "apple_photos";
+
+/// Photos stores times as seconds since 2001-01-01 UTC.
+const CORE_DATA_EPOCH: i64 = 978_307_200;
+
+/// What the library's database says about one original.
+#[derive(Debug, Default)]
+struct Asset {
+    date: Option<String>,
+    gps: Option<(f64, f64)>,
+    fields: Vec<(&'static str, String)>,
+}
+
+pub struct Library {
+    /// By the original's path
+    assets: HashMap<PathBuf, Asset>,
+}
+
+pub fn is_library(dir: &Path) -> bool {
+    dir.extension().is_some_and(|extension| extension == "photoslibrary") && dir.join("database/Photos.sqlite").is_file()
+}
+
+/// The table linking albums to their photos, and its album and photo
+/// columns, e.g. `Z_26ASSETS (Z_26ALBUMS, Z_3ASSETS)`. The numbers differ
+/// between versions of Photos.
+fn album_table(db: &Connection) -> Result<Option<(String, String, String)>, Error> {
+    let tables: Vec<String> = db.prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name GLOB 'Z_[0-9]*ASSETS'")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    for table in tables {
+        let columns: Vec<String> = db.prepare("SELECT name FROM pragma_table_info(?1)")?
+            .query_map([&table], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        let albums = columns.iter().find(|column| column.ends_with("ALBUMS"));
+        let assets = columns.iter().find(|column| column.ends_with("ASSETS"));
+        if let (Some(albums), Some(assets)) = (albums, assets) {
+            return Ok(Some((table.clone(), albums.clone(), assets.clone())));
+        }
+    }
+    Ok(None)
+}
+
+impl Library {
+    pub fn open(dir: &Path) -> Result<Library, Error> {
+        let path = dir.join("database/Photos.sqlite");
+        let db = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("opening {}", path.display()))?;
+        Library::read(dir, &db).with_context(|| format!("reading the Photos library {}", dir.display()))
+    }
+
+    fn read(dir: &Path, db: &Connection) -> Result<Library, Error> {
+        let mut by_id: HashMap<i64, (PathBuf, Asset)> = HashMap::new();
+        let mut stmt = db.prepare(
+            "SELECT a.Z_PK, a.ZDIRECTORY, a.ZFILENAME, a.ZDATECREATED, a.ZLATITUDE, a.ZLONGITUDE,
+                    a.ZFAVORITE, a.ZHIDDEN, attributes.ZORIGINALFILENAME, description.ZLONGDESCRIPTION
+             FROM ZASSET a
+             LEFT JOIN ZADDITIONALASSETATTRIBUTES attributes ON attributes.ZASSET = a.Z_PK
+             LEFT JOIN ZASSETDESCRIPTION description ON description.ZASSETATTRIBUTES = attributes.Z_PK
+             WHERE a.ZTRASHEDSTATE = 0",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let (Some(directory), Some(file)) = (row.get::<_, Option<String>>(1)?, row.get::<_, Option<String>>(2)?) else { continue };
+            let mut asset = Asset {
+                date: row.get::<_, Option<f64>>(3)?
+                    .and_then(|seconds| DateTime::from_timestamp(CORE_DATA_EPOCH + seconds as i64, 0))
+                    .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string()),
+                // Photos writes -180, -180 for "no location"
+                gps: match (row.get::<_, Option<f64>>(4)?, row.get::<_, Option<f64>>(5)?) {
+                    (Some(latitude), Some(longitude)) if (-90.0..=90.0).contains(&latitude) && longitude > -180.0 => Some((latitude, longitude)),
+                    _ => None,
+                },
+                fields: Vec::new(),
+            };
+            if row.get::<_, Option<i64>>(6)?.unwrap_or_default() != 0 {
+                asset.fields.push(("favorite", String::from("true")));
+            }
+            if row.get::<_, Option<i64>>(7)?.unwrap_or_default() != 0 {
+                asset.fields.push(("hidden", String::from("true")));
+            }
+            if let Some(name) = row.get::<_, Option<String>>(8)? {
+                asset.fields.push(("original_file_name", name));
+            }
+            if let Some(caption) = row.get::<_, Option<String>>(9)?.filter(|caption| !caption.trim().is_empty()) {
+                asset.fields.push(("caption", caption));
+            }
+            by_id.insert(row.get(0)?, (dir.join("originals").join(directory).join(file), asset));
+        }
+        drop(rows);
+
+        if let Some((table, albums, assets)) = album_table(db)? {
+            // Kind 2 is an album the user made, rather than a smart album
+            // or a shared one
+            let mut stmt = db.prepare(&format!(
+                "SELECT j.{}, album.ZTITLE FROM {} j JOIN ZGENERICALBUM album ON album.Z_PK = j.{}
+                 WHERE album.ZKIND = 2 AND album.ZTRASHEDSTATE = 0 AND album.ZTITLE IS NOT NULL
+                 ORDER BY album.ZTITLE",
+                assets, table, albums,
+            ))?;
+            let members = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+            for member in members {
+                let (asset, album) = member?;
+                if let Some((_, asset)) = by_id.get_mut(&asset) {
+                    asset.fields.push(("album", album));
+                }
+            }
+        }
+        Ok(Library { assets: by_id.into_values().collect() })
+    }
+
+    /// The originals still in the library, in a stable order.
+    pub fn originals(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.assets.keys().filter(|path| path.is_file()).cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    /// Merge what the library says about a photo into its metadata.
+    pub fn apply(&self, metadata: &mut ImageMetadata) -> Result<(), Error> {
+        let asset = self.assets.get(Path::new(&metadata.path))
+            .ok_or_else(|| anyhow!("{} isn't in the Photos library", metadata.path))?;
+        for (field, value) in &asset.fields {
+            metadata.external.push(ExternalMetadata { source: SOURCE.to_string(), field: field.to_string(), value: value.clone() });
+        }
+        if metadata.creation_date.is_none() {
+            metadata.creation_date = asset.date.clone();
+        }
+        if metadata.gps.is_none() {
+            metadata.gps = asset.gps;
+            metadata.place = asset.gps.and_then(|(latitude, longitude)| geocode::reverse_geocode(latitude, longitude));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_photos_library() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let bundle = dir.path().join("Photos Library.photoslibrary");
+        fs::create_dir_all(bundle.join("database"))?;
+        fs::create_dir_all(bundle.join("originals/4"))?;
+        fs::create_dir_all(bundle.join("originals/9"))?;
+        assert!(!is_library(&bundle));
+        let db = Connection::open(bundle.join("database/Photos.sqlite"))?;
+        db.execute_batch(
+            "CREATE TABLE ZASSET (Z_PK INTEGER PRIMARY KEY, ZDIRECTORY VARCHAR, ZFILENAME VARCHAR, ZDATECREATED TIMESTAMP,
+                 ZLATITUDE FLOAT, ZLONGITUDE FLOAT, ZFAVORITE INTEGER, ZHIDDEN INTEGER, ZTRASHEDSTATE INTEGER);
+             CREATE TABLE ZADDITIONALASSETATTRIBUTES (Z_PK INTEGER PRIMARY KEY, ZASSET INTEGER, ZORIGINALFILENAME VARCHAR);
+             CREATE TABLE ZASSETDESCRIPTION (Z_PK INTEGER PRIMARY KEY, ZASSETATTRIBUTES INTEGER, ZLONGDESCRIPTION VARCHAR);
+             CREATE TABLE ZGENERICALBUM (Z_PK INTEGER PRIMARY KEY, ZTITLE VARCHAR, ZKIND INTEGER, ZTRASHEDSTATE INTEGER);
+             CREATE TABLE Z_28ASSETS (Z_28ALBUMS INTEGER, Z_3ASSETS INTEGER);
+             INSERT INTO ZASSET VALUES
+                 (1, '4', '4A1F-UUID.jpeg', 711033727.0, 38.7111, -9.1302, 1, 0, 0),
+                 (2, '9', '9C2E-UUID.jpeg', 711033800.0, -180.0, -180.0, 0, 1, 0),
+                 (3, '9', '9D3F-UUID.jpeg', 711033900.0, -180.0, -180.0, 0, 0, 1);
+             INSERT INTO ZADDITIONALASSETATTRIBUTES VALUES (10, 1, 'IMG_0042.JPG'), (11, 2, 'IMG_0043.JPG');
+             INSERT INTO ZASSETDESCRIPTION VALUES (20, 10, 'Tram 28');
+             INSERT INTO ZGENERICALBUM VALUES (30, 'Lisbon', 2, 0), (31, 'Favorites', 1509, 0), (32, 'Old', 2, 1);
+             INSERT INTO Z_28ASSETS VALUES (30, 1), (31, 1), (32, 1);",
+        )?;
+        drop(db);
+        for file in ["4/4A1F-UUID.jpeg", "9/9D3F-UUID.jpeg"] {
+            fs::write(bundle.join("originals").join(file), crate::test_support::jpeg_with_exif(&[]))?;
+        }
+        assert!(is_library(&bundle));
+
+        let library = Library::open(&bundle)?;
+        // The second is missing from disk (still in iCloud), the third is
+        // in the trash
+        let first = bundle.join("originals/4/4A1F-UUID.jpeg");
+        assert_eq!(library.originals(), vec![first.clone()]);
+
+        let mut metadata = ImageMetadata { path: first.to_string_lossy().into_owned(), ..ImageMetadata::default() };
+        library.apply(&mut metadata)?;
+        let fields: Vec<(&str, &str)> = metadata.external.iter().map(|m| (m.field.as_str(), m.value.as_str())).collect();
+        assert_eq!(fields, vec![("favorite", "true"), ("original_file_name", "IMG_0042.JPG"), ("caption", "Tram 28"), ("album", "Lisbon")]);
+        assert_eq!(metadata.gps, Some((38.7111, -9.1302)));
+        let expected = DateTime::from_timestamp(CORE_DATA_EPOCH + 711033727, 0).unwrap().with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string();
+        assert_eq!(metadata.creation_date, Some(expected));
+
+        let mut outside = ImageMetadata { path: String::from("/elsewhere.jpg"), ..ImageMetadata::default() };
+        assert!(library.apply(&mut outside).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+//! The audit log: every change made through the API, with who made it (see
+//! `roles`), what it was done to, and the values before and after, as
+//! JSON. The table only takes new rows; triggers refuse the rest, so the
+//! log can't be quietly rewritten through the catalog. `audit show` reads
+//! it.
+
+use anyhow::Error;
+use rusqlite::{params_from_iter, Connection, ToSql};
+#[cfg(feature = "server")]
+use rusqlite::params;
+#[cfg(feature = "server")]
+use serde_json::Value;
+
+/// Who made changes when there are no users to tell apart.
+#[cfg(feature = "server")]
+pub const ANONYMOUS: &str = "anonymous";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub at: String,
+    pub actor: String,
+    /// e.g. "edit", "scan", "cancel"
+    pub action: String,
+    /// e.g. an image's path, or "operation 12"
+    pub target: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// Log that `actor` did `action` to `target`.
+#[cfg(feature = "server")]
+pub fn record(conn: &Connection, actor: &str, action: &str, target: &str, before: Option<&Value>, after: Option<&Value>) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO audit (at, actor, action, target, before, after) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            chrono::Utc::now().to_rfc3339(), actor, action, target,
+            before.map(Value::to_string), after.map(Value::to_string),
+        ],
+    )?;
+    Ok(())
+}
+
+/// The latest `limit` entries, or just `actor`'s, oldest first.
+pub fn list(conn: &Connection, actor: Option<&str>, limit: usize) -> Result<Vec<Entry>, Error> {
+    let mut sql = String::from("SELECT at, actor, action, target, before, after FROM audit");
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+    if let Some(actor) = actor {
+        sql.push_str(" WHERE actor = ?");
+        values.push(Box::new(actor.to_string()));
+    }
+    sql.push_str(&format!(" ORDER BY id DESC LIMIT {}", limit));
+    let mut stmt = conn.prepare(&sql)?;
+    let mut entries: Vec<Entry> = stmt
+        .query_map(params_from_iter(values), |row| {
+            Ok(Entry { at: row.get(0)?, actor: row.get(1)?, action: row.get(2)?, target: row.get(3)?, before: row.get(4)?, after: row.get(5)? })
+        })?
+        .collect::<Result<_, _>>()?;
+    entries.reverse();
+    Ok(entries)
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_audit() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        record(&conn, "rui", "edit", "/tram.jpg", Some(&json!({ "keywords": "tram" })), Some(&json!({ "keywords": "tram, lisbon" })))?;
+        record(&conn, "ana", "cancel", "operation 3", None, None)?;
+        record(&conn, "rui", "edit", "/boat.jpg", None, Some(&json!({ "keywords": "boat" })))?;
+
+        let entries = list(&conn, None, 2)?;
+        assert_eq!(entries.iter().map(|entry| entry.target.as_str()).collect::<Vec<_>>(), vec!["operation 3", "/boat.jpg"]);
+        let entries = list(&conn, Some("rui"), 10)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].before.as_deref(), Some(r#"{"keywords":"tram"}"#));
+        assert_eq!(entries[0].after.as_deref(), Some(r#"{"keywords":"tram, lisbon"}"#));
+
+        // Append-only
+        assert!(conn.execute("UPDATE audit SET actor = 'ana'", []).is_err());
+        assert!(conn.execute("DELETE FROM audit", []).is_err());
+        assert_eq!(list(&conn, Some("rui"), 10)?.len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+//! Image quality by client, so browsing a catalog served from home stays
+//! usable over a poor connection. Clients on the `full_quality` networks
+//! (the LAN, by default) get thumbnails as asked for; everyone else gets
+//! them no bigger than `remote` allows and more compressed, and clients
+//! asking to save data (`Save-Data: on`) or on a slow connection (by the
+//! `ECT` client hint) get `slow`'s, wherever they are.
+
+#[cfg(any(feature = "server", test))]
+use std::net::IpAddr;
+use serde::Deserialize;
+#[cfg(feature = "server")]
+use anyhow::Error;
+#[cfg(feature = "server")]
+use crate::error::{CliError, ErrorKind};
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub struct BandwidthConfig {
+    /// Networks served at full quality, as CIDR, e.g. "192.168.1.0/24".
+    pub full_quality: Vec<String>,
+    pub remote: Limits,
+    pub slow: Limits,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub struct Limits {
+    /// Longest edge of thumbnails, however big they're asked for.
+    pub max_edge: u32,
+    /// 1 to 100.
+    pub jpeg_quality: u8,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        let private = ["127.0.0.0/8", "10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "::1/128", "fc00::/7", "fe80::/10"];
+        BandwidthConfig {
+            full_quality: private.iter().map(|network| network.to_string()).collect(),
+            remote: Limits { max_edge: 1600, jpeg_quality: 75 },
+            slow: Limits { max_edge: 640, jpeg_quality: 50 },
+        }
+    }
+}
+
+/// A network, as CIDR.
+#[cfg(any(feature = "server", test))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Network {
+    address: IpAddr,
+    prefix: u32,
+}
+
+#[cfg(any(feature = "server", test))]
+impl Network {
+    fn parse(cidr: &str) -> Option<Network> {
+        let (address, prefix) = cidr.trim().split_once('/')?;
+        let address: IpAddr = address.parse().ok()?;
+        let prefix: u32 = prefix.parse().ok()?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        (prefix <= bits).then_some(Network { address, prefix })
+    }
+
+    fn contains(&self, address: IpAddr) -> bool {
+        // e.g. ::ffff:192.168.1.2, as a dual-stack listener sees IPv4 clients
+        let (network, address, bits) = match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => (u32::from(network) as u128, u32::from(address) as u128, 32),
+            (IpAddr::V6(network), IpAddr::V6(address)) => (u128::from(network), u128::from(address), 128),
+            _ => return false,
+        };
+        let shift = bits - self.prefix;
+        shift == bits || network >> shift == address >> shift
+    }
+}
+
+/// The configuration, with the networks parsed.
+#[cfg(feature = "server")]
+pub struct Bandwidth {
+    full_quality: Vec<Network>,
+    remote: Limits,
+    slow: Limits,
+}
+
+#[cfg(feature = "server")]
+impl Bandwidth {
+    pub fn new(config: &BandwidthConfig) -> Result<Bandwidth, Error> {
+        let full_quality = config.full_quality.iter()
+            .map(|cidr| Network::parse(cidr).ok_or_else(|| CliError::new(ErrorKind::Config, format!("bandwidth.full_quality: {} isn't a network, e.g. 192.168.1.0/24", cidr))))
+            .collect::<Result<_, _>>()?;
+        for limits in [&config.remote, &config.slow] {
+            if !(1..=100).contains(&limits.jpeg_quality) {
+                return Err(CliError::new(ErrorKind::Config, "bandwidth: jpeg_quality goes from 1 to 100").into());
+            }
+        }
+        Ok(Bandwidth { full_quality, remote: config.remote, slow: config.slow })
+    }
+
+    /// The limits for a request from `client` with the `Save-Data` and
+    /// `ECT` headers given, or none for full quality.
+    pub fn limits(&self, client: IpAddr, save_data: Option<&str>, ect: Option<&str>) -> Option<Limits> {
+        let slow = save_data.is_some_and(|value| value.trim().eq_ignore_ascii_case("on"))
+            || ect.is_some_and(|value| matches!(value.trim(), "slow-2g" | "2g" | "3g"));
+        if slow {
+            Some(self.slow)
+        } else if self.full_quality.iter().any(|network| network.contains(client)) {
+            None
+        } else {
+            Some(self.remote)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_networks() {
+        let lan = Network::parse("192.168.1.0/24").unwrap();
+        assert!(lan.contains("192.168.1.20".parse().unwrap()));
+        assert!(lan.contains("::ffff:192.168.1.20".parse().unwrap()));
+        assert!(!lan.contains("192.168.2.20".parse().unwrap()));
+        assert!(!lan.contains("fe80::1".parse().unwrap()));
+        assert!(Network::parse("fc00::/7").unwrap().contains("fd12:3456::1".parse().unwrap()));
+        assert!(Network::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+        for bad in ["192.168.1.0", "192.168.1.0/33", "lan/24"] {
+            assert_eq!(Network::parse(bad), None);
+        }
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_limits() -> Result<(), Error> {
+        let config = BandwidthConfig::default();
+        let bandwidth = Bandwidth::new(&config)?;
+        let (home, away) = ("192.168.1.20".parse()?, "203.0.113.9".parse()?);
+        assert_eq!(bandwidth.limits(home, None, Some("4g")), None);
+        assert_eq!(bandwidth.limits(away, None, None), Some(config.remote));
+        assert_eq!(bandwidth.limits(home, Some("on"), None), Some(config.slow));
+        assert_eq!(bandwidth.limits(away, None, Some("3g")), Some(config.slow));
+
+        let config = BandwidthConfig { full_quality: vec![String::from("home")], ..BandwidthConfig::default() };
+        assert!(Bandwidth::new(&config).is_err());
+        Ok(())
+    }
+}
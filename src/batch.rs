@@ -0,0 +1,109 @@
+//! Writing many rows to the catalog. Committing each row on its own makes
+//! SQLite sync the file every time, which is most of what a large scan
+//! spends on the catalog, so rows are grouped into transactions instead.
+//! Each row still goes in whole or not at all, and a transaction is never
+//! held open long, so `status` and `cancel` from another terminal keep up
+//! and a second Ctrl-C, which waits for it, quits without losing it.
+
+use std::time::{Duration, Instant};
+use anyhow::Error;
+use rusqlite::Connection;
+use crate::cancel;
+
+/// Longest a transaction is kept open for, however few rows it has.
+const MAX_AGE: Duration = Duration::from_secs(1);
+
+pub struct Batch<'a> {
+    conn: &'a Connection,
+    size: usize,
+    pending: usize,
+    /// When the open transaction began, if one is
+    started: Option<Instant>,
+    writing: Option<cancel::Writing>,
+}
+
+impl<'a> Batch<'a> {
+    /// Commit every `size` rows (or `MAX_AGE`).
+    pub fn new(conn: &'a Connection, size: usize) -> Batch<'a> {
+        Batch { conn, size: size.max(1), pending: 0, started: None, writing: None }
+    }
+
+    /// Write one row with `write`, in the open transaction. If it fails,
+    /// whatever it wrote is undone and the rest of the batch is kept.
+    pub fn write<T>(&mut self, write: impl FnOnce(&Connection) -> Result<T, Error>) -> Result<T, Error> {
+        if self.started.is_none() {
+            self.writing = Some(cancel::writing());
+            self.conn.execute_batch("BEGIN IMMEDIATE")?;
+            self.started = Some(Instant::now());
+        }
+        self.conn.execute_batch("SAVEPOINT batch_row")?;
+        let written = write(self.conn);
+        if written.is_err() {
+            self.conn.execute_batch("ROLLBACK TO batch_row")?;
+        }
+        self.conn.execute_batch("RELEASE batch_row")?;
+        self.pending += 1;
+        if self.pending >= self.size || self.started.is_some_and(|started| started.elapsed() >= MAX_AGE) {
+            self.commit()?;
+        }
+        written
+    }
+
+    /// Commit the open transaction, if there is one.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        if self.started.take().is_some() {
+            self.conn.execute_batch("COMMIT")?;
+        }
+        self.pending = 0;
+        self.writing = None;
+        Ok(())
+    }
+}
+
+/// Rows written before a scan stops, by error or Ctrl-C, are kept.
+impl Drop for Batch<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit() {
+            tracing::error!("Error saving to the catalog: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(conn: &Connection) -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM numbers", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn test_batches() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("catalog.db");
+        let conn = crate::open_catalog(&path)?;
+        let mode: String = conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+        assert_eq!(mode, "wal");
+        conn.execute_batch("CREATE TABLE numbers (n INTEGER NOT NULL)")?;
+        let reader = Connection::open(&path)?;
+
+        let mut batch = Batch::new(&conn, 3);
+        for n in 0..2 {
+            batch.write(|conn| Ok(conn.execute("INSERT INTO numbers (n) VALUES (?1)", [n])?))?;
+        }
+        // Not committed yet, and readers aren't held up
+        assert_eq!(count(&reader), 0);
+        // A row that fails halfway leaves nothing behind
+        let failed = batch.write(|conn| {
+            conn.execute("INSERT INTO numbers (n) VALUES (2)", [])?;
+            Ok(conn.execute("INSERT INTO numbers (n) VALUES (NULL)", [])?)
+        });
+        assert!(failed.is_err());
+        assert_eq!(count(&reader), 2);
+
+        batch.write(|conn| Ok(conn.execute("INSERT INTO numbers (n) VALUES (3)", [])?))?;
+        drop(batch);
+        assert_eq!(count(&reader), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,408 @@
+//! `browse`: the catalog in the terminal, for servers reached over SSH where
+//! a browser is a tunnel away. A list of photos narrowed down by a query, the
+//! selected photo's metadata beside it, and its keywords editable in place.
+//! Terminals speaking the kitty graphics protocol (kitty, WezTerm, Ghostty,
+//! Konsole) can show the photo itself with `--preview`.
+
+use std::io::Write;
+use std::path::Path;
+use anyhow::Error;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::Frame;
+use rusqlite::{params_from_iter, Connection, OptionalExtension};
+use crate::config::Config;
+use crate::edits::Editor;
+use crate::{corrections, jobs, query, tags};
+
+/// Photos listed at most; a query narrows down the rest.
+const MAX_ROWS: usize = 5000;
+/// Longest edge of the image sent to the terminal, in pixels
+const PREVIEW_EDGE: u32 = 800;
+/// Base64 bytes per kitty graphics escape, as the protocol asks
+const KITTY_CHUNK: usize = 4096;
+
+struct Row {
+    path: String,
+    file_name: String,
+    keywords: Option<String>,
+    /// As loaded, to tell if someone else edits it meanwhile
+    version: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    List,
+    Search,
+    EditKeywords,
+}
+
+struct App {
+    query: String,
+    rows: Vec<Row>,
+    list: ListState,
+    /// Label and value of each known field of the selected photo
+    details: Vec<(&'static str, String)>,
+    mode: Mode,
+    /// What's being typed, in the search and edit modes
+    input: String,
+    message: Option<String>,
+    quit: bool,
+}
+
+impl App {
+    fn new(conn: &Connection) -> Result<App, Error> {
+        let mut app = App {
+            query: String::new(),
+            rows: Vec::new(),
+            list: ListState::default(),
+            details: Vec::new(),
+            mode: Mode::List,
+            input: String::new(),
+            message: None,
+            quit: false,
+        };
+        app.search(conn, "")?;
+        Ok(app)
+    }
+
+    fn selected(&self) -> Option<&Row> {
+        self.rows.get(self.list.selected()?)
+    }
+
+    /// List the photos matching `q`, or say what's wrong with it.
+    fn search(&mut self, conn: &Connection, q: &str) -> Result<(), Error> {
+        let terms = match query::parse(q) {
+            Ok(terms) => terms,
+            Err(e) => {
+                self.message = Some(e);
+                return Ok(());
+            }
+        };
+        let (clause, params) = crate::filter_clause(&query::filter(&terms));
+        self.rows = conn
+            .prepare(&format!("SELECT path, file_name, {}, version FROM images{} ORDER BY path LIMIT {}", tags::KEYWORDS, clause, MAX_ROWS + 1))?
+            .query_map(params_from_iter(params), |row| {
+                Ok(Row { path: row.get(0)?, file_name: row.get(1)?, keywords: row.get(2)?, version: row.get(3)? })
+            })?
+            .collect::<Result<_, _>>()?;
+        self.message = match self.rows.len() {
+            0 => Some(String::from("No photos match")),
+            n if n > MAX_ROWS => {
+                self.rows.truncate(MAX_ROWS);
+                Some(format!("Showing the first {}; a query narrows them down", MAX_ROWS))
+            }
+            _ => None,
+        };
+        self.query = q.to_string();
+        self.select(conn, Some(0))
+    }
+
+    fn select(&mut self, conn: &Connection, index: Option<usize>) -> Result<(), Error> {
+        let index = index.filter(|_| !self.rows.is_empty()).map(|index| index.min(self.rows.len() - 1));
+        self.list.select(index);
+        self.details = match self.selected() {
+            Some(row) => details(conn, &row.path)?,
+            None => Vec::new(),
+        };
+        Ok(())
+    }
+
+    fn handle(&mut self, conn: &Connection, key: KeyEvent) -> Result<(), Error> {
+        if key.kind != KeyEventKind::Press {
+            return Ok(());
+        }
+        match self.mode {
+            Mode::List => self.handle_list(conn, key),
+            Mode::Search | Mode::EditKeywords => self.handle_input(conn, key),
+        }
+    }
+
+    fn handle_list(&mut self, conn: &Connection, key: KeyEvent) -> Result<(), Error> {
+        let selected = self.list.selected().unwrap_or_default();
+        let last = self.rows.len().saturating_sub(1);
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Down | KeyCode::Char('j') => self.select(conn, Some(selected + 1))?,
+            KeyCode::Up | KeyCode::Char('k') => self.select(conn, Some(selected.saturating_sub(1)))?,
+            KeyCode::PageDown => self.select(conn, Some(selected + 20))?,
+            KeyCode::PageUp => self.select(conn, Some(selected.saturating_sub(20)))?,
+            KeyCode::Home | KeyCode::Char('g') => self.select(conn, Some(0))?,
+            KeyCode::End | KeyCode::Char('G') => self.select(conn, Some(last))?,
+            KeyCode::Char('/') => {
+                self.input = self.query.clone();
+                self.mode = Mode::Search;
+            }
+            KeyCode::Char('e') => {
+                if let Some(keywords) = self.selected().map(|row| row.keywords.clone().unwrap_or_default()) {
+                    self.input = keywords;
+                    self.mode = Mode::EditKeywords;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_input(&mut self, conn: &Connection, key: KeyEvent) -> Result<(), Error> {
+        match key.code {
+            KeyCode::Esc => self.mode = Mode::List,
+            KeyCode::Backspace => { self.input.pop(); }
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Enter => {
+                let input = std::mem::take(&mut self.input);
+                let mode = std::mem::replace(&mut self.mode, Mode::List);
+                match mode {
+                    Mode::Search => self.search(conn, input.trim())?,
+                    Mode::EditKeywords => self.save_keywords(conn, input.trim())?,
+                    Mode::List => {}
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Replace the selected photo's keywords, as `correct` would.
+    fn save_keywords(&mut self, conn: &Connection, keywords: &str) -> Result<(), Error> {
+        let Some(index) = self.list.selected() else { return Ok(()) };
+        let row = &mut self.rows[index];
+        let editor = Editor { source: "browse", seen: Some(row.version) };
+        let edited = corrections::correct(conn, &row.path, None, Some(keywords), &editor)?;
+        row.keywords = Some(keywords.to_string());
+        row.version = edited.version;
+        self.message = Some(match edited.conflict {
+            true => format!("Saved the keywords of {}, over an edit made meanwhile (see `conflicts`)", row.file_name),
+            false => format!("Saved the keywords of {}", row.file_name),
+        });
+        self.select(conn, Some(index))
+    }
+}
+
+/// What the catalog knows of the photo at `path`, labeled for display.
+fn details(conn: &Connection, path: &str) -> Result<Vec<(&'static str, String)>, Error> {
+    let row = conn.query_row(
+        &format!(
+            "SELECT creation_date, city, region, country, camera_make, camera_model, lens_model, focal_length, aperture,
+                    exposure_time, iso, width, height, format, duration_secs, description, {}
+             FROM images WHERE path = ?1",
+            tags::KEYWORDS,
+        ),
+        [path],
+        |row| {
+            let text = |i: usize| -> rusqlite::Result<Option<String>> { row.get(i) };
+            let number = |i: usize| -> rusqlite::Result<Option<f64>> { row.get(i) };
+            let joined = |parts: Vec<Option<String>>, separator: &str| {
+                Some(parts.into_iter().flatten().collect::<Vec<_>>().join(separator)).filter(|joined| !joined.is_empty())
+            };
+            let exposure = number(9)?.map(|secs| match secs < 1.0 {
+                true => format!("1/{} s", (1.0 / secs).round()),
+                false => format!("{} s", secs),
+            });
+            let size = row.get::<_, Option<u32>>(11)?.zip(row.get::<_, Option<u32>>(12)?).map(|(w, h)| format!("{} × {}", w, h));
+            Ok(vec![
+                ("Taken", text(0)?),
+                ("Place", joined(vec![text(1)?, text(2)?, text(3)?], ", ")),
+                ("Camera", joined(vec![text(4)?, text(5)?], " ")),
+                ("Lens", text(6)?),
+                ("Focal length", number(7)?.map(|mm| format!("{} mm", mm))),
+                ("Aperture", number(8)?.map(|f| format!("f/{}", f))),
+                ("Exposure", exposure),
+                ("ISO", row.get::<_, Option<u32>>(10)?.map(|iso| iso.to_string())),
+                ("Size", size),
+                ("Format", text(13)?),
+                ("Length", number(14)?.map(|secs| format!("{:.0} s", secs))),
+                ("Description", text(15)?),
+                ("Keywords", text(16)?),
+                ("File", Some(path.to_string())),
+            ])
+        },
+    ).optional()?;
+    Ok(row.unwrap_or_default().into_iter().filter_map(|(label, value)| Some((label, value?))).collect())
+}
+
+/// The list on the left, the selected photo's details (and room for its
+/// preview) on the right, and the input or message line at the bottom.
+/// Returns where the preview goes.
+fn draw(frame: &mut Frame, app: &mut App, preview: bool) -> Rect {
+    let [main, bottom] = Layout::vertical([Constraint::Min(3), Constraint::Length(1)]).areas(frame.area());
+    let [left, right] = Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(main);
+
+    let title = match app.query.is_empty() {
+        true => format!(" {} photos ", app.rows.len()),
+        false => format!(" {} photos: {} ", app.rows.len(), app.query),
+    };
+    let items: Vec<ListItem> = app.rows.iter().map(|row| ListItem::new(row.file_name.as_str())).collect();
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(list, left, &mut app.list);
+
+    let block = Block::default().borders(Borders::ALL).title(" Details ");
+    let inner = block.inner(right);
+    frame.render_widget(block, right);
+    let [image, text] = match preview {
+        true => Layout::vertical([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(inner),
+        false => [Rect::default(), inner],
+    };
+    let lines: Vec<Line> = app.details.iter()
+        .map(|(label, value)| Line::from(vec![Span::styled(format!("{}: ", label), Style::default().add_modifier(Modifier::BOLD)), Span::raw(value.as_str())]))
+        .collect();
+    frame.render_widget(Paragraph::new(lines).wrap(Wrap { trim: false }), text);
+
+    let status = match app.mode {
+        Mode::Search => format!("Query: {}▏", app.input),
+        Mode::EditKeywords => format!("Keywords: {}▏", app.input),
+        Mode::List => app.message.clone().unwrap_or_else(|| String::from("/ search  e edit keywords  j/k move  q quit")),
+    };
+    frame.render_widget(Paragraph::new(status), bottom);
+    image
+}
+
+/// Escapes showing `png` in `columns` by `rows` cells at the cursor, by the
+/// kitty graphics protocol.
+fn kitty_image(png: &[u8], columns: u16, rows: u16) -> String {
+    let data = STANDARD.encode(png);
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(KITTY_CHUNK).collect();
+    let mut escapes = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        let control = match i {
+            0 => format!("a=T,f=100,q=2,c={},r={},m={}", columns, rows, more),
+            _ => format!("m={}", more),
+        };
+        escapes.push_str(&format!("\x1b_G{};{}\x1b\\", control, String::from_utf8_lossy(chunk)));
+    }
+    escapes
+}
+
+/// Removes every image placed by `kitty_image`.
+const KITTY_CLEAR: &str = "\x1b_Ga=d,d=A,q=2\x1b\\";
+
+/// The photo at `path` as a PNG no bigger than `PREVIEW_EDGE`.
+fn preview_png(path: &Path, config: &Config) -> Result<Vec<u8>, Error> {
+    let jpeg = jobs::derivative(path, config, PREVIEW_EDGE)?;
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::load_from_memory(&jpeg)?.write_to(&mut png, image::ImageFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+/// Browse the catalog until the user quits.
+pub fn browse(conn: &Connection, config: &Config, preview: bool) -> Result<(), Error> {
+    let mut app = App::new(conn)?;
+    let mut terminal = ratatui::init();
+    let result = (|| -> Result<(), Error> {
+        // What's on screen, to redraw the preview only when it changes
+        let mut shown: Option<(String, Rect)> = None;
+        while !app.quit {
+            let mut area = Rect::default();
+            terminal.draw(|frame| area = draw(frame, &mut app, preview))?;
+            let current = app.selected().map(|row| (row.path.clone(), area));
+            if preview && current != shown {
+                let mut out = std::io::stdout();
+                write!(out, "{}", KITTY_CLEAR)?;
+                if let Some((path, area)) = &current {
+                    if let Ok(png) = preview_png(Path::new(path), config) {
+                        write!(out, "\x1b[{};{}H{}", area.y + 1, area.x + 1, kitty_image(&png, area.width, area.height))?;
+                    }
+                }
+                out.flush()?;
+                shown = current;
+            }
+            if let Event::Key(key) = event::read()? {
+                app.handle(conn, key)?;
+            }
+        }
+        Ok(())
+    })();
+    if preview {
+        print!("{}", KITTY_CLEAR);
+    }
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    fn press(app: &mut App, conn: &Connection, keys: &str) -> Result<(), Error> {
+        for c in keys.chars() {
+            let code = match c {
+                '\n' => KeyCode::Enter,
+                '\x1b' => KeyCode::Esc,
+                '\x08' => KeyCode::Backspace,
+                c => KeyCode::Char(c),
+            };
+            app.handle(conn, KeyEvent::from(code))?;
+        }
+        Ok(())
+    }
+
+    fn screen(app: &mut App) -> Result<String, Error> {
+        let mut terminal = Terminal::new(TestBackend::new(100, 20))?;
+        terminal.draw(|frame| { draw(frame, app, false); })?;
+        let buffer = terminal.backend().buffer();
+        Ok(buffer.content().chunks(buffer.area.width as usize)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    #[test]
+    fn test_browse() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, camera_make, camera_model, aperture, description) VALUES
+                ('/photos/beach.jpg', 'beach.jpg', 1, 'FUJIFILM', 'X100V', 2.0, 'Waves on the sand'),
+                ('/photos/cake.jpg', 'cake.jpg', 1, 'Apple', 'iPhone 15', NULL, NULL)",
+            [],
+        )?;
+        crate::test_support::set_keywords(&conn, &[("/photos/beach.jpg", "beach, sea"), ("/photos/cake.jpg", "cake")]);
+        let mut app = App::new(&conn)?;
+        let text = screen(&mut app)?;
+        assert!(text.contains("2 photos"));
+        assert!(text.contains("Camera: FUJIFILM X100V"));
+        assert!(text.contains("Aperture: f/2"));
+
+        press(&mut app, &conn, "j")?;
+        assert!(screen(&mut app)?.contains("Camera: Apple iPhone 15"));
+
+        press(&mut app, &conn, "/camera:X100V\n")?;
+        assert_eq!(app.rows.len(), 1);
+        assert!(screen(&mut app)?.contains("1 photos: camera:X100V"));
+        // The box starts with the query, to refine it
+        press(&mut app, &conn, &format!("/{}colour:red\n", "\x08".repeat("camera:X100V".len())))?;
+        assert!(app.message.as_deref().is_some_and(|message| message.contains("unknown query key")));
+
+        // Edited in place, and kept as a correction
+        press(&mut app, &conn, "e\x08\x08\x08\x08\x08, waves\n")?;
+        let keywords: String = conn.query_row(&format!("SELECT {} FROM images WHERE path = '/photos/beach.jpg'", tags::KEYWORDS), [], |row| row.get(0))?;
+        assert_eq!(keywords, "beach, waves");
+        assert!(screen(&mut app)?.contains("Keywords: beach, waves"));
+        let corrections: i64 = conn.query_row("SELECT COUNT(*) FROM corrections", [], |row| row.get(0))?;
+        assert_eq!(corrections, 1);
+
+        press(&mut app, &conn, "e\x1bq")?;
+        assert!(app.quit);
+        Ok(())
+    }
+
+    #[test]
+    fn test_kitty_image() {
+        let escapes = kitty_image(&[0; 5000], 40, 12);
+        let parts: Vec<&str> = escapes.split("\x1b\\").filter(|part| !part.is_empty()).collect();
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].starts_with("\x1b_Ga=T,f=100,q=2,c=40,r=12,m=1;"));
+        assert!(parts[1].starts_with("\x1b_Gm=0;"));
+    }
+}
@@ -0,0 +1,94 @@
+use exif::{Exif, In, Tag, Value};
+
+/// Camera body, lens and exposure settings from EXIF.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CameraInfo {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    /// Body serial number
+    pub serial: Option<String>,
+    pub iso: Option<u32>,
+    /// f-number, e.g. 1.8 for f/1.8
+    pub aperture: Option<f64>,
+    /// Exposure time in seconds
+    pub exposure_time: Option<f64>,
+    /// Focal length in millimetres
+    pub focal_length: Option<f64>,
+    pub flash_fired: Option<bool>,
+}
+
+impl CameraInfo {
+    pub fn from_exif(exif: &Exif) -> CameraInfo {
+        let field = |tag| exif.get_field(tag, In::PRIMARY).map(|f| &f.value);
+        let text = |tag| field(tag).and_then(ascii_value);
+        let number = |tag| field(tag).and_then(rational_value);
+        let integer = |tag| field(tag).and_then(|v| v.get_uint(0));
+
+        CameraInfo {
+            make: text(Tag::Make),
+            model: text(Tag::Model),
+            lens: text(Tag::LensModel),
+            serial: text(Tag::BodySerialNumber),
+            iso: integer(Tag::PhotographicSensitivity),
+            aperture: number(Tag::FNumber),
+            exposure_time: number(Tag::ExposureTime),
+            focal_length: number(Tag::FocalLength),
+            // Bit 0 of the Flash tag says whether it fired
+            flash_fired: integer(Tag::Flash).map(|flash| flash & 1 == 1),
+        }
+    }
+}
+
+fn ascii_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Ascii(v) => {
+            let text = String::from_utf8_lossy(v.first()?);
+            let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!text.is_empty()).then(|| text.to_string())
+        }
+        _ => None,
+    }
+}
+
+fn rational_value(value: &Value) -> Option<f64> {
+    let number = match value {
+        Value::Rational(v) => v.first()?.to_f64(),
+        Value::SRational(v) => v.first()?.to_f64(),
+        _ => return None,
+    };
+    number.is_finite().then_some(number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_camera_info_from_exif() {
+        let tiff = exif_tiff(&[
+            field(Tag::Make, ascii("FUJIFILM")),
+            field(Tag::Model, ascii("X-T4 ")),
+            field(Tag::LensModel, ascii("XF35mmF1.4 R")),
+            field(Tag::BodySerialNumber, ascii("5CA12345")),
+            field(Tag::PhotographicSensitivity, Value::Short(vec![400])),
+            field(Tag::FNumber, rational(18, 10)),
+            field(Tag::ExposureTime, rational(1, 250)),
+            field(Tag::FocalLength, rational(35, 1)),
+            field(Tag::Flash, Value::Short(vec![0x10])),
+        ]);
+        let exif = exif::Reader::new().read_raw(tiff).unwrap();
+
+        let camera = CameraInfo::from_exif(&exif);
+        assert_eq!(camera.make.as_deref(), Some("FUJIFILM"));
+        assert_eq!(camera.model.as_deref(), Some("X-T4"));
+        assert_eq!(camera.lens.as_deref(), Some("XF35mmF1.4 R"));
+        assert_eq!(camera.serial.as_deref(), Some("5CA12345"));
+        assert_eq!(camera.iso, Some(400));
+        assert_eq!(camera.aperture, Some(1.8));
+        assert_eq!(camera.exposure_time, Some(0.004));
+        assert_eq!(camera.focal_length, Some(35.0));
+        assert_eq!(camera.flash_fired, Some(false));
+    }
+}
@@ -0,0 +1,278 @@
+//! Cooperative cancellation. One token is shared by everything working on
+//! an operation; Ctrl-C, `cancel`/the API and time limits all trip it, and
+//! the walker, the workers and in-flight analyzer requests stop at the next
+//! safe point, so whatever was finished is saved. Ctrl-C (or SIGTERM, or
+//! SIGHUP) lets the images already being analyzed finish too, and a second
+//! one quits at once, though not in the middle of writing to the catalog.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use anyhow::Error;
+use tokio::sync::Notify;
+use crate::error::{CliError, ErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    /// Ctrl-C
+    Interrupted,
+    /// `cancel` or the API
+    Requested,
+    /// The time limit ran out
+    TimeLimit,
+    /// Background work stepping aside for someone using the machine
+    Yield,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Reason::Interrupted => "interrupted",
+            Reason::Requested => "cancelled on request",
+            Reason::TimeLimit => "time limit reached",
+            Reason::Yield => "yielded to user activity",
+        })
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    reason: Mutex<Option<Reason>>,
+    deadline: Mutex<Option<Instant>>,
+    notify: Notify,
+    /// Tripping the parent trips this token too, but not the other way round
+    parent: Option<CancelToken>,
+    /// Whether an interruption of the parent is left for the work to notice
+    /// by itself once it's done
+    finishes: bool,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// A token for part of the work, which can be cancelled on its own and
+    /// is cancelled along with this one.
+    pub fn child(&self) -> CancelToken {
+        CancelToken { inner: Arc::new(Inner { parent: Some(self.clone()), ..Inner::default() }) }
+    }
+
+    /// A token for an item already under way: cancelled along with this
+    /// one, except by an interruption, which lets it finish.
+    pub fn in_flight(&self) -> CancelToken {
+        CancelToken { inner: Arc::new(Inner { parent: Some(self.clone()), finishes: true, ..Inner::default() }) }
+    }
+
+    /// Cancel once `limit` has passed from now.
+    pub fn set_time_limit(&self, limit: Duration) {
+        *self.inner.deadline.lock().unwrap() = Some(Instant::now() + limit);
+    }
+
+    /// Trip the token. The first reason given is the one reported.
+    pub fn cancel(&self, reason: Reason) {
+        self.inner.reason.lock().unwrap().get_or_insert(reason);
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        if self.inner.cancelled.load(Ordering::SeqCst) {
+            return true;
+        }
+        let deadline = *self.inner.deadline.lock().unwrap();
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            self.cancel(Reason::TimeLimit);
+            return true;
+        }
+        match self.inner.parent.as_ref().and_then(CancelToken::reason) {
+            Some(Reason::Interrupted) if self.inner.finishes => false,
+            Some(reason) => {
+                self.cancel(reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn reason(&self) -> Option<Reason> {
+        if self.is_cancelled() { *self.inner.reason.lock().unwrap() } else { None }
+    }
+
+    /// A safe point: fail with a `Cancelled` error if the token has tripped.
+    pub fn check(&self) -> Result<(), Error> {
+        match self.reason() {
+            Some(reason) => Err(CliError::new(ErrorKind::Cancelled, reason.to_string()).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Resolves once the token trips, for racing against async work.
+    pub async fn cancelled(&self) {
+        let parent = async {
+            match &self.inner.parent {
+                Some(parent) => Box::pin(parent.cancelled()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(parent);
+        // Set once the parent trips without tripping this token
+        let mut spared = false;
+        loop {
+            let notified = self.inner.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.is_cancelled() {
+                return;
+            }
+            let deadline = *self.inner.deadline.lock().unwrap();
+            let expired = async move {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = notified => {}
+                _ = expired => {}
+                _ = &mut parent, if !spared => spared = !self.is_cancelled(),
+            }
+        }
+    }
+
+    /// Run `future`, giving up with a `Cancelled` error if the token trips
+    /// first.
+    pub async fn run<T>(&self, future: impl std::future::Future<Output = Result<T, Error>>) -> Result<T, Error> {
+        tokio::select! {
+            result = future => result,
+            _ = self.cancelled() => Err(self.check().unwrap_err()),
+        }
+    }
+}
+
+/// Writes to the catalog under way, which a second Ctrl-C waits for
+static WRITING: AtomicUsize = AtomicUsize::new(0);
+/// Whether a second Ctrl-C asked to quit
+static QUITTING: AtomicBool = AtomicBool::new(false);
+
+/// The token Ctrl-C trips, the parent of each operation's.
+pub fn shutdown() -> &'static CancelToken {
+    static SHUTDOWN: OnceLock<CancelToken> = OnceLock::new();
+    SHUTDOWN.get_or_init(CancelToken::new)
+}
+
+/// Trip `shutdown()` on Ctrl-C, SIGTERM or SIGHUP, for the whole process;
+/// operations stop at their next safe point. A second one quits as soon
+/// as no write to the catalog is under way, for when the safe point is too
+/// far away.
+pub fn trap_signals() -> Result<(), Error> {
+    ctrlc::set_handler(|| {
+        if !shutdown().is_cancelled() {
+            eprintln!("Stopping once the work in progress is saved (Ctrl-C again to quit now)");
+            shutdown().cancel(Reason::Interrupted);
+            return;
+        }
+        QUITTING.store(true, Ordering::SeqCst);
+        if WRITING.load(Ordering::SeqCst) == 0 {
+            std::process::exit(130);
+        }
+    })?;
+    Ok(())
+}
+
+/// Held while writing to the catalog, so quitting waits until it's done.
+pub struct Writing(());
+
+pub fn writing() -> Writing {
+    WRITING.fetch_add(1, Ordering::SeqCst);
+    Writing(())
+}
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        if WRITING.fetch_sub(1, Ordering::SeqCst) == 1 && QUITTING.load(Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error;
+
+    #[test]
+    fn test_cancel() {
+        let token = CancelToken::new();
+        assert!(token.check().is_ok());
+        token.clone().cancel(Reason::Requested);
+        token.cancel(Reason::Interrupted);
+        assert_eq!(token.reason(), Some(Reason::Requested));
+        let error = token.check().unwrap_err();
+        assert_eq!(error::classify(&error), ErrorKind::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_time_limit_interrupts_work() {
+        let token = CancelToken::new();
+        token.set_time_limit(Duration::from_millis(20));
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            Ok(())
+        };
+        let started = Instant::now();
+        assert!(token.run(slow).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(token.reason(), Some(Reason::TimeLimit));
+    }
+
+    #[tokio::test]
+    async fn test_child() {
+        let parent = CancelToken::new();
+        let child = parent.child();
+        child.cancel(Reason::Yield);
+        assert!(!parent.is_cancelled());
+
+        let child = parent.child();
+        let waiting = tokio::spawn({
+            let child = child.clone();
+            async move { child.cancelled().await }
+        });
+        parent.cancel(Reason::Interrupted);
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert_eq!(child.reason(), Some(Reason::Interrupted));
+    }
+
+    #[tokio::test]
+    async fn test_in_flight() {
+        // An interruption waits for work under way to finish, anything else
+        // doesn't
+        let parent = CancelToken::new();
+        let operation = parent.child();
+        let item = operation.in_flight();
+        parent.cancel(Reason::Interrupted);
+        assert!(operation.is_cancelled() && !item.is_cancelled());
+        let slow = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(())
+        };
+        assert!(item.run(slow).await.is_ok());
+
+        let operation = CancelToken::new();
+        let item = operation.in_flight();
+        operation.set_time_limit(Duration::from_millis(20));
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            Ok(())
+        };
+        assert!(tokio::time::timeout(Duration::from_secs(5), item.run(slow)).await.unwrap().is_err());
+        assert_eq!(item.reason(), Some(Reason::TimeLimit));
+    }
+}
@@ -0,0 +1,277 @@
+//! Caption templates: how the caption written out with an image is put
+//! together from catalog fields, e.g. `"{description}[ — {date_taken}][ at
+//! {place}]"`. A section in square brackets is left out when any field in
+//! it is empty, so missing dates or places don't leave dangling words.
+//! `{{`, `}}`, `[[` and `]]` stand for the characters themselves.
+
+use anyhow::Error;
+use rusqlite::{Connection, OptionalExtension};
+use serde::Deserialize;
+use crate::tags;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Description,
+    Keywords,
+    DateTaken,
+    TimeTaken,
+    Place,
+    City,
+    Region,
+    Country,
+    Camera,
+    Lens,
+    FileName,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("description", Field::Description),
+    ("keywords", Field::Keywords),
+    ("date_taken", Field::DateTaken),
+    ("time_taken", Field::TimeTaken),
+    ("place", Field::Place),
+    ("city", Field::City),
+    ("region", Field::Region),
+    ("country", Field::Country),
+    ("camera", Field::Camera),
+    ("lens", Field::Lens),
+    ("file_name", Field::FileName),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Field(Field),
+    /// Dropped unless every field in it has a value
+    Optional(Vec<Part>),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        // The enclosing parts while inside a [section]
+        let mut outer: Option<Vec<Part>> = None;
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            if matches!(c, '{' | '}' | '[' | ']') && chars.peek() == Some(&c) {
+                chars.next();
+                text.push(c);
+                continue;
+            }
+            match c {
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed '{{' in caption template {:?}", s)),
+                        }
+                    }
+                    let field = FIELDS.iter()
+                        .find(|(known, _)| *known == name.trim())
+                        .map(|(_, field)| *field)
+                        .ok_or_else(|| {
+                            let known: Vec<&str> = FIELDS.iter().map(|(name, _)| *name).collect();
+                            format!("unknown caption field {{{}}}; known fields are {}", name, known.join(", "))
+                        })?;
+                    flush(&mut text, &mut parts);
+                    parts.push(Part::Field(field));
+                }
+                '[' if outer.is_none() => {
+                    flush(&mut text, &mut parts);
+                    outer = Some(std::mem::take(&mut parts));
+                }
+                ']' => {
+                    let Some(enclosing) = outer.take() else { return Err(format!("unmatched ']' in caption template {:?}", s)) };
+                    flush(&mut text, &mut parts);
+                    let section = std::mem::replace(&mut parts, enclosing);
+                    parts.push(Part::Optional(section));
+                }
+                '[' => return Err(format!("nested '[' in caption template {:?}", s)),
+                '}' => return Err(format!("unmatched '}}' in caption template {:?}", s)),
+                c => text.push(c),
+            }
+        }
+        if outer.is_some() {
+            return Err(format!("unclosed '[' in caption template {:?}", s));
+        }
+        flush(&mut text, &mut parts);
+        Ok(Template { parts })
+    }
+}
+
+fn flush(text: &mut String, parts: &mut Vec<Part>) {
+    if !text.is_empty() {
+        parts.push(Part::Text(std::mem::take(text)));
+    }
+}
+
+/// The catalog fields of one image that templates can use.
+#[derive(Debug, Default)]
+pub struct Values {
+    pub description: Option<String>,
+    pub keywords: Option<String>,
+    /// As EXIF has it, "2024-05-01 18:30:00"
+    pub creation_date: Option<String>,
+    pub city: Option<String>,
+    pub region: Option<String>,
+    pub country: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens: Option<String>,
+    pub file_name: Option<String>,
+}
+
+impl Values {
+    pub fn load(conn: &Connection, id: i64) -> Result<Option<Values>, Error> {
+        let values = conn.query_row(
+            &format!(
+                "SELECT description, {}, creation_date, city, region, country,
+                        camera_make, camera_model, lens_model, file_name
+                 FROM images WHERE id = ?1",
+                tags::KEYWORDS,
+            ),
+            [id],
+            |row| Ok(Values {
+                description: row.get(0)?,
+                keywords: row.get(1)?,
+                creation_date: row.get(2)?,
+                city: row.get(3)?,
+                region: row.get(4)?,
+                country: row.get(5)?,
+                camera_make: row.get(6)?,
+                camera_model: row.get(7)?,
+                lens: row.get(8)?,
+                file_name: row.get(9)?,
+            }),
+        ).optional()?;
+        Ok(values)
+    }
+
+    fn get(&self, field: Field) -> Option<String> {
+        let value = match field {
+            Field::Description => self.description.clone(),
+            Field::Keywords => self.keywords.clone(),
+            Field::DateTaken => self.creation_date.as_ref().map(|d| d.split(' ').next().unwrap_or(d).to_string()),
+            Field::TimeTaken => self.creation_date.as_ref()
+                .and_then(|d| d.split(' ').nth(1))
+                .map(|time| time.get(..5).unwrap_or(time).to_string()),
+            Field::Place => {
+                let parts: Vec<&str> = [self.city.as_ref().or(self.region.as_ref()), self.country.as_ref()]
+                    .into_iter()
+                    .flatten()
+                    .map(String::as_str)
+                    .collect();
+                (!parts.is_empty()).then(|| parts.join(", "))
+            }
+            Field::City => self.city.clone(),
+            Field::Region => self.region.clone(),
+            Field::Country => self.country.clone(),
+            Field::Camera => match (&self.camera_make, &self.camera_model) {
+                // Models usually repeat the brand: "Canon" + "Canon EOS R5"
+                (Some(make), Some(model)) if starts_with_brand(model, make) => Some(model.clone()),
+                (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+                (make, model) => model.clone().or_else(|| make.clone()),
+            },
+            Field::Lens => self.lens.clone(),
+            Field::FileName => self.file_name.clone(),
+        };
+        value.filter(|v| !v.trim().is_empty())
+    }
+}
+
+/// Whether `model` begins with the first word of `make`, as in "NIKON Z 6"
+/// from "NIKON CORPORATION".
+fn starts_with_brand(model: &str, make: &str) -> bool {
+    let brand = make.split_whitespace().next().unwrap_or_default();
+    model.to_lowercase().starts_with(&brand.to_lowercase())
+}
+
+impl Template {
+    /// The caption, or `None` if it comes out empty.
+    pub fn render(&self, values: &Values) -> Option<String> {
+        let mut caption = String::new();
+        render_parts(&self.parts, values, &mut caption);
+        let caption = caption.trim();
+        (!caption.is_empty()).then(|| caption.to_string())
+    }
+}
+
+/// Append `parts` to `out`; false if any field had no value.
+fn render_parts(parts: &[Part], values: &Values, out: &mut String) -> bool {
+    let mut complete = true;
+    for part in parts {
+        match part {
+            Part::Text(text) => out.push_str(text),
+            Part::Field(field) => match values.get(*field) {
+                Some(value) => out.push_str(&value),
+                None => complete = false,
+            },
+            Part::Optional(section) => {
+                let mut rendered = String::new();
+                if render_parts(section, values, &mut rendered) {
+                    out.push_str(&rendered);
+                }
+            }
+        }
+    }
+    complete
+}
+
+/// The caption to write out for image `id`: `description` itself without a
+/// template, the rendered template with one.
+pub fn caption(conn: &Connection, id: i64, template: Option<&Template>, description: Option<String>) -> Result<Option<String>, Error> {
+    let Some(template) = template else { return Ok(description) };
+    Ok(Values::load(conn, id)?.and_then(|values| template.render(&values)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(s: &str) -> Template {
+        Template::try_from(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_render() {
+        let values = Values {
+            description: Some(String::from("A yellow tram")),
+            creation_date: Some(String::from("2024-05-01 18:30:00")),
+            city: Some(String::from("Lisbon")),
+            country: Some(String::from("Portugal")),
+            camera_make: Some(String::from("FUJIFILM")),
+            camera_model: Some(String::from("X100V")),
+            ..Values::default()
+        };
+        let full = template("{description} — {date_taken}[ at {place}][ ({lens})]");
+        assert_eq!(full.render(&values).as_deref(), Some("A yellow tram — 2024-05-01 at Lisbon, Portugal"));
+        assert_eq!(template("{time_taken}, {camera}").render(&values).as_deref(), Some("18:30, FUJIFILM X100V"));
+
+        let nikon = Values {
+            camera_make: Some(String::from("NIKON CORPORATION")),
+            camera_model: Some(String::from("NIKON Z 6")),
+            ..Values::default()
+        };
+        assert_eq!(template("{camera}").render(&nikon).as_deref(), Some("NIKON Z 6"));
+        assert_eq!(template("[{description}]").render(&nikon), None);
+        assert_eq!(template("{{{file_name}}} [[draft]]").render(&Values::default()).as_deref(), Some("{} [draft]"));
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        for bad in ["{descripton}", "[{place}", "{place}]", "[a [b]]", "{place", "}"] {
+            assert!(Template::try_from(bad.to_string()).is_err(), "{}", bad);
+        }
+    }
+}
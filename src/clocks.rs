@@ -0,0 +1,297 @@
+//! `reconcile-clocks`: when several cameras covered one event, working out
+//! how far each one's clock was off from a trusted one, so the merged
+//! timeline interleaves the way things happened. A device's offset comes
+//! from anchors, pairs of photos the user says were taken at the same
+//! moment, or else from photos taken at the same spot as the reference
+//! device's. Corrections are applied as ordinary date shifts, so `fix-dates
+//! --undo` reverses them.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use anyhow::Error;
+use chrono::NaiveDateTime;
+use rusqlite::Connection;
+use crate::dates::{self, Change};
+use crate::error::{CliError, ErrorKind};
+use crate::geocode;
+use crate::SearchFilter;
+
+/// Photos this close together were taken at the same spot
+const SAME_SPOT_METERS: f64 = 50.0;
+/// Clock offsets beyond this aren't believed
+const MAX_OFFSET_SECS: i64 = 48 * 3600;
+/// Offsets within this of each other count as the same
+const OFFSET_TOLERANCE_SECS: i64 = 15 * 60;
+/// Same-spot pairs needed before the offset they suggest is trusted
+const MIN_GPS_MATCHES: usize = 3;
+
+struct Photo {
+    id: i64,
+    path: String,
+    date: String,
+    time: NaiveDateTime,
+    gps: Option<(f64, f64)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Evidence {
+    /// The device everything else is set to
+    Reference,
+    /// From this many anchor pairs
+    Anchors(usize),
+    /// From this many photos taken where the reference device also was
+    Gps(usize),
+    Unknown,
+}
+
+pub struct Device {
+    /// "Make Model"
+    pub name: String,
+    /// Seconds to add to this device's times; `None` if there's no telling
+    pub offset: Option<i64>,
+    pub evidence: Evidence,
+    photos: Vec<Photo>,
+}
+
+impl Device {
+    pub fn photo_count(&self) -> usize {
+        self.photos.len()
+    }
+
+    /// The date changes that apply this device's offset.
+    pub fn changes(&self) -> Vec<Change> {
+        let Some(offset) = self.offset.filter(|&offset| offset != 0) else { return Vec::new() };
+        self.photos.iter()
+            .filter_map(|photo| dates::change(photo.id, photo.path.clone(), photo.date.clone(), offset))
+            .collect()
+    }
+}
+
+/// An anchor: "reference.jpg=other.jpg", by path or file name.
+pub fn parse_anchor(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((a, b)) if !a.trim().is_empty() && !b.trim().is_empty() => Ok((a.trim().to_string(), b.trim().to_string())),
+        _ => Err(format!("expected two photos as a=b, got {:?}", s)),
+    }
+}
+
+/// Each device's clock offset, for the dated photos matching `filter`.
+/// The reference device is `reference` if given, otherwise the one with the
+/// most GPS-tagged photos, phones keeping good time.
+pub fn estimate(conn: &Connection, filter: &SearchFilter, reference: Option<&str>, anchors: &[(String, String)]) -> Result<Vec<Device>, Error> {
+    let by_device = load(conn, filter)?;
+    if by_device.len() < 2 {
+        return Err(CliError::new(ErrorKind::NothingToDo, "the query matches photos from fewer than two devices").into());
+    }
+    let reference = match reference {
+        Some(name) => by_device.keys()
+            .find(|device| device.eq_ignore_ascii_case(name))
+            .cloned()
+            .ok_or_else(|| {
+                let known: Vec<&str> = by_device.keys().map(String::as_str).collect();
+                CliError::new(ErrorKind::NothingToDo, format!("no photos from {} match; the devices are {}", name, known.join(", ")))
+            })?,
+        None => by_device.iter()
+            .max_by_key(|(_, photos)| photos.iter().filter(|photo| photo.gps.is_some()).count())
+            .map(|(device, _)| device.clone())
+            .expect("at least two devices"),
+    };
+
+    // Anchors first, then same-spot photos against the reference for the
+    // devices anchors don't reach; anchors can then reach further from those
+    let constraints = anchor_constraints(&by_device, anchors)?;
+    let mut known = BTreeMap::from([(reference.clone(), (0, Evidence::Reference))]);
+    propagate(&constraints, &mut known);
+    let reference_photos = &by_device[&reference];
+    for (name, photos) in &by_device {
+        if !known.contains_key(name) {
+            if let Some((offset, count)) = gps_offset(reference_photos, photos) {
+                known.insert(name.clone(), (offset, Evidence::Gps(count)));
+            }
+        }
+    }
+    propagate(&constraints, &mut known);
+
+    let mut devices: Vec<Device> = by_device.into_iter()
+        .map(|(name, photos)| {
+            let (offset, evidence) = match known.remove(&name) {
+                Some((offset, evidence)) => (Some(offset), evidence),
+                None => (None, Evidence::Unknown),
+            };
+            Device { name, offset, evidence, photos }
+        })
+        .collect();
+    devices.sort_by_key(|device| device.evidence != Evidence::Reference);
+    Ok(devices)
+}
+
+/// Dated photos with a known camera, by device.
+fn load(conn: &Connection, filter: &SearchFilter) -> Result<BTreeMap<String, Vec<Photo>>, Error> {
+    let (clause, params) = crate::filter_clause(filter);
+    let sql = format!(
+        "SELECT id, path, creation_date, latitude, longitude,
+                TRIM(COALESCE(camera_make, '') || ' ' || COALESCE(camera_model, ''))
+         FROM images{}",
+        clause,
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<String>>(2)?,
+            row.get::<_, Option<f64>>(3)?.zip(row.get::<_, Option<f64>>(4)?),
+            row.get::<_, String>(5)?,
+        ))
+    })?.collect::<Result<Vec<_>, _>>()?;
+
+    let mut by_device: BTreeMap<String, Vec<Photo>> = BTreeMap::new();
+    for (id, path, date, gps, device) in rows {
+        let Some(date) = date else { continue };
+        let Ok(time) = NaiveDateTime::parse_from_str(&date, "%Y-%m-%d %H:%M:%S") else { continue };
+        if !device.is_empty() {
+            by_device.entry(device).or_default().push(Photo { id, path, date, time, gps });
+        }
+    }
+    Ok(by_device)
+}
+
+/// For each pair of devices the anchors tie together, the seconds to add
+/// to the second device's times to match the first's, once per anchor.
+fn anchor_constraints(by_device: &BTreeMap<String, Vec<Photo>>, anchors: &[(String, String)]) -> Result<HashMap<(String, String), Vec<i64>>, Error> {
+    let find = |name: &str| {
+        let matches: Vec<(&String, &Photo)> = by_device.iter()
+            .flat_map(|(device, photos)| photos.iter().map(move |photo| (device, photo)))
+            .filter(|(_, photo)| photo.path == name || photo.path.rsplit(['/', '\\']).next() == Some(name))
+            .collect();
+        match matches.as_slice() {
+            [found] => Ok(*found),
+            [] => Err(CliError::new(ErrorKind::NothingToDo, format!("anchor {} isn't among the dated photos the query matches", name))),
+            _ => Err(CliError::new(ErrorKind::NothingToDo, format!("anchor {} is ambiguous; give its full path", name))),
+        }
+    };
+
+    let mut constraints: HashMap<(String, String), Vec<i64>> = HashMap::new();
+    for (a, b) in anchors {
+        let ((device_a, photo_a), (device_b, photo_b)) = (find(a)?, find(b)?);
+        if device_a == device_b {
+            return Err(CliError::new(ErrorKind::NothingToDo, format!("anchors {} and {} are from the same device", a, b)).into());
+        }
+        let offset = (photo_a.time - photo_b.time).num_seconds();
+        constraints.entry((device_a.clone(), device_b.clone())).or_default().push(offset);
+        constraints.entry((device_b.clone(), device_a.clone())).or_default().push(-offset);
+    }
+    Ok(constraints)
+}
+
+/// Extend `known` offsets along the anchors to every device they connect.
+fn propagate(constraints: &HashMap<(String, String), Vec<i64>>, known: &mut BTreeMap<String, (i64, Evidence)>) {
+    let mut queue: VecDeque<(String, i64)> = known.iter().map(|(device, (offset, _))| (device.clone(), *offset)).collect();
+    while let Some((device, offset)) = queue.pop_front() {
+        for ((from, to), values) in constraints {
+            if *from == device && !known.contains_key(to) {
+                let offset = offset + median(values.clone());
+                known.insert(to.clone(), (offset, Evidence::Anchors(values.len())));
+                queue.push_back((to.clone(), offset));
+            }
+        }
+    }
+}
+
+/// The offset most same-spot pairs agree on, and how many do.
+fn gps_offset(reference: &[Photo], photos: &[Photo]) -> Option<(i64, usize)> {
+    let mut candidates: Vec<i64> = Vec::new();
+    for photo in photos {
+        let Some(here) = photo.gps else { continue };
+        for other in reference {
+            let Some(there) = other.gps else { continue };
+            let offset = (other.time - photo.time).num_seconds();
+            if offset.abs() <= MAX_OFFSET_SECS && geocode::distance_km(here.0, here.1, there.0, there.1) * 1000.0 <= SAME_SPOT_METERS {
+                candidates.push(offset);
+            }
+        }
+    }
+    candidates.sort_unstable();
+    // The densest cluster of candidates, then its median
+    let (mut best, mut start) = (0..0, 0);
+    for end in 0..candidates.len() {
+        while candidates[end] - candidates[start] > 2 * OFFSET_TOLERANCE_SECS {
+            start += 1;
+        }
+        if end + 1 - start > best.len() {
+            best = start..end + 1;
+        }
+    }
+    (best.len() >= MIN_GPS_MATCHES).then(|| (median(candidates[best.clone()].to_vec()), best.len()))
+}
+
+fn median(mut values: Vec<i64>) -> i64 {
+    values.sort_unstable();
+    values[values.len() / 2]
+}
+
+/// Apply every known, nonzero offset as its own date shift, returning the
+/// shift numbers by device.
+pub fn apply(conn: &Connection, devices: &[Device], query: &str, exif: bool) -> Result<Vec<(String, dates::Applied)>, Error> {
+    let mut applied = Vec::new();
+    for device in devices {
+        let changes = device.changes();
+        if let (Some(offset), false) = (device.offset, changes.is_empty()) {
+            let description = format!("reconcile-clocks {} (device: {})", query, device.name);
+            applied.push((device.name.clone(), dates::apply(conn, &changes, offset, &description, exif)?));
+        }
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cataloged photo with just the fields reconciliation reads.
+    fn insert(conn: &Connection, path: &str, model: &str, date: &str, gps: Option<(f64, f64)>) -> Result<(), Error> {
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, creation_date, latitude, longitude, camera_model)
+             VALUES (?1, ?1, 0, ?2, ?3, ?4, ?5)",
+            rusqlite::params![path, date, gps.map(|(lat, _)| lat), gps.map(|(_, lon)| lon), model],
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_estimate() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        let spots = [(38.7139, -9.1394), (38.6916, -9.2160), (38.7223, -9.1393)];
+        for (i, spot) in spots.iter().enumerate() {
+            // The phone keeps good time; the second phone is an hour behind
+            insert(&conn, &format!("iphone{}.jpg", i), "iPhone 15", &format!("2023-07-14 1{}:00:00", i), Some(*spot))?;
+            insert(&conn, &format!("pixel{}.jpg", i), "Pixel 8", &format!("2023-07-14 {:02}:01:00", 9 + i), Some(*spot))?;
+        }
+        // A camera without GPS, an hour ahead, tied in by an anchor to the
+        // second phone
+        insert(&conn, "DSCF0001.JPG", "X100V", "2023-07-14 11:00:00", None)?;
+        insert(&conn, "DSCF0002.JPG", "X100V", "2023-07-14 13:00:00", None)?;
+        insert(&conn, "IMG_0001.JPG", "EOS R5", "2023-07-14 12:00:00", None)?;
+
+        let filter = SearchFilter { date: Some(String::from("2023-07-14")), ..SearchFilter::default() };
+        let anchors = vec![parse_anchor("pixel0.jpg=DSCF0001.JPG").unwrap()];
+        let devices = estimate(&conn, &filter, None, &anchors)?;
+        let summary: Vec<(&str, Option<i64>, &Evidence)> = devices.iter().map(|d| (d.name.as_str(), d.offset, &d.evidence)).collect();
+        assert_eq!(summary, vec![
+            ("iPhone 15", Some(0), &Evidence::Reference),
+            ("EOS R5", None, &Evidence::Unknown),
+            ("Pixel 8", Some(3540), &Evidence::Gps(3)),
+            ("X100V", Some(-3600), &Evidence::Anchors(1)),
+        ]);
+
+        let applied = apply(&conn, &devices, "date:2023-07-14", false)?;
+        assert_eq!(applied.iter().map(|(name, applied)| (name.as_str(), applied.dates)).collect::<Vec<_>>(), vec![("Pixel 8", 3), ("X100V", 2)]);
+        let date: String = conn.query_row("SELECT creation_date FROM images WHERE path = 'DSCF0002.JPG'", [], |row| row.get(0))?;
+        assert_eq!(date, "2023-07-14 12:00:00");
+
+        assert!(estimate(&conn, &filter, Some("Nokia 3310"), &[]).is_err());
+        assert!(estimate(&conn, &filter, None, &[parse_anchor("DSCF0001.JPG=DSCF0002.JPG").unwrap()]).is_err());
+        assert!(parse_anchor("DSCF0001.JPG").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,561 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Error};
+use serde::Deserialize;
+use crate::bandwidth::BandwidthConfig;
+use crate::caption::Template;
+use crate::derivative::MetadataPolicy;
+use crate::devices::{self, Profile};
+use crate::geofence::{self, Geofence};
+use crate::notes;
+use crate::remote::RemoteConfig;
+use crate::renames::OrganizeConfig;
+use crate::roles::User;
+use crate::schedule::Window;
+use crate::share::ShareConfig;
+use crate::tiering::TieringConfig;
+use crate::sequence;
+use crate::sources::SourceConfig;
+use crate::trips::TripConfig;
+use crate::walk::ScanConfig;
+
+/// Loaded from the working directory when `--config` isn't given.
+pub const DEFAULT_CONFIG_PATH: &str = "photo_catalog.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub scan: ScanConfig,
+    /// Named folders to scan, as `[[sources]]`
+    pub sources: Vec<SourceConfig>,
+    pub shares: ShareConfig,
+    pub analyzer: AnalyzerConfig,
+    pub privacy: PrivacyConfig,
+    pub video: VideoConfig,
+    pub daemon: DaemonConfig,
+    pub captions: CaptionConfig,
+    pub derivatives: DerivativeConfig,
+    /// Per-device profiles, as `[[devices]]`
+    pub devices: Vec<Profile>,
+    /// Named places photos get tagged with, as `[[geofences]]`
+    pub geofences: Vec<Geofence>,
+    pub trips: TripConfig,
+    pub reel: ReelConfig,
+    pub takeout: TakeoutConfig,
+    pub keywords: KeywordConfig,
+    pub transcriber: TranscriberConfig,
+    pub catalog: CatalogConfig,
+    pub tiering: TieringConfig,
+    pub organize: OrganizeConfig,
+    pub remote: RemoteConfig,
+    /// Who may use the API, as `[[users]]`
+    pub users: Vec<User>,
+    pub bandwidth: BandwidthConfig,
+    pub web: WebConfig,
+    pub downloads: DownloadsConfig,
+}
+
+/// The ZIPs of originals `serve` makes for downloading many at once.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub struct DownloadsConfig {
+    /// Where they're made and kept.
+    pub dir: String,
+    /// Hours each is kept once made, for the one who asked to fetch it.
+    pub keep_hours: u64,
+}
+
+impl Default for DownloadsConfig {
+    fn default() -> Self {
+        DownloadsConfig { dir: String::from("downloads"), keep_hours: 24 }
+    }
+}
+
+/// Branding the web page `serve` has, and its maps.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub struct WebConfig {
+    /// A CSS color for buttons and highlights.
+    pub accent: Option<String>,
+    /// A stylesheet applied after the page's own, e.g. to set its color
+    /// variables.
+    pub stylesheet: Option<PathBuf>,
+    /// Map tiles for geotagged photos' details, as a URL with `{z}`, `{x}`
+    /// and `{y}` in it; empty for no map. Tiles come straight from there to
+    /// the browser, so there's no map in local-only mode.
+    pub map_tiles: String,
+    /// Credit for the tiles, shown under the map.
+    pub map_attribution: String,
+    /// What the page calls this catalog's photos when `catalogs` shows
+    /// others' with them.
+    pub name: String,
+    /// Other catalogs whose photos the page shows alongside, searched and
+    /// sorted together with this one's, each marked with its name.
+    pub catalogs: Vec<AttachedCatalog>,
+}
+
+/// A catalog `serve` shows as well, as `[[web.catalogs]]` or `--attach`
+/// gives it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub struct AttachedCatalog {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// `NAME=PATH`, for `serve --attach`.
+pub fn parse_attached(value: &str) -> Result<AttachedCatalog, String> {
+    match value.split_once('=') {
+        Some((name, path)) if !name.trim().is_empty() && !path.is_empty() => {
+            Ok(AttachedCatalog { name: name.trim().to_string(), path: PathBuf::from(path) })
+        }
+        _ => Err(format!("expected NAME=PATH, like archive=/photos/archive.db, not {}", value)),
+    }
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        WebConfig {
+            accent: None,
+            stylesheet: None,
+            map_tiles: String::from("https://tile.openstreetmap.org/{z}/{x}/{y}.png"),
+            map_attribution: String::from("© OpenStreetMap contributors"),
+            name: String::from("main"),
+            catalogs: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CatalogConfig {
+    /// Images saved per transaction while scanning; 1 commits each on its
+    /// own.
+    pub batch_size: usize,
+}
+
+impl Default for CatalogConfig {
+    fn default() -> Self {
+        CatalogConfig { batch_size: 500 }
+    }
+}
+
+/// Transcribing voice notes: either a command printing the transcript of
+/// `{audio}`, like whisper.cpp's `whisper-cli`, or a server with OpenAI's
+/// `/v1/audio/transcriptions` (whisper.cpp's server, faster-whisper-server,
+/// OpenAI itself).
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TranscriberConfig {
+    pub command: Option<Vec<String>>,
+    pub url: Option<String>,
+    /// The model the server is asked for.
+    pub model: String,
+    /// Environment variable holding a bearer token for the server.
+    pub api_key_env: Option<String>,
+}
+
+impl Default for TranscriberConfig {
+    fn default() -> Self {
+        TranscriberConfig { command: None, url: None, model: String::from("whisper-1"), api_key_env: None }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeywordConfig {
+    /// Tags the model comes up with that are never kept, e.g. "photo".
+    pub blocked: Vec<String>,
+    /// A controlled vocabulary: when there is one, only these are kept.
+    pub allowed: Vec<String>,
+    /// A file adding to `allowed`, one tag per line (`#` starts a comment),
+    /// relative to the config file.
+    pub allowed_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TakeoutConfig {
+    /// Read the JSON sidecars of Google Photos Takeout archives while
+    /// scanning (same as `scan --takeout`).
+    pub enabled: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DerivativeConfig {
+    /// What metadata resized and re-encoded copies keep.
+    pub metadata: MetadataPolicy,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptionConfig {
+    /// How captions written with images are put together, e.g.
+    /// "{description}[ — {date_taken}]"; just the description when unset.
+    pub template: Option<Template>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Directories watched for new images.
+    pub roots: Vec<PathBuf>,
+    /// Seconds to wait before looking for more work once there is none.
+    pub interval_secs: u64,
+    /// Local times of day work may run, e.g. "01:00-06:00"; any time if empty.
+    pub windows: Vec<Window>,
+    pub idle: IdleConfig,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig { roots: Vec::new(), interval_secs: 900, windows: Vec::new(), idle: IdleConfig::default() }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdleConfig {
+    /// Only analyze while nobody is using the machine.
+    pub enabled: bool,
+    /// Seconds without keyboard or mouse input before the machine is idle.
+    pub input_secs: u64,
+    /// CPU usage, averaged over all cores, above which it isn't.
+    pub max_cpu_percent: f32,
+    /// GPU usage above which it isn't (NVIDIA only).
+    pub max_gpu_percent: f32,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        IdleConfig { enabled: false, input_secs: 300, max_cpu_percent: 25.0, max_gpu_percent: 20.0 }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    /// Extracts the frame that gets analyzed.
+    pub ffmpeg: String,
+    /// Reads metadata from videos that aren't MP4 or QuickTime.
+    pub ffprobe: String,
+    /// Split videos into scenes and caption each, so they can be searched
+    /// for what happens when.
+    pub scenes: bool,
+    /// How much the picture has to change for a new scene, from 0 to 1.
+    pub scene_threshold: f64,
+    /// Scenes shorter than this many seconds are joined to the one next
+    /// to them.
+    pub min_scene_secs: f64,
+    /// More scenes than this are joined up, shortest first.
+    pub max_scenes: usize,
+    /// Frames shown to the model to tag what a clip's subjects are doing;
+    /// 0 leaves actions untagged.
+    pub action_frames: usize,
+    /// Only clips up to this long get action tags.
+    pub action_max_secs: f64,
+    /// Measure how loud videos' sound is, which takes decoding all of it,
+    /// and so which are silent.
+    pub loudness: bool,
+    /// Where `serve` keeps the browser-playable copies it makes of videos.
+    pub preview_cache: String,
+    /// Height of those copies, at most.
+    pub preview_height: u32,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig {
+            ffmpeg: String::from("ffmpeg"),
+            ffprobe: String::from("ffprobe"),
+            scenes: false,
+            scene_threshold: 0.4,
+            min_scene_secs: 2.0,
+            max_scenes: 30,
+            action_frames: 0,
+            action_max_secs: 60.0,
+            loudness: true,
+            preview_cache: String::from("previews"),
+            preview_height: 720,
+        }
+    }
+}
+
+/// Highlight reels (`reel`), which need the `video` feature to be made;
+/// the section is read either way.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReelConfig {
+    /// How long a reel runs, at most, in seconds.
+    pub seconds: f64,
+    /// How long each photo is shown.
+    pub photo_secs: f64,
+    /// How much of each video goes in, at most.
+    pub clip_secs: f64,
+    /// Played under the reel, and looped if it's shorter; relative to the
+    /// config file. Reels are silent without one.
+    pub music: Option<PathBuf>,
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+
+impl Default for ReelConfig {
+    fn default() -> Self {
+        ReelConfig { seconds: 60.0, photo_secs: 3.0, clip_secs: 4.0, music: None, width: 1920, height: 1080, fps: 30 }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivacyConfig {
+    /// Refuse any configuration that would send images off this machine.
+    pub local_only: bool,
+    /// In local-only mode, also accept hosts on private networks.
+    pub allow_lan: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalyzerConfig {
+    /// Analysis endpoints; a single local Ollama is used when empty.
+    pub hosts: Vec<HostConfig>,
+    /// Seconds an unreachable host sits out before it is tried again.
+    pub retry_after_secs: u64,
+    /// Models for each stage of the analysis, instead of the hosts' own.
+    pub stages: StageModels,
+    /// How many of the captions you corrected are shown to the model as
+    /// examples of what you want; 0 for none.
+    pub examples: usize,
+    pub sequence: SequenceConfig,
+}
+
+impl Default for AnalyzerConfig {
+    fn default() -> Self {
+        AnalyzerConfig {
+            hosts: Vec::new(),
+            retry_after_secs: 60,
+            stages: StageModels::default(),
+            examples: 3,
+            sequence: SequenceConfig::default(),
+        }
+    }
+}
+
+/// Showing the model the photos taken just before and after one, so a
+/// frame of a burst or an event is described as part of it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SequenceConfig {
+    /// How many neighboring photos go along with each, up to 4; 0 to
+    /// analyze photos on their own.
+    pub frames: usize,
+    /// Photos further apart than this in seconds aren't the same sequence.
+    pub gap_secs: u64,
+}
+
+impl Default for SequenceConfig {
+    fn default() -> Self {
+        SequenceConfig { frames: 0, gap_secs: 10 }
+    }
+}
+
+/// A model per stage of the analysis, e.g. a small fast one for keywords
+/// and a better one for descriptions. Stages without one use the host's
+/// `model`; when description and keywords come out as the same model they
+/// are asked for in a single request.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StageModels {
+    pub description: Option<String>,
+    pub keywords: Option<String>,
+    /// Reading text in the image; skipped unless some model is set for it.
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostKind {
+    /// Ollama's `/api/generate`
+    #[default]
+    Ollama,
+    /// Any OpenAI-compatible `/v1/chat/completions` endpoint
+    OpenAi,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    pub name: Option<String>,
+    pub url: String,
+    #[serde(default)]
+    pub kind: HostKind,
+    #[serde(default = "default_model")]
+    pub model: String,
+    /// Relative share of requests among healthy hosts of the same tier.
+    #[serde(default = "default_one")]
+    pub weight: u32,
+    /// Requests this host is allowed to have in flight at once.
+    #[serde(default = "default_one_usize")]
+    pub concurrency: usize,
+    /// Only used while every non-fallback host is down.
+    #[serde(default)]
+    pub fallback: bool,
+    /// Environment variable holding a bearer token for the host.
+    pub api_key_env: Option<String>,
+    /// Stage models for this host, for when it names them differently
+    /// from `analyzer.stages`.
+    #[serde(default)]
+    pub stages: StageModels,
+}
+
+impl HostConfig {
+    /// An Ollama host with default settings.
+    pub fn ollama(url: &str) -> HostConfig {
+        HostConfig {
+            name: None,
+            url: url.to_string(),
+            kind: HostKind::Ollama,
+            model: default_model(),
+            weight: 1,
+            concurrency: 1,
+            fallback: false,
+            api_key_env: None,
+            stages: StageModels::default(),
+        }
+    }
+}
+
+fn default_model() -> String {
+    String::from("llava")
+}
+
+fn default_one() -> u32 {
+    1
+}
+
+fn default_one_usize() -> usize {
+    1
+}
+
+impl Config {
+    /// Read `path`, or the default config file if it exists, or fall back to
+    /// built-in defaults. An explicitly given path must exist.
+    pub fn load(path: Option<&Path>) -> Result<Config, Error> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Path::new(DEFAULT_CONFIG_PATH),
+            None => return Ok(Config::default()),
+        };
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("reading config {}", path.display()))?;
+        let mut config = Config::parse(&text).with_context(|| format!("parsing config {}", path.display()))?;
+        if let Some(file) = &config.keywords.allowed_file {
+            let file = path.parent().unwrap_or(Path::new("")).join(file);
+            let vocabulary = fs::read_to_string(&file).with_context(|| format!("reading vocabulary {}", file.display()))?;
+            config.keywords.allowed.extend(
+                vocabulary.lines()
+                    .map(|line| line.split('#').next().unwrap_or_default().trim())
+                    .filter(|tag| !tag.is_empty())
+                    .map(String::from),
+            );
+        }
+        if let Some(music) = &mut config.reel.music {
+            *music = path.parent().unwrap_or(Path::new("")).join(&*music);
+        }
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, Error> {
+        let config: Config = toml::from_str(text)?;
+        devices::validate(&config.devices)?;
+        geofence::validate(&config.geofences)?;
+        sequence::validate(&config.analyzer.sequence)?;
+        notes::validate(&config.transcriber)?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_analyzer_hosts() -> Result<(), Error> {
+        let config = Config::parse(r#"
+            [analyzer]
+            retry_after_secs = 30
+
+            [[analyzer.hosts]]
+            name = "desktop"
+            url = "http://10.0.0.5:11434"
+            weight = 3
+            concurrency = 2
+
+            [[analyzer.hosts]]
+            url = "https://api.openai.com"
+            kind = "openai"
+            model = "gpt-4o-mini"
+            fallback = true
+            api_key_env = "OPENAI_API_KEY"
+        "#)?;
+
+        let hosts = &config.analyzer.hosts;
+        assert_eq!(config.analyzer.retry_after_secs, 30);
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].kind, HostKind::Ollama);
+        assert_eq!(hosts[0].model, "llava");
+        assert_eq!((hosts[0].weight, hosts[0].concurrency), (3, 2));
+        assert_eq!(hosts[1].kind, HostKind::OpenAi);
+        assert!(hosts[1].fallback);
+        assert!(config.analyzer.stages.keywords.is_none());
+
+        let config = Config::parse(r#"
+            [analyzer.stages]
+            description = "llava:13b"
+            keywords = "moondream"
+
+            [[analyzer.hosts]]
+            url = "https://api.openai.com"
+            kind = "openai"
+            stages = { text = "gpt-4o" }
+        "#)?;
+        assert_eq!(config.analyzer.stages.keywords.as_deref(), Some("moondream"));
+        assert_eq!(config.analyzer.hosts[0].stages.text.as_deref(), Some("gpt-4o"));
+        assert!(Config::parse("[analyzer.stages]\nocr = \"x\"").is_err());
+
+        assert!(Config::parse("[analyzer]\nhots = []").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_daemon() -> Result<(), Error> {
+        let config = Config::parse(r#"
+            [daemon]
+            roots = ["/photos"]
+            windows = ["01:00-06:00", "22:00-23:30"]
+        "#)?;
+        assert_eq!(config.daemon.roots, vec![PathBuf::from("/photos")]);
+        assert_eq!(config.daemon.interval_secs, 900);
+        assert_eq!(config.daemon.windows[1].to_string(), "22:00-23:30");
+        assert!(!config.daemon.idle.enabled);
+
+        let config = Config::parse("[daemon.idle]\nenabled = true\ninput_secs = 60")?;
+        assert!(config.daemon.idle.enabled);
+        assert_eq!(config.daemon.idle.input_secs, 60);
+        assert_eq!(config.daemon.idle.max_cpu_percent, 25.0);
+
+        assert!(Config::parse("[captions]\ntemplate = \"{description} at {place}\"")?.captions.template.is_some());
+        assert!(Config::parse("[captions]\ntemplate = \"{nonsense}\"").is_err());
+        let config = Config::parse("[derivatives]\nmetadata = \"keep-copyright-only\"")?;
+        assert_eq!(config.derivatives.metadata, MetadataPolicy::KeepCopyrightOnly);
+        assert!(Config::parse("[derivatives]\nmetadata = \"some\"").is_err());
+
+        assert!(Config::parse("[daemon]\nwindows = [\"nightly\"]").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+//! Exact copies: catalogued files with the same content hash. `duplicates`
+//! lists them by how much space deleting all but one of each would free,
+//! biggest first, to know where cleaning up is worth it; it changes
+//! nothing (`organize` recommends which copies to delete, and moves them).
+
+use anyhow::Error;
+use rusqlite::Connection;
+use serde::Serialize;
+
+/// The copies of one file.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Group {
+    pub content_hash: String,
+    /// Of each copy
+    pub file_size: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub paths: Vec<String>,
+}
+
+impl Group {
+    /// What deleting all the copies but one would free.
+    pub fn recoverable(&self) -> u64 {
+        self.file_size * (self.paths.len() as u64).saturating_sub(1)
+    }
+}
+
+/// Every set of copies, the most space recoverable first.
+pub fn groups(conn: &Connection) -> Result<Vec<Group>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT content_hash, file_size, width, height, path FROM images
+         WHERE archived_at IS NULL AND content_hash IN (
+             SELECT content_hash FROM images WHERE archived_at IS NULL AND content_hash IS NOT NULL
+             GROUP BY content_hash HAVING COUNT(*) > 1
+         )
+         ORDER BY content_hash, path",
+    )?;
+    let mut rows = stmt.query([])?;
+    let mut groups: Vec<Group> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let content_hash: String = row.get(0)?;
+        let path: String = row.get(4)?;
+        match groups.last_mut() {
+            Some(group) if group.content_hash == content_hash => group.paths.push(path),
+            _ => groups.push(Group { content_hash, file_size: row.get(1)?, width: row.get(2)?, height: row.get(3)?, paths: vec![path] }),
+        }
+    }
+    groups.sort_by(|a, b| b.recoverable().cmp(&a.recoverable()).then_with(|| a.paths.cmp(&b.paths)));
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_groups() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute_batch(
+            "INSERT INTO images (path, file_name, file_size, content_hash, width, height, archived_at) VALUES
+                 ('/photos/beach.jpg', 'beach.jpg', 100, 'beach', 4000, 3000, NULL),
+                 ('/backup/beach.jpg', 'beach.jpg', 100, 'beach', 4000, 3000, NULL),
+                 ('/backup/old/beach.jpg', 'beach.jpg', 100, 'beach', 4000, 3000, NULL),
+                 ('/photos/tram.mov', 'tram.mov', 500, 'tram', 1920, 1080, NULL),
+                 ('/backup/tram.mov', 'tram.mov', 500, 'tram', 1920, 1080, NULL),
+                 ('/photos/cat.jpg', 'cat.jpg', 900, 'cat', NULL, NULL, NULL),
+                 ('/gone/cat.jpg', 'cat.jpg', 900, 'cat', NULL, NULL, '2024-01-01');",
+        )?;
+
+        let groups = groups(&conn)?;
+        let summary: Vec<(&str, u64, usize)> = groups.iter().map(|group| (group.content_hash.as_str(), group.recoverable(), group.paths.len())).collect();
+        assert_eq!(summary, vec![("tram", 500, 2), ("beach", 200, 3)]);
+        assert_eq!(groups[1].paths, vec!["/backup/beach.jpg", "/backup/old/beach.jpg", "/photos/beach.jpg"]);
+        assert_eq!((groups[1].width, groups[1].height), (Some(4000), Some(3000)));
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+//! Captions the user corrected, kept so the model can be shown how they
+//! like them written. The latest corrections go into the analysis prompt as
+//! examples, taken from photos of the same tier where there are any, so
+//! archive scans and phone snapshots can each converge on their own style.
+
+use anyhow::{anyhow, Error};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use crate::edits::{self, Caption, Edited, Editor};
+use crate::tags;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Example {
+    pub tier: Option<String>,
+    pub description: String,
+    pub keywords: String,
+}
+
+/// The examples an analyzer has to choose from, newest first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Examples {
+    examples: Vec<Example>,
+    per_prompt: usize,
+}
+
+impl Examples {
+    /// The examples for a photo of `tier`: the latest from that tier, or the
+    /// latest of all if it has none.
+    pub fn for_tier(&self, tier: Option<&str>) -> Vec<&Example> {
+        let same: Vec<&Example> = self.examples.iter()
+            .filter(|example| example.tier.as_deref() == tier)
+            .take(self.per_prompt)
+            .collect();
+        match same.is_empty() {
+            true => self.examples.iter().take(self.per_prompt).collect(),
+            false => same,
+        }
+    }
+}
+
+/// Replace the description and/or keywords of the photo at `path` with
+/// the user's, remembering them as an example for later analyses, and log
+/// the edit (see `edits`).
+pub fn correct(conn: &Connection, path: &str, description: Option<&str>, keywords: Option<&str>, editor: &Editor) -> Result<Edited, Error> {
+    let tx = conn.unchecked_transaction()?;
+    let (id, tier, version, before): (i64, Option<String>, i64, Caption) = tx
+        .query_row(&format!("SELECT id, tier, version, description, {} FROM images WHERE path = ?1", tags::KEYWORDS), [path], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, Caption { description: row.get(3)?, keywords: row.get(4)? }))
+        })
+        .optional()?
+        .ok_or_else(|| anyhow!("{} isn't in the catalog", path))?;
+    let description = description.map(str::to_string).or(before.description.clone()).unwrap_or_default();
+    let keywords = keywords.map(str::to_string).or(before.keywords.clone()).unwrap_or_default();
+    tx.execute("UPDATE images SET description = ?1 WHERE id = ?2", params![description, id])?;
+    tags::set(&tx, id, &keywords)?;
+    let after = Caption { description: Some(description.clone()), keywords: Some(keywords.clone()) };
+    let edited = edits::log(&tx, id, editor, version, &before, &after)?;
+    // Only the latest correction of a photo is an example
+    tx.execute("DELETE FROM corrections WHERE image_id = ?1", [id])?;
+    tx.execute(
+        "INSERT INTO corrections (image_id, tier, description, keywords, corrected_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![id, tier, description, keywords, chrono::Utc::now().to_rfc3339()],
+    )?;
+    tx.commit()?;
+    Ok(edited)
+}
+
+/// Up to `per_prompt` of the latest corrections of each tier.
+pub fn examples(conn: &Connection, per_prompt: usize) -> Result<Examples, Error> {
+    let mut stmt = conn.prepare("SELECT tier, description, keywords FROM corrections ORDER BY corrected_at DESC, rowid DESC")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(Example { tier: row.get(0)?, description: row.get(1)?, keywords: row.get(2)? })
+    })?;
+    let mut examples: Vec<Example> = Vec::new();
+    for example in rows {
+        let example = example?;
+        if examples.iter().filter(|e| e.tier == example.tier).count() < per_prompt {
+            examples.push(example);
+        }
+    }
+    Ok(Examples { examples, per_prompt })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corrections_become_examples() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, tier, description) VALUES
+             ('/scan1.jpg', 'scan1.jpg', 1, 'archive', 'A photo of people'),
+             ('/scan2.jpg', 'scan2.jpg', 1, 'archive', 'A photo'),
+             ('/phone.jpg', 'phone.jpg', 1, NULL, 'A dog')",
+            [],
+        )?;
+        crate::test_support::set_keywords(&conn, &[("/scan1.jpg", "people"), ("/scan2.jpg", "photo"), ("/phone.jpg", "dog")]);
+        correct(&conn, "/scan1.jpg", Some("Grandma and Grandpa at the beach, 1970s"), None, &Editor::CLI)?;
+        correct(&conn, "/scan2.jpg", None, Some("wedding, church"), &Editor::CLI)?;
+        correct(&conn, "/scan2.jpg", Some("Their wedding"), None, &Editor::CLI)?;
+        assert!(correct(&conn, "/missing.jpg", Some("x"), None, &Editor::CLI).is_err());
+
+        let description: String = conn.query_row("SELECT description FROM images WHERE path = '/scan1.jpg'", [], |row| row.get(0))?;
+        assert_eq!(description, "Grandma and Grandpa at the beach, 1970s");
+
+        let examples = examples(&conn, 1)?;
+        let archive = examples.for_tier(Some("archive"));
+        assert_eq!(archive, vec![&Example {
+            tier: Some(String::from("archive")),
+            description: String::from("Their wedding"),
+            keywords: String::from("wedding, church"),
+        }]);
+        // Nothing corrected for phone photos yet, so they get the latest
+        assert_eq!(examples.for_tier(None), archive);
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+//! Culling in the catalog: star ratings (1 to 5, 0 for none), favorites
+//! and rejects, set with `rate`, `favorite` and `reject` or from the web
+//! page, and searched for like anything else (`rating:4 rejected:no`).
+//! They're the catalog's own: ratings and picks imported from Lightroom or
+//! XMP stay in `external_metadata` as they came, though tiering and reels
+//! go by both.
+
+use anyhow::Error;
+use rusqlite::{params, Connection};
+#[cfg(any(feature = "server", test))]
+use serde::Serialize;
+use crate::error::{CliError, ErrorKind};
+
+pub const MAX_RATING: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mark {
+    Rating(u8),
+    Favorite(bool),
+    Rejected(bool),
+}
+
+impl Mark {
+    fn column(self) -> &'static str {
+        match self {
+            Mark::Rating(_) => "rating",
+            Mark::Favorite(_) => "favorite",
+            Mark::Rejected(_) => "rejected",
+        }
+    }
+
+    fn value(self) -> i64 {
+        match self {
+            Mark::Rating(stars) => stars.into(),
+            Mark::Favorite(flag) | Mark::Rejected(flag) => flag.into(),
+        }
+    }
+}
+
+/// A photo's rating and flags, as the API shows them.
+#[cfg(any(feature = "server", test))]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Culled {
+    pub rating: u8,
+    pub favorite: bool,
+    pub rejected: bool,
+}
+
+/// Mark the images with `ids`. Gives how many changed.
+pub fn mark(conn: &Connection, ids: &[i64], mark: Mark) -> Result<usize, Error> {
+    if let Mark::Rating(stars) = mark {
+        if stars > MAX_RATING {
+            return Err(CliError::new(ErrorKind::Config, format!("ratings go from 0 to {} stars", MAX_RATING)).into());
+        }
+    }
+    let tx = conn.unchecked_transaction()?;
+    let column = mark.column();
+    let mut stmt = tx.prepare(&format!("UPDATE images SET {} = ?1 WHERE id = ?2 AND {} != ?1", column, column))?;
+    let mut changed = 0;
+    for id in ids {
+        changed += stmt.execute(params![mark.value(), id])?;
+    }
+    drop(stmt);
+    tx.commit()?;
+    Ok(changed)
+}
+
+#[cfg(any(feature = "server", test))]
+pub fn get(conn: &Connection, id: i64) -> rusqlite::Result<Culled> {
+    conn.query_row("SELECT rating, favorite, rejected FROM images WHERE id = ?1", [id], |row| {
+        Ok(Culled { rating: row.get(0)?, favorite: row.get(1)?, rejected: row.get(2)? })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SearchFilter;
+
+    #[test]
+    fn test_mark() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute_batch(
+            "INSERT INTO images (path, file_name, file_size) VALUES ('/a.jpg', 'a.jpg', 1), ('/b.jpg', 'b.jpg', 1), ('/c.jpg', 'c.jpg', 1);",
+        )?;
+        assert_eq!(get(&conn, 1)?, Culled { rating: 0, favorite: false, rejected: false });
+        assert_eq!(mark(&conn, &[1, 2], Mark::Rating(4))?, 2);
+        assert_eq!(mark(&conn, &[2, 3], Mark::Rating(4))?, 1);
+        assert_eq!(mark(&conn, &[1], Mark::Rating(2))?, 1);
+        assert!(mark(&conn, &[1], Mark::Rating(6)).is_err());
+        mark(&conn, &[2], Mark::Favorite(true))?;
+        mark(&conn, &[3], Mark::Rejected(true))?;
+        assert_eq!(get(&conn, 2)?, Culled { rating: 4, favorite: true, rejected: false });
+
+        let search = |filter: SearchFilter| crate::search_images(&conn, &filter);
+        assert_eq!(search(SearchFilter { rating: Some(3), ..SearchFilter::default() })?, vec!["/b.jpg", "/c.jpg"]);
+        assert_eq!(search(SearchFilter { rating: Some(3), rejected: Some(false), ..SearchFilter::default() })?, vec!["/b.jpg"]);
+        assert_eq!(search(SearchFilter { favorite: Some(false), ..SearchFilter::default() })?, vec!["/a.jpg", "/c.jpg"]);
+        Ok(())
+    }
+}
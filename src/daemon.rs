@@ -0,0 +1,380 @@
+//! `daemon`: background upkeep of the catalog. New files under the
+//! configured roots are cataloged, and images without an analysis yet get
+//! one, but only inside the configured processing windows; outside them
+//! the daemon pauses and picks up where it left off when the next opens.
+//! With `[daemon.idle]` enabled, analysis also waits for the machine to be
+//! idle and steps aside, abandoning the request in flight, as soon as
+//! someone uses it again. It steps aside the same way, whatever the
+//! configuration, for interactive requests (see `priority`).
+
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::Error;
+use chrono::Local;
+use rusqlite::{params, Connection};
+use crate::analyzer::{self, Analysis, AnalyzerPool};
+use crate::cancel::Reason;
+use crate::config::Config;
+use crate::error::{self, ErrorKind};
+use crate::idle::Monitor;
+use crate::priority::Lane;
+use crate::progress::Operation;
+use crate::{keywords, schedule, tags};
+
+/// Images fetched per query while backfilling
+const PAGE_SIZE: i64 = 100;
+/// How often a busy machine is checked for having gone idle
+const IDLE_POLL: Duration = Duration::from_secs(10);
+/// How often the interactive lane is checked, waiting or analyzing
+const LANE_POLL: Duration = Duration::from_millis(250);
+/// How often the user is checked for while analyzing
+const USER_POLL: Duration = Duration::from_secs(1);
+
+pub fn run(conn: &Connection, operation: &Operation, config: &Config, analyzer: &AnalyzerPool) -> Result<(), Error> {
+    if config.daemon.roots.is_empty() {
+        println!("No daemon roots configured; only analyzing images already in the catalog");
+    }
+    let mut idle = config.daemon.idle.enabled.then(|| Monitor::new(&config.daemon.idle));
+    let lane = Lane::of(conn);
+    loop {
+        wait_for_window(conn, operation, config)?;
+        let cataloged = catalog_new_files(conn, operation, config, lane.as_ref())?;
+        let analyzed = backfill(conn, operation, config, analyzer, idle.as_mut(), lane.as_ref())?;
+        if cataloged + analyzed == 0 {
+            pause(conn, operation, Duration::from_secs(config.daemon.interval_secs))?;
+        }
+    }
+}
+
+fn window_open(config: &Config) -> bool {
+    schedule::is_open(&config.daemon.windows, Local::now().time())
+}
+
+/// Block until a processing window is open.
+fn wait_for_window(conn: &Connection, operation: &Operation, config: &Config) -> Result<(), Error> {
+    if window_open(config) {
+        return Ok(());
+    }
+    let windows = &config.daemon.windows;
+    if let Some(next) = schedule::next_opening(windows, Local::now().time()) {
+        let list: Vec<String> = windows.iter().map(|w| w.to_string()).collect();
+        println!("Outside processing windows ({}), pausing until {}", list.join(", "), next.format("%H:%M"));
+    }
+    while !window_open(config) {
+        pause(conn, operation, Duration::from_secs(1))?;
+    }
+    println!("Processing window open, resuming");
+    Ok(())
+}
+
+/// Block until the machine may be used for analysis. Returns false if the
+/// processing window closes first.
+fn wait_for_idle(conn: &Connection, operation: &Operation, config: &Config, monitor: &mut Monitor) -> Result<bool, Error> {
+    if monitor.may_run() {
+        return Ok(true);
+    }
+    println!("Machine in use, pausing analysis until it is idle");
+    loop {
+        pause(conn, operation, IDLE_POLL)?;
+        if !window_open(config) {
+            return Ok(false);
+        }
+        if monitor.may_run() {
+            println!("Machine idle, resuming analysis");
+            return Ok(true);
+        }
+    }
+}
+
+/// Block while interactive requests have the lane.
+fn wait_for_lane(conn: &Connection, operation: &Operation, lane: Option<&Lane>) -> Result<(), Error> {
+    let Some(lane) = lane.filter(|lane| lane.busy()) else { return Ok(()) };
+    println!("Interactive requests waiting, pausing");
+    while lane.busy() {
+        pause(conn, operation, LANE_POLL)?;
+    }
+    println!("Interactive requests done, resuming");
+    Ok(())
+}
+
+/// Analyze one image, starting over whenever interactive requests need
+/// the lane and, with an idle monitor, waiting for the machine to be idle
+/// and starting over whenever the user interrupts; `None` if the window
+/// closes before it's done.
+#[allow(clippy::too_many_arguments)]
+fn analyze(
+    conn: &Connection,
+    operation: &Operation,
+    config: &Config,
+    analyzer: &AnalyzerPool,
+    mut idle: Option<&mut Monitor>,
+    lane: Option<&Lane>,
+    data: &[u8],
+    tier: Option<&str>,
+) -> Result<Option<Analysis>, Error> {
+    if idle.is_none() && lane.is_none() {
+        return analyzer.analyze_blocking(data, tier, &operation.cancel.in_flight()).map(Some);
+    }
+    loop {
+        if let Some(monitor) = idle.as_deref_mut() {
+            if !wait_for_idle(conn, operation, config, monitor)? {
+                return Ok(None);
+            }
+        }
+        wait_for_lane(conn, operation, lane)?;
+        if let Some(result) = analyze_unless_needed(operation, analyzer, idle.as_deref(), lane, data, tier)? {
+            return Ok(Some(result));
+        }
+        println!("Needed elsewhere, abandoning the analysis in progress");
+    }
+}
+
+/// Analyze `data`, giving up on the request (with `None`) if the user comes
+/// back or interactive requests come in while it is in flight.
+fn analyze_unless_needed(
+    operation: &Operation,
+    analyzer: &AnalyzerPool,
+    monitor: Option<&Monitor>,
+    lane: Option<&Lane>,
+    data: &[u8],
+    tier: Option<&str>,
+) -> Result<Option<Analysis>, Error> {
+    let request = operation.cancel.in_flight();
+    let (done, finished) = mpsc::channel::<()>();
+    let poll = if lane.is_some() { LANE_POLL } else { USER_POLL };
+    let result = thread::scope(|scope| {
+        let request = &request;
+        scope.spawn(move || {
+            let mut user_checked = Instant::now();
+            while let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(poll) {
+                let user_back = monitor.is_some_and(|monitor| {
+                    user_checked.elapsed() >= USER_POLL && {
+                        user_checked = Instant::now();
+                        monitor.user_active()
+                    }
+                });
+                if user_back || lane.is_some_and(Lane::busy) {
+                    request.cancel(Reason::Yield);
+                    return;
+                }
+            }
+        });
+        let result = analyzer.analyze_blocking(data, tier, request);
+        drop(done);
+        result
+    });
+    match result {
+        Err(_) if request.reason() == Some(Reason::Yield) => Ok(None),
+        result => result.map(Some),
+    }
+}
+
+/// Sleep, waking every second to notice cancellation.
+fn pause(conn: &Connection, operation: &Operation, duration: Duration) -> Result<(), Error> {
+    let mut left = duration;
+    while !left.is_zero() {
+        operation.checkpoint(conn)?;
+        let step = left.min(Duration::from_secs(1));
+        thread::sleep(step);
+        left -= step;
+    }
+    operation.checkpoint(conn)
+}
+
+/// Catalog (without analysis) files under the roots that aren't in the
+/// catalog yet. Returns how many were added.
+fn catalog_new_files(conn: &Connection, operation: &Operation, config: &Config, lane: Option<&Lane>) -> Result<usize, Error> {
+    let mut added = 0;
+    let mut batch = crate::batch::Batch::new(conn, config.catalog.batch_size);
+    let mut missing = crate::moves::Missing::find(conn, &mut crate::share::Shares::new(&config.shares))?;
+    let filter = crate::walk::Filter::new(&config.scan)?;
+    for root in &config.daemon.roots {
+        for path in crate::find_images(root, &filter, &operation.cancel) {
+            operation.checkpoint(conn)?;
+            if !window_open(config) {
+                batch.commit()?;
+                return Ok(added);
+            }
+            let known: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM images WHERE path = ?1)",
+                [path.to_string_lossy()],
+                |row| row.get(0),
+            )?;
+            if known {
+                continue;
+            }
+            if let Some(old_path) = missing.relocate(conn, &path)? {
+                println!("Moved: {} -> {}", old_path, path.display());
+                continue;
+            }
+            if lane.is_some_and(Lane::busy) {
+                batch.commit()?;
+                wait_for_lane(conn, operation, lane)?;
+            }
+            match crate::process_image(&path, config, None, &operation.cancel) {
+                Ok(metadata) => {
+                    batch.write(|conn| Ok(crate::save_metadata(conn, &metadata)?))?;
+                    tracing::info!("Cataloged: {}", path.display());
+                    added += 1;
+                }
+                Err(e) => {
+                    tracing::error!("Error processing {}: {}", path.display(), e);
+                    if let Some(category) = crate::problems::category_of(&e) {
+                        let message = format!("{:#}", e);
+                        batch.write(|conn| Ok(crate::problems::record(conn, &path.to_string_lossy(), category, &message)?))?;
+                    }
+                }
+            }
+        }
+    }
+    batch.commit()?;
+    crate::sources::assign(conn)?;
+    Ok(added)
+}
+
+/// Analyze images that don't have a description yet, oldest first, until
+/// there are none left, the window closes or the analyzer hosts go away.
+/// With an idle monitor, each image waits for the machine to be idle and
+/// is retried if the user interrupts it. Returns how many were analyzed.
+fn backfill(
+    conn: &Connection,
+    operation: &Operation,
+    config: &Config,
+    analyzer: &AnalyzerPool,
+    mut idle: Option<&mut Monitor>,
+    lane: Option<&Lane>,
+) -> Result<usize, Error> {
+    let mut analyzed = 0;
+    // Walk forward by id so images that fail are retried next round rather
+    // than over and over in this one
+    let mut after = 0;
+    loop {
+        let mut stmt = conn.prepare(
+            "SELECT id, path, tier FROM images WHERE description IS NULL AND id > ?1 ORDER BY id LIMIT ?2",
+        )?;
+        let page = stmt.query_map(params![after, PAGE_SIZE], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?))
+        })?
+            .collect::<Result<Vec<_>, _>>()?;
+        if page.is_empty() {
+            return Ok(analyzed);
+        }
+
+        for (id, path, tier) in page {
+            operation.checkpoint(conn)?;
+            if !window_open(config) {
+                return Ok(analyzed);
+            }
+            after = id;
+            let _span = tracing::debug_span!("image", path = %path).entered();
+            let result = tracing::debug_span!("read").in_scope(|| crate::jobs::analyzable_data(path.as_ref(), config))
+                .and_then(|data| {
+                    tracing::debug_span!("ai").in_scope(|| analyze(conn, operation, config, analyzer, idle.as_deref_mut(), lane, &data, tier.as_deref()))
+                });
+            // Nothing yet means the window closed while waiting for the machine
+            let Some(result) = result.transpose() else { return Ok(analyzed) };
+            match result {
+                Ok(analysis) => {
+                    let updated = conn.execute(
+                        "UPDATE images SET description = ?1 WHERE id = ?2 AND description IS NULL",
+                        params![analysis.description, id],
+                    )?;
+                    if updated > 0 {
+                        tags::set(conn, id, &keywords::enforce(&config.keywords, &path, &analysis.keywords))?;
+                        save_text(conn, id, &analysis)?;
+                    }
+                    tracing::info!("Analyzed: {}", path);
+                    analyzed += 1;
+                    operation.advance(conn, true)?;
+                }
+                Err(e) => match error::classify(&e) {
+                    ErrorKind::Cancelled => return Err(e),
+                    ErrorKind::BackendUnreachable => {
+                        tracing::warn!("Analyzer unreachable ({}), waiting before trying again", e);
+                        return Ok(analyzed);
+                    }
+                    _ => {
+                        tracing::error!("Error analyzing {}: {}", path, e);
+                        operation.advance(conn, false)?;
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Keep the text the analysis read in the image, replacing any earlier.
+pub fn save_text(conn: &Connection, id: i64, analysis: &Analysis) -> Result<(), Error> {
+    conn.execute("DELETE FROM external_metadata WHERE image_id = ?1 AND source = ?2", params![id, analyzer::TEXT_SOURCE])?;
+    for entry in analysis.external() {
+        conn.execute(
+            "INSERT INTO external_metadata (image_id, source, field, value) VALUES (?1, ?2, ?3, ?4)",
+            params![id, entry.source, entry.field, entry.value],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use crate::cancel::CancelToken;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_catalog_and_backfill() -> Result<(), Error> {
+        let dir = tempdir()?;
+        image::DynamicImage::new_rgb8(8, 6).save(dir.path().join("new.png"))?;
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+
+        let mut config = Config::default();
+        config.daemon.roots = vec![dir.path().to_path_buf()];
+        let operation = crate::progress::start(&conn, "daemon", CancelToken::new())?;
+        let (_server, analyzer) = mock_ollama();
+
+        assert_eq!(catalog_new_files(&conn, &operation, &config, None)?, 1);
+        // Already cataloged files are left alone
+        assert_eq!(catalog_new_files(&conn, &operation, &config, None)?, 0);
+        assert_eq!(backfill(&conn, &operation, &config, &analyzer, None, None)?, 1);
+        assert_eq!(backfill(&conn, &operation, &config, &analyzer, None, None)?, 0);
+
+        let description: String = conn.query_row("SELECT description FROM images", [], |row| row.get(0))?;
+        assert_eq!(description, "A colorful sunset over mountains");
+
+        // A quiet enough machine gets on with it when analysis waits for idle
+        image::DynamicImage::new_rgb8(8, 6).save(dir.path().join("later.png"))?;
+        assert_eq!(catalog_new_files(&conn, &operation, &config, None)?, 1);
+        let quiet = crate::config::IdleConfig { enabled: true, input_secs: 0, max_cpu_percent: 100.0, max_gpu_percent: 100.0 };
+        let mut monitor = Monitor::new(&quiet);
+        assert_eq!(backfill(&conn, &operation, &config, &analyzer, Some(&mut monitor), None)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_yield_to_interactive() -> Result<(), Error> {
+        let dir = tempdir()?;
+        image::DynamicImage::new_rgb8(8, 6).save(dir.path().join("new.png"))?;
+        let conn = crate::open_catalog(&dir.path().join("photo_catalog.db"))?;
+        let mut config = Config::default();
+        config.daemon.roots = vec![dir.path().to_path_buf()];
+        let operation = crate::progress::start(&conn, "daemon", CancelToken::new())?;
+        let (_server, analyzer) = mock_ollama();
+        assert_eq!(catalog_new_files(&conn, &operation, &config, None)?, 1);
+
+        // Someone browsing for a moment holds the backfill up until they're done
+        let lane = Lane::of(&conn).unwrap();
+        let claim = lane.claim();
+        let browsing = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(claim);
+        });
+        let started = Instant::now();
+        assert_eq!(backfill(&conn, &operation, &config, &analyzer, None, Some(&lane))?, 1);
+        assert!(started.elapsed() >= Duration::from_secs(2));
+        browsing.join().unwrap();
+        Ok(())
+    }
+}
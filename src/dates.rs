@@ -0,0 +1,316 @@
+//! `fix-dates`: moving capture times by a fixed amount, for the camera
+//! whose clock was still on home time. Every shift is recorded along with
+//! the dates it changed, so it can be previewed first and undone later.
+//! With `--exif` the files' own dates move too, patched in place so that
+//! nothing else in the file changes.
+
+use std::fs;
+use std::path::Path;
+use anyhow::Error;
+use chrono::{NaiveDateTime, TimeDelta};
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::error::{CliError, ErrorKind};
+use crate::hash;
+use crate::jpeg::EXIF_SIGNATURE;
+use crate::tiff::Tiff;
+use crate::SearchFilter;
+
+/// DateTime, DateTimeOriginal and DateTimeDigitized
+const DATE_TAGS: &[u16] = &[0x0132, 0x9003, 0x9004];
+/// How capture times are kept in the catalog
+pub const CATALOG_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const EXIF_FORMAT: &str = "%Y:%m:%d %H:%M:%S";
+
+/// A signed shift such as "+2h", "-1h30m" or "+1d", in seconds.
+pub fn parse_shift(s: &str) -> Result<i64, String> {
+    let invalid = || format!("invalid shift: {} (expected e.g. +2h, -1h30m or +1d)", s);
+    let s = s.trim();
+    let (sign, magnitude) = match s.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let secs = crate::parse_duration(magnitude).map_err(|_| invalid())?.as_secs();
+    let secs = i64::try_from(secs).ok().filter(|&secs| secs > 0 && TimeDelta::try_seconds(secs).is_some()).ok_or_else(invalid)?;
+    Ok(sign * secs)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub id: i64,
+    pub path: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// What shifting the photos matching `filter` by `seconds` would change.
+/// Photos without a (complete) date are left out.
+pub fn plan(conn: &Connection, filter: &SearchFilter, seconds: i64) -> Result<Vec<Change>, Error> {
+    let (clause, params) = crate::filter_clause(filter);
+    let mut stmt = conn.prepare(&format!("SELECT id, path, creation_date FROM images{} ORDER BY creation_date, path", clause))?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?))
+    })?.collect::<Result<Vec<_>, _>>()?;
+    Ok(rows.into_iter().filter_map(|(id, path, old)| change(id, path, old?, seconds)).collect())
+}
+
+/// The change shifting image `id`, dated `old`, by `seconds`; `None` if
+/// the date isn't complete.
+pub fn change(id: i64, path: String, old: String, seconds: i64) -> Option<Change> {
+    let new = shifted(&old, seconds)?;
+    Some(Change { id, path, old, new })
+}
+
+/// A catalog date moved by `seconds`; `None` if it isn't complete.
+pub fn shifted(date: &str, seconds: i64) -> Option<String> {
+    shift(date, CATALOG_FORMAT, seconds)
+}
+
+fn shift(date: &str, format: &str, seconds: i64) -> Option<String> {
+    let date = NaiveDateTime::parse_from_str(date.trim_end_matches('\0').trim(), format).ok()?;
+    Some(date.checked_add_signed(TimeDelta::try_seconds(seconds)?)?.format(format).to_string())
+}
+
+/// The outcome of `apply`.
+#[derive(Debug, PartialEq)]
+pub struct Applied {
+    /// What `undo` takes
+    pub shift_id: i64,
+    pub dates: usize,
+    pub files: usize,
+    pub files_skipped: usize,
+}
+
+/// Make the `changes` from `plan`, recording them as one shift; with
+/// `exif`, shift the dates in the files as well.
+pub fn apply(conn: &Connection, changes: &[Change], seconds: i64, query: &str, exif: bool) -> Result<Applied, Error> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "INSERT INTO date_shifts (seconds, query, created_at) VALUES (?1, ?2, strftime('%s', 'now'))",
+        params![seconds, query],
+    )?;
+    let shift_id = tx.last_insert_rowid();
+    // The files follow only the dates recorded here, so `undo` can put
+    // back every one it moved
+    let mut shifted = Vec::new();
+    for change in changes {
+        // Only if nothing has moved it since the preview
+        let updated = tx.execute(
+            "UPDATE images SET creation_date = ?1 WHERE id = ?2 AND creation_date = ?3",
+            params![change.new, change.id, change.old],
+        )?;
+        if updated > 0 {
+            tx.execute(
+                "INSERT INTO date_shift_images (shift_id, image_id, old_date, new_date, exif_shifted) VALUES (?1, ?2, ?3, ?4, 0)",
+                params![shift_id, change.id, change.old, change.new],
+            )?;
+            shifted.push(change);
+        }
+    }
+    tx.commit()?;
+
+    let (mut files, mut files_skipped) = (0, 0);
+    if exif {
+        for change in &shifted {
+            match shift_file_dates(Path::new(&change.path), seconds) {
+                Ok(Some(rewritten)) => {
+                    conn.execute(
+                        "UPDATE date_shift_images SET exif_shifted = 1 WHERE shift_id = ?1 AND image_id = ?2",
+                        params![shift_id, change.id],
+                    )?;
+                    record_rewrite(conn, change.id, rewritten)?;
+                    files += 1;
+                }
+                Ok(None) => {
+                    tracing::warn!("Skipping {}: no EXIF dates we can update", change.path);
+                    files_skipped += 1;
+                }
+                Err(e) => {
+                    tracing::warn!("Skipping {}: {}", change.path, e);
+                    files_skipped += 1;
+                }
+            }
+        }
+    }
+    Ok(Applied { shift_id, dates: shifted.len(), files, files_skipped })
+}
+
+/// Reverse shift `shift_id`: the catalog dates it changed (unless they've
+/// been changed again since) and the EXIF dates it moved. Returns how many
+/// of each were restored.
+pub fn undo(conn: &Connection, shift_id: i64) -> Result<(usize, usize), Error> {
+    let shift: Option<(i64, Option<i64>)> = conn.query_row(
+        "SELECT seconds, undone_at FROM date_shifts WHERE id = ?1",
+        [shift_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+    let seconds = match shift {
+        None => return Err(CliError::new(ErrorKind::NothingToDo, format!("no date shift {}", shift_id)).into()),
+        Some((_, Some(_))) => return Err(CliError::new(ErrorKind::NothingToDo, format!("date shift {} was already undone", shift_id)).into()),
+        Some((seconds, None)) => seconds,
+    };
+
+    let mut stmt = conn.prepare(
+        "SELECT s.image_id, i.path, s.old_date, s.new_date, s.exif_shifted
+         FROM date_shift_images s JOIN images i ON i.id = s.image_id
+         WHERE s.shift_id = ?1",
+    )?;
+    let rows = stmt.query_map([shift_id], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?, row.get::<_, bool>(4)?))
+    })?.collect::<Result<Vec<_>, _>>()?;
+
+    let (mut dates, mut files) = (0, 0);
+    for (id, path, old, new, exif_shifted) in rows {
+        dates += conn.execute(
+            "UPDATE images SET creation_date = ?1 WHERE id = ?2 AND creation_date = ?3",
+            params![old, id, new],
+        )?;
+        if exif_shifted {
+            match shift_file_dates(Path::new(&path), -seconds) {
+                Ok(Some(rewritten)) => {
+                    record_rewrite(conn, id, rewritten)?;
+                    files += 1;
+                }
+                Ok(None) => tracing::warn!("Can't restore the EXIF dates of {}: they're gone", path),
+                Err(e) => tracing::warn!("Can't restore the EXIF dates of {}: {}", path, e),
+            }
+        }
+    }
+    conn.execute("UPDATE date_shifts SET undone_at = strftime('%s', 'now') WHERE id = ?1", [shift_id])?;
+    Ok((dates, files))
+}
+
+/// Move the EXIF dates of a JPEG or TIFF-based (RAW) file by `seconds`,
+/// rewriting the 19 characters of each and nothing else. Gives the file's
+/// new size and content hash; `None` if it has no EXIF dates in a form we
+/// can patch.
+fn shift_file_dates(path: &Path, seconds: i64) -> Result<Option<(usize, String)>, Error> {
+    let mut data = fs::read(path)?;
+    let Some(start) = tiff_start(&data) else { return Ok(None) };
+    let locations = Tiff::new(&data[start..])?.ascii_locations(DATE_TAGS);
+    let mut changed = false;
+    for (_, at, len) in locations {
+        let Some(value) = data.get(start + at..start + at + len.min(19)) else { continue };
+        let Some(shifted) = std::str::from_utf8(value).ok().and_then(|date| shift(date, EXIF_FORMAT, seconds)) else { continue };
+        if shifted.len() == value.len() {
+            data[start + at..start + at + shifted.len()].copy_from_slice(shifted.as_bytes());
+            changed = true;
+        }
+    }
+    if !changed {
+        return Ok(None);
+    }
+    let temp = format!("{}.tmp", path.display());
+    fs::write(&temp, &data)?;
+    fs::rename(&temp, path)?;
+    Ok(Some((data.len(), hash::of_bytes(&data))))
+}
+
+/// Keep image `id`'s size and content hash those of its rewritten file, so
+/// `verify` doesn't take the shift for a change made elsewhere.
+fn record_rewrite(conn: &Connection, id: i64, (size, content_hash): (usize, String)) -> Result<(), Error> {
+    conn.execute(
+        "UPDATE images SET file_size = ?1, content_hash = ?2 WHERE id = ?3",
+        params![size as i64, content_hash, id],
+    )?;
+    Ok(())
+}
+
+/// Where the TIFF structure holding the EXIF starts: an APP1 segment of a
+/// JPEG, or the start of a TIFF-based file.
+fn tiff_start(data: &[u8]) -> Option<usize> {
+    if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        return Some(0);
+    }
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut pos = 2;
+    loop {
+        let (0xFF, marker) = (*data.get(pos)?, *data.get(pos + 1)?) else { return None };
+        // The image data starts: no EXIF before it
+        if matches!(marker, 0xD9 | 0xDA) {
+            return None;
+        }
+        let len = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+        if marker == 0xE1 && data.get(pos + 4..)?.starts_with(EXIF_SIGNATURE) {
+            return Some(pos + 4 + EXIF_SIGNATURE.len());
+        }
+        pos += 2 + len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::{In, Reader, Tag};
+    use tempfile::tempdir;
+    use crate::cancel::CancelToken;
+    use crate::config::Config;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_parse_shift() {
+        assert_eq!(parse_shift("+2h"), Ok(7200));
+        assert_eq!(parse_shift("-1h30m"), Ok(-5400));
+        assert_eq!(parse_shift("1d"), Ok(86400));
+        for bad in ["", "+", "-0h", "+2x", "++2h"] {
+            assert!(parse_shift(bad).is_err(), "{}", bad);
+        }
+    }
+
+    fn exif_date(path: &Path) -> Option<String> {
+        let file = fs::read(path).ok()?;
+        let exif = Reader::new().read_from_container(&mut std::io::Cursor::new(file)).ok()?;
+        Some(exif.get_field(Tag::DateTimeOriginal, In::PRIMARY)?.display_value().to_string())
+    }
+
+    #[test]
+    fn test_shift_and_undo() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        for (name, model) in [("vacation.jpg", "X100V"), ("phone.jpg", "Pixel 8")] {
+            let path = dir.path().join(name);
+            fs::write(&path, jpeg_with_exif(&[
+                field(Tag::Model, ascii(model)),
+                field(Tag::DateTimeOriginal, ascii("2023:07:14 23:30:00")),
+            ]))?;
+            crate::save_metadata(&conn, &crate::process_image(&path, &Config::default(), None, &CancelToken::new())?)?;
+        }
+        let vacation = dir.path().join("vacation.jpg");
+        let original = fs::read(&vacation)?;
+
+        let filter = SearchFilter { camera: Some(String::from("X100V")), date: Some(String::from("2023-07")), ..SearchFilter::default() };
+        let changes = plan(&conn, &filter, 7200)?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].old.as_str(), changes[0].new.as_str()), ("2023-07-14 23:30:00", "2023-07-15 01:30:00"));
+
+        let applied = apply(&conn, &changes, 7200, "camera:X100V date:2023-07", true)?;
+        assert_eq!((applied.dates, applied.files, applied.files_skipped), (1, 1, 0));
+        let dates: Vec<String> = conn.prepare("SELECT creation_date FROM images ORDER BY path")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(dates, vec!["2023-07-14 23:30:00", "2023-07-15 01:30:00"]);
+        assert_eq!(exif_date(&vacation).as_deref(), Some("2023-07-15 01:30:00"));
+        assert_eq!(fs::read(&vacation)?.len(), original.len());
+        // The stored hash follows the file, both ways
+        let report = crate::verify::verify(&conn, crate::verify::Rehash::All, &[])?;
+        assert_eq!((report.hashed, report.modified.len()), (2, 0));
+
+        assert_eq!(undo(&conn, applied.shift_id)?, (1, 1));
+        assert_eq!(fs::read(&vacation)?, original);
+        let report = crate::verify::verify(&conn, crate::verify::Rehash::All, &[])?;
+        assert_eq!((report.hashed, report.modified.len()), (2, 0));
+        let date: String = conn.query_row("SELECT creation_date FROM images WHERE path LIKE '%vacation.jpg'", [], |row| row.get(0))?;
+        assert_eq!(date, "2023-07-14 23:30:00");
+        assert!(undo(&conn, applied.shift_id).is_err());
+
+        // Redated after the preview: neither the catalog nor the file moves
+        let changes = plan(&conn, &filter, 7200)?;
+        conn.execute("UPDATE images SET creation_date = '2023-07-14 22:00:00' WHERE path LIKE '%vacation.jpg'", [])?;
+        let applied = apply(&conn, &changes, 7200, "camera:X100V date:2023-07", true)?;
+        assert_eq!((applied.dates, applied.files, applied.files_skipped), (0, 0, 0));
+        assert_eq!(fs::read(&vacation)?, original);
+        Ok(())
+    }
+}
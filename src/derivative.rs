@@ -0,0 +1,178 @@
+//! Metadata on derived images: the downsized copies packed into analysis
+//! batches, and whatever else gets resized or re-encoded for export. What a
+//! derivative carries is decided here, by `[derivatives] metadata`, rather
+//! than by whichever encoder happened to make it:
+//!
+//! - `strip-all` (the default): nothing
+//! - `keep-copyright-only`: the artist and copyright
+//! - `copy-all`: the original's EXIF, less what describes its pixel
+//!   layout, plus its XMP and IPTC
+//!
+//! Derivatives are made upright, so the orientation is never carried over:
+//! applied a second time it would show them sideways.
+
+use std::io::Cursor;
+use anyhow::{bail, Error};
+use exif::{Context, Exif, Field, In, Reader, Tag, Value};
+use serde::Deserialize;
+use crate::jpeg::{self, Segment, APP1, APP13, EXIF_SIGNATURE, XMP_SIGNATURE};
+use crate::{heif, iptc, webp};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetadataPolicy {
+    #[default]
+    StripAll,
+    KeepCopyrightOnly,
+    CopyAll,
+}
+
+/// IFD0 tags worth carrying over; the rest describe the original's layout
+/// (or, in RAW files, the sensor data).
+const COPIED_TIFF_TAGS: &[Tag] = &[
+    Tag::Make,
+    Tag::Model,
+    Tag::DateTime,
+    Tag::Artist,
+    Tag::Copyright,
+    Tag::ImageDescription,
+    Tag::Software,
+];
+const COPYRIGHT_TAGS: &[Tag] = &[Tag::Artist, Tag::Copyright];
+/// Exif IFD tags that would be wrong once the image is resized
+const LAYOUT_EXIF_TAGS: &[Tag] = &[Tag::PixelXDimension, Tag::PixelYDimension];
+/// TIFF tag 700, where TIFF files keep their XMP
+const XMP_TAG: Tag = Tag(Context::Tiff, 700);
+/// Room for the EXIF block in an APP1 segment
+const MAX_EXIF: usize = u16::MAX as usize - 2 - EXIF_SIGNATURE.len();
+
+/// `derivative`, a JPEG made from `original` (in any format we read), with
+/// exactly the metadata `policy` allows. Whatever the encoder put in, other
+/// than the JFIF header, is dropped.
+pub fn apply(policy: MetadataPolicy, original: &[u8], derivative: &[u8]) -> Result<Vec<u8>, Error> {
+    let (segments, image_data) = jpeg::split(derivative)?;
+    // APP1 to APP15 and comments
+    let mut segments: Vec<Segment> = segments.into_iter()
+        .filter(|s| !matches!(s.marker, 0xE1..=0xEF | 0xFE))
+        .collect();
+
+    let exif = Reader::new().read_from_container(&mut Cursor::new(original)).ok();
+    if let Some(exif) = &exif {
+        let fields: Vec<&Field> = exif.fields()
+            .filter(|field| field.ifd_num == In::PRIMARY && allowed(policy, field))
+            .collect();
+        if let Some(tiff) = exif_block(&fields)? {
+            jpeg::upsert(&mut segments, APP1, Segment::is_exif, [EXIF_SIGNATURE, &tiff].concat())?;
+        }
+    }
+
+    if policy == MetadataPolicy::CopyAll {
+        if let Some(packet) = xmp_packet(original, exif.as_ref()) {
+            jpeg::upsert(&mut segments, APP1, Segment::is_xmp, [XMP_SIGNATURE, &packet].concat())?;
+        }
+        if let Some(iim) = iptc::iim(original, exif.as_ref()) {
+            let resources = iptc::replace_resource(&[], iptc::IPTC_RESOURCE_ID, &iim);
+            jpeg::upsert(&mut segments, APP13, Segment::is_photoshop, [iptc::PHOTOSHOP_SIGNATURE, &resources].concat())?;
+        }
+    }
+    Ok(jpeg::join(&segments, image_data))
+}
+
+fn allowed(policy: MetadataPolicy, field: &Field) -> bool {
+    if matches!(field.value, Value::Unknown(..)) {
+        return false;
+    }
+    match policy {
+        MetadataPolicy::StripAll => false,
+        MetadataPolicy::KeepCopyrightOnly => COPYRIGHT_TAGS.contains(&field.tag),
+        MetadataPolicy::CopyAll => match field.tag.context() {
+            Context::Tiff => COPIED_TIFF_TAGS.contains(&field.tag),
+            Context::Exif => !LAYOUT_EXIF_TAGS.contains(&field.tag),
+            Context::Gps => true,
+            _ => false,
+        },
+    }
+}
+
+/// The fields as a TIFF block for an APP1 segment, leaving out the maker
+/// note if that's what makes it too big.
+fn exif_block(fields: &[&Field]) -> Result<Option<Vec<u8>>, Error> {
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    let write = |fields: &mut dyn Iterator<Item = &&Field>| -> Result<Vec<u8>, Error> {
+        let mut writer = exif::experimental::Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false)?;
+        Ok(tiff.into_inner())
+    };
+    let mut tiff = write(&mut fields.iter())?;
+    if tiff.len() > MAX_EXIF {
+        tiff = write(&mut fields.iter().filter(|field| field.tag != Tag::MakerNote))?;
+    }
+    if tiff.len() > MAX_EXIF {
+        bail!("EXIF too large for a JPEG segment ({} bytes)", tiff.len());
+    }
+    Ok(Some(tiff))
+}
+
+/// The original's XMP packet, wherever its format keeps it.
+fn xmp_packet(original: &[u8], exif: Option<&Exif>) -> Option<Vec<u8>> {
+    if original.starts_with(&[0xFF, 0xD8]) {
+        let (segments, _) = jpeg::split(original).ok()?;
+        let segment = segments.into_iter().find(Segment::is_xmp)?;
+        return Some(segment.payload[XMP_SIGNATURE.len()..].to_vec());
+    }
+    if let Some(packet) = webp::chunk(original, b"XMP ").or_else(|| heif::xmp(original)) {
+        return Some(packet.to_vec());
+    }
+    match &exif?.get_field(XMP_TAG, In::PRIMARY)?.value {
+        Value::Byte(packet) | Value::Undefined(packet, _) => Some(packet.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    fn fields_of(jpeg: &[u8]) -> Vec<Tag> {
+        Reader::new().read_from_container(&mut Cursor::new(jpeg))
+            .map(|exif| exif.fields().map(|field| field.tag).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_policies() -> Result<(), Error> {
+        let original = jpeg_with_exif(&[
+            field(Tag::Make, ascii("FUJIFILM")),
+            field(Tag::Orientation, Value::Short(vec![6])),
+            field(Tag::Copyright, ascii("Jo Bloggs")),
+            field(Tag::GPSLatitude, Value::Rational(vec![(38, 1).into(), (42, 1).into(), (0, 1).into()])),
+        ]);
+        // With XMP and IPTC too
+        let original = crate::writeback::embed(&original, Some("A yellow tram"), &[String::from("tram")])?;
+        let derivative = jpeg_with_exif(&[field(Tag::Software, ascii("encoder"))]);
+
+        let stripped = apply(MetadataPolicy::StripAll, &original, &derivative)?;
+        assert!(fields_of(&stripped).is_empty());
+        assert_eq!(image::load_from_memory(&stripped)?.width(), 8);
+
+        let copyright = apply(MetadataPolicy::KeepCopyrightOnly, &original, &derivative)?;
+        assert_eq!(fields_of(&copyright), vec![Tag::Copyright]);
+        assert!(iptc::read(&copyright, None).keywords.is_empty());
+
+        let copied = apply(MetadataPolicy::CopyAll, &original, &derivative)?;
+        let tags = fields_of(&copied);
+        assert!(tags.contains(&Tag::Make) && tags.contains(&Tag::GPSLatitude));
+        assert!(!tags.contains(&Tag::Software) && !tags.contains(&Tag::Orientation));
+        assert_eq!(iptc::read(&copied, None).keywords, vec!["tram"]);
+        let (segments, _) = jpeg::split(&copied)?;
+        assert!(segments.iter().any(Segment::is_xmp));
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+//! Device profiles: rules for the photos from one camera or phone,
+//! configured once under `[[devices]]` and applied as its photos are
+//! cataloged. A profile recognizes its device by any of EXIF make, model
+//! and body serial number (all that are given must match, ignoring case);
+//! when several match, the one naming the most wins.
+//!
+//! A profile can shift capture times (for the camera that never leaves
+//! home time), rename files after a template, add keywords and assign a
+//! storage tier.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Error};
+use serde::Deserialize;
+use crate::camera::CameraInfo;
+use crate::{dates, xmp, ExternalMetadata, ImageMetadata};
+
+/// Where profile keywords are recorded in `external_metadata`
+const SOURCE: &str = "device";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub name: String,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /// Added to capture times, e.g. "-1h" for a clock an hour ahead.
+    pub clock_offset: Option<ClockOffset>,
+    /// New file name, without the extension, e.g. "{date}_{time}_{device}".
+    pub rename: Option<RenameTemplate>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub tier: Option<String>,
+}
+
+impl Profile {
+    /// How many of make, model and serial the profile names, if `camera`
+    /// matches all of them.
+    fn matches(&self, camera: &CameraInfo) -> Option<usize> {
+        let criteria = [(&self.make, &camera.make), (&self.model, &camera.model), (&self.serial, &camera.serial)];
+        let mut named = 0;
+        for (wanted, actual) in criteria {
+            let Some(wanted) = wanted else { continue };
+            if !actual.as_ref().is_some_and(|actual| actual.trim().eq_ignore_ascii_case(wanted.trim())) {
+                return None;
+            }
+            named += 1;
+        }
+        Some(named)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ClockOffset(i64);
+
+impl TryFrom<String> for ClockOffset {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<ClockOffset, String> {
+        dates::parse_shift(&s).map(ClockOffset)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Date,
+    Time,
+    Make,
+    Model,
+    Device,
+    Name,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("date", Field::Date),
+    ("time", Field::Time),
+    ("make", Field::Make),
+    ("model", Field::Model),
+    ("device", Field::Device),
+    ("name", Field::Name),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+/// A file name made of text and `{field}`s: `{date}` (20230714), `{time}`
+/// (101500), `{make}`, `{model}`, `{device}` (the profile name) and
+/// `{name}` (the original name).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RenameTemplate {
+    parts: Vec<Part>,
+}
+
+impl TryFrom<String> for RenameTemplate {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<RenameTemplate, String> {
+        let mut parts = Vec::new();
+        let mut rest = s.as_str();
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| format!("unclosed '{{' in rename template {:?}", s))?;
+            let name = &rest[start + 1..start + end];
+            let field = FIELDS.iter()
+                .find(|(known, _)| *known == name.trim())
+                .map(|(_, field)| *field)
+                .ok_or_else(|| {
+                    let known: Vec<&str> = FIELDS.iter().map(|(name, _)| *name).collect();
+                    format!("unknown rename field {{{}}}; known fields are {}", name, known.join(", "))
+                })?;
+            parts.push(Part::Field(field));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        if parts.iter().any(|part| matches!(part, Part::Text(text) if text.contains(['/', '\\', '}']))) {
+            return Err(format!("rename template {:?} can only name a file, not a directory", s));
+        }
+        if !parts.iter().any(|part| matches!(part, Part::Field(_))) {
+            return Err(format!("rename template {:?} would give every file the same name", s));
+        }
+        Ok(RenameTemplate { parts })
+    }
+}
+
+impl RenameTemplate {
+    /// The new file stem, or `None` if a field it uses has no value.
+    fn render(&self, profile: &str, metadata: &ImageMetadata) -> Option<String> {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => name.push_str(text),
+                Part::Field(field) => {
+                    let date = metadata.creation_date.as_deref();
+                    let value = match field {
+                        // Catalog dates are "2023-07-14 10:15:00"
+                        Field::Date => date.and_then(|date| date.get(..10)).map(|date| date.replace('-', "")),
+                        Field::Time => date.and_then(|date| date.get(11..19)).map(|time| time.replace(':', "")),
+                        Field::Make => metadata.camera.make.clone(),
+                        Field::Model => metadata.camera.model.clone(),
+                        Field::Device => Some(profile.to_string()),
+                        Field::Name => Path::new(&metadata.file_name).file_stem().map(|stem| stem.to_string_lossy().into_owned()),
+                    };
+                    // Nothing from EXIF gets to make a directory
+                    name.push_str(&value.filter(|value| !value.is_empty())?.replace(['/', '\\'], "-"));
+                }
+            }
+        }
+        Some(name)
+    }
+}
+
+/// Check what serde can't: every profile has to recognize something.
+pub fn validate(profiles: &[Profile]) -> Result<(), Error> {
+    for profile in profiles {
+        if profile.make.is_none() && profile.model.is_none() && profile.serial.is_none() {
+            bail!("device profile {:?} needs a make, model or serial to recognize its photos by", profile.name);
+        }
+    }
+    Ok(())
+}
+
+/// The profile for the device that took a photo, if any.
+pub fn matching<'a>(profiles: &'a [Profile], camera: &CameraInfo) -> Option<&'a Profile> {
+    let mut best: Option<(&Profile, usize)> = None;
+    for profile in profiles {
+        if let Some(named) = profile.matches(camera) {
+            // Earlier profiles win ties
+            if best.is_none_or(|(_, best)| named > best) {
+                best = Some((profile, named));
+            }
+        }
+    }
+    best.map(|(profile, _)| profile)
+}
+
+/// Apply the matching profile's rules to a freshly read photo. The clock
+/// offset comes first, so renamed files carry the corrected time.
+pub fn apply(profiles: &[Profile], mut metadata: ImageMetadata) -> Result<ImageMetadata, Error> {
+    let Some(profile) = matching(profiles, &metadata.camera) else { return Ok(metadata) };
+    metadata.device = Some(profile.name.clone());
+    metadata.tier = profile.tier.clone();
+    if let Some(ClockOffset(seconds)) = profile.clock_offset {
+        // Partial dates can't be shifted, and stay as they are
+        metadata.creation_date = metadata.creation_date.map(|date| dates::shifted(&date, seconds).unwrap_or(date));
+    }
+    for tag in &profile.tags {
+        metadata.external.push(ExternalMetadata {
+            source: SOURCE.to_string(),
+            field: String::from("keyword"),
+            value: tag.clone(),
+        });
+    }
+    if let Some(template) = &profile.rename {
+        match template.render(&profile.name, &metadata) {
+            Some(stem) => rename(&mut metadata, &stem)?,
+            None => tracing::warn!("Not renaming {}: the rename template needs a field it doesn't have", metadata.path),
+        }
+    }
+    Ok(metadata)
+}
+
+/// Rename the file (and its XMP sidecar) to `stem`, keeping the extension
+/// and numbering the name if it's taken.
+fn rename(metadata: &mut ImageMetadata, stem: &str) -> Result<(), Error> {
+    let path = PathBuf::from(&metadata.path);
+    let extension = path.extension().map(|ext| ext.to_string_lossy().into_owned());
+    let name = |n: usize| {
+        let stem = if n == 1 { stem.to_string() } else { format!("{}-{}", stem, n) };
+        match &extension {
+            Some(extension) => format!("{}.{}", stem, extension),
+            None => stem,
+        }
+    };
+    let mut n = 1;
+    let target = loop {
+        let target = path.with_file_name(name(n));
+        // Already named (say, by an earlier scan)
+        if target == path || !target.exists() {
+            break target;
+        }
+        n += 1;
+    };
+    if target == path {
+        return Ok(());
+    }
+    let sidecar = xmp::find_sidecar(&path);
+    fs::rename(&path, &target)?;
+    if let Some(sidecar) = sidecar {
+        // photo.jpg.xmp or photo.xmp, whichever it was
+        let sidecar_name = sidecar.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let old_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let new_sidecar = match sidecar_name.strip_prefix(&old_name) {
+            Some(suffix) => target.with_file_name(format!("{}{}", name(n), suffix)),
+            None => target.with_extension(sidecar.extension().unwrap_or_default()),
+        };
+        fs::rename(&sidecar, new_sidecar)?;
+    }
+    metadata.file_name = name(n);
+    metadata.path = target.to_string_lossy().into_owned();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use crate::config::Config;
+
+    fn camera(make: &str, model: &str, serial: Option<&str>) -> CameraInfo {
+        CameraInfo {
+            make: Some(make.to_string()),
+            model: Some(model.to_string()),
+            serial: serial.map(String::from),
+            ..CameraInfo::default()
+        }
+    }
+
+    fn photo(path: &Path) -> ImageMetadata {
+        ImageMetadata {
+            path: path.to_string_lossy().into_owned(),
+            file_name: path.file_name().unwrap().to_string_lossy().into_owned(),
+            ..ImageMetadata::default()
+        }
+    }
+
+    #[test]
+    fn test_matching() -> Result<(), Error> {
+        let config = Config::parse(r#"
+            [[devices]]
+            name = "fuji"
+            make = "FUJIFILM"
+
+            [[devices]]
+            name = "jo-x100v"
+            model = "x100v"
+            serial = "123"
+        "#)?;
+        let name = |camera| matching(&config.devices, &camera).map(|profile| profile.name.as_str());
+        assert_eq!(name(camera("FUJIFILM", "X100V", Some("123"))), Some("jo-x100v"));
+        assert_eq!(name(camera("FUJIFILM", "X100V", Some("456"))), Some("fuji"));
+        assert_eq!(name(camera("Apple", "iPhone 15", None)), None);
+
+        assert!(Config::parse("[[devices]]\nname = \"any\"").is_err());
+        assert!(Config::parse("[[devices]]\nname = \"a\"\nmake = \"b\"\nrename = \"{nonsense}\"").is_err());
+        assert!(Config::parse("[[devices]]\nname = \"a\"\nmake = \"b\"\nrename = \"photos/{date}\"").is_err());
+        assert!(Config::parse("[[devices]]\nname = \"a\"\nmake = \"b\"\nclock_offset = \"soon\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let path = dir.path().join("DSCF0001.JPG");
+        fs::write(&path, b"")?;
+        fs::write(dir.path().join("DSCF0001.JPG.xmp"), b"")?;
+        // Take the first name, so the photo gets the second
+        fs::write(dir.path().join("20230714_091500_DSCF0001.JPG"), b"")?;
+        let config = Config::parse(r#"
+            [[devices]]
+            name = "x100v"
+            model = "X100V"
+            clock_offset = "-1h"
+            rename = "{date}_{time}_{name}"
+            tags = ["fuji"]
+            tier = "archive"
+        "#)?;
+
+        let metadata = ImageMetadata {
+            creation_date: Some(String::from("2023-07-14 10:15:00")),
+            camera: camera("FUJIFILM", "X100V", None),
+            ..photo(&path)
+        };
+        let metadata = apply(&config.devices, metadata)?;
+        assert_eq!(metadata.device.as_deref(), Some("x100v"));
+        assert_eq!(metadata.tier.as_deref(), Some("archive"));
+        assert_eq!(metadata.creation_date.as_deref(), Some("2023-07-14 09:15:00"));
+        assert_eq!(metadata.external[0].value, "fuji");
+        assert_eq!(metadata.file_name, "20230714_091500_DSCF0001-2.JPG");
+        assert!(Path::new(&metadata.path).is_file() && !path.exists());
+        assert!(dir.path().join("20230714_091500_DSCF0001-2.JPG.xmp").is_file());
+
+        // Other devices' photos are left alone
+        let other = ImageMetadata { camera: camera("Apple", "iPhone 15", None), ..photo(&path) };
+        assert_eq!(apply(&config.devices, other)?.device, None);
+        Ok(())
+    }
+}
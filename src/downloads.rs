@@ -0,0 +1,410 @@
+//! Downloading many originals at once from `serve`: a ZIP made in the
+//! background as an operation (followed through `/jobs/{id}` like a scan),
+//! then fetched from `/downloads/{id}` by whoever asked for it, for
+//! `downloads.keep_hours` after it's made.
+//!
+//! Files are stored in the ZIP rather than compressed, since photos and
+//! videos already are, which keeps making one of a few thousand originals
+//! about as slow as copying them. ZIP64 records are added where sizes or
+//! offsets need them, so archives and the files in them can be over 4 GB.
+//! The same photos make the same ZIP, whatever order they were asked for
+//! in: they go in by path, dated as their files are.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::Error;
+use chrono::{DateTime, Datelike, Local, Timelike};
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::config::Config;
+use crate::error::{CliError, ErrorKind};
+use crate::progress::Operation;
+use crate::remote;
+
+/// The kind of operation making one.
+pub const KIND: &str = "download";
+
+/// Record that operation `operation` is making a ZIP of `images` photos
+/// for `user`.
+pub fn record(conn: &Connection, operation: i64, user: &str, images: usize) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO downloads (operation_id, user, images, created_at) VALUES (?1, ?2, ?3, strftime('%s', 'now'))",
+        params![operation, user, images as i64],
+    )?;
+    Ok(())
+}
+
+/// The ZIP download `id` is kept as, once made.
+pub fn file(dir: &Path, id: i64) -> PathBuf {
+    dir.join(format!("{}.zip", id))
+}
+
+/// Make the ZIP of the images with `ids` in `dir`, counting each as done
+/// or, if its original can't be had, failed. Gives how many went in.
+pub fn make(conn: &Connection, operation: &Operation, ids: &[i64], dir: &Path, config: &Config) -> Result<usize, Error> {
+    operation.set_total(conn, ids.len())?;
+    fs::create_dir_all(dir)?;
+    // Only named as the download once it's complete
+    let part = dir.join(format!("{}.zip.part", operation.id));
+    let result = (|| -> Result<usize, Error> {
+        let mut zip = ZipWriter::new(BufWriter::new(File::create(&part)?));
+        let mut names = HashSet::new();
+        let mut stmt = conn.prepare("SELECT path FROM images WHERE id = ?1")?;
+        let mut paths = Vec::new();
+        for id in ids {
+            match stmt.query_row([id], |row| row.get::<_, String>(0)).optional()? {
+                Some(path) => paths.push(path),
+                None => operation.advance(conn, false)?,
+            }
+        }
+        paths.sort();
+        for path in paths {
+            operation.checkpoint(conn)?;
+            let original = match remote::original(Path::new(&path), config).and_then(|original| Ok((File::open(&original)?, original))) {
+                Ok(original) => original,
+                Err(e) => {
+                    tracing::warn!("Leaving {} out of download {}: {}", path, operation.id, e);
+                    operation.advance(conn, false)?;
+                    continue;
+                }
+            };
+            let (mut file, original) = original;
+            // Not the time it's made, which would make every ZIP different
+            let modified = file.metadata()?.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            zip.add(&entry_name(&original, &mut names), modified, &mut file)?;
+            operation.advance(conn, true)?;
+        }
+        let added = zip.entries.len();
+        zip.finish()?.flush()?;
+        Ok(added)
+    })();
+    match result {
+        Ok(added) => {
+            fs::rename(&part, file(dir, operation.id))?;
+            Ok(added)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&part);
+            Err(e)
+        }
+    }
+}
+
+/// The ZIP made for download `id`, if it's `user`'s and ready.
+pub fn ready(conn: &Connection, dir: &Path, id: i64, user: &str) -> Result<PathBuf, Error> {
+    let state: Option<String> = conn
+        .query_row(
+            "SELECT operations.state FROM downloads JOIN operations ON operations.id = downloads.operation_id
+             WHERE operation_id = ?1 AND user = ?2",
+            params![id, user],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let gone = |message: String| -> Error { CliError::new(ErrorKind::NothingToDo, message).into() };
+    match state.as_deref() {
+        None => Err(gone(format!("no download {}", id))),
+        Some("running") => Err(gone(format!("download {} is still being made; /jobs/{} says how far along it is", id, id))),
+        Some("completed") if file(dir, id).is_file() => Ok(file(dir, id)),
+        Some("completed") => Err(gone(format!("download {} is no longer kept", id))),
+        Some(state) => Err(gone(format!("download {} was {}", id, state))),
+    }
+}
+
+/// Delete the ZIPs kept `keep_hours` since they were made, and what's
+/// left of those that failed or were cancelled. Gives how many went.
+pub fn expire(conn: &Connection, dir: &Path, keep_hours: u64) -> Result<usize, Error> {
+    let expired: Vec<i64> = conn
+        .prepare(
+            "SELECT operation_id FROM downloads JOIN operations ON operations.id = downloads.operation_id
+             WHERE (state = 'completed' AND finished_at <= strftime('%s', 'now') - ?1) OR state IN ('failed', 'cancelled')",
+        )?
+        .query_map([(keep_hours * 3600) as i64], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    for id in &expired {
+        for path in [file(dir, *id), dir.join(format!("{}.zip.part", id))] {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        conn.execute("DELETE FROM downloads WHERE operation_id = ?1", [id])?;
+    }
+    Ok(expired.len())
+}
+
+/// The name `path` goes into the ZIP under: its file name, numbered if
+/// another file in it already has that name.
+fn entry_name(path: &Path, names: &mut HashSet<String>) -> String {
+    let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| String::from("photo"));
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem.to_string(), format!(".{}", extension)),
+        _ => (file_name.clone(), String::new()),
+    };
+    let mut name = file_name;
+    let mut copy = 1;
+    // Case-insensitive file systems would have them overwrite each other
+    while !names.insert(name.to_lowercase()) {
+        copy += 1;
+        name = format!("{} ({}){}", stem, copy, extension);
+    }
+    name
+}
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const ZIP64_EXTRA: u16 = 0x0001;
+/// 4.5, the version that brought ZIP64
+const VERSION: u16 = 45;
+/// Names are UTF-8
+const UTF8: u16 = 1 << 11;
+
+struct Entry {
+    name: String,
+    time: u16,
+    date: u16,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+/// A ZIP of stored files, written as they're added.
+struct ZipWriter<W: Write + Seek> {
+    out: W,
+    entries: Vec<Entry>,
+}
+
+impl<W: Write + Seek> ZipWriter<W> {
+    fn new(out: W) -> ZipWriter<W> {
+        ZipWriter { out, entries: Vec::new() }
+    }
+
+    /// Add `reader`'s contents as `name`. The local header always has ZIP64
+    /// sizes, since how big the file is is only known once it's copied;
+    /// they and the CRC are filled in after.
+    fn add(&mut self, name: &str, modified: SystemTime, reader: &mut impl Read) -> io::Result<()> {
+        let offset = self.out.stream_position()?;
+        let (time, date) = dos_time(modified);
+        let mut header = Vec::with_capacity(30 + name.len() + 20);
+        header.extend(LOCAL_HEADER.to_le_bytes());
+        header.extend(VERSION.to_le_bytes());
+        header.extend(UTF8.to_le_bytes());
+        // Stored
+        header.extend(0u16.to_le_bytes());
+        header.extend(time.to_le_bytes());
+        header.extend(date.to_le_bytes());
+        // CRC, and sizes in the ZIP64 extra field
+        header.extend(0u32.to_le_bytes());
+        header.extend(u32::MAX.to_le_bytes());
+        header.extend(u32::MAX.to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend(20u16.to_le_bytes());
+        header.extend(name.as_bytes());
+        header.extend(ZIP64_EXTRA.to_le_bytes());
+        header.extend(16u16.to_le_bytes());
+        header.extend([0; 16]);
+        self.out.write_all(&header)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size = 0u64;
+        let mut buffer = vec![0; 64 << 10];
+        loop {
+            let n = reader.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            self.out.write_all(&buffer[..n])?;
+            size += n as u64;
+        }
+        let crc = hasher.finalize();
+        let end = self.out.stream_position()?;
+        self.out.seek(SeekFrom::Start(offset + 14))?;
+        self.out.write_all(&crc.to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(offset + 30 + name.len() as u64 + 4))?;
+        self.out.write_all(&size.to_le_bytes())?;
+        self.out.write_all(&size.to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.entries.push(Entry { name: name.to_string(), time, date, crc, size, offset });
+        Ok(())
+    }
+
+    /// Write the central directory, giving back what was written to.
+    fn finish(mut self) -> io::Result<W> {
+        let start = self.out.stream_position()?;
+        for entry in &self.entries {
+            let big = entry.size >= u32::MAX as u64;
+            let far = entry.offset >= u32::MAX as u64;
+            let mut extra = Vec::new();
+            if big {
+                extra.extend(entry.size.to_le_bytes());
+                extra.extend(entry.size.to_le_bytes());
+            }
+            if far {
+                extra.extend(entry.offset.to_le_bytes());
+            }
+            let mut header = Vec::with_capacity(46 + entry.name.len() + 4 + extra.len());
+            header.extend(CENTRAL_HEADER.to_le_bytes());
+            header.extend(VERSION.to_le_bytes());
+            header.extend(VERSION.to_le_bytes());
+            header.extend(UTF8.to_le_bytes());
+            header.extend(0u16.to_le_bytes());
+            header.extend(entry.time.to_le_bytes());
+            header.extend(entry.date.to_le_bytes());
+            header.extend(entry.crc.to_le_bytes());
+            let size = if big { u32::MAX } else { entry.size as u32 };
+            header.extend(size.to_le_bytes());
+            header.extend(size.to_le_bytes());
+            header.extend((entry.name.len() as u16).to_le_bytes());
+            header.extend((if extra.is_empty() { 0 } else { 4 + extra.len() as u16 }).to_le_bytes());
+            // Comment, disk, internal and external attributes
+            header.extend([0; 10]);
+            header.extend((if far { u32::MAX } else { entry.offset as u32 }).to_le_bytes());
+            header.extend(entry.name.as_bytes());
+            if !extra.is_empty() {
+                header.extend(ZIP64_EXTRA.to_le_bytes());
+                header.extend((extra.len() as u16).to_le_bytes());
+                header.extend(extra);
+            }
+            self.out.write_all(&header)?;
+        }
+        let end = self.out.stream_position()?;
+        let (count, size) = (self.entries.len() as u64, end - start);
+
+        let mut trailer = Vec::new();
+        let zip64 = count >= u16::MAX as u64 || size >= u32::MAX as u64 || start >= u32::MAX as u64;
+        if zip64 {
+            trailer.extend(ZIP64_END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+            trailer.extend(44u64.to_le_bytes());
+            trailer.extend(VERSION.to_le_bytes());
+            trailer.extend(VERSION.to_le_bytes());
+            trailer.extend([0; 8]);
+            trailer.extend(count.to_le_bytes());
+            trailer.extend(count.to_le_bytes());
+            trailer.extend(size.to_le_bytes());
+            trailer.extend(start.to_le_bytes());
+            trailer.extend(ZIP64_LOCATOR.to_le_bytes());
+            trailer.extend(0u32.to_le_bytes());
+            trailer.extend(end.to_le_bytes());
+            trailer.extend(1u32.to_le_bytes());
+        }
+        trailer.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        trailer.extend([0; 4]);
+        let count = if zip64 { u16::MAX } else { count as u16 };
+        trailer.extend(count.to_le_bytes());
+        trailer.extend(count.to_le_bytes());
+        trailer.extend((if zip64 { u32::MAX } else { size as u32 }).to_le_bytes());
+        trailer.extend((if zip64 { u32::MAX } else { start as u32 }).to_le_bytes());
+        trailer.extend(0u16.to_le_bytes());
+        self.out.write_all(&trailer)?;
+        Ok(self.out)
+    }
+}
+
+/// MS-DOS time and date, local and to two seconds, as ZIP has them; 1980,
+/// when they start, for anything earlier.
+fn dos_time(time: SystemTime) -> (u16, u16) {
+    let time: DateTime<Local> = time.into();
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let dos_time = ((time.hour() << 11) | (time.minute() << 5) | (time.second() / 2)) as u16;
+    let dos_date = ((((time.year() - 1980) as u32) << 9) | (time.month() << 5) | time.day()) as u16;
+    (dos_time, dos_date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::cancel::CancelToken;
+    use crate::progress;
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_zip() -> Result<(), Error> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.add("beach.jpg", SystemTime::UNIX_EPOCH, &mut &b"sand"[..])?;
+        zip.add("café.jpg", SystemTime::now(), &mut &b"coffee"[..])?;
+        let bytes = zip.finish()?.into_inner();
+
+        assert_eq!(u32_at(&bytes, 0), LOCAL_HEADER);
+        assert_eq!(u32_at(&bytes, 14), crc32fast::hash(b"sand"));
+        assert_eq!(&bytes[30..39], b"beach.jpg");
+        // Sizes in the ZIP64 extra field, then the file
+        assert_eq!(u64::from_le_bytes(bytes[43..51].try_into()?), 4);
+        assert_eq!(&bytes[59..63], b"sand");
+        // The end record says where the directory is, and how many it lists
+        let end = bytes.len() - 22;
+        assert_eq!(u32_at(&bytes, end), END_OF_CENTRAL_DIRECTORY);
+        assert_eq!(u16_at(&bytes, end + 10), 2);
+        let directory = u32_at(&bytes, end + 16) as usize;
+        assert_eq!(u32_at(&bytes, directory), CENTRAL_HEADER);
+        assert_eq!((u32_at(&bytes, directory + 20), u32_at(&bytes, directory + 24)), (4, 4));
+        assert_eq!(u32_at(&bytes, directory + 42), 0);
+        let second = directory + 46 + "beach.jpg".len();
+        assert_eq!(&bytes[second + 46..second + 46 + "café.jpg".len()], "café.jpg".as_bytes());
+        assert_eq!(u32_at(&bytes, second + 42) as usize, 63);
+        assert_eq!(dos_time(SystemTime::UNIX_EPOCH), (0, 33));
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_name() {
+        let mut names = HashSet::new();
+        assert_eq!(entry_name(Path::new("/a/IMG_1.jpg"), &mut names), "IMG_1.jpg");
+        assert_eq!(entry_name(Path::new("/b/img_1.JPG"), &mut names), "img_1 (2).JPG");
+        assert_eq!(entry_name(Path::new("/c/IMG_1.jpg"), &mut names), "IMG_1 (3).jpg");
+        assert_eq!(entry_name(Path::new("/c/README"), &mut names), "README");
+    }
+
+    #[test]
+    fn test_make() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        for (name, contents) in [("a.jpg", "first"), ("b.jpg", "second")] {
+            fs::write(dir.path().join(name), contents)?;
+        }
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size) VALUES (?1, 'a.jpg', 5), (?2, 'b.jpg', 6), ('/gone.jpg', 'gone.jpg', 1)",
+            params![dir.path().join("a.jpg").to_string_lossy(), dir.path().join("b.jpg").to_string_lossy()],
+        )?;
+        let downloads = dir.path().join("downloads");
+        let operation = progress::start(&conn, KIND, CancelToken::new())?;
+        record(&conn, operation.id, "ana", 3)?;
+        assert!(ready(&conn, &downloads, operation.id, "ana").is_err());
+
+        let result = make(&conn, &operation, &[1, 2, 3], &downloads, &Config::default());
+        assert_eq!(result.as_ref().ok(), Some(&2));
+        operation.finish(&conn, &result)?;
+        let status = progress::get(&conn, operation.id)?.unwrap();
+        assert_eq!((status.done, status.failed), (2, 1));
+        let zip = ready(&conn, &downloads, operation.id, "ana")?;
+        assert_eq!(zip, file(&downloads, operation.id));
+        assert!(fs::read(&zip)?.windows(6).any(|window| window == b"second"));
+        // The same photos asked for the other way round are the same ZIP
+        let again = progress::start(&conn, KIND, CancelToken::new())?;
+        make(&conn, &again, &[3, 2, 1], &downloads, &Config::default())?;
+        assert_eq!(fs::read(&zip)?, fs::read(file(&downloads, again.id))?);
+        // Only for the one who asked
+        assert!(ready(&conn, &downloads, operation.id, "rui").is_err());
+
+        assert_eq!(expire(&conn, &downloads, 1)?, 0);
+        conn.execute("UPDATE operations SET finished_at = finished_at - 7200", [])?;
+        assert_eq!(expire(&conn, &downloads, 1)?, 1);
+        assert!(!zip.exists() && ready(&conn, &downloads, operation.id, "ana").is_err());
+        Ok(())
+    }
+}
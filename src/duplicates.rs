@@ -0,0 +1,385 @@
+//! Near-duplicates: the same picture in more than one file, such as a
+//! phone's Display P3 original and the sRGB JPEG exported from it, a copy
+//! brightened in an editor, or a crop. Pixels are only compared once both
+//! are in the same terms: copies are turned upright, taken through their
+//! embedded ICC profiles to linear sRGB, lined up where one is a crop of
+//! the other, and scaled to the same mean brightness, so color space,
+//! exposure and framing don't count as differences in content and what's
+//! left is what's in the picture.
+//!
+//! What's left can be drawn as a heatmap over the part the two share, to
+//! show where an edit changed things when choosing which copy to keep.
+
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use anyhow::Error;
+use image::codecs::{jpeg::JpegDecoder, png::PngDecoder, tiff::TiffDecoder, webp::WebPDecoder};
+use image::{DynamicImage, ImageDecoder, ImageFormat, Rgb, RgbImage};
+use crate::config::Config;
+use crate::icc::{self, Profile};
+use crate::{jobs, orientation, video};
+
+/// Longest edge of the copies `compare` works on, in pixels
+pub const COMPARE_EDGE: u32 = 256;
+/// Width and height of the grid compared
+const GRID: u32 = 64;
+/// Median linear luminance both sides are scaled to
+const TARGET_LUMINANCE: f64 = 0.18;
+/// Mean difference per channel, as a fraction of full scale, below which
+/// two copies are the same picture. Recompression stays well under it; a
+/// different frame of a burst doesn't.
+pub const SAME_PICTURE: f64 = 0.04;
+/// The smallest part of the other copy a crop is looked for as, by width
+const MIN_CROP: f64 = 0.6;
+/// Longest edge of the copies crops are first looked for on
+const SEARCH_EDGE: u32 = 48;
+/// Difference per pixel that shows as fully hot in heatmaps
+const HOT: f64 = 0.3;
+
+/// An image in linear sRGB.
+pub struct Frame {
+    /// The name of the color space it came in
+    pub color_space: String,
+    width: u32,
+    height: u32,
+    pixels: Vec<[f64; 3]>,
+}
+
+/// Part of a frame, in fractions of its width and height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Region {
+    pub const WHOLE: Region = Region { x: 0.0, y: 0.0, width: 1.0, height: 1.0 };
+
+    pub fn area(&self) -> f64 {
+        self.width * self.height
+    }
+}
+
+/// The parts of two frames that show the same thing, and how different
+/// they look.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Alignment {
+    pub a: Region,
+    pub b: Region,
+    /// The mean difference per channel once both are encoded as sRGB, from
+    /// 0 (identical) to 1
+    pub difference: f64,
+}
+
+impl Frame {
+    fn pixel(&self, x: u32, y: u32) -> [f64; 3] {
+        self.pixels[(y.min(self.height - 1) * self.width + x.min(self.width - 1)) as usize]
+    }
+
+    /// Linear light at (`x`, `y`) in fractions of the frame, interpolated.
+    fn sample(&self, x: f64, y: f64) -> [f64; 3] {
+        let (x, y) = ((x * self.width as f64 - 0.5).max(0.0), (y * self.height as f64 - 0.5).max(0.0));
+        let (x0, y0, fx, fy) = (x.floor() as u32, y.floor() as u32, x.fract(), y.fract());
+        let (top_left, top_right) = (self.pixel(x0, y0), self.pixel(x0 + 1, y0));
+        let (bottom_left, bottom_right) = (self.pixel(x0, y0 + 1), self.pixel(x0 + 1, y0 + 1));
+        [0, 1, 2].map(|i| {
+            let top = top_left[i] + (top_right[i] - top_left[i]) * fx;
+            let bottom = bottom_left[i] + (bottom_right[i] - bottom_left[i]) * fx;
+            top + (bottom - top) * fy
+        })
+    }
+
+    /// A copy no bigger than `max_edge`, for looking over quickly.
+    fn shrink(&self, max_edge: u32) -> Frame {
+        let scale = (max_edge as f64 / self.width.max(self.height) as f64).min(1.0);
+        let (width, height) = (((self.width as f64 * scale).round() as u32).max(1), ((self.height as f64 * scale).round() as u32).max(1));
+        Frame { color_space: self.color_space.clone(), width, height, pixels: self.grid(Region::WHOLE, width, height) }
+    }
+
+    /// `region` as `width` by `height` pixels, each the average of the
+    /// part it covers, brought to the target brightness.
+    fn grid(&self, region: Region, width: u32, height: u32) -> Vec<[f64; 3]> {
+        // Samples per output pixel, each way
+        let n = ((region.width * self.width as f64 / width as f64).ceil() as u32).clamp(1, 8);
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for row in 0..height {
+            for column in 0..width {
+                let mut sum = [0.0; 3];
+                for (i, j) in (0..n).flat_map(|i| (0..n).map(move |j| (i, j))) {
+                    let x = region.x + region.width * (column as f64 + (i as f64 + 0.5) / n as f64) / width as f64;
+                    let y = region.y + region.height * (row as f64 + (j as f64 + 0.5) / n as f64) / height as f64;
+                    let value = self.sample(x, y);
+                    sum = [0, 1, 2].map(|c| sum[c] + value[c]);
+                }
+                pixels.push(sum.map(|v| v / (n * n) as f64));
+            }
+        }
+        expose(&mut pixels);
+        pixels
+    }
+}
+
+/// Scale `pixels` to the target brightness, as a change of exposure
+/// would. Brightness is the median, so that an edit to part of a copy
+/// doesn't throw the rest of it out.
+fn expose(pixels: &mut [[f64; 3]]) {
+    let mut luminance: Vec<f64> = pixels.iter().map(|[r, g, b]| 0.2126 * r + 0.7152 * g + 0.0722 * b).collect();
+    luminance.sort_by(f64::total_cmp);
+    match luminance.get(luminance.len() / 2) {
+        Some(&median) if median > 0.0 => {
+            let gain = TARGET_LUMINANCE / median;
+            for pixel in pixels {
+                *pixel = pixel.map(|v| v * gain);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The image in `data` and its embedded ICC profile, for the formats that
+/// can carry one.
+fn decode(data: &[u8]) -> Option<(DynamicImage, Option<Vec<u8>>)> {
+    fn with_profile<'a>(mut decoder: impl ImageDecoder<'a>) -> Option<(DynamicImage, Option<Vec<u8>>)> {
+        let profile = decoder.icc_profile();
+        Some((DynamicImage::from_decoder(decoder).ok()?, profile))
+    }
+    let cursor = Cursor::new(data);
+    match image::guess_format(data).ok()? {
+        ImageFormat::Jpeg => with_profile(JpegDecoder::new(cursor).ok()?),
+        ImageFormat::Png => with_profile(PngDecoder::new(cursor).ok()?),
+        ImageFormat::WebP => with_profile(WebPDecoder::new(cursor).ok()?),
+        ImageFormat::Tiff => with_profile(TiffDecoder::new(cursor).ok()?),
+        _ => None,
+    }
+}
+
+/// `img`, no bigger than `max_edge`, taken through `profile` to linear sRGB.
+pub fn normalize(img: &DynamicImage, profile: &Profile, max_edge: u32) -> Frame {
+    let small = img.thumbnail(max_edge, max_edge).to_rgb8();
+    let to_srgb = profile.to_srgb();
+    Frame {
+        color_space: profile.name.clone(),
+        width: small.width().max(1),
+        height: small.height().max(1),
+        pixels: small.pixels().map(|pixel| profile.linear_srgb(&to_srgb, pixel.0)).collect(),
+    }
+}
+
+/// The image at `path`, normalized to `max_edge`. Formats without profiles
+/// we can read (RAW, HEIF, videos' frames) are taken as the analyzer is
+/// sent them, in sRGB.
+pub fn load(path: &Path, config: &Config, max_edge: u32) -> Result<Frame, Error> {
+    // A video is too big to read for a frame ffmpeg takes from it anyway
+    let original = if video::is_video(path) { Vec::new() } else { fs::read(path)? };
+    let (img, profile) = match decode(&original) {
+        Some((img, profile)) => (orientation::upright(img, orientation::read_from(&original).unwrap_or(1)), profile),
+        None => (image::load_from_memory(&jobs::derivative(path, config, max_edge)?)?, None),
+    };
+    let profile = profile.as_deref().and_then(Profile::parse).unwrap_or_else(Profile::srgb);
+    Ok(normalize(&img, &profile, max_edge))
+}
+
+/// The mean difference per channel of two grids, encoded as sRGB.
+fn difference(a: &[[f64; 3]], b: &[[f64; 3]]) -> f64 {
+    let total: f64 = a.iter().zip(b).map(|(a, b)| heat(a, b)).sum();
+    total / a.len().max(1) as f64
+}
+
+fn heat(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    (0..3).map(|i| (icc::encode_srgb(a[i]) - icc::encode_srgb(b[i])).abs()).sum::<f64>() / 3.0
+}
+
+/// The parts of `big` the shape of `small`, at sizes from the whole of it
+/// down to `MIN_CROP`, in `steps` positions each way.
+fn crops(big: &Frame, small: &Frame, around: Option<(Region, f64)>, steps: u32) -> Vec<Region> {
+    // Width over height of the crop, in fractions of `big`
+    let aspect = (small.width as f64 / small.height as f64) / (big.width as f64 / big.height as f64);
+    let (full_width, full_height) = if aspect >= 1.0 { (1.0, 1.0 / aspect) } else { (aspect, 1.0) };
+    let mut regions = Vec::new();
+    for scale_step in 0..=steps {
+        let scale = match around {
+            Some((region, spread)) => region.width / full_width + spread * (scale_step as f64 / steps as f64 - 0.5),
+            None => 1.0 - (1.0 - MIN_CROP) * scale_step as f64 / steps as f64,
+        };
+        let (width, height) = (full_width * scale.clamp(MIN_CROP, 1.0), full_height * scale.clamp(MIN_CROP, 1.0));
+        for (i, j) in (0..=steps).flat_map(|i| (0..=steps).map(move |j| (i, j))) {
+            let (x, y) = match around {
+                Some((region, spread)) => (
+                    region.x + spread * (i as f64 / steps as f64 - 0.5),
+                    region.y + spread * (j as f64 / steps as f64 - 0.5),
+                ),
+                None => ((1.0 - width) * i as f64 / steps as f64, (1.0 - height) * j as f64 / steps as f64),
+            };
+            regions.push(Region { x: x.clamp(0.0, 1.0 - width), y: y.clamp(0.0, 1.0 - height), width, height });
+        }
+    }
+    regions
+}
+
+/// Where `small` fits best in `big`.
+fn best_crop(big: &Frame, small: &Frame) -> Region {
+    let score = |big: &Frame, small: &Frame, regions: Vec<Region>, size: u32| {
+        let target = small.grid(Region::WHOLE, size, size);
+        regions.into_iter()
+            .map(|region| (region, difference(&big.grid(region, size, size), &target)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(Region::WHOLE, |(region, _)| region)
+    };
+    // Roughly over all of it on small copies, then closely around the best
+    // place found
+    let (big_copy, small_copy) = (big.shrink(SEARCH_EDGE), small.shrink(SEARCH_EDGE));
+    let coarse = score(&big_copy, &small_copy, crops(big, small, None, 8), 16);
+    let (big_copy, small_copy) = (big.shrink(SEARCH_EDGE * 2), small.shrink(SEARCH_EDGE * 2));
+    score(&big_copy, &small_copy, crops(big, small, Some((coarse, 0.1)), 4), 32)
+}
+
+/// Line `a` and `b` up, if one is a crop of the other, and compare them.
+pub fn compare(a: &Frame, b: &Frame) -> Alignment {
+    let whole = difference(&a.grid(Region::WHOLE, GRID, GRID), &b.grid(Region::WHOLE, GRID, GRID));
+    let mut best = Alignment { a: Region::WHOLE, b: Region::WHOLE, difference: whole };
+    // Copies that match whole aren't crops, however much an edit to one
+    // corner makes leaving it out look better
+    if whole < SAME_PICTURE {
+        return best;
+    }
+    let (in_a, in_b) = (best_crop(a, b), best_crop(b, a));
+    for (region_a, region_b) in [(in_a, Region::WHOLE), (Region::WHOLE, in_b)] {
+        let difference = difference(&a.grid(region_a, GRID, GRID), &b.grid(region_b, GRID, GRID));
+        if difference < best.difference {
+            best = Alignment { a: region_a, b: region_b, difference };
+        }
+    }
+    best
+}
+
+/// Where `a` and `b` differ, over the part they share, `max_edge` pixels
+/// on its longest edge: `b` in grey, reddening and then yellowing where
+/// they differ.
+pub fn heatmap(a: &Frame, b: &Frame, alignment: &Alignment, max_edge: u32) -> RgbImage {
+    let (width, height) = (alignment.b.width * b.width as f64, alignment.b.height * b.height as f64);
+    let scale = max_edge as f64 / width.max(height);
+    let (width, height) = (((width * scale).round() as u32).max(1), ((height * scale).round() as u32).max(1));
+    let (first, second) = (a.grid(alignment.a, width, height), b.grid(alignment.b, width, height));
+    let pixels = first.iter().zip(&second).map(|(a, b)| {
+        let t = (heat(a, b) / HOT).min(1.0);
+        let grey = 0.6 * icc::encode_srgb(0.2126 * b[0] + 0.7152 * b[1] + 0.0722 * b[2]);
+        let hot = [1.0, t, 0.0];
+        hot.map(|channel| (((grey * (1.0 - t) + channel * t) * 255.0).round()) as u8)
+    });
+    RgbImage::from_fn(width, height, {
+        let pixels: Vec<[u8; 3]> = pixels.collect();
+        move |x, y| Rgb(pixels[(y * width + x) as usize])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg;
+
+    /// Blocks of saturated color, which color spaces disagree on most.
+    fn blocks(flip: bool) -> RgbImage {
+        let colors = [[220, 20, 30], [30, 200, 40], [20, 40, 220], [240, 220, 30]];
+        RgbImage::from_fn(128, 96, |x, y| {
+            let block = (x / 64 + 2 * (y / 48)) as usize;
+            Rgb(colors[if flip { 3 - block } else { block }])
+        })
+    }
+
+    /// `img` as a JPEG, with `profile` embedded if given.
+    fn jpeg_file(img: &RgbImage, profile: Option<Vec<u8>>, dir: &Path, name: &str) -> Result<std::path::PathBuf, Error> {
+        let mut data = Vec::new();
+        img.write_to(&mut Cursor::new(&mut data), ImageFormat::Jpeg)?;
+        if let Some(profile) = profile {
+            let (mut segments, image_data) = jpeg::split(&data)?;
+            let payload = [&b"ICC_PROFILE\0\x01\x01"[..], &profile].concat();
+            jpeg::upsert(&mut segments, 0xE2, |_| false, payload)?;
+            data = jpeg::join(&segments, image_data);
+        }
+        let path = dir.join(name);
+        fs::write(&path, data)?;
+        Ok(path)
+    }
+
+    /// `img`'s colors, with each pixel's linear light scaled by `gain`, as
+    /// `profile` encodes them.
+    fn convert(img: &RgbImage, profile: &Profile, gain: f64) -> RgbImage {
+        let from_srgb = icc::invert(&profile.to_srgb());
+        let srgb = Profile::srgb();
+        let to_linear = srgb.to_srgb();
+        RgbImage::from_fn(img.width(), img.height(), |x, y| {
+            let linear = srgb.linear_srgb(&to_linear, img.get_pixel(x, y).0).map(|v| v * gain);
+            let converted = [0, 1, 2].map(|row| (0..3).map(|i| from_srgb[row][i] * linear[i]).sum::<f64>());
+            Rgb(converted.map(|v| (icc::encode_srgb(v) * 255.0).round() as u8))
+        })
+    }
+
+    /// Smooth shapes all over, so every crop of it is different.
+    fn scene() -> RgbImage {
+        RgbImage::from_fn(160, 120, |x, y| {
+            let (x, y) = (x as f64, y as f64);
+            Rgb([
+                (128.0 + 100.0 * (x / 9.0).sin()) as u8,
+                (128.0 + 100.0 * (y / 7.0).cos()) as u8,
+                (128.0 + 100.0 * ((x + y) / 13.0).sin()) as u8,
+            ])
+        })
+    }
+
+    #[test]
+    fn test_color_spaces_and_exposure() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let config = Config::default();
+        let p3_profile = crate::test_support::display_p3_profile();
+        let p3 = Profile::parse(&p3_profile).unwrap();
+        let load = |path: std::path::PathBuf| load(&path, &config, COMPARE_EDGE);
+
+        let export = load(jpeg_file(&blocks(false), None, dir.path(), "export.jpg")?)?;
+        let original = load(jpeg_file(&convert(&blocks(false), &p3, 1.0), Some(p3_profile), dir.path(), "original.jpg")?)?;
+        assert_eq!((export.color_space.as_str(), original.color_space.as_str()), ("sRGB", "Display P3"));
+        let same = compare(&export, &original);
+        assert!(same.difference < SAME_PICTURE, "{:?}", same);
+        assert_eq!((same.a, same.b), (Region::WHOLE, Region::WHOLE));
+        // Read as if it were sRGB, the P3 original looks like another picture
+        let unmanaged = normalize(&image::open(dir.path().join("original.jpg"))?, &Profile::srgb(), COMPARE_EDGE);
+        assert!(compare(&export, &unmanaged).difference > SAME_PICTURE);
+
+        let darker = load(jpeg_file(&convert(&blocks(false), &Profile::srgb(), 0.5), None, dir.path(), "darker.jpg")?)?;
+        assert!(compare(&export, &darker).difference < SAME_PICTURE);
+
+        let other = load(jpeg_file(&blocks(true), None, dir.path(), "other.jpg")?)?;
+        assert!(compare(&export, &other).difference > 0.2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_crop_and_heatmap() {
+        let whole = scene();
+        let frame = |img: &RgbImage| normalize(&DynamicImage::ImageRgb8(img.clone()), &Profile::srgb(), COMPARE_EDGE);
+        // The middle part, 75% of the width
+        let crop = image::imageops::crop_imm(&whole, 20, 15, 120, 90).to_image();
+        let alignment = compare(&frame(&crop), &frame(&whole));
+        assert!(alignment.difference < SAME_PICTURE, "{:?}", alignment);
+        assert_eq!(alignment.a, Region::WHOLE);
+        assert!((alignment.b.width - 0.75).abs() < 0.03 && (alignment.b.x - 0.125).abs() < 0.03, "{:?}", alignment.b);
+
+        // An edit shows up where it was made
+        let mut edited = whole.clone();
+        for (x, y, pixel) in edited.enumerate_pixels_mut() {
+            if x < 30 && y < 30 {
+                *pixel = Rgb([255, 255, 255]);
+            }
+        }
+        let (a, b) = (frame(&whole), frame(&edited));
+        let alignment = compare(&a, &b);
+        let map = heatmap(&a, &b, &alignment, 80);
+        assert_eq!(map.dimensions(), (80, 60));
+        let (hot, cold) = (map.get_pixel(5, 5), map.get_pixel(60, 45));
+        assert!(hot[0] > 200 && hot[2] < 100, "{:?}", hot);
+        // Grey, or nearly, where nothing changed
+        assert!(cold[0] < 160 && cold[0].abs_diff(cold[2]) < 30, "{:?}", cold);
+    }
+}
@@ -0,0 +1,129 @@
+//! Edits made by hand, from the command line, `browse` or the HTTP API,
+//! any of which may be at it at once. Each photo has a version, counted up
+//! by every edit, and every edit is logged with the values it replaced. An
+//! editor that says which version it was looking at (the API and `browse`
+//! do) and finds the photo edited since still wins, as the last writer,
+//! but the edit is logged as a conflict, so the edit it overwrote can be
+//! found with `conflicts` and put back.
+
+use anyhow::Error;
+use rusqlite::{params, Connection};
+
+/// Who's making an edit, and the version of the photo they had in front
+/// of them, if they know it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Editor<'a> {
+    /// "cli", "browse", "web", "tag" or "import"
+    pub source: &'a str,
+    pub seen: Option<i64>,
+}
+
+impl Editor<'_> {
+    /// `correct`, which edits blind.
+    pub const CLI: Editor<'static> = Editor { source: "cli", seen: None };
+}
+
+/// A photo's description and keywords.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Caption {
+    pub description: Option<String>,
+    pub keywords: Option<String>,
+}
+
+/// The outcome of an edit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edited {
+    /// The photo's version now
+    pub version: i64,
+    /// Whether it overwrote an edit the editor hadn't seen
+    pub conflict: bool,
+}
+
+/// Log the edit of image `id` from `before` (at `version`) to `after`,
+/// counting up its version. Called inside the edit's transaction.
+pub fn log(tx: &Connection, id: i64, editor: &Editor, version: i64, before: &Caption, after: &Caption) -> rusqlite::Result<Edited> {
+    let conflict = editor.seen.is_some_and(|seen| seen < version);
+    tx.execute("UPDATE images SET version = ?1 WHERE id = ?2", params![version + 1, id])?;
+    tx.execute(
+        "INSERT INTO edits (image_id, version, seen, source, description, keywords, previous_description, previous_keywords, conflict, edited_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            id, version + 1, editor.seen, editor.source, after.description, after.keywords,
+            before.description, before.keywords, conflict, chrono::Utc::now().to_rfc3339(),
+        ],
+    )?;
+    Ok(Edited { version: version + 1, conflict })
+}
+
+/// An edit that overwrote another its editor hadn't seen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub path: String,
+    pub edited_at: String,
+    pub source: String,
+    pub seen: i64,
+    pub version: i64,
+    /// Who made the edit overwritten, if it's in the log
+    pub overwrote: Option<String>,
+    /// What the photo had before, and what it has from this edit
+    pub lost: Caption,
+    pub kept: Caption,
+}
+
+/// The conflicts logged, latest first.
+pub fn conflicts(conn: &Connection) -> Result<Vec<Conflict>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT i.path, e.edited_at, e.source, e.seen, e.version,
+                (SELECT source FROM edits previous WHERE previous.image_id = e.image_id AND previous.version = e.version - 1),
+                e.previous_description, e.previous_keywords, e.description, e.keywords
+         FROM edits e JOIN images i ON i.id = e.image_id
+         WHERE e.conflict
+         ORDER BY e.id DESC",
+    )?;
+    let conflicts = stmt.query_map([], |row| {
+        Ok(Conflict {
+            path: row.get(0)?,
+            edited_at: row.get(1)?,
+            source: row.get(2)?,
+            seen: row.get(3)?,
+            version: row.get(4)?,
+            overwrote: row.get(5)?,
+            lost: Caption { description: row.get(6)?, keywords: row.get(7)? },
+            kept: Caption { description: row.get(8)?, keywords: row.get(9)? },
+        })
+    })?.collect::<Result<_, _>>()?;
+    Ok(conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corrections::correct;
+
+    #[test]
+    fn test_conflicts() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute("INSERT INTO images (path, file_name, file_size) VALUES ('/tram.jpg', 'tram.jpg', 1)", [])?;
+        crate::test_support::set_keywords(&conn, &[("/tram.jpg", "tram")]);
+
+        // The web page and the terminal both load version 0
+        let web = Editor { source: "web", seen: Some(0) };
+        let browse = Editor { source: "browse", seen: Some(0) };
+        assert_eq!(correct(&conn, "/tram.jpg", None, Some("tram, lisbon"), &web)?, Edited { version: 1, conflict: false });
+        assert_eq!(correct(&conn, "/tram.jpg", None, Some("tram, yellow"), &browse)?, Edited { version: 2, conflict: true });
+        // Blind edits aren't checked
+        assert_eq!(correct(&conn, "/tram.jpg", Some("Tram 28"), None, &Editor::CLI)?, Edited { version: 3, conflict: false });
+
+        let conflicts = conflicts(&conn)?;
+        assert_eq!(conflicts.len(), 1);
+        let conflict = &conflicts[0];
+        assert_eq!((conflict.source.as_str(), conflict.seen, conflict.version), ("browse", 0, 2));
+        assert_eq!(conflict.overwrote.as_deref(), Some("web"));
+        assert_eq!(conflict.lost.keywords.as_deref(), Some("tram, lisbon"));
+        assert_eq!(conflict.kept.keywords.as_deref(), Some("tram, yellow"));
+        let edits: i64 = conn.query_row("SELECT COUNT(*) FROM edits", [], |row| row.get(0))?;
+        assert_eq!(edits, 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//! Error categories with stable exit codes, so scripts wrapping the CLI can
+//! tell what happened without parsing messages.
+
+use std::fmt;
+use std::process::ExitCode;
+use anyhow::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// Anything not covered below
+    Internal,
+    /// Invalid or unreadable configuration, or a configuration that the
+    /// requested mode refuses
+    Config,
+    /// No analysis backend could be reached
+    BackendUnreachable,
+    /// The command ran but some items failed
+    PartialFailure,
+    /// There was nothing to process
+    NothingToDo,
+    Database,
+    Io,
+    /// Stopped on request before finishing
+    Cancelled,
+}
+
+impl ErrorKind {
+    /// Exit codes are part of the CLI's interface; never renumber them.
+    /// (2 is left to argument parsing errors.)
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorKind::Internal => 1,
+            ErrorKind::Config => 3,
+            ErrorKind::BackendUnreachable => 4,
+            ErrorKind::PartialFailure => 5,
+            ErrorKind::NothingToDo => 6,
+            ErrorKind::Database => 7,
+            ErrorKind::Io => 8,
+            ErrorKind::Cancelled => 9,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Internal => "internal",
+            ErrorKind::Config => "config",
+            ErrorKind::BackendUnreachable => "backend_unreachable",
+            ErrorKind::PartialFailure => "partial_failure",
+            ErrorKind::NothingToDo => "nothing_to_do",
+            ErrorKind::Database => "database",
+            ErrorKind::Io => "io",
+            ErrorKind::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// An error whose category is known where it is raised.
+#[derive(Debug)]
+pub struct CliError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl CliError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> CliError {
+        CliError { kind, message: message.into() }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CliError {}
+
+/// Categorise an error by the first recognisable cause in its chain.
+pub fn classify(error: &Error) -> ErrorKind {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<CliError>() {
+            return e.kind;
+        }
+        if cause.is::<toml::de::Error>() {
+            return ErrorKind::Config;
+        }
+        if cause.is::<rusqlite::Error>() {
+            return ErrorKind::Database;
+        }
+        if cause.is::<reqwest::Error>() {
+            return ErrorKind::BackendUnreachable;
+        }
+        if cause.is::<std::io::Error>() {
+            return ErrorKind::Io;
+        }
+    }
+    ErrorKind::Internal
+}
+
+/// The one-line JSON summary written to stderr when a command fails.
+pub fn summary(error: &Error) -> serde_json::Value {
+    let kind = classify(error);
+    serde_json::json!({
+        "error": {
+            "kind": kind.as_str(),
+            "exit_code": kind.exit_code(),
+            "message": error.to_string(),
+            "causes": error.chain().skip(1).map(|c| c.to_string()).collect::<Vec<_>>(),
+        }
+    })
+}
+
+/// Print the error for humans, then the JSON summary, and pick the exit code.
+pub fn report(error: &Error) -> ExitCode {
+    eprintln!("Error: {:#}", error);
+    eprintln!("{}", summary(error));
+    ExitCode::from(classify(error).exit_code())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let partial = Error::from(CliError::new(ErrorKind::PartialFailure, "2 of 10 images failed"));
+        assert_eq!(classify(&partial), ErrorKind::PartialFailure);
+
+        let config = crate::config::Config::parse("nonsense = [").unwrap_err();
+        assert_eq!(classify(&config.context("parsing config")), ErrorKind::Config);
+
+        let io = Error::from(std::io::Error::other("disk on fire")).context("reading image");
+        assert_eq!(classify(&io), ErrorKind::Io);
+        assert_eq!(classify(&anyhow::anyhow!("huh")), ErrorKind::Internal);
+    }
+
+    #[test]
+    fn test_summary() {
+        let error = Error::from(CliError::new(ErrorKind::NothingToDo, "no images found")).context("scanning /photos");
+        let summary = summary(&error);
+        assert_eq!(summary["error"]["kind"], "nothing_to_do");
+        assert_eq!(summary["error"]["exit_code"], 6);
+        assert_eq!(summary["error"]["message"], "scanning /photos");
+        assert_eq!(summary["error"]["causes"][0], "no images found");
+    }
+}
@@ -0,0 +1,215 @@
+//! Getting catalog data back out to where other tools can use it.
+
+use std::io::Write;
+use std::path::Path;
+use anyhow::Error;
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+use crate::caption::{self, Template};
+use crate::error::{CliError, ErrorKind};
+use crate::progress::Operation;
+use crate::{keywords, tags, xmp, SearchFilter};
+
+/// Write the AI description and keywords of every analyzed image to its XMP
+/// sidecar so Lightroom, darktable and digiKam pick them up, with the
+/// description formatted by `template` if there is one. With a
+/// `digikam_root`, the tags also go into digiKam's tag tree (see
+/// `digikam_tags`). Existing sidecars are merged into rather than replaced.
+/// Returns how many sidecars were written and how many images were skipped.
+pub fn export_xmp_sidecars(conn: &Connection, operation: &Operation, template: Option<&Template>, digikam_root: Option<&str>) -> Result<(usize, usize), Error> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, path, description, {0} FROM images
+         WHERE description IS NOT NULL OR {0} IS NOT NULL
+         ORDER BY path",
+        tags::KEYWORDS,
+    ))?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?, row.get::<_, Option<String>>(3)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    operation.set_total(conn, rows.len())?;
+
+    let (mut written, mut skipped) = (0, 0);
+    for (id, path, description, keyword_list) in rows {
+        operation.checkpoint(conn)?;
+        let image = Path::new(&path);
+        if !image.exists() {
+            tracing::warn!("Skipping {}: original no longer exists", path);
+            skipped += 1;
+            operation.advance(conn, false)?;
+            continue;
+        }
+        let keywords = keyword_list.as_deref().map(keywords::split).unwrap_or_default();
+        let description = caption::caption(conn, id, template, description)?;
+        let paths = match digikam_root {
+            Some(root) => digikam_tags(conn, id, root, &keywords)?,
+            None => Vec::new(),
+        };
+        let result = xmp::write_sidecar(image, description.as_deref(), &keywords, &paths);
+        match &result {
+            Ok(_) => written += 1,
+            Err(e) => {
+                tracing::error!("Error writing sidecar for {}: {}", path, e);
+                skipped += 1;
+            }
+        }
+        operation.advance(conn, result.is_ok())?;
+    }
+    Ok((written, skipped))
+}
+
+/// Tag paths (`|` between levels) for digiKam's tag tree: the AI keywords,
+/// and those from device profiles and geofences, under `root`, and the
+/// place a photo was taken under "Places", e.g. "Places|Portugal|Lisbon".
+fn digikam_tags(conn: &Connection, id: i64, root: &str, keywords: &[String]) -> Result<Vec<String>, Error> {
+    // A level can't contain the separators digiKam and Lightroom use
+    let level = |name: &str| name.trim().replace(['|', '/'], "-");
+    let (country, region, city): (Option<String>, Option<String>, Option<String>) =
+        conn.query_row("SELECT country, region, city FROM images WHERE id = ?1", [id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    let tagged: Vec<String> = conn.prepare(
+        "SELECT value FROM external_metadata WHERE image_id = ?1 AND source IN ('device', 'geofence') AND field = 'keyword'",
+    )?
+        .query_map([id], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+
+    let mut paths: Vec<String> = Vec::new();
+    for keyword in keywords.iter().chain(&tagged) {
+        let path = format!("{}|{}", level(root), level(keyword));
+        if !paths.iter().any(|p| p.eq_ignore_ascii_case(&path)) {
+            paths.push(path);
+        }
+    }
+    let place: Vec<String> = [country, region, city].into_iter().flatten().map(|name| level(&name)).collect();
+    if !place.is_empty() {
+        paths.push(format!("Places|{}", place.join("|")));
+    }
+    Ok(paths)
+}
+
+/// Write the `columns` of the images matching `filter` (every column of
+/// the images table if none are named) to `out` as CSV, with a header row.
+/// Returns the number of rows written.
+pub fn export_csv(conn: &Connection, filter: &SearchFilter, columns: &[String], out: &mut impl Write) -> Result<usize, Error> {
+    // The keywords too, which are kept in their own table (see `tags`)
+    let mut known: Vec<String> = conn.prepare("SELECT name FROM pragma_table_info('images')")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    known.push(String::from("keywords"));
+    let columns: Vec<String> = if columns.is_empty() { known.clone() } else { columns.iter().map(|c| c.trim().to_lowercase()).collect() };
+    if let Some(unknown) = columns.iter().find(|column| !known.contains(column)) {
+        let message = format!("unknown column {:?}; known columns are {}", unknown, known.join(", "));
+        return Err(CliError::new(ErrorKind::Config, message).into());
+    }
+
+    let (clause, params) = crate::filter_clause(filter);
+    let selected: Vec<&str> = columns.iter().map(|column| if column == "keywords" { tags::KEYWORDS } else { column }).collect();
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM images{} ORDER BY creation_date, path", selected.join(", "), clause))?;
+    // CRLF line ends, as RFC 4180 has them
+    write!(out, "{}\r\n", columns.iter().map(|column| csv_field(column)).collect::<Vec<_>>().join(","))?;
+    let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        let mut fields = Vec::with_capacity(columns.len());
+        for i in 0..columns.len() {
+            fields.push(match row.get_ref(i)? {
+                ValueRef::Null => String::new(),
+                ValueRef::Integer(n) => n.to_string(),
+                ValueRef::Real(x) => x.to_string(),
+                ValueRef::Text(text) => csv_field(&String::from_utf8_lossy(text)),
+                ValueRef::Blob(_) => String::new(),
+            });
+        }
+        write!(out, "{}\r\n", fields.join(","))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// A field quoted if it needs to be, with quotes doubled.
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\r', '\n']) || text.starts_with(' ') || text.ends_with(' ') {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_export_xmp_sidecars() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let photo = dir.path().join("tram.jpg");
+        fs::write(&photo, b"")?;
+
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, format, description)
+             VALUES (?1, 'tram.jpg', 0, 'Jpeg', 'A yellow tram')",
+            [photo.to_string_lossy()],
+        )?;
+        crate::test_support::set_keywords(&conn, &[(&photo.to_string_lossy(), "tram, yellow, Tram")]);
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, format, description)
+             VALUES ('/gone/missing.jpg', 'missing.jpg', 0, 'Jpeg', 'Gone')",
+            [],
+        )?;
+
+        let operation = crate::progress::start(&conn, "export", crate::cancel::CancelToken::new())?;
+        assert_eq!(export_xmp_sidecars(&conn, &operation, None, None)?, (1, 1));
+        let data = xmp::parse(&fs::read_to_string(dir.path().join("tram.jpg.xmp"))?)?;
+        assert_eq!(data.description.as_deref(), Some("A yellow tram"));
+        assert_eq!(data.keywords, vec!["tram", "yellow"]);
+
+        let template = Template::try_from(String::from("{description}[ — {date_taken}] ({file_name})")).unwrap();
+        fs::remove_file(dir.path().join("tram.jpg.xmp"))?;
+        export_xmp_sidecars(&conn, &operation, Some(&template), None)?;
+        let data = xmp::parse(&fs::read_to_string(dir.path().join("tram.jpg.xmp"))?)?;
+        assert_eq!(data.description.as_deref(), Some("A yellow tram (tram.jpg)"));
+        assert!(data.hierarchical_keywords.is_empty());
+
+        conn.execute("UPDATE images SET country = 'Portugal', city = 'Lisbon' WHERE id = 1", [])?;
+        conn.execute("INSERT INTO external_metadata VALUES (1, 'geofence', 'keyword', 'Baixa/Chiado')", [])?;
+        export_xmp_sidecars(&conn, &operation, None, Some("AI"))?;
+        let xml = fs::read_to_string(dir.path().join("tram.jpg.xmp"))?;
+        assert!(xml.contains("<rdf:li>Places/Portugal/Lisbon</rdf:li>"));
+        let data = xmp::parse(&xml)?;
+        let paths = ["AI|tram", "AI|yellow", "AI|Baixa-Chiado", "Places|Portugal|Lisbon"];
+        assert_eq!(data.hierarchical_keywords, paths);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_csv() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, iso, camera_model, description) VALUES
+             ('/a.jpg', 'a.jpg', 10, 400, 'X100V', 'A \"yellow\" tram, at night'),
+             ('/b.jpg', 'b.jpg', 20, NULL, 'iPhone 15', 'Line one\nline two')",
+            [],
+        )?;
+        crate::test_support::set_keywords(&conn, &[("/a.jpg", "tram, night")]);
+        let columns = ["path".to_string(), "iso".to_string(), "description".to_string(), "keywords".to_string()];
+        let mut out = Vec::new();
+        assert_eq!(export_csv(&conn, &SearchFilter::default(), &columns, &mut out)?, 2);
+        assert_eq!(
+            String::from_utf8(out)?,
+            "path,iso,description,keywords\r\n/a.jpg,400,\"A \"\"yellow\"\" tram, at night\",\"tram, night\"\r\n/b.jpg,,\"Line one\nline two\",\r\n",
+        );
+
+        let filter = SearchFilter { camera: Some(String::from("X100V")), ..SearchFilter::default() };
+        let mut out = Vec::new();
+        assert_eq!(export_csv(&conn, &filter, &[], &mut out)?, 1);
+        assert!(String::from_utf8(out)?.starts_with("id,path,file_name,file_size,"));
+
+        assert!(export_csv(&conn, &filter, &["path; DROP TABLE images".to_string()], &mut Vec::new()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,19 @@
+/// Optional subsystems selected with Cargo features, and whether this build
+/// includes each of them.
+pub const FEATURES: &[(&str, bool)] = &[
+    ("geocode", cfg!(feature = "geocode")),
+    ("heif", cfg!(feature = "heif")),
+    ("raw", cfg!(feature = "raw")),
+    ("server", cfg!(feature = "server")),
+    ("tui", cfg!(feature = "tui")),
+    ("video", cfg!(feature = "video")),
+];
+
+pub fn print_version(features: bool) {
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    if features {
+        for (name, enabled) in FEATURES {
+            println!("  {:<10} {}", name, if *enabled { "enabled" } else { "disabled" });
+        }
+    }
+}
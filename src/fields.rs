@@ -0,0 +1,311 @@
+//! Fields of the user's own on photos, for whatever a workflow needs kept
+//! with them that the catalog has no column for: a client, an invoice
+//! number, a herbarium specimen ID. A field is a key and a value, set with
+//! `field set client=Smith` on photos picked like any others. Each key has
+//! a type, given the first time it's set and kept after: a string (the
+//! default), a number or a date. Keys are matched whatever their case.
+//!
+//! `search --field client=smith` (or the query term
+//! `field:client=smith`) finds photos by them: strings whatever the case,
+//! dates by year, month or day, and numbers and dates by range too, with
+//! `<`, `<=`, `>` or `>=` in place of `=`: `--field invoice>=1000`.
+
+use std::fmt;
+use anyhow::Error;
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::error::{CliError, ErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    String,
+    Number,
+    /// A year, month or day, as `query::parse_date` reads them
+    Date,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 3] = [Kind::String, Kind::Number, Kind::Date];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::String => "string",
+            Kind::Number => "number",
+            Kind::Date => "date",
+        }
+    }
+
+    /// For `--type`'s value parser.
+    pub fn parse(name: &str) -> Result<Kind, String> {
+        Kind::ALL.into_iter().find(|kind| kind.name() == name.trim().to_lowercase()).ok_or_else(|| {
+            let names: Vec<&str> = Kind::ALL.iter().map(|kind| kind.name()).collect();
+            format!("no field type {:?}; there are {}", name, names.join(", "))
+        })
+    }
+
+    /// `value` as a field of this type keeps it, and as a number if it's
+    /// one.
+    fn read(self, value: &str) -> Result<(String, Option<f64>), String> {
+        let value = value.trim();
+        match self {
+            Kind::String => Ok((value.to_string(), None)),
+            Kind::Number => match value.parse::<f64>() {
+                Ok(number) if number.is_finite() => Ok((value.to_string(), Some(number))),
+                _ => Err(format!("{:?} isn't a number", value)),
+            },
+            Kind::Date => crate::query::parse_date(value).map(|date| (date, None)),
+        }
+    }
+}
+
+/// How a field is compared in a search.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    /// Longest first, so "<=" isn't taken for "<"
+    const OPERATORS: [(&'static str, Comparison); 5] = [
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+        ("=", Comparison::Equal),
+    ];
+
+    fn operator(self) -> &'static str {
+        Comparison::OPERATORS.iter().find(|(_, comparison)| *comparison == self).map_or("=", |(operator, _)| operator)
+    }
+}
+
+/// One `--field` criterion: `client=Smith`, `invoice>=1000`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub key: String,
+    pub comparison: Comparison,
+    pub value: String,
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{}", self.key, self.comparison.operator(), self.value)
+    }
+}
+
+/// A key as fields can have it: letters, digits and `_`, `-` or `.`.
+fn parse_key(key: &str) -> Result<String, String> {
+    let key = key.trim();
+    match !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        true => Ok(key.to_string()),
+        false => Err(format!("invalid field name {:?}; it takes letters, digits, _, - and .", key)),
+    }
+}
+
+/// A `--field` criterion.
+pub fn parse_condition(s: &str) -> Result<Condition, String> {
+    let (at, operator, comparison) = s.find(['<', '>', '='])
+        .and_then(|at| Comparison::OPERATORS.iter().find(|(operator, _)| s[at..].starts_with(operator)).map(|(operator, comparison)| (at, *operator, *comparison)))
+        .ok_or_else(|| format!("expected a field and a value, like client=Smith or invoice>=1000; got {:?}", s))?;
+    let key = parse_key(&s[..at])?;
+    let value = s[at + operator.len()..].trim();
+    if value.is_empty() {
+        return Err(format!("no value for field {}", key));
+    }
+    Ok(Condition { key, comparison, value: value.to_string() })
+}
+
+/// A `field set` assignment, `key=value`.
+pub fn parse_assignment(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s.split_once('=').ok_or_else(|| format!("expected key=value, got {:?}", s))?;
+    Ok((parse_key(key)?, value.trim().to_string()))
+}
+
+impl Condition {
+    /// The SQL condition on `images` this is, and its parameters. The
+    /// comparison goes by the type of the key: as numbers, as dates (where
+    /// a year or month takes in its days) or as strings, whatever the case.
+    pub fn clause(&self) -> (String, Vec<Box<dyn rusqlite::ToSql>>) {
+        let number = self.value.parse::<f64>().ok().filter(|number| number.is_finite());
+        let date = crate::query::parse_date(&self.value).unwrap_or_else(|_| self.value.clone());
+        // Whether the date is the one searched for or within it
+        let within = "(value = ? OR value LIKE ? || '-%')";
+        let (numbers, dates, strings) = match self.comparison {
+            Comparison::Equal => ("number = ?", within.to_string(), "value = ? COLLATE NOCASE"),
+            Comparison::Less => ("number < ?", String::from("value < ?"), "value < ? COLLATE NOCASE"),
+            Comparison::LessOrEqual => ("number <= ?", format!("(value <= ? OR {})", within), "value <= ? COLLATE NOCASE"),
+            Comparison::Greater => ("number > ?", format!("(value > ? AND NOT {})", within), "value > ? COLLATE NOCASE"),
+            Comparison::GreaterOrEqual => ("number >= ?", String::from("value >= ?"), "value >= ? COLLATE NOCASE"),
+        };
+        let mut params: Vec<Box<dyn rusqlite::ToSql>> = vec![Box::new(self.key.clone()), Box::new(number)];
+        for _ in 0..dates.matches('?').count() {
+            params.push(Box::new(date.clone()));
+        }
+        params.push(Box::new(self.value.clone()));
+        let clause = format!(
+            "id IN (SELECT image_id FROM fields JOIN field_keys ON field_keys.key = fields.key WHERE fields.key = ?
+                    AND CASE field_keys.kind WHEN 'number' THEN {} WHEN 'date' THEN {} ELSE {} END)",
+            numbers, dates, strings,
+        );
+        (clause, params)
+    }
+}
+
+/// A key, as `field list` shows it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Key {
+    pub key: String,
+    pub kind: Kind,
+    /// Photos that have it
+    pub images: i64,
+}
+
+/// The key `key` as it was first set, and its type, if it has been.
+fn known(conn: &Connection, key: &str) -> Result<Option<(String, Kind)>, Error> {
+    let known: Option<(String, String)> = conn
+        .query_row("SELECT key, kind FROM field_keys WHERE key = ?1", [key], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+    Ok(known.map(|(key, kind)| (key, Kind::parse(&kind).unwrap_or(Kind::String))))
+}
+
+/// Set `key` to `value` on the images `ids`, as a value of type `kind`,
+/// which for a key set before has to be the one it has. Gives how many
+/// changed.
+pub fn set(conn: &Connection, ids: &[i64], key: &str, value: &str, kind: Option<Kind>) -> Result<usize, Error> {
+    let tx = conn.unchecked_transaction()?;
+    let changed = write(&tx, ids, key, value, kind)?;
+    tx.commit()?;
+    Ok(changed)
+}
+
+/// `set`, in the caller's transaction.
+pub fn write(tx: &Connection, ids: &[i64], key: &str, value: &str, kind: Option<Kind>) -> Result<usize, Error> {
+    let key = parse_key(key).map_err(|e| CliError::new(ErrorKind::Config, e))?;
+    let (key, kind) = match (known(tx, &key)?, kind) {
+        (Some((known, was)), Some(kind)) if kind != was => {
+            let message = format!("field {} holds {}s; it can't be made a {}", known, was.name(), kind.name());
+            return Err(CliError::new(ErrorKind::Config, message).into());
+        }
+        (Some(known), _) => known,
+        (None, kind) => {
+            let kind = kind.unwrap_or(Kind::String);
+            tx.execute("INSERT INTO field_keys (key, kind) VALUES (?1, ?2)", params![key, kind.name()])?;
+            (key, kind)
+        }
+    };
+    let (value, number) = kind.read(value).map_err(|e| CliError::new(ErrorKind::Config, format!("field {}: {}", key, e)))?;
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO fields (image_id, key, value, number) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (image_id, key) DO UPDATE SET value = excluded.value, number = excluded.number
+         WHERE value IS NOT excluded.value",
+    )?;
+    let mut changed = 0;
+    for id in ids {
+        changed += stmt.execute(params![id, key, value, number])?;
+    }
+    Ok(changed)
+}
+
+/// `key` as it's kept and `value` as it would be set on a photo, without
+/// setting it: for showing what a change would be. A key not set before
+/// would be a string.
+pub fn normalize(conn: &Connection, key: &str, value: &str) -> Result<(String, String), Error> {
+    let key = parse_key(key).map_err(|e| CliError::new(ErrorKind::Config, e))?;
+    let (key, kind) = known(conn, &key)?.unwrap_or((key, Kind::String));
+    let (value, _) = kind.read(value).map_err(|e| CliError::new(ErrorKind::Config, format!("field {}: {}", key, e)))?;
+    Ok((key, value))
+}
+
+/// Take `key` off the images `ids`, and forget it if no photo has it any
+/// more. Gives how many changed.
+pub fn remove(conn: &Connection, ids: &[i64], key: &str) -> Result<usize, Error> {
+    let tx = conn.unchecked_transaction()?;
+    let mut stmt = tx.prepare("DELETE FROM fields WHERE image_id = ?1 AND key = ?2")?;
+    let mut removed = 0;
+    for id in ids {
+        removed += stmt.execute(params![id, key.trim()])?;
+    }
+    drop(stmt);
+    tx.execute("DELETE FROM field_keys WHERE key NOT IN (SELECT key FROM fields)", [])?;
+    tx.commit()?;
+    Ok(removed)
+}
+
+/// Every key, with how many photos have it.
+pub fn keys(conn: &Connection) -> Result<Vec<Key>, Error> {
+    let keys = conn.prepare(
+        "SELECT key, kind, (SELECT COUNT(*) FROM fields WHERE fields.key = field_keys.key) FROM field_keys ORDER BY key",
+    )?
+        .query_map([], |row| {
+            let kind: String = row.get(1)?;
+            Ok(Key { key: row.get(0)?, kind: Kind::parse(&kind).unwrap_or(Kind::String), images: row.get(2)? })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(keys)
+}
+
+/// The fields of the image `id`, by key.
+pub fn of(conn: &Connection, id: i64) -> rusqlite::Result<Vec<(String, String)>> {
+    conn.prepare_cached("SELECT key, value FROM fields WHERE image_id = ?1 ORDER BY key")?
+        .query_map([id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SearchFilter;
+
+    #[test]
+    fn test_parse_condition() {
+        let condition = parse_condition("invoice>=1000").unwrap();
+        assert_eq!((condition.key.as_str(), condition.comparison, condition.value.as_str()), ("invoice", Comparison::GreaterOrEqual, "1000"));
+        assert_eq!(parse_condition("project = wedding-smith").unwrap().to_string(), "project=wedding-smith");
+        assert_eq!(parse_condition("shot<2023-06").unwrap().comparison, Comparison::Less);
+        for bad in ["project", "=smith", "project=", "my project=x"] {
+            assert!(parse_condition(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(parse_assignment("herbarium.id=K000697"), Ok((String::from("herbarium.id"), String::from("K000697"))));
+    }
+
+    #[test]
+    fn test_fields() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute_batch(
+            "INSERT INTO images (id, path, file_name, file_size) VALUES
+                 (1, '/a.jpg', 'a.jpg', 1), (2, '/b.jpg', 'b.jpg', 1), (3, '/c.jpg', 'c.jpg', 1)",
+        )?;
+        assert_eq!(set(&conn, &[1, 2], "Project", "wedding-smith", None)?, 2);
+        assert_eq!(set(&conn, &[2], "project", "wedding-smith", None)?, 0);
+        set(&conn, &[1], "invoice", "980", Some(Kind::Number))?;
+        set(&conn, &[2], "invoice", "1042", None)?;
+        set(&conn, &[1, 3], "delivered", "2023-07-14", Some(Kind::Date))?;
+        set(&conn, &[2], "delivered", "2024-01", None)?;
+        assert!(set(&conn, &[3], "invoice", "soon", None).is_err());
+        assert!(set(&conn, &[3], "invoice", "7", Some(Kind::Date)).is_err());
+        assert_eq!(of(&conn, 1)?[2], (String::from("Project"), String::from("wedding-smith")));
+
+        let search = |conditions: &[&str]| -> Result<Vec<String>, Error> {
+            let field = conditions.iter().map(|condition| parse_condition(condition).unwrap()).collect();
+            Ok(crate::search_images(&conn, &SearchFilter { field, ..SearchFilter::default() })?)
+        };
+        assert_eq!(search(&["project=WEDDING-SMITH"])?, vec!["/a.jpg", "/b.jpg"]);
+        // 980 < 1000, as numbers rather than as text
+        assert_eq!(search(&["invoice>=1000"])?, vec!["/b.jpg"]);
+        assert_eq!(search(&["invoice=980.0"])?, vec!["/a.jpg"]);
+        assert_eq!(search(&["delivered=2023"])?, vec!["/a.jpg", "/c.jpg"]);
+        assert_eq!(search(&["delivered>2023"])?, vec!["/b.jpg"]);
+        assert_eq!(search(&["delivered<=2023-07"])?, vec!["/a.jpg", "/c.jpg"]);
+        assert_eq!(search(&["project=wedding-smith", "invoice<1000"])?, vec!["/a.jpg"]);
+        assert!(search(&["client=Smith"])?.is_empty());
+
+        assert_eq!(remove(&conn, &[1, 2], "PROJECT")?, 2);
+        let keys: Vec<(String, i64)> = keys(&conn)?.into_iter().map(|key| (key.key, key.images)).collect();
+        assert_eq!(keys, vec![(String::from("delivered"), 3), (String::from("invoice"), 2)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+//! `db fsck`: checks of what the catalog's parts assume about each other
+//! that can stop being true: foreign keys hold for what this build writes,
+//! but not for a catalog older versions or other tools wrote to, and files
+//! on disk can go behind its back. Each kind of problem has a check
+//! of its own, which `--repair` can fix: rows and files no longer belonging
+//! to anything are deleted, and operations abandoned by a process that
+//! died are marked failed.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use anyhow::Error;
+use rusqlite::{params, Connection};
+use crate::config::Config;
+use crate::remote;
+
+/// An operation still `running` with no progress for this long was left
+/// by a process that's gone.
+const ABANDONED_SECS: i64 = 24 * 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// Rows pointing at a photo, tag, album, trip, source or operation
+    /// that isn't there
+    References,
+    /// Tags no photo has
+    UnusedTags,
+    /// Histograms made from another version of the photo's file
+    Histograms,
+    /// Completed downloads whose ZIP is gone
+    Downloads,
+    /// Operations left running by a process that's gone
+    Operations,
+    /// Mirror thumbnails of photos no longer in the catalog
+    Thumbnails,
+}
+
+impl Check {
+    /// In the order they're run and repaired, which is one where repairing
+    /// one can only leave more for the ones after it to find.
+    pub const ALL: [Check; 6] = [Check::References, Check::UnusedTags, Check::Histograms, Check::Downloads, Check::Operations, Check::Thumbnails];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Check::References => "references",
+            Check::UnusedTags => "unused-tags",
+            Check::Histograms => "histograms",
+            Check::Downloads => "downloads",
+            Check::Operations => "operations",
+            Check::Thumbnails => "thumbnails",
+        }
+    }
+
+    /// What the problems found are, after a count: "3 rows pointing at ...".
+    pub fn about(self) -> &'static str {
+        match self {
+            Check::References => "rows pointing at rows that aren't there",
+            Check::UnusedTags => "tags no photo has",
+            Check::Histograms => "histograms of another version of the file",
+            Check::Downloads => "downloads whose ZIP is gone",
+            Check::Operations => "operations left running by a process that's gone",
+            Check::Thumbnails => "mirror thumbnails of photos no longer catalogued",
+        }
+    }
+
+    /// For `--repair`'s value parser.
+    pub fn parse(name: &str) -> Result<Check, String> {
+        Check::ALL.into_iter().find(|check| check.name() == name.trim()).ok_or_else(|| {
+            let names: Vec<&str> = Check::ALL.iter().map(|check| check.name()).collect();
+            format!("no check {:?}; there are {}", name, names.join(", "))
+        })
+    }
+}
+
+/// What one check found: a line about each problem.
+pub struct Finding {
+    pub check: Check,
+    pub problems: Vec<String>,
+}
+
+/// Run every check.
+pub fn check(conn: &Connection, config: &Config) -> Result<Vec<Finding>, Error> {
+    Check::ALL.into_iter().map(|check| Ok(Finding { check, problems: find(conn, config, check)? })).collect()
+}
+
+/// The problems `check` finds.
+pub fn find(conn: &Connection, config: &Config, check: Check) -> Result<Vec<String>, Error> {
+    let problems = match check {
+        Check::References => dangling(conn)?
+            .into_iter()
+            .map(|row| format!("{} row {}: {} {} isn't in {}", row.table, row.rowid, row.column, row.value, row.parent))
+            .collect(),
+        Check::UnusedTags => conn
+            .prepare("SELECT name FROM tags WHERE id NOT IN (SELECT tag_id FROM image_tags) ORDER BY name")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .map(|name| Ok(format!("tag {:?}", name?)))
+            .collect::<Result<_, Error>>()?,
+        Check::Histograms => conn
+            .prepare(&format!("SELECT images.path FROM histograms JOIN images ON images.id = image_id WHERE {} ORDER BY images.path", STALE_HISTOGRAM))?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .map(|path| Ok(format!("histogram of {}", path?)))
+            .collect::<Result<_, Error>>()?,
+        Check::Downloads => missing_downloads(conn, config)?.into_iter().map(|id| format!("download {}", id)).collect(),
+        Check::Operations => conn
+            .prepare(&format!("SELECT id, kind FROM operations WHERE {} ORDER BY id", ABANDONED))?
+            .query_map([now() - ABANDONED_SECS], |row| Ok(format!("operation {} ({})", row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<_, _>>()?,
+        Check::Thumbnails => stray_thumbnails(conn, config)?.iter().map(|path| path.display().to_string()).collect(),
+    };
+    Ok(problems)
+}
+
+/// Fix what `check` finds. Gives how many problems were fixed.
+pub fn repair(conn: &Connection, config: &Config, check: Check) -> Result<usize, Error> {
+    let repaired = match check {
+        Check::References => {
+            let rows = dangling(conn)?;
+            for row in &rows {
+                conn.execute(&format!("DELETE FROM {} WHERE rowid = ?1", row.table), [row.rowid])?;
+            }
+            rows.len()
+        }
+        Check::UnusedTags => conn.execute("DELETE FROM tags WHERE id NOT IN (SELECT tag_id FROM image_tags)", [])?,
+        Check::Histograms => conn.execute(
+            &format!("DELETE FROM histograms WHERE image_id IN (SELECT image_id FROM histograms JOIN images ON images.id = image_id WHERE {})", STALE_HISTOGRAM),
+            [],
+        )?,
+        Check::Downloads => {
+            let ids = missing_downloads(conn, config)?;
+            for id in &ids {
+                conn.execute("DELETE FROM downloads WHERE operation_id = ?1", [id])?;
+            }
+            ids.len()
+        }
+        Check::Operations => conn.execute(
+            &format!("UPDATE operations SET state = 'failed', finished_at = updated_at, message = 'abandoned' WHERE {}", ABANDONED),
+            [now() - ABANDONED_SECS],
+        )?,
+        Check::Thumbnails => {
+            let paths = stray_thumbnails(conn, config)?;
+            for path in &paths {
+                fs::remove_file(path)?;
+            }
+            paths.len()
+        }
+    };
+    Ok(repaired)
+}
+
+/// Histograms, joined with their images, made from other content than the
+/// image's file has now. They'd be made again when next asked for.
+const STALE_HISTOGRAM: &str = "histograms.content_hash IS NOT images.content_hash";
+
+/// Operations, given the time before which they're abandoned.
+const ABANDONED: &str = "state = 'running' AND updated_at < ?1";
+
+/// A row whose foreign key has nothing to point at.
+struct Dangling {
+    table: String,
+    rowid: i64,
+    column: String,
+    value: String,
+    parent: String,
+}
+
+fn dangling(conn: &Connection) -> Result<Vec<Dangling>, Error> {
+    let violations: Vec<(String, i64, String, i64)> = conn
+        .prepare("PRAGMA foreign_key_check")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+        .collect::<Result<_, _>>()?;
+    let mut rows = Vec::new();
+    for (table, rowid, parent, key) in violations {
+        let column: String = conn.query_row("SELECT \"from\" FROM pragma_foreign_key_list(?1) WHERE id = ?2", params![table, key], |row| row.get(0))?;
+        let value: rusqlite::types::Value = conn.query_row(&format!("SELECT {} FROM {} WHERE rowid = ?1", column, table), [rowid], |row| row.get(0))?;
+        let value = match value {
+            rusqlite::types::Value::Integer(n) => n.to_string(),
+            rusqlite::types::Value::Text(text) => format!("{:?}", text),
+            other => format!("{:?}", other),
+        };
+        rows.push(Dangling { table, rowid, column, value, parent });
+    }
+    Ok(rows)
+}
+
+/// Completed downloads whose ZIP isn't where `serve` keeps them.
+#[cfg(feature = "server")]
+fn missing_downloads(conn: &Connection, config: &Config) -> Result<Vec<i64>, Error> {
+    let dir = std::path::Path::new(&config.downloads.dir);
+    let completed: Vec<i64> = conn
+        .prepare("SELECT operation_id FROM downloads JOIN operations ON operations.id = operation_id WHERE state = 'completed' ORDER BY operation_id")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(completed.into_iter().filter(|id| !crate::downloads::file(dir, *id).is_file()).collect())
+}
+
+/// Only `serve` makes downloads, so a build without it has nothing to check.
+#[cfg(not(feature = "server"))]
+fn missing_downloads(_: &Connection, _: &Config) -> Result<Vec<i64>, Error> {
+    Ok(Vec::new())
+}
+
+/// Thumbnails in the mirror's staging folder with no catalogued photo of
+/// their content hash.
+fn stray_thumbnails(conn: &Connection, config: &Config) -> Result<Vec<PathBuf>, Error> {
+    let dir = config.remote.staging.join(remote::THUMBNAILS);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let hashes: HashSet<String> = conn
+        .prepare("SELECT content_hash FROM images WHERE content_hash IS NOT NULL")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    let mut stray = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "jpg") && path.file_stem().is_some_and(|stem| !hashes.contains(stem.to_string_lossy().as_ref())) {
+            stray.push(path);
+        }
+    }
+    stray.sort();
+    Ok(stray)
+}
+
+fn now() -> i64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_fsck() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let config = Config { remote: remote::RemoteConfig { staging: dir.path().to_path_buf(), ..Default::default() }, ..Config::default() };
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        // Written as if by a tool that doesn't enforce foreign keys
+        conn.pragma_update(None, "foreign_keys", false)?;
+        conn.execute_batch(
+            "INSERT INTO images (id, path, file_name, file_size, content_hash) VALUES (1, '/a.jpg', 'a.jpg', 1, 'aaa');
+             INSERT INTO tags (id, name) VALUES (1, 'beach'), (2, 'gone'), (3, 'unused');
+             INSERT INTO image_tags (image_id, tag_id, position) VALUES (1, 1, 0), (1, 9, 1), (7, 2, 0);
+             INSERT INTO histograms (image_id, content_hash, levels) VALUES (1, 'old', '[]');
+             INSERT INTO operations (id, kind, state, done, failed, started_at, updated_at, cancel_requested)
+             VALUES (1, 'scan', 'running', 0, 0, 0, 0, 0);",
+        )?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        fs::create_dir_all(dir.path().join(remote::THUMBNAILS))?;
+        fs::write(dir.path().join(remote::THUMBNAILS).join("aaa.jpg"), "")?;
+        fs::write(dir.path().join(remote::THUMBNAILS).join("zzz.jpg"), "")?;
+
+        let found: Vec<(Check, usize)> = check(&conn, &config)?.iter().map(|finding| (finding.check, finding.problems.len())).collect();
+        assert_eq!(found, vec![
+            (Check::References, 2),
+            (Check::UnusedTags, 1),
+            (Check::Histograms, 1),
+            (Check::Downloads, 0),
+            (Check::Operations, 1),
+            (Check::Thumbnails, 1),
+        ]);
+        assert_eq!(find(&conn, &config, Check::References)?, vec![
+            "image_tags row 2: tag_id 9 isn't in tags",
+            "image_tags row 3: image_id 7 isn't in images",
+        ]);
+
+        assert_eq!(repair(&conn, &config, Check::References)?, 2);
+        // Which leaves "gone" unused too
+        assert_eq!(find(&conn, &config, Check::UnusedTags)?, vec!["tag \"gone\"", "tag \"unused\""]);
+        for check in Check::ALL {
+            repair(&conn, &config, check)?;
+            assert!(find(&conn, &config, check)?.is_empty(), "{} after repairing", check.name());
+        }
+        assert!(dir.path().join(remote::THUMBNAILS).join("aaa.jpg").exists());
+        let tags: i64 = conn.query_row("SELECT COUNT(*) FROM image_tags", [], |row| row.get(0))?;
+        assert_eq!(tags, 1);
+        let state: String = conn.query_row("SELECT state FROM operations WHERE id = 1", [], |row| row.get(0))?;
+        assert_eq!(state, "failed");
+
+        assert_eq!(Check::parse("unused-tags"), Ok(Check::UnusedTags));
+        assert!(Check::parse("everything").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+//! `export --html`: the catalog as a static site that can be put anywhere
+//! and browsed without the database. The index is a thumbnail grid with
+//! keyword filtering done in the browser; every photo has a page of its
+//! own with its description, keywords and camera details.
+//!
+//! Photos are named by catalog id, so no paths leak, and the images are
+//! derivatives: upright, downsized and with only the metadata the
+//! derivative policy allows.
+//!
+//! Exporting the same catalog again writes the same bytes, and takes away
+//! pages and images of photos no longer in it, so successive exports to
+//! one folder can be diffed or kept in version control.
+
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use anyhow::{bail, Context, Error};
+use image::ImageFormat;
+use rusqlite::Connection;
+use crate::config::Config;
+use crate::progress::Operation;
+use crate::{jobs, keywords, tags, SearchFilter};
+
+/// Longest edge of the grid thumbnails
+const THUMBNAIL_EDGE: u32 = 400;
+/// Longest edge of the image on a photo's own page
+const PREVIEW_EDGE: u32 = 1600;
+
+const STYLE: &str = "\
+body { font-family: system-ui, sans-serif; margin: 0 auto; max-width: 1400px; padding: 1rem; }
+header { display: flex; gap: 1rem; align-items: center; flex-wrap: wrap; }
+#filter { flex: 1; min-width: 12rem; font-size: 1rem; padding: 0.4rem; }
+#keywords button { margin: 0.1rem; border: 1px solid #bbb; border-radius: 1rem; background: none; cursor: pointer; }
+.grid { display: grid; grid-template-columns: repeat(auto-fill, minmax(200px, 1fr)); gap: 0.5rem; }
+.grid figure { margin: 0; }
+.grid img { width: 100%; aspect-ratio: 1; object-fit: cover; display: block; }
+.grid figcaption { font-size: 0.8rem; overflow: hidden; white-space: nowrap; text-overflow: ellipsis; }
+.photo img { max-width: 100%; max-height: 80vh; display: block; margin: 0 auto; }
+dl { display: grid; grid-template-columns: max-content 1fr; gap: 0.2rem 1rem; }
+dt { font-weight: bold; }
+nav { display: flex; justify-content: space-between; }
+";
+
+/// Shows the photos having every word typed as part of some keyword.
+const SCRIPT: &str = "\
+const filter = document.getElementById('filter');
+const photos = [...document.querySelectorAll('.grid figure')];
+function apply() {
+  const words = filter.value.toLowerCase().split(/\\s+/).filter(w => w);
+  for (const photo of photos) {
+    const keywords = photo.dataset.keywords.toLowerCase().split('|');
+    photo.hidden = !words.every(w => keywords.some(k => k.includes(w)));
+  }
+}
+filter.addEventListener('input', apply);
+for (const button of document.querySelectorAll('#keywords button')) {
+  button.addEventListener('click', () => { filter.value = button.textContent; apply(); });
+}
+";
+
+struct Photo {
+    id: i64,
+    path: String,
+    file_name: String,
+    dimensions: Option<(u32, u32)>,
+    creation_date: Option<String>,
+    place: Vec<String>,
+    camera: Vec<String>,
+    lens: Option<String>,
+    focal_length: Option<f64>,
+    aperture: Option<f64>,
+    exposure_time: Option<f64>,
+    iso: Option<u32>,
+    keywords: Vec<String>,
+    description: Option<String>,
+}
+
+/// Write the gallery of the photos matching `filter` to `dir`. Returns how
+/// many photos are in it and how many were skipped.
+pub fn export_html(conn: &Connection, operation: &Operation, config: &Config, filter: &SearchFilter, dir: &Path) -> Result<(usize, usize), Error> {
+    let photos = load(conn, filter)?;
+    operation.set_total(conn, photos.len())?;
+    for sub in ["thumbs", "images", "photos"] {
+        fs::create_dir_all(dir.join(sub)).with_context(|| format!("creating {}", dir.join(sub).display()))?;
+    }
+
+    let mut included = Vec::new();
+    let mut written = HashSet::new();
+    let mut skipped = 0;
+    for photo in photos {
+        operation.checkpoint(conn)?;
+        let images = || -> Result<(), Error> {
+            for (edge, sub) in [(THUMBNAIL_EDGE, "thumbs"), (PREVIEW_EDGE, "images")] {
+                let data = jobs::derivative(Path::new(&photo.path), config, edge)?;
+                // Whatever we can't decode, browsers likely can't either
+                if image::guess_format(&data).ok() != Some(ImageFormat::Jpeg) {
+                    bail!("can't make a JPEG of it");
+                }
+                fs::write(dir.join(sub).join(format!("{}.jpg", photo.id)), data)?;
+            }
+            Ok(())
+        };
+        match images() {
+            Ok(()) => {
+                written.extend(["thumbs", "images"].map(|sub| dir.join(sub).join(format!("{}.jpg", photo.id))));
+                included.push(photo);
+            }
+            Err(e) => {
+                tracing::warn!("Skipping {}: {}", photo.path, e);
+                skipped += 1;
+                operation.advance(conn, false)?;
+                continue;
+            }
+        }
+        operation.advance(conn, true)?;
+    }
+
+    fs::write(dir.join("style.css"), STYLE)?;
+    fs::write(dir.join("index.html"), index_page(&included))?;
+    for (i, photo) in included.iter().enumerate() {
+        let previous = i.checked_sub(1).map(|i| included[i].id);
+        let next = included.get(i + 1).map(|photo| photo.id);
+        fs::write(dir.join("photos").join(format!("{}.html", photo.id)), photo_page(photo, previous, next))?;
+        written.insert(dir.join("photos").join(format!("{}.html", photo.id)));
+    }
+    // Left from an export of photos that have gone since, or are skipped now
+    for sub in ["photos", "thumbs", "images"] {
+        for entry in fs::read_dir(dir.join(sub))? {
+            let path = entry?.path();
+            if path.is_file() && !written.contains(&path) {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok((included.len(), skipped))
+}
+
+fn load(conn: &Connection, filter: &SearchFilter) -> Result<Vec<Photo>, Error> {
+    let (clause, params) = crate::filter_clause(filter);
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, path, file_name, width, height, creation_date, city, region, country,
+                camera_make, camera_model, lens_model, focal_length, aperture, exposure_time, iso,
+                {}, description
+         FROM images{} ORDER BY creation_date, path",
+        tags::KEYWORDS, clause,
+    ))?;
+    let mut photos = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+        let texts = |columns: &[usize]| -> rusqlite::Result<Vec<String>> {
+            Ok(columns.iter().map(|&i| row.get::<_, Option<String>>(i)).collect::<Result<Vec<_>, _>>()?.into_iter().flatten().collect())
+        };
+        Ok(Photo {
+            id: row.get(0)?,
+            path: row.get(1)?,
+            file_name: row.get(2)?,
+            dimensions: row.get::<_, Option<u32>>(3)?.zip(row.get::<_, Option<u32>>(4)?),
+            creation_date: row.get(5)?,
+            place: texts(&[6, 7, 8])?,
+            camera: texts(&[9, 10])?,
+            lens: row.get(11)?,
+            focal_length: row.get(12)?,
+            aperture: row.get(13)?,
+            exposure_time: row.get(14)?,
+            iso: row.get(15)?,
+            keywords: row.get::<_, Option<String>>(16)?.as_deref().map(keywords::split).unwrap_or_default(),
+            description: row.get(17)?,
+        })
+    })?.collect::<Result<Vec<_>, _>>()?;
+
+    let mut stmt = conn.prepare("SELECT value FROM external_metadata WHERE image_id = ?1 AND field = 'keyword' ORDER BY rowid")?;
+    for photo in &mut photos {
+        for keyword in stmt.query_map([photo.id], |row| row.get::<_, String>(0))? {
+            let keyword = keyword?;
+            if !photo.keywords.iter().any(|k| k.eq_ignore_ascii_case(&keyword)) {
+                photo.keywords.push(keyword);
+            }
+        }
+    }
+    Ok(photos)
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn page(title: &str, style: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n<link rel=\"stylesheet\" href=\"{}\">\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape(title), style, body,
+    )
+}
+
+fn index_page(photos: &[Photo]) -> String {
+    // Every keyword, most used first
+    let mut counts: Vec<(String, usize)> = Vec::new();
+    for keyword in photos.iter().flat_map(|photo| &photo.keywords) {
+        match counts.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(keyword)) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((keyword.clone(), 1)),
+        }
+    }
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.to_lowercase().cmp(&b.0.to_lowercase())));
+
+    let mut body = String::from("<header>\n<h1>Photos</h1>\n<input id=\"filter\" type=\"search\" placeholder=\"Filter by keyword\" aria-label=\"Filter by keyword\">\n</header>\n");
+    body.push_str("<p id=\"keywords\">");
+    for (keyword, count) in &counts {
+        let _ = write!(body, "<button type=\"button\" title=\"{} photos\">{}</button>", count, escape(keyword));
+    }
+    body.push_str("</p>\n<main class=\"grid\">\n");
+    for photo in photos {
+        let alt = photo.description.as_deref().unwrap_or(&photo.file_name);
+        let _ = writeln!(
+            body,
+            "<figure data-keywords=\"{}\"><a href=\"photos/{id}.html\"><img src=\"thumbs/{id}.jpg\" alt=\"{}\" loading=\"lazy\"></a><figcaption>{}</figcaption></figure>",
+            escape(&photo.keywords.join("|")), escape(alt), escape(photo.creation_date.as_deref().unwrap_or(&photo.file_name)),
+            id = photo.id,
+        );
+    }
+    let _ = write!(body, "</main>\n<script>\n{}</script>\n", SCRIPT);
+    page("Photos", "style.css", &body)
+}
+
+fn photo_page(photo: &Photo, previous: Option<i64>, next: Option<i64>) -> String {
+    let link = |id: Option<i64>, label: &str| match id {
+        Some(id) => format!("<a href=\"{}.html\">{}</a>", id, label),
+        None => String::from("<span></span>"),
+    };
+    let mut body = format!("<nav>{}<a href=\"../index.html\">All photos</a>{}</nav>\n", link(previous, "← Previous"), link(next, "Next →"));
+    let alt = photo.description.as_deref().unwrap_or(&photo.file_name);
+    let _ = writeln!(body, "<main class=\"photo\">\n<img src=\"../images/{}.jpg\" alt=\"{}\">", photo.id, escape(alt));
+    if let Some(description) = &photo.description {
+        let _ = writeln!(body, "<p>{}</p>", escape(description));
+    }
+
+    let mut details: Vec<(&str, String)> = vec![("File", photo.file_name.clone())];
+    details.extend(photo.creation_date.clone().map(|date| ("Taken", date)));
+    if !photo.place.is_empty() {
+        details.push(("Place", photo.place.join(", ")));
+    }
+    if !photo.camera.is_empty() {
+        details.push(("Camera", photo.camera.join(" ")));
+    }
+    details.extend(photo.lens.clone().map(|lens| ("Lens", lens)));
+    let exposure: Vec<String> = [
+        photo.focal_length.map(|mm| format!("{}mm", mm)),
+        photo.aperture.map(|f| format!("f/{}", f)),
+        photo.exposure_time.map(exposure_time),
+        photo.iso.map(|iso| format!("ISO {}", iso)),
+    ].into_iter().flatten().collect();
+    if !exposure.is_empty() {
+        details.push(("Exposure", exposure.join(", ")));
+    }
+    details.extend(photo.dimensions.map(|(width, height)| ("Size", format!("{} × {}", width, height))));
+    if !photo.keywords.is_empty() {
+        details.push(("Keywords", photo.keywords.join(", ")));
+    }
+    body.push_str("<dl>\n");
+    for (label, value) in details {
+        let _ = writeln!(body, "<dt>{}</dt><dd>{}</dd>", label, escape(&value));
+    }
+    body.push_str("</dl>\n</main>\n");
+    page(photo.description.as_deref().unwrap_or(&photo.file_name), "../style.css", &body)
+}
+
+/// "1/250 s" for fractions of a second, "2 s" otherwise.
+fn exposure_time(seconds: f64) -> String {
+    if seconds > 0.0 && seconds < 1.0 {
+        format!("1/{} s", (1.0 / seconds).round())
+    } else {
+        format!("{} s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use crate::cancel::CancelToken;
+
+    #[test]
+    fn test_export_html() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let photo = dir.path().join("tram.png");
+        image::DynamicImage::new_rgb8(1000, 500).save(&photo)?;
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, creation_date, camera_model, exposure_time, description)
+             VALUES (?1, 'tram.png', 1, '2023-07-14 10:00:00', 'X100V', 0.004, 'A <yellow> tram')",
+            [photo.to_string_lossy()],
+        )?;
+        let id = conn.last_insert_rowid();
+        crate::tags::set(&conn, id, "tram, yellow")?;
+        conn.execute("INSERT INTO external_metadata VALUES (?1, 'iptc', 'keyword', 'Lisbon')", [id])?;
+        conn.execute("INSERT INTO images (path, file_name, file_size) VALUES ('/gone.jpg', 'gone.jpg', 1)", [])?;
+
+        let out = dir.path().join("site");
+        let operation = crate::progress::start(&conn, "export", CancelToken::new())?;
+        assert_eq!(export_html(&conn, &operation, &Config::default(), &SearchFilter::default(), &out)?, (1, 1));
+
+        let thumbnail = image::open(out.join(format!("thumbs/{}.jpg", id)))?;
+        assert_eq!((thumbnail.width(), thumbnail.height()), (400, 200));
+        let index = fs::read_to_string(out.join("index.html"))?;
+        assert!(index.contains("data-keywords=\"tram|yellow|Lisbon\""));
+        assert!(index.contains("alt=\"A &lt;yellow&gt; tram\""));
+        assert!(!index.contains(&photo.to_string_lossy().into_owned()));
+        let page = fs::read_to_string(out.join(format!("photos/{}.html", id)))?;
+        assert!(page.contains("<dt>Exposure</dt><dd>1/250 s</dd>"));
+        assert!(page.contains("<dt>Keywords</dt><dd>tram, yellow, Lisbon</dd>"));
+
+        // Again, to the same bytes, and without the photo once it's gone
+        let files = ["index.html".to_string(), format!("photos/{}.html", id), format!("thumbs/{}.jpg", id)];
+        let before = files.iter().map(|file| fs::read(out.join(file))).collect::<Result<Vec<_>, _>>()?;
+        export_html(&conn, &operation, &Config::default(), &SearchFilter::default(), &out)?;
+        for (file, before) in files.iter().zip(before) {
+            assert_eq!(fs::read(out.join(file))?, before, "{}", file);
+        }
+        fs::remove_file(&photo)?;
+        assert_eq!(export_html(&conn, &operation, &Config::default(), &SearchFilter::default(), &out)?, (0, 2));
+        assert!(!out.join(format!("photos/{}.html", id)).exists() && !out.join(format!("thumbs/{}.jpg", id)).exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! Geofences: named circles ("Home", "Office") configured under
+//! `[[geofences]]`. Photos taken inside one get its tag as a keyword, and
+//! photos inside a private one are left out of anything shared.
+//!
+//! Both are kept in `external_metadata` under the source `geofence`, so
+//! searches and exports don't need the config to tell them apart: the tag
+//! as a `keyword`, and the fence's name as `private` for the private ones.
+
+use anyhow::{bail, Error};
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use crate::{geocode, ExternalMetadata};
+
+const SOURCE: &str = "geofence";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Geofence {
+    pub name: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(default = "default_radius")]
+    pub radius_meters: f64,
+    /// The keyword photos inside get; the name when unset.
+    pub tag: Option<String>,
+    /// Leave photos inside out of shared exports unless asked for.
+    #[serde(default)]
+    pub private: bool,
+}
+
+fn default_radius() -> f64 {
+    100.0
+}
+
+impl Geofence {
+    pub fn contains(&self, (latitude, longitude): (f64, f64)) -> bool {
+        geocode::distance_km(self.latitude, self.longitude, latitude, longitude) * 1000.0 <= self.radius_meters
+    }
+
+    fn tag(&self) -> &str {
+        self.tag.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Check what serde can't: coordinates on the globe and a real radius.
+pub fn validate(fences: &[Geofence]) -> Result<(), Error> {
+    for fence in fences {
+        if !(-90.0..=90.0).contains(&fence.latitude) || !(-180.0..=180.0).contains(&fence.longitude) {
+            bail!("geofence {:?} has coordinates off the globe", fence.name);
+        }
+        if fence.radius_meters.is_nan() || fence.radius_meters <= 0.0 {
+            bail!("geofence {:?} needs a radius above zero", fence.name);
+        }
+    }
+    Ok(())
+}
+
+/// What the fences around `gps` say about a photo taken there.
+pub fn metadata(fences: &[Geofence], gps: (f64, f64)) -> Vec<ExternalMetadata> {
+    let mut metadata = Vec::new();
+    for fence in fences.iter().filter(|fence| fence.contains(gps)) {
+        let entry = |field: &str, value: &str| ExternalMetadata {
+            source: SOURCE.to_string(),
+            field: field.to_string(),
+            value: value.to_string(),
+        };
+        metadata.push(entry("keyword", fence.tag()));
+        if fence.private {
+            metadata.push(entry("private", &fence.name));
+        }
+    }
+    metadata
+}
+
+/// Tag every cataloged photo with GPS afresh, after fences were added,
+/// moved or removed. Returns how many photos are inside one.
+pub fn retag(conn: &Connection, fences: &[Geofence]) -> Result<usize, Error> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM external_metadata WHERE source = ?1", [SOURCE])?;
+    let mut stmt = tx.prepare("SELECT id, latitude, longitude FROM images WHERE latitude IS NOT NULL AND longitude IS NOT NULL")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?, row.get::<_, f64>(2)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    drop(stmt);
+    let mut inside = 0;
+    for (id, latitude, longitude) in rows {
+        let entries = metadata(fences, (latitude, longitude));
+        inside += usize::from(!entries.is_empty());
+        for entry in entries {
+            tx.execute(
+                "INSERT INTO external_metadata (image_id, source, field, value) VALUES (?1, ?2, ?3, ?4)",
+                params![id, entry.source, entry.field, entry.value],
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(inside)
+}
+
+/// SQL condition keeping only photos outside every private fence.
+pub const SHAREABLE_CONDITION: &str =
+    "id NOT IN (SELECT image_id FROM external_metadata WHERE source = 'geofence' AND field = 'private')";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_geofences() -> Result<(), Error> {
+        let config = Config::parse(r#"
+            [[geofences]]
+            name = "Home"
+            latitude = 38.7100
+            longitude = -9.1400
+            private = true
+
+            [[geofences]]
+            name = "Grandma's house"
+            latitude = 41.1500
+            longitude = -8.6100
+            radius_meters = 250
+            tag = "grandma"
+        "#)?;
+        let fences = &config.geofences;
+        // About 50 m from home
+        let tags: Vec<(String, String)> = metadata(fences, (38.7104, -9.1400)).into_iter().map(|m| (m.field, m.value)).collect();
+        assert_eq!(tags, vec![(String::from("keyword"), String::from("Home")), (String::from("private"), String::from("Home"))]);
+        // About 200 m from Grandma's
+        assert_eq!(metadata(fences, (41.1518, -8.6100))[0].value, "grandma");
+        assert!(metadata(fences, (38.7200, -9.1400)).is_empty());
+
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, latitude, longitude) VALUES
+             ('/home.jpg', 'home.jpg', 1, 38.7101, -9.1401), ('/away.jpg', 'away.jpg', 1, 40.0, -8.0)",
+            [],
+        )?;
+        assert_eq!(retag(&conn, fences)?, 1);
+        // Retagging replaces rather than adds
+        assert_eq!(retag(&conn, fences)?, 1);
+        let shared: Vec<String> = conn.prepare(&format!("SELECT path FROM images WHERE {}", SHAREABLE_CONDITION))?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(shared, vec!["/away.jpg"]);
+
+        assert!(Config::parse("[[geofences]]\nname = \"x\"\nlatitude = 91\nlongitude = 0").is_err());
+        assert!(Config::parse("[[geofences]]\nname = \"x\"\nlatitude = 0\nlongitude = 0\nradius_meters = 0").is_err());
+        Ok(())
+    }
+}
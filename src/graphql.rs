@@ -0,0 +1,371 @@
+//! The catalog as GraphQL, at `/graphql` next to the REST routes, for
+//! frontends that would rather ask for the fields a gallery shows than
+//! take whole images from `/images/{id}`. Images, their tags (keywords, as
+//! `search --keyword` matches them) and albums (the catalog's own, those
+//! imported from Apple Photos and Lightroom's collections) each lead to the
+//! others, and lists of images are filtered as `search` is and come a page
+//! at a time.
+
+use std::path::PathBuf;
+use anyhow::Error;
+use async_graphql::{ComplexObject, Context, EmptyMutation, EmptySubscription, InputObject, Object, SimpleObject};
+use rusqlite::{params_from_iter, Connection, OptionalExtension};
+use crate::error::{CliError, ErrorKind};
+use crate::stats;
+use crate::{keywords, query, tags, SearchFilter};
+
+/// Images in a page when the query doesn't say, and at most.
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+/// Every album's name with each image in it: the catalog's albums, and the
+/// `external_metadata` entries that put an image in an imported one.
+const ALBUM_IMAGES: &str = "(SELECT albums.name, album_images.image_id FROM album_images JOIN albums ON albums.id = album_images.album_id
+    UNION ALL SELECT value, image_id FROM external_metadata
+    WHERE (source = 'apple_photos' AND field = 'album') OR (source = 'lightroom' AND field = 'collection'))";
+const IMAGE_COLUMNS: &str = "id, path, file_name, format, width, height, duration_secs, creation_date, latitude, longitude, city, region, \
+                             country, camera_make, camera_model, lens_model, iso, aperture, exposure_time, focal_length, description, \
+                             rating, favorite, rejected";
+
+pub type Schema = async_graphql::Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+/// The schema, reading the catalog at `catalog`.
+pub fn schema(catalog: PathBuf) -> Schema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription).data(Catalog(catalog)).finish()
+}
+
+struct Catalog(PathBuf);
+
+/// Run a catalog query off the async workers.
+async fn with_catalog<T: Send + 'static>(
+    ctx: &Context<'_>,
+    f: impl FnOnce(&Connection) -> Result<T, Error> + Send + 'static,
+) -> async_graphql::Result<T> {
+    let catalog = ctx.data::<Catalog>()?.0.clone();
+    Ok(tokio::task::spawn_blocking(move || f(&crate::open_catalog(&catalog)?)).await??)
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(complex)]
+pub struct Image {
+    id: i64,
+    path: String,
+    file_name: String,
+    format: Option<String>,
+    width: Option<i64>,
+    height: Option<i64>,
+    /// Of videos
+    duration_secs: Option<f64>,
+    /// As EXIF dates are: "2023-07-14 18:30:00"
+    creation_date: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    city: Option<String>,
+    region: Option<String>,
+    country: Option<String>,
+    camera_make: Option<String>,
+    camera_model: Option<String>,
+    lens_model: Option<String>,
+    iso: Option<i64>,
+    aperture: Option<f64>,
+    exposure_time: Option<f64>,
+    focal_length: Option<f64>,
+    /// The AI description, or its correction
+    description: Option<String>,
+    /// Stars, 1 to 5, or 0 if it hasn't been rated
+    rating: i64,
+    favorite: bool,
+    rejected: bool,
+    #[graphql(skip)]
+    keywords: Option<String>,
+}
+
+impl Image {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Image> {
+        Ok(Image {
+            id: row.get(0)?,
+            path: row.get(1)?,
+            file_name: row.get(2)?,
+            format: row.get(3)?,
+            width: row.get(4)?,
+            height: row.get(5)?,
+            duration_secs: row.get(6)?,
+            creation_date: row.get(7)?,
+            latitude: row.get(8)?,
+            longitude: row.get(9)?,
+            city: row.get(10)?,
+            region: row.get(11)?,
+            country: row.get(12)?,
+            camera_make: row.get(13)?,
+            camera_model: row.get(14)?,
+            lens_model: row.get(15)?,
+            iso: row.get(16)?,
+            aperture: row.get(17)?,
+            exposure_time: row.get(18)?,
+            focal_length: row.get(19)?,
+            description: row.get(20)?,
+            rating: row.get(21)?,
+            favorite: row.get(22)?,
+            rejected: row.get(23)?,
+            keywords: row.get(24)?,
+        })
+    }
+}
+
+#[ComplexObject]
+impl Image {
+    /// Its keywords, in order.
+    async fn tags(&self) -> Vec<Tag> {
+        keywords::split(self.keywords.as_deref().unwrap_or_default()).into_iter().map(|name| Tag { name }).collect()
+    }
+
+    /// The albums it's in.
+    async fn albums(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Album>> {
+        let id = self.id;
+        with_catalog(ctx, move |conn| {
+            let albums = conn
+                .prepare(&format!("SELECT DISTINCT name FROM {} WHERE image_id = ?1 ORDER BY name", ALBUM_IMAGES))?
+                .query_map([id], |row| Ok(Album { name: row.get(0)? }))?
+                .collect::<Result<_, _>>()?;
+            Ok(albums)
+        }).await
+    }
+
+    /// Where `serve` has an upright JPEG of it, `size` pixels on its
+    /// longest edge.
+    async fn thumbnail_url(&self, size: Option<u32>) -> String {
+        match size {
+            Some(size) => format!("/images/{}/thumbnail?size={}", self.id, size),
+            None => format!("/images/{}/thumbnail", self.id),
+        }
+    }
+}
+
+/// A page of images, and how many there are in all.
+#[derive(SimpleObject)]
+pub struct ImagePage {
+    total_count: i64,
+    items: Vec<Image>,
+}
+
+/// What the images have to match; every field given must. `query` takes
+/// `key:value` terms as `--query` does, and the other fields narrow it
+/// down further.
+#[derive(InputObject, Default)]
+pub struct ImageFilter {
+    query: Option<String>,
+    place: Option<String>,
+    camera: Option<String>,
+    lens: Option<String>,
+    keyword: Option<String>,
+    /// A year, month or day: "2023", "2023-07" or "2023-07-14"
+    date: Option<String>,
+    trip: Option<i64>,
+    album: Option<String>,
+    action: Option<String>,
+    /// Only videos with sound (true) or without (false)
+    sound: Option<bool>,
+    /// Rated at least this many stars
+    rating: Option<u8>,
+    favorite: Option<bool>,
+    rejected: Option<bool>,
+}
+
+impl ImageFilter {
+    fn search_filter(self) -> Result<SearchFilter, Error> {
+        let terms = query::parse(self.query.as_deref().unwrap_or_default()).map_err(|e| CliError::new(ErrorKind::Config, e))?;
+        let mut filter = query::filter(&terms);
+        filter.place = self.place.or(filter.place);
+        filter.camera = self.camera.or(filter.camera);
+        filter.lens = self.lens.or(filter.lens);
+        filter.keyword = self.keyword.or(filter.keyword);
+        if let Some(date) = self.date {
+            filter.date = Some(query::parse_date(&date).map_err(|e| CliError::new(ErrorKind::Config, e))?);
+        }
+        filter.trip = self.trip.or(filter.trip);
+        filter.album = self.album.or(filter.album);
+        filter.action = self.action.or(filter.action);
+        filter.sound = self.sound.or(filter.sound);
+        filter.rating = self.rating.or(filter.rating);
+        filter.favorite = self.favorite.or(filter.favorite);
+        filter.rejected = self.rejected.or(filter.rejected);
+        Ok(filter)
+    }
+}
+
+/// The images matching `filter` (and in `album`, if given), by path.
+fn page(conn: &Connection, filter: &SearchFilter, album: Option<&str>, limit: Option<usize>, offset: usize) -> Result<ImagePage, Error> {
+    let (mut clause, mut params) = crate::filter_clause(filter);
+    if let Some(album) = album {
+        clause.push_str(if clause.is_empty() { " WHERE " } else { " AND " });
+        clause.push_str(&format!("id IN (SELECT image_id FROM {} WHERE name = ?)", ALBUM_IMAGES));
+        params.push(Box::new(album.to_string()));
+    }
+    let total_count = conn.query_row(&format!("SELECT COUNT(*) FROM images{}", clause), params_from_iter(&params), |row| row.get(0))?;
+    params.push(Box::new(limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as i64));
+    params.push(Box::new(offset as i64));
+    let items = conn
+        .prepare(&format!("SELECT {}, {} FROM images{} ORDER BY path LIMIT ? OFFSET ?", IMAGE_COLUMNS, tags::KEYWORDS, clause))?
+        .query_map(params_from_iter(&params), Image::from_row)?
+        .collect::<Result<Vec<Image>, _>>()?;
+    crate::tiering::record(conn, &items.iter().map(|image| image.id).collect::<Vec<_>>())?;
+    Ok(ImagePage { total_count, items })
+}
+
+/// A keyword, and the images that have it.
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct Tag {
+    name: String,
+}
+
+#[ComplexObject]
+impl Tag {
+    async fn images(&self, ctx: &Context<'_>, limit: Option<usize>, #[graphql(default)] offset: usize) -> async_graphql::Result<ImagePage> {
+        let filter = SearchFilter { keyword: Some(self.name.clone()), ..SearchFilter::default() };
+        with_catalog(ctx, move |conn| page(conn, &filter, None, limit, offset)).await
+    }
+}
+
+/// An album (the catalog's, Apple Photos' or a Lightroom collection), and
+/// the images in it.
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct Album {
+    name: String,
+}
+
+#[ComplexObject]
+impl Album {
+    async fn images(&self, ctx: &Context<'_>, limit: Option<usize>, #[graphql(default)] offset: usize) -> async_graphql::Result<ImagePage> {
+        let name = self.name.clone();
+        with_catalog(ctx, move |conn| page(conn, &SearchFilter::default(), Some(&name), limit, offset)).await
+    }
+}
+
+/// A tag and how many images have it.
+#[derive(SimpleObject)]
+pub struct TagCount {
+    tag: Tag,
+    count: i64,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// A page of the images matching `filter`, by path.
+    async fn images(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: ImageFilter,
+        limit: Option<usize>,
+        #[graphql(default)] offset: usize,
+    ) -> async_graphql::Result<ImagePage> {
+        let filter = filter.search_filter()?;
+        with_catalog(ctx, move |conn| page(conn, &filter, None, limit, offset)).await
+    }
+
+    async fn image(&self, ctx: &Context<'_>, id: i64) -> async_graphql::Result<Option<Image>> {
+        with_catalog(ctx, move |conn| {
+            let image = conn.query_row(&format!("SELECT {}, {} FROM images WHERE id = ?1", IMAGE_COLUMNS, tags::KEYWORDS), [id], Image::from_row).optional()?;
+            if image.is_some() {
+                crate::tiering::record(conn, &[id])?;
+            }
+            Ok(image)
+        }).await
+    }
+
+    /// The keywords in the catalog, most used first, with how many images
+    /// have each.
+    async fn tags(&self, ctx: &Context<'_>, limit: Option<usize>) -> async_graphql::Result<Vec<TagCount>> {
+        with_catalog(ctx, move |conn| {
+            let mut counts = stats::keyword_counts(conn)?;
+            counts.truncate(limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT));
+            Ok(counts.into_iter().map(|(name, count)| TagCount { tag: Tag { name }, count }).collect())
+        }).await
+    }
+
+    async fn tag(&self, name: String) -> Tag {
+        Tag { name }
+    }
+
+    /// The albums in the catalog, by name.
+    async fn albums(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Album>> {
+        with_catalog(ctx, |conn| {
+            let albums = conn
+                // Including the catalog's with nothing in them yet
+                .prepare(&format!("SELECT name FROM albums UNION SELECT name FROM {} ORDER BY name", ALBUM_IMAGES))?
+                .query_map([], |row| Ok(Album { name: row.get(0)? }))?
+                .collect::<Result<_, _>>()?;
+            Ok(albums)
+        }).await
+    }
+
+    async fn album(&self, ctx: &Context<'_>, name: String) -> async_graphql::Result<Option<Album>> {
+        with_catalog(ctx, move |conn| {
+            let exists = conn
+                .query_row(&format!("SELECT 1 FROM (SELECT name FROM albums UNION SELECT name FROM {}) WHERE name = ?1", ALBUM_IMAGES), [&name], |_| Ok(()))
+                .optional()?;
+            Ok(exists.map(|()| Album { name }))
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_queries() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let catalog = dir.path().join("catalog.db");
+        let conn = crate::open_catalog(&catalog)?;
+        conn.execute_batch(
+            "INSERT INTO images (path, file_name, file_size, creation_date, camera_model) VALUES
+                ('/a.jpg', 'a.jpg', 1, '2023-07-14 10:00:00', 'X100V'),
+                ('/b.jpg', 'b.jpg', 1, '2023-07-15 10:00:00', 'iPhone 15'),
+                ('/c.jpg', 'c.jpg', 1, '2022-01-01 10:00:00', 'X100V');
+             INSERT INTO external_metadata (image_id, source, field, value) VALUES
+                (1, 'apple_photos', 'album', 'Lisbon'), (2, 'lightroom', 'collection', 'Lisbon'), (3, 'lightroom', 'keyword', 'snow');
+             INSERT INTO albums (name, created_at) VALUES ('Winter', '2024-01-01');
+             INSERT INTO album_images (album_id, image_id, added_at) VALUES (1, 3, '2024-01-01');",
+        )?;
+        crate::test_support::set_keywords(&conn, &[("/a.jpg", "beach, sea"), ("/b.jpg", "beach")]);
+        let schema = schema(catalog);
+        let run = |query: &str| {
+            let schema = schema.clone();
+            let query = query.to_string();
+            async move {
+                let response = schema.execute(query).await;
+                assert!(response.errors.is_empty(), "{:?}", response.errors);
+                response.data.into_json().unwrap()
+            }
+        };
+
+        let data = run(r#"{ images(filter: { query: "camera:X100V", date: "2023" }) { totalCount items { fileName tags { name } albums { name } } } }"#).await;
+        assert_eq!(data["images"], json!({ "totalCount": 1, "items": [{ "fileName": "a.jpg", "tags": [{ "name": "beach" }, { "name": "sea" }], "albums": [{ "name": "Lisbon" }] }] }));
+
+        let data = run("{ images(limit: 1, offset: 1) { totalCount items { path thumbnailUrl(size: 200) } } }").await;
+        assert_eq!(data["images"], json!({ "totalCount": 3, "items": [{ "path": "/b.jpg", "thumbnailUrl": "/images/2/thumbnail?size=200" }] }));
+
+        let data = run("{ tags { tag { name } count } }").await;
+        assert_eq!(data["tags"], json!([
+            { "tag": { "name": "beach" }, "count": 2 },
+            { "tag": { "name": "sea" }, "count": 1 },
+            { "tag": { "name": "snow" }, "count": 1 },
+        ]));
+        let data = run(r#"{ tag(name: "snow") { images { items { path } } } albums { name images { totalCount } } }"#).await;
+        assert_eq!(data["tag"]["images"]["items"], json!([{ "path": "/c.jpg" }]));
+        assert_eq!(data["albums"], json!([{ "name": "Lisbon", "images": { "totalCount": 2 } }, { "name": "Winter", "images": { "totalCount": 1 } }]));
+        let data = run(r#"{ images(filter: { album: "Winter" }) { items { path albums { name } } } }"#).await;
+        assert_eq!(data["images"]["items"], json!([{ "path": "/c.jpg", "albums": [{ "name": "Winter" }] }]));
+
+        let data = run(r#"{ image(id: 3) { creationDate } missing: image(id: 9) { path } album(name: "Porto") { name } }"#).await;
+        assert_eq!(data, json!({ "image": { "creationDate": "2022-01-01 10:00:00" }, "missing": null, "album": null }));
+
+        let response = schema.execute(r#"{ images(filter: { query: "colour:red" }) { totalCount } }"#).await;
+        assert!(response.errors[0].message.contains("unknown query key"));
+        Ok(())
+    }
+}
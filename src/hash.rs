@@ -0,0 +1,61 @@
+//! Content hashes: the SHA-256 of a file's bytes, in hex. They identify a
+//! photo wherever it lives, so catalogs can be merged and files followed
+//! across machines and renames.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+pub fn of_bytes(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+/// The hash of the file at `path`, read in pieces so videos never have to
+/// fit in memory.
+pub fn of_file(path: &Path) -> io::Result<String> {
+    digest_file::<Sha256>(path)
+}
+
+/// The SHA-1 of the file at `path`, which is what Immich identifies
+/// uploads by.
+pub fn sha1_of_file(path: &Path) -> io::Result<String> {
+    digest_file::<Sha1>(path)
+}
+
+fn digest_file<D: Digest>(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = D::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hash() -> Result<(), io::Error> {
+        let dir = tempdir()?;
+        let path = dir.path().join("photo.jpg");
+        std::fs::write(&path, b"abc")?;
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(of_bytes(b"abc"), expected);
+        assert_eq!(of_file(&path)?, expected);
+        assert_eq!(sha1_of_file(&path)?, "a9993e364706816aba3e25717850c26c9cd0d89d");
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+//! HEIF/HEIC images, as iPhones take them, and AVIF, which is the same
+//! container with AV1 inside. The container is parsed here for dimensions
+//! and the EXIF reader handles it itself; decoding HEVC and AV1 is left to
+//! libheif, whose pixels are re-encoded as JPEG for analysis.
+
+use std::path::Path;
+use anyhow::Error;
+#[cfg(feature = "heif")]
+use anyhow::anyhow;
+#[cfg(not(feature = "heif"))]
+use anyhow::bail;
+#[cfg(feature = "heif")]
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+use crate::isobmff::{be, boxes, find_box};
+
+/// Extensions handled here, with the format name recorded for each
+#[cfg(feature = "heif")]
+pub const FORMATS: &[(&str, &str)] = &[
+    ("heic", "Heif"),
+    ("heif", "Heif"),
+    ("hif", "Heif"),
+    ("avif", "Avif"),
+];
+
+/// The format name for a path, if its extension is one we handle.
+#[cfg(feature = "heif")]
+pub fn format_for(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_string_lossy().to_lowercase();
+    FORMATS.iter().find(|(e, _)| *e == ext).map(|(_, format)| *format)
+}
+
+/// Builds without the `heif` feature catalog no HEIF or AVIF files.
+#[cfg(not(feature = "heif"))]
+pub fn format_for(_path: &Path) -> Option<&'static str> {
+    None
+}
+
+pub fn is_heif(path: &Path) -> bool {
+    format_for(path).is_some()
+}
+
+/// The primary image of `data` as JPEG bytes, turned and mirrored as the
+/// file says.
+#[cfg(feature = "heif")]
+pub fn to_jpeg(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let context = HeifContext::read_from_bytes(data)?;
+    let image = LibHeif::new().decode(&context.primary_image_handle()?, ColorSpace::Rgb(RgbChroma::Rgb), None)?;
+    let plane = image.planes().interleaved.ok_or_else(|| anyhow!("no RGB plane decoded"))?;
+    // Rows can be padded past the image's width
+    let row_len = plane.width as usize * 3;
+    let pixels: Vec<u8> = plane.data.chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| row.get(..row_len).unwrap_or(row))
+        .copied()
+        .collect();
+    let rgb = image::RgbImage::from_raw(plane.width, plane.height, pixels).ok_or_else(|| anyhow!("short RGB plane"))?;
+    crate::encode_jpeg(&image::DynamicImage::ImageRgb8(rgb))
+}
+
+/// Builds without the `heif` feature have no decoder.
+#[cfg(not(feature = "heif"))]
+pub fn to_jpeg(_data: &[u8]) -> Result<Vec<u8>, Error> {
+    bail!("this build was compiled without the heif feature")
+}
+
+/// Width and height of the primary image as displayed: its `ispe`
+/// property, turned by its `irot` property if it has one. (HEIF ignores
+/// the EXIF orientation.)
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let meta = find_box(data, b"meta")?;
+    // `meta` is a full box: skip version and flags
+    let meta = meta.get(4..)?;
+    let pitm = find_box(meta, b"pitm")?;
+    let primary = if *pitm.first()? == 0 { be(pitm.get(4..6)?) } else { be(pitm.get(4..8)?) };
+
+    let iprp = find_box(meta, b"iprp")?;
+    let properties: Vec<(&[u8; 4], &[u8])> = boxes(find_box(iprp, b"ipco")?).collect();
+    let ipma = find_box(iprp, b"ipma")?;
+    let (version, flags) = (*ipma.first()?, *ipma.get(3)?);
+    let count = be(ipma.get(4..8)?);
+    let mut pos = 8;
+    let (mut size, mut quarter_turns) = (None, 0);
+    for _ in 0..count {
+        let id_len = if version < 1 { 2 } else { 4 };
+        let item = be(ipma.get(pos..pos + id_len)?);
+        let associations = *ipma.get(pos + id_len)? as usize;
+        pos += id_len + 1;
+        let index_len = if flags & 1 == 1 { 2 } else { 1 };
+        for i in 0..associations {
+            let raw = be(ipma.get(pos + i * index_len..pos + (i + 1) * index_len)?);
+            // The top bit marks an essential property
+            let index = (raw & if index_len == 2 { 0x7fff } else { 0x7f }) as usize;
+            if item == primary && index > 0 {
+                match properties.get(index - 1) {
+                    Some((b"ispe", ispe)) => size = Some((be(ispe.get(4..8)?), be(ispe.get(8..12)?))),
+                    Some((b"irot", irot)) => quarter_turns = irot.first()? & 3,
+                    _ => {}
+                }
+            }
+        }
+        pos += associations * index_len;
+    }
+    size.map(|(width, height)| if quarter_turns % 2 == 1 { (height, width) } else { (width, height) })
+}
+
+/// The XMP packet, stored as an item of MIME type `application/rdf+xml`.
+pub fn xmp(data: &[u8]) -> Option<&[u8]> {
+    let meta = find_box(data, b"meta")?.get(4..)?;
+
+    let iinf = find_box(meta, b"iinf")?;
+    let entries = if *iinf.first()? == 0 { iinf.get(6..)? } else { iinf.get(8..)? };
+    let item = boxes(entries).filter(|(kind, _)| *kind == b"infe").find_map(|(_, infe)| {
+        // Versions 2 and 3 only; older ones predate item types
+        let (id, rest) = match *infe.first()? {
+            2 => (be(infe.get(4..6)?), infe.get(8..)?),
+            3 => (be(infe.get(4..8)?), infe.get(10..)?),
+            _ => return None,
+        };
+        let (kind, rest) = rest.split_at_checked(4)?;
+        // Skip the item name, then the content type follows
+        let rest = &rest[rest.iter().position(|&b| b == 0)? + 1..];
+        let content_type = rest.split(|&b| b == 0).next()?;
+        (kind == b"mime" && content_type == b"application/rdf+xml").then_some(id)
+    })?;
+
+    let (offset, len) = item_location(find_box(meta, b"iloc")?, item)?;
+    data.get(offset..offset.checked_add(len)?)
+}
+
+/// File offset and length of an item stored as one extent, from `iloc`.
+fn item_location(iloc: &[u8], item: u32) -> Option<(usize, usize)> {
+    let version = *iloc.first()?;
+    let (sizes, more) = (*iloc.get(4)?, *iloc.get(5)?);
+    let (offset_size, length_size) = ((sizes >> 4) as usize, (sizes & 0xF) as usize);
+    let base_offset_size = (more >> 4) as usize;
+    let index_size = if version >= 1 { (more & 0xF) as usize } else { 0 };
+    let (count, mut pos) = if version < 2 { (be(iloc.get(6..8)?), 8) } else { (be(iloc.get(6..10)?), 10) };
+
+    let mut read = |size: usize| {
+        let value = be(iloc.get(pos..pos + size)?);
+        pos += size;
+        Some(value as usize)
+    };
+    for _ in 0..count {
+        let id = read(if version < 2 { 2 } else { 4 })?;
+        // Construction method; only plain file offsets (0) are handled
+        let method = if version >= 1 { read(2)? & 0xF } else { 0 };
+        read(2)?;
+        let base = read(base_offset_size)?;
+        let extents = read(2)?;
+        let mut location = None;
+        for _ in 0..extents {
+            read(index_size)?;
+            let (offset, len) = (read(offset_size)?, read(length_size)?);
+            location = Some((base + offset, len));
+        }
+        if id == item as usize {
+            return if method == 0 && extents == 1 { location } else { None };
+        }
+    }
+    None
+}
+
+#[cfg(all(test, feature = "heif"))]
+mod tests {
+    use super::*;
+
+    fn make_box(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        let mut b = ((contents.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(kind);
+        b.extend_from_slice(contents);
+        b
+    }
+
+    /// The boxes of a HEIC with a 4032x3024 primary item and a thumbnail,
+    /// the primary turned a quarter if `portrait`.
+    fn heic_turned(portrait: bool) -> Vec<u8> {
+        let ispe = |w: u32, h: u32| make_box(b"ispe", &[&[0; 4][..], &w.to_be_bytes(), &h.to_be_bytes()].concat());
+        let irot = make_box(b"irot", &[if portrait { 3 } else { 0 }]);
+        let ipco = make_box(b"ipco", &[ispe(320, 240), make_box(b"hvcC", &[0; 4]), ispe(4032, 3024), irot].concat());
+        // Item 2 (thumbnail) has property 1; item 1 (primary) has 2, 3 and 4
+        let ipma = make_box(b"ipma", &[0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 1, 0x81, 0, 1, 3, 0x82, 0x03, 0x84]);
+        let meta = [
+            &[0; 4][..],
+            &make_box(b"hdlr", &[0; 20]),
+            &make_box(b"pitm", &[0, 0, 0, 0, 0, 1]),
+            &make_box(b"iprp", &[ipco, ipma].concat()),
+        ].concat();
+        [make_box(b"ftyp", b"heicmif1"), make_box(b"meta", &meta)].concat()
+    }
+
+    fn heic() -> Vec<u8> {
+        heic_turned(false)
+    }
+
+    #[test]
+    fn test_dimensions() {
+        assert_eq!(dimensions(&heic()), Some((4032, 3024)));
+        assert_eq!(dimensions(&heic_turned(true)), Some((3024, 4032)));
+        assert_eq!(dimensions(b"not a heif file"), None);
+    }
+
+    #[test]
+    fn test_xmp() {
+        const PACKET: &[u8] = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>";
+        let infe = |id: u8, kind: &[u8; 4], extra: &[u8]| make_box(b"infe", &[&[2, 0, 0, 0, 0, id, 0, 0][..], kind, extra].concat());
+        let iinf = make_box(b"iinf", &[
+            &[0, 0, 0, 0, 0, 2][..],
+            &infe(1, b"av01", b"\0"),
+            &infe(2, b"mime", b"XMP\0application/rdf+xml\0"),
+        ].concat());
+        let ftyp = make_box(b"ftyp", b"avifmif1");
+        // Version 0, 4-byte offsets and lengths, no base offset; the offset
+        // is filled in once the layout is known
+        let iloc = |offset: u32| make_box(b"iloc", &[
+            &[0, 0, 0, 0, 0x44, 0, 0, 1, 0, 2, 0, 0, 0, 1][..],
+            &offset.to_be_bytes(),
+            &(PACKET.len() as u32).to_be_bytes(),
+        ].concat());
+        let meta = |offset| make_box(b"meta", &[&[0; 4][..], &iinf, &iloc(offset)].concat());
+        let offset = (ftyp.len() + meta(0).len() + 8) as u32;
+        let avif = [ftyp, meta(offset), make_box(b"mdat", PACKET)].concat();
+
+        assert_eq!(xmp(&avif), Some(PACKET));
+        assert_eq!(xmp(&heic()), None);
+    }
+
+    #[test]
+    fn test_to_jpeg() -> Result<(), Error> {
+        use libheif_rs::{Channel, CompressionFormat, EncoderQuality, Image};
+
+        // A 64x48 HEIC, red on the left half and blue on the right
+        let (width, height) = (64, 48);
+        let mut image = Image::new(width, height, ColorSpace::Rgb(RgbChroma::Rgb))?;
+        image.create_plane(Channel::Interleaved, width, height, 8)?;
+        let plane = image.planes_mut().interleaved.unwrap();
+        for row in plane.data.chunks_mut(plane.stride).take(height as usize) {
+            for x in 0..width as usize {
+                let pixel = if x < 32 { [255, 0, 0] } else { [0, 0, 255] };
+                row[x * 3..x * 3 + 3].copy_from_slice(&pixel);
+            }
+        }
+        let lib_heif = LibHeif::new();
+        let mut encoder = lib_heif.encoder_for_format(CompressionFormat::Hevc)?;
+        encoder.set_quality(EncoderQuality::Lossy(95))?;
+        let mut context = HeifContext::new()?;
+        context.encode_image(&image, &mut encoder, None)?;
+        let heic = context.write_to_bytes()?;
+
+        let jpeg = image::load_from_memory_with_format(&to_jpeg(&heic)?, image::ImageFormat::Jpeg)?.to_rgb8();
+        assert_eq!(jpeg.dimensions(), (64, 48));
+        let (left, right) = (jpeg.get_pixel(8, 24), jpeg.get_pixel(56, 24));
+        assert!(left[0] > 200 && left[2] < 60, "{:?}", left);
+        assert!(right[2] > 200 && right[0] < 60, "{:?}", right);
+        assert!(is_heif(Path::new("IMG_0001.HEIC")));
+        assert_eq!(format_for(Path::new("export.AVIF")), Some("Avif"));
+        assert!(to_jpeg(b"not a heif file").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+//! Help beyond each command's `--help`. Topics too long for an option's
+//! line (`help query-language`, `help config`, `help analyzers`) are built
+//! into the binary from `help/*.txt`, so they're there offline, and
+//! `manpages` writes a page per command from the same definitions `--help`
+//! is made from, plus one per topic.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::Error;
+use clap::Command;
+use crate::error::{CliError, ErrorKind};
+
+pub struct Topic {
+    pub name: &'static str,
+    pub summary: &'static str,
+    pub text: &'static str,
+}
+
+pub const TOPICS: &[Topic] = &[
+    Topic { name: "query-language", summary: "the key:value terms --query takes", text: include_str!("help/query-language.txt") },
+    Topic { name: "config", summary: "the sections of photo_catalog.toml", text: include_str!("help/config.txt") },
+    Topic { name: "analyzers", summary: "the models and hosts images are analyzed by", text: include_str!("help/analyzers.txt") },
+];
+
+/// Help for `words`: a topic, or the command they name (`tag add`), or
+/// everything there is with no words at all.
+pub fn show(mut cli: Command, words: &[String]) -> Result<String, Error> {
+    if let [word] = words {
+        if let Some(topic) = TOPICS.iter().find(|topic| topic.name == word.as_str()) {
+            return Ok(topic.text.to_string());
+        }
+    }
+    cli.build();
+    let mut command = &cli;
+    for word in words {
+        command = command.find_subcommand(word).ok_or_else(|| {
+            let topics: Vec<&str> = TOPICS.iter().map(|topic| topic.name).collect();
+            let message = format!("no command or help topic {:?}; the topics are {}", words.join(" "), topics.join(", "));
+            CliError::new(ErrorKind::Config, message)
+        })?;
+    }
+    Ok(command.clone().render_long_help().to_string())
+}
+
+/// Write a manpage for `cli` and each of its commands, and one for each
+/// topic, into `dir`. Gives the files written.
+pub fn manpages(mut cli: Command, dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    cli.build();
+    fs::create_dir_all(dir)?;
+    let stem = cli.get_name().to_lowercase();
+    let version = cli.get_version().unwrap_or_default().to_string();
+    let mut pages = Vec::new();
+    let mut written = Vec::new();
+    collect(&cli, std::slice::from_ref(&stem), &mut pages);
+    for (names, command) in &pages {
+        let path = dir.join(format!("{}.1", names.join("-")));
+        fs::write(&path, page(command, names, &version))?;
+        written.push(path);
+    }
+    for topic in TOPICS {
+        let name = format!("{}-{}", stem, topic.name);
+        let mut text = header(&name, 7, &version);
+        text.push_str(&format!(".SH NAME\n{} \\- {}\n.SH DESCRIPTION\n", roff(&name), roff(topic.summary)));
+        text.push_str(&paragraphs(topic.text));
+        text.push_str(&format!(".SH SEE ALSO\n.BR {} (1)\n", roff(&stem)));
+        let path = dir.join(format!("{}.7", name));
+        fs::write(&path, text)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// `command` and every command under it, with the names leading to each.
+fn collect<'a>(command: &'a Command, names: &[String], pages: &mut Vec<(Vec<String>, &'a Command)>) {
+    pages.push((names.to_vec(), command));
+    for sub in command.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+        let mut names = names.to_vec();
+        names.push(sub.get_name().to_string());
+        collect(sub, &names, pages);
+    }
+}
+
+fn header(name: &str, section: u8, version: &str) -> String {
+    format!(".TH \"{}\" {} \"\" \"PhotoCataloger {}\" \"PhotoCataloger Manual\"\n", roff(&name.to_uppercase()), section, roff(version))
+}
+
+/// The page for `command`, reached by `names` (`photocataloger tag add`).
+fn page(command: &Command, names: &[String], version: &str) -> String {
+    let mut text = header(&names.join("-"), 1, version);
+    let about = command.get_about().map(|about| about.to_string()).unwrap_or_default();
+    text.push_str(&format!(".SH NAME\n{} \\- {}\n", roff(&names.join("-")), roff(&about)));
+
+    let usage = command.clone().render_usage().to_string();
+    let usage: Vec<&str> = usage.trim().trim_start_matches("Usage:").lines().map(str::trim).collect();
+    text.push_str(&format!(".SH SYNOPSIS\n.nf\n{}\n.fi\n", roff(&usage.join("\n"))));
+    if let Some(long) = command.get_long_about() {
+        text.push_str(".SH DESCRIPTION\n");
+        text.push_str(&paragraphs(&long.to_string()));
+    }
+
+    let arguments: Vec<&clap::Arg> = command.get_arguments().filter(|arg| !arg.is_hide_set()).collect();
+    for (heading, positional) in [("ARGUMENTS", true), ("OPTIONS", false)] {
+        let listed: Vec<&&clap::Arg> = arguments.iter().filter(|arg| arg.is_positional() == positional).collect();
+        if listed.is_empty() {
+            continue;
+        }
+        text.push_str(&format!(".SH {}\n", heading));
+        for arg in listed {
+            text.push_str(&format!(".TP\n{}\n", roff_styled(&term(arg))));
+            let help = arg.get_long_help().or(arg.get_help()).map(|help| help.to_string()).unwrap_or_default();
+            let mut notes = Vec::new();
+            let defaults: Vec<String> = arg.get_default_values().iter().map(|value| value.to_string_lossy().into_owned()).collect();
+            if !defaults.is_empty() && arg.get_action().takes_values() {
+                notes.push(format!("[default: {}]", defaults.join(", ")));
+            }
+            let possible: Vec<String> = arg.get_possible_values().iter().filter(|value| !value.is_hide_set()).map(|value| value.get_name().to_string()).collect();
+            if !possible.is_empty() && arg.get_action().takes_values() {
+                notes.push(format!("[possible values: {}]", possible.join(", ")));
+            }
+            let line = [help, notes.join(" ")].into_iter().filter(|part| !part.is_empty()).collect::<Vec<_>>().join(" ");
+            if !line.is_empty() {
+                text.push_str(&format!("{}\n", roff(&line)));
+            }
+        }
+    }
+
+    let subcommands: Vec<&Command> = command.get_subcommands().filter(|sub| !sub.is_hide_set()).collect();
+    if !subcommands.is_empty() {
+        text.push_str(".SH COMMANDS\n");
+        for sub in &subcommands {
+            let about = sub.get_about().map(|about| about.to_string()).unwrap_or_default();
+            text.push_str(&format!(".TP\n\\fB{}\\fR\n{}\n", roff(sub.get_name()), roff(&about)));
+        }
+    }
+
+    text.push_str(".SH SEE ALSO\n");
+    let mut see: Vec<String> = Vec::new();
+    if names.len() > 1 {
+        see.push(format!(".BR {} (1)", roff(&names[..names.len() - 1].join("-"))));
+    }
+    see.extend(subcommands.iter().map(|sub| format!(".BR {} (1)", roff(&format!("{}-{}", names.join("-"), sub.get_name())))));
+    if names.len() == 1 {
+        see.extend(TOPICS.iter().map(|topic| format!(".BR {} (7)", roff(&format!("{}-{}", names[0], topic.name)))));
+    }
+    text.push_str(&see.join(",\n"));
+    text.push('\n');
+    text
+}
+
+/// How an argument is written on its page's list: `--limit <LIMIT>`,
+/// bold for what's typed as is. Parts are split by `\0` for styling.
+fn term(arg: &clap::Arg) -> String {
+    let value = || match arg.get_value_names() {
+        Some(names) => names.iter().map(|name| format!("<{}>", name)).collect::<Vec<_>>().join(" "),
+        None => format!("<{}>", arg.get_id().as_str().to_uppercase()),
+    };
+    if arg.is_positional() {
+        return value();
+    }
+    let mut flags = Vec::new();
+    if let Some(short) = arg.get_short() {
+        flags.push(format!("\0-{}\0", short));
+    }
+    if let Some(long) = arg.get_long() {
+        flags.push(format!("\0--{}\0", long));
+    }
+    let mut term = flags.join(", ");
+    if arg.get_action().takes_values() {
+        term.push(' ');
+        term.push_str(&value());
+    }
+    term
+}
+
+/// `text` with the parts between `\0`s in bold.
+fn roff_styled(text: &str) -> String {
+    text.split('\0')
+        .enumerate()
+        .map(|(i, part)| match i % 2 {
+            1 => format!("\\fB{}\\fR", roff_inline(part)),
+            _ => roff_inline(part),
+        })
+        .collect()
+}
+
+/// Plain text as paragraphs, keeping indented lines (examples, tables) as
+/// they are.
+fn paragraphs(text: &str) -> String {
+    let mut out = String::new();
+    for block in text.trim().split("\n\n") {
+        match block.lines().all(|line| line.starts_with("  ")) {
+            true => out.push_str(&format!(".PP\n.nf\n{}\n.fi\n", roff(block))),
+            false => out.push_str(&format!(".PP\n{}\n", roff(block))),
+        }
+    }
+    out
+}
+
+/// `text` as roff, so backslashes, hyphens and lines starting with a dot
+/// come out as written.
+fn roff(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let line = roff_inline(line);
+            match line.starts_with('.') || line.starts_with('\'') {
+                true => format!("\\&{}", line),
+                false => line,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn roff_inline(text: &str) -> String {
+    text.replace('\\', "\\e").replace('-', "\\-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_show() -> Result<(), Error> {
+        let show = |words: &[&str]| show(crate::Cli::command(), &words.iter().map(|word| word.to_string()).collect::<Vec<_>>());
+        assert!(show(&["query-language"])?.contains("rating:N"));
+        assert!(show(&[])?.contains("query-language"));
+        let help = show(&["tag", "add"])?;
+        assert!(help.contains("Add a keyword to the photos matching a query") && help.contains("--query"));
+        assert!(show(&["tag", "nonsense"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_manpages() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let written = manpages(crate::Cli::command(), dir.path())?;
+        assert!(written.contains(&dir.path().join("photocataloger-query-language.7")));
+
+        let root = fs::read_to_string(dir.path().join("photocataloger.1"))?;
+        assert!(root.starts_with(".TH \"PHOTOCATALOGER\" 1 "));
+        assert!(root.contains(".BR photocataloger\\-scan (1)"));
+        let add = fs::read_to_string(dir.path().join("photocataloger-tag-add.1"))?;
+        assert!(add.contains("photocataloger\\-tag\\-add \\- Add a keyword"));
+        assert!(add.contains("\\fB\\-\\-query\\fR <QUERY>"));
+        assert!(add.contains(".BR photocataloger\\-tag (1)"));
+        // Global options are on every page
+        assert!(add.contains("\\fB\\-\\-dry\\-run\\fR"));
+        let stats = fs::read_to_string(dir.path().join("photocataloger-stats.1"))?;
+        assert!(stats.contains("[default: 10]"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_roff() {
+        assert_eq!(roff(".hidden\nC:\\photos - 2023"), "\\&.hidden\nC:\\ephotos \\- 2023");
+        assert_eq!(roff_styled("\0--limit\0 <N>"), "\\fB\\-\\-limit\\fR <N>");
+    }
+}
@@ -0,0 +1,80 @@
+//! Histograms for the web page's details: how many pixels of an image have
+//! each level of red, green, blue and luminance. Working one out means
+//! decoding the image, so each is kept in `histograms` with the content
+//! hash it was made from, and made again once the file changes.
+
+use anyhow::Error;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+/// Longest edge of the copy counted; more pixels don't change the shape.
+pub const EDGE: u32 = 512;
+const LEVELS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    pub red: Vec<u32>,
+    pub green: Vec<u32>,
+    pub blue: Vec<u32>,
+    /// Rec. 709 luma, as editors show it
+    pub luminance: Vec<u32>,
+}
+
+pub fn compute(img: &image::RgbImage) -> Histogram {
+    let mut histogram = Histogram { red: vec![0; LEVELS], green: vec![0; LEVELS], blue: vec![0; LEVELS], luminance: vec![0; LEVELS] };
+    for pixel in img.pixels() {
+        let [r, g, b] = pixel.0;
+        histogram.red[r as usize] += 1;
+        histogram.green[g as usize] += 1;
+        histogram.blue[b as usize] += 1;
+        let luminance = 0.2126 * r as f64 + 0.7152 * g as f64 + 0.0722 * b as f64;
+        histogram.luminance[(luminance.round() as usize).min(LEVELS - 1)] += 1;
+    }
+    histogram
+}
+
+/// Image `id`'s histogram as kept, if it was made from the file with
+/// `content_hash`.
+pub fn cached(conn: &Connection, id: i64, content_hash: Option<&str>) -> Result<Option<Histogram>, Error> {
+    let levels: Option<String> = conn
+        .query_row("SELECT levels FROM histograms WHERE image_id = ?1 AND content_hash IS ?2", params![id, content_hash], |row| row.get(0))
+        .optional()?;
+    Ok(levels.map(|levels| serde_json::from_str(&levels)).transpose()?)
+}
+
+pub fn store(conn: &Connection, id: i64, content_hash: Option<&str>, histogram: &Histogram) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO histograms (image_id, content_hash, levels) VALUES (?1, ?2, ?3)
+         ON CONFLICT (image_id) DO UPDATE SET content_hash = excluded.content_hash, levels = excluded.levels",
+        params![id, content_hash, serde_json::to_string(histogram)?],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() -> Result<(), Error> {
+        let mut img = image::RgbImage::from_pixel(4, 1, image::Rgb([255, 0, 0]));
+        img.put_pixel(3, 0, image::Rgb([255, 255, 255]));
+        let histogram = compute(&img);
+        assert_eq!((histogram.red[255], histogram.green[0], histogram.green[255], histogram.blue[0]), (4, 3, 1, 3));
+        // Pure red is dark, white is as bright as it gets
+        assert_eq!((histogram.luminance[54], histogram.luminance[255]), (3, 1));
+        assert_eq!(histogram.luminance.iter().sum::<u32>(), 4);
+
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute("INSERT INTO images (path, file_name, file_size, content_hash) VALUES ('/a.jpg', 'a.jpg', 1, 'abc')", [])?;
+        assert_eq!(cached(&conn, 1, Some("abc"))?, None);
+        store(&conn, 1, Some("abc"), &histogram)?;
+        assert_eq!(cached(&conn, 1, Some("abc"))?, Some(histogram.clone()));
+        // Since changed
+        assert_eq!(cached(&conn, 1, Some("def"))?, None);
+        store(&conn, 1, None, &histogram)?;
+        assert_eq!(cached(&conn, 1, None)?, Some(histogram));
+        Ok(())
+    }
+}
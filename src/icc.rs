@@ -0,0 +1,200 @@
+//! ICC color profiles, as far as comparing pixels needs them: the
+//! matrix/curve profiles cameras, phones and editors embed (sRGB, Display
+//! P3, Adobe RGB, ProPhoto). Pixels go through the profile's curves and
+//! matrix to linear sRGB, so a P3 original and its sRGB export come out
+//! alike. Profiles we can't read are taken to be sRGB, as viewers do.
+
+/// What a matrix/curve profile takes to get from its RGB to the profile
+/// connection space (CIE XYZ, D50).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    /// Columns are the red, green and blue primaries
+    to_xyz: [[f64; 3]; 3],
+    curves: [Curve; 3],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Curve {
+    Gamma(f64),
+    /// ICC parametric curve parameters g, a, b, c, d, e, f
+    Parametric([f64; 7]),
+    Table(Vec<f64>),
+}
+
+impl Curve {
+    /// The linear value of an encoded one, both 0 to 1.
+    fn linear(&self, x: f64) -> f64 {
+        match self {
+            Curve::Gamma(g) => x.powf(*g),
+            Curve::Parametric([g, a, b, c, d, e, f]) => {
+                if x >= *d { (a * x + b).max(0.0).powf(*g) + e } else { c * x + f }
+            }
+            Curve::Table(table) => {
+                let at = x.clamp(0.0, 1.0) * (table.len() - 1) as f64;
+                let (i, frac) = (at.floor() as usize, at.fract());
+                let next = table.get(i + 1).unwrap_or(&table[i]);
+                table[i] + (next - table[i]) * frac
+            }
+        }
+    }
+}
+
+/// sRGB's primaries adapted to D50, as its ICC profile has them
+const SRGB_TO_XYZ: [[f64; 3]; 3] = [
+    [0.4360747, 0.3850649, 0.1430804],
+    [0.2225045, 0.7168786, 0.0606169],
+    [0.0139322, 0.0971045, 0.7141733],
+];
+const SRGB_CURVE: [f64; 7] = [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045, 0.0, 0.0];
+
+impl Profile {
+    pub fn srgb() -> Profile {
+        let curve = Curve::Parametric(SRGB_CURVE);
+        Profile { name: String::from("sRGB"), to_xyz: SRGB_TO_XYZ, curves: [curve.clone(), curve.clone(), curve] }
+    }
+
+    /// The profile in `data`, if it's a matrix/curve RGB one.
+    pub fn parse(data: &[u8]) -> Option<Profile> {
+        if data.get(16..20)? != b"RGB " || data.get(36..40)? != b"acsp" {
+            return None;
+        }
+        let tag = |signature: &[u8; 4]| -> Option<&[u8]> {
+            let count = be32(data.get(128..132)?) as usize;
+            (0..count.min(256)).find_map(|i| {
+                let entry = data.get(132 + i * 12..144 + i * 12)?;
+                if &entry[..4] != signature {
+                    return None;
+                }
+                let (offset, size) = (be32(&entry[4..8]) as usize, be32(&entry[8..12]) as usize);
+                data.get(offset..offset.checked_add(size)?)
+            })
+        };
+        let primary = |signature| -> Option<[f64; 3]> {
+            let xyz = tag(signature)?;
+            (xyz.get(..4)? == b"XYZ ").then_some(())?;
+            Some([s15f16(xyz.get(8..12)?), s15f16(xyz.get(12..16)?), s15f16(xyz.get(16..20)?)])
+        };
+        let (r, g, b) = (primary(b"rXYZ")?, primary(b"gXYZ")?, primary(b"bXYZ")?);
+        let to_xyz = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+        let curves = [curve(tag(b"rTRC")?)?, curve(tag(b"gTRC")?)?, curve(tag(b"bTRC")?)?];
+        let name = tag(b"desc").and_then(description).unwrap_or_default();
+        Some(Profile { name, to_xyz, curves })
+    }
+
+    /// The matrix taking linear RGB in this profile to linear sRGB.
+    pub fn to_srgb(&self) -> [[f64; 3]; 3] {
+        multiply(&invert(&SRGB_TO_XYZ), &self.to_xyz)
+    }
+
+    /// Linear light of 8-bit values `rgb` in this profile, in sRGB's
+    /// primaries (`to_srgb` being this profile's matrix). Colors outside
+    /// sRGB have parts below 0 or above 1.
+    pub fn linear_srgb(&self, to_srgb: &[[f64; 3]; 3], rgb: [u8; 3]) -> [f64; 3] {
+        let linear = [0, 1, 2].map(|i| self.curves[i].linear(rgb[i] as f64 / 255.0));
+        [0, 1, 2].map(|row| (0..3).map(|i| to_srgb[row][i] * linear[i]).sum())
+    }
+}
+
+/// A linear sRGB value encoded as sRGB, 0 to 1.
+pub fn encode_srgb(linear: f64) -> f64 {
+    let linear = linear.clamp(0.0, 1.0);
+    if linear <= 0.0031308 { linear * 12.92 } else { 1.055 * linear.powf(1.0 / 2.4) - 0.055 }
+}
+
+fn curve(data: &[u8]) -> Option<Curve> {
+    match data.get(..4)? {
+        b"curv" => {
+            let count = be32(data.get(8..12)?) as usize;
+            let entries: Vec<f64> = (0..count).map(|i| data.get(12 + i * 2..14 + i * 2).map(|v| u16::from_be_bytes([v[0], v[1]]) as f64)).collect::<Option<_>>()?;
+            match entries.as_slice() {
+                [] => Some(Curve::Gamma(1.0)),
+                [gamma] => Some(Curve::Gamma(gamma / 256.0)),
+                _ => Some(Curve::Table(entries.iter().map(|v| v / 65535.0).collect())),
+            }
+        }
+        b"para" => {
+            let kind = u16::from_be_bytes([*data.get(8)?, *data.get(9)?]);
+            let count = [1, 3, 4, 5, 7].get(kind as usize)?;
+            let mut p: Vec<f64> = (0..*count).map(|i| data.get(12 + i * 4..16 + i * 4).map(s15f16)).collect::<Option<_>>()?;
+            // As the full form: g, a, b, c, d, e, f
+            let params = match kind {
+                0 => [p[0], 1.0, 0.0, 0.0, f64::NEG_INFINITY, 0.0, 0.0],
+                1 => [p[0], p[1], p[2], 0.0, -p[2] / p[1], 0.0, 0.0],
+                2 => [p[0], p[1], p[2], 0.0, -p[2] / p[1], p[3], p[3]],
+                3 => [p[0], p[1], p[2], p[3], p[4], 0.0, 0.0],
+                _ => {
+                    p.resize(7, 0.0);
+                    [p[0], p[1], p[2], p[3], p[4], p[5], p[6]]
+                }
+            };
+            Some(Curve::Parametric(params))
+        }
+        _ => None,
+    }
+}
+
+/// The text of a `desc` tag: ASCII in version 2 profiles, the first
+/// UTF-16 record of a `mluc` in version 4 ones.
+fn description(data: &[u8]) -> Option<String> {
+    let text = match data.get(..4)? {
+        b"desc" => {
+            let len = be32(data.get(8..12)?) as usize;
+            String::from_utf8_lossy(data.get(12..12 + len)?).to_string()
+        }
+        b"mluc" => {
+            let (len, offset) = (be32(data.get(20..24)?) as usize, be32(data.get(24..28)?) as usize);
+            let units: Vec<u16> = data.get(offset..offset + len)?.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => return None,
+    };
+    Some(text.trim_end_matches('\0').trim().to_string())
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn s15f16(bytes: &[u8]) -> f64 {
+    i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64 / 65536.0
+}
+
+fn multiply(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    [0, 1, 2].map(|row| [0, 1, 2].map(|column| (0..3).map(|i| a[row][i] * b[i][column]).sum()))
+}
+
+pub fn invert(m: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let cofactor = |row: usize, column: usize| {
+        let (r1, r2) = ((row + 1) % 3, (row + 2) % 3);
+        let (c1, c2) = ((column + 1) % 3, (column + 2) % 3);
+        m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+    };
+    let determinant: f64 = (0..3).map(|column| m[0][column] * cofactor(0, column)).sum();
+    // The inverse is the transposed cofactors over the determinant
+    [0, 1, 2].map(|row| [0, 1, 2].map(|column| cofactor(column, row) / determinant))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profile() {
+        let p3 = Profile::parse(&crate::test_support::display_p3_profile()).unwrap();
+        assert_eq!(p3.name, "Display P3");
+        let to_srgb = p3.to_srgb();
+        // White is white in both
+        let white = p3.linear_srgb(&to_srgb, [255, 255, 255]);
+        assert!(white.iter().all(|v| (v - 1.0).abs() < 0.001), "{:?}", white);
+        // P3's red is redder than sRGB can show
+        let red = p3.linear_srgb(&to_srgb, [255, 0, 0]);
+        assert!(red[0] > 1.1 && red[1] < 0.0 && red[2] < 0.0, "{:?}", red);
+
+        let srgb = Profile::srgb();
+        let grey = srgb.linear_srgb(&srgb.to_srgb(), [128, 128, 128]);
+        assert!((encode_srgb(grey[0]) * 255.0 - 128.0).abs() < 0.01);
+
+        assert_eq!(Profile::parse(b"not a profile"), None);
+    }
+}
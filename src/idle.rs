@@ -0,0 +1,140 @@
+//! Idle detection, so the daemon's analysis can stay out of the way of
+//! whoever is using the machine. Idle means no keyboard or mouse input for
+//! a while and little CPU and GPU load.
+//!
+//! Input idle time comes from `xprintidle` on X11 and `ioreg` on macOS, CPU
+//! load from the OS and GPU load from `nvidia-smi`. A signal that can't be
+//! read is left out, so a headless server goes by load alone.
+
+use std::env;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use sysinfo::System;
+use crate::config::IdleConfig;
+
+/// What the machine looks like right now.
+#[derive(Debug, Default)]
+struct Readings {
+    input_idle: Option<Duration>,
+    cpu_percent: Option<f32>,
+    gpu_percent: Option<f32>,
+}
+
+impl Readings {
+    fn idle(&self, config: &IdleConfig) -> bool {
+        self.input_idle.is_none_or(|idle| idle >= Duration::from_secs(config.input_secs))
+            && self.cpu_percent.is_none_or(|cpu| cpu <= config.max_cpu_percent)
+            && self.gpu_percent.is_none_or(|gpu| gpu <= config.max_gpu_percent)
+    }
+}
+
+pub struct Monitor {
+    config: IdleConfig,
+    system: System,
+    /// Whether the machine was idle when last asked and nobody has touched
+    /// it since
+    running: bool,
+}
+
+impl Monitor {
+    pub fn new(config: &IdleConfig) -> Monitor {
+        Monitor { config: config.clone(), system: System::new(), running: false }
+    }
+
+    /// Whether background work may run now. Load only counts when starting:
+    /// once work is running it is the load, so from then on only the user
+    /// coming back stops it.
+    pub fn may_run(&mut self) -> bool {
+        self.running = if self.running {
+            !self.user_active()
+        } else {
+            self.readings().idle(&self.config)
+        };
+        self.running
+    }
+
+    /// Whether there has been input recently. Cheap enough to poll every
+    /// second.
+    pub fn user_active(&self) -> bool {
+        input_idle().is_some_and(|idle| idle < Duration::from_secs(self.config.input_secs))
+    }
+
+    fn readings(&mut self) -> Readings {
+        Readings { input_idle: input_idle(), cpu_percent: Some(self.cpu_percent()), gpu_percent: gpu_percent() }
+    }
+
+    fn cpu_percent(&mut self) -> f32 {
+        // Usage is measured between two refreshes
+        self.system.refresh_cpu_usage();
+        thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+        self.system.refresh_cpu_usage();
+        self.system.global_cpu_usage()
+    }
+}
+
+/// Time since the last keyboard or mouse input, where the platform tells us.
+fn input_idle() -> Option<Duration> {
+    if cfg!(target_os = "macos") {
+        parse_hid_idle(&run("ioreg", &["-c", "IOHIDSystem", "-d", "4"])?)
+    } else if env::var_os("DISPLAY").is_some() {
+        let millis = run("xprintidle", &[])?.trim().parse().ok()?;
+        Some(Duration::from_millis(millis))
+    } else {
+        None
+    }
+}
+
+/// The busiest NVIDIA GPU's utilization.
+fn gpu_percent() -> Option<f32> {
+    let output = run("nvidia-smi", &["--query-gpu=utilization.gpu", "--format=csv,noheader,nounits"])?;
+    parse_gpu_utilization(&output)
+}
+
+/// Stdout of a successful run, or `None` if the tool is missing or fails.
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// `"HIDIdleTime" = 1234567890`, in nanoseconds, from `ioreg`.
+fn parse_hid_idle(output: &str) -> Option<Duration> {
+    let line = output.lines().find(|line| line.contains("\"HIDIdleTime\""))?;
+    let nanos = line.split('=').nth(1)?.trim().parse().ok()?;
+    Some(Duration::from_nanos(nanos))
+}
+
+fn parse_gpu_utilization(output: &str) -> Option<f32> {
+    output.lines()
+        .filter_map(|line| line.trim().parse::<f32>().ok())
+        .reduce(f32::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readings() {
+        let config = IdleConfig { enabled: true, ..IdleConfig::default() };
+        let quiet = Readings {
+            input_idle: Some(Duration::from_secs(600)),
+            cpu_percent: Some(3.0),
+            gpu_percent: Some(0.0),
+        };
+        assert!(quiet.idle(&config));
+        assert!(!Readings { input_idle: Some(Duration::from_secs(5)), ..quiet }.idle(&config));
+        assert!(!Readings { cpu_percent: Some(80.0), input_idle: None, gpu_percent: None }.idle(&config));
+        // Nothing known: nothing stands in the way
+        assert!(Readings::default().idle(&config));
+    }
+
+    #[test]
+    fn test_parse_tool_output() {
+        let ioreg = "    | |   \"HIDIdleTime\" = 4500000000\n    | |   \"HIDParameters\" = {}\n";
+        assert_eq!(parse_hid_idle(ioreg), Some(Duration::from_millis(4500)));
+        assert_eq!(parse_hid_idle("nothing here"), None);
+        assert_eq!(parse_gpu_utilization("3\n57\n"), Some(57.0));
+        assert_eq!(parse_gpu_utilization("[N/A]\n"), None);
+    }
+}
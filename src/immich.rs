@@ -0,0 +1,216 @@
+//! Pushing the AI descriptions and keywords to an Immich server, for
+//! libraries that live there but get tagged here by a local model.
+//!
+//! Photos are matched to Immich assets by the SHA-1 Immich keeps of every
+//! upload, then by original file name (and size, when several share it).
+//! Descriptions replace the asset's; keywords become tags under one root
+//! tag, so they're easy to tell apart from ones added in Immich.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use anyhow::{anyhow, Error};
+use reqwest::{Client, RequestBuilder};
+use rusqlite::{params_from_iter, Connection};
+use serde_json::{json, Value};
+use crate::{hash, keywords, tags, SearchFilter};
+
+/// Checksums asked about at once.
+const CHECK_BATCH: usize = 500;
+
+/// The outcome of `sync`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Synced {
+    pub updated: usize,
+    /// Paths of photos Immich doesn't seem to have
+    pub unmatched: Vec<String>,
+    pub failed: usize,
+}
+
+struct Photo {
+    path: String,
+    file_name: String,
+    file_size: i64,
+    description: Option<String>,
+    keywords: Option<String>,
+}
+
+struct Server {
+    client: Client,
+    url: String,
+    api_key: String,
+}
+
+impl Server {
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        self.client.request(method, format!("{}/api{}", self.url.trim_end_matches('/'), path)).header("x-api-key", &self.api_key)
+    }
+
+    async fn send(&self, method: reqwest::Method, path: &str, body: Value) -> Result<Value, Error> {
+        let response = self.request(method, path).json(&body).send().await?.error_for_status()?;
+        Ok(response.json().await.unwrap_or(Value::Null))
+    }
+
+    /// The assets Immich already has, by the index of the photo with the
+    /// same checksum.
+    async fn match_by_checksum(&self, photos: &[Photo]) -> Result<BTreeMap<usize, String>, Error> {
+        let checks: Vec<Value> = photos.iter().enumerate()
+            .filter_map(|(i, photo)| hash::sha1_of_file(Path::new(&photo.path)).ok().map(|sha1| json!({ "id": i.to_string(), "checksum": sha1 })))
+            .collect();
+        let mut matched = BTreeMap::new();
+        for batch in checks.chunks(CHECK_BATCH) {
+            let response = self.send(reqwest::Method::POST, "/assets/bulk-upload-check", json!({ "assets": batch })).await?;
+            for result in response["results"].as_array().into_iter().flatten() {
+                if let (Some(i), Some(asset)) = (result["id"].as_str().and_then(|i| i.parse().ok()), result["assetId"].as_str()) {
+                    matched.insert(i, asset.to_string());
+                }
+            }
+        }
+        Ok(matched)
+    }
+
+    /// The one asset with the photo's original file name, narrowed down by
+    /// size when there are several.
+    async fn match_by_name(&self, photo: &Photo) -> Result<Option<String>, Error> {
+        let response = self.send(reqwest::Method::POST, "/search/metadata", json!({ "originalFileName": photo.file_name, "withExif": true })).await?;
+        let items: Vec<&Value> = response["assets"]["items"].as_array().into_iter().flatten()
+            .filter(|asset| asset["originalFileName"].as_str() == Some(photo.file_name.as_str()))
+            .collect();
+        let candidates = match items.len() {
+            0 | 1 => items,
+            _ => items.into_iter().filter(|asset| asset["exifInfo"]["fileSizeInByte"].as_i64() == Some(photo.file_size)).collect(),
+        };
+        Ok(match candidates[..] {
+            [asset] => asset["id"].as_str().map(str::to_string),
+            _ => None,
+        })
+    }
+}
+
+/// Push the descriptions and keywords of the photos matching `filter` to
+/// the Immich server at `url`, tagging keywords as `<tag_root>/<keyword>`.
+pub fn sync(conn: &Connection, filter: &SearchFilter, url: &str, api_key: &str, tag_root: &str) -> Result<Synced, Error> {
+    let (clause, params) = crate::filter_clause(filter);
+    let analyzed = format!("(description IS NOT NULL OR {} IS NOT NULL)", tags::KEYWORDS);
+    let clause = match clause.is_empty() {
+        true => format!(" WHERE {}", analyzed),
+        false => format!("{} AND {}", clause, analyzed),
+    };
+    let photos: Vec<Photo> = conn
+        .prepare(&format!("SELECT path, file_name, file_size, description, {} FROM images{} ORDER BY path", tags::KEYWORDS, clause))?
+        .query_map(params_from_iter(params), |row| {
+            Ok(Photo { path: row.get(0)?, file_name: row.get(1)?, file_size: row.get(2)?, description: row.get(3)?, keywords: row.get(4)? })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    let server = Server { client: Client::new(), url: url.to_string(), api_key: api_key.to_string() };
+    tokio::runtime::Runtime::new()?.block_on(push(&server, &photos, tag_root))
+}
+
+async fn push(server: &Server, photos: &[Photo], tag_root: &str) -> Result<Synced, Error> {
+    let mut synced = Synced::default();
+    let mut assets = server.match_by_checksum(photos).await?;
+    for (i, photo) in photos.iter().enumerate() {
+        if assets.contains_key(&i) {
+            continue;
+        }
+        match server.match_by_name(photo).await? {
+            Some(asset) => { assets.insert(i, asset); }
+            None => synced.unmatched.push(photo.path.clone()),
+        }
+    }
+
+    // Asset IDs by tag
+    let mut tagged: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (&i, asset) in &assets {
+        let photo = &photos[i];
+        if let Some(description) = &photo.description {
+            let result = server.send(reqwest::Method::PUT, &format!("/assets/{}", asset), json!({ "description": description })).await;
+            if let Err(e) = result {
+                tracing::warn!("Can't update {} in Immich: {}", photo.path, e);
+                synced.failed += 1;
+                continue;
+            }
+        }
+        // A slash in a keyword would make a tag hierarchy of it
+        for keyword in keywords::split(photo.keywords.as_deref().unwrap_or_default()) {
+            tagged.entry(format!("{}/{}", tag_root, keyword.replace('/', "-"))).or_default().push(asset.clone());
+        }
+        println!("Synced: {}", photo.path);
+        synced.updated += 1;
+    }
+
+    if !tagged.is_empty() {
+        let values: Vec<&String> = tagged.keys().collect();
+        let tags = server.send(reqwest::Method::PUT, "/tags", json!({ "tags": values })).await?;
+        for tag in tags.as_array().into_iter().flatten() {
+            let (Some(id), Some(value)) = (tag["id"].as_str(), tag["value"].as_str()) else { continue };
+            if let Some(ids) = tagged.get(value) {
+                server.send(reqwest::Method::PUT, &format!("/tags/{}/assets", id), json!({ "ids": ids })).await
+                    .map_err(|e| anyhow!("tagging assets with {}: {}", value, e))?;
+            }
+        }
+    }
+    Ok(synced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use mockito::{Matcher, Server as MockServer};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_sync() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let by_hash = dir.path().join("IMG_0001.jpg");
+        fs::write(&by_hash, b"abc")?;
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, description) VALUES
+                (?1, 'IMG_0001.jpg', 3, 'A dog on a beach'),
+                ('/moved/IMG_0002.jpg', 'IMG_0002.jpg', 2048, NULL),
+                ('/moved/IMG_0003.jpg', 'IMG_0003.jpg', 10, 'Sand'),
+                ('/moved/IMG_0004.jpg', 'IMG_0004.jpg', 10, NULL)",
+            [by_hash.to_string_lossy()],
+        )?;
+        crate::test_support::set_keywords(&conn, &[(&by_hash.to_string_lossy(), "dog, beach"), ("/moved/IMG_0002.jpg", "beach")]);
+
+        let mut server = MockServer::new();
+        let key = Matcher::Exact(String::from("secret"));
+        let check = server.mock("POST", "/api/assets/bulk-upload-check")
+            .match_header("x-api-key", key.clone())
+            .match_body(Matcher::PartialJson(json!({ "assets": [{ "id": "2", "checksum": "a9993e364706816aba3e25717850c26c9cd0d89d" }] })))
+            .with_body(r#"{"results": [{"id": "2", "action": "reject", "reason": "duplicate", "assetId": "a1"}]}"#)
+            .create();
+        // Two with the same name, told apart by size
+        server.mock("POST", "/api/search/metadata")
+            .match_body(Matcher::PartialJson(json!({ "originalFileName": "IMG_0002.jpg" })))
+            .with_body(r#"{"assets": {"items": [
+                {"id": "a2", "originalFileName": "IMG_0002.jpg", "exifInfo": {"fileSizeInByte": 2048}},
+                {"id": "a9", "originalFileName": "IMG_0002.jpg", "exifInfo": {"fileSizeInByte": 4096}}
+            ]}}"#)
+            .create();
+        server.mock("POST", "/api/search/metadata")
+            .match_body(Matcher::PartialJson(json!({ "originalFileName": "IMG_0003.jpg" })))
+            .with_body(r#"{"assets": {"items": []}}"#)
+            .create();
+        let description = server.mock("PUT", "/api/assets/a1")
+            .match_body(Matcher::Json(json!({ "description": "A dog on a beach" })))
+            .create();
+        let tags = server.mock("PUT", "/api/tags")
+            .match_body(Matcher::Json(json!({ "tags": ["AI/beach", "AI/dog"] })))
+            .with_body(r#"[{"id": "t1", "value": "AI/beach"}, {"id": "t2", "value": "AI/dog"}]"#)
+            .create();
+        let beach = server.mock("PUT", "/api/tags/t1/assets").match_body(Matcher::Json(json!({ "ids": ["a2", "a1"] }))).create();
+        let dog = server.mock("PUT", "/api/tags/t2/assets").match_body(Matcher::Json(json!({ "ids": ["a1"] }))).create();
+
+        let synced = sync(&conn, &SearchFilter::default(), &server.url(), "secret", "AI")?;
+        assert_eq!(synced, Synced { updated: 2, unmatched: vec![String::from("/moved/IMG_0003.jpg")], failed: 0 });
+        for mock in [check, description, tags, beach, dog] {
+            mock.assert();
+        }
+        Ok(())
+    }
+}
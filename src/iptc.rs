@@ -0,0 +1,324 @@
+//! IPTC-IIM metadata, as found in a JPEG's Photoshop APP13 segment or a
+//! TIFF's IPTC-NAA tag.
+
+use exif::{Context, Exif, In, Tag, Value};
+use crate::ExternalMetadata;
+
+const SOURCE: &str = "iptc";
+
+/// Photoshop image resource holding the IIM block
+pub const IPTC_RESOURCE_ID: u16 = 0x0404;
+/// Signature starting a JPEG APP13 segment of Photoshop image resources
+pub const PHOTOSHOP_SIGNATURE: &[u8] = b"Photoshop 3.0\0";
+/// IIM coded character set value declaring UTF-8
+const UTF8_MARKER: &[u8] = b"\x1b%G";
+/// TIFF tag 33723
+const IPTC_NAA: Tag = Tag(Context::Tiff, 0x83bb);
+
+#[derive(Debug, Default, PartialEq)]
+pub struct IptcData {
+    pub title: Option<String>,
+    pub caption: Option<String>,
+    pub byline: Option<String>,
+    pub copyright: Option<String>,
+    pub keywords: Vec<String>,
+}
+
+impl IptcData {
+    /// Flatten into catalog rows tagged with their IPTC provenance.
+    pub fn into_external(self) -> Vec<ExternalMetadata> {
+        let single = [
+            ("title", self.title),
+            ("caption", self.caption),
+            ("byline", self.byline),
+            ("copyright", self.copyright),
+        ];
+        single.into_iter()
+            .filter_map(|(field, value)| Some((field, value?)))
+            .chain(self.keywords.into_iter().map(|k| ("keyword", k)))
+            .map(|(field, value)| ExternalMetadata {
+                source: SOURCE.to_string(),
+                field: field.to_string(),
+                value,
+            })
+            .collect()
+    }
+}
+
+/// Find and parse IPTC data in a JPEG file or, failing that, in TIFF-style
+/// EXIF data.
+pub fn read(file: &[u8], exif: Option<&Exif>) -> IptcData {
+    iim(file, exif).map(|iim| parse(&iim)).unwrap_or_default()
+}
+
+/// The raw IIM block, from wherever `read` would find it.
+pub fn iim(file: &[u8], exif: Option<&Exif>) -> Option<Vec<u8>> {
+    jpeg_iim(file).or_else(|| exif.and_then(tiff_iim))
+}
+
+/// The IIM block from the Photoshop resources in a JPEG's APP13 segment.
+fn jpeg_iim(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        let marker = data[pos + 1];
+        // Start of scan: no more metadata segments
+        if marker == 0xDA {
+            break;
+        }
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let segment = data.get(pos + 4..pos + 2 + len)?;
+        if marker == 0xED {
+            if let Some(resources) = segment.strip_prefix(PHOTOSHOP_SIGNATURE) {
+                if let Some(iim) = photoshop_resource(resources, IPTC_RESOURCE_ID) {
+                    return Some(iim.to_vec());
+                }
+            }
+        }
+        pos += 2 + len;
+    }
+    None
+}
+
+/// Look up one image resource in a sequence of "8BIM" blocks.
+pub fn photoshop_resource(data: &[u8], wanted: u16) -> Option<&[u8]> {
+    resources(data).find(|(id, _, _)| *id == wanted).map(|(_, _, body)| body)
+}
+
+/// The (id, whole block, body) of each "8BIM" block, stopping at the first
+/// malformed one.
+fn resources(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < 12 || !data.starts_with(b"8BIM") {
+            return None;
+        }
+        let id = u16::from_be_bytes([data[4], data[5]]);
+        // Pascal-string name, padded so the length byte plus name is even
+        let name_len = data[6] as usize;
+        let name_end = 6 + ((name_len + 2) & !1);
+        let size_bytes = data.get(name_end..name_end + 4)?;
+        let size = u32::from_be_bytes(size_bytes.try_into().ok()?) as usize;
+        let start = name_end + 4;
+        let body = data.get(start..start + size)?;
+        let end = (start + ((size + 1) & !1)).min(data.len());
+        let block = &data[..end];
+        data = &data[end..];
+        Some((id, block, body))
+    })
+}
+
+/// Replace (or append) one resource in a sequence of "8BIM" blocks, keeping
+/// the others as they are.
+pub fn replace_resource(data: &[u8], id: u16, body: &[u8]) -> Vec<u8> {
+    let mut result: Vec<u8> = resources(data)
+        .filter(|(other, _, _)| *other != id)
+        .flat_map(|(_, block, _)| block.iter().copied())
+        .collect();
+    result.extend_from_slice(b"8BIM");
+    result.extend_from_slice(&id.to_be_bytes());
+    result.extend_from_slice(&[0, 0]); // empty name, padded
+    result.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    result.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        result.push(0);
+    }
+    result
+}
+
+fn tiff_iim(exif: &Exif) -> Option<Vec<u8>> {
+    // Writers disagree on the type; LONG is common, so undo the word
+    // swapping that reading it as integers implies.
+    match &exif.get_field(IPTC_NAA, In::PRIMARY)?.value {
+        Value::Undefined(bytes, _) | Value::Byte(bytes) => Some(bytes.clone()),
+        Value::Long(words) => Some(words.iter().flat_map(|w| {
+            if exif.little_endian() { w.to_le_bytes() } else { w.to_be_bytes() }
+        }).collect()),
+        _ => None,
+    }
+}
+
+/// The (record, dataset, value) triples of an IIM block, stopping at the
+/// first malformed one.
+fn datasets(iim: &[u8]) -> Vec<(u8, u8, &[u8])> {
+    let mut datasets = Vec::new();
+    let mut pos = 0;
+    while pos + 5 <= iim.len() && iim[pos] == 0x1C {
+        let (record, dataset) = (iim[pos + 1], iim[pos + 2]);
+        let len = u16::from_be_bytes([iim[pos + 3], iim[pos + 4]]) as usize;
+        // Extended-length datasets aren't used by any field we read
+        if len & 0x8000 != 0 {
+            break;
+        }
+        let Some(value) = iim.get(pos + 5..pos + 5 + len) else { break };
+        datasets.push((record, dataset, value));
+        pos += 5 + len;
+    }
+    datasets
+}
+
+fn is_utf8(datasets: &[(u8, u8, &[u8])]) -> bool {
+    // Coded character set; ESC % G means UTF-8
+    datasets.iter().any(|&(record, dataset, value)| (record, dataset) == (1, 90) && value == UTF8_MARKER)
+}
+
+/// Decode the application record (record 2) datasets we care about.
+pub fn parse(iim: &[u8]) -> IptcData {
+    let datasets = datasets(iim);
+    let utf8 = is_utf8(&datasets);
+
+    let mut data = IptcData::default();
+    for (_, dataset, value) in datasets.into_iter().filter(|(record, _, _)| *record == 2) {
+        let text = decode_text(value, utf8);
+        if text.is_empty() {
+            continue;
+        }
+        match dataset {
+            5 => data.title = Some(text),
+            25 => data.keywords.push(text),
+            80 => data.byline = Some(text),
+            116 => data.copyright = Some(text),
+            120 => data.caption = Some(text),
+            _ => {}
+        }
+    }
+    data
+}
+
+/// Add a caption and keywords to an IIM block (or start a new one),
+/// keeping its other datasets. Keywords are merged with the existing ones
+/// and an existing caption is kept. The result is always UTF-8.
+pub fn merge(existing: &[u8], caption: Option<&str>, keywords: &[String]) -> Vec<u8> {
+    let datasets = datasets(existing);
+    let utf8 = is_utf8(&datasets);
+    let current = parse(existing);
+
+    let mut iim = Vec::new();
+    let mut push = |record: u8, dataset: u8, value: &[u8]| {
+        // Longer values would need the extended length form; keywords and
+        // captions are limited to far less by the standard anyway
+        let value = &value[..value.len().min(0x7fff)];
+        iim.extend_from_slice(&[0x1C, record, dataset]);
+        iim.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        iim.extend_from_slice(value);
+    };
+    for &(record, dataset, value) in datasets.iter().filter(|(record, _, _)| *record == 1) {
+        if dataset != 90 {
+            push(record, dataset, value);
+        }
+    }
+    push(1, 90, UTF8_MARKER);
+    for &(record, dataset, value) in &datasets {
+        match (record, dataset) {
+            // The record version is binary; everything else in record 2 is text
+            (2, 0) => push(2, 0, value),
+            (2, 25) => {}
+            (2, _) => push(2, dataset, decode_text(value, utf8).as_bytes()),
+            _ => {}
+        }
+    }
+    let mut merged = current.keywords.clone();
+    for keyword in keywords {
+        if !merged.iter().any(|k| k.eq_ignore_ascii_case(keyword)) {
+            merged.push(keyword.clone());
+        }
+    }
+    for keyword in &merged {
+        push(2, 25, keyword.as_bytes());
+    }
+    if let (None, Some(caption)) = (&current.caption, caption) {
+        push(2, 120, caption.as_bytes());
+    }
+    iim
+}
+
+/// Text is UTF-8 when declared or when it happens to be valid UTF-8, and
+/// otherwise assumed to be Latin-1, which is what most older software wrote.
+fn decode_text(value: &[u8], utf8: bool) -> String {
+    let text = match std::str::from_utf8(value) {
+        Ok(text) => text.to_string(),
+        Err(_) if utf8 => String::from_utf8_lossy(value).into_owned(),
+        Err(_) => value.iter().map(|&b| b as char).collect(),
+    };
+    text.trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iim(datasets: &[(u8, u8, &[u8])]) -> Vec<u8> {
+        let mut iim = Vec::new();
+        for (record, dataset, value) in datasets {
+            iim.extend_from_slice(&[0x1C, *record, *dataset]);
+            iim.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            iim.extend_from_slice(value);
+        }
+        iim
+    }
+
+    /// A minimal JPEG header with an APP13 segment carrying `iim`.
+    fn jpeg_with_iptc(iim: &[u8]) -> Vec<u8> {
+        let mut resources = b"Photoshop 3.0\08BIM".to_vec();
+        resources.extend_from_slice(&IPTC_RESOURCE_ID.to_be_bytes());
+        resources.extend_from_slice(&[0, 0]); // empty name, padded
+        resources.extend_from_slice(&(iim.len() as u32).to_be_bytes());
+        resources.extend_from_slice(iim);
+        if iim.len() % 2 == 1 {
+            resources.push(0);
+        }
+
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xED];
+        jpeg.extend_from_slice(&((resources.len() + 2) as u16).to_be_bytes());
+        jpeg.extend_from_slice(&resources);
+        jpeg.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02]);
+        jpeg
+    }
+
+    #[test]
+    fn test_read_jpeg_iptc() {
+        let iim = iim(&[
+            (1, 90, b"\x1b%G"),
+            (2, 5, b"Harbour"),
+            (2, 25, b"boats"),
+            (2, 25, "Lisboa cais".as_bytes()),
+            (2, 80, b"Jane Doe"),
+            (2, 120, "Fishing boats at dawn \u{2014} Cais do Sodr\u{e9}".as_bytes()),
+        ]);
+        let data = read(&jpeg_with_iptc(&iim), None);
+        assert_eq!(data.title.as_deref(), Some("Harbour"));
+        assert_eq!(data.byline.as_deref(), Some("Jane Doe"));
+        assert_eq!(data.keywords, vec!["boats", "Lisboa cais"]);
+        assert!(data.caption.unwrap().ends_with("Sodr\u{e9}"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let existing = iim(&[(2, 0, &[0, 4]), (2, 25, b"caf\xe9"), (2, 80, b"Fran\xe7ois")]);
+        let keywords = vec![String::from("Café"), String::from("terrace")];
+        let merged = merge(&existing, Some("Evening light"), &keywords);
+
+        let data = parse(&merged);
+        assert_eq!(data.keywords, vec!["caf\u{e9}", "terrace"]);
+        assert_eq!(data.byline.as_deref(), Some("Fran\u{e7}ois"));
+        assert_eq!(data.caption.as_deref(), Some("Evening light"));
+        assert!(datasets(&merged).contains(&(2, 0, &[0, 4][..])));
+
+        // A caption someone already wrote is kept
+        let again = merge(&merged, Some("Something else"), &[]);
+        assert_eq!(parse(&again).caption.as_deref(), Some("Evening light"));
+
+        let resources = replace_resource(&[], IPTC_RESOURCE_ID, &merged);
+        let resources = replace_resource(&resources, 0x0425, b"digest");
+        assert_eq!(photoshop_resource(&resources, IPTC_RESOURCE_ID), Some(&merged[..]));
+        assert_eq!(photoshop_resource(&resources, 0x0425), Some(&b"digest"[..]));
+    }
+
+    #[test]
+    fn test_parse_latin1() {
+        let data = parse(&iim(&[(2, 25, b"caf\xe9")]));
+        assert_eq!(data.keywords, vec!["caf\u{e9}"]);
+        assert_eq!(parse(b"garbage"), IptcData::default());
+    }
+}
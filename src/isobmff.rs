@@ -0,0 +1,26 @@
+//! The ISO base media file format's boxes, shared by HEIF/AVIF images and
+//! MP4/QuickTime videos.
+
+pub fn be(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |value, &b| value << 8 | b as u32)
+}
+
+/// The (type, contents) of each ISOBMFF box in `data`.
+pub fn boxes(mut data: &[u8]) -> impl Iterator<Item = (&[u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = be(data.get(..4)?) as usize;
+        let kind: &[u8; 4] = data.get(4..8)?.try_into().ok()?;
+        let (start, end) = match size {
+            0 => (8, data.len()),
+            1 => (16, usize::try_from(u64::from_be_bytes(data.get(8..16)?.try_into().ok()?)).ok()?),
+            size => (8, size),
+        };
+        let contents = data.get(start..end)?;
+        data = &data[end..];
+        Some((kind, contents))
+    })
+}
+
+pub fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data).find(|(k, _)| *k == kind).map(|(_, contents)| contents)
+}
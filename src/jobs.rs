@@ -0,0 +1,360 @@
+//! Batches for analyzing part of the catalog on another machine: `jobs export`
+//! packs downsized copies of unanalyzed images with a manifest, `jobs run`
+//! analyzes them wherever the model lives, and `jobs import` merges the
+//! results back into the catalog.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use anyhow::{anyhow, Context, Error};
+use image::ImageFormat;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use crate::analyzer::{Analysis, AnalyzerPool};
+use crate::cancel::CancelToken;
+use crate::config::{Config, KeywordConfig};
+use crate::corrections::{self, Examples};
+use crate::derivative::{self, MetadataPolicy};
+use crate::{animation, heif, keywords, orientation, raw, tags, tiff, video, webp};
+use crate::progress::Operation;
+
+const BATCH_FORMAT: u32 = 1;
+const MANIFEST_FILE: &str = "manifest.json";
+const RESULTS_FILE: &str = "results.json";
+/// Longest edge of the copies shipped in a batch; plenty for captioning.
+const MAX_EDGE: u32 = 1024;
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    format: u32,
+    items: Vec<BatchItem>,
+    /// The catalog's corrected captions, for the analyzing machine's prompts
+    #[serde(default)]
+    examples: Examples,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct BatchItem {
+    id: i64,
+    path: String,
+    /// Name of the image inside the archive
+    file: String,
+    #[serde(default)]
+    tier: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BatchResults {
+    format: u32,
+    results: Vec<BatchResult>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BatchResult {
+    id: i64,
+    path: String,
+    description: String,
+    keywords: String,
+    /// Text read in the image, when the analyzing machine has a text stage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+}
+
+/// Write up to `limit` images that have no analysis yet into a tar batch,
+/// keeping the metadata the derivative policy allows. Returns the number of
+/// images packed.
+pub fn export_batch(
+    conn: &Connection,
+    operation: &Operation,
+    config: &Config,
+    limit: usize,
+    out: &Path,
+) -> Result<usize, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, path, tier FROM images WHERE description IS NULL ORDER BY id LIMIT ?1",
+    )?;
+    let rows = stmt.query_map([limit as i64], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    operation.set_total(conn, rows.len())?;
+
+    let mut archive = tar::Builder::new(File::create(out)?);
+    let mut items = Vec::new();
+    for (id, path, tier) in rows {
+        operation.checkpoint(conn)?;
+        let data = match derivative(Path::new(&path), config, MAX_EDGE) {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::warn!("Skipping {}: {}", path, e);
+                operation.advance(conn, false)?;
+                continue;
+            }
+        };
+        operation.advance(conn, true)?;
+        let file = format!("images/{}", id);
+        append(&mut archive, &file, &data)?;
+        items.push(BatchItem { id, path, file, tier });
+    }
+
+    let count = items.len();
+    let examples = corrections::examples(conn, config.analyzer.examples)?;
+    let manifest = serde_json::to_vec_pretty(&Manifest { format: BATCH_FORMAT, items, examples })?;
+    append(&mut archive, MANIFEST_FILE, &manifest)?;
+    archive.finish()?;
+    Ok(count)
+}
+
+/// Analyze every image in `batch` and write the results archive to `out`.
+/// Returns the number of images analyzed and the number that failed. If
+/// `cancel` trips, the results so far are still written (so they can be
+/// imported, and the rest exported again) before the cancellation is
+/// reported.
+pub fn run_batch(analyzer: AnalyzerPool, batch: &Path, out: &Path, cancel: &CancelToken) -> Result<(usize, usize), Error> {
+    let manifest: Manifest = serde_json::from_slice(&read_entry(batch, MANIFEST_FILE)?)
+        .context("reading batch manifest")?;
+    if manifest.format != BATCH_FORMAT {
+        return Err(anyhow!("unsupported batch format {}", manifest.format));
+    }
+    let analyzer = &analyzer.with_examples(manifest.examples);
+    let items: HashMap<String, BatchItem> = manifest.items
+        .into_iter()
+        .map(|item| (item.file.clone(), item))
+        .collect();
+
+    // Stream images out of the archive into analyzer workers; the bounded
+    // queue keeps only a few decoded entries in memory at a time.
+    let workers = analyzer.concurrency();
+    let (queue, pending) = mpsc::sync_channel::<(BatchItem, Vec<u8>)>(workers);
+    let pending = Mutex::new(pending);
+    let results = Mutex::new(Vec::new());
+    let failed = Mutex::new(0);
+    thread::scope(|scope| -> Result<(), Error> {
+        // Owned here so an early return still hangs up on the workers
+        let queue = queue;
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let Ok((item, data)) = pending.lock().unwrap().recv() else { break };
+                let in_flight = cancel.in_flight();
+                // Keep draining once cancelled so the reader never blocks on a full queue
+                if cancel.is_cancelled() {
+                    continue;
+                }
+                match analyzer.analyze_blocking(&data, item.tier.as_deref(), &in_flight) {
+                    Ok(analysis) => results.lock().unwrap().push(BatchResult {
+                        id: item.id,
+                        path: item.path,
+                        description: analysis.description,
+                        keywords: analysis.keywords,
+                        text: analysis.text,
+                    }),
+                    Err(_) if in_flight.is_cancelled() => {}
+                    Err(e) => {
+                        tracing::error!("Error analyzing {}: {}", item.path, e);
+                        *failed.lock().unwrap() += 1;
+                    }
+                }
+            });
+        }
+
+        let mut archive = tar::Archive::new(File::open(batch)?);
+        for entry in archive.entries()? {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            if let Some(item) = items.get(&name) {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                if queue.send((item.clone(), data)).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|r| r.id);
+    let analyzed = results.len();
+    let json = serde_json::to_vec_pretty(&BatchResults { format: BATCH_FORMAT, results })?;
+    let mut archive = tar::Builder::new(File::create(out)?);
+    append(&mut archive, RESULTS_FILE, &json)?;
+    archive.finish()?;
+    cancel.check()?;
+    Ok((analyzed, failed.into_inner().unwrap()))
+}
+
+/// Merge a results archive into the catalog. Rows are matched on both id and
+/// path so results from a different catalog can't land on the wrong image,
+/// and images that were analyzed locally in the meantime are left alone.
+/// Returns the number of images updated.
+pub fn import_results(conn: &Connection, results: &Path, vocabulary: &KeywordConfig) -> Result<usize, Error> {
+    let results: BatchResults = serde_json::from_slice(&read_entry(results, RESULTS_FILE)?)
+        .context("reading batch results")?;
+    if results.format != BATCH_FORMAT {
+        return Err(anyhow!("unsupported results format {}", results.format));
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    let mut updated = 0;
+    for result in &results.results {
+        let changed = tx.execute(
+            "UPDATE images SET description = ?1 WHERE id = ?2 AND path = ?3 AND description IS NULL",
+            params![result.description, result.id, result.path],
+        )?;
+        if changed > 0 {
+            tags::set(&tx, result.id, &keywords::enforce(vocabulary, &result.path, &result.keywords))?;
+            let analysis = Analysis { description: result.description.clone(), keywords: result.keywords.clone(), text: result.text.clone() };
+            crate::daemon::save_text(&tx, result.id, &analysis)?;
+        }
+        updated += changed;
+    }
+    tx.commit()?;
+    Ok(updated)
+}
+
+/// What the model gets to see of a file: the file itself, the embedded
+/// preview of a RAW file, a HEIF, AVIF, WebP or TIFF image converted to
+/// JPEG, or the middle frame of an animation or a frame of a video.
+pub fn analyzable_data(path: &Path, config: &Config) -> Result<Vec<u8>, Error> {
+    if video::is_video(path) {
+        let duration = video::probe(path, &config.video).ok().and_then(|probe| probe.duration_secs);
+        return video::frame(path, duration, &config.video);
+    }
+    let data = crate::share::read(path, &config.shares)?;
+    if heif::is_heif(path) {
+        return heif::to_jpeg(&data);
+    }
+    if webp::is_webp(path) {
+        return webp::to_jpeg(&data);
+    }
+    if tiff::is_tiff(path) {
+        return tiff::to_jpeg(&data);
+    }
+    if let Some(animation) = animation::read(&data) {
+        return crate::encode_jpeg(&animation::middle_frame(&data, animation.frame_count)?);
+    }
+    match raw::format_for(path) {
+        Some(format) => raw::read(&data, format)?.preview.ok_or_else(|| anyhow!("no embedded preview")),
+        None => Ok(data),
+    }
+}
+
+/// An upright JPEG copy of the image at `path`, no bigger than `max_edge`,
+/// with the metadata the derivative policy allows. Formats we can't decode
+/// come back as `analyzable_data` gave them.
+pub fn derivative(path: &Path, config: &Config, max_edge: u32) -> Result<Vec<u8>, Error> {
+    let data = analyzable_data(path, config)?;
+    // A video's frame has no metadata to carry over, and the video itself
+    // is too big to read for none
+    let original = if video::is_video(path) { Vec::new() } else { crate::share::read(path, &config.shares)? };
+    // HEIF conversion already turns the pixels by the file's own rotation
+    let orientation = if heif::is_heif(path) { None } else { orientation::read_from(&original) };
+    Ok(shrink(&original, data, config.derivatives.metadata, orientation, max_edge))
+}
+
+/// Turn upright by `orientation`, downsize to `max_edge` and re-encode as
+/// JPEG (small, upright JPEGs are kept as they are), with the metadata
+/// `policy` allows from `original`. Formats we can't decode are returned
+/// as-is, for the analyzer to deal with.
+fn shrink(original: &[u8], data: Vec<u8>, policy: MetadataPolicy, orientation: Option<u16>, max_edge: u32) -> Vec<u8> {
+    let Ok(img) = image::load_from_memory(&data) else { return data };
+    let orientation = orientation.unwrap_or(1);
+    let small = img.width() <= max_edge && img.height() <= max_edge;
+    let jpeg = if orientation == 1 && small && image::guess_format(&data).ok() == Some(ImageFormat::Jpeg) {
+        data
+    } else {
+        let img = orientation::upright(img, orientation);
+        // thumbnail() would scale small images up
+        let img = if small { img } else { img.thumbnail(max_edge, max_edge) };
+        let mut jpeg = Vec::new();
+        match img.to_rgb8().write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg) {
+            Ok(()) => jpeg,
+            Err(_) => return data,
+        }
+    };
+    derivative::apply(policy, original, &jpeg).unwrap_or_else(|e| {
+        // Err on the side of sending less
+        tracing::warn!("Can't carry metadata over ({}); sending the image without it", e);
+        derivative::apply(MetadataPolicy::StripAll, &[], &jpeg).unwrap_or(jpeg)
+    })
+}
+
+fn append(archive: &mut tar::Builder<File>, name: &str, data: &[u8]) -> Result<(), Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archive.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+fn read_entry(archive: &Path, name: &str) -> Result<Vec<u8>, Error> {
+    let mut archive = tar::Archive::new(File::open(archive)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_string_lossy() == name {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            return Ok(data);
+        }
+    }
+    Err(anyhow!("{} not found in archive", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use crate::test_support::{field, jpeg_with_exif, mock_ollama};
+
+    #[test]
+    fn test_export_run_import_roundtrip() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let big = dir.path().join("big.png");
+        image::DynamicImage::new_rgb8(2048, 1024).save(&big)?;
+        let small = dir.path().join("small.jpg");
+        std::fs::write(&small, [0xFF, 0xD8, 0xFF, 0xE0])?;
+
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, description) VALUES
+             (?1, 'big.png', 1, NULL), (?2, 'small.jpg', 1, NULL),
+             ('/gone.jpg', 'gone.jpg', 1, NULL), ('/done.jpg', 'done.jpg', 1, 'Already done')",
+            params![big.to_string_lossy(), small.to_string_lossy()],
+        )?;
+
+        let batch = dir.path().join("batch.tar");
+        let operation = crate::progress::start(&conn, "jobs export", crate::cancel::CancelToken::new())?;
+        assert_eq!(export_batch(&conn, &operation, &Config::default(), 10, &batch)?, 2);
+        let packed = read_entry(&batch, "images/1")?;
+        assert_eq!(image::load_from_memory(&packed)?.width(), MAX_EDGE);
+
+        let (_server, analyzer) = mock_ollama();
+        let output = dir.path().join("results.tar");
+        assert_eq!(run_batch(analyzer, &batch, &output, &CancelToken::new())?, (2, 0));
+
+        assert_eq!(import_results(&conn, &output, &KeywordConfig::default())?, 2);
+        let description: String = conn.query_row(
+            "SELECT description FROM images WHERE file_name = 'small.jpg'", [], |row| row.get(0),
+        )?;
+        assert_eq!(description, "A colorful sunset over mountains");
+        // Importing twice changes nothing
+        assert_eq!(import_results(&conn, &output, &KeywordConfig::default())?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shrink_turns_upright() -> Result<(), Error> {
+        let original = jpeg_with_exif(&[field(exif::Tag::Orientation, exif::Value::Short(vec![6]))]);
+        let shrunk = shrink(&original, original.clone(), MetadataPolicy::StripAll, orientation::read_from(&original), MAX_EDGE);
+        let img = image::load_from_memory(&shrunk)?;
+        assert_eq!((img.width(), img.height()), (6, 8));
+        assert_eq!(orientation::read_from(&shrunk), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+//! JPEG files as a list of metadata segments followed by the image data,
+//! for putting metadata in without touching the compressed image.
+
+use anyhow::{anyhow, bail, Error};
+use crate::iptc::PHOTOSHOP_SIGNATURE;
+
+pub const XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+pub const EXIF_SIGNATURE: &[u8] = b"Exif\0\0";
+pub const APP1: u8 = 0xE1;
+pub const APP13: u8 = 0xED;
+/// Start of scan; the compressed image data follows
+const SOS: u8 = 0xDA;
+
+pub struct Segment {
+    pub marker: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Segment {
+    pub fn is_xmp(&self) -> bool {
+        self.marker == APP1 && self.payload.starts_with(XMP_SIGNATURE)
+    }
+
+    pub fn is_exif(&self) -> bool {
+        self.marker == APP1 && self.payload.starts_with(EXIF_SIGNATURE)
+    }
+
+    pub fn is_photoshop(&self) -> bool {
+        self.marker == APP13 && self.payload.starts_with(PHOTOSHOP_SIGNATURE)
+    }
+}
+
+/// Split a JPEG into its metadata segments and everything from the start
+/// of scan on.
+pub fn split(jpeg: &[u8]) -> Result<(Vec<Segment>, &[u8]), Error> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        bail!("not a JPEG file");
+    }
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    loop {
+        if jpeg.get(pos) != Some(&0xFF) || pos + 4 > jpeg.len() {
+            bail!("malformed JPEG header");
+        }
+        let marker = jpeg[pos + 1];
+        if marker == SOS {
+            return Ok((segments, &jpeg[pos..]));
+        }
+        let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        let payload = jpeg.get(pos + 4..pos + 2 + len).ok_or_else(|| anyhow!("truncated JPEG segment"))?;
+        segments.push(Segment { marker, payload: payload.to_vec() });
+        pos += 2 + len;
+    }
+}
+
+/// Put `payload` in the first segment matching `is_match`, or insert a new
+/// one after the leading APP0/APP1 (JFIF and EXIF expect to come first).
+pub fn upsert(segments: &mut Vec<Segment>, marker: u8, is_match: impl Fn(&Segment) -> bool, payload: Vec<u8>) -> Result<(), Error> {
+    if payload.len() + 2 > u16::MAX as usize {
+        bail!("metadata too large for a JPEG segment ({} bytes)", payload.len());
+    }
+    match segments.iter_mut().find(|s| is_match(s)) {
+        Some(segment) => segment.payload = payload,
+        None => {
+            let at = segments.iter().take_while(|s| s.marker == 0xE0 || s.marker == APP1).count();
+            segments.insert(at, Segment { marker, payload });
+        }
+    }
+    Ok(())
+}
+
+/// Put a JPEG back together.
+pub fn join(segments: &[Segment], image_data: &[u8]) -> Vec<u8> {
+    let mut result = vec![0xFF, 0xD8];
+    for segment in segments {
+        result.extend_from_slice(&[0xFF, segment.marker]);
+        result.extend_from_slice(&((segment.payload.len() + 2) as u16).to_be_bytes());
+        result.extend_from_slice(&segment.payload);
+    }
+    result.extend_from_slice(image_data);
+    result
+}
@@ -0,0 +1,63 @@
+//! Helpers for the free-form keyword lists the analyzer produces.
+
+use crate::config::KeywordConfig;
+
+/// Split a keyword list as written by the model into individual keywords.
+/// Models use commas, semicolons or one-per-line lists, sometimes bulleted
+/// or numbered; duplicates (ignoring case) are dropped.
+pub fn split(keywords: &str) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    for keyword in keywords.split([',', ';', '\n']) {
+        let keyword = strip_list_marker(keyword.trim()).trim_end_matches('.').trim();
+        if !keyword.is_empty() && !result.iter().any(|k| k.eq_ignore_ascii_case(keyword)) {
+            result.push(keyword.to_string());
+        }
+    }
+    result
+}
+
+/// Drop the tags `config` doesn't allow from a keyword list the model wrote
+/// for the image at `path`, saying which. The rest are rejoined with ", ".
+pub fn enforce(config: &KeywordConfig, path: &str, keywords: &str) -> String {
+    let contains = |list: &[String], keyword: &str| list.iter().any(|k| k.eq_ignore_ascii_case(keyword));
+    let (kept, rejected): (Vec<String>, Vec<String>) = split(keywords).into_iter()
+        .partition(|keyword| !contains(&config.blocked, keyword) && (config.allowed.is_empty() || contains(&config.allowed, keyword)));
+    if !rejected.is_empty() {
+        tracing::info!("Rejected tags for {}: {}", path, rejected.join(", "));
+    }
+    kept.join(", ")
+}
+
+/// Remove a leading "-", "*", "•" or "1." / "1)" list marker.
+fn strip_list_marker(item: &str) -> &str {
+    if let Some(rest) = item.strip_prefix(['-', '*', '•']) {
+        return rest.trim_start();
+    }
+    let digits = item.len() - item.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    match item[digits..].strip_prefix(['.', ')']) {
+        Some(rest) if digits > 0 && (rest.is_empty() || rest.starts_with(' ')) => rest.trim_start(),
+        _ => item,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("sunset, mountains,nature ,"), vec!["sunset", "mountains", "nature"]);
+        assert_eq!(split("1. Dog\n2. Beach\n- dog\n* Sand."), vec!["Dog", "Beach", "Sand"]);
+        assert_eq!(split("blue sky; 4x4 truck; 3.5mm jack"), vec!["blue sky", "4x4 truck", "3.5mm jack"]);
+        assert!(split("").is_empty());
+    }
+
+    #[test]
+    fn test_enforce() {
+        let blocked = KeywordConfig { blocked: vec![String::from("photo"), String::from("Image")], ..KeywordConfig::default() };
+        assert_eq!(enforce(&blocked, "/a.jpg", "Photo, dog, image; beach"), "dog, beach");
+        let vocabulary = KeywordConfig { allowed: vec![String::from("Dog"), String::from("beach")], ..blocked };
+        assert_eq!(enforce(&vocabulary, "/a.jpg", "dog, golden retriever, beach, photo"), "dog, beach");
+        assert_eq!(enforce(&vocabulary, "/a.jpg", "sunset"), "");
+    }
+}
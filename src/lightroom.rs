@@ -0,0 +1,231 @@
+//! Lightroom Classic catalogs (`.lrcat`, a SQLite database): the curation
+//! done there — captions, keywords, ratings, picks and collections — is
+//! brought over as metadata from the source `lightroom`, next to what
+//! sidecars and the files themselves say. Photos not cataloged yet are
+//! added with just their path, for the next scan to fill in.
+
+use std::collections::HashMap;
+use std::path::Path;
+use anyhow::{Context, Error};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+const SOURCE: &str = "lightroom";
+
+/// Regular collections list their photos; smart ones are queries, and
+/// collection sets hold other collections.
+const REGULAR_COLLECTION: &str = "com.adobe.ag.library.collection";
+
+/// A photo as the Lightroom catalog has it.
+#[derive(Debug, Default)]
+struct Photo {
+    path: String,
+    capture_time: Option<String>,
+    fields: Vec<(&'static str, String)>,
+}
+
+/// What `import_catalog` did.
+#[derive(Debug, PartialEq)]
+pub struct Imported {
+    /// Photos already in the catalog, now with Lightroom's metadata
+    pub updated: usize,
+    /// Photos only Lightroom knew about, added by path
+    pub added: usize,
+}
+
+/// Names up the tree for rows of `table` with `id_local`, `name` and
+/// `parent` columns (keywords, collections), joined with `|`. The roots
+/// Lightroom keeps above everything have no name and are left out.
+fn paths(catalog: &Connection, table: &str) -> Result<HashMap<i64, String>, Error> {
+    let rows: Vec<(i64, Option<String>, Option<i64>)> = catalog
+        .prepare(&format!("SELECT id_local, name, parent FROM {}", table))?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<_, _>>()?;
+    let by_id: HashMap<i64, (Option<String>, Option<i64>)> = rows.iter().map(|(id, name, parent)| (*id, (name.clone(), *parent))).collect();
+    let mut paths = HashMap::new();
+    for (id, _, _) in &rows {
+        let mut names = Vec::new();
+        let mut current = Some(*id);
+        // Bounded, in case of a damaged catalog with a cycle
+        while let Some((name, parent)) = current.and_then(|id| by_id.get(&id)).filter(|_| names.len() < 64) {
+            names.extend(name.clone());
+            current = *parent;
+        }
+        names.reverse();
+        paths.insert(*id, names.join("|"));
+    }
+    Ok(paths)
+}
+
+fn read_catalog(catalog: &Connection) -> Result<Vec<Photo>, Error> {
+    let mut photos: HashMap<i64, Photo> = HashMap::new();
+    let mut stmt = catalog.prepare(
+        "SELECT i.id_local, r.absolutePath || f.pathFromRoot || l.baseName || '.' || l.extension,
+                i.captureTime, i.rating, i.pick, iptc.caption
+         FROM Adobe_images i
+         JOIN AgLibraryFile l ON l.id_local = i.rootFile
+         JOIN AgLibraryFolder f ON f.id_local = l.folder
+         JOIN AgLibraryRootFolder r ON r.id_local = f.rootFolder
+         LEFT JOIN AgLibraryIPTC iptc ON iptc.image = i.id_local",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let mut photo = Photo { path: row.get(1)?, capture_time: row.get(2)?, fields: Vec::new() };
+        if let Some(rating) = row.get::<_, Option<f64>>(3)?.filter(|rating| *rating > 0.0) {
+            photo.fields.push(("rating", (rating.round() as i64).to_string()));
+        }
+        match row.get::<_, Option<f64>>(4)?.unwrap_or_default() {
+            pick if pick > 0.0 => photo.fields.push(("pick", String::from("picked"))),
+            pick if pick < 0.0 => photo.fields.push(("pick", String::from("rejected"))),
+            _ => {}
+        }
+        if let Some(caption) = row.get::<_, Option<String>>(5)?.filter(|caption| !caption.trim().is_empty()) {
+            photo.fields.push(("caption", caption));
+        }
+        photos.insert(row.get(0)?, photo);
+    }
+
+    let keywords = paths(catalog, "AgLibraryKeyword")?;
+    let mut stmt = catalog.prepare("SELECT image, tag FROM AgLibraryKeywordImage ORDER BY image, tag")?;
+    let tagged = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?;
+    for tagged in tagged {
+        let (image, tag) = tagged?;
+        let (Some(photo), Some(path)) = (photos.get_mut(&image), keywords.get(&tag)) else { continue };
+        if let Some(leaf) = path.rsplit('|').next().filter(|leaf| !leaf.is_empty()) {
+            photo.fields.push(("keyword", leaf.to_string()));
+        }
+        if path.contains('|') {
+            photo.fields.push(("hierarchical_keyword", path.clone()));
+        }
+    }
+
+    let collections = paths(catalog, "AgLibraryCollection")?;
+    let mut stmt = catalog.prepare(
+        "SELECT ci.image, ci.collection FROM AgLibraryCollectionImage ci
+         JOIN AgLibraryCollection c ON c.id_local = ci.collection
+         WHERE c.creationId = ?1 ORDER BY ci.image, ci.collection",
+    )?;
+    let members = stmt.query_map([REGULAR_COLLECTION], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?;
+    for member in members {
+        let (image, collection) = member?;
+        if let (Some(photo), Some(path)) = (photos.get_mut(&image), collections.get(&collection)) {
+            photo.fields.push(("collection", path.clone()));
+        }
+    }
+
+    let mut photos: Vec<Photo> = photos.into_values().collect();
+    photos.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(photos)
+}
+
+/// Bring the curation in the Lightroom catalog at `lrcat` into this one,
+/// replacing what an earlier import brought.
+pub fn import_catalog(conn: &Connection, lrcat: &Path) -> Result<Imported, Error> {
+    let catalog = Connection::open_with_flags(lrcat, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("opening {}", lrcat.display()))?;
+    let photos = read_catalog(&catalog).with_context(|| format!("reading Lightroom catalog {}", lrcat.display()))?;
+
+    let tx = conn.unchecked_transaction()?;
+    let mut imported = Imported { updated: 0, added: 0 };
+    for photo in photos {
+        let existing: Option<i64> = tx.query_row("SELECT id FROM images WHERE path = ?1 ORDER BY id", [&photo.path], |row| row.get(0)).optional()?;
+        let id = match existing {
+            Some(id) => {
+                imported.updated += 1;
+                id
+            }
+            None => {
+                let path = Path::new(&photo.path);
+                let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                let file_size = path.metadata().map(|m| m.len()).unwrap_or_default();
+                // Lightroom's "2023-07-14T18:42:07.35" the way EXIF dates are kept
+                let creation_date = photo.capture_time.as_ref().map(|time| time.get(..19).unwrap_or(time).replacen('T', " ", 1));
+                tx.execute(
+                    "INSERT INTO images (path, file_name, file_size, creation_date) VALUES (?1, ?2, ?3, ?4)",
+                    params![photo.path, file_name, file_size, creation_date],
+                )?;
+                imported.added += 1;
+                tx.last_insert_rowid()
+            }
+        };
+        tx.execute("DELETE FROM external_metadata WHERE image_id = ?1 AND source = ?2", params![id, SOURCE])?;
+        for (field, value) in &photo.fields {
+            tx.execute(
+                "INSERT INTO external_metadata (image_id, source, field, value) VALUES (?1, ?2, ?3, ?4)",
+                params![id, SOURCE, field, value],
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// The few tables of a Lightroom catalog this reads, with two photos.
+    const CATALOG: &str = "
+        CREATE TABLE AgLibraryRootFolder (id_local INTEGER PRIMARY KEY, absolutePath TEXT, name TEXT);
+        CREATE TABLE AgLibraryFolder (id_local INTEGER PRIMARY KEY, rootFolder INTEGER, pathFromRoot TEXT);
+        CREATE TABLE AgLibraryFile (id_local INTEGER PRIMARY KEY, folder INTEGER, baseName TEXT, extension TEXT);
+        CREATE TABLE Adobe_images (id_local INTEGER PRIMARY KEY, rootFile INTEGER, captureTime TEXT, rating REAL, pick REAL);
+        CREATE TABLE AgLibraryIPTC (id_local INTEGER PRIMARY KEY, image INTEGER, caption TEXT);
+        CREATE TABLE AgLibraryKeyword (id_local INTEGER PRIMARY KEY, name TEXT, parent INTEGER);
+        CREATE TABLE AgLibraryKeywordImage (id_local INTEGER PRIMARY KEY, image INTEGER, tag INTEGER);
+        CREATE TABLE AgLibraryCollection (id_local INTEGER PRIMARY KEY, name TEXT, parent INTEGER, creationId TEXT);
+        CREATE TABLE AgLibraryCollectionImage (id_local INTEGER PRIMARY KEY, image INTEGER, collection INTEGER);
+
+        INSERT INTO AgLibraryRootFolder VALUES (1, '/photos/', 'photos');
+        INSERT INTO AgLibraryFolder VALUES (10, 1, '2023/lisbon/');
+        INSERT INTO AgLibraryFile VALUES (100, 10, 'DSCF0107', 'RAF'), (101, 10, 'DSCF0108', 'RAF');
+        INSERT INTO Adobe_images VALUES
+            (1000, 100, '2023-07-14T18:42:07', 4, 1),
+            (1001, 101, '2023-07-14T18:45:00', NULL, -1);
+        INSERT INTO AgLibraryIPTC VALUES (1, 1000, 'Tram 28 on the hill');
+        INSERT INTO AgLibraryKeyword VALUES (1, NULL, NULL), (2, 'Places', 1), (3, 'Lisbon', 2), (4, 'tram', 1);
+        INSERT INTO AgLibraryKeywordImage VALUES (1, 1000, 3), (2, 1000, 4);
+        INSERT INTO AgLibraryCollection VALUES
+            (1, 'Portfolio', NULL, 'com.adobe.ag.library.group'),
+            (2, 'Best of 2023', 1, 'com.adobe.ag.library.collection'),
+            (3, 'Four stars', NULL, 'com.adobe.ag.library.smart_collection');
+        INSERT INTO AgLibraryCollectionImage VALUES (1, 1000, 2), (2, 1000, 3);
+    ";
+
+    #[test]
+    fn test_import_catalog() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let lrcat = dir.path().join("catalog.lrcat");
+        Connection::open(&lrcat)?.execute_batch(CATALOG)?;
+
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute("INSERT INTO images (path, file_name, file_size) VALUES ('/photos/2023/lisbon/DSCF0107.RAF', 'DSCF0107.RAF', 1)", [])?;
+
+        assert_eq!(import_catalog(&conn, &lrcat)?, Imported { updated: 1, added: 1 });
+        let fields: Vec<(String, String)> = conn.prepare("SELECT field, value FROM external_metadata WHERE image_id = 1 ORDER BY rowid")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let expected = [
+            ("rating", "4"), ("pick", "picked"), ("caption", "Tram 28 on the hill"),
+            ("keyword", "Lisbon"), ("hierarchical_keyword", "Places|Lisbon"), ("keyword", "tram"),
+            ("collection", "Portfolio|Best of 2023"),
+        ];
+        assert_eq!(fields, expected.map(|(field, value)| (field.to_string(), value.to_string())));
+
+        let (file_name, date): (String, String) = conn.query_row(
+            "SELECT file_name, creation_date FROM images WHERE id = 2", [], |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        assert_eq!((file_name.as_str(), date.as_str()), ("DSCF0108.RAF", "2023-07-14 18:45:00"));
+        let pick: String = conn.query_row("SELECT value FROM external_metadata WHERE image_id = 2", [], |row| row.get(0))?;
+        assert_eq!(pick, "rejected");
+
+        // Importing again replaces rather than adds
+        assert_eq!(import_catalog(&conn, &lrcat)?, Imported { updated: 2, added: 0 });
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM external_metadata", [], |row| row.get(0))?;
+        assert_eq!(count, 8);
+
+        assert!(import_catalog(&conn, &dir.path().join("missing.lrcat")).is_err());
+        Ok(())
+    }
+}
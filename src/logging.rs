@@ -0,0 +1,70 @@
+//! Diagnostics, on standard error through `tracing`, leaving standard
+//! output to what commands print as their result. By default that's what's
+//! being processed and what went wrong with it; `-q` keeps only warnings
+//! and errors, `-v` adds a line as each stage of each image finishes
+//! (read, decode, exif, ai, db) with how long it took, and `-vv`
+//! everything, dependencies' included. `RUST_LOG`, as `target=level`
+//! pairs (`PhotoCataloger::analyzer=debug,warn`), overrides them.
+
+use std::io::IsTerminal;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::time::Uptime;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{Layer, Registry};
+
+/// The filter for `-v` given `verbose` times, or `-q`.
+fn filter(verbose: u8, quiet: bool) -> Targets {
+    let (own, others) = match (quiet, verbose) {
+        (true, _) => (LevelFilter::WARN, LevelFilter::WARN),
+        (false, 0) => (LevelFilter::INFO, LevelFilter::WARN),
+        (false, 1) => (LevelFilter::DEBUG, LevelFilter::WARN),
+        (false, _) => (LevelFilter::TRACE, LevelFilter::DEBUG),
+    };
+    Targets::new().with_target(env!("CARGO_CRATE_NAME"), own).with_default(others)
+}
+
+/// Start logging, once, first thing.
+pub fn init(verbose: u8, quiet: bool) {
+    let spec = std::env::var("RUST_LOG").ok().filter(|spec| !spec.trim().is_empty());
+    let debugging = verbose > 0 || spec.is_some();
+    let filter = match spec.map(|spec| spec.parse::<Targets>()) {
+        Some(Ok(filter)) => filter,
+        Some(Err(e)) => {
+            eprintln!("Ignoring RUST_LOG ({}): it's target=level pairs, like PhotoCataloger=debug,warn", e);
+            filter(verbose, quiet)
+        }
+        None => filter(verbose, quiet),
+    };
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_target(verbose > 1)
+        // The stages' spans say what they took as they close
+        .with_span_events(FmtSpan::CLOSE);
+    // Timestamps (which the timings come with) only when debugging
+    let layer: Box<dyn Layer<Registry> + Send + Sync> = match debugging {
+        true => Box::new(layer.with_timer(Uptime::default())),
+        false => Box::new(layer.without_time()),
+    };
+    let _ = tracing_subscriber::registry().with(layer.with_filter(filter)).try_init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Level;
+
+    #[test]
+    fn test_filter() {
+        let own = env!("CARGO_CRATE_NAME");
+        assert!(filter(0, false).would_enable(own, &Level::INFO));
+        assert!(!filter(0, false).would_enable(own, &Level::DEBUG));
+        assert!(!filter(0, false).would_enable("reqwest", &Level::INFO));
+        assert!(!filter(2, true).would_enable(own, &Level::INFO));
+        assert!(filter(0, true).would_enable(own, &Level::WARN));
+        assert!(filter(1, false).would_enable(&format!("{}::scan", own), &Level::DEBUG));
+        assert!(filter(2, false).would_enable("reqwest", &Level::DEBUG));
+    }
+}
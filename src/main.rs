@@ -1,211 +1,3092 @@
+mod actions;
+mod albums;
+mod analyzer;
+mod animation;
+mod apple_photos;
+mod audit;
+mod bandwidth;
+mod batch;
+#[cfg(feature = "tui")]
+mod browse;
+mod camera;
+mod caption;
+mod cancel;
+mod clocks;
+mod config;
+mod copies;
+mod corrections;
+mod culling;
+mod daemon;
+mod dates;
+mod derivative;
+mod devices;
+#[cfg(feature = "server")]
+mod downloads;
+mod duplicates;
+mod edits;
+mod error;
+mod export;
+mod features;
+mod fields;
+mod fsck;
+mod gallery;
+mod geocode;
+mod geofence;
+#[cfg(feature = "server")]
+mod graphql;
+mod hash;
+mod heif;
+mod help;
+#[cfg(feature = "server")]
+mod histogram;
+mod icc;
+mod idle;
+mod immich;
+mod iptc;
+mod isobmff;
+mod jpeg;
+mod jobs;
+mod keywords;
+mod lightroom;
+mod logging;
+mod migrations;
+mod moves;
+mod notes;
+mod orientation;
+mod photoprism;
+mod portable;
+#[cfg(feature = "server")]
+mod preferences;
+mod priority;
+mod privacy;
+mod problems;
+mod progress;
+mod prune;
+mod query;
+mod raw;
+#[cfg(feature = "video")]
+mod reel;
+mod remote;
+mod renames;
+mod report;
+mod resume;
+mod roles;
+mod scenes;
+mod schedule;
+mod sequence;
+mod sources;
+mod spreadsheet;
+#[cfg(feature = "server")]
+mod server;
+mod share;
+mod snapshot;
+mod stats;
+mod table;
+mod tags;
+mod takeout;
+#[cfg(test)]
+mod test_support;
+mod tiering;
+mod tiff;
+mod trips;
+mod verify;
+mod video;
+mod walk;
+#[cfg(feature = "server")]
+mod web;
+mod webp;
+mod writeback;
+mod xmp;
+
 use std::path::{Path, PathBuf};
 use std::fs;
-use walkdir::WalkDir;
-use rusqlite::{Connection, Result};
-use image::ImageFormat;
-use exif::{Reader, In};
+use rusqlite::{Connection, OptionalExtension, Result};
+use exif::{Reader, In, Tag};
 use anyhow::Error;
-use serde_json::Value;
-use reqwest;
 use image::GenericImageView;
 use std::env;
-use base64::Engine;
-use base64::engine::general_purpose::STANDARD;
+use std::net::SocketAddr;
+use std::sync::{mpsc, Mutex};
+use std::process::ExitCode;
+use std::time::Duration;
+use std::thread;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use analyzer::AnalyzerPool;
+use camera::CameraInfo;
+use cancel::CancelToken;
+use config::Config;
+use error::{CliError, ErrorKind};
+use geocode::Place;
+use progress::Operation;
+
+#[derive(Parser)]
+#[command(version, about = "Catalog images and their metadata into a SQLite database")]
+#[command(disable_help_subcommand = true)]
+#[command(after_help = "More in `PhotoCataloger help <topic>`: query-language, config and analyzers.")]
+struct Cli {
+    /// Configuration file (defaults to photo_catalog.toml if present)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Refuse to send image data off this machine (same as `privacy.local_only`)
+    #[arg(long, global = true)]
+    local_only: bool,
+
+    /// Print what organize, prune, writeback, tag, fix-dates,
+    /// reconcile-clocks or import csv would change, and change nothing
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// The catalog file; ":memory:" for one that's gone when the command
+    /// finishes, with `scan --csv` or `--json` to keep what it found
+    #[arg(long, global = true, value_name = "PATH", default_value = CATALOG_PATH)]
+    db: PathBuf,
+
+    /// Log each stage of each image as it finishes; twice for everything
+    /// (`RUST_LOG` overrides)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Log only warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Directory to scan (defaults to the current directory)
+    dir: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Scan a directory and catalog the images in it
+    Scan {
+        /// Directories or names of sources to scan (defaults to the
+        /// configured `[[sources]]`, else the current directory)
+        dirs: Vec<PathBuf>,
+        /// Catalog file metadata only and leave AI analysis for later
+        /// (e.g. `jobs export` on a machine that can't run the model)
+        #[arg(long)]
+        no_analyze: bool,
+        /// Stop cleanly after this long, e.g. "2h" or "1h30m"
+        #[arg(long, value_parser = parse_duration)]
+        max_duration: Option<Duration>,
+        /// Merge in the JSON sidecars of a Google Photos Takeout archive
+        /// (same as `takeout.enabled`)
+        #[arg(long)]
+        takeout: bool,
+        /// Skip files and folders matching this glob, e.g. "@eaDir" or
+        /// "2019/phone-backup", on top of `scan.exclude`; may be repeated
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+        /// Only catalog files matching this glob, e.g. "2023/**" or
+        /// "*.CR3"; may be repeated
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+        /// Go into symlinked folders and catalog symlinked files (same as
+        /// `scan.follow_symlinks`)
+        #[arg(long)]
+        follow_symlinks: bool,
+        /// Go at most this many folders down; 0 for only the folder's own
+        /// files (same as `scan.max_depth`)
+        #[arg(long, value_name = "N")]
+        max_depth: Option<usize>,
+        /// Skip files smaller than this, e.g. "50KB" for web thumbnails
+        /// (same as `scan.min_size`)
+        #[arg(long, value_name = "SIZE", value_parser = walk::parse_size)]
+        min_size: Option<u64>,
+        /// Skip files bigger than this, e.g. "2GB" (same as `scan.max_size`)
+        #[arg(long, value_name = "SIZE", value_parser = walk::parse_size)]
+        max_size: Option<u64>,
+        /// Write what the scan did as JSON to this file when it ends:
+        /// counts, failures and their reasons, timing (same as
+        /// `scan.report`)
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+        /// Only fail once more images than this fail, e.g. "10" or "2%"
+        /// of those tried (same as `scan.max_failures`)
+        #[arg(long, value_name = "N|N%", value_parser = report::parse_threshold)]
+        max_failures: Option<report::Threshold>,
+        /// Scan from a snapshot of the filesystem, taken and removed again
+        /// automatically on Btrfs, ZFS and APFS, so the scan sees the
+        /// folder as it was when it started (same as `scan.snapshot`)
+        #[arg(long)]
+        snapshot: bool,
+        /// Write the catalog as CSV to this file when the scan is done, as
+        /// `export --csv` does ("-" for standard output)
+        #[arg(long, value_name = "FILE")]
+        csv: Option<PathBuf>,
+        /// Write the catalog as JSON to this file when the scan is done, as
+        /// `export --json` does ("-" for standard output)
+        #[arg(long, value_name = "FILE")]
+        json: Option<PathBuf>,
+        /// Pick up each folder's last scan where it stopped, skipping the
+        /// files it saved; with no folders, every scan that didn't finish
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Keep cataloging new images and analyzing unanalyzed ones in the background
+    Daemon {
+        /// Stop cleanly after this long, e.g. "8h"
+        #[arg(long, value_parser = parse_duration)]
+        max_duration: Option<Duration>,
+    },
+    /// Search the catalog
+    Search {
+        #[command(flatten)]
+        filter: SearchFilter,
+        #[command(flatten)]
+        table: table::TableArgs,
+    },
+    /// Count what's in the catalog, by year, format, camera and folder
+    Stats {
+        /// Print them as JSON
+        #[arg(long)]
+        json: bool,
+        /// How many formats, cameras, folders and keywords to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+    /// Resolve place names for cataloged photos that have GPS coordinates but no place yet
+    Geocode,
+    /// Tag cataloged photos by the configured geofences again, after
+    /// changing them
+    Geofence,
+    /// Analyze images on another machine
+    Jobs {
+        #[command(subcommand)]
+        command: JobsCommand,
+    },
+    /// Write catalog data out for other tools
+    Export(ExportArgs),
+    /// Merge a catalog written by `export --json` into this one, matching
+    /// photos by content hash, or bring over the curation in a Lightroom
+    /// catalog or a spreadsheet (`import csv`)
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Import {
+        #[command(subcommand)]
+        command: Option<ImportCommand>,
+        /// The file to read ("-" for standard input)
+        #[arg(required_unless_present = "lightroom")]
+        file: Option<PathBuf>,
+        /// Read captions, keywords, ratings, picks and collections from this
+        /// Lightroom Classic catalog
+        #[arg(long, value_name = "CATALOG", conflicts_with = "file")]
+        lightroom: Option<PathBuf>,
+    },
+    /// Embed AI descriptions and keywords into the JPEG files themselves
+    Writeback {
+        /// Keep a copy of each original as `photo.jpg.bak`
+        #[arg(long)]
+        backup: bool,
+    },
+    /// Replace a photo's AI description or keywords with your own. Later
+    /// analyses are shown your corrections as examples to follow
+    Correct {
+        /// The photo, with the path it was cataloged under
+        path: PathBuf,
+        #[arg(long, required_unless_present = "keywords")]
+        description: Option<String>,
+        #[arg(long)]
+        keywords: Option<String>,
+    },
+    /// List edits that overwrote another made at the same time, e.g. in the
+    /// web page and in `browse`
+    Conflicts,
+    /// Add, remove, rename or merge keywords, on every photo that has
+    /// them or those a query matches
+    Tag {
+        #[command(subcommand)]
+        command: TagCommand,
+    },
+    /// Give photos a star rating, 1 to 5 (0 takes it away)
+    Rate {
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=5))]
+        stars: u8,
+        #[command(flatten)]
+        photos: Photos,
+    },
+    /// Mark photos as favorites
+    Favorite {
+        #[command(flatten)]
+        photos: Photos,
+        /// Unmark them instead
+        #[arg(long)]
+        unset: bool,
+    },
+    /// Flag photos as rejected, to leave out or delete later
+    Reject {
+        #[command(flatten)]
+        photos: Photos,
+        /// Flag them as kept again
+        #[arg(long)]
+        unset: bool,
+    },
+    /// Make albums and put photos in them, by hand or from a query; an
+    /// album is then a query term, `album:Lisbon`
+    Album {
+        #[command(subcommand)]
+        command: AlbumCommand,
+    },
+    /// Keep fields of your own on photos, e.g. `field set client=Smith`;
+    /// they're then searched with `--field client=Smith`
+    Field {
+        #[command(subcommand)]
+        command: FieldCommand,
+    },
+    /// List, rename or forget the named folders scans have cataloged; a
+    /// source is then a query term, `source:nas`
+    Source {
+        #[command(subcommand)]
+        command: SourceCommand,
+    },
+    /// List the files found broken (truncated, undecodable, with garbled
+    /// EXIF), or scan them again
+    Problems {
+        #[command(subcommand)]
+        command: ProblemsCommand,
+    },
+    /// List sets of exact copies, those wasting the most space first
+    Duplicates {
+        /// Only this many sets
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Print them as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Tell whether two files are the same picture, whatever their color
+    /// spaces, exposure and cropping
+    Compare {
+        a: PathBuf,
+        b: PathBuf,
+        /// Draw where they differ to this PNG
+        #[arg(long)]
+        heatmap: Option<PathBuf>,
+        /// Longest edge of the heatmap, in pixels
+        #[arg(long, default_value_t = 800, requires = "heatmap")]
+        size: u32,
+    },
+    /// Push AI descriptions and keywords to the photo server a library lives on
+    Sync(SyncArgs),
+    /// Copy the catalog and thumbnails, without the originals, to an rclone
+    /// remote for `serve` to browse elsewhere
+    Mirror {
+        /// The remote, e.g. "vps:photos" (defaults to `remote.destination`)
+        #[arg(long, value_name = "REMOTE")]
+        to: Option<String>,
+    },
+    /// Attach notes to photos, typed or as voice memos, and transcribe them
+    Note {
+        #[command(subcommand)]
+        command: NoteCommand,
+    },
+    /// Shift the capture times of matching photos, e.g. for a camera clock
+    /// left on the wrong time zone
+    FixDates(FixDatesArgs),
+    /// Work out how far each camera's clock was off at an event shot with
+    /// several, and correct their times to match a reference device
+    ReconcileClocks(ReconcileArgs),
+    /// Find trips away from home, and list or report on them
+    Trips {
+        #[command(subcommand)]
+        command: TripsCommand,
+    },
+    /// Cut a highlight video of a trip, one of its events or a year
+    Reel(ReelArgs),
+    /// Recommend files to move to cold storage or delete, going by how
+    /// they're used, and move them; or with --into, rename and move
+    /// originals into folders by capture time
+    Organize(OrganizeArgs),
+    /// Check that the catalogued files are all still there and unchanged,
+    /// e.g. after a disk swap
+    Verify {
+        /// Re-hash this many files, picked at random, to catch changed content
+        #[arg(long, conflicts_with = "all")]
+        sample: Option<usize>,
+        /// Re-hash every file
+        #[arg(long)]
+        all: bool,
+        /// Look for missing files under this directory, to tell moved from
+        /// lost; may be repeated
+        #[arg(long)]
+        search: Vec<PathBuf>,
+    },
+    /// Remove catalogued photos whose files have been deleted
+    Prune {
+        /// Flag them as archived instead, keeping what the catalog knows
+        #[arg(long)]
+        archive: bool,
+    },
+    /// Show the progress of running and recent scans and exports
+    Status {
+        /// Show only this operation
+        id: Option<i64>,
+    },
+    /// Ask a running scan or export to stop
+    Cancel {
+        id: i64,
+    },
+    /// Browse and search the catalog in the terminal
+    Browse {
+        /// Show the selected photo, in terminals with the kitty graphics protocol
+        #[arg(long)]
+        preview: bool,
+    },
+    /// Serve the HTTP API
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+        /// Show another catalog's photos alongside, as NAME=PATH; may be
+        /// repeated, on top of `[[web.catalogs]]`
+        #[arg(long, value_name = "NAME=PATH", value_parser = config::parse_attached)]
+        attach: Vec<config::AttachedCatalog>,
+    },
+    /// Show which configured features would send image data off this machine
+    Privacy,
+    /// Look after the catalog database itself
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Read the log of changes made through the API
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommand,
+    },
+    /// Show help for a command (`help tag add`) or a topic:
+    /// query-language, config or analyzers
+    Help {
+        words: Vec<String>,
+    },
+    /// Write manpages for every command and help topic
+    Manpages {
+        /// The directory to write them to
+        #[arg(long, default_value = "man")]
+        out: PathBuf,
+    },
+    /// Print version information
+    Version {
+        /// Also list the optional features compiled into this build
+        #[arg(long)]
+        features: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum SourceCommand {
+    /// List the sources, with how many photos each has
+    List {
+        #[command(flatten)]
+        table: table::TableArgs,
+    },
+    /// Give a source another name
+    Rename {
+        name: String,
+        new_name: String,
+    },
+    /// Forget a source, leaving its photos in the catalog
+    Remove {
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProblemsCommand {
+    /// List them, with what's wrong and since when
+    List {
+        /// Only problems of this kind: read, decode or exif
+        #[arg(long, value_parser = problems::Category::parse)]
+        category: Option<problems::Category>,
+        #[command(flatten)]
+        table: table::TableArgs,
+    },
+    /// Scan the files again, clearing the problems of those that are fixed
+    /// and forgetting those that are gone
+    Retry {
+        /// Only files with problems of this kind
+        #[arg(long, value_parser = problems::Category::parse)]
+        category: Option<problems::Category>,
+        /// Catalog file metadata only, as `scan --no-analyze` does
+        #[arg(long)]
+        no_analyze: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum DbCommand {
+    /// Check that the catalog's tables and files agree with each other:
+    /// references, unused tags, histograms, downloads, operations and
+    /// mirror thumbnails
+    Fsck {
+        /// Fix what's found, by every check or only those named, e.g.
+        /// "references,unused-tags"
+        #[arg(long, value_name = "CHECKS", num_args = 0.., value_delimiter = ',', value_parser = fsck::Check::parse)]
+        repair: Option<Vec<fsck::Check>>,
+    },
+}
+
+#[derive(Subcommand)]
+enum AuditCommand {
+    /// List the latest changes, oldest first
+    Show {
+        /// Only this user's
+        #[arg(long)]
+        actor: Option<String>,
+        /// The latest 50 unless `--limit` says otherwise
+        #[command(flatten)]
+        table: table::TableArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum TagCommand {
+    /// Add a keyword to the photos matching a query
+    Add {
+        tag: String,
+        /// Which photos, as key:value terms, e.g. `date:2023-07 place:Lisbon`
+        #[arg(long, num_args = 1.., value_parser = query::parse_term, required = true)]
+        query: Vec<query::Term>,
+    },
+    /// Remove a keyword
+    Remove {
+        tag: String,
+        /// Only from the photos matching these key:value terms
+        #[arg(long, num_args = 1.., value_parser = query::parse_term)]
+        query: Vec<query::Term>,
+    },
+    /// Rename a keyword, e.g. `tag rename dog dogs`
+    Rename {
+        from: String,
+        to: String,
+        #[arg(long, num_args = 1.., value_parser = query::parse_term)]
+        query: Vec<query::Term>,
+    },
+    /// List the keywords, with how many photos have each
+    List {
+        #[command(flatten)]
+        table: table::TableArgs,
+    },
+    /// Make several keywords one, e.g. `tag merge puppy dog --into dogs`
+    Merge {
+        #[arg(required = true)]
+        tags: Vec<String>,
+        #[arg(long)]
+        into: String,
+        #[arg(long, num_args = 1.., value_parser = query::parse_term)]
+        query: Vec<query::Term>,
+    },
+}
+
+/// Photos for a command to act on, by path or query or both.
+#[derive(Args)]
+struct Photos {
+    /// The photos, with the paths they were cataloged under
+    #[arg(required_unless_present = "query")]
+    paths: Vec<PathBuf>,
+    /// And the photos matching these key:value terms, e.g. `place:Lisbon date:2023-07`
+    #[arg(long, num_args = 1.., value_parser = query::parse_term)]
+    query: Vec<query::Term>,
+}
+
+impl Photos {
+    fn ids(&self, conn: &Connection) -> Result<Vec<i64>, Error> {
+        let paths: Vec<String> = self.paths.iter().map(|path| path.to_string_lossy().into_owned()).collect();
+        let filter = (!self.query.is_empty()).then(|| query::filter(&self.query));
+        selected_ids(conn, &paths, filter.as_ref())
+    }
+}
+
+#[derive(Subcommand)]
+enum AlbumCommand {
+    /// Make an empty album
+    Create {
+        name: String,
+    },
+    /// Put photos in an album
+    Add {
+        name: String,
+        #[command(flatten)]
+        photos: Photos,
+    },
+    /// Take photos out of an album, leaving them in the catalog
+    Remove {
+        name: String,
+        #[command(flatten)]
+        photos: Photos,
+    },
+    /// List the albums, or the photos in one
+    List {
+        name: Option<String>,
+        #[command(flatten)]
+        table: table::TableArgs,
+    },
+    /// Delete an album, leaving its photos in the catalog
+    Delete {
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ImportCommand {
+    /// Merge keywords, ratings, picks, capture times and fields
+    /// (`field:client` columns) from a CSV file into the photos its rows
+    /// match, showing each change; `--dry-run` only shows them
+    Csv {
+        /// The file to read ("-" for standard input)
+        file: PathBuf,
+        /// Match rows to photos by their `path` or `content_hash` column
+        #[arg(long, value_parser = spreadsheet::MatchOn::parse, default_value = "path")]
+        match_on: spreadsheet::MatchOn,
+    },
+}
+
+#[derive(Subcommand)]
+enum FieldCommand {
+    /// Set a field on photos, e.g. `field set invoice=1042 --type number`
+    Set {
+        #[arg(value_name = "KEY=VALUE", value_parser = fields::parse_assignment)]
+        field: (String, String),
+        #[command(flatten)]
+        photos: Photos,
+        /// What the field holds, the first time it's set: string (the
+        /// default), number or date
+        #[arg(long = "type", value_parser = fields::Kind::parse)]
+        kind: Option<fields::Kind>,
+    },
+    /// Take a field off photos
+    Remove {
+        key: String,
+        #[command(flatten)]
+        photos: Photos,
+    },
+    /// List the fields, with what they hold and how many photos have them
+    List {
+        #[command(flatten)]
+        table: table::TableArgs,
+    },
+}
+
+#[derive(Subcommand)]
+enum NoteCommand {
+    /// Attach a note to a photo
+    Add {
+        /// The photo, with the path it was cataloged under
+        path: PathBuf,
+        #[arg(long, required_unless_present = "audio", conflicts_with = "audio")]
+        text: Option<String>,
+        /// A voice memo, transcribed right away if a transcriber is configured
+        #[arg(long)]
+        audio: Option<PathBuf>,
+    },
+    /// List the notes on a photo, or on every photo
+    List {
+        path: Option<PathBuf>,
+    },
+    /// Transcribe the voice notes that haven't been yet
+    Transcribe,
+}
+
+#[derive(Subcommand)]
+enum JobsCommand {
+    /// Pack downsized copies of unanalyzed images into a batch archive
+    Export {
+        /// Maximum number of images in the batch
+        #[arg(long, default_value_t = 1000)]
+        limit: usize,
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Analyze a batch archive, writing a results archive
+    Run {
+        batch: PathBuf,
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Merge a results archive into the catalog
+    Import {
+        results: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum TripsCommand {
+    /// Find the trips in the catalog again, replacing the ones found before
+    Detect,
+    /// List the trips found
+    List,
+    /// Write a Markdown report of one trip
+    Report {
+        id: i64,
+        /// Where to write it (standard output when unset)
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Write the route of a trip as GeoJSON, with the distance covered each day
+    Route {
+        id: i64,
+        /// Just this event of the trip, by its number in the report
+        #[arg(long)]
+        event: Option<usize>,
+        /// Where to write it (standard output when unset)
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+#[group(required = true)]
+struct ExportArgs {
+    /// Write AI descriptions and keywords to XMP sidecars next to the originals
+    #[arg(long)]
+    xmp_sidecars: bool,
+    /// Write sidecars as `--xmp-sidecars` does, with the tags also in
+    /// digiKam's tag tree
+    #[arg(long)]
+    digikam: bool,
+    /// The digiKam tag the keywords go under
+    #[arg(long, value_name = "TAG", default_value = "AI", requires = "digikam")]
+    digikam_root: String,
+    /// Write YAML sidecars for PhotoPrism's indexer into its sidecar folder
+    #[arg(long, value_name = "DIR", group = "select", requires = "originals")]
+    photoprism: Option<PathBuf>,
+    /// PhotoPrism's originals folder, which the photos' paths are taken from
+    #[arg(long, value_name = "DIR", requires = "photoprism")]
+    originals: Option<PathBuf>,
+    /// Write a static HTML gallery of the catalog to this directory
+    #[arg(long, value_name = "DIR", group = "share", group = "select")]
+    html: Option<PathBuf>,
+    /// Write the catalog as CSV to this file ("-" for standard output)
+    #[arg(long, value_name = "FILE", group = "share", group = "select")]
+    csv: Option<PathBuf>,
+    /// Write every field of the catalog as JSON to this file ("-" for
+    /// standard output), for `import` to read back
+    #[arg(long, value_name = "FILE", group = "select")]
+    json: Option<PathBuf>,
+    /// Write `--json` as one record per line instead of an array
+    #[arg(long, requires = "json")]
+    json_lines: bool,
+    /// Columns for `--csv`, e.g. "path,creation_date,keywords"; all when unset
+    #[arg(long, value_delimiter = ',', requires = "csv")]
+    columns: Vec<String>,
+    /// Only photos matching these key:value terms, e.g. `camera:X100V date:2023-07`
+    #[arg(long, num_args = 1.., value_parser = query::parse_term, requires = "select")]
+    query: Vec<query::Term>,
+    /// Include photos taken inside private geofences
+    #[arg(long, requires = "share")]
+    include_private: bool,
+}
+
+#[derive(Args)]
+struct SyncArgs {
+    /// The Immich server, e.g. "http://nas.local:2283"
+    #[arg(long, value_name = "URL")]
+    immich: String,
+    /// Environment variable holding an Immich API key
+    #[arg(long, value_name = "VAR", default_value = "IMMICH_API_KEY")]
+    api_key_env: String,
+    /// The Immich tag the keywords go under
+    #[arg(long, value_name = "TAG", default_value = "AI")]
+    tag_root: String,
+    /// Only photos matching these key:value terms, e.g. `camera:X100V date:2023-07`
+    #[arg(long, num_args = 1.., value_parser = query::parse_term)]
+    query: Vec<query::Term>,
+}
+
+#[derive(Args)]
+struct FixDatesArgs {
+    /// How far to move the times, e.g. "+2h", "-1h30m" or "+1d"
+    #[arg(long, allow_hyphen_values = true, value_parser = dates::parse_shift, required_unless_present = "undo")]
+    shift: Option<i64>,
+    /// Which photos, as key:value terms, e.g. `camera:X100V date:2023-07`
+    #[arg(long, num_args = 1.., value_parser = query::parse_term, required_unless_present = "undo")]
+    query: Vec<query::Term>,
+    /// Also rewrite the dates in the files' EXIF (JPEG and TIFF-based RAW)
+    #[arg(long)]
+    exif: bool,
+    /// Make the changes; without this, only show what would change
+    #[arg(long)]
+    apply: bool,
+    /// Reverse an earlier shift, by the number `--apply` reported
+    #[arg(long, conflicts_with_all = ["shift", "query", "exif", "apply"])]
+    undo: Option<i64>,
+}
+
+#[derive(Args)]
+struct OrganizeArgs {
+    /// Move the recommended files here, keeping their paths below it
+    #[arg(long, conflicts_with = "into")]
+    archive_to: Option<PathBuf>,
+    /// Rename and move originals below this directory by --pattern
+    #[arg(long)]
+    into: Option<PathBuf>,
+    /// Where each goes below --into, e.g. "{YYYY}/{MM}/{YYYY}-{MM}-{DD}_{hh}{mm}{ss}_{camera}"
+    /// (defaults to `organize.pattern`)
+    #[arg(long, requires = "into")]
+    pattern: Option<String>,
+    /// Only these photos, as key:value terms, e.g. `camera:X100V date:2023-07`
+    #[arg(long, num_args = 1.., value_parser = query::parse_term, requires = "into")]
+    query: Vec<query::Term>,
+    /// Make the moves; without this, only show them
+    #[arg(long, requires = "into")]
+    apply: bool,
+}
+
+#[derive(Args)]
+struct ReconcileArgs {
+    /// The event's photos, as key:value terms, e.g. `date:2023-07-14 place:Lisbon`
+    #[arg(long, num_args = 1.., value_parser = query::parse_term, required = true)]
+    query: Vec<query::Term>,
+    /// The device whose clock is right, as "make model" (defaults to the
+    /// one with the most GPS-tagged photos)
+    #[arg(long)]
+    reference: Option<String>,
+    /// Two photos from different devices taken at the same moment, as
+    /// "a.jpg=b.jpg"; may be repeated
+    #[arg(long, value_parser = clocks::parse_anchor)]
+    anchor: Vec<(String, String)>,
+    /// Also rewrite the dates in the files' EXIF (JPEG and TIFF-based RAW)
+    #[arg(long)]
+    exif: bool,
+    /// Make the corrections; without this, only show them
+    #[arg(long)]
+    apply: bool,
+}
+
+#[derive(Args)]
+#[group(id = "of", required = true, multiple = true)]
+struct ReelArgs {
+    /// A trip, by its number in `trips list`
+    #[arg(long, group = "of")]
+    trip: Option<i64>,
+    /// Just this event of the trip, by its number in the report
+    #[arg(long, requires = "trip")]
+    event: Option<usize>,
+    /// The photos and videos of a year, e.g. 2023
+    #[arg(long, group = "of", value_parser = clap::value_parser!(u16).range(1000..=9999))]
+    year: Option<u16>,
+    /// Only photos matching these key:value terms, e.g. `place:Lisbon`
+    #[arg(long, group = "of", num_args = 1.., value_parser = query::parse_term)]
+    query: Vec<query::Term>,
+    /// Where to write the MP4
+    #[arg(long)]
+    out: PathBuf,
+    /// How long it runs at most (same as `reel.seconds`)
+    #[arg(long)]
+    seconds: Option<f64>,
+    /// Play this under it (same as `reel.music`)
+    #[arg(long)]
+    music: Option<PathBuf>,
+}
+
+/// Criteria for `search`; every criterion given must match.
+#[derive(Args, Default)]
+struct SearchFilter {
+    /// Only photos taken in this country, region or city
+    #[arg(long)]
+    place: Option<String>,
+    /// Camera make and/or model, e.g. "X-T4" or "Fujifilm"
+    #[arg(long)]
+    camera: Option<String>,
+    /// Part of the lens model name
+    #[arg(long)]
+    lens: Option<String>,
+    /// Focal length in mm, e.g. "35mm"
+    #[arg(long, value_parser = parse_focal_length)]
+    focal_length: Option<f64>,
+    /// Aperture as an f-number, e.g. "f/1.8"
+    #[arg(long, value_parser = parse_aperture)]
+    aperture: Option<f64>,
+    #[arg(long)]
+    iso: Option<u32>,
+    /// A keyword, whether AI-generated or from embedded metadata
+    #[arg(long)]
+    keyword: Option<String>,
+    /// Taken in this year, month or day, e.g. "2023-07"
+    #[arg(long, value_parser = query::parse_date)]
+    date: Option<String>,
+    /// Only photos from this trip, by the number `trips list` shows
+    #[arg(long)]
+    trip: Option<i64>,
+    /// Only photos in this album (see `album`)
+    #[arg(long)]
+    album: Option<String>,
+    /// Only photos from this source (see `source`)
+    #[arg(long)]
+    source: Option<String>,
+    /// Words from a note on the photo, typed or transcribed
+    #[arg(long)]
+    note: Option<String>,
+    /// Words describing a scene of a video; the scenes' times are listed
+    #[arg(long)]
+    scene: Option<String>,
+    /// What's being done in a clip, as tagged (e.g. diving)
+    #[arg(long)]
+    action: Option<String>,
+    /// Videos with sound (yes) or silent ones (no)
+    #[arg(long, value_parser = query::parse_yes_no)]
+    sound: Option<bool>,
+    /// Rated at least this many stars (see `rate`)
+    #[arg(long, value_parser = query::parse_rating)]
+    rating: Option<u8>,
+    /// Favorites (yes) or the rest (no)
+    #[arg(long, value_parser = query::parse_yes_no)]
+    favorite: Option<bool>,
+    /// Rejected photos (yes) or those kept (no)
+    #[arg(long, value_parser = query::parse_yes_no)]
+    rejected: Option<bool>,
+    /// A field of your own (see `field`), e.g. "client=Smith" or
+    /// "invoice>=1000"; may be given more than once
+    #[arg(long = "field", value_name = "KEY=VALUE", value_parser = fields::parse_condition)]
+    field: Vec<fields::Condition>,
+    /// Leave out photos taken inside private geofences
+    #[arg(long)]
+    shareable: bool,
+}
+
+fn parse_focal_length(s: &str) -> Result<f64, String> {
+    s.trim().trim_end_matches("mm").trim().parse()
+        .map_err(|_| format!("invalid focal length: {}", s))
+}
+
+fn parse_aperture(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    let s = s.strip_prefix("f/").or_else(|| s.strip_prefix("F/")).unwrap_or(s);
+    s.trim_start_matches(['f', 'F']).parse()
+        .map_err(|_| format!("invalid aperture: {}", s))
+}
+
+/// A duration such as "90s", "45m", "2h" or "1h30m"; a bare number is seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration: {}", s);
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    let mut total = 0;
+    let mut digits = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = digits.parse().map_err(|_| invalid())?;
+        total += value * unit;
+        digits.clear();
+    }
+    if !digits.is_empty() || s.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(total))
+}
+
+/// A value that came with the file (or its sidecars) rather than from our
+/// own analysis, kept with the name of the source it came from.
+#[derive(Debug, Clone, PartialEq)]
+struct ExternalMetadata {
+    /// e.g. "iptc"
+    source: String,
+    /// e.g. "keyword", "caption", "title", "byline"
+    field: String,
+    value: String,
+}
+
+#[derive(Default)]
+struct ImageMetadata {
+    path: String,
+    file_name: String,
+    file_size: u64,
+    /// SHA-256 of the file, in hex
+    content_hash: Option<String>,
+    /// As displayed, i.e. with the orientation applied
+    dimensions: Option<(u32, u32)>,
+    /// EXIF orientation, 1 to 8
+    orientation: Option<u16>,
+    format: Option<String>,
+    /// Pages in a multi-page document (TIFF only)
+    page_count: Option<u32>,
+    /// Videos, and one play through an animation
+    duration_secs: Option<f64>,
+    video_codec: Option<String>,
+    /// Animated GIF and PNG only
+    frame_count: Option<u32>,
+    /// Times an animation plays, 0 for forever
+    play_count: Option<u32>,
+    creation_date: Option<String>,
+    gps: Option<(f64, f64)>,
+    place: Option<Place>,
+    camera: CameraInfo,
+    /// The device profile that matched
+    device: Option<String>,
+    tier: Option<String>,
+    external: Vec<ExternalMetadata>,
+    keywords: Option<String>,
+    description: Option<String>,
+    /// Videos only, with `video.scenes` on
+    scenes: Vec<scenes::Scene>,
+    /// What's broken in the file, short of it not being cataloged
+    problems: Vec<(problems::Category, String)>,
+}
+
+const CATALOG_PATH: &str = "photo_catalog.db";
+/// What `--db` takes for a catalog kept in memory, as SQLite names it.
+const IN_MEMORY: &str = ":memory:";
+
+/// Open (creating if needed) the catalog. Several connections may be open
+/// at once, e.g. `status` while a scan runs, so wait for locks briefly
+/// rather than failing. The catalog is kept in WAL mode, where readers
+/// don't wait for a writer at all.
+fn open_catalog(path: &Path) -> Result<Connection, Error> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(std::time::Duration::from_secs(5))?;
+    // The answer is "memory" for in-memory catalogs, which can't be WAL
+    conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+    // Safe with WAL: a power cut may lose the last transactions, not the catalog
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    init_database(&conn)?;
+    Ok(conn)
+}
+
+/// Create the catalog's tables, or bring an older catalog's up to date.
+fn init_database(conn: &Connection) -> Result<(), Error> {
+    migrations::migrate(conn)
+}
+
+/// Decode the GPS latitude/longitude (degrees, minutes, seconds plus N/S/E/W
+/// references) into signed decimal degrees.
+fn gps_coordinates(exif: &exif::Exif) -> Option<(f64, f64)> {
+    let coordinate = |tag: Tag, ref_tag: Tag, negative: u8| {
+        let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+            exif::Value::Rational(v) if v.len() >= 3 => {
+                v[0].to_f64() + v[1].to_f64() / 60.0 + v[2].to_f64() / 3600.0
+            }
+            _ => return None,
+        };
+        match &exif.get_field(ref_tag, In::PRIMARY)?.value {
+            exif::Value::Ascii(v) if v.first().and_then(|r| r.first()) == Some(&negative) => Some(-degrees),
+            _ => Some(degrees),
+        }
+    };
+
+    let latitude = coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S')?;
+    let longitude = coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W')?;
+    latitude.is_finite().then_some((latitude, longitude))
+}
+
+/// Read (and unless `analyzer` is `None`, analyze) one file, with its
+/// Takeout sidecar (if enabled), device profile and geofences applied.
+fn process_image(
+    path: &Path,
+    config: &Config,
+    analyzer: Option<&AnalyzerPool>,
+    cancel: &CancelToken,
+) -> Result<ImageMetadata, Error> {
+    process_copy(path, path, config, analyzer, cancel)
+}
+
+/// Like `process_image`, reading the file from `copy`, in a snapshot, for
+/// the one at `path`.
+fn process_copy(
+    path: &Path,
+    copy: &Path,
+    config: &Config,
+    analyzer: Option<&AnalyzerPool>,
+    cancel: &CancelToken,
+) -> Result<ImageMetadata, Error> {
+    let mut metadata = match video::format_for(copy) {
+        Some(format) => process_video(copy, format, config, analyzer, cancel)?,
+        None => process_still(copy, config, analyzer, cancel)?,
+    };
+    metadata.path = path.to_string_lossy().into_owned();
+    metadata.keywords = metadata.keywords.map(|list| keywords::enforce(&config.keywords, &metadata.path, &list));
+    if config.takeout.enabled {
+        takeout::apply(&mut metadata)?;
+    }
+    let mut metadata = devices::apply(&config.devices, metadata)?;
+    if let Some(gps) = metadata.gps {
+        metadata.external.extend(geofence::metadata(&config.geofences, gps));
+    }
+    Ok(metadata)
+}
+
+/// Like `process_image`, for an original in an Apple Photos library, with
+/// what the library knows about it merged in.
+fn process_original(
+    path: &Path,
+    library: &apple_photos::Library,
+    config: &Config,
+    analyzer: Option<&AnalyzerPool>,
+    cancel: &CancelToken,
+) -> Result<ImageMetadata, Error> {
+    let mut metadata = process_image(path, config, analyzer, cancel)?;
+    let located = metadata.gps.is_some();
+    library.apply(&mut metadata)?;
+    if let (false, Some(gps)) = (located, metadata.gps) {
+        metadata.external.extend(geofence::metadata(&config.geofences, gps));
+    }
+    Ok(metadata)
+}
+
+fn process_still(
+    path: &Path,
+    config: &Config,
+    analyzer: Option<&AnalyzerPool>,
+    cancel: &CancelToken,
+) -> Result<ImageMetadata, Error> {
+    let file_name = path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+
+    let metadata = fs::metadata(path)?;
+    let file_size = metadata.len();
+    
+    // Instead of trying to get format from DynamicImage
+    let file = tracing::debug_span!("read").in_scope(|| share::read(path, &config.shares))?;
+    let decoding = tracing::debug_span!("decode").entered();
+    // RAW files are decoded and analyzed through the camera's own preview,
+    // HEIF, AVIF, WebP and TIFF files through a JPEG conversion (only
+    // needed for analysis)
+    let raw = raw::format_for(path)
+        .map(|format| raw::read(&file, format))
+        .transpose()
+        .map_err(|e| problems::corrupt(problems::Category::Decode, e))?;
+    let preview = raw.as_ref().and_then(|raw| raw.preview.as_deref());
+    let heif_format = heif::format_for(path);
+    let is_heif = heif_format.is_some();
+    let is_webp = webp::is_webp(path);
+    let tiff_pages = if tiff::is_tiff(path) { tiff::pages(&file) } else { None };
+    // Animations are analyzed by their middle frame
+    let animation = animation::read(&file);
+    let converted = match (analyzer, &animation) {
+        (Some(_), _) if is_heif => Some(heif::to_jpeg(&file)),
+        (Some(_), _) if is_webp => Some(webp::to_jpeg(&file)),
+        (Some(_), _) if tiff_pages.is_some() => Some(tiff::to_jpeg(&file)),
+        (Some(_), Some(animation)) => Some(animation::middle_frame(&file, animation.frame_count).and_then(|img| encode_jpeg(&img))),
+        _ => None,
+    };
+    let converted = converted.and_then(|result| {
+        result.map_err(|e| tracing::warn!("Can't convert {} for analysis: {}", path.display(), e)).ok()
+    });
+    let image_data = match (&raw, is_heif || is_webp || tiff_pages.is_some() || animation.is_some()) {
+        (Some(_), _) => preview,
+        (None, true) => converted.as_deref(),
+        (None, false) => Some(&file[..]),
+    };
+    let mut problems = Vec::new();
+    let img = image_data.and_then(|data| match image::load_from_memory(data) {
+        Ok(img) => Some(img),
+        Err(e) => {
+            tracing::warn!("Can't decode {}: {}; cataloged without its pixels", path.display(), e);
+            problems.push((problems::Category::Decode, e.to_string()));
+            None
+        }
+    });
+    drop(decoding);
+
+    let reading = tracing::debug_span!("exif").entered();
+    // Get EXIF data for creation date and location
+    let read_exif = |data: &[u8]| Reader::new().read_from_container(&mut std::io::Cursor::new(data));
+    let exif = match read_exif(&file) {
+        Ok(exif) => Some(exif),
+        Err(e) => match preview.map(read_exif) {
+            Some(Ok(exif)) => Some(exif),
+            // Not having any is fine; having it broken isn't, though a file
+            // that didn't decode needn't have that said twice
+            _ if matches!(e, exif::Error::NotFound(_) | exif::Error::NotSupported(_)) || !problems.is_empty() => None,
+            _ => {
+                tracing::warn!("Can't read the EXIF of {}: {}", path.display(), e);
+                problems.push((problems::Category::Exif, e.to_string()));
+                None
+            }
+        },
+    };
+    // HEIF turns images with its own property, which `heif::dimensions`
+    // reads, and says the EXIF one is to be ignored
+    let orientation = exif.as_ref().and_then(orientation::read).filter(|_| !is_heif);
+    let stored_dimensions = raw.as_ref()
+        .and_then(|raw| raw.dimensions)
+        .or_else(|| is_heif.then(|| heif::dimensions(&file)).flatten())
+        .or_else(|| is_webp.then(|| webp::dimensions(&file)).flatten())
+        .or_else(|| tiff_pages.and_then(|(_, first)| first))
+        .or_else(|| animation.as_ref().map(|animation| animation.dimensions))
+        .or_else(|| img.as_ref().map(|img| img.dimensions()));
+    let dimensions = stored_dimensions.map(|size| orientation::displayed(size, orientation));
+    let format = match &raw {
+        Some(raw) => Some(raw.format.to_string()),
+        None if is_heif => heif_format.map(String::from),
+        None => image::guess_format(&file).ok().map(|f| format!("{:?}", f)),
+    };
+
+    let creation_date = exif.as_ref().and_then(|exif| {
+        exif.get_field(Tag::DateTimeOriginal, In::PRIMARY)
+            .map(|field| field.display_value().to_string())
+    });
+    let gps = exif.as_ref().and_then(gps_coordinates);
+    let camera = exif.as_ref().map(CameraInfo::from_exif).unwrap_or_default();
+    let mut external = iptc::read(&file, exif.as_ref()).into_external();
+    let embedded_xmp = match () {
+        _ if is_webp => webp::chunk(&file, b"XMP "),
+        _ if is_heif => heif::xmp(&file),
+        _ => None,
+    };
+    if let Some(embedded) = embedded_xmp.and_then(|packet| xmp::read_embedded(path, packet)) {
+        external.extend(embedded.into_external());
+    }
+    if let Some(sidecar) = xmp::read_sidecar(path) {
+        external.extend(sidecar.into_external());
+    }
+    let place = gps.and_then(|(lat, lon)| geocode::reverse_geocode(lat, lon));
+    drop(reading);
+
+    // Get image analysis from the analyzer pool, unless it's deferred
+    let (description, keywords) = match (analyzer, image_data) {
+        (Some(analyzer), Some(data)) => {
+            let tier = devices::matching(&config.devices, &camera).and_then(|profile| profile.tier.as_deref());
+            let neighbors = sequence::neighbors(path, &config.analyzer.sequence);
+            let analysis = tracing::debug_span!("ai").in_scope(|| analyzer.analyze_in_sequence(data, &neighbors, tier, cancel))?;
+            external.extend(analysis.external());
+            (Some(analysis.description), Some(analysis.keywords))
+        }
+        (Some(_), None) => {
+            tracing::warn!("Nothing to analyze in {}; cataloged without analysis", path.display());
+            (None, None)
+        }
+        (None, _) => (None, None),
+    };
+
+    Ok(ImageMetadata {
+        path: path.to_string_lossy().into_owned(),
+        file_name,
+        file_size,
+        content_hash: Some(hash::of_bytes(&file)),
+        dimensions,
+        orientation,
+        format,
+        page_count: tiff_pages.map(|(count, _)| count),
+        duration_secs: animation.as_ref().map(|animation| animation.duration_secs),
+        video_codec: None,
+        frame_count: animation.as_ref().map(|animation| animation.frame_count),
+        play_count: animation.as_ref().map(|animation| animation.play_count),
+        creation_date,
+        gps,
+        place,
+        camera,
+        device: None,
+        tier: None,
+        external,
+        keywords,
+        description,
+        scenes: Vec::new(),
+        problems,
+    })
+}
+
+/// Videos are never read whole: the metadata comes from `video::probe`,
+/// and analysis gets a representative frame.
+fn process_video(
+    path: &Path,
+    format: &str,
+    config: &Config,
+    analyzer: Option<&AnalyzerPool>,
+    cancel: &CancelToken,
+) -> Result<ImageMetadata, Error> {
+    let file_name = path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let file_size = fs::metadata(path)?.len();
+
+    let mut external = Vec::new();
+    let probe = match tracing::debug_span!("read").in_scope(|| video::probe(path, &config.video)) {
+        Ok(probe) => {
+            external.extend(video::audio_metadata(path, probe.audio.as_ref(), &config.video));
+            probe
+        }
+        Err(e) => {
+            tracing::warn!("Can't read video metadata from {}: {}", path.display(), e);
+            video::Probe::default()
+        }
+    };
+    if let Some(sidecar) = xmp::read_sidecar(path) {
+        external.extend(sidecar.into_external());
+    }
+    let place = probe.gps.and_then(|(lat, lon)| geocode::reverse_geocode(lat, lon));
+
+    let (description, keywords) = match analyzer {
+        Some(analyzer) => match tracing::debug_span!("decode").in_scope(|| video::frame(path, probe.duration_secs, &config.video)) {
+            Ok(frame) => {
+                let analysis = tracing::debug_span!("ai").in_scope(|| analyzer.analyze_blocking(&frame, None, cancel))?;
+                external.extend(analysis.external());
+                (Some(analysis.description), Some(analysis.keywords))
+            }
+            Err(e) => {
+                tracing::warn!("Can't extract a frame from {} ({}); cataloged without analysis", path.display(), e);
+                (None, None)
+            }
+        },
+        None => (None, None),
+    };
+    let scenes = match analyzer {
+        Some(analyzer) if config.video.scenes && description.is_some() => {
+            match scenes::caption(path, probe.duration_secs, &config.video, analyzer, cancel) {
+                Ok(scenes) => scenes,
+                Err(e) if error::classify(&e) == ErrorKind::Cancelled => return Err(e),
+                Err(e) => {
+                    tracing::warn!("Can't split {} into scenes: {}", path.display(), e);
+                    Vec::new()
+                }
+            }
+        }
+        _ => Vec::new(),
+    };
+    if let Some(analyzer) = analyzer.filter(|_| description.is_some()) {
+        match actions::tag_clip(path, probe.duration_secs, &config.video, analyzer, cancel) {
+            Ok(tags) => external.extend(tags),
+            Err(e) if error::classify(&e) == ErrorKind::Cancelled => return Err(e),
+            Err(e) => tracing::warn!("Can't tag the actions in {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(ImageMetadata {
+        path: path.to_string_lossy().into_owned(),
+        file_name,
+        file_size,
+        content_hash: Some(hash::of_file(path)?),
+        dimensions: probe.dimensions,
+        orientation: None,
+        format: Some(format.to_string()),
+        page_count: None,
+        duration_secs: probe.duration_secs,
+        video_codec: probe.codec,
+        frame_count: None,
+        play_count: None,
+        creation_date: probe.creation_date,
+        gps: probe.gps,
+        place,
+        camera: CameraInfo::default(),
+        device: None,
+        tier: None,
+        external,
+        keywords,
+        description,
+        scenes,
+        problems: Vec::new(),
+    })
+}
+
+/// Add an image to the catalog, or update it if its path is catalogued
+/// already. A rescan without analysis or geocoding keeps the description,
+/// keywords and place found before, and descriptions corrected by hand are
+/// kept either way. Metadata from the sources this scan read replaces what
+/// they gave before. The statements are kept prepared on the connection,
+/// since scans save thousands of rows in a row.
+fn save_metadata(conn: &Connection, metadata: &ImageMetadata) -> Result<()> {
+    let image_id: i64 = conn.prepare_cached(
+        "INSERT INTO images (
+            path, file_name, file_size, content_hash, width, height, orientation, format, page_count,
+            duration_secs, video_codec, frame_count, play_count, creation_date,
+            latitude, longitude, country, region, city,
+            camera_make, camera_model, lens_model, iso, aperture, exposure_time,
+            focal_length, flash_fired, camera_serial, device, tier, description
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15,
+                  ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25,
+                  ?26, ?27, ?28, ?29, ?30, ?31)
+        ON CONFLICT (path) DO UPDATE SET
+            file_name = excluded.file_name, file_size = excluded.file_size, content_hash = excluded.content_hash,
+            width = excluded.width, height = excluded.height, orientation = excluded.orientation,
+            format = excluded.format, page_count = excluded.page_count, duration_secs = excluded.duration_secs,
+            video_codec = excluded.video_codec, frame_count = excluded.frame_count, play_count = excluded.play_count,
+            creation_date = excluded.creation_date, latitude = excluded.latitude, longitude = excluded.longitude,
+            country = COALESCE(excluded.country, country), region = COALESCE(excluded.region, region),
+            city = COALESCE(excluded.city, city), camera_make = excluded.camera_make,
+            camera_model = excluded.camera_model, lens_model = excluded.lens_model, iso = excluded.iso,
+            aperture = excluded.aperture, exposure_time = excluded.exposure_time,
+            focal_length = excluded.focal_length, flash_fired = excluded.flash_fired,
+            camera_serial = excluded.camera_serial, device = excluded.device, tier = excluded.tier,
+            archived_at = NULL,
+            description = CASE WHEN EXISTS (SELECT 1 FROM corrections WHERE image_id = images.id) THEN description
+                               ELSE COALESCE(excluded.description, description) END
+        RETURNING id",
+    )?.query_row(
+        rusqlite::params![
+            metadata.path,
+            metadata.file_name,
+            metadata.file_size,
+            metadata.content_hash,
+            metadata.dimensions.map(|(w, _)| w),
+            metadata.dimensions.map(|(_, h)| h),
+            metadata.orientation,
+            metadata.format,
+            metadata.page_count,
+            metadata.duration_secs,
+            metadata.video_codec,
+            metadata.frame_count,
+            metadata.play_count,
+            metadata.creation_date,
+            metadata.gps.map(|(lat, _)| lat),
+            metadata.gps.map(|(_, lon)| lon),
+            metadata.place.as_ref().map(|p| &p.country),
+            metadata.place.as_ref().and_then(|p| p.region.as_ref()),
+            metadata.place.as_ref().and_then(|p| p.city.as_ref()),
+            metadata.camera.make,
+            metadata.camera.model,
+            metadata.camera.lens,
+            metadata.camera.iso,
+            metadata.camera.aperture,
+            metadata.camera.exposure_time,
+            metadata.camera.focal_length,
+            metadata.camera.flash_fired,
+            metadata.camera.serial,
+            metadata.device,
+            metadata.tier,
+            metadata.description,
+        ],
+        |row| row.get(0),
+    )?;
+    if let Some(keywords) = &metadata.keywords {
+        let corrected: bool = conn.prepare_cached("SELECT EXISTS (SELECT 1 FROM corrections WHERE image_id = ?1)")?
+            .query_row([image_id], |row| row.get(0))?;
+        if !corrected {
+            tags::set(conn, image_id, keywords)?;
+        }
+    }
+    let mut sources: Vec<&str> = metadata.external.iter().map(|external| external.source.as_str()).collect();
+    sources.sort_unstable();
+    sources.dedup();
+    let mut delete_external = conn.prepare_cached("DELETE FROM external_metadata WHERE image_id = ?1 AND source = ?2")?;
+    for source in sources {
+        delete_external.execute(rusqlite::params![image_id, source])?;
+    }
+    let mut insert_external = conn.prepare_cached("INSERT INTO external_metadata (image_id, source, field, value) VALUES (?1, ?2, ?3, ?4)")?;
+    for external in &metadata.external {
+        insert_external.execute(rusqlite::params![image_id, external.source, external.field, external.value])?;
+    }
+    if !metadata.scenes.is_empty() {
+        conn.prepare_cached("DELETE FROM scenes WHERE image_id = ?1")?.execute([image_id])?;
+    }
+    scenes::save(conn, image_id, &metadata.scenes)?;
+    problems::settle(conn, &metadata.path, &metadata.problems)?;
+    Ok(())
+}
+
+/// Fill in place names for rows that have coordinates but were cataloged
+/// without a place (or before geocoding existed). Returns the number updated.
+fn geocode_missing(conn: &Connection) -> Result<usize> {
+    let mut stmt = conn.prepare(
+        "SELECT id, latitude, longitude FROM images
+         WHERE latitude IS NOT NULL AND longitude IS NOT NULL AND country IS NULL",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?, row.get::<_, f64>(2)?))
+    })?.collect::<Result<Vec<_>>>()?;
+
+    let mut updated = 0;
+    for (id, lat, lon) in rows {
+        if let Some(place) = geocode::reverse_geocode(lat, lon) {
+            conn.execute(
+                "UPDATE images SET country = ?1, region = ?2, city = ?3 WHERE id = ?4",
+                rusqlite::params![place.country, place.region, place.city, id],
+            )?;
+            updated += 1;
+        }
+    }
+    Ok(updated)
+}
+
+/// Paths of images matching every criterion in `filter`.
+#[cfg(test)]
+fn search_images(conn: &Connection, filter: &SearchFilter) -> Result<Vec<String>> {
+    let (clause, params) = filter_clause(filter);
+    let mut stmt = conn.prepare(&format!("SELECT path FROM images{} ORDER BY path", clause))?;
+    let paths = stmt.query_map(rusqlite::params_from_iter(params), |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?;
+    Ok(paths)
+}
+
+/// The columns `search` can show, and those it does unless told
+/// otherwise.
+const SEARCH_COLUMNS: &[&str] = &["path", "name", "date", "camera", "lens", "place", "size", "rating", "keywords", "scenes", "fields"];
+const SEARCH_DEFAULT: &[&str] = &["path", "date", "camera", "place"];
+
+/// The images matching `filter` as a table of `SEARCH_COLUMNS`, by path.
+/// Scenes are only looked up when searching for them; fields are shown as
+/// `key=value`, by key.
+fn search_table(conn: &Connection, filter: &SearchFilter) -> Result<table::Table, Error> {
+    use table::Cell;
+    let (clause, params) = filter_clause(filter);
+    let mut stmt = conn.prepare(&format!(
+        "SELECT path, file_name, creation_date, COALESCE(camera_model, camera_make), lens_model, city, country, file_size, rating, {}, id
+         FROM images{} ORDER BY path",
+        tags::KEYWORDS, clause,
+    ))?;
+    let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+    let mut found = table::Table::new(SEARCH_COLUMNS);
+    while let Some(row) = rows.next()? {
+        let path: String = row.get(0)?;
+        let place: Vec<String> = [row.get::<_, Option<String>>(5)?, row.get(6)?].into_iter().flatten().collect();
+        let rating: i64 = row.get(8)?;
+        let scenes = match &filter.scene {
+            Some(words) => scenes::matching(conn, &path, words)?
+                .iter()
+                .map(|scene| format!("{} {}", scenes::timestamp(scene.start_secs), scene.description))
+                .collect::<Vec<_>>()
+                .join("; "),
+            None => String::new(),
+        };
+        let fields: Vec<String> = fields::of(conn, row.get(10)?)?.into_iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        found.push(vec![
+            Cell::Text(path),
+            Cell::Text(row.get(1)?),
+            Cell::from(row.get::<_, Option<String>>(2)?),
+            Cell::from(row.get::<_, Option<String>>(3)?),
+            Cell::from(row.get::<_, Option<String>>(4)?),
+            Cell::from(Some(place.join(", "))),
+            Cell::Size(row.get::<_, i64>(7)? as u64),
+            if rating > 0 { Cell::Number(rating) } else { Cell::Empty },
+            Cell::from(row.get::<_, Option<String>>(9)?),
+            Cell::from(Some(scenes)),
+            Cell::from(Some(fields.join("; "))),
+        ]);
+    }
+    Ok(found)
+}
+
+/// The ids of the photos at `paths`, as cataloged, and those `filter`
+/// matches if given, for commands taking either. A photo selected both
+/// ways is there once.
+fn selected_ids(conn: &Connection, paths: &[String], filter: Option<&SearchFilter>) -> Result<Vec<i64>, Error> {
+    let mut ids = Vec::new();
+    for path in paths {
+        let id = conn.query_row("SELECT id FROM images WHERE path = ?1", [path], |row| row.get(0))
+            .optional()?
+            .ok_or_else(|| anyhow::anyhow!("{} isn't in the catalog", path))?;
+        ids.push(id);
+    }
+    if let Some(filter) = filter {
+        let (clause, params) = filter_clause(filter);
+        let mut stmt = conn.prepare(&format!("SELECT id FROM images{} ORDER BY id", clause))?;
+        let matching = stmt.query_map(rusqlite::params_from_iter(params), |row| row.get(0))?;
+        for id in matching {
+            ids.push(id?);
+        }
+    }
+    Ok(first_occurrences(ids))
+}
+
+/// `ids` without repeats, each where it first appeared.
+fn first_occurrences(mut ids: Vec<i64>) -> Vec<i64> {
+    let mut seen = std::collections::HashSet::new();
+    ids.retain(|id| seen.insert(*id));
+    ids
+}
+
+/// The `WHERE` clause (empty if there are no criteria) selecting the images
+/// that match `filter`, and its parameters. Text criteria are
+/// case-insensitive; numeric ones allow for EXIF rounding.
+fn filter_clause(filter: &SearchFilter) -> (String, Vec<Box<dyn rusqlite::ToSql>>) {
+    let (fields, field_params): (Vec<String>, Vec<_>) = filter.field.iter().map(fields::Condition::clause).unzip();
+    let mut conditions: Vec<&str> = Vec::new();
+    let mut params: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+
+    if let Some(place) = &filter.place {
+        conditions.push(
+            "(country = ? COLLATE NOCASE OR region = ? COLLATE NOCASE OR city = ? COLLATE NOCASE)",
+        );
+        for _ in 0..3 {
+            params.push(Box::new(place.clone()));
+        }
+    }
+    if let Some(camera) = &filter.camera {
+        conditions.push("COALESCE(camera_make, '') || ' ' || COALESCE(camera_model, '') LIKE ?");
+        params.push(Box::new(format!("%{}%", camera)));
+    }
+    if let Some(lens) = &filter.lens {
+        conditions.push("lens_model LIKE ?");
+        params.push(Box::new(format!("%{}%", lens)));
+    }
+    if let Some(focal_length) = filter.focal_length {
+        conditions.push("ABS(focal_length - ?) < 0.5");
+        params.push(Box::new(focal_length));
+    }
+    if let Some(aperture) = filter.aperture {
+        conditions.push("ABS(aperture - ?) < 0.05");
+        params.push(Box::new(aperture));
+    }
+    if let Some(iso) = filter.iso {
+        conditions.push("iso = ?");
+        params.push(Box::new(iso));
+    }
+    if let Some(keyword) = &filter.keyword {
+        conditions.push(
+            "(id IN (SELECT image_id FROM image_tags JOIN tags ON tags.id = image_tags.tag_id WHERE tags.name LIKE ?)
+              OR id IN (SELECT image_id FROM external_metadata WHERE field = 'keyword' AND value LIKE ?))",
+        );
+        params.push(Box::new(format!("%{}%", keyword)));
+        params.push(Box::new(keyword.clone()));
+    }
+    if let Some(date) = &filter.date {
+        conditions.push("creation_date LIKE ?");
+        params.push(Box::new(format!("{}%", date)));
+    }
+
+    if let Some(trip) = filter.trip {
+        conditions.push("id IN (SELECT image_id FROM trip_images WHERE trip_id = ?)");
+        params.push(Box::new(trip));
+    }
+    if let Some(album) = &filter.album {
+        conditions.push("id IN (SELECT image_id FROM album_images JOIN albums ON albums.id = album_images.album_id WHERE albums.name = ?)");
+        params.push(Box::new(album.clone()));
+    }
+    if let Some(source) = &filter.source {
+        conditions.push("source_id IN (SELECT id FROM sources WHERE name = ?)");
+        params.push(Box::new(source.trim().to_string()));
+    }
+    if let Some(note) = &filter.note {
+        conditions.push("id IN (SELECT image_id FROM notes WHERE id IN (SELECT rowid FROM notes_fts WHERE notes_fts MATCH ?))");
+        params.push(Box::new(notes::match_query(note)));
+    }
+    if let Some(scene) = &filter.scene {
+        conditions.push("id IN (SELECT image_id FROM scenes WHERE id IN (SELECT rowid FROM scenes_fts WHERE scenes_fts MATCH ?))");
+        params.push(Box::new(notes::match_query(scene)));
+    }
+    if let Some(sound) = filter.sound {
+        conditions.push("id IN (SELECT image_id FROM external_metadata WHERE source = 'audio' AND field = 'silent' AND value = ?)");
+        params.push(Box::new((!sound).to_string()));
+    }
+    if let Some(action) = &filter.action {
+        conditions.push("id IN (SELECT image_id FROM external_metadata WHERE source = 'action' AND value = ?)");
+        params.push(Box::new(action.to_lowercase().replace(' ', "-")));
+    }
+    if let Some(rating) = filter.rating {
+        conditions.push("rating >= ?");
+        params.push(Box::new(rating));
+    }
+    if let Some(favorite) = filter.favorite {
+        conditions.push("favorite = ?");
+        params.push(Box::new(favorite));
+    }
+    if let Some(rejected) = filter.rejected {
+        conditions.push("rejected = ?");
+        params.push(Box::new(rejected));
+    }
+    conditions.extend(fields.iter().map(String::as_str));
+    params.extend(field_params.into_iter().flatten());
+    if filter.shareable {
+        conditions.push(geofence::SHAREABLE_CONDITION);
+    }
+
+    let clause = if conditions.is_empty() { String::new() } else { format!(" WHERE {}", conditions.join(" AND ")) };
+    (clause, params)
+}
+
+/// The command line, refusing the old bare-directory form next to a
+/// subcommand, where the directory would go unused.
+fn parse_cli<I, T>(args: I) -> Result<Cli, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let cli = Cli::try_parse_from(args)?;
+    if let (Some(dir), Some(_)) = (&cli.dir, &cli.command) {
+        let message = format!("unexpected argument '{}' before the subcommand; give folders to scan after `scan`", dir.display());
+        return Err(Cli::command().error(clap::error::ErrorKind::ArgumentConflict, message));
+    }
+    Ok(cli)
+}
+
+fn main() -> ExitCode {
+    let cli = parse_cli(std::env::args_os()).unwrap_or_else(|e| e.exit());
+    logging::init(cli.verbose, cli.quiet);
+    if let Err(e) = cancel::trap_signals() {
+        tracing::warn!("Ctrl-C won't stop cleanly: {}", e);
+    }
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => error::report(&e),
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
+    if cli.dry_run && !rehearsable(&cli.command) {
+        let message = "this command has no dry run; organize, prune, writeback, tag, fix-dates, reconcile-clocks and import csv do";
+        return Err(CliError::new(ErrorKind::Config, message).into());
+    }
+    // Before the configuration, which help shouldn't need to be valid
+    match &cli.command {
+        Some(Command::Help { words }) => {
+            print!("{}", help::show(Cli::command(), words)?);
+            return Ok(());
+        }
+        Some(Command::Manpages { out }) => {
+            let written = help::manpages(Cli::command(), out)?;
+            println!("Wrote {} manpages to {}", written.len(), out.display());
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let mut config = Config::load(cli.config.as_deref())?;
+    let local_only = cli.local_only || config.privacy.local_only;
+
+    let in_memory = cli.db.as_os_str() == IN_MEMORY;
+    if in_memory && matches!(cli.command, Some(Command::Serve { .. })) {
+        let message = "serve opens the catalog for each request, so it can't be kept in memory";
+        return Err(CliError::new(ErrorKind::Config, message).into());
+    }
+    let conn = open_catalog(&cli.db)?;
+
+    match cli.command {
+        Some(Command::Scan { dirs, no_analyze, max_duration, takeout, exclude, include, follow_symlinks, max_depth, min_size, max_size, report, max_failures, snapshot, csv, json, resume }) => {
+            config.takeout.enabled |= takeout;
+            config.scan.follow_symlinks |= follow_symlinks;
+            config.scan.snapshot |= snapshot;
+            config.scan.max_depth = max_depth.or(config.scan.max_depth);
+            config.scan.min_size = min_size.map(|size| size.to_string()).or(config.scan.min_size);
+            config.scan.max_size = max_size.map(|size| size.to_string()).or(config.scan.max_size);
+            config.scan.report = report.or(config.scan.report);
+            config.scan.max_failures = max_failures.map(|threshold| threshold.to_string()).or(config.scan.max_failures);
+            config.scan.exclude.extend(exclude);
+            config.scan.include.extend(include);
+            if !no_analyze {
+                privacy::enforce(&config, local_only)?;
+            }
+            let cancel = interruptible();
+            if let Some(limit) = max_duration {
+                cancel.set_time_limit(limit);
+            }
+            scan_sources(&conn, &config, &dirs, !no_analyze, resume, &cancel)?;
+            if let Some(out) = &csv {
+                write_csv(&conn, &SearchFilter { shareable: true, ..SearchFilter::default() }, &[], out)?;
+            }
+            if let Some(out) = &json {
+                write_json(&conn, &SearchFilter::default(), portable::JsonFormat::Array, out)?;
+            }
+            if in_memory && csv.is_none() && json.is_none() {
+                tracing::warn!("The catalog was kept in memory and is gone now; scan with --csv or --json to keep it");
+            }
+            Ok(())
+        }
+        Some(Command::Daemon { max_duration }) => {
+            privacy::enforce(&config, local_only)?;
+            let cancel = interruptible();
+            if let Some(limit) = max_duration {
+                cancel.set_time_limit(limit);
+            }
+            // No health check: the daemon waits for hosts to come back
+            let analyzer = AnalyzerPool::new(&config.analyzer)?
+                .with_examples(corrections::examples(&conn, config.analyzer.examples)?);
+            match progress::track(&conn, "daemon", &cancel, |operation| daemon::run(&conn, operation, &config, &analyzer)) {
+                // Being stopped is how a daemon ends normally
+                Err(e) if error::classify(&e) == ErrorKind::Cancelled => {
+                    println!("Daemon stopped: {}", e);
+                    Ok(())
+                }
+                result => result,
+            }
+        }
+        Some(Command::Search { filter, table }) => {
+            if !cli.dry_run {
+                tiering::record_matches(&conn, &filter)?;
+            }
+            let default: &[&str] = match (&filter.scene, filter.field.is_empty()) {
+                (Some(_), _) => &["path", "date", "scenes"],
+                (None, false) => &["path", "date", "camera", "fields"],
+                (None, true) => SEARCH_DEFAULT,
+            };
+            search_table(&conn, &filter)?.print(&table, default)
+        }
+        Some(Command::Geocode) => {
+            if !geocode::AVAILABLE {
+                let message = "this build was compiled without the geocode feature";
+                return Err(CliError::new(ErrorKind::Config, message).into());
+            }
+            let updated = geocode_missing(&conn)?;
+            println!("Resolved places for {} images", updated);
+            Ok(())
+        }
+        Some(Command::Geofence) => {
+            if config.geofences.is_empty() {
+                return Err(CliError::new(ErrorKind::NothingToDo, "no geofences configured").into());
+            }
+            let inside = geofence::retag(&conn, &config.geofences)?;
+            println!("{} photos are inside a geofence", inside);
+            Ok(())
+        }
+        Some(Command::Trips { command }) => match command {
+            TripsCommand::Detect => {
+                let trips = trips::detect(&conn, &config.trips, &config.geofences)?;
+                trips::save(&conn, &trips)?;
+                let photos: usize = trips.iter().map(trips::Trip::photo_count).sum();
+                println!("Found {} trips, with {} photos", trips.len(), photos);
+                Ok(())
+            }
+            TripsCommand::List => {
+                let trips = trips::list(&conn)?;
+                if trips.is_empty() {
+                    return Err(CliError::new(ErrorKind::NothingToDo, "no trips; `trips detect` finds them").into());
+                }
+                for trip in trips {
+                    println!(
+                        "{:>4}  {}  {} to {}  {:.0} km  {} photos in {} events  {}",
+                        trip.id, trip.name, trip.start, trip.end, trip.distance_km, trip.photos, trip.events, trip.countries,
+                    );
+                }
+                Ok(())
+            }
+            TripsCommand::Report { id, out } => {
+                let report = trips::report(&conn, id)?;
+                match out {
+                    Some(out) => fs::write(&out, report)?,
+                    None => print!("{}", report),
+                }
+                Ok(())
+            }
+            TripsCommand::Route { id, event, out } => {
+                let route = trips::route(&conn, id, event)?;
+                let name = match event {
+                    Some(event) => format!("Trip {}, event {}", id, event),
+                    None => format!("Trip {}", id),
+                };
+                let geojson = serde_json::to_string_pretty(&route.geojson(&name))?;
+                match out {
+                    Some(out) => fs::write(&out, geojson + "\n")?,
+                    None => println!("{}", geojson),
+                }
+                for (day, km) in &route.days {
+                    eprintln!("{}  {:.1} km", day, km);
+                }
+                eprintln!("{:.1} km in all", route.distance_km());
+                Ok(())
+            }
+        },
+        Some(Command::Jobs { command }) => match command {
+            JobsCommand::Export { limit, out } => {
+                if local_only {
+                    let message = "local-only mode refuses to export images for analysis elsewhere";
+                    return Err(CliError::new(ErrorKind::Config, message).into());
+                }
+                let count = progress::track(&conn, "jobs export", &interruptible(), |operation| {
+                    jobs::export_batch(&conn, operation, &config, limit, &out)
+                })?;
+                if count == 0 {
+                    return Err(CliError::new(ErrorKind::NothingToDo, "no unanalyzed images to export").into());
+                }
+                println!("Exported {} images to {}", count, out.display());
+                Ok(())
+            }
+            JobsCommand::Run { batch, out } => {
+                privacy::enforce(&config, local_only)?;
+                let analyzer = AnalyzerPool::new(&config.analyzer)?;
+                require_healthy(&analyzer)?;
+                let (analyzed, failed) = jobs::run_batch(analyzer, &batch, &out, &interruptible())?;
+                println!("Analyzed {} images ({} failed), results in {}", analyzed, failed, out.display());
+                if failed > 0 {
+                    return Err(CliError::new(
+                        ErrorKind::PartialFailure,
+                        format!("{} of {} images failed", failed, analyzed + failed),
+                    ).into());
+                }
+                Ok(())
+            }
+            JobsCommand::Import { results } => {
+                let updated = jobs::import_results(&conn, &results, &config.keywords)?;
+                println!("Imported analysis for {} images", updated);
+                Ok(())
+            }
+        },
+        Some(Command::Export(args)) => {
+            if args.xmp_sidecars || args.digikam {
+                let digikam_root = args.digikam.then_some(args.digikam_root.as_str());
+                let (written, skipped) = progress::track(&conn, "export", &interruptible(), |operation| {
+                    export::export_xmp_sidecars(&conn, operation, config.captions.template.as_ref(), digikam_root)
+                })?;
+                println!("Wrote {} XMP sidecars ({} skipped)", written, skipped);
+                if written == 0 {
+                    return Err(CliError::new(ErrorKind::NothingToDo, "no analyzed images to export").into());
+                }
+            }
+            if let (Some(sidecars), Some(originals)) = (&args.photoprism, &args.originals) {
+                let (written, skipped) = progress::track(&conn, "export photoprism", &interruptible(), |operation| {
+                    photoprism::export_sidecars(&conn, operation, &query::filter(&args.query), config.captions.template.as_ref(), originals, sidecars)
+                })?;
+                println!("Wrote {} PhotoPrism sidecars ({} skipped)", written, skipped);
+                if written == 0 {
+                    return Err(CliError::new(ErrorKind::NothingToDo, "no analyzed photos in the originals folder").into());
+                }
+            }
+            let filter = SearchFilter { shareable: !args.include_private, ..query::filter(&args.query) };
+            if let Some(dir) = &args.html {
+                let (written, skipped) = progress::track(&conn, "export html", &interruptible(), |operation| {
+                    gallery::export_html(&conn, operation, &config, &filter, dir)
+                })?;
+                println!("Wrote a gallery of {} photos to {} ({} skipped)", written, dir.display(), skipped);
+                if written == 0 {
+                    return Err(CliError::new(ErrorKind::NothingToDo, "no photos to put in the gallery").into());
+                }
+            }
+            if let Some(out) = &args.csv {
+                if write_csv(&conn, &filter, &args.columns, out)? == 0 {
+                    return Err(CliError::new(ErrorKind::NothingToDo, "no photos match").into());
+                }
+            }
+            if let Some(out) = &args.json {
+                // A backup, so private photos are in it
+                let format = if args.json_lines { portable::JsonFormat::Lines } else { portable::JsonFormat::Array };
+                if write_json(&conn, &query::filter(&args.query), format, out)? == 0 {
+                    return Err(CliError::new(ErrorKind::NothingToDo, "no photos match").into());
+                }
+            }
+            Ok(())
+        }
+        Some(Command::Import { command: Some(ImportCommand::Csv { file, match_on }), .. }) => {
+            let merged = spreadsheet::merge(&conn, &read_input(&file)?, match_on, cli.dry_run)?;
+            if !merged.ignored_columns.is_empty() {
+                tracing::warn!("Ignored columns it doesn't know: {}", merged.ignored_columns.join(", "));
+            }
+            if !merged.unmatched.is_empty() {
+                let shown: Vec<&str> = merged.unmatched.iter().take(5).map(String::as_str).collect();
+                let more = if merged.unmatched.len() > shown.len() { ", …" } else { "" };
+                tracing::warn!("{} rows matched no photo: {}{}", merged.unmatched.len(), shown.join(", "), more);
+            }
+            if merged.changes.is_empty() {
+                return Err(CliError::new(ErrorKind::NothingToDo, "nothing in the file would change a photo").into());
+            }
+            for change in &merged.changes {
+                let before = if change.before.is_empty() { "(none)" } else { &change.before };
+                println!("{}: {} {} -> {}", change.path, change.what, before, change.after);
+            }
+            match cli.dry_run {
+                true => println!("{} photos would change", merged.photos),
+                false => println!("Updated {} photos", merged.photos),
+            }
+            Ok(())
+        }
+        Some(Command::Import { lightroom: Some(lrcat), .. }) => {
+            let imported = lightroom::import_catalog(&conn, &lrcat)?;
+            println!("Imported Lightroom metadata for {} photos, {} of them new to the catalog", imported.updated + imported.added, imported.added);
+            Ok(())
+        }
+        Some(Command::Import { file, .. }) => {
+            let imported = portable::import_json(&conn, &read_input(&file.unwrap_or_default())?)?;
+            println!("Imported {} new photos and updated {}", imported.inserted, imported.updated);
+            if !imported.ignored_fields.is_empty() {
+                let fields: Vec<&str> = imported.ignored_fields.iter().map(String::as_str).collect();
+                tracing::warn!("Ignored fields this catalog doesn't have: {}", fields.join(", "));
+            }
+            Ok(())
+        }
+        Some(Command::Correct { path, description, keywords }) => {
+            corrections::correct(&conn, &path.to_string_lossy(), description.as_deref(), keywords.as_deref(), &edits::Editor::CLI)?;
+            println!("Corrected {}", path.display());
+            Ok(())
+        }
+        Some(Command::Tag { command: TagCommand::List { table } }) => {
+            let mut listed = table::Table::new(&["keyword", "photos"]);
+            for usage in tags::list(&conn)? {
+                listed.push(vec![table::Cell::Text(usage.name), table::Cell::Number(usage.images)]);
+            }
+            if listed.is_empty() {
+                return Err(CliError::new(ErrorKind::NothingToDo, "no keywords").into());
+            }
+            listed.print(&table, &["keyword", "photos"])
+        }
+        Some(Command::Tag { command }) => {
+            let (change, terms) = match command {
+                TagCommand::List { .. } => unreachable!("listed above"),
+                TagCommand::Add { tag, query } => (tags::Change::Add(tag), query),
+                TagCommand::Remove { tag, query } => (tags::Change::Remove(tag), query),
+                TagCommand::Rename { from, to, query } => (tags::Change::Rename { from: vec![from], to }, query),
+                TagCommand::Merge { tags, into, query } => (tags::Change::Rename { from: tags, to: into }, query),
+            };
+            let retagged = tags::retag(&conn, &query::filter(&terms), &change, cli.dry_run)?;
+            if retagged.is_empty() {
+                return Err(CliError::new(ErrorKind::NothingToDo, "no photo's keywords would change").into());
+            }
+            for photo in &retagged {
+                println!("{}: {} -> {}", photo.path, photo.before, photo.after);
+            }
+            match cli.dry_run {
+                true => println!("{} photos would be retagged", retagged.len()),
+                false => println!("{} photos retagged", retagged.len()),
+            }
+            Ok(())
+        }
+        Some(Command::Rate { stars, photos }) => {
+            let changed = culling::mark(&conn, &photos.ids(&conn)?, culling::Mark::Rating(stars))?;
+            println!("Rated {} photos {} stars", changed, stars);
+            Ok(())
+        }
+        Some(Command::Favorite { photos, unset }) => {
+            let changed = culling::mark(&conn, &photos.ids(&conn)?, culling::Mark::Favorite(!unset))?;
+            match unset {
+                true => println!("{} photos are no longer favorites", changed),
+                false => println!("Marked {} photos as favorites", changed),
+            }
+            Ok(())
+        }
+        Some(Command::Reject { photos, unset }) => {
+            let changed = culling::mark(&conn, &photos.ids(&conn)?, culling::Mark::Rejected(!unset))?;
+            match unset {
+                true => println!("Kept {} photos", changed),
+                false => println!("Rejected {} photos", changed),
+            }
+            Ok(())
+        }
+        Some(Command::Source { command }) => {
+            match command {
+                SourceCommand::List { table } => {
+                    let sources = sources::list(&conn)?;
+                    if sources.is_empty() {
+                        return Err(CliError::new(ErrorKind::NothingToDo, "no sources; `scan` makes one of each folder it's given").into());
+                    }
+                    let mut listed = table::Table::new(&["source", "path", "photos", "scanned", "there"]);
+                    for source in sources {
+                        let there = match (&source.offline_since, source.path.is_dir()) {
+                            (_, true) => String::from("yes"),
+                            (Some(since), false) => format!("offline since {}", since),
+                            (None, false) => String::from("no"),
+                        };
+                        listed.push(vec![
+                            table::Cell::Text(source.name),
+                            table::Cell::Text(source.path.display().to_string()),
+                            table::Cell::Number(source.images),
+                            table::Cell::from(source.scanned_at),
+                            table::Cell::Text(there),
+                        ]);
+                    }
+                    listed.print(&table, &["source", "path", "photos", "scanned", "there"])?;
+                }
+                SourceCommand::Rename { name, new_name } => {
+                    sources::rename(&conn, &name, &new_name)?;
+                    println!("Renamed source {} to {}", name.trim(), new_name.trim());
+                }
+                SourceCommand::Remove { name } => {
+                    let unlinked = sources::remove(&conn, &name)?;
+                    println!("Forgot source {}; its {} photos are still in the catalog", name.trim(), unlinked);
+                }
+            }
+            Ok(())
+        }
+        Some(Command::Problems { command }) => {
+            match command {
+                ProblemsCommand::List { category, table } => {
+                    let found = problems::list(&conn, category)?;
+                    if found.is_empty() {
+                        return Err(CliError::new(ErrorKind::NothingToDo, "no problems recorded").into());
+                    }
+                    let mut listed = table::Table::new(&["path", "category", "problem", "first seen", "last seen", "attempts"]);
+                    for problem in found {
+                        listed.push(vec![
+                            table::Cell::Text(problem.path.display().to_string()),
+                            table::Cell::Text(problem.category.name().to_string()),
+                            table::Cell::Text(problem.message),
+                            table::Cell::Text(problem.first_seen),
+                            table::Cell::Text(problem.last_seen),
+                            table::Cell::Number(problem.attempts),
+                        ]);
+                    }
+                    listed.print(&table, &["path", "category", "problem", "last seen", "attempts"])?;
+                }
+                ProblemsCommand::Retry { category, no_analyze } => {
+                    let mut paths: Vec<PathBuf> = problems::list(&conn, category)?.into_iter().map(|problem| problem.path).collect();
+                    paths.dedup();
+                    if paths.is_empty() {
+                        return Err(CliError::new(ErrorKind::NothingToDo, "no problems to retry").into());
+                    }
+                    let (there, gone): (Vec<PathBuf>, Vec<PathBuf>) = paths.into_iter().partition(|path| path.is_file());
+                    for path in &gone {
+                        problems::forget(&conn, path)?;
+                    }
+                    if !gone.is_empty() {
+                        println!("Forgot the problems of {} files that are gone", gone.len());
+                    }
+                    if !there.is_empty() {
+                        if !no_analyze {
+                            privacy::enforce(&config, local_only)?;
+                        }
+                        let result = scan_sources(&conn, &config, &there, !no_analyze, false, &interruptible());
+                        let left: std::collections::HashSet<PathBuf> = problems::list(&conn, category)?.into_iter()
+                            .map(|problem| problem.path)
+                            .filter(|path| there.contains(path))
+                            .collect();
+                        println!("{} of {} files fixed", there.len() - left.len(), there.len());
+                        result?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        Some(Command::Album { command }) => {
+            match command {
+                AlbumCommand::Create { name } => {
+                    albums::create(&conn, &name)?;
+                    println!("Created album {}", name.trim());
+                }
+                AlbumCommand::Add { name, photos } => {
+                    let added = albums::add(&conn, &name, &photos.ids(&conn)?)?;
+                    println!("Added {} photos to {}", added, name);
+                }
+                AlbumCommand::Remove { name, photos } => {
+                    let removed = albums::remove(&conn, &name, &photos.ids(&conn)?)?;
+                    println!("Removed {} photos from {}", removed, name);
+                }
+                AlbumCommand::List { name: Some(name), table } => {
+                    albums::find(&conn, &name)?;
+                    let filter = SearchFilter { album: Some(name), ..SearchFilter::default() };
+                    search_table(&conn, &filter)?.print(&table, SEARCH_DEFAULT)?;
+                }
+                AlbumCommand::List { name: None, table } => {
+                    let albums = albums::list(&conn)?;
+                    if albums.is_empty() {
+                        return Err(CliError::new(ErrorKind::NothingToDo, "no albums; `album create` makes one").into());
+                    }
+                    let mut listed = table::Table::new(&["album", "photos"]);
+                    for album in albums {
+                        listed.push(vec![table::Cell::Text(album.name), table::Cell::Number(album.images)]);
+                    }
+                    listed.print(&table, &["album", "photos"])?;
+                }
+                AlbumCommand::Delete { name } => {
+                    let had = albums::delete(&conn, &name)?;
+                    println!("Deleted album {} ({} photos left in the catalog)", name, had);
+                }
+            }
+            Ok(())
+        }
+        Some(Command::Field { command }) => {
+            match command {
+                FieldCommand::Set { field: (key, value), photos, kind } => {
+                    let changed = fields::set(&conn, &photos.ids(&conn)?, &key, &value, kind)?;
+                    println!("Set {} on {} photos", key, changed);
+                }
+                FieldCommand::Remove { key, photos } => {
+                    let removed = fields::remove(&conn, &photos.ids(&conn)?, &key)?;
+                    println!("Took {} off {} photos", key.trim(), removed);
+                }
+                FieldCommand::List { table } => {
+                    let mut listed = table::Table::new(&["field", "type", "photos"]);
+                    for key in fields::keys(&conn)? {
+                        listed.push(vec![table::Cell::Text(key.key), table::Cell::Text(key.kind.name().to_string()), table::Cell::Number(key.images)]);
+                    }
+                    if listed.is_empty() {
+                        return Err(CliError::new(ErrorKind::NothingToDo, "no fields; `field set` sets one").into());
+                    }
+                    listed.print(&table, &["field", "type", "photos"])?;
+                }
+            }
+            Ok(())
+        }
+        Some(Command::Stats { json, top }) => {
+            let stats = stats::gather(&conn, top)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+                return Ok(());
+            }
+            println!("{} images, {}", stats.images, tiering::size(stats.bytes));
+            let tables = [
+                ("Year", &stats.by_year),
+                ("Month", &stats.by_month),
+                ("Format", &stats.by_format),
+                ("Camera", &stats.by_camera),
+                ("Folder", &stats.by_folder),
+            ];
+            for (heading, counts) in tables.into_iter().filter(|(_, counts)| !counts.is_empty()) {
+                let width = counts.iter().map(|count| count.name.chars().count()).chain([heading.len()]).max().unwrap_or_default();
+                println!();
+                println!("{:<width$}  {:>8}  {:>10}", heading, "Images", "Size");
+                for count in counts {
+                    println!("{:<width$}  {:>8}  {:>10}", count.name, count.images, tiering::size(count.bytes));
+                }
+            }
+            println!();
+            println!("Top keywords:");
+            for keyword in &stats.top_keywords {
+                println!("  {}  {}", keyword.keyword, keyword.images);
+            }
+            println!();
+            let missing = &stats.missing;
+            println!("Without a capture date: {}", missing.capture_date);
+            println!("Without a camera: {}", missing.camera);
+            println!("Without a location: {}", missing.location);
+            println!("Not analyzed: {}", missing.description);
+            Ok(())
+        }
+        Some(Command::Duplicates { limit, json }) => {
+            let mut groups = copies::groups(&conn)?;
+            let total: u64 = groups.iter().map(copies::Group::recoverable).sum();
+            groups.truncate(limit.unwrap_or(usize::MAX));
+            if json {
+                println!("{}", serde_json::to_string_pretty(&groups)?);
+                return Ok(());
+            }
+            if groups.is_empty() {
+                println!("No copies");
+                return Ok(());
+            }
+            for group in &groups {
+                let resolution = match (group.width, group.height) {
+                    (Some(width), Some(height)) => format!(", {}x{}", width, height),
+                    _ => String::new(),
+                };
+                println!(
+                    "{} recoverable: {} copies of {}{}",
+                    tiering::size(group.recoverable()), group.paths.len(), tiering::size(group.file_size), resolution,
+                );
+                for path in &group.paths {
+                    println!("  {}", path);
+                }
+            }
+            println!("Recoverable in all: {}", tiering::size(total));
+            Ok(())
+        }
+        Some(Command::Conflicts) => {
+            let conflicts = edits::conflicts(&conn)?;
+            if conflicts.is_empty() {
+                println!("No conflicting edits");
+            }
+            for conflict in &conflicts {
+                let overwrote = conflict.overwrote.as_deref().unwrap_or("another edit");
+                println!("{}  {}", conflict.path, conflict.edited_at);
+                println!("  {} edited version {} after {} made version {}", conflict.source, conflict.seen, overwrote, conflict.version - 1);
+                let fields = [
+                    ("description", &conflict.lost.description, &conflict.kept.description),
+                    ("keywords", &conflict.lost.keywords, &conflict.kept.keywords),
+                ];
+                for (field, lost, kept) in fields.into_iter().filter(|(_, lost, kept)| lost != kept) {
+                    println!("  {}: {:?} replaced by {:?}", field, lost.as_deref().unwrap_or_default(), kept.as_deref().unwrap_or_default());
+                }
+            }
+            Ok(())
+        }
+        Some(Command::Compare { a, b, heatmap, size }) => {
+            let edge = if heatmap.is_some() { size.max(duplicates::COMPARE_EDGE) } else { duplicates::COMPARE_EDGE };
+            let (first, second) = (duplicates::load(&a, &config, edge)?, duplicates::load(&b, &config, edge)?);
+            println!("{}: {}", a.display(), first.color_space);
+            println!("{}: {}", b.display(), second.color_space);
+            let alignment = duplicates::compare(&first, &second);
+            if alignment.a != duplicates::Region::WHOLE {
+                println!("{} is a crop of {} ({:.0}%)", b.display(), a.display(), alignment.a.area() * 100.0);
+            } else if alignment.b != duplicates::Region::WHOLE {
+                println!("{} is a crop of {} ({:.0}%)", a.display(), b.display(), alignment.b.area() * 100.0);
+            }
+            let verdict = if alignment.difference < duplicates::SAME_PICTURE { "the same picture" } else { "different pictures" };
+            println!("Difference: {:.1}% ({})", alignment.difference * 100.0, verdict);
+            if let Some(out) = heatmap {
+                duplicates::heatmap(&first, &second, &alignment, size).save_with_format(&out, image::ImageFormat::Png)?;
+                println!("Wrote {}", out.display());
+            }
+            Ok(())
+        }
+        Some(Command::Mirror { to }) => {
+            privacy::enforce(&config, local_only)?;
+            privacy::refuse(local_only, to.as_deref().and_then(privacy::mirror_violation).into_iter().collect())?;
+            let mirrored = remote::mirror(&conn, &config, to.as_deref())?;
+            println!("Mirrored the catalog with {} new thumbnails", mirrored.thumbnails);
+            if mirrored.failed > 0 {
+                let message = format!("no thumbnail could be made of {} photos", mirrored.failed);
+                return Err(CliError::new(ErrorKind::PartialFailure, message).into());
+            }
+            Ok(())
+        }
+        Some(Command::Sync(args)) => {
+            privacy::enforce(&config, local_only)?;
+            privacy::refuse(local_only, privacy::immich_violation(&args.immich, config.privacy.allow_lan).into_iter().collect())?;
+            let api_key = env::var(&args.api_key_env).map_err(|_| {
+                CliError::new(ErrorKind::Config, format!("environment variable {} is not set", args.api_key_env))
+            })?;
+            let synced = immich::sync(&conn, &query::filter(&args.query), &args.immich, &api_key, &args.tag_root)?;
+            println!("Updated {} photos in Immich", synced.updated);
+            for path in &synced.unmatched {
+                tracing::warn!("Not in Immich: {}", path);
+            }
+            match (synced.updated, synced.failed) {
+                (0, 0) => Err(CliError::new(ErrorKind::NothingToDo, "no analyzed photos matched assets in Immich").into()),
+                (_, 0) => Ok(()),
+                (_, failed) => Err(CliError::new(ErrorKind::PartialFailure, format!("{} photos failed", failed)).into()),
+            }
+        }
+        Some(Command::Note { command }) => match command {
+            NoteCommand::Add { path, text, audio } => {
+                notes::add(&conn, &path.to_string_lossy(), audio.as_deref(), text.as_deref())?;
+                println!("Added a note to {}", path.display());
+                if audio.is_none() {
+                    return Ok(());
+                }
+                if !notes::can_transcribe(&config.transcriber) {
+                    println!("Set up [transcriber] and run `note transcribe` to make it searchable");
+                    return Ok(());
+                }
+                privacy::enforce(&config, local_only)?;
+                let (_, failed) = notes::transcribe_pending(&conn, &config.transcriber)?;
+                match failed {
+                    0 => Ok(()),
+                    _ => Err(CliError::new(ErrorKind::PartialFailure, "transcription failed; `note transcribe` tries again").into()),
+                }
+            }
+            NoteCommand::List { path } => {
+                let path = path.map(|path| path.to_string_lossy().into_owned());
+                let notes = notes::list(&conn, path.as_deref())?;
+                if notes.is_empty() {
+                    return Err(CliError::new(ErrorKind::NothingToDo, "no notes").into());
+                }
+                for note in notes {
+                    let text = note.text.as_deref().unwrap_or("(not transcribed yet)");
+                    match &note.audio {
+                        Some(audio) => println!("{}  {}  [{}]  {}", note.created_at, note.path, audio, text),
+                        None => println!("{}  {}  {}", note.created_at, note.path, text),
+                    }
+                }
+                Ok(())
+            }
+            NoteCommand::Transcribe => {
+                if !notes::can_transcribe(&config.transcriber) {
+                    return Err(CliError::new(ErrorKind::Config, "no transcriber configured; set transcriber.command or transcriber.url").into());
+                }
+                privacy::enforce(&config, local_only)?;
+                let (done, failed) = notes::transcribe_pending(&conn, &config.transcriber)?;
+                println!("Transcribed {} voice notes", done);
+                match (done, failed) {
+                    (0, 0) => Err(CliError::new(ErrorKind::NothingToDo, "no voice notes to transcribe").into()),
+                    (_, 0) => Ok(()),
+                    _ => Err(CliError::new(ErrorKind::PartialFailure, format!("{} voice notes failed", failed)).into()),
+                }
+            }
+        },
+        Some(Command::Writeback { backup }) => {
+            let template = config.captions.template.as_ref();
+            let (written, skipped) = match cli.dry_run {
+                true => writeback::rehearse(&conn, template, backup)?,
+                false => progress::track(&conn, "writeback", &interruptible(), |operation| {
+                    writeback::writeback_catalog(&conn, operation, template, backup)
+                })?,
+            };
+            match cli.dry_run {
+                true => println!("{} files would be updated ({} skipped)", written, skipped),
+                false => println!("Updated {} files ({} skipped)", written, skipped),
+            }
+            if written == 0 {
+                return Err(CliError::new(ErrorKind::NothingToDo, "no analyzed JPEG files to update").into());
+            }
+            Ok(())
+        }
+        Some(Command::FixDates(args)) => {
+            if let Some(id) = args.undo {
+                let (dates, files) = dates::undo(&conn, id)?;
+                println!("Undid date shift {}: restored {} catalog dates and the EXIF of {} files", id, dates, files);
+                return Ok(());
+            }
+            let shift = args.shift.expect("clap requires --shift without --undo");
+            let changes = dates::plan(&conn, &query::filter(&args.query), shift)?;
+            if changes.is_empty() {
+                return Err(CliError::new(ErrorKind::NothingToDo, "no dated images match the query").into());
+            }
+            for change in &changes {
+                println!("{}: {} -> {}", change.path, change.old, change.new);
+            }
+            if cli.dry_run || !args.apply {
+                println!("{} dates would change{}", changes.len(), rehearsal(cli.dry_run, "change them"));
+                return Ok(());
+            }
+            let applied = dates::apply(&conn, &changes, shift, &query::to_string(&args.query), args.exif)?;
+            println!("Shifted {} dates (undo with `fix-dates --undo {}`)", applied.dates, applied.shift_id);
+            if args.exif {
+                println!("Updated the EXIF of {} files ({} skipped)", applied.files, applied.files_skipped);
+            }
+            Ok(())
+        }
+        Some(Command::ReconcileClocks(args)) => {
+            let devices = clocks::estimate(&conn, &query::filter(&args.query), args.reference.as_deref(), &args.anchor)?;
+            for device in &devices {
+                let evidence = match device.evidence {
+                    clocks::Evidence::Reference => String::from("reference"),
+                    clocks::Evidence::Anchors(count) => format!("from {} anchors", count),
+                    clocks::Evidence::Gps(count) => format!("from {} photos at the same spots", count),
+                    clocks::Evidence::Unknown => String::from("unknown; add an --anchor"),
+                };
+                let offset = device.offset.map_or(String::from("?"), |offset| format!("{:+}s", offset));
+                println!("{} ({} photos): {} ({})", device.name, device.photo_count(), offset, evidence);
+            }
+            if cli.dry_run || !args.apply {
+                let count: usize = devices.iter().map(|device| device.changes().len()).sum();
+                println!("{} dates would change{}", count, rehearsal(cli.dry_run, "change them"));
+                return Ok(());
+            }
+            for (device, applied) in clocks::apply(&conn, &devices, &query::to_string(&args.query), args.exif)? {
+                println!("{}: shifted {} dates (undo with `fix-dates --undo {}`)", device, applied.dates, applied.shift_id);
+                if args.exif {
+                    println!("  updated the EXIF of {} files ({} skipped)", applied.files, applied.files_skipped);
+                }
+            }
+            Ok(())
+        }
+        Some(Command::Reel(args)) => {
+            #[cfg(feature = "video")]
+            return make_reel(&conn, &mut config, args);
+            #[cfg(not(feature = "video"))]
+            {
+                let _ = args;
+                Err(CliError::new(ErrorKind::Config, "this build was compiled without the video feature").into())
+            }
+        }
+        Some(Command::Organize(OrganizeArgs { into: Some(into), pattern, query, apply, .. })) => {
+            let pattern = renames::Pattern::parse(pattern.as_deref().unwrap_or(&config.organize.pattern))?;
+            let plan = renames::plan(&conn, &query::filter(&query), &pattern, &into)?;
+            for (path, reason) in &plan.skipped {
+                if *reason != "already there" {
+                    println!("Leaving {}: {}", path, reason);
+                }
+            }
+            if plan.renames.is_empty() {
+                return Err(CliError::new(ErrorKind::NothingToDo, "nothing to move: every photo is where the pattern puts it, or can't be placed").into());
+            }
+            if cli.dry_run || !apply {
+                for rename in &plan.renames {
+                    println!("{} -> {}", rename.from.display(), rename.to.display());
+                }
+                println!("{} files would move{}", plan.renames.len(), rehearsal(cli.dry_run, "move them"));
+                return Ok(());
+            }
+            let moved = renames::apply(&conn, &plan.renames)?;
+            println!("Moved {} of {} files under {}", moved, plan.renames.len(), into.display());
+            Ok(())
+        }
+        Some(Command::Organize(OrganizeArgs { archive_to, .. })) => {
+            let candidates = tiering::recommend(&conn, &config.tiering)?;
+            if candidates.is_empty() {
+                return Err(CliError::new(ErrorKind::NothingToDo, "nothing to archive or delete").into());
+            }
+            for (action, heading) in [(tiering::Action::Delete, "Delete"), (tiering::Action::Archive, "Cold storage")] {
+                let chosen: Vec<&tiering::Candidate> = candidates.iter().filter(|candidate| candidate.action == action).collect();
+                if chosen.is_empty() {
+                    continue;
+                }
+                let bytes = chosen.iter().map(|candidate| candidate.file_size).sum();
+                println!("{} ({} files, {}):", heading, chosen.len(), tiering::size(bytes));
+                for candidate in chosen {
+                    println!("  {}  {}  {}", candidate.path, tiering::size(candidate.file_size), candidate.reasons.join(", "));
+                }
+            }
+            let total = candidates.iter().map(|candidate| candidate.file_size).sum();
+            println!("Projected savings: {}", tiering::size(total));
+            if let Some(archive) = archive_to {
+                let (moved, bytes) = tiering::archive(&conn, &candidates, &archive, cli.dry_run)?;
+                match cli.dry_run {
+                    true => println!("{} files ({}) would move to {}", moved, tiering::size(bytes), archive.display()),
+                    false => println!("Moved {} files ({}) to {}", moved, tiering::size(bytes), archive.display()),
+                }
+            }
+            Ok(())
+        }
+        Some(Command::Verify { sample, all, search }) => {
+            let rehash = match (sample, all) {
+                (_, true) => verify::Rehash::All,
+                (Some(n), _) => verify::Rehash::Sample(n),
+                (None, false) => verify::Rehash::None,
+            };
+            let report = verify::verify(&conn, rehash, &search)?;
+            for path in &report.missing {
+                println!("Missing: {}", path);
+            }
+            for (path, now) in &report.moved {
+                println!("Moved: {} -> {}", path, now.display());
+            }
+            for path in &report.modified {
+                println!("Modified: {}", path);
+            }
+            println!("Checked {} files, re-hashed {}", report.checked, report.hashed);
+            if report.problems() > 0 {
+                let message = format!(
+                    "{} missing, {} moved and {} modified of {} files",
+                    report.missing.len(), report.moved.len(), report.modified.len(), report.checked,
+                );
+                return Err(CliError::new(ErrorKind::PartialFailure, message).into());
+            }
+            Ok(())
+        }
+        Some(Command::Prune { archive }) => {
+            let mut shares = share::Shares::new(&config.shares);
+            let missing = prune::missing(&conn, &mut shares)?;
+            for root in shares.unreachable() {
+                tracing::warn!("Leaving the photos on {} alone: the share isn't answering", root.display());
+            }
+            if missing.is_empty() {
+                return Err(CliError::new(ErrorKind::NothingToDo, "every catalogued file is still there").into());
+            }
+            for (_, path) in &missing {
+                println!("{}", path);
+            }
+            let (how, done) = match archive {
+                true => (prune::Prune::Archive, "archived"),
+                false => (prune::Prune::Remove, "removed"),
+            };
+            if cli.dry_run {
+                println!("{} files would be {}", missing.len(), done);
+                return Ok(());
+            }
+            let ids: Vec<i64> = missing.iter().map(|(id, _)| *id).collect();
+            prune::prune(&conn, &ids, how)?;
+            println!("{} files {}", missing.len(), done);
+            Ok(())
+        }
+        Some(Command::Db { command: DbCommand::Fsck { repair } }) => {
+            let findings = fsck::check(&conn, &config)?;
+            for finding in findings.iter().filter(|finding| !finding.problems.is_empty()) {
+                println!("{}: {} {}", finding.check.name(), finding.problems.len(), finding.check.about());
+                for problem in finding.problems.iter().take(10) {
+                    println!("  {}", problem);
+                }
+                if finding.problems.len() > 10 {
+                    println!("  and {} more", finding.problems.len() - 10);
+                }
+            }
+            let found: usize = findings.iter().map(|finding| finding.problems.len()).sum();
+            if found == 0 {
+                println!("No problems found");
+                return Ok(());
+            }
+            let Some(checks) = repair else {
+                let message = format!("{} problems found; run again with --repair to fix them, or --repair CHECKS for some", found);
+                return Err(CliError::new(ErrorKind::PartialFailure, message).into());
+            };
+            for check in fsck::Check::ALL.into_iter().filter(|check| checks.is_empty() || checks.contains(check)) {
+                let repaired = match cli.dry_run {
+                    true => fsck::find(&conn, &config, check)?.len(),
+                    false => fsck::repair(&conn, &config, check)?,
+                };
+                if repaired > 0 {
+                    println!("{}: {} {}", check.name(), if cli.dry_run { "would repair" } else { "repaired" }, repaired);
+                }
+            }
+            let left: usize = fsck::check(&conn, &config)?.iter().map(|finding| finding.problems.len()).sum();
+            if left > 0 && !cli.dry_run {
+                return Err(CliError::new(ErrorKind::PartialFailure, format!("{} problems are left", left)).into());
+            }
+            Ok(())
+        }
+        Some(Command::Status { id }) => {
+            match id {
+                Some(id) => match progress::get(&conn, id)? {
+                    Some(status) => progress::print_status(&status),
+                    None => return Err(CliError::new(ErrorKind::NothingToDo, format!("no operation {}", id)).into()),
+                },
+                None => progress::list(&conn, 20)?.iter().for_each(progress::print_status),
+            }
+            Ok(())
+        }
+        Some(Command::Cancel { id }) => {
+            if !progress::request_cancel(&conn, id)? {
+                return Err(CliError::new(ErrorKind::NothingToDo, format!("operation {} is not running", id)).into());
+            }
+            println!("Asked operation {} to stop", id);
+            Ok(())
+        }
+        Some(Command::Browse { preview }) => {
+            #[cfg(feature = "tui")]
+            return browse::browse(&conn, &config, preview);
+            #[cfg(not(feature = "tui"))]
+            {
+                let _ = preview;
+                Err(CliError::new(ErrorKind::Config, "this build was compiled without the tui feature").into())
+            }
+        }
+        Some(Command::Serve { listen, attach }) => {
+            config.web.catalogs.extend(attach);
+            #[cfg(feature = "server")]
+            return server::serve(cli.db, config, local_only, listen);
+            #[cfg(not(feature = "server"))]
+            {
+                let _ = listen;
+                Err(CliError::new(ErrorKind::Config, "this build was compiled without the server feature").into())
+            }
+        }
+        Some(Command::Audit { command: AuditCommand::Show { actor, table } }) => {
+            let entries = audit::list(&conn, actor.as_deref(), table.limit.unwrap_or(50))?;
+            if entries.is_empty() {
+                println!("No changes logged");
+                return Ok(());
+            }
+            let columns = &["at", "actor", "action", "target", "before", "after"];
+            let mut listed = table::Table::new(columns);
+            for entry in entries {
+                listed.push(vec![
+                    table::Cell::Text(entry.at),
+                    table::Cell::Text(entry.actor),
+                    table::Cell::Text(entry.action),
+                    table::Cell::Text(entry.target),
+                    table::Cell::from(entry.before),
+                    table::Cell::from(entry.after),
+                ]);
+            }
+            listed.print(&table, columns)
+        }
+        Some(Command::Version { features }) => {
+            features::print_version(features);
+            Ok(())
+        }
+        Some(Command::Help { .. } | Command::Manpages { .. }) => unreachable!("handled before the catalog is opened"),
+        Some(Command::Privacy) => {
+            privacy::print_audit(&config, local_only);
+            Ok(())
+        }
+        None => {
+            privacy::enforce(&config, local_only)?;
+            scan_sources(&conn, &config, cli.dir.as_slice(), true, false, &interruptible())
+        }
+    }
+}
 
-struct ImageMetadata {
-    path: String,
-    file_name: String,
-    file_size: u64,
-    dimensions: Option<(u32, u32)>,
-    format: Option<ImageFormat>,
-    creation_date: Option<String>,
-    keywords: Option<String>,
-    description: Option<String>,
+/// Whether `--dry-run` can be given to `command`: those that change
+/// something have to be able to say what instead, and those that only read
+/// have nothing to leave out.
+fn rehearsable(command: &Option<Command>) -> bool {
+    match command {
+        Some(Command::Organize(_) | Command::Prune { .. } | Command::Writeback { .. } | Command::Tag { .. }) => true,
+        Some(Command::FixDates(args)) => args.undo.is_none(),
+        Some(Command::ReconcileClocks(_)) => true,
+        Some(Command::Search { .. } | Command::Stats { .. } | Command::Conflicts | Command::Duplicates { .. } | Command::Verify { .. }) => true,
+        Some(Command::Status { .. } | Command::Audit { .. } | Command::Privacy | Command::Version { .. } | Command::Help { .. }) => true,
+        Some(Command::Compare { heatmap, .. }) => heatmap.is_none(),
+        Some(Command::Trips { command: TripsCommand::List }) => true,
+        Some(Command::Trips { command: TripsCommand::Report { out, .. } | TripsCommand::Route { out, .. } }) => out.is_none(),
+        Some(Command::Album { command: AlbumCommand::List { .. } }) => true,
+        Some(Command::Field { command: FieldCommand::List { .. } }) => true,
+        Some(Command::Import { command: Some(ImportCommand::Csv { .. }), .. }) => true,
+        Some(Command::Source { command: SourceCommand::List { .. } }) => true,
+        Some(Command::Problems { command: ProblemsCommand::List { .. } }) => true,
+        Some(Command::Note { command: NoteCommand::List { .. } }) => true,
+        Some(Command::Db { command: DbCommand::Fsck { .. } }) => true,
+        _ => false,
+    }
 }
 
-fn init_database(conn: &Connection) -> Result<()> {
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS images (
-            id INTEGER PRIMARY KEY,
-            path TEXT NOT NULL,
-            file_name TEXT NOT NULL,
-            file_size INTEGER NOT NULL,
-            width INTEGER,
-            height INTEGER,
-            format TEXT,
-            creation_date TEXT,
-            keywords TEXT,
-            description TEXT
-        )",
-        [],
-    )?;
-    Ok(())
+/// How a preview of changes ends: what to run to make them, unless it was
+/// a dry run and the command would have.
+fn rehearsal(dry_run: bool, make: &str) -> String {
+    match dry_run {
+        true => String::new(),
+        false => format!("; run again with --apply to {}", make),
+    }
 }
 
-async fn get_image_analysis(image_path: &Path) -> Result<(String, String), Error> {
-    // Read the image file as base64
-    let image_data = fs::read(image_path)?;
-    let base64_image = STANDARD.encode(image_data);
-
-    // Create the client
-    let client = reqwest::Client::new();
-
-    // Prepare the prompt
-    let prompt = format!(
-        "Analyze this image and provide: \
-        1. A concise description of what you see \
-        2. A list of relevant keywords separated by commas"
-    );
-
-    // Make request to local Ollama server
-    let response = client
-        .post("http://localhost:11434/api/generate")
-        .header("Content-Type", "application/json")
-        .body(serde_json::json!({
-            "model": "llava",
-            "prompt": prompt,
-            "images": [base64_image],
-            "stream": false
-        }).to_string())
-        .send()
-        .await?;
-
-    // Get the response text as a String
-    let response_text = response.text().await?;
-
-    // Parse the JSON response
-    let response_json: Value = serde_json::from_str(&response_text)?;
-
-    // Extract the response field and convert to owned String
-    let full_response = response_json["response"]
-        .as_str()
-        .unwrap_or("No response")
-        .to_owned();
-
-    // Split the response into description and keywords
-    // Split the response into description and keywords
-    let parts: Vec<&str> = full_response.split("\n\n").collect();
-    let description = parts.get(0).unwrap_or(&"").to_string();  // Changed to to_string()
-    let keywords = parts
-        .get(1)
-        .unwrap_or(&"")
-        .trim_start_matches("Keywords: ")
-        .to_string();  // Changed to to_string()
-    
-    // print keywords and description
-    println!("Keywords: {}", keywords);
-    println!("Description: {}", description);
+/// A token for the command's operation that Ctrl-C trips.
+fn interruptible() -> CancelToken {
+    cancel::shutdown().child()
+}
 
-    Ok((description, keywords))
+/// Probe the analyzer hosts and give up early if none of them answers.
+fn require_healthy(analyzer: &AnalyzerPool) -> Result<(), Error> {
+    if analyzer.check_health() == 0 {
+        return Err(CliError::new(ErrorKind::BackendUnreachable, "none of the analyzer hosts is reachable").into());
+    }
+    Ok(())
 }
 
-fn process_image(path: &Path) -> Result<ImageMetadata, Error> {
-    let file_name = path.file_name()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .into_owned();
+/// Whether the file at `path` looks like something we catalog.
+fn is_supported(path: &Path) -> bool {
+    let ordinary = path.extension()
+        .map(|ext| {
+            let ext = ext.to_string_lossy().to_lowercase();
+            matches!(ext.as_str(), "jpg" | "jpeg" | "png" | "gif" | "bmp" | "webp")
+        })
+        .unwrap_or(false);
+    ordinary || raw::format_for(path).is_some() || heif::is_heif(path) || tiff::is_tiff(path) || video::is_video(path)
+}
 
-    let metadata = fs::metadata(path)?;
-    let file_size = metadata.len();
-    
-    // Instead of trying to get format from DynamicImage
-    let file = fs::read(path)?;
-    let img = image::load_from_memory(&file).ok();
-    let dimensions = img.as_ref().map(|img| img.dimensions());
-    let format = image::guess_format(&file).ok();
-
-    // Get EXIF data for creation date
-    let file = fs::File::open(path)?;
-    let creation_date = Reader::new()
-        .read_from_container(&mut std::io::BufReader::new(&file))
-        .ok()
-        .and_then(|exif| {
-            exif.get_field(exif::Tag::DateTimeOriginal, In::PRIMARY)
-                .map(|field| field.display_value().to_string())
-        });
+/// Re-encode as JPEG, for formats analyzers don't take. 16-bit images are
+/// scaled down to 8 bits on the way.
+fn encode_jpeg(img: &image::DynamicImage) -> Result<Vec<u8>, Error> {
+    let mut jpeg = Vec::new();
+    img.to_rgb8().write_to(&mut std::io::Cursor::new(&mut jpeg), image::ImageOutputFormat::Jpeg(90))?;
+    Ok(jpeg)
+}
 
-    // Get image analysis from Ollama
-    let rt = tokio::runtime::Runtime::new()?;
-    let (description, keywords) = rt.block_on(get_image_analysis(path))?;
+/// Every supported file under `dir` that `filter` lets through, stopping
+/// early if `cancel` trips.
+fn find_images(dir: &Path, filter: &walk::Filter, cancel: &CancelToken) -> Vec<PathBuf> {
+    filter.files(dir)
+        .take_while(|_| !cancel.is_cancelled())
+        .filter(|path| is_supported(path))
+        .collect()
+}
 
-    Ok(ImageMetadata {
-        path: path.to_string_lossy().into_owned(),
-        file_name,
-        file_size,
-        dimensions,
-        format,
-        creation_date,
-        keywords: Some(keywords),
-        description: Some(description),
-    })
+/// Scan each of `targets`, folders or names of sources, or what
+/// `[[sources]]` configures if there are none, else the current directory;
+/// with `resume` and none, the folders whose scans didn't finish. A source
+/// whose folder isn't there (a drive that isn't plugged in, a share that
+/// isn't mounted) is skipped, and the others scanned anyway. What they
+/// did is written to `scan.report` at the end, if that's set.
+fn scan_sources(conn: &Connection, config: &Config, targets: &[PathBuf], analyze: bool, resume: bool, cancel: &CancelToken) -> Result<(), Error> {
+    let mut report = report::Report::start();
+    let result = scan_targets(conn, config, targets, analyze, resume, cancel, &mut report);
+    let Some(path) = &config.scan.report else { return result };
+    report.finish(&result);
+    let written = report.write(path);
+    match (result, written) {
+        (Err(e), Err(unwritten)) => {
+            tracing::error!("{:#}", unwritten);
+            Err(e)
+        }
+        (result, written) => result.and(written),
+    }
 }
 
-fn save_metadata(conn: &Connection, metadata: &ImageMetadata) -> Result<()> {
-    conn.execute(
-        "INSERT INTO images (
-            path, file_name, file_size, width, height, format,
-            creation_date, keywords, description
-        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-        rusqlite::params![
-            metadata.path,
-            metadata.file_name,
-            metadata.file_size,
-            metadata.dimensions.map(|(w, _)| w),
-            metadata.dimensions.map(|(_, h)| h),
-            metadata.format.map(|f| format!("{:?}", f)),
-            metadata.creation_date,
-            metadata.keywords,
-            metadata.description,
-        ],
-    )?;
+fn scan_targets(conn: &Connection, config: &Config, targets: &[PathBuf], analyze: bool, resume: bool, cancel: &CancelToken, report: &mut report::Report) -> Result<(), Error> {
+    sources::configure(conn, &config.sources)?;
+    let targets: Vec<PathBuf> = match (targets.is_empty(), resume, config.sources.is_empty()) {
+        (false, _, _) => targets.to_vec(),
+        (true, true, _) => {
+            let interrupted = resume::interrupted(conn)?;
+            if interrupted.is_empty() {
+                return Err(CliError::new(ErrorKind::NothingToDo, "no scans to resume; every one finished").into());
+            }
+            interrupted
+        }
+        (true, false, false) => config.sources.iter().map(|source| PathBuf::from(&source.name)).collect(),
+        (true, false, true) => vec![env::current_dir()?],
+    };
+    // A single photo is scanned as it is, rather than made a source, and
+    // as someone waiting on it, ahead of the daemon
+    let (files, folders): (Vec<PathBuf>, Vec<PathBuf>) = targets.into_iter().partition(|target| target.is_file());
+    let sources = folders.iter().map(|target| sources::resolve(conn, target)).collect::<Result<Vec<_>, _>>()?;
+    let lane = (!files.is_empty()).then(|| priority::Lane::of(conn)).flatten();
+    let claim = lane.as_ref().map(priority::Lane::claim);
+    let mut errors = Vec::new();
+    for file in &files {
+        let result = progress::track(conn, "scan", cancel, |operation| scan(conn, operation, config, Some(file.clone()), analyze, resume, report));
+        report.target(file, result.as_ref().err());
+        sources::assign(conn)?;
+        match result {
+            Err(e) if error::classify(&e) == ErrorKind::Cancelled => return Err(stopped(e)),
+            Err(e) => errors.push(e.context(file.display().to_string())),
+            Ok(()) => {}
+        }
+    }
+    drop(claim);
+    let mut shares = share::Shares::new(&config.shares);
+    for source in &sources {
+        // Its photos stay as they are until it's back
+        if let Some(root) = shares.offline(&source.path) {
+            sources::offline(conn, source)?;
+            let message = format!("source {}: the share at {} isn't answering; marked offline", source.name, root.display());
+            let e = Error::from(CliError::new(ErrorKind::BackendUnreachable, message));
+            report.target(&source.path, Some(&e));
+            errors.push(e);
+            continue;
+        }
+        if !source.path.is_dir() {
+            sources::offline(conn, source)?;
+            let message = format!("source {}: {} isn't there; is it mounted?", source.name, source.path.display());
+            let e = Error::from(CliError::new(ErrorKind::NothingToDo, message));
+            report.target(&source.path, Some(&e));
+            errors.push(e);
+            continue;
+        }
+        let result = progress::track(conn, "scan", cancel, |operation| scan(conn, operation, config, Some(source.path.clone()), analyze, resume, report));
+        report.target(&source.path, result.as_ref().err());
+        match result {
+            Ok(()) => sources::scanned(conn, source)?,
+            // What was cataloged before the error still belongs to it
+            Err(e) => {
+                sources::assign(conn)?;
+                shares.forget(&source.path);
+                if shares.offline(&source.path).is_some() {
+                    sources::offline(conn, source)?;
+                }
+                match error::classify(&e) {
+                    ErrorKind::Cancelled => return Err(stopped(e)),
+                    _ => errors.push(e.context(format!("source {}", source.name))),
+                }
+            }
+        }
+    }
+    let scanned = files.len() + sources.len();
+    match errors.len() {
+        0 => Ok(()),
+        1 if scanned == 1 => Err(errors.remove(0)),
+        _ => {
+            for e in &errors {
+                tracing::error!("{:#}", e);
+            }
+            let kind = match errors.iter().all(|e| error::classify(e) == ErrorKind::NothingToDo) {
+                true if errors.len() == scanned => ErrorKind::NothingToDo,
+                _ => ErrorKind::PartialFailure,
+            };
+            Err(CliError::new(kind, format!("{} of {} weren't scanned in full", errors.len(), scanned)).into())
+        }
+    }
+}
+
+/// `reel`: cut the photos `args` pick into a video at `args.out`.
+#[cfg(feature = "video")]
+fn make_reel(conn: &Connection, config: &mut Config, args: ReelArgs) -> Result<(), Error> {
+    let mut filter = query::filter(&args.query);
+    filter.trip = args.trip.or(filter.trip);
+    filter.date = args.year.map(|year| year.to_string()).or(filter.date);
+    config.reel.seconds = args.seconds.unwrap_or(config.reel.seconds);
+    config.reel.music = args.music.or(config.reel.music.take());
+    let name = match (args.trip, args.event, args.year) {
+        (Some(trip), Some(event), _) => format!("Event {} of trip {}", event, trip),
+        (Some(trip), None, _) => format!("Trip {}", trip),
+        (None, _, Some(year)) => year.to_string(),
+        (None, _, None) => query::to_string(&args.query),
+    };
+    let selection = reel::Selection { filter: &filter, event: args.event, name };
+    let (shots, secs) = reel::make(conn, &selection, config, &args.out)?;
+    println!("Wrote {}: {} shots, {:.0} seconds", args.out.display(), shots, secs);
     Ok(())
 }
 
-fn main() -> Result<(), Error> {
-    // Get the directory to scan from command line argument or use current directory
-    let scan_dir = env::args().nth(1)
-        .map(PathBuf::from)
-        .unwrap_or_else(|| env::current_dir().unwrap());
+/// A scan's cancellation, after saying how to carry on from it.
+fn stopped(e: Error) -> Error {
+    tracing::info!("What was done is saved; `scan --resume` picks up from there");
+    e
+}
 
-    println!("Scanning directory: {}", scan_dir.display());
+/// What's in the file at `path`, or on standard input for "-".
+fn read_input(path: &Path) -> Result<String, Error> {
+    match path.as_os_str() == "-" {
+        true => Ok(std::io::read_to_string(std::io::stdin())?),
+        false => Ok(fs::read_to_string(path)?),
+    }
+}
 
-    // Initialize SQLite database
-    let conn = Connection::open("photo_catalog.db")?;
-    init_database(&conn)?;
+/// Write the photos matching `filter` as CSV to `out`, or standard output
+/// for "-". Gives how many rows there were.
+fn write_csv(conn: &Connection, filter: &SearchFilter, columns: &[String], out: &Path) -> Result<usize, Error> {
+    if out.as_os_str() == "-" {
+        return export::export_csv(conn, filter, columns, &mut std::io::stdout().lock());
+    }
+    let mut file = std::io::BufWriter::new(fs::File::create(out)?);
+    let rows = export::export_csv(conn, filter, columns, &mut file)?;
+    std::io::Write::flush(&mut file)?;
+    tracing::info!("Wrote {} rows to {}", rows, out.display());
+    Ok(rows)
+}
+
+/// Write the photos matching `filter` as JSON to `out`, as `write_csv`
+/// does. Gives how many records there were.
+fn write_json(conn: &Connection, filter: &SearchFilter, format: portable::JsonFormat, out: &Path) -> Result<usize, Error> {
+    if out.as_os_str() == "-" {
+        return portable::export_json(conn, filter, format, &mut std::io::stdout().lock());
+    }
+    let mut file = std::io::BufWriter::new(fs::File::create(out)?);
+    let records = portable::export_json(conn, filter, format, &mut file)?;
+    std::io::Write::flush(&mut file)?;
+    tracing::info!("Wrote {} records to {}", records, out.display());
+    Ok(records)
+}
+
+/// Scan `dir`, counting what was done in `report`.
+fn scan(conn: &Connection, operation: &Operation, config: &Config, dir: Option<PathBuf>, analyze: bool, resume: bool, report: &mut report::Report) -> Result<(), Error> {
+    // Scan the given directory or fall back to the current directory
+    let scan_dir = match dir {
+        Some(dir) => dir,
+        None => env::current_dir()?,
+    };
+    let threshold = config.scan.max_failures.as_deref()
+        .map(report::parse_threshold)
+        .transpose()
+        .map_err(|e| CliError::new(ErrorKind::Config, format!("scan.max_failures: {}", e)))?
+        .unwrap_or_default();
+
+    tracing::info!("Scanning directory: {}", scan_dir.display());
+
+    let analyzer = if analyze {
+        let analyzer = AnalyzerPool::new(&config.analyzer)?
+            .with_examples(corrections::examples(conn, config.analyzer.examples)?);
+        require_healthy(&analyzer)?;
+        Some(analyzer)
+    } else {
+        None
+    };
+    let workers = match &analyzer {
+        Some(analyzer) => analyzer.concurrency(),
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
 
     // Count for processed images
     let mut processed_count = 0;
+    let mut failed_count = 0;
+    let mut unreachable_count = 0;
 
-    // Walk through the directory
-    for entry in WalkDir::new(scan_dir)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| {
-            e.path().extension()
-                .map(|ext| {
-                    let ext = ext.to_string_lossy().to_lowercase();
-                    matches!(ext.as_str(), "jpg" | "jpeg" | "png" | "gif" | "bmp")
-                })
-                .unwrap_or(false)
-        })
-    {
-        match process_image(entry.path()) {
-            Ok(metadata) => {
-                println!("Processing: {}", entry.path().display());
-                if let Err(e) = save_metadata(&conn, &metadata) {
-                    eprintln!("Error saving metadata for {}: {}", entry.path().display(), e);
-                } else {
-                    processed_count += 1;
-                }
+    let cancel = &operation.cancel;
+    // Photos libraries list their own originals
+    let library = apple_photos::is_library(&scan_dir).then(|| apple_photos::Library::open(&scan_dir)).transpose()?;
+    // Removed once the scan is done, however it ends
+    let snapshot = (library.is_none() && config.scan.snapshot).then(|| snapshot::Snapshot::take(&scan_dir)).flatten();
+    let paths: Vec<PathBuf> = match (&library, &snapshot) {
+        (Some(library), _) => library.originals().into_iter().filter(|path| is_supported(path)).collect(),
+        (None, Some(snapshot)) => find_images(snapshot.view(), &walk::Filter::new(&config.scan)?, cancel)
+            .iter()
+            .map(|path| snapshot.live(path))
+            .collect(),
+        (None, None) => find_images(&scan_dir, &walk::Filter::new(&config.scan)?, cancel),
+    };
+    // Files catalogued before under another path only need their row moved
+    let mut shares = share::Shares::new(&config.shares);
+    let mut missing = moves::Missing::find(conn, &mut shares)?;
+    let mut moved_count = 0;
+    let mut unmoved = Vec::new();
+    report.discovered += paths.len();
+    for path in paths {
+        match missing.relocate(conn, &path) {
+            Ok(Some(old_path)) => {
+                tracing::info!("Moved: {} -> {}", old_path, path.display());
+                moved_count += 1;
             }
+            Ok(None) => unmoved.push(path),
             Err(e) => {
-                eprintln!("Error processing {}: {}", entry.path().display(), e);
+                tracing::error!("Error checking whether {} was moved: {}", path.display(), e);
+                unmoved.push(path);
             }
         }
     }
+    // What a scan that stopped saved already needn't be analyzed again
+    let session = resume::open(conn, &scan_dir, operation.id, resume)?;
+    let (paths, done): (Vec<PathBuf>, Vec<PathBuf>) = unmoved.into_iter().partition(|path| !session.is_done(path));
+    operation.checkpoint(conn)?;
+    operation.set_total(conn, paths.len())?;
 
-    println!("Successfully processed {} images", processed_count);
-    Ok(())
+    // Analysis is the slow part, so keep every analyzer slot busy with its
+    // own worker while this thread owns the database connection.
+    let queue = Mutex::new(paths.into_iter());
+    let (results, received) = mpsc::channel();
+    let mut batch = batch::Batch::new(conn, config.catalog.batch_size);
+    // The share that went away mid-scan, if one did
+    let mut lost = None;
+    thread::scope(|scope| -> Result<(), Error> {
+        for _ in 0..workers {
+            let (queue, analyzer, library, snapshot, results) = (&queue, analyzer.as_ref(), library.as_ref(), snapshot.as_ref(), results.clone());
+            scope.spawn(move || loop {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let Some(path) = queue.lock().unwrap().next() else { break };
+                // Ctrl-C leaves the images started to finish and be saved
+                let in_flight = cancel.in_flight();
+                let span = tracing::debug_span!("image", path = %path.display()).entered();
+                let metadata = match (library, snapshot) {
+                    (Some(library), _) => process_original(&path, library, config, analyzer, &in_flight),
+                    (None, Some(snapshot)) => process_copy(&path, &snapshot.inside(&path), config, analyzer, &in_flight),
+                    (None, None) => process_image(&path, config, analyzer, &in_flight),
+                };
+                drop(span);
+                if results.send((path, metadata)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(results);
+
+        loop {
+            // Wake up now and then to notice `cancel` requests even while
+            // every worker is waiting on a slow analyzer
+            let (path, metadata) = match received.recv_timeout(Duration::from_secs(1)) {
+                Ok(result) => result,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    // Don't hold the catalog while waiting
+                    batch.commit()?;
+                    operation.poll_cancel(conn)?;
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            let ok = match metadata {
+                Ok(metadata) => {
+                    tracing::info!("Processing: {}", path.display());
+                    let saving = tracing::debug_span!("image", path = %path.display()).entered();
+                    let saved = tracing::debug_span!("db").in_scope(|| batch.write(|conn| {
+                        save_metadata(conn, &metadata)?;
+                        session.record(conn, &path)
+                    }));
+                    drop(saving);
+                    if let Err(e) = saved {
+                        tracing::error!("Error saving metadata for {}: {}", path.display(), e);
+                        report.fail(&path, &e.context("saving it to the catalog"));
+                        failed_count += 1;
+                        false
+                    } else {
+                        processed_count += 1;
+                        true
+                    }
+                }
+                // Abandoned mid-analysis; it's picked up by the next scan
+                Err(e) if error::classify(&e) == ErrorKind::Cancelled => continue,
+                Err(e) => {
+                    tracing::error!("Error processing {}: {}", path.display(), e);
+                    failed_count += 1;
+                    if error::classify(&e) == ErrorKind::BackendUnreachable {
+                        unreachable_count += 1;
+                    }
+                    report.fail(&path, &e);
+                    if let Some(category) = problems::category_of(&e).filter(|_| lost.is_none()) {
+                        let message = format!("{:#}", e);
+                        batch.write(|conn| Ok(problems::record(conn, &path.to_string_lossy(), category, &message)?))?;
+                    }
+                    // A share that's gone takes the rest of its files with it,
+                    // rather than each failing in turn
+                    if lost.is_none() && shares.root(&path).is_some() {
+                        shares.forget(&path);
+                        if let Some(root) = shares.offline(&path) {
+                            tracing::warn!("{} stopped answering; leaving the rest of the scan to when it's back", root.display());
+                            queue.lock().unwrap().by_ref().for_each(drop);
+                            lost = Some(root);
+                        }
+                    }
+                    false
+                }
+            };
+            operation.advance(conn, ok)?;
+            operation.poll_cancel(conn)?;
+        }
+        batch.commit()
+    })?;
+
+    tracing::info!("Successfully processed {} images", processed_count);
+    if moved_count > 0 {
+        tracing::info!("Followed {} moved or renamed files", moved_count);
+    }
+    if !done.is_empty() {
+        tracing::info!("Skipped {} images the scan being resumed had done", done.len());
+    }
+    report.processed += processed_count;
+    report.moved += moved_count;
+    report.skipped += done.len();
+    let total = processed_count + failed_count;
+    let result = if cancel.is_cancelled() {
+        cancel.check()
+    } else if let Some(root) = lost {
+        let message = format!("the share at {} stopped answering; `scan --resume` carries on once it's back", root.display());
+        Err(CliError::new(ErrorKind::BackendUnreachable, message).into())
+    } else if total == 0 && moved_count == 0 && done.is_empty() {
+        Err(CliError::new(ErrorKind::NothingToDo, "no images found").into())
+    } else if processed_count == 0 && unreachable_count == failed_count {
+        Err(CliError::new(ErrorKind::BackendUnreachable, "lost contact with every analyzer host").into())
+    } else if threshold.exceeded(failed_count, total) {
+        let message = format!("{} of {} images failed", failed_count, total);
+        Err(CliError::new(ErrorKind::PartialFailure, message).into())
+    } else {
+        if failed_count > 0 {
+            tracing::warn!("{} of {} images failed, within the {} allowed", failed_count, total, threshold);
+        }
+        Ok(())
+    };
+    // Stopped or with failures, it's left for `--resume` to finish
+    match &result {
+        Err(e) if error::classify(e) != ErrorKind::NothingToDo => {}
+        Ok(()) if failed_count > 0 => {}
+        _ => session.finish(conn)?,
+    }
+    result
 }
 
 #[cfg(test)]
@@ -214,8 +3095,7 @@ mod tests {
     use std::fs::File;
     use std::io::Write;
     use tempfile::tempdir;
-    use mockito::Server;
-    use tokio;
+    use crate::test_support::*;
 
     #[test]
     fn test_init_database() -> Result<(), Error> {
@@ -230,8 +3110,13 @@ mod tests {
 
         // Check for all expected columns
         let expected_columns = vec![
-            "id", "path", "file_name", "file_size", "width", "height",
-            "format", "creation_date", "keywords", "description"
+            "id", "path", "file_name", "file_size", "content_hash", "width", "height",
+            "orientation", "format", "page_count", "duration_secs", "video_codec", "frame_count", "play_count",
+            "creation_date", "latitude", "longitude",
+            "country", "region", "city", "camera_make", "camera_model",
+            "lens_model", "iso", "aperture", "exposure_time", "focal_length",
+            "flash_fired", "camera_serial", "device", "tier", "description", "version",
+            "archived_at", "rating", "favorite", "rejected", "source_id"
         ];
 
         assert_eq!(columns.len(), expected_columns.len());
@@ -241,62 +3126,86 @@ mod tests {
         Ok(())
     }
 
-    #[tokio::test]
-    async fn test_get_image_analysis() -> Result<(), Error> {
-        // Create a mock server
-        let mut server = Server::new();
-
-        // Create a mock response
-        let mock_response = r#"{
-            "model": "llava",
-            "response": "A colorful sunset over mountains\n\nKeywords: sunset, mountains, nature, landscape, evening, colorful"
-        }"#;
-
-        // Set up the mock endpoint
-        let _m = server.mock("POST", "/api/generate")
-            .with_status(200)
-            .with_header("content-type", "application/json")
-            .with_body(mock_response)
-            .create();
-
-        // Create a temporary test image
+    #[test]
+    fn test_process_image() -> Result<(), Error> {
         let dir = tempdir()?;
         let test_image_path = dir.path().join("test.jpg");
-        let mut test_image = File::create(&test_image_path)?;
 
-        // Write some dummy image data
+        // Create a test JPEG image
+        let mut test_image = File::create(&test_image_path)?;
         test_image.write_all(&[0xFF, 0xD8, 0xFF, 0xE0])?; // JPEG header
         test_image.sync_all()?;
 
-        // Test the analysis function
-        let (description, keywords) = get_image_analysis(&test_image_path).await?;
+        let (_server, analyzer) = mock_ollama();
+        let metadata = process_image(&test_image_path, &Config::default(), Some(&analyzer), &CancelToken::new())?;
 
-        assert_eq!(description, "A colorful sunset over mountains");
-        assert_eq!(
-            keywords,
-            "sunset, mountains, nature, landscape, evening, colorful"
-        );
+        assert_eq!(metadata.file_name, "test.jpg");
+        assert_eq!(metadata.file_size, 4); // Size of our minimal JPEG header
+        assert!(metadata.keywords.is_some());
+        assert!(metadata.description.is_some());
 
         Ok(())
     }
 
     #[test]
-    fn test_process_image() -> Result<(), Error> {
+    fn test_process_image_gps() -> Result<(), Error> {
+        let dms = |d, m, s| exif::Value::Rational(vec![
+            exif::Rational::from((d, 1)), exif::Rational::from((m, 1)), exif::Rational::from((s, 100)),
+        ]);
+        let fields = vec![
+            field(Tag::GPSLatitudeRef, ascii("N")),
+            field(Tag::GPSLatitude, dms(38, 43, 2028)),
+            field(Tag::GPSLongitudeRef, ascii("W")),
+            field(Tag::GPSLongitude, dms(9, 8, 2148)),
+        ];
         let dir = tempdir()?;
-        let test_image_path = dir.path().join("test.jpg");
+        let path = dir.path().join("lisbon.jpg");
+        fs::write(&path, jpeg_with_exif(&fields))?;
 
-        // Create a test JPEG image
-        let mut test_image = File::create(&test_image_path)?;
-        test_image.write_all(&[0xFF, 0xD8, 0xFF, 0xE0])?; // JPEG header
-        test_image.sync_all()?;
+        let (_server, analyzer) = mock_ollama();
+        let metadata = process_image(&path, &Config::default(), Some(&analyzer), &CancelToken::new())?;
 
-        let metadata = process_image(&test_image_path)?;
+        let (lat, lon) = metadata.gps.expect("GPS should be decoded");
+        assert!((lat - 38.7223).abs() < 1e-3);
+        assert!((lon + 9.1393).abs() < 1e-3);
+        assert_eq!(metadata.dimensions, Some((8, 6)));
+        #[cfg(feature = "geocode")]
+        assert_eq!(metadata.place.map(|p| p.country).as_deref(), Some("Portugal"));
+        Ok(())
+    }
 
-        assert_eq!(metadata.file_name, "test.jpg");
-        assert_eq!(metadata.file_size, 4); // Size of our minimal JPEG header
-        assert!(metadata.keywords.is_some());
-        assert!(metadata.description.is_some());
+    #[test]
+    fn test_process_rotated_image() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let path = dir.path().join("portrait.jpg");
+        fs::write(&path, jpeg_with_exif(&[field(Tag::Orientation, exif::Value::Short(vec![6]))]))?;
+
+        let metadata = process_image(&path, &Config::default(), None, &CancelToken::new())?;
+        assert_eq!(metadata.orientation, Some(6));
+        // Stored 8x6, displayed 6x8
+        assert_eq!(metadata.dimensions, Some((6, 8)));
+        Ok(())
+    }
 
+    #[test]
+    #[cfg(feature = "geocode")]
+    fn test_geocode_and_search_by_place() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, latitude, longitude)
+             VALUES ('/photos/porto.jpg', 'porto.jpg', 1, 41.1496, -8.6110),
+                    ('/photos/nogps.jpg', 'nogps.jpg', 1, NULL, NULL)",
+            [],
+        )?;
+
+        let place = |p: &str| SearchFilter { place: Some(p.to_string()), ..Default::default() };
+        assert!(search_images(&conn, &place("portugal"))?.is_empty());
+        assert_eq!(geocode_missing(&conn)?, 1);
+        assert_eq!(search_images(&conn, &place("portugal"))?, vec!["/photos/porto.jpg"]);
+        assert!(search_images(&conn, &place("Spain"))?.is_empty());
+        // Already-resolved rows are left alone on a second pass
+        assert_eq!(geocode_missing(&conn)?, 0);
         Ok(())
     }
 
@@ -309,26 +3218,49 @@ mod tests {
             path: String::from("/test/path"),
             file_name: String::from("test.jpg"),
             file_size: 1000,
+            content_hash: None,
             dimensions: Some((800, 600)),
-            format: Some(ImageFormat::Jpeg),
+            orientation: Some(1),
+            format: Some(String::from("Jpeg")),
+            page_count: None,
+            duration_secs: None,
+            video_codec: None,
+            frame_count: None,
+            play_count: None,
             creation_date: Some(String::from("2024-01-01 00:00:00")),
+            gps: None,
+            place: None,
+            camera: CameraInfo::default(),
+            device: None,
+            tier: None,
+            external: vec![ExternalMetadata {
+                source: String::from("iptc"),
+                field: String::from("keyword"),
+                value: String::from("Lisbon"),
+            }],
             keywords: Some(String::from("test, image, mock")),
             description: Some(String::from("A test image")),
+            scenes: Vec::new(),
+            problems: Vec::new(),
         };
 
         save_metadata(&conn, &metadata)?;
 
         // Verify the saved data
-        let mut stmt = conn.prepare("SELECT * FROM images WHERE file_name = 'test.jpg'")?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT path, file_name, file_size, width, height, {}, description
+             FROM images WHERE file_name = 'test.jpg'",
+            tags::KEYWORDS,
+        ))?;
         let rows = stmt.query_map([], |row| {
             Ok((
-                row.get::<_, String>(1)?, // path
-                row.get::<_, String>(2)?, // file_name
-                row.get::<_, i64>(3)?,    // file_size
-                row.get::<_, i64>(4)?,    // width
-                row.get::<_, i64>(5)?,    // height
-                row.get::<_, String>(8)?, // keywords
-                row.get::<_, String>(9)?, // description
+                row.get::<_, String>(0)?, // path
+                row.get::<_, String>(1)?, // file_name
+                row.get::<_, i64>(2)?,    // file_size
+                row.get::<_, i64>(3)?,    // width
+                row.get::<_, i64>(4)?,    // height
+                row.get::<_, String>(5)?, // keywords
+                row.get::<_, String>(6)?, // description
             ))
         })?.collect::<Result<Vec<_>, _>>()?;
 
@@ -342,6 +3274,220 @@ mod tests {
         assert_eq!(row.5, "test, image, mock");
         assert_eq!(row.6, "A test image");
 
+        // Embedded keywords are searchable alongside the AI ones
+        let filter = |k: &str| SearchFilter { keyword: Some(k.to_string()), ..Default::default() };
+        assert_eq!(search_images(&conn, &filter("lisbon"))?, vec!["/test/path"]);
+        assert_eq!(search_images(&conn, &filter("mock"))?, vec!["/test/path"]);
+        assert!(search_images(&conn, &filter("porto"))?.is_empty());
+
+        // Rescanned without analysis: still one row, with the description
+        // found before and the embedded keywords as they are now
+        let rescanned = ImageMetadata {
+            file_size: 1200,
+            keywords: None,
+            description: None,
+            external: vec![ExternalMetadata { source: String::from("iptc"), field: String::from("keyword"), value: String::from("Porto") }],
+            ..metadata
+        };
+        save_metadata(&conn, &rescanned)?;
+        let (count, file_size, description): (i64, i64, String) = conn.query_row(
+            "SELECT COUNT(*), MAX(file_size), MAX(description) FROM images", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        assert_eq!((count, file_size, description.as_str()), (1, 1200, "A test image"));
+        assert_eq!(search_images(&conn, &filter("porto"))?, vec!["/test/path"]);
+        assert!(search_images(&conn, &filter("lisbon"))?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "raw")]
+    fn test_process_raw() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let path = dir.path().join("DSC_0001.NEF");
+        let preview = jpeg_with_exif(&[]);
+        let fields = [field(Tag::Make, ascii("NIKON CORPORATION")), field(Tag::Model, ascii("NIKON Z 6"))];
+        fs::write(&path, raw_with_preview(&fields, &preview, (6048, 4024)))?;
+
+        let (_server, analyzer) = mock_ollama();
+        let metadata = process_image(&path, &Config::default(), Some(&analyzer), &CancelToken::new())?;
+        assert_eq!(metadata.format.as_deref(), Some("Nef"));
+        assert_eq!(metadata.dimensions, Some((6048, 4024)));
+        assert_eq!(metadata.camera.model.as_deref(), Some("NIKON Z 6"));
+        assert_eq!(metadata.description.as_deref(), Some("A colorful sunset over mountains"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_webp() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let path = dir.path().join("download.webp");
+        let packet = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+            <rdf:Description xmlns:dc="http://purl.org/dc/elements/1.1/">
+            <dc:subject><rdf:Bag><rdf:li>harbour</rdf:li></rdf:Bag></dc:subject>
+            </rdf:Description></rdf:RDF></x:xmpmeta>"#;
+        fs::write(&path, webp_with_xmp(packet))?;
+
+        let (_server, analyzer) = mock_ollama();
+        let metadata = process_image(&path, &Config::default(), Some(&analyzer), &CancelToken::new())?;
+        assert_eq!(metadata.format.as_deref(), Some("WebP"));
+        assert_eq!(metadata.dimensions, Some((1, 1)));
+        assert!(metadata.external.iter().any(|e| e.source == "xmp" && e.value == "harbour"));
+        assert!(metadata.description.is_some());
+        assert!(is_supported(&path));
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_multi_page_tiff() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let path = dir.path().join("scan.tiff");
+        fs::write(&path, multi_page_tiff(&[(40, 30, false), (40, 30, false), (40, 30, false)]))?;
+
+        let (_server, analyzer) = mock_ollama();
+        let metadata = process_image(&path, &Config::default(), Some(&analyzer), &CancelToken::new())?;
+        assert_eq!(metadata.format.as_deref(), Some("Tiff"));
+        assert_eq!(metadata.page_count, Some(3));
+        assert_eq!(metadata.dimensions, Some((40, 30)));
+        assert!(metadata.description.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_animated_gif() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let path = dir.path().join("reaction.gif");
+        fs::write(&path, animated_gif(&[[255, 0, 0], [0, 255, 0], [0, 0, 255], [0, 0, 0]], 5, Some(0)))?;
+
+        let (_server, analyzer) = mock_ollama();
+        let metadata = process_image(&path, &Config::default(), Some(&analyzer), &CancelToken::new())?;
+        assert_eq!(metadata.format.as_deref(), Some("Gif"));
+        assert_eq!(metadata.dimensions, Some((4, 3)));
+        assert_eq!(metadata.frame_count, Some(4));
+        assert_eq!(metadata.play_count, Some(0));
+        assert_eq!(metadata.duration_secs, Some(0.2));
+        assert!(metadata.description.is_some());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[cfg(feature = "video")]
+    fn test_process_video() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let path = dir.path().join("IMG_0042.MOV");
+        fs::write(&path, mp4(30.0, (3840, 2160), Some("+38.7139-009.1394/")))?;
+        let frame = dir.path().join("frame.jpg");
+        image::DynamicImage::new_rgb8(16, 9).save(&frame)?;
+        let mut config = Config::default();
+        config.video.ffmpeg = fake_tool(dir.path(), &format!("cat '{}'", frame.display()))?;
+
+        let (_server, analyzer) = mock_ollama();
+        let metadata = process_video(&path, "QuickTime", &config, Some(&analyzer), &CancelToken::new())?;
+        assert_eq!(metadata.format.as_deref(), Some("QuickTime"));
+        assert_eq!(metadata.duration_secs, Some(30.0));
+        assert_eq!(metadata.video_codec.as_deref(), Some("hevc"));
+        assert_eq!(metadata.dimensions, Some((3840, 2160)));
+        assert_eq!(metadata.creation_date.as_deref(), Some("2024-05-01 18:30:00"));
+        assert_eq!(metadata.gps, Some((38.7139, -9.1394)));
+        assert!(metadata.description.is_some());
+        assert!(is_supported(&path));
+
+        // Without ffmpeg the video is still cataloged
+        config.video.ffmpeg = String::from("no-such-ffmpeg");
+        let metadata = process_image(&path, &config, Some(&analyzer), &CancelToken::new())?;
+        assert_eq!(metadata.description, None);
+        assert_eq!(metadata.duration_secs, Some(30.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("45"), Ok(Duration::from_secs(45)));
+        assert!(parse_duration("2 hours").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("5x").is_err());
+    }
+
+    #[test]
+    fn test_local_only_outbound() {
+        let refused = |args: &[&str]| {
+            let cli = Cli::try_parse_from([&["PhotoCataloger"], args, &["--local-only", "--db", IN_MEMORY]].concat()).unwrap();
+            let e = run(cli).unwrap_err();
+            assert_eq!(error::classify(&e), ErrorKind::Config, "{:#}", e);
+            format!("{:#}", e)
+        };
+        assert!(refused(&["mirror", "--to", "vps:photos"]).contains("mirror: vps:photos is an rclone remote"));
+        assert!(refused(&["sync", "--immich", "https://photos.example.com"]).contains("Immich sync"));
+    }
+
+    #[test]
+    fn test_rehearsable() {
+        let rehearsable = |args: &[&str]| {
+            let cli = Cli::try_parse_from([&["PhotoCataloger"], args].concat()).unwrap();
+            assert!(cli.dry_run);
+            super::rehearsable(&cli.command)
+        };
+        assert!(rehearsable(&["prune", "--dry-run"]));
+        assert!(rehearsable(&["organize", "--archive-to", "/cold", "--dry-run"]));
+        assert!(rehearsable(&["tag", "rename", "dog", "dogs", "--dry-run"]));
+        assert!(rehearsable(&["fix-dates", "--shift", "+2h", "--query", "camera:X100V", "--apply", "--dry-run"]));
+        assert!(!rehearsable(&["fix-dates", "--undo", "3", "--dry-run"]));
+        assert!(rehearsable(&["search", "--dry-run"]));
+        assert!(rehearsable(&["db", "fsck", "--repair", "--dry-run"]));
+        assert!(rehearsable(&["import", "csv", "curated.csv", "--match-on", "hash", "--dry-run"]));
+        assert!(!rehearsable(&["import", "catalog.json", "--dry-run"]));
+        assert!(!rehearsable(&["rate", "5", "/a.jpg", "--dry-run"]));
+        assert!(!rehearsable(&["compare", "a.jpg", "b.jpg", "--heatmap", "diff.png", "--dry-run"]));
+        assert!(!rehearsable(&["--dry-run", "."]));
+    }
+
+    #[test]
+    fn test_global_flags_before_subcommand() {
+        let cli = parse_cli(["PhotoCataloger", "--dry-run", "prune"]).unwrap();
+        assert!(cli.dry_run && matches!(cli.command, Some(Command::Prune { .. })) && cli.dir.is_none());
+        let cli = parse_cli(["PhotoCataloger", "--db", IN_MEMORY, "scan", "--no-analyze", "/photos"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Scan { no_analyze: true, .. })));
+        assert_eq!(cli.db, Path::new(IN_MEMORY));
+        // The old form still scans a folder, but not alongside a subcommand
+        let cli = parse_cli(["PhotoCataloger", "--db", IN_MEMORY, "/photos"]).unwrap();
+        assert!(cli.command.is_none() && cli.dir.as_deref() == Some(Path::new("/photos")));
+        assert!(parse_cli(["PhotoCataloger", "/photos", "prune"]).is_err());
+    }
+
+    #[test]
+    fn test_search_camera_settings() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, camera_make, camera_model, focal_length, aperture)
+             VALUES ('/a.jpg', 'a.jpg', 1, 'FUJIFILM', 'X-T4', 35.0, 1.8),
+                    ('/b.jpg', 'b.jpg', 1, 'FUJIFILM', 'X-T4', 23.0, 1.8),
+                    ('/c.jpg', 'c.jpg', 1, 'Canon', 'EOS R5', 35.0, 1.8)",
+            [],
+        )?;
+
+        let filter = SearchFilter {
+            camera: Some(String::from("x-t4")),
+            focal_length: Some(parse_focal_length("35mm").unwrap()),
+            aperture: Some(parse_aperture("f/1.8").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(search_images(&conn, &filter)?, vec!["/a.jpg"]);
+        assert_eq!(search_images(&conn, &SearchFilter::default())?.len(), 3);
+        assert!(parse_aperture("wide").is_err());
+
+        conn.execute("UPDATE images SET city = 'Lisbon', country = 'Portugal', rating = 4 WHERE path = '/a.jpg'", [])?;
+        let args = table::TableArgs { sort: Some(String::from("-rating")), ..Default::default() };
+        let rendered = search_table(&conn, &SearchFilter::default())?.render(&args, &["path", "camera", "place", "rating"])?;
+        assert_eq!(rendered.lines().collect::<Vec<_>>(), vec![
+            "Path    Camera  Place             Rating",
+            "/a.jpg  X-T4    Lisbon, Portugal       4",
+            "/b.jpg  X-T4",
+            "/c.jpg  EOS R5",
+        ]);
         Ok(())
     }
 
@@ -358,7 +3504,8 @@ mod tests {
         test_image.sync_all()?;
 
         // Process and save the image
-        let metadata = process_image(&test_image_path)?;
+        let (_server, analyzer) = mock_ollama();
+        let metadata = process_image(&test_image_path, &Config::default(), Some(&analyzer), &CancelToken::new())?;
         save_metadata(&conn, &metadata)?;
 
         // Verify the image was processed and saved
@@ -371,4 +3518,177 @@ mod tests {
         assert_eq!(count, 1);
         Ok(())
     }
+
+    #[test]
+    fn test_in_memory_catalog() -> Result<(), Error> {
+        let cli = Cli::try_parse_from(["PhotoCataloger", "scan", "--no-analyze", "--db", ":memory:", "--csv", "-"])?;
+        assert_eq!(cli.db, Path::new(IN_MEMORY));
+        assert_eq!(Cli::try_parse_from(["PhotoCataloger", "stats"])?.db, Path::new(CATALOG_PATH));
+
+        let dir = tempdir()?;
+        image::DynamicImage::new_rgb8(8, 6).save(dir.path().join("a.png"))?;
+        let conn = open_catalog(Path::new(IN_MEMORY))?;
+        progress::track(&conn, "scan", &CancelToken::new(), |operation| {
+            scan(&conn, operation, &Config::default(), Some(dir.path().to_path_buf()), false, false, &mut report::Report::start())
+        })?;
+        let out = dir.path().join("photos.csv");
+        assert_eq!(write_csv(&conn, &SearchFilter::default(), &[String::from("file_name")], &out)?, 1);
+        assert_eq!(fs::read_to_string(&out)?, "file_name\r\na.png\r\n");
+        assert!(!Path::new(IN_MEMORY).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_sources() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        init_database(&conn)?;
+        let (disk, usb) = (tempdir()?, tempdir()?);
+        image::DynamicImage::new_rgb8(8, 6).save(disk.path().join("a.png"))?;
+        image::DynamicImage::new_rgb8(8, 6).save(usb.path().join("b.png"))?;
+        let config = Config { sources: vec![sources::SourceConfig { name: String::from("usb"), path: usb.path().to_path_buf() }], ..Config::default() };
+        scan_sources(&conn, &config, &[disk.path().to_path_buf(), PathBuf::from("usb")], false, false, &CancelToken::new())?;
+        let usb_photos = SearchFilter { source: Some(String::from("USB")), ..SearchFilter::default() };
+        assert_eq!(search_images(&conn, &usb_photos)?, vec![usb.path().join("b.png").to_string_lossy()]);
+        assert_eq!(sources::list(&conn)?.len(), 2);
+        // One photo is scanned without becoming a source of its own
+        scan_sources(&conn, &config, &[usb.path().join("b.png")], false, false, &CancelToken::new())?;
+        assert_eq!(sources::list(&conn)?.len(), 2);
+
+        // A source that's gone is skipped, and the rest scanned
+        let gone = tempdir()?;
+        let target = gone.path().to_path_buf();
+        sources::resolve(&conn, &target)?;
+        drop(gone);
+        let result = scan_sources(&conn, &Config::default(), &[target, PathBuf::from("usb")], false, false, &CancelToken::new());
+        assert_eq!(error::classify(&result.unwrap_err()), ErrorKind::PartialFailure);
+        assert!(sources::find(&conn, "usb")?.scanned_at.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_offline_share() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        init_database(&conn)?;
+        let (nas, disk) = (tempdir()?, tempdir()?);
+        image::DynamicImage::new_rgb8(8, 6).save(nas.path().join("a.png"))?;
+        let mut config = Config::default();
+        config.shares.paths.push(nas.path().to_path_buf());
+        config.shares.retry_delay_ms = 1;
+        scan_sources(&conn, &config, &[nas.path().to_path_buf()], false, false, &CancelToken::new())?;
+
+        // Unmounted, with a copy of its photo elsewhere
+        fs::copy(nas.path().join("a.png"), disk.path().join("a.png"))?;
+        fs::remove_file(nas.path().join("a.png"))?;
+        let result = scan_sources(&conn, &config, &[nas.path().to_path_buf(), disk.path().to_path_buf()], false, false, &CancelToken::new());
+        assert_eq!(error::classify(&result.unwrap_err()), ErrorKind::PartialFailure);
+        assert!(sources::resolve(&conn, nas.path())?.offline_since.is_some());
+        // The copy is a photo of its own, not the share's moved
+        assert_eq!(search_images(&conn, &SearchFilter::default())?.len(), 2);
+
+        fs::copy(disk.path().join("a.png"), nas.path().join("a.png"))?;
+        scan_sources(&conn, &config, &[nas.path().to_path_buf()], false, false, &CancelToken::new())?;
+        assert!(sources::resolve(&conn, nas.path())?.offline_since.is_none());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "raw")]
+    fn test_scan_report() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        init_database(&conn)?;
+        let dir = tempdir()?;
+        for name in ["a.png", "b.png", "c.png"] {
+            image::DynamicImage::new_rgb8(8, 6).save(dir.path().join(name))?;
+        }
+        fs::write(dir.path().join("broken.dng"), b"not a raw file")?;
+        let mut config = Config::default();
+        config.scan.report = Some(dir.path().join("report.json"));
+        let result = scan_sources(&conn, &config, &[dir.path().to_path_buf()], false, false, &CancelToken::new());
+        assert_eq!(error::classify(&result.unwrap_err()), ErrorKind::PartialFailure);
+        let report: serde_json::Value = serde_json::from_slice(&fs::read(dir.path().join("report.json"))?)?;
+        assert_eq!((report["discovered"].as_u64(), report["processed"].as_u64(), report["failed"].as_u64()), (Some(4), Some(3), Some(1)));
+        assert!(report["failures"][0]["path"].as_str().unwrap().ends_with("broken.dng"));
+        assert_eq!(report["exit_code"], 5);
+
+        // Within what's allowed, and still left to be retried
+        config.scan.max_failures = Some(String::from("25%"));
+        scan_sources(&conn, &config, &[dir.path().to_path_buf()], false, false, &CancelToken::new())?;
+        let report: serde_json::Value = serde_json::from_slice(&fs::read(dir.path().join("report.json"))?)?;
+        assert_eq!((report["failed"].as_u64(), report["exit_code"].as_u64()), (Some(1), Some(0)));
+        assert_eq!(resume::interrupted(&conn)?, vec![dir.path().to_path_buf()]);
+        config.scan.max_failures = Some(String::from("lots"));
+        let result = scan_sources(&conn, &config, &[dir.path().to_path_buf()], false, false, &CancelToken::new());
+        assert_eq!(error::classify(&result.unwrap_err()), ErrorKind::Config);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "raw")]
+    fn test_scan_records_problems() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        init_database(&conn)?;
+        let dir = tempdir()?;
+        let mut jpeg = Vec::new();
+        image::DynamicImage::new_rgb8(64, 48).write_to(&mut std::io::Cursor::new(&mut jpeg), image::ImageOutputFormat::Jpeg(90))?;
+        fs::write(dir.path().join("truncated.jpg"), &jpeg[..jpeg.len() / 2])?;
+        fs::write(dir.path().join("broken.dng"), b"not a raw file")?;
+        let result = scan_sources(&conn, &Config::default(), &[dir.path().to_path_buf()], false, false, &CancelToken::new());
+        assert_eq!(error::classify(&result.unwrap_err()), ErrorKind::PartialFailure);
+
+        let found: Vec<(String, problems::Category)> = problems::list(&conn, None)?.into_iter()
+            .map(|problem| (problem.path.file_name().unwrap().to_string_lossy().into_owned(), problem.category))
+            .collect();
+        assert_eq!(found, vec![(String::from("broken.dng"), problems::Category::Decode), (String::from("truncated.jpg"), problems::Category::Decode)]);
+        // The truncated one is cataloged anyway, the other isn't
+        assert_eq!(search_images(&conn, &SearchFilter::default())?.len(), 1);
+
+        // Replaced with a good copy
+        fs::write(dir.path().join("truncated.jpg"), &jpeg)?;
+        scan_sources(&conn, &Config::default(), &[dir.path().join("truncated.jpg")], false, false, &CancelToken::new())?;
+        assert_eq!(problems::list(&conn, None)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_stops_when_cancelled() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        init_database(&conn)?;
+        let dir = tempdir()?;
+        image::DynamicImage::new_rgb8(8, 6).save(dir.path().join("a.png"))?;
+
+        let cancel = CancelToken::new();
+        cancel.cancel(cancel::Reason::Interrupted);
+        let result = progress::track(&conn, "scan", &cancel, |operation| {
+            scan(&conn, operation, &Config::default(), Some(dir.path().to_path_buf()), false, false, &mut report::Report::start())
+        });
+        assert_eq!(error::classify(&result.unwrap_err()), ErrorKind::Cancelled);
+
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM images", [], |row| row.get(0))?;
+        assert_eq!(count, 0);
+        let status = progress::list(&conn, 1)?.remove(0);
+        assert_eq!((status.state.as_str(), status.message.as_deref()), ("cancelled", Some("interrupted")));
+        assert_eq!(resume::interrupted(&conn)?, vec![dir.path().to_path_buf()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_scan() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        init_database(&conn)?;
+        let dir = tempdir()?;
+        for name in ["a.png", "b.png"] {
+            image::DynamicImage::new_rgb8(8, 6).save(dir.path().join(name))?;
+        }
+        // As if a scan had saved a.png before the power went
+        let stopped = progress::start(&conn, "scan", CancelToken::new())?;
+        resume::open(&conn, dir.path(), stopped.id, false)?.record(&conn, &dir.path().join("a.png"))?;
+
+        scan_sources(&conn, &Config::default(), &[], false, true, &CancelToken::new())?;
+        let paths = search_images(&conn, &SearchFilter::default())?;
+        assert_eq!(paths, vec![dir.path().join("b.png").to_string_lossy()]);
+        assert!(resume::interrupted(&conn)?.is_empty());
+        let result = scan_sources(&conn, &Config::default(), &[], false, true, &CancelToken::new());
+        assert_eq!(error::classify(&result.unwrap_err()), ErrorKind::NothingToDo);
+        Ok(())
+    }
 }
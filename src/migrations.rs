@@ -0,0 +1,607 @@
+//! Versions of the catalog's schema. Each migration takes a catalog from
+//! the version before it to its own, in a transaction of its own, and the
+//! versions applied are kept in `schema_version`, so opening a catalog
+//! made by an older build brings it up to date and one made by a newer
+//! build is refused rather than misread.
+//!
+//! Changes to the schema go in a new migration at the end of `MIGRATIONS`;
+//! the ones there already have run on catalogs out in the world and must
+//! not change.
+
+use anyhow::Error;
+use rusqlite::{params, Connection, Transaction, TransactionBehavior};
+use crate::error::{CliError, ErrorKind};
+
+struct Migration {
+    version: i64,
+    description: &'static str,
+    apply: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, description: "baseline", apply: baseline },
+    Migration { version: 2, description: "image access counts", apply: image_access },
+    Migration { version: 3, description: "one row per path", apply: unique_paths },
+    Migration { version: 4, description: "edit versions", apply: edit_versions },
+    Migration { version: 5, description: "archived rows", apply: archived_rows },
+    Migration { version: 6, description: "audit log", apply: audit_log },
+    Migration { version: 7, description: "preferences", apply: preferences },
+    Migration { version: 8, description: "tags table", apply: tags_table },
+    Migration { version: 9, description: "albums", apply: albums },
+    Migration { version: 10, description: "histograms", apply: histograms },
+    Migration { version: 11, description: "ratings and flags", apply: culling },
+    Migration { version: 12, description: "downloads", apply: downloads },
+    Migration { version: 13, description: "sources", apply: sources },
+    Migration { version: 14, description: "scan sessions", apply: scan_sessions },
+    Migration { version: 15, description: "offline sources", apply: offline_sources },
+    Migration { version: 16, description: "problems", apply: problems },
+    Migration { version: 17, description: "custom fields", apply: fields },
+];
+
+/// The columns of `images` as of the baseline, in order. Catalogs from
+/// before versions were kept may lack some, which get added.
+const IMAGE_COLUMNS: &[(&str, &str)] = &[
+    ("id", "INTEGER PRIMARY KEY"),
+    ("path", "TEXT NOT NULL"),
+    ("file_name", "TEXT NOT NULL"),
+    ("file_size", "INTEGER NOT NULL"),
+    ("content_hash", "TEXT"),
+    ("width", "INTEGER"),
+    ("height", "INTEGER"),
+    ("orientation", "INTEGER"),
+    ("format", "TEXT"),
+    ("page_count", "INTEGER"),
+    ("duration_secs", "REAL"),
+    ("video_codec", "TEXT"),
+    ("frame_count", "INTEGER"),
+    ("play_count", "INTEGER"),
+    ("creation_date", "TEXT"),
+    ("latitude", "REAL"),
+    ("longitude", "REAL"),
+    ("country", "TEXT"),
+    ("region", "TEXT"),
+    ("city", "TEXT"),
+    ("camera_make", "TEXT"),
+    ("camera_model", "TEXT"),
+    ("lens_model", "TEXT"),
+    ("iso", "INTEGER"),
+    ("aperture", "REAL"),
+    ("exposure_time", "REAL"),
+    ("focal_length", "REAL"),
+    ("flash_fired", "INTEGER"),
+    ("camera_serial", "TEXT"),
+    ("device", "TEXT"),
+    ("tier", "TEXT"),
+    ("keywords", "TEXT"),
+    ("description", "TEXT"),
+];
+
+/// The schema version of the catalog: 0 for one that's new, or from
+/// before versions were kept.
+pub fn version(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))
+}
+
+/// The newest schema this build knows.
+pub fn latest() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// Bring the catalog up to the latest schema.
+pub fn migrate(conn: &Connection) -> Result<(), Error> {
+    let current = version(conn)?;
+    if current > latest() {
+        let message = format!(
+            "the catalog is at schema version {}, newer than this build of PhotoCataloger knows ({}); upgrade it to open the catalog",
+            current,
+            latest(),
+        );
+        return Err(CliError::new(ErrorKind::Config, message).into());
+    }
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        // Taking the write lock first, so two processes opening the
+        // catalog at once don't both migrate it
+        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        if version(&tx)? >= migration.version {
+            continue;
+        }
+        (migration.apply)(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, datetime('now'))",
+            params![migration.version, migration.description],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// The schema as it was when versions started being kept, made to match
+/// in catalogs that had some of it already.
+fn baseline(tx: &Connection) -> rusqlite::Result<()> {
+    let columns: Vec<String> = IMAGE_COLUMNS.iter().map(|(name, kind)| format!("{} {}", name, kind)).collect();
+    tx.execute(&format!("CREATE TABLE IF NOT EXISTS images ({})", columns.join(", ")), [])?;
+    let existing: Vec<String> = tx.prepare("PRAGMA table_info(images)")?
+        .query_map([], |row| row.get(1))?
+        .collect::<Result<_, _>>()?;
+    for (name, kind) in IMAGE_COLUMNS.iter().filter(|(name, _)| !existing.iter().any(|column| column == name)) {
+        tx.execute(&format!("ALTER TABLE images ADD COLUMN {} {}", name, kind), [])?;
+    }
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS external_metadata (
+            image_id INTEGER NOT NULL REFERENCES images(id),
+            source TEXT NOT NULL,
+            field TEXT NOT NULL,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS operations (
+            id INTEGER PRIMARY KEY,
+            kind TEXT NOT NULL,
+            state TEXT NOT NULL,
+            total INTEGER,
+            done INTEGER NOT NULL,
+            failed INTEGER NOT NULL,
+            started_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            finished_at INTEGER,
+            cancel_requested INTEGER NOT NULL,
+            message TEXT
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS date_shifts (
+            id INTEGER PRIMARY KEY,
+            seconds INTEGER NOT NULL,
+            query TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            undone_at INTEGER
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS date_shift_images (
+            shift_id INTEGER NOT NULL REFERENCES date_shifts(id),
+            image_id INTEGER NOT NULL REFERENCES images(id),
+            old_date TEXT NOT NULL,
+            new_date TEXT NOT NULL,
+            exif_shifted INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS trips (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            start_date TEXT NOT NULL,
+            end_date TEXT NOT NULL,
+            distance_km REAL NOT NULL,
+            countries TEXT NOT NULL,
+            event_count INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS trip_images (
+            trip_id INTEGER NOT NULL REFERENCES trips(id),
+            image_id INTEGER NOT NULL REFERENCES images(id),
+            event INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS corrections (
+            image_id INTEGER NOT NULL REFERENCES images(id),
+            tier TEXT,
+            description TEXT NOT NULL,
+            keywords TEXT NOT NULL,
+            corrected_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS notes (
+            id INTEGER PRIMARY KEY,
+            image_id INTEGER NOT NULL REFERENCES images(id),
+            audio_path TEXT,
+            text TEXT,
+            created_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    // Full-text index of the notes, kept up to date by the triggers
+    tx.execute_batch(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS notes_fts USING fts5(text, content = 'notes', content_rowid = 'id');
+         CREATE TRIGGER IF NOT EXISTS notes_fts_insert AFTER INSERT ON notes BEGIN
+             INSERT INTO notes_fts (rowid, text) VALUES (new.id, new.text);
+         END;
+         CREATE TRIGGER IF NOT EXISTS notes_fts_delete AFTER DELETE ON notes BEGIN
+             INSERT INTO notes_fts (notes_fts, rowid, text) VALUES ('delete', old.id, old.text);
+         END;
+         CREATE TRIGGER IF NOT EXISTS notes_fts_update AFTER UPDATE OF text ON notes BEGIN
+             INSERT INTO notes_fts (notes_fts, rowid, text) VALUES ('delete', old.id, old.text);
+             INSERT INTO notes_fts (rowid, text) VALUES (new.id, new.text);
+         END;",
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS scenes (
+            id INTEGER PRIMARY KEY,
+            image_id INTEGER NOT NULL REFERENCES images(id),
+            start_secs REAL NOT NULL,
+            end_secs REAL NOT NULL,
+            description TEXT NOT NULL,
+            keywords TEXT NOT NULL
+        )",
+        [],
+    )?;
+    tx.execute_batch(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS scenes_fts USING fts5(description, keywords, content = 'scenes', content_rowid = 'id');
+         CREATE TRIGGER IF NOT EXISTS scenes_fts_insert AFTER INSERT ON scenes BEGIN
+             INSERT INTO scenes_fts (rowid, description, keywords) VALUES (new.id, new.description, new.keywords);
+         END;
+         CREATE TRIGGER IF NOT EXISTS scenes_fts_delete AFTER DELETE ON scenes BEGIN
+             INSERT INTO scenes_fts (scenes_fts, rowid, description, keywords) VALUES ('delete', old.id, old.description, old.keywords);
+         END;",
+    )?;
+    Ok(())
+}
+
+/// How often and when each image was last searched for or looked at (see
+/// `tiering`).
+fn image_access(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE image_access (
+            image_id INTEGER PRIMARY KEY REFERENCES images(id),
+            hits INTEGER NOT NULL,
+            last_accessed TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Rescans used to add a row per file each time. Of each path's rows the
+/// last scanned is kept, and what was attached to the others by hand
+/// (notes, corrections, trips, date shifts, accesses) moved to it; what
+/// scans attached to them goes with them.
+fn unique_paths(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TEMP TABLE replaced AS
+             SELECT id AS old, (SELECT MAX(id) FROM images newer WHERE newer.path = images.path) AS new
+             FROM images WHERE id < (SELECT MAX(id) FROM images newer WHERE newer.path = images.path);
+         UPDATE notes SET image_id = (SELECT new FROM replaced WHERE old = image_id) WHERE image_id IN (SELECT old FROM replaced);
+         UPDATE corrections SET image_id = (SELECT new FROM replaced WHERE old = image_id) WHERE image_id IN (SELECT old FROM replaced);
+         UPDATE trip_images SET image_id = (SELECT new FROM replaced WHERE old = image_id) WHERE image_id IN (SELECT old FROM replaced);
+         UPDATE date_shift_images SET image_id = (SELECT new FROM replaced WHERE old = image_id) WHERE image_id IN (SELECT old FROM replaced);
+         UPDATE OR IGNORE image_access SET image_id = (SELECT new FROM replaced WHERE old = image_id) WHERE image_id IN (SELECT old FROM replaced);
+         DELETE FROM image_access WHERE image_id IN (SELECT old FROM replaced);
+         DELETE FROM external_metadata WHERE image_id IN (SELECT old FROM replaced);
+         DELETE FROM scenes WHERE image_id IN (SELECT old FROM replaced);
+         DELETE FROM images WHERE id IN (SELECT old FROM replaced);
+         DROP TABLE replaced;
+         CREATE UNIQUE INDEX images_path ON images (path);",
+    )
+}
+
+/// A version per image, counted up by edits made by hand, and the log of
+/// those edits (see `edits`).
+fn edit_versions(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE images ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+         CREATE TABLE edits (
+             id INTEGER PRIMARY KEY,
+             image_id INTEGER NOT NULL REFERENCES images(id),
+             version INTEGER NOT NULL,
+             seen INTEGER,
+             source TEXT NOT NULL,
+             description TEXT,
+             keywords TEXT,
+             previous_description TEXT,
+             previous_keywords TEXT,
+             conflict INTEGER NOT NULL,
+             edited_at TEXT NOT NULL
+         );
+         CREATE INDEX edits_image ON edits (image_id, version);",
+    )
+}
+
+/// When `prune --archive` found an image's file gone, if it has.
+fn archived_rows(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE images ADD COLUMN archived_at TEXT")
+}
+
+/// Changes made through the API (see `audit`), which can be added to but
+/// not changed.
+fn audit_log(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE audit (
+             id INTEGER PRIMARY KEY,
+             at TEXT NOT NULL,
+             actor TEXT NOT NULL,
+             action TEXT NOT NULL,
+             target TEXT NOT NULL,
+             before TEXT,
+             after TEXT
+         );
+         CREATE TRIGGER audit_no_update BEFORE UPDATE ON audit BEGIN SELECT RAISE(ABORT, 'the audit log is append-only'); END;
+         CREATE TRIGGER audit_no_delete BEFORE DELETE ON audit BEGIN SELECT RAISE(ABORT, 'the audit log is append-only'); END;",
+    )
+}
+
+/// Settings of the web page, per user (see `preferences`).
+fn preferences(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE preferences (
+             user TEXT NOT NULL,
+             name TEXT NOT NULL,
+             value TEXT NOT NULL,
+             PRIMARY KEY (user, name)
+         )",
+    )
+}
+
+/// Keywords move from the `images.keywords` list to `tags`, a row per
+/// keyword whatever its case, and `image_tags`, in the order the list had
+/// them. The lists are split as the model writes them, so the commas,
+/// semicolons and bullets it mixes all come out the same.
+fn tags_table(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE tags (
+             id INTEGER PRIMARY KEY,
+             name TEXT NOT NULL UNIQUE COLLATE NOCASE
+         );
+         CREATE TABLE image_tags (
+             image_id INTEGER NOT NULL REFERENCES images(id),
+             tag_id INTEGER NOT NULL REFERENCES tags(id),
+             position INTEGER NOT NULL,
+             PRIMARY KEY (image_id, tag_id)
+         );
+         CREATE INDEX image_tags_tag ON image_tags (tag_id);",
+    )?;
+    let lists: Vec<(i64, String)> = tx.prepare("SELECT id, keywords FROM images WHERE keywords IS NOT NULL")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    let mut tag = tx.prepare("INSERT INTO tags (name) VALUES (?1) ON CONFLICT (name) DO UPDATE SET name = name RETURNING id")?;
+    let mut link = tx.prepare("INSERT INTO image_tags (image_id, tag_id, position) VALUES (?1, ?2, ?3)")?;
+    for (id, list) in lists {
+        for (position, name) in crate::keywords::split(&list).iter().enumerate() {
+            let tag_id: i64 = tag.query_row([name], |row| row.get(0))?;
+            link.execute(params![id, tag_id, position])?;
+        }
+    }
+    tx.execute_batch("ALTER TABLE images DROP COLUMN keywords")
+}
+
+/// Albums made in the catalog (see `albums`), and the photos in them.
+fn albums(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE albums (
+             id INTEGER PRIMARY KEY,
+             name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+             created_at TEXT NOT NULL
+         );
+         CREATE TABLE album_images (
+             album_id INTEGER NOT NULL REFERENCES albums(id),
+             image_id INTEGER NOT NULL REFERENCES images(id),
+             added_at TEXT NOT NULL,
+             PRIMARY KEY (album_id, image_id)
+         );
+         CREATE INDEX album_images_image ON album_images (image_id);",
+    )
+}
+
+/// The web page's histograms (see `histogram`), with the content hash of
+/// the file each was made from.
+fn histograms(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE histograms (
+             image_id INTEGER PRIMARY KEY REFERENCES images(id),
+             content_hash TEXT,
+             levels TEXT NOT NULL
+         )",
+    )
+}
+
+/// The catalog's own star ratings (0 for none), favorites and rejects
+/// (see `culling`).
+fn culling(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE images ADD COLUMN rating INTEGER NOT NULL DEFAULT 0;
+         ALTER TABLE images ADD COLUMN favorite INTEGER NOT NULL DEFAULT 0;
+         ALTER TABLE images ADD COLUMN rejected INTEGER NOT NULL DEFAULT 0;",
+    )
+}
+
+/// ZIPs of originals `serve` makes in the background, one per operation
+/// (see `downloads`).
+fn downloads(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE downloads (
+             operation_id INTEGER PRIMARY KEY REFERENCES operations(id),
+             user TEXT NOT NULL,
+             images INTEGER NOT NULL,
+             created_at INTEGER NOT NULL
+         )",
+    )
+}
+
+/// The folders scanned, by name, and which each photo is under (see
+/// `sources`).
+fn sources(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE sources (
+             id INTEGER PRIMARY KEY,
+             name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+             path TEXT NOT NULL UNIQUE,
+             added_at TEXT NOT NULL,
+             scanned_at TEXT
+         );
+         ALTER TABLE images ADD COLUMN source_id INTEGER REFERENCES sources(id);
+         CREATE INDEX images_source ON images (source_id);",
+    )
+}
+
+/// Each folder's scans and the files they've saved, so a scan that
+/// stopped can be resumed (see `resume`).
+fn scan_sessions(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE scan_sessions (
+             id INTEGER PRIMARY KEY,
+             dir TEXT NOT NULL,
+             operation_id INTEGER REFERENCES operations(id),
+             started_at TEXT NOT NULL,
+             finished_at TEXT
+         );
+         CREATE INDEX scan_sessions_dir ON scan_sessions (dir);
+         CREATE TABLE scan_files (
+             session_id INTEGER NOT NULL REFERENCES scan_sessions(id),
+             path TEXT NOT NULL,
+             PRIMARY KEY (session_id, path)
+         );",
+    )
+}
+
+/// When each source's share stopped answering, while it's away.
+fn offline_sources(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE sources ADD COLUMN offline_since TEXT;")
+}
+
+/// Broken files, by path, since a file that can't be read has no row.
+fn problems(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE problems (
+             path TEXT NOT NULL,
+             category TEXT NOT NULL,
+             message TEXT NOT NULL,
+             first_seen TEXT NOT NULL,
+             last_seen TEXT NOT NULL,
+             attempts INTEGER NOT NULL,
+             PRIMARY KEY (path, category)
+         );",
+    )
+}
+
+/// Custom fields, with the kind each key was declared as.
+fn fields(tx: &Connection) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE field_keys (
+             key TEXT PRIMARY KEY COLLATE NOCASE,
+             kind TEXT NOT NULL
+         );
+         CREATE TABLE fields (
+             image_id INTEGER NOT NULL REFERENCES images(id),
+             key TEXT NOT NULL COLLATE NOCASE REFERENCES field_keys(key),
+             value TEXT NOT NULL,
+             number REAL,
+             PRIMARY KEY (image_id, key)
+         );
+         CREATE INDEX fields_key ON fields (key, value);",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A new catalog as this build would have made it at `version`.
+    fn migrate_to(conn: &Connection, version: i64) -> Result<(), Error> {
+        super::version(conn)?;
+        for migration in MIGRATIONS.iter().filter(|migration| migration.version <= version) {
+            (migration.apply)(conn)?;
+            conn.execute(
+                "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, datetime('now'))",
+                params![migration.version, migration.description],
+            )?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        migrate(&conn)?;
+        assert_eq!(version(&conn)?, latest());
+        // Nothing to do the second time
+        migrate(&conn)?;
+        let applied: i64 = conn.query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))?;
+        assert_eq!(applied, latest());
+
+        conn.execute("INSERT INTO schema_version (version, description, applied_at) VALUES (?1, 'from the future', '')", [latest() + 1])?;
+        let error = migrate(&conn).unwrap_err();
+        assert_eq!(crate::error::classify(&error), ErrorKind::Config);
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_old_catalog() -> Result<(), Error> {
+        // As the first releases made it, with a photo in it
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE images (id INTEGER PRIMARY KEY, path TEXT NOT NULL, file_name TEXT NOT NULL, file_size INTEGER NOT NULL,
+                                  creation_date TEXT, keywords TEXT, description TEXT);
+             INSERT INTO images (path, file_name, file_size, keywords) VALUES ('/beach.jpg', 'beach.jpg', 1, 'beach');",
+        )?;
+        migrate(&conn)?;
+        let (keywords, tier): (String, Option<String>) = conn.query_row(
+            &format!("SELECT {}, tier FROM images", crate::tags::KEYWORDS), [], |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        assert_eq!((keywords.as_str(), tier), ("beach", None));
+        let filter = crate::SearchFilter { keyword: Some(String::from("beach")), ..crate::SearchFilter::default() };
+        assert_eq!(crate::search_images(&conn, &filter)?, vec!["/beach.jpg"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unique_paths() -> Result<(), Error> {
+        // Scanned twice at version 2, with a note on the first row
+        let conn = Connection::open_in_memory()?;
+        migrate_to(&conn, 2)?;
+        conn.execute_batch(
+            "INSERT INTO images (id, path, file_name, file_size, description) VALUES
+                 (1, '/beach.jpg', 'beach.jpg', 1, NULL), (2, '/cat.jpg', 'cat.jpg', 1, NULL), (3, '/beach.jpg', 'beach.jpg', 1, 'Sand');
+             INSERT INTO external_metadata (image_id, source, field, value) VALUES (1, 'xmp', 'rating', '3'), (3, 'xmp', 'rating', '3');
+             INSERT INTO notes (image_id, text, created_at) VALUES (1, 'Low tide', '2024-05-01');",
+        )?;
+        migrate(&conn)?;
+        let rows: Vec<(i64, String)> = conn.prepare("SELECT id, path FROM images ORDER BY id")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(rows, vec![(2, String::from("/cat.jpg")), (3, String::from("/beach.jpg"))]);
+        let note: i64 = conn.query_row("SELECT image_id FROM notes", [], |row| row.get(0))?;
+        let external: i64 = conn.query_row("SELECT COUNT(*) FROM external_metadata", [], |row| row.get(0))?;
+        assert_eq!((note, external), (3, 1));
+        assert!(conn.execute("INSERT INTO images (path, file_name, file_size) VALUES ('/cat.jpg', 'cat.jpg', 1)", []).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tags_table() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        migrate_to(&conn, 7)?;
+        conn.execute_batch(
+            "INSERT INTO images (path, file_name, file_size, keywords) VALUES
+                 ('/beach.jpg', 'beach.jpg', 1, 'Beach; sea\n- sand, beach'),
+                 ('/dog.jpg', 'dog.jpg', 1, 'dog, beach'),
+                 ('/cat.jpg', 'cat.jpg', 1, NULL);",
+        )?;
+        migrate(&conn)?;
+        let lists: Vec<Option<String>> = conn.prepare(&format!("SELECT {} FROM images ORDER BY id", crate::tags::KEYWORDS))?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lists, vec![Some(String::from("Beach, sea, sand")), Some(String::from("dog, Beach")), None]);
+        let tags: Vec<String> = conn.prepare("SELECT name FROM tags ORDER BY id")?.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+        assert_eq!(tags, vec!["Beach", "sea", "sand", "dog"]);
+        assert!(conn.prepare("SELECT keywords FROM images").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+//! Files moved or renamed since they were catalogued. A file found under a
+//! new path with the content hash of a catalogued one whose file is gone
+//! is the same file: its row follows it, keeping the analysis, notes and
+//! everything else, instead of the file being processed again as new and
+//! the old row left pointing nowhere.
+
+use std::collections::HashMap;
+use std::path::Path;
+use anyhow::Error;
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::hash;
+use crate::share::Shares;
+
+/// The catalogued files that are gone, by size, so only files of the same
+/// size need hashing.
+pub struct Missing {
+    by_size: HashMap<u64, Vec<(i64, String)>>,
+}
+
+impl Missing {
+    /// Look for catalogued files that aren't where they were, and not on a
+    /// share that's offline either.
+    pub fn find(conn: &Connection, shares: &mut Shares) -> Result<Missing, Error> {
+        let mut by_size: HashMap<u64, Vec<(i64, String)>> = HashMap::new();
+        let mut stmt = conn.prepare("SELECT id, path, file_size, content_hash FROM images WHERE content_hash IS NOT NULL")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let path: String = row.get(1)?;
+            if shares.gone(Path::new(&path)) {
+                by_size.entry(row.get(2)?).or_default().push((row.get(0)?, row.get(3)?));
+            }
+        }
+        Ok(Missing { by_size })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_size.values().all(Vec::is_empty)
+    }
+
+    /// If the file at `path` is a missing one moved there, catalog it there
+    /// and return where it was.
+    pub fn relocate(&mut self, conn: &Connection, path: &Path) -> Result<Option<String>, Error> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let Some(candidates) = path.metadata().ok().and_then(|metadata| self.by_size.get_mut(&metadata.len())) else {
+            return Ok(None);
+        };
+        let new_path = path.to_string_lossy();
+        let known: bool = conn.query_row("SELECT EXISTS(SELECT 1 FROM images WHERE path = ?1)", [&new_path], |row| row.get(0))?;
+        if known {
+            return Ok(None);
+        }
+        let hash = hash::of_file(path)?;
+        let Some(i) = candidates.iter().position(|(_, missing)| *missing == hash) else { return Ok(None) };
+        let (id, _) = candidates.remove(i);
+        let old_path: Option<String> = conn.query_row("SELECT path FROM images WHERE id = ?1", [id], |row| row.get(0)).optional()?;
+        let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        conn.execute("UPDATE images SET path = ?1, file_name = ?2, archived_at = NULL WHERE id = ?3", params![new_path, file_name, id])?;
+        Ok(old_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_relocate() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        let (old, new, copy, other) = (dir.path().join("IMG_1.jpg"), dir.path().join("2024/tram.jpg"), dir.path().join("kept.jpg"), dir.path().join("other.jpg"));
+        fs::create_dir(dir.path().join("2024"))?;
+        for path in [&new, &copy] {
+            fs::write(path, "tram")?;
+        }
+        fs::write(&other, "boat")?;
+        let hash = hash::of_bytes(b"tram");
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, content_hash, description) VALUES (?1, 'IMG_1.jpg', 4, ?3, 'A tram'), (?2, 'kept.jpg', 4, ?3, NULL)",
+            params![old.to_string_lossy(), copy.to_string_lossy(), hash],
+        )?;
+
+        let mut missing = Missing::find(&conn, &mut Shares::new(&Default::default()))?;
+        // Same size, different content
+        assert_eq!(missing.relocate(&conn, &other)?, None);
+        // Still where it was
+        assert_eq!(missing.relocate(&conn, &copy)?, None);
+        assert_eq!(missing.relocate(&conn, &new)?, Some(old.to_string_lossy().into_owned()));
+        let (path, file_name, description): (String, String, String) = conn.query_row(
+            "SELECT path, file_name, description FROM images WHERE id = 1", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        assert_eq!((path, file_name.as_str(), description.as_str()), (new.to_string_lossy().into_owned(), "tram.jpg", "A tram"));
+        assert!(missing.is_empty());
+        Ok(())
+    }
+}
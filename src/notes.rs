@@ -0,0 +1,200 @@
+//! Notes on photos, typed or spoken. People tell the story behind a photo
+//! far faster than they type it, so a voice memo can be attached and is
+//! transcribed by a whisper backend (`[transcriber]`). Transcripts and typed
+//! notes go into a full-text index that `search --note` looks in.
+//!
+//! Audio files are referenced where they are, like the photos themselves.
+
+use std::path::Path;
+use std::process::Command;
+use anyhow::{anyhow, bail, Context, Error};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+use crate::config::TranscriberConfig;
+
+#[derive(Debug, PartialEq)]
+pub struct Note {
+    pub id: i64,
+    /// The photo it's attached to
+    pub path: String,
+    pub audio: Option<String>,
+    /// Typed, or transcribed from `audio`; `None` while that's still to do
+    pub text: Option<String>,
+    pub created_at: String,
+}
+
+pub fn validate(config: &TranscriberConfig) -> Result<(), Error> {
+    if config.command.is_some() && config.url.is_some() {
+        bail!("transcriber needs either a command or a url, not both");
+    }
+    if config.command.as_ref().is_some_and(Vec::is_empty) {
+        bail!("empty transcriber command");
+    }
+    Ok(())
+}
+
+/// Whether voice notes can be transcribed at all.
+pub fn can_transcribe(config: &TranscriberConfig) -> bool {
+    config.command.is_some() || config.url.is_some()
+}
+
+/// Attach a note to the photo at `path`: typed `text`, or an `audio`
+/// recording to transcribe.
+pub fn add(conn: &Connection, path: &str, audio: Option<&Path>, text: Option<&str>) -> Result<i64, Error> {
+    let image_id: i64 = conn.query_row("SELECT id FROM images WHERE path = ?1", [path], |row| row.get(0))
+        .optional()?
+        .ok_or_else(|| anyhow!("{} isn't in the catalog", path))?;
+    let audio = audio.map(|audio| audio.canonicalize().with_context(|| format!("reading {}", audio.display()))).transpose()?;
+    conn.execute(
+        "INSERT INTO notes (image_id, audio_path, text, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![image_id, audio.map(|audio| audio.to_string_lossy().into_owned()), text, chrono::Utc::now().to_rfc3339()],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// The notes on the photo at `path`, or on every photo, oldest first.
+pub fn list(conn: &Connection, path: Option<&str>) -> Result<Vec<Note>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT notes.id, images.path, notes.audio_path, notes.text, notes.created_at
+         FROM notes JOIN images ON images.id = notes.image_id
+         WHERE ?1 IS NULL OR images.path = ?1
+         ORDER BY notes.created_at, notes.id",
+    )?;
+    let notes = stmt.query_map([path], |row| {
+        Ok(Note { id: row.get(0)?, path: row.get(1)?, audio: row.get(2)?, text: row.get(3)?, created_at: row.get(4)? })
+    })?;
+    Ok(notes.collect::<Result<_, _>>()?)
+}
+
+/// Transcribe the voice notes that haven't been. Returns how many were,
+/// and how many failed (reported as they do).
+pub fn transcribe_pending(conn: &Connection, config: &TranscriberConfig) -> Result<(usize, usize), Error> {
+    let pending: Vec<(i64, String)> = conn
+        .prepare("SELECT id, audio_path FROM notes WHERE text IS NULL AND audio_path IS NOT NULL ORDER BY id")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    let (mut done, mut failed) = (0, 0);
+    for (id, audio) in pending {
+        match transcribe(config, Path::new(&audio)) {
+            Ok(text) => {
+                conn.execute("UPDATE notes SET text = ?1 WHERE id = ?2", params![text, id])?;
+                done += 1;
+            }
+            Err(e) => {
+                tracing::warn!("Can't transcribe {}: {}", audio, e);
+                failed += 1;
+            }
+        }
+    }
+    Ok((done, failed))
+}
+
+/// The words of the recording at `audio`.
+pub fn transcribe(config: &TranscriberConfig, audio: &Path) -> Result<String, Error> {
+    let text = match (&config.command, &config.url) {
+        (Some(command), _) => run(command, audio)?,
+        (None, Some(url)) => tokio::runtime::Runtime::new()?.block_on(request(config, url, audio))?,
+        (None, None) => bail!("no transcriber configured; set transcriber.command or transcriber.url"),
+    };
+    // whisper.cpp marks each segment with its times unless told not to
+    let lines: Vec<&str> = text.lines()
+        .map(|line| match line.trim().strip_prefix('[') {
+            Some(timed) => timed.split_once(']').map_or(line, |(_, words)| words).trim(),
+            None => line.trim(),
+        })
+        .filter(|line| !line.is_empty())
+        .collect();
+    Ok(lines.join(" "))
+}
+
+fn run(command: &[String], audio: &Path) -> Result<String, Error> {
+    let Some((program, args)) = command.split_first() else { bail!("empty transcriber command") };
+    let args: Vec<String> = args.iter().map(|arg| arg.replace("{audio}", &audio.to_string_lossy())).collect();
+    let output = Command::new(program).args(&args).output().with_context(|| format!("running {}", program))?;
+    if !output.status.success() {
+        bail!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+async fn request(config: &TranscriberConfig, url: &str, audio: &Path) -> Result<String, Error> {
+    let name = audio.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let file = reqwest::multipart::Part::bytes(std::fs::read(audio)?).file_name(name);
+    let form = reqwest::multipart::Form::new().text("model", config.model.clone()).part("file", file);
+    let mut request = reqwest::Client::new()
+        .post(format!("{}/v1/audio/transcriptions", url.trim_end_matches('/')))
+        .multipart(form);
+    if let Some(var) = &config.api_key_env {
+        request = request.bearer_auth(std::env::var(var).map_err(|_| anyhow!("environment variable {} is not set", var))?);
+    }
+    let response: Value = request.send().await?.error_for_status()?.json().await?;
+    response["text"].as_str().map(str::to_string).ok_or_else(|| anyhow!("no text in the transcription response"))
+}
+
+/// A full-text query for notes with all of `words`, quoted so that
+/// punctuation in them isn't taken for query syntax.
+pub fn match_query(words: &str) -> String {
+    words.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use mockito::Server;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_notes() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute("INSERT INTO images (path, file_name, file_size) VALUES ('/cake.jpg', 'cake.jpg', 1), ('/beach.jpg', 'beach.jpg', 1)", [])?;
+        let dir = tempdir()?;
+        let memo = dir.path().join("memo.m4a");
+        fs::write(&memo, b"audio")?;
+
+        add(&conn, "/beach.jpg", None, Some("Our first trip to the sea"))?;
+        add(&conn, "/cake.jpg", Some(&memo), None)?;
+        assert!(add(&conn, "/missing.jpg", None, Some("x")).is_err());
+
+        let mut server = Server::new();
+        let mock = server.mock("POST", "/v1/audio/transcriptions")
+            .match_body(mockito::Matcher::Regex(String::from("whisper-1")))
+            .with_body(r#"{"text": " Grandma baked this for Ana's fifth birthday."}"#)
+            .create();
+        let config = TranscriberConfig { url: Some(server.url()), ..TranscriberConfig::default() };
+        assert_eq!(transcribe_pending(&conn, &config)?, (1, 0));
+        mock.assert();
+        assert_eq!(transcribe_pending(&conn, &config)?, (0, 0));
+
+        let notes = list(&conn, Some("/cake.jpg"))?;
+        assert_eq!(notes[0].text.as_deref(), Some("Grandma baked this for Ana's fifth birthday."));
+        assert_eq!(list(&conn, None)?.len(), 2);
+
+        let search = |words: &str| -> Result<Vec<String>, Error> {
+            let filter = crate::SearchFilter { note: Some(words.to_string()), ..crate::SearchFilter::default() };
+            Ok(crate::search_images(&conn, &filter)?)
+        };
+        assert_eq!(search("birthday grandma")?, vec!["/cake.jpg"]);
+        assert_eq!(search("sea")?, vec!["/beach.jpg"]);
+        assert!(search("birthday sea")?.is_empty());
+        // Not query syntax
+        assert_eq!(search("Ana's AND")?, Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcribe_with_command() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let memo = dir.path().join("memo.wav");
+        fs::write(&memo, "[00:00:00.000 --> 00:00:02.000]  This was the summer\n[00:00:02.000 --> 00:00:03.500]  we moved.\n")?;
+        let config = TranscriberConfig { command: Some(vec![String::from("cat"), String::from("{audio}")]), ..TranscriberConfig::default() };
+        assert_eq!(transcribe(&config, &memo)?, "This was the summer we moved.");
+
+        assert!(validate(&TranscriberConfig { url: Some(String::from("http://localhost:8080")), ..config }).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! EXIF orientation: how a camera says its pixels should be turned for
+//! display. The catalog records the tag and the dimensions as displayed,
+//! and the images we generate have the turn applied to their pixels.
+
+use std::io::Cursor;
+use exif::{Exif, In, Reader, Tag};
+use image::DynamicImage;
+
+/// The Orientation tag (1 to 8), if present and valid.
+pub fn read(exif: &Exif) -> Option<u16> {
+    let value = exif.get_field(Tag::Orientation, In::PRIMARY)?.value.get_uint(0)?;
+    u16::try_from(value).ok().filter(|value| (1..=8).contains(value))
+}
+
+/// The orientation in a file's own EXIF, in any format the EXIF reader
+/// takes.
+pub fn read_from(data: &[u8]) -> Option<u16> {
+    read(&Reader::new().read_from_container(&mut Cursor::new(data)).ok()?)
+}
+
+/// Whether displaying the image turns it a quarter, swapping width and
+/// height.
+pub fn swaps_dimensions(orientation: u16) -> bool {
+    (5..=8).contains(&orientation)
+}
+
+/// `(width, height)` as displayed.
+pub fn displayed((width, height): (u32, u32), orientation: Option<u16>) -> (u32, u32) {
+    match orientation {
+        Some(orientation) if swaps_dimensions(orientation) => (height, width),
+        _ => (width, height),
+    }
+}
+
+/// The image with the turn (and mirroring) applied to its pixels.
+pub fn upright(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::Value;
+    use image::{GenericImageView, Rgba, RgbaImage};
+    use crate::test_support::*;
+
+    #[test]
+    fn test_orientation() {
+        let jpeg = jpeg_with_exif(&[field(Tag::Orientation, Value::Short(vec![6]))]);
+        assert_eq!(read_from(&jpeg), Some(6));
+        assert_eq!(read_from(&jpeg_with_exif(&[field(Tag::Orientation, Value::Short(vec![9]))])), None);
+        assert_eq!(displayed((4000, 3000), Some(6)), (3000, 4000));
+        assert_eq!(displayed((4000, 3000), Some(3)), (4000, 3000));
+
+        // Red on the left, blue on the right, as the sensor saw it
+        let (red, blue) = (Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]));
+        let mut sensor = RgbaImage::new(2, 1);
+        sensor.put_pixel(0, 0, red);
+        sensor.put_pixel(1, 0, blue);
+        let sensor = DynamicImage::ImageRgba8(sensor);
+        for (orientation, size, top_left) in [(1, (2, 1), red), (2, (2, 1), blue), (3, (2, 1), blue), (5, (1, 2), red), (6, (1, 2), red), (8, (1, 2), blue)] {
+            let img = upright(sensor.clone(), orientation);
+            assert_eq!((img.dimensions(), img.get_pixel(0, 0)), (size, top_left), "orientation {}", orientation);
+        }
+    }
+}
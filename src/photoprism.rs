@@ -0,0 +1,181 @@
+//! YAML sidecars for PhotoPrism. PhotoPrism keeps one per photo under its
+//! sidecar folder, at the photo's path within the originals folder with a
+//! `.yml` extension, and its indexer reads them back in. Written there
+//! before indexing, they bring in the AI captions and keywords, so
+//! PhotoCataloger can tag a library offline ahead of PhotoPrism.
+//!
+//! PhotoPrism writes these files itself, as backups of what it knows, so
+//! only files written here are ever replaced.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::Error;
+use rusqlite::{params_from_iter, Connection};
+use crate::caption::{self, Template};
+use crate::progress::Operation;
+use crate::{keywords, tags, SearchFilter};
+
+/// The first line of every sidecar written here.
+const MARKER: &str = "# Written by PhotoCataloger";
+
+/// What goes into one sidecar.
+#[derive(Debug, Default)]
+struct Sidecar {
+    taken: Option<String>,
+    gps: Option<(f64, f64)>,
+    caption: Option<String>,
+    keywords: Vec<String>,
+}
+
+/// A YAML scalar: JSON strings are valid double-quoted YAML.
+fn quoted(text: &str) -> String {
+    serde_json::to_string(text).unwrap_or_default()
+}
+
+fn render(sidecar: &Sidecar) -> String {
+    let mut yaml = format!("{}\n", MARKER);
+    // Catalog dates are local time, which is what PhotoPrism assumes for
+    // photos without a time zone too
+    if let Some(taken) = sidecar.taken.as_deref().and_then(|date| chrono::NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S").ok()) {
+        let taken = taken.format("%Y-%m-%dT%H:%M:%SZ");
+        yaml.push_str(&format!("TakenAt: {}\nTakenAtLocal: {}\nTakenSrc: meta\n", taken, taken));
+    }
+    if let Some((latitude, longitude)) = sidecar.gps {
+        yaml.push_str(&format!("Lat: {}\nLng: {}\n", latitude, longitude));
+    }
+    // Set as if by hand, so reindexing the files doesn't overwrite them
+    if let Some(caption) = &sidecar.caption {
+        yaml.push_str(&format!("Caption: {}\nCaptionSrc: manual\n", quoted(caption)));
+    }
+    if !sidecar.keywords.is_empty() {
+        yaml.push_str(&format!("Details:\n  Keywords: {}\n  KeywordsSrc: manual\n", quoted(&sidecar.keywords.join(", "))));
+    }
+    yaml
+}
+
+/// Where PhotoPrism looks for the sidecar of `image`, if it's inside
+/// `originals`.
+fn sidecar_path(image: &Path, originals: &Path, sidecars: &Path) -> Option<PathBuf> {
+    let relative = image.strip_prefix(originals).ok()?;
+    Some(sidecars.join(relative).with_extension("yml"))
+}
+
+/// Write sidecars into `sidecars` for the analyzed photos matching
+/// `filter` that are inside `originals`, PhotoPrism's originals folder.
+/// Returns how many were written and how many photos were skipped.
+pub fn export_sidecars(
+    conn: &Connection,
+    operation: &Operation,
+    filter: &SearchFilter,
+    template: Option<&Template>,
+    originals: &Path,
+    sidecars: &Path,
+) -> Result<(usize, usize), Error> {
+    let (clause, params) = crate::filter_clause(filter);
+    let analyzed = format!("(description IS NOT NULL OR {} IS NOT NULL)", tags::KEYWORDS);
+    let clause = match clause.is_empty() {
+        true => format!(" WHERE {}", analyzed),
+        false => format!("{} AND {}", clause, analyzed),
+    };
+    // With the caption still to be formatted
+    let rows: Vec<(i64, String, Option<String>, Sidecar)> = conn
+        .prepare(&format!(
+            "SELECT id, path, description, creation_date, latitude, longitude, {} FROM images{} ORDER BY path",
+            tags::KEYWORDS, clause,
+        ))?
+        .query_map(params_from_iter(params), |row| {
+            let sidecar = Sidecar {
+                taken: row.get(3)?,
+                gps: row.get::<_, Option<f64>>(4)?.zip(row.get(5)?),
+                caption: None,
+                keywords: row.get::<_, Option<String>>(6)?.as_deref().map(keywords::split).unwrap_or_default(),
+            };
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, sidecar))
+        })?
+        .collect::<Result<_, _>>()?;
+    operation.set_total(conn, rows.len())?;
+
+    let (mut written, mut skipped) = (0, 0);
+    for (id, path, description, mut sidecar) in rows {
+        operation.checkpoint(conn)?;
+        let Some(target) = sidecar_path(Path::new(&path), originals, sidecars) else {
+            tracing::warn!("Skipping {}: not in {}", path, originals.display());
+            skipped += 1;
+            operation.advance(conn, false)?;
+            continue;
+        };
+        if fs::read_to_string(&target).is_ok_and(|existing| !existing.starts_with(MARKER)) {
+            tracing::warn!("Skipping {}: {} is PhotoPrism's own", path, target.display());
+            skipped += 1;
+            operation.advance(conn, false)?;
+            continue;
+        }
+        sidecar.caption = caption::caption(conn, id, template, description)?;
+        let result = target.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::write(&target, render(&sidecar)));
+        match &result {
+            Ok(_) => written += 1,
+            Err(e) => {
+                tracing::error!("Error writing {}: {}", target.display(), e);
+                skipped += 1;
+            }
+        }
+        operation.advance(conn, result.is_ok())?;
+    }
+    Ok((written, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::CancelToken;
+    use crate::progress;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_export_sidecars() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, creation_date, latitude, longitude, description) VALUES
+                ('/photos/2023/07/IMG_1.jpg', 'IMG_1.jpg', 1, '2023-07-14 18:42:07', 38.7111, -9.1302, 'Tram 28 on a \"steep\" street'),
+                ('/photos/2023/07/IMG_2.heic', 'IMG_2.heic', 1, NULL, NULL, NULL, NULL),
+                ('/photos/2023/07/IMG_3.jpg', 'IMG_3.jpg', 1, NULL, NULL, NULL, NULL),
+                ('/elsewhere/IMG_4.jpg', 'IMG_4.jpg', 1, NULL, NULL, NULL, 'A cat'),
+                ('/photos/IMG_5.jpg', 'IMG_5.jpg', 1, NULL, NULL, NULL, NULL)",
+            [],
+        )?;
+        crate::test_support::set_keywords(&conn, &[
+            ("/photos/2023/07/IMG_1.jpg", "tram, Lisbon"), ("/photos/2023/07/IMG_2.heic", "sea"), ("/photos/2023/07/IMG_3.jpg", "cake"),
+        ]);
+        let dir = tempdir()?;
+        let sidecars = dir.path().join("sidecar");
+        // Written by PhotoPrism, so left alone
+        fs::create_dir_all(sidecars.join("2023/07"))?;
+        fs::write(sidecars.join("2023/07/IMG_3.yml"), "TakenAt: 2023-07-15T10:00:00Z\n")?;
+
+        let (written, skipped) = progress::track(&conn, "export", &CancelToken::new(), |operation| {
+            export_sidecars(&conn, operation, &SearchFilter::default(), None, Path::new("/photos"), &sidecars)
+        })?;
+        assert_eq!((written, skipped), (2, 2));
+        assert_eq!(fs::read_to_string(sidecars.join("2023/07/IMG_1.yml"))?, "# Written by PhotoCataloger
+TakenAt: 2023-07-14T18:42:07Z
+TakenAtLocal: 2023-07-14T18:42:07Z
+TakenSrc: meta
+Lat: 38.7111
+Lng: -9.1302
+Caption: \"Tram 28 on a \\\"steep\\\" street\"
+CaptionSrc: manual
+Details:
+  Keywords: \"tram, Lisbon\"
+  KeywordsSrc: manual
+");
+        assert!(fs::read_to_string(sidecars.join("2023/07/IMG_2.yml"))?.contains("Keywords: \"sea\""));
+        assert_eq!(fs::read_to_string(sidecars.join("2023/07/IMG_3.yml"))?, "TakenAt: 2023-07-15T10:00:00Z\n");
+
+        // Ours are replaced on the next export
+        assert_eq!(progress::track(&conn, "export", &CancelToken::new(), |operation| {
+            export_sidecars(&conn, operation, &SearchFilter::default(), None, Path::new("/photos"), &sidecars)
+        })?, (2, 2));
+        Ok(())
+    }
+}
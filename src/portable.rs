@@ -0,0 +1,267 @@
+//! The catalog as JSON, for backups, diffs and moving between machines:
+//! `export --json` writes one object per photo (every column but the local
+//! id, plus its keywords and external metadata), and `import` merges such
+//! a file back in. Photos are matched by content hash, so a record finds
+//! its photo even after it moved; records without a hash are matched by
+//! path.
+
+use std::collections::BTreeSet;
+use std::io::Write;
+use anyhow::{anyhow, bail, Context, Error};
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use serde_json::{Map, Value as Json};
+use crate::{hash, tags, SearchFilter};
+
+const EXTERNAL_KEY: &str = "external_metadata";
+/// The keywords, as a list, as when they were a column of `images`
+const KEYWORDS_KEY: &str = "keywords";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JsonFormat {
+    /// One array holding every record
+    Array,
+    /// One record per line
+    Lines,
+}
+
+/// Columns of the images table, in order.
+fn columns(conn: &Connection) -> Result<Vec<String>, Error> {
+    let columns = conn.prepare("SELECT name FROM pragma_table_info('images')")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(columns)
+}
+
+/// A column's value as JSON. Blobs aren't kept in the images table.
+pub fn to_json(value: ValueRef) -> Json {
+    match value {
+        ValueRef::Null | ValueRef::Blob(_) => Json::Null,
+        ValueRef::Integer(n) => Json::from(n),
+        ValueRef::Real(x) => serde_json::Number::from_f64(x).map_or(Json::Null, Json::Number),
+        ValueRef::Text(text) => Json::String(String::from_utf8_lossy(text).into_owned()),
+    }
+}
+
+/// Write the photos matching `filter` to `out`. Photos cataloged before
+/// content hashes were recorded get theirs now, if the file is still
+/// there. Returns the number of records written.
+pub fn export_json(conn: &Connection, filter: &SearchFilter, format: JsonFormat, out: &mut impl Write) -> Result<usize, Error> {
+    let columns = columns(conn)?;
+    let (clause, params) = crate::filter_clause(filter);
+    let mut stmt = conn.prepare(&format!("SELECT {}, {} FROM images{} ORDER BY path, id", columns.join(", "), tags::KEYWORDS, clause))?;
+    let mut rows = stmt.query(params_from_iter(params))?;
+    let mut records = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut record = Map::new();
+        let mut id = 0;
+        for (i, column) in columns.iter().enumerate() {
+            let value = to_json(row.get_ref(i)?);
+            match column.as_str() {
+                // Local to this catalog
+                "id" => id = value.as_i64().unwrap_or_default(),
+                "source_id" => {}
+                _ => { record.insert(column.clone(), value); }
+            }
+        }
+        record.insert(String::from(KEYWORDS_KEY), to_json(row.get_ref(columns.len())?));
+        records.push((id, record));
+    }
+    drop(rows);
+
+    let mut external = conn.prepare("SELECT source, field, value FROM external_metadata WHERE image_id = ?1 ORDER BY rowid")?;
+    for (i, (id, record)) in records.iter_mut().enumerate() {
+        let id = *id;
+        if record.get("content_hash").is_none_or(Json::is_null) {
+            if let Some(hash) = record.get("path").and_then(Json::as_str).and_then(|path| hash::of_file(path.as_ref()).ok()) {
+                conn.execute("UPDATE images SET content_hash = ?1 WHERE id = ?2", params![hash, id])?;
+                record.insert(String::from("content_hash"), Json::String(hash));
+            }
+        }
+        let entries: Vec<Json> = external.query_map([id], |row| {
+            Ok(serde_json::json!({ "source": row.get::<_, String>(0)?, "field": row.get::<_, String>(1)?, "value": row.get::<_, String>(2)? }))
+        })?.collect::<Result<_, _>>()?;
+        record.insert(String::from(EXTERNAL_KEY), Json::Array(entries));
+
+        match format {
+            JsonFormat::Lines => writeln!(out, "{}", serde_json::to_string(record)?)?,
+            JsonFormat::Array => {
+                out.write_all(if i == 0 { b"[\n" } else { b",\n" })?;
+                out.write_all(serde_json::to_string(record)?.as_bytes())?;
+            }
+        }
+    }
+    if format == JsonFormat::Array {
+        out.write_all(if records.is_empty() { b"[]\n" } else { b"\n]\n" })?;
+    }
+    Ok(records.len())
+}
+
+/// The outcome of `import_json`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Imported {
+    pub inserted: usize,
+    pub updated: usize,
+    /// Fields this catalog has no column for, e.g. from a newer version
+    pub ignored_fields: BTreeSet<String>,
+}
+
+/// Merge records written by `export_json` (either format) into the
+/// catalog. A photo already cataloged keeps its path, and gets every other
+/// field the record has; its external metadata is replaced.
+pub fn import_json(conn: &Connection, text: &str) -> Result<Imported, Error> {
+    let records: Vec<(usize, Json)> = if text.trim_start().starts_with('[') {
+        let records: Vec<Json> = serde_json::from_str(text).context("reading JSON array")?;
+        records.into_iter().enumerate().map(|(i, record)| (i + 1, record)).collect()
+    } else {
+        text.lines().enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| Ok((i + 1, serde_json::from_str(line).with_context(|| format!("reading line {}", i + 1))?)))
+            .collect::<Result<_, Error>>()?
+    };
+
+    let columns = columns(conn)?;
+    let tx = conn.unchecked_transaction()?;
+    let mut imported = Imported::default();
+    for (number, record) in records {
+        let Json::Object(record) = record else { bail!("record {} isn't an object", number) };
+        let mut values: Vec<(&str, Value)> = Vec::new();
+        for (key, value) in &record {
+            if key == EXTERNAL_KEY || key == KEYWORDS_KEY || key == "id" {
+                continue;
+            }
+            let Some(column) = columns.iter().find(|column| *column == key) else {
+                imported.ignored_fields.insert(key.clone());
+                continue;
+            };
+            let value = match value {
+                Json::Null => Value::Null,
+                Json::Bool(b) => Value::Integer(i64::from(*b)),
+                Json::Number(n) => match n.as_i64() {
+                    Some(n) => Value::Integer(n),
+                    None => Value::Real(n.as_f64().unwrap_or_default()),
+                },
+                Json::String(s) => Value::Text(s.clone()),
+                Json::Array(_) | Json::Object(_) => bail!("record {}: {} isn't a plain value", number, key),
+            };
+            values.push((column.as_str(), value));
+        }
+        let text = |name: &str| values.iter().find_map(|(column, value)| match value {
+            Value::Text(text) if *column == name => Some(text.clone()),
+            _ => None,
+        });
+        let path = text("path").ok_or_else(|| anyhow!("record {} has no path", number))?;
+
+        let existing: Option<i64> = match text("content_hash") {
+            Some(hash) => tx.query_row("SELECT id FROM images WHERE content_hash = ?1 ORDER BY id", [hash], |row| row.get(0)).optional()?,
+            None => None,
+        };
+        let existing = match existing {
+            Some(id) => Some(id),
+            None => tx.query_row("SELECT id FROM images WHERE path = ?1 ORDER BY id", [&path], |row| row.get(0)).optional()?,
+        };
+        let id = match existing {
+            Some(id) => {
+                let updates: Vec<&(&str, Value)> = values.iter().filter(|(column, _)| *column != "path").collect();
+                if !updates.is_empty() {
+                    let assignments: Vec<String> = updates.iter().map(|(column, _)| format!("{} = ?", column)).collect();
+                    let mut params: Vec<&Value> = updates.iter().map(|(_, value)| value).collect();
+                    let id_value = Value::Integer(id);
+                    params.push(&id_value);
+                    tx.execute(&format!("UPDATE images SET {} WHERE id = ?", assignments.join(", ")), params_from_iter(params))?;
+                }
+                imported.updated += 1;
+                id
+            }
+            None => {
+                if text("file_name").is_none() {
+                    let file_name = std::path::Path::new(&path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                    values.push(("file_name", Value::Text(file_name)));
+                }
+                if !values.iter().any(|(column, _)| *column == "file_size") {
+                    values.push(("file_size", Value::Integer(0)));
+                }
+                let names: Vec<&str> = values.iter().map(|(column, _)| *column).collect();
+                let placeholders = vec!["?"; names.len()].join(", ");
+                tx.execute(
+                    &format!("INSERT INTO images ({}) VALUES ({})", names.join(", "), placeholders),
+                    params_from_iter(values.iter().map(|(_, value)| value)),
+                )?;
+                imported.inserted += 1;
+                tx.last_insert_rowid()
+            }
+        };
+
+        match record.get(KEYWORDS_KEY) {
+            Some(Json::String(keywords)) => tags::set(&tx, id, keywords)?,
+            Some(Json::Null) => tags::set(&tx, id, "")?,
+            Some(_) => bail!("record {}: {} isn't a list of keywords", number, KEYWORDS_KEY),
+            None => {}
+        }
+        if let Some(external) = record.get(EXTERNAL_KEY) {
+            tx.execute("DELETE FROM external_metadata WHERE image_id = ?1", [id])?;
+            for entry in external.as_array().ok_or_else(|| anyhow!("record {}: {} isn't a list", number, EXTERNAL_KEY))? {
+                let field = |name: &str| entry.get(name).and_then(Json::as_str).ok_or_else(|| anyhow!("record {}: external metadata without a {}", number, name));
+                tx.execute(
+                    "INSERT INTO external_metadata (image_id, source, field, value) VALUES (?1, ?2, ?3, ?4)",
+                    params![id, field("source")?, field("field")?, field("value")?],
+                )?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_import_roundtrip() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, content_hash, aperture, description) VALUES
+             ('/photos/tram.jpg', 'tram.jpg', 10, 'abc', 1.8, 'A yellow tram'),
+             ('/photos/gone.jpg', 'gone.jpg', 20, NULL, NULL, NULL)",
+            [],
+        )?;
+        crate::test_support::set_keywords(&conn, &[("/photos/tram.jpg", "tram, yellow")]);
+        conn.execute("INSERT INTO external_metadata VALUES (1, 'iptc', 'keyword', 'Lisbon')", [])?;
+
+        for format in [JsonFormat::Array, JsonFormat::Lines] {
+            let mut out = Vec::new();
+            assert_eq!(export_json(&conn, &SearchFilter::default(), format, &mut out)?, 2);
+            let text = String::from_utf8(out)?;
+            assert_eq!(text.starts_with('['), format == JsonFormat::Array);
+
+            // Into a catalog where the tram photo lives somewhere else, with
+            // no analysis yet
+            let other = Connection::open_in_memory()?;
+            crate::init_database(&other)?;
+            other.execute("INSERT INTO images (path, file_name, file_size, content_hash) VALUES ('/mnt/tram.jpg', 'tram.jpg', 10, 'abc')", [])?;
+            let imported = import_json(&other, &text)?;
+            assert_eq!((imported.inserted, imported.updated), (1, 1));
+            let (path, description, aperture, keywords): (String, String, f64, String) = other.query_row(
+                &format!("SELECT path, description, aperture, {} FROM images WHERE content_hash = 'abc'", tags::KEYWORDS),
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )?;
+            assert_eq!((path.as_str(), description.as_str(), aperture, keywords.as_str()), ("/mnt/tram.jpg", "A yellow tram", 1.8, "tram, yellow"));
+            let keyword: String = other.query_row("SELECT value FROM external_metadata WHERE image_id = 1", [], |row| row.get(0))?;
+            assert_eq!(keyword, "Lisbon");
+
+            // Importing again updates rather than duplicates
+            assert_eq!(import_json(&other, &text)?.updated, 2);
+            let count: i64 = other.query_row("SELECT COUNT(*) FROM images", [], |row| row.get(0))?;
+            assert_eq!(count, 2);
+        }
+
+        let newer = r#"{"path": "/new.jpg", "file_name": "new.jpg", "file_size": 1, "mood": "happy"}"#;
+        assert_eq!(import_json(&conn, newer)?.ignored_fields, BTreeSet::from([String::from("mood")]));
+        assert!(import_json(&conn, r#"{"file_name": "x.jpg"}"#).is_err());
+        assert!(import_json(&conn, "not json").is_err());
+        Ok(())
+    }
+}
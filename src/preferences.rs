@@ -0,0 +1,168 @@
+//! Settings the web page keeps per user (see `roles`), such as its theme,
+//! so they follow someone from one browser to the next. They're names and
+//! string values, for the page to interpret, though those making up its
+//! layout are checked first: the grid's `sort` order and `density`, the
+//! search `facets` shown, and the `landing` query it opens with (an album,
+//! or any smart album written as a query, such as `favorite:yes`).
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use anyhow::Error;
+use rusqlite::{params, Connection};
+use crate::error::{CliError, ErrorKind};
+use crate::query;
+
+/// Longest a name or value may be.
+pub const MAX_LENGTH: usize = 4096;
+pub const DENSITIES: [&str; 3] = ["small", "medium", "large"];
+/// The search fields the page can show next to the query.
+pub const FACETS: [&str; 5] = ["place", "camera", "date", "album", "rating"];
+
+/// The orders the grid can come in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sort {
+    Path,
+    Newest,
+    Oldest,
+    Rating,
+}
+
+impl Sort {
+    pub const NAMES: [&'static str; 4] = ["path", "newest", "oldest", "rating"];
+
+    pub fn parse(name: &str) -> Result<Sort, Error> {
+        match name {
+            "path" => Ok(Sort::Path),
+            "newest" => Ok(Sort::Newest),
+            "oldest" => Ok(Sort::Oldest),
+            "rating" => Ok(Sort::Rating),
+            _ => Err(CliError::new(ErrorKind::Config, format!("sort is one of {}, not {}", Sort::NAMES.join(", "), name)).into()),
+        }
+    }
+
+    /// The `ORDER BY` of `images` for it; photos with no capture time last.
+    pub fn order_by(self) -> &'static str {
+        match self {
+            Sort::Path => "path",
+            Sort::Newest => "creation_date IS NULL, creation_date DESC, path",
+            Sort::Oldest => "creation_date IS NULL, creation_date, path",
+            Sort::Rating => "rating DESC, creation_date IS NULL, creation_date, path",
+        }
+    }
+
+    /// The same order as `order_by`, for images listed as JSON from
+    /// several catalogs and put in one list.
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub fn compare(self, a: &serde_json::Value, b: &serde_json::Value) -> Ordering {
+        let path = |image: &serde_json::Value| image["path"].as_str().unwrap_or_default().to_owned();
+        let date = |image: &serde_json::Value| image["creation_date"].as_str().map(str::to_owned);
+        let undated = |image| date(image).is_none();
+        let by_path = path(a).cmp(&path(b));
+        let by_date = undated(a).cmp(&undated(b)).then_with(|| date(a).cmp(&date(b)));
+        match self {
+            Sort::Path => by_path,
+            Sort::Newest => undated(a).cmp(&undated(b)).then_with(|| date(b).cmp(&date(a))).then(by_path),
+            Sort::Oldest => by_date.then(by_path),
+            Sort::Rating => b["rating"].as_u64().cmp(&a["rating"].as_u64()).then(by_date).then(by_path),
+        }
+    }
+}
+
+/// Check `changes` before they're kept, so a page (or a script) can't save
+/// a layout it couldn't show.
+pub fn check(changes: &BTreeMap<String, String>) -> Result<(), Error> {
+    let invalid = |message: String| -> Error { CliError::new(ErrorKind::Config, message).into() };
+    for (name, value) in changes {
+        if name.len() > MAX_LENGTH || value.len() > MAX_LENGTH {
+            return Err(invalid(format!("preferences are {} bytes at most", MAX_LENGTH)));
+        }
+        match name.as_str() {
+            "sort" => {
+                Sort::parse(value)?;
+            }
+            "density" if !DENSITIES.contains(&value.as_str()) => {
+                return Err(invalid(format!("density is one of {}, not {}", DENSITIES.join(", "), value)));
+            }
+            "facets" => {
+                if let Some(facet) = value.split(',').map(str::trim).find(|facet| !facet.is_empty() && !FACETS.contains(facet)) {
+                    return Err(invalid(format!("facets are some of {}, not {}", FACETS.join(", "), facet)));
+                }
+            }
+            "landing" => {
+                query::parse(value).map_err(|e| invalid(format!("the landing view isn't a query: {}", e)))?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub fn get(conn: &Connection, user: &str) -> Result<BTreeMap<String, String>, Error> {
+    let mut stmt = conn.prepare("SELECT name, value FROM preferences WHERE user = ?1")?;
+    let preferences = stmt.query_map([user], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<_, _>>()?;
+    Ok(preferences)
+}
+
+/// Set `user`'s preferences named in `changes`, leaving the rest.
+pub fn set(conn: &Connection, user: &str, changes: &BTreeMap<String, String>) -> Result<(), Error> {
+    let tx = conn.unchecked_transaction()?;
+    let mut stmt = tx.prepare(
+        "INSERT INTO preferences (user, name, value) VALUES (?1, ?2, ?3)
+         ON CONFLICT (user, name) DO UPDATE SET value = excluded.value",
+    )?;
+    for (name, value) in changes {
+        stmt.execute(params![user, name, value])?;
+    }
+    drop(stmt);
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preferences() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        let changes = |pairs: &[(&str, &str)]| pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        set(&conn, "ana", &changes(&[("theme", "dark"), ("grid", "small")]))?;
+        set(&conn, "ana", &changes(&[("theme", "light")]))?;
+        set(&conn, "rui", &changes(&[("theme", "dark")]))?;
+        assert_eq!(get(&conn, "ana")?, changes(&[("grid", "small"), ("theme", "light")]));
+        assert_eq!(get(&conn, "avo")?, BTreeMap::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() {
+        let changes = |pairs: &[(&str, &str)]| pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        let layout = changes(&[("sort", "newest"), ("density", "large"), ("facets", "place, album"), ("landing", "album:Lisbon rating:4"), ("theme", "dark")]);
+        assert!(check(&layout).is_ok());
+        assert!(check(&changes(&[("facets", "")])).is_ok());
+        assert!(check(&changes(&[("sort", "size")])).is_err());
+        assert!(check(&changes(&[("density", "huge")])).is_err());
+        assert!(check(&changes(&[("facets", "place,lens")])).is_err());
+        assert!(check(&changes(&[("landing", "rating:9")])).is_err());
+        assert!(check(&changes(&[("theme", &"x".repeat(MAX_LENGTH + 1))])).is_err());
+        assert_eq!(Sort::parse("rating").ok(), Some(Sort::Rating));
+    }
+
+    #[test]
+    fn test_compare() {
+        let dated = serde_json::json!({ "path": "/b.jpg", "creation_date": "2021-05-01", "rating": 2 });
+        let later = serde_json::json!({ "path": "/c.jpg", "creation_date": "2023-01-01", "rating": 0 });
+        let undated = serde_json::json!({ "path": "/a.jpg", "creation_date": null, "rating": 5 });
+        let order = |sort: Sort| {
+            let mut images = vec![&dated, &later, &undated];
+            images.sort_by(|a, b| sort.compare(a, b));
+            images.into_iter().map(|image| image["path"].as_str().unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(order(Sort::Path), ["/a.jpg", "/b.jpg", "/c.jpg"]);
+        // Undated last either way
+        assert_eq!(order(Sort::Newest), ["/c.jpg", "/b.jpg", "/a.jpg"]);
+        assert_eq!(order(Sort::Oldest), ["/b.jpg", "/c.jpg", "/a.jpg"]);
+        assert_eq!(order(Sort::Rating), ["/a.jpg", "/b.jpg", "/c.jpg"]);
+    }
+}
@@ -0,0 +1,113 @@
+//! The interactive lane: work someone is waiting on (the web page's
+//! thumbnails, histograms and previews, `scan` of a single photo) goes
+//! ahead of the daemon's backfill. While it runs it holds a claim, a file
+//! in a folder beside the catalog, so claiming never waits on the write
+//! lock the daemon may be holding. The daemon looks for claims before each
+//! image and several times a second while one is being analyzed, and
+//! abandons the analysis in flight to retry it once the lane is clear.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use rusqlite::Connection;
+
+/// How long the lane stays busy after the last claim ends, so a page of
+/// thumbnails is one interruption rather than fifty
+const LINGER: Duration = Duration::from_secs(2);
+/// A claim older than this is taken for one whose process didn't live to
+/// let go of it
+const STALE: Duration = Duration::from_secs(300);
+/// Touched whenever a claim ends, for `LINGER`
+const LAST: &str = "last";
+
+static CLAIMS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone)]
+pub struct Lane {
+    dir: PathBuf,
+}
+
+/// Interactive work under way, until dropped.
+pub struct Claim {
+    dir: PathBuf,
+    path: Option<PathBuf>,
+}
+
+impl Lane {
+    /// The lane of the catalog at `catalog`.
+    pub fn new(catalog: &Path) -> Lane {
+        let mut name = catalog.file_name().unwrap_or_default().to_os_string();
+        name.push(".interactive");
+        Lane { dir: catalog.with_file_name(name) }
+    }
+
+    /// The lane of the catalog `conn` has open; none for one in memory,
+    /// which no other process can be using.
+    pub fn of(conn: &Connection) -> Option<Lane> {
+        conn.path().filter(|path| !path.is_empty()).map(|path| Lane::new(Path::new(path)))
+    }
+
+    /// Claim the lane for as long as the claim is kept. Being unable to
+    /// (a read-only folder) only costs the priority, so it isn't an error.
+    pub fn claim(&self) -> Claim {
+        let name = format!("{}-{}", std::process::id(), CLAIMS.fetch_add(1, Ordering::Relaxed));
+        let path = self.dir.join(name);
+        let claimed = fs::create_dir_all(&self.dir).and_then(|_| fs::write(&path, b""));
+        Claim { dir: self.dir.clone(), path: claimed.is_ok().then_some(path) }
+    }
+
+    /// Whether interactive work is under way or just was.
+    pub fn busy(&self) -> bool {
+        let Ok(entries) = fs::read_dir(&self.dir) else { return false };
+        let now = SystemTime::now();
+        entries.flatten().any(|entry| {
+            let within = if entry.file_name() == LAST { LINGER } else { STALE };
+            entry.metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() < within)
+        })
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = fs::write(self.dir.join(LAST), b"");
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn age(path: &Path, by: Duration) -> std::io::Result<()> {
+        fs::File::options().write(true).open(path)?.set_modified(SystemTime::now() - by)
+    }
+
+    #[test]
+    fn test_lane() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let lane = Lane::new(&dir.path().join("photo_catalog.db"));
+        assert!(!lane.busy());
+        let claim = lane.claim();
+        let second = lane.claim();
+        assert!(lane.busy());
+        drop(claim);
+        drop(second);
+        // Still, for a moment
+        assert!(lane.busy());
+        age(&dir.path().join("photo_catalog.db.interactive").join(LAST), LINGER)?;
+        assert!(!lane.busy());
+
+        // Left behind by a process that died
+        let claim = lane.claim();
+        age(claim.path.as_ref().unwrap(), STALE)?;
+        assert!(!lane.busy());
+
+        assert!(Lane::of(&Connection::open_in_memory().unwrap()).is_none());
+        Ok(())
+    }
+}
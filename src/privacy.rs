@@ -0,0 +1,188 @@
+//! Local-only mode: a guarantee that no image bytes leave the machine.
+
+use std::net::IpAddr;
+use anyhow::Error;
+use reqwest::Url;
+use crate::config::Config;
+use crate::error::{CliError, ErrorKind};
+
+/// A configured feature that would send image data off the machine.
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    pub feature: String,
+    pub reason: String,
+}
+
+/// Whether `url` points at this machine, or with `allow_lan` at a host on
+/// the local network.
+pub fn is_local_url(url: &str, allow_lan: bool) -> bool {
+    let Ok(url) = Url::parse(url) else { return false };
+    match url.host() {
+        Some(url::Host::Ipv4(ip)) => is_local_ip(IpAddr::V4(ip), allow_lan),
+        Some(url::Host::Ipv6(ip)) => is_local_ip(IpAddr::V6(ip), allow_lan),
+        Some(url::Host::Domain(domain)) => {
+            let domain = domain.to_ascii_lowercase();
+            domain == "localhost" || domain.ends_with(".localhost")
+                || (allow_lan && (domain.ends_with(".local") || domain.ends_with(".lan") || !domain.contains('.')))
+        }
+        None => false,
+    }
+}
+
+fn is_local_ip(ip: IpAddr, allow_lan: bool) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || (allow_lan && (ip.is_private() || ip.is_link_local())),
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || (allow_lan && ((ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80))
+        }
+    }
+}
+
+/// Whether the rclone destination `destination` is a folder on this
+/// machine rather than a remote ("vps:photos"). A remote might be on the
+/// local network, but there's no telling, so none counts.
+pub fn is_local_destination(destination: &str) -> bool {
+    let destination = destination.trim();
+    let drive = destination.len() >= 2 && destination.as_bytes()[1] == b':' && destination.as_bytes()[0].is_ascii_alphabetic()
+        && destination[2..].starts_with(['/', '\\']);
+    match destination.find(':') {
+        None => true,
+        // A colon in a folder's name, as "./a:b" or "/srv/a:b" have
+        Some(colon) => drive || destination[..colon].contains(['/', '\\']),
+    }
+}
+
+/// The violation mirroring to `destination` would be, if it would.
+pub fn mirror_violation(destination: &str) -> Option<Violation> {
+    (!is_local_destination(destination)).then(|| Violation {
+        feature: String::from("mirror"),
+        reason: format!("{} is an rclone remote, not a folder on this machine", destination),
+    })
+}
+
+/// The violation syncing with the Immich server at `url` would be, if it
+/// would.
+pub fn immich_violation(url: &str, allow_lan: bool) -> Option<Violation> {
+    (!is_local_url(url, allow_lan)).then(|| Violation {
+        feature: String::from("Immich sync"),
+        reason: format!("{} is not on this machine{}", url, if allow_lan { " or the local network" } else { "" }),
+    })
+}
+
+/// Every configured feature that local-only mode would refuse.
+pub fn audit(config: &Config) -> Vec<Violation> {
+    let allow_lan = config.privacy.allow_lan;
+    let mut violations = Vec::new();
+    for host in &config.analyzer.hosts {
+        if !is_local_url(&host.url, allow_lan) {
+            let name = host.name.as_deref().unwrap_or(&host.url);
+            violations.push(Violation {
+                feature: format!("analyzer host {}", name),
+                reason: format!("{} is not on this machine{}", host.url, if allow_lan { " or the local network" } else { "" }),
+            });
+        }
+    }
+    if let Some(url) = config.transcriber.url.as_ref().filter(|url| !is_local_url(url, allow_lan)) {
+        violations.push(Violation {
+            feature: String::from("transcriber"),
+            reason: format!("{} is not on this machine{}", url, if allow_lan { " or the local network" } else { "" }),
+        });
+    }
+    // Thumbnails are image data too
+    violations.extend(config.remote.destination.as_deref().and_then(mirror_violation));
+    violations
+}
+
+/// Fail with the list of violations if local-only mode is on and the
+/// configuration would send images elsewhere.
+pub fn enforce(config: &Config, local_only: bool) -> Result<(), Error> {
+    refuse(local_only, audit(config))
+}
+
+/// Fail with `violations`, if there are any and local-only mode is on.
+pub fn refuse(local_only: bool, violations: Vec<Violation>) -> Result<(), Error> {
+    if !local_only || violations.is_empty() {
+        return Ok(());
+    }
+    let list: Vec<String> = violations.iter().map(|v| format!("  {}: {}", v.feature, v.reason)).collect();
+    Err(CliError::new(
+        ErrorKind::Config,
+        format!("local-only mode refuses this configuration:\n{}", list.join("\n")),
+    ).into())
+}
+
+/// Print what local-only mode allows and refuses for this configuration.
+pub fn print_audit(config: &Config, local_only: bool) {
+    println!("Local-only mode: {}", if local_only { "on" } else { "off" });
+    match crate::geocode::AVAILABLE {
+        true => println!("Reverse geocoding: offline (embedded GeoNames data)"),
+        false => println!("Reverse geocoding: not built in"),
+    }
+    let violations = audit(config);
+    if violations.is_empty() {
+        println!("No configured feature sends images off this machine");
+    }
+    for violation in violations {
+        println!("Would be refused: {}: {}", violation.feature, violation.reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_local_url() {
+        assert!(is_local_url("http://localhost:11434", false));
+        assert!(is_local_url("http://127.0.0.1:11434", false));
+        assert!(is_local_url("http://[::1]:11434", false));
+        assert!(!is_local_url("http://192.168.1.20:11434", false));
+        assert!(is_local_url("http://192.168.1.20:11434", true));
+        assert!(is_local_url("http://gpu-box.local:11434", true));
+        assert!(!is_local_url("https://api.openai.com", true));
+        assert!(!is_local_url("not a url", true));
+
+        assert!(is_local_destination("/mnt/backup/photos"));
+        assert!(is_local_destination("backup"));
+        assert!(is_local_destination("./a:b"));
+        assert!(is_local_destination("D:\\Backup"));
+        assert!(!is_local_destination("vps:photos"));
+        assert!(!is_local_destination("s3:bucket/photos"));
+
+        assert!(immich_violation("http://localhost:2283", false).is_none());
+        assert!(immich_violation("http://nas.local:2283", true).is_none());
+        assert_eq!(immich_violation("https://photos.example.com", true).unwrap().feature, "Immich sync");
+    }
+
+    #[test]
+    fn test_enforce() -> Result<(), Error> {
+        let config = Config::parse(r#"
+            [[analyzer.hosts]]
+            url = "http://localhost:11434"
+
+            [[analyzer.hosts]]
+            name = "cloud"
+            url = "https://api.openai.com"
+            kind = "openai"
+            fallback = true
+        "#)?;
+        let violations = audit(&config);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].feature, "analyzer host cloud");
+
+        assert!(enforce(&config, false).is_ok());
+        let refused = enforce(&config, true).unwrap_err();
+        assert_eq!(crate::error::classify(&refused), ErrorKind::Config);
+
+        // Voice notes are as private as the photos
+        let config = Config::parse("[transcriber]\nurl = \"https://api.openai.com\"")?;
+        assert_eq!(audit(&config)[0].feature, "transcriber");
+
+        let config = Config::parse("[remote]\ndestination = \"vps:photos\"")?;
+        assert_eq!(audit(&config)[0].feature, "mirror");
+        assert!(enforce(&config, true).is_err());
+        assert!(audit(&Config::parse("[remote]\ndestination = \"/mnt/backup/photos\"")?).is_empty());
+        Ok(())
+    }
+}
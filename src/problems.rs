@@ -0,0 +1,170 @@
+//! Files that are broken, kept track of: a truncated JPEG, a RAW file cut
+//! short, EXIF that's garbage. A file that can't be read or decoded at all
+//! isn't cataloged, and one whose pixels or EXIF can't be read is
+//! cataloged without them; either way it goes in the `problems` table
+//! with what's wrong, for `problems list` to find again later. Scanning
+//! it again (`problems retry`) clears the problem once it's fixed, or
+//! counts another attempt if it isn't.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use anyhow::Error;
+use rusqlite::{params, Connection};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// The file couldn't be read
+    Read,
+    /// Its image data is broken
+    Decode,
+    /// Its EXIF is
+    Exif,
+}
+
+impl Category {
+    pub const ALL: [Category; 3] = [Category::Read, Category::Decode, Category::Exif];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Read => "read",
+            Category::Decode => "decode",
+            Category::Exif => "exif",
+        }
+    }
+
+    /// For `--category`'s value parser.
+    pub fn parse(name: &str) -> Result<Category, String> {
+        Category::ALL.into_iter().find(|category| category.name() == name.trim()).ok_or_else(|| {
+            let names: Vec<&str> = Category::ALL.iter().map(|category| category.name()).collect();
+            format!("no problem category {:?}; there are {}", name, names.join(", "))
+        })
+    }
+}
+
+/// An error that's the file's fault, with what kind it is.
+#[derive(Debug)]
+pub struct Corrupt {
+    pub category: Category,
+    pub source: Error,
+}
+
+impl fmt::Display for Corrupt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#}", self.source)
+    }
+}
+
+impl std::error::Error for Corrupt {}
+
+/// `error`, as the file's being broken in the way `category` is.
+pub fn corrupt(category: Category, error: impl Into<Error>) -> Error {
+    Corrupt { category, source: error.into() }.into()
+}
+
+/// What's wrong with the file a scan failed on with `error`, if it's the
+/// file's fault rather than, say, the analyzer's.
+pub fn category_of(error: &Error) -> Option<Category> {
+    // Only through `context`, not into an HTTP client's errors, which have
+    // I/O errors of their own under them
+    match error.downcast_ref::<Corrupt>() {
+        Some(corrupt) => Some(corrupt.category),
+        None => error.downcast_ref::<std::io::Error>().map(|_| Category::Read),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub path: PathBuf,
+    pub category: Category,
+    pub message: String,
+    /// RFC 3339
+    pub first_seen: String,
+    pub last_seen: String,
+    /// Scans that found it
+    pub attempts: i64,
+}
+
+/// Record that the file at `path` has the problem `category`, as
+/// `message` describes it.
+pub fn record(conn: &Connection, path: &str, category: Category, message: &str) -> rusqlite::Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO problems (path, category, message, first_seen, last_seen, attempts) VALUES (?1, ?2, ?3, ?4, ?4, 1)
+         ON CONFLICT (path, category) DO UPDATE SET message = excluded.message, last_seen = excluded.last_seen, attempts = attempts + 1",
+    )?.execute(params![path, category.name(), message, chrono::Utc::now().to_rfc3339()])?;
+    Ok(())
+}
+
+/// What a scan that got through `path` found wrong with it: those
+/// problems recorded, and any others it had cleared.
+pub fn settle(conn: &Connection, path: &str, found: &[(Category, String)]) -> rusqlite::Result<()> {
+    let mut forget = conn.prepare_cached("DELETE FROM problems WHERE path = ?1 AND category = ?2")?;
+    for category in Category::ALL.into_iter().filter(|category| !found.iter().any(|(found, _)| found == category)) {
+        forget.execute(params![path, category.name()])?;
+    }
+    for (category, message) in found {
+        record(conn, path, *category, message)?;
+    }
+    Ok(())
+}
+
+/// Forget the problems of the file at `path`, which is gone.
+pub fn forget(conn: &Connection, path: &Path) -> Result<usize, Error> {
+    Ok(conn.execute("DELETE FROM problems WHERE path = ?1", [path.to_string_lossy()])?)
+}
+
+/// The problems recorded, of `category` or all of them, by path.
+pub fn list(conn: &Connection, category: Option<Category>) -> Result<Vec<Problem>, Error> {
+    let problems = conn.prepare(
+        "SELECT path, category, message, first_seen, last_seen, attempts FROM problems
+         WHERE ?1 IS NULL OR category = ?1 ORDER BY path, category",
+    )?
+        .query_map([category.map(Category::name)], |row| {
+            let category: String = row.get(1)?;
+            Ok(Problem {
+                path: PathBuf::from(row.get::<_, String>(0)?),
+                category: Category::parse(&category).unwrap_or(Category::Read),
+                message: row.get(2)?,
+                first_seen: row.get(3)?,
+                last_seen: row.get(4)?,
+                attempts: row.get(5)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_category_of() {
+        let truncated = corrupt(Category::Decode, anyhow::anyhow!("unexpected end of file"));
+        assert_eq!(category_of(&truncated.context("/photos/a.jpg")), Some(Category::Decode));
+        let unreadable = Error::from(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert_eq!(category_of(&unreadable), Some(Category::Read));
+        assert_eq!(category_of(&anyhow::anyhow!("the analyzer answered 500")), None);
+        assert_eq!(Category::parse("exif"), Ok(Category::Exif));
+        assert!(Category::parse("gone").is_err());
+    }
+
+    #[test]
+    fn test_problems() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        record(&conn, "/photos/a.jpg", Category::Read, "permission denied")?;
+        settle(&conn, "/photos/b.jpg", &[(Category::Exif, String::from("truncated IFD"))])?;
+        settle(&conn, "/photos/b.jpg", &[(Category::Exif, String::from("truncated IFD"))])?;
+        let problems = list(&conn, None)?;
+        assert_eq!(problems.len(), 2);
+        assert_eq!((problems[1].category, problems[1].attempts), (Category::Exif, 2));
+        assert_eq!(list(&conn, Some(Category::Read))?[0].path, Path::new("/photos/a.jpg"));
+
+        // Read fine this time
+        settle(&conn, "/photos/a.jpg", &[])?;
+        assert!(list(&conn, Some(Category::Read))?.is_empty());
+        assert_eq!(forget(&conn, Path::new("/photos/b.jpg"))?, 1);
+        assert!(list(&conn, None)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+//! Progress of long-running operations (scans, exports), recorded in the
+//! catalog so it can be followed from another process: `status`, or the
+//! `/jobs` endpoints of `serve`.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::Error;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use crate::cancel::{CancelToken, Reason};
+use crate::error::{self, ErrorKind};
+
+/// An operation this process is running.
+pub struct Operation {
+    pub id: i64,
+    pub cancel: CancelToken,
+}
+
+/// A snapshot of an operation, as reported to clients.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Status {
+    pub id: i64,
+    pub kind: String,
+    /// `running`, `completed`, `failed` or `cancelled`
+    pub state: String,
+    pub total: Option<u64>,
+    pub done: u64,
+    pub failed: u64,
+    pub percent: Option<f64>,
+    /// Estimated seconds left, from the average pace so far
+    pub eta_secs: Option<u64>,
+    pub started_at: i64,
+    pub updated_at: i64,
+    pub finished_at: Option<i64>,
+    pub cancel_requested: bool,
+    pub message: Option<String>,
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Record the start of an operation of the given kind.
+pub fn start(conn: &Connection, kind: &str, cancel: CancelToken) -> Result<Operation, Error> {
+    let now = now();
+    conn.execute(
+        "INSERT INTO operations (kind, state, done, failed, started_at, updated_at, cancel_requested)
+         VALUES (?1, 'running', 0, 0, ?2, ?2, 0)",
+        params![kind, now],
+    )?;
+    Ok(Operation { id: conn.last_insert_rowid(), cancel })
+}
+
+/// Run `f` as a tracked operation, recording how it ended.
+pub fn track<T>(
+    conn: &Connection,
+    kind: &str,
+    cancel: &CancelToken,
+    f: impl FnOnce(&Operation) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let operation = start(conn, kind, cancel.clone())?;
+    let result = f(&operation);
+    operation.finish(conn, &result)?;
+    result
+}
+
+impl Operation {
+    pub fn set_total(&self, conn: &Connection, total: usize) -> Result<(), Error> {
+        conn.execute(
+            "UPDATE operations SET total = ?1, updated_at = ?2 WHERE id = ?3",
+            params![total as i64, now(), self.id],
+        )?;
+        Ok(())
+    }
+
+    /// Count one item as done, or as failed.
+    pub fn advance(&self, conn: &Connection, ok: bool) -> Result<(), Error> {
+        let column = if ok { "done" } else { "failed" };
+        conn.execute(
+            &format!("UPDATE operations SET {0} = {0} + 1, updated_at = ?1 WHERE id = ?2", column),
+            params![now(), self.id],
+        )?;
+        Ok(())
+    }
+
+    /// Whether someone asked for this operation to stop.
+    pub fn cancel_requested(&self, conn: &Connection) -> Result<bool, Error> {
+        let requested = conn.query_row(
+            "SELECT cancel_requested FROM operations WHERE id = ?1",
+            [self.id],
+            |row| row.get(0),
+        )?;
+        Ok(requested)
+    }
+
+    /// Pass on a cancellation requested through the catalog to the token.
+    /// Returns whether the token has tripped.
+    pub fn poll_cancel(&self, conn: &Connection) -> Result<bool, Error> {
+        if !self.cancel.is_cancelled() && self.cancel_requested(conn)? {
+            self.cancel.cancel(Reason::Requested);
+        }
+        Ok(self.cancel.is_cancelled())
+    }
+
+    /// A safe point between items: stop if the operation has been cancelled.
+    pub fn checkpoint(&self, conn: &Connection) -> Result<(), Error> {
+        self.poll_cancel(conn)?;
+        self.cancel.check()
+    }
+
+    pub fn finish<T>(&self, conn: &Connection, result: &Result<T, Error>) -> Result<(), Error> {
+        let (state, message) = match result {
+            Ok(_) => ("completed", None),
+            Err(e) if error::classify(e) == ErrorKind::Cancelled => ("cancelled", Some(e.to_string())),
+            Err(e) => ("failed", Some(e.to_string())),
+        };
+        let now = now();
+        conn.execute(
+            "UPDATE operations SET state = ?1, message = ?2, updated_at = ?3, finished_at = ?3 WHERE id = ?4",
+            params![state, message, now, self.id],
+        )?;
+        Ok(())
+    }
+}
+
+const COLUMNS: &str =
+    "id, kind, state, total, done, failed, started_at, updated_at, finished_at, cancel_requested, message";
+
+fn status_from_row(row: &Row) -> rusqlite::Result<Status> {
+    let total: Option<u64> = row.get(3)?;
+    let (done, failed): (u64, u64) = (row.get(4)?, row.get(5)?);
+    let state: String = row.get(2)?;
+    let (started_at, updated_at): (i64, i64) = (row.get(6)?, row.get(7)?);
+
+    let processed = done + failed;
+    let percent = total.map(|total| if total == 0 { 100.0 } else { processed as f64 * 100.0 / total as f64 });
+    let eta_secs = match total {
+        Some(total) if state == "running" && processed > 0 => {
+            let elapsed = (updated_at - started_at).max(0) as u64;
+            Some(elapsed * total.saturating_sub(processed) / processed)
+        }
+        _ => None,
+    };
+    Ok(Status {
+        id: row.get(0)?,
+        kind: row.get(1)?,
+        state,
+        total,
+        done,
+        failed,
+        percent,
+        eta_secs,
+        started_at,
+        updated_at,
+        finished_at: row.get(8)?,
+        cancel_requested: row.get(9)?,
+        message: row.get(10)?,
+    })
+}
+
+pub fn get(conn: &Connection, id: i64) -> Result<Option<Status>, Error> {
+    let sql = format!("SELECT {} FROM operations WHERE id = ?1", COLUMNS);
+    Ok(conn.query_row(&sql, [id], status_from_row).optional()?)
+}
+
+/// The most recent operations, newest first.
+pub fn list(conn: &Connection, limit: usize) -> Result<Vec<Status>, Error> {
+    let sql = format!("SELECT {} FROM operations ORDER BY id DESC LIMIT ?1", COLUMNS);
+    let mut stmt = conn.prepare(&sql)?;
+    let statuses = stmt.query_map([limit as i64], status_from_row)?.collect::<Result<Vec<_>, _>>()?;
+    Ok(statuses)
+}
+
+/// Ask a running operation to stop; it notices between items. Returns
+/// false if there is no such operation still running.
+pub fn request_cancel(conn: &Connection, id: i64) -> Result<bool, Error> {
+    let updated = conn.execute(
+        "UPDATE operations SET cancel_requested = 1 WHERE id = ?1 AND state = 'running'",
+        [id],
+    )?;
+    Ok(updated > 0)
+}
+
+pub fn print_status(status: &Status) {
+    let progress = match (status.total, status.percent) {
+        (Some(total), Some(percent)) => format!("{}/{} ({:.0}%)", status.done + status.failed, total, percent),
+        _ => format!("{}", status.done + status.failed),
+    };
+    let mut line = format!("{:>4}  {:<12} {:<10} {}", status.id, status.kind, status.state, progress);
+    if status.failed > 0 {
+        line.push_str(&format!(", {} failed", status.failed));
+    }
+    if let Some(eta) = status.eta_secs {
+        line.push_str(&format!(", about {}s left", eta));
+    }
+    if status.cancel_requested && status.state == "running" {
+        line.push_str(", cancelling");
+    }
+    if let Some(message) = &status.message {
+        line.push_str(&format!(": {}", message));
+    }
+    println!("{}", line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operation_progress() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+
+        let operation = start(&conn, "scan", CancelToken::new())?;
+        operation.set_total(&conn, 4)?;
+        operation.advance(&conn, true)?;
+        operation.advance(&conn, false)?;
+        // Pretend the two items took ten seconds
+        conn.execute("UPDATE operations SET started_at = updated_at - 10", [])?;
+
+        let status = get(&conn, operation.id)?.unwrap();
+        assert_eq!((status.done, status.failed, status.total), (1, 1, Some(4)));
+        assert_eq!(status.percent, Some(50.0));
+        assert_eq!(status.eta_secs, Some(10));
+
+        assert!(operation.checkpoint(&conn).is_ok());
+        assert!(request_cancel(&conn, operation.id)?);
+        let result = operation.checkpoint(&conn);
+        assert!(operation.cancel.is_cancelled());
+        operation.finish(&conn, &result)?;
+
+        let status = get(&conn, operation.id)?.unwrap();
+        assert_eq!(status.state, "cancelled");
+        assert_eq!(status.message.as_deref(), Some("cancelled on request"));
+        assert_eq!(status.eta_secs, None);
+        // Finished operations can't be cancelled again
+        assert!(!request_cancel(&conn, operation.id)?);
+        assert!(get(&conn, operation.id + 1)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_track_records_failure() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+
+        let cancel = CancelToken::new();
+        let result: Result<(), Error> = track(&conn, "export", &cancel, |_| Err(anyhow::anyhow!("disk full")));
+        assert!(result.is_err());
+        track(&conn, "scan", &cancel, |_| Ok(()))?;
+
+        let statuses = list(&conn, 10)?;
+        assert_eq!(statuses.len(), 2);
+        assert_eq!((statuses[0].kind.as_str(), statuses[0].state.as_str()), ("scan", "completed"));
+        assert_eq!(statuses[1].state, "failed");
+        assert_eq!(statuses[1].message.as_deref(), Some("disk full"));
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+//! Catalogued files that have been deleted. `prune` removes their rows,
+//! with everything hanging off them, or with `--archive` only flags them
+//! (`archived_at`), keeping what the catalog knew in case the files turn up
+//! again; a later scan that finds them clears the flag.
+
+use std::path::Path;
+use anyhow::Error;
+use rusqlite::{params, Connection};
+use crate::share::Shares;
+
+/// Tables with rows per image, which go with it.
+const IMAGE_TABLES: &[&str] = &[
+    "external_metadata", "scenes", "notes", "corrections", "trip_images", "date_shift_images", "image_access", "edits",
+    "image_tags", "album_images", "histograms", "fields",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Prune {
+    Remove,
+    Archive,
+}
+
+/// The id and path of each catalogued file that's gone, leaving out those
+/// flagged already and those on shares that aren't answering.
+pub fn missing(conn: &Connection, shares: &mut Shares) -> Result<Vec<(i64, String)>, Error> {
+    let mut stmt = conn.prepare("SELECT id, path FROM images WHERE archived_at IS NULL ORDER BY path")?;
+    let rows: Vec<(i64, String)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<_, _>>()?;
+    Ok(rows.into_iter().filter(|(_, path)| shares.gone(Path::new(path))).collect())
+}
+
+/// Remove or flag the images `ids`, in one transaction.
+pub fn prune(conn: &Connection, ids: &[i64], how: Prune) -> Result<(), Error> {
+    let tx = conn.unchecked_transaction()?;
+    match how {
+        Prune::Remove => {
+            for table in IMAGE_TABLES {
+                let mut stmt = tx.prepare(&format!("DELETE FROM {} WHERE image_id = ?1", table))?;
+                for id in ids {
+                    stmt.execute([id])?;
+                }
+            }
+            let mut stmt = tx.prepare("DELETE FROM images WHERE id = ?1")?;
+            for id in ids {
+                stmt.execute([id])?;
+            }
+            crate::tags::drop_unused(&tx)?;
+        }
+        Prune::Archive => {
+            let now = chrono::Utc::now().to_rfc3339();
+            let mut stmt = tx.prepare("UPDATE images SET archived_at = ?1 WHERE id = ?2")?;
+            for id in ids {
+                stmt.execute(params![now, id])?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::cancel::CancelToken;
+    use crate::config::Config;
+    use crate::share::ShareConfig;
+
+    #[test]
+    fn test_prune() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        let (kept, deleted, lost) = (dir.path().join("kept.jpg"), dir.path().join("deleted.jpg"), dir.path().join("lost.jpg"));
+        fs::write(&kept, "")?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size) VALUES (?1, 'kept.jpg', 0), (?2, 'deleted.jpg', 0), (?3, 'lost.jpg', 0)",
+            params![kept.to_string_lossy(), deleted.to_string_lossy(), lost.to_string_lossy()],
+        )?;
+        // On a share that isn't mounted
+        let nas = dir.path().join("nas");
+        fs::create_dir(&nas)?;
+        conn.execute("INSERT INTO images (path, file_name, file_size) VALUES (?1, 'away.jpg', 0)", [nas.join("away.jpg").to_string_lossy()])?;
+        let mut shares = Shares::new(&ShareConfig { paths: vec![nas], retry_delay_ms: 1, ..ShareConfig::default() });
+        conn.execute("INSERT INTO notes (image_id, text, created_at) VALUES (2, 'Low tide', '2024-05-01')", [])?;
+        conn.execute("INSERT INTO external_metadata (image_id, source, field, value) VALUES (2, 'xmp', 'rating', '3'), (3, 'xmp', 'rating', '5')", [])?;
+
+        let gone = missing(&conn, &mut shares)?;
+        assert_eq!(gone, vec![(2, deleted.to_string_lossy().into_owned()), (3, lost.to_string_lossy().into_owned())]);
+        prune(&conn, &[2], Prune::Remove)?;
+        prune(&conn, &[3], Prune::Archive)?;
+        let ids: Vec<i64> = conn.prepare("SELECT id FROM images ORDER BY id")?.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+        assert_eq!(ids, vec![1, 3, 4]);
+        let orphans: i64 = conn.query_row(
+            "SELECT (SELECT COUNT(*) FROM notes) + (SELECT COUNT(*) FROM external_metadata WHERE image_id = 2)", [], |row| row.get(0),
+        )?;
+        assert_eq!(orphans, 0);
+        // Flagged, with what the catalog knew, and not found again
+        let archived: Option<String> = conn.query_row("SELECT archived_at FROM images WHERE id = 3", [], |row| row.get(0))?;
+        assert!(archived.is_some());
+        assert!(missing(&conn, &mut shares)?.is_empty());
+
+        // Back again
+        image::DynamicImage::new_rgb8(4, 4).save_with_format(&lost, image::ImageFormat::Jpeg)?;
+        crate::save_metadata(&conn, &crate::process_image(&lost, &Config::default(), None, &CancelToken::new())?)?;
+        let archived: Option<String> = conn.query_row("SELECT archived_at FROM images WHERE id = 3", [], |row| row.get(0))?;
+        assert_eq!(archived, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+//! `key:value` queries, for commands that act on part of the catalog:
+//! `--query camera:X100V date:2023-07` picks out what `search --camera
+//! X100V --date 2023-07` would list.
+
+use std::fmt;
+use crate::SearchFilter;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Place(String),
+    Camera(String),
+    Lens(String),
+    FocalLength(f64),
+    Aperture(f64),
+    Iso(u32),
+    Keyword(String),
+    Date(String),
+    Trip(i64),
+    Album(String),
+    Source(String),
+    Note(String),
+    Scene(String),
+    Action(String),
+    Sound(bool),
+    Rating(u8),
+    Favorite(bool),
+    Rejected(bool),
+    Field(crate::fields::Condition),
+}
+
+const KEYS: &[&str] = &["place", "camera", "lens", "focal_length", "aperture", "iso", "keyword", "date", "trip", "album", "source", "note", "scene", "action", "sound", "rating", "favorite", "rejected", "field"];
+
+/// One `key:value` term.
+pub fn parse_term(s: &str) -> Result<Term, String> {
+    let Some((key, value)) = s.split_once(':') else {
+        return Err(format!("expected key:value, got {:?}", s));
+    };
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(format!("no value for {}", key));
+    }
+    Ok(match key.trim().to_lowercase().as_str() {
+        "place" => Term::Place(value.to_string()),
+        "camera" => Term::Camera(value.to_string()),
+        "lens" => Term::Lens(value.to_string()),
+        "focal_length" => Term::FocalLength(crate::parse_focal_length(value)?),
+        "aperture" => Term::Aperture(crate::parse_aperture(value)?),
+        "iso" => Term::Iso(value.parse().map_err(|_| format!("invalid ISO: {}", value))?),
+        "keyword" => Term::Keyword(value.to_string()),
+        "date" => Term::Date(parse_date(value)?),
+        "trip" => Term::Trip(value.parse().map_err(|_| format!("invalid trip number: {}", value))?),
+        "album" => Term::Album(value.to_string()),
+        "source" => Term::Source(value.to_string()),
+        "note" => Term::Note(value.to_string()),
+        "scene" => Term::Scene(value.to_string()),
+        "action" => Term::Action(value.to_string()),
+        "sound" => Term::Sound(parse_yes_no(value)?),
+        "rating" => Term::Rating(parse_rating(value)?),
+        "favorite" => Term::Favorite(parse_yes_no(value)?),
+        "rejected" => Term::Rejected(parse_yes_no(value)?),
+        "field" => Term::Field(crate::fields::parse_condition(value)?),
+        other => return Err(format!("unknown query key {:?}; known keys are {}", other, KEYS.join(", "))),
+    })
+}
+
+/// The terms of a query typed as one line. Words without a key go with the
+/// term before them, so values can have spaces: `keyword:birthday cake
+/// date:2023`.
+#[cfg(any(feature = "server", feature = "tui"))]
+pub fn parse(q: &str) -> Result<Vec<Term>, String> {
+    let mut terms: Vec<String> = Vec::new();
+    for word in q.split_whitespace() {
+        match terms.last_mut() {
+            Some(term) if !word.contains(':') => {
+                term.push(' ');
+                term.push_str(word);
+            }
+            _ => terms.push(word.to_string()),
+        }
+    }
+    terms.iter().map(|term| parse_term(term)).collect()
+}
+
+/// A year, month or day: "2023", "2023-07" or "2023-07-14".
+pub fn parse_date(s: &str) -> Result<String, String> {
+    let parts: Vec<&str> = s.trim().split('-').collect();
+    let widths = [4, 2, 2];
+    let valid = parts.len() <= widths.len()
+        && parts.iter().zip(widths).all(|(part, width)| part.len() == width && part.bytes().all(|b| b.is_ascii_digit()));
+    if valid { Ok(parts.join("-")) } else { Err(format!("invalid date: {} (expected e.g. 2023, 2023-07 or 2023-07-14)", s)) }
+}
+
+/// "yes" or "no" (or "true" or "false").
+pub fn parse_yes_no(s: &str) -> Result<bool, String> {
+    match s.trim().to_lowercase().as_str() {
+        "yes" | "true" => Ok(true),
+        "no" | "false" => Ok(false),
+        _ => Err(format!("expected yes or no, got {:?}", s)),
+    }
+}
+
+/// A star rating, 1 to 5, as `3` or `***`.
+pub fn parse_rating(s: &str) -> Result<u8, String> {
+    let s = s.trim();
+    let stars = match s.bytes().all(|b| b == b'*') {
+        true => s.len(),
+        false => s.parse().map_err(|_| format!("invalid rating: {} (expected 1 to 5 stars)", s))?,
+    };
+    match stars {
+        1..=5 => Ok(stars as u8),
+        _ => Err(format!("invalid rating: {} (expected 1 to 5 stars)", s)),
+    }
+}
+
+/// The search filter the terms add up to; a key given twice keeps the
+/// last value, but for fields, which all have to match.
+pub fn filter(terms: &[Term]) -> SearchFilter {
+    let mut filter = SearchFilter::default();
+    for term in terms {
+        match term.clone() {
+            Term::Place(place) => filter.place = Some(place),
+            Term::Camera(camera) => filter.camera = Some(camera),
+            Term::Lens(lens) => filter.lens = Some(lens),
+            Term::FocalLength(focal_length) => filter.focal_length = Some(focal_length),
+            Term::Aperture(aperture) => filter.aperture = Some(aperture),
+            Term::Iso(iso) => filter.iso = Some(iso),
+            Term::Keyword(keyword) => filter.keyword = Some(keyword),
+            Term::Date(date) => filter.date = Some(date),
+            Term::Trip(trip) => filter.trip = Some(trip),
+            Term::Album(album) => filter.album = Some(album),
+            Term::Source(source) => filter.source = Some(source),
+            Term::Note(note) => filter.note = Some(note),
+            Term::Scene(scene) => filter.scene = Some(scene),
+            Term::Action(action) => filter.action = Some(action),
+            Term::Sound(sound) => filter.sound = Some(sound),
+            Term::Rating(rating) => filter.rating = Some(rating),
+            Term::Favorite(favorite) => filter.favorite = Some(favorite),
+            Term::Rejected(rejected) => filter.rejected = Some(rejected),
+            Term::Field(condition) => filter.field.push(condition),
+        }
+    }
+    filter
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Term::Place(place) => write!(f, "place:{}", place),
+            Term::Camera(camera) => write!(f, "camera:{}", camera),
+            Term::Lens(lens) => write!(f, "lens:{}", lens),
+            Term::FocalLength(focal_length) => write!(f, "focal_length:{}mm", focal_length),
+            Term::Aperture(aperture) => write!(f, "aperture:f/{}", aperture),
+            Term::Iso(iso) => write!(f, "iso:{}", iso),
+            Term::Keyword(keyword) => write!(f, "keyword:{}", keyword),
+            Term::Date(date) => write!(f, "date:{}", date),
+            Term::Trip(trip) => write!(f, "trip:{}", trip),
+            Term::Album(album) => write!(f, "album:{}", album),
+            Term::Source(source) => write!(f, "source:{}", source),
+            Term::Note(note) => write!(f, "note:{}", note),
+            Term::Scene(scene) => write!(f, "scene:{}", scene),
+            Term::Action(action) => write!(f, "action:{}", action),
+            Term::Sound(sound) => write!(f, "sound:{}", if *sound { "yes" } else { "no" }),
+            Term::Rating(rating) => write!(f, "rating:{}", rating),
+            Term::Favorite(favorite) => write!(f, "favorite:{}", if *favorite { "yes" } else { "no" }),
+            Term::Rejected(rejected) => write!(f, "rejected:{}", if *rejected { "yes" } else { "no" }),
+            Term::Field(condition) => write!(f, "field:{}", condition),
+        }
+    }
+}
+
+/// Terms as they'd be typed, for showing or recording a query.
+pub fn to_string(terms: &[Term]) -> String {
+    terms.iter().map(Term::to_string).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(any(feature = "server", feature = "tui"))]
+    fn test_parse() {
+        assert_eq!(parse("keyword:birthday cake  date:2023"), Ok(vec![Term::Keyword(String::from("birthday cake")), Term::Date(String::from("2023"))]));
+        assert!(parse("birthday").is_err());
+    }
+
+    #[test]
+    fn test_parse_terms() {
+        let terms: Vec<Term> = ["camera:X100V", "date:2023-07", "aperture:f/2"].iter().map(|s| parse_term(s).unwrap()).collect();
+        let filter = filter(&terms);
+        assert_eq!(filter.camera.as_deref(), Some("X100V"));
+        assert_eq!(filter.date.as_deref(), Some("2023-07"));
+        assert_eq!(filter.aperture, Some(2.0));
+        assert_eq!(to_string(&terms), "camera:X100V date:2023-07 aperture:f/2");
+
+        assert_eq!(parse_term("sound:no"), Ok(Term::Sound(false)));
+        assert_eq!(super::filter(&[parse_term("sound:Yes").unwrap()]).sound, Some(true));
+        assert_eq!(parse_term("rating:***"), Ok(Term::Rating(3)));
+        assert_eq!(super::filter(&[parse_term("rating:4").unwrap(), parse_term("rejected:no").unwrap()]).rejected, Some(false));
+        let fields = [parse_term("field:client=Smith").unwrap(), parse_term("field:invoice>=1000").unwrap()];
+        assert_eq!(super::filter(&fields).field.len(), 2);
+        assert_eq!(to_string(&fields), "field:client=Smith field:invoice>=1000");
+
+        for bad in ["X100V", "camera:", "colour:red", "date:July", "date:2023-7", "iso:lots", "trip:first", "sound:loud", "rating:6", "rating:0", "rating:******"] {
+            assert!(parse_term(bad).is_err(), "{}", bad);
+        }
+    }
+}
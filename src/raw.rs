@@ -0,0 +1,132 @@
+//! Camera RAW files. Decoding the sensor data isn't needed for cataloging:
+//! every supported format embeds a full-size or large JPEG preview made by
+//! the camera, which is what gets analyzed. CR2, NEF, ARW and DNG are TIFF
+//! files underneath, so their EXIF reads directly; RAF wraps a JPEG (with
+//! the EXIF) in its own header.
+
+use std::path::Path;
+use anyhow::{bail, Error};
+use crate::tiff::Tiff;
+
+/// Extensions recognised as RAW, with the format name recorded for each
+#[cfg(feature = "raw")]
+pub const FORMATS: &[(&str, &str)] = &[
+    ("cr2", "Cr2"),
+    ("nef", "Nef"),
+    ("arw", "Arw"),
+    ("raf", "Raf"),
+    ("dng", "Dng"),
+];
+
+const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW ";
+
+pub struct RawImage {
+    pub format: &'static str,
+    /// The largest embedded JPEG preview
+    pub preview: Option<Vec<u8>>,
+    /// Largest image size declared in the file's IFDs, usually the sensor's
+    pub dimensions: Option<(u32, u32)>,
+}
+
+/// The RAW format name for a path, if its extension is one we handle.
+#[cfg(feature = "raw")]
+pub fn format_for(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_string_lossy().to_lowercase();
+    FORMATS.iter().find(|(e, _)| *e == ext).map(|(_, format)| *format)
+}
+
+/// Builds without the `raw` feature catalog no RAW files.
+#[cfg(not(feature = "raw"))]
+pub fn format_for(_path: &Path) -> Option<&'static str> {
+    None
+}
+
+pub fn read(data: &[u8], format: &'static str) -> Result<RawImage, Error> {
+    if data.starts_with(RAF_MAGIC) {
+        // Offset and length of the embedded JPEG, big-endian, at fixed places
+        let (Some(offset), Some(len)) = (be_u32(data, 84), be_u32(data, 88)) else {
+            bail!("truncated RAF header");
+        };
+        let preview = data.get(offset as usize..(offset as usize).saturating_add(len as usize))
+            .filter(|jpeg| is_baseline_jpeg(jpeg))
+            .map(<[u8]>::to_vec);
+        return Ok(RawImage { format, preview, dimensions: None });
+    }
+
+    let tiff = Tiff::new(data)?;
+    let ifds = tiff.ifds();
+    let dimensions = ifds.iter()
+        .filter_map(|ifd| Some((ifd.width?, ifd.height?)))
+        .max_by_key(|(w, h)| *w as u64 * *h as u64);
+    let preview = ifds.iter()
+        .filter_map(|ifd| ifd.jpeg)
+        .filter_map(|(offset, len)| data.get(offset as usize..(offset as usize).saturating_add(len as usize)))
+        .filter(|jpeg| is_baseline_jpeg(jpeg))
+        .max_by_key(|jpeg| jpeg.len())
+        .map(<[u8]>::to_vec);
+    Ok(RawImage { format, preview, dimensions })
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+/// Whether `data` is a JPEG that ordinary decoders handle. RAW data itself
+/// is often stored as lossless JPEG (SOF3), which they don't.
+fn is_baseline_jpeg(data: &[u8]) -> bool {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return false;
+    }
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        match data[pos + 1] {
+            0xC0..=0xC2 => return true,
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF | 0xDA => return false,
+            _ => pos += 2 + u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize,
+        }
+    }
+    false
+}
+
+#[cfg(all(test, feature = "raw"))]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_read_tiff_raw() -> Result<(), Error> {
+        let preview = jpeg_with_exif(&[field(exif::Tag::Make, ascii("NIKON CORPORATION"))]);
+        let nef = raw_with_preview(&[field(exif::Tag::Make, ascii("NIKON CORPORATION"))], &preview, (6048, 4024));
+
+        let raw = read(&nef, "Nef")?;
+        assert_eq!(raw.preview.as_deref(), Some(&preview[..]));
+        assert_eq!(raw.dimensions, Some((6048, 4024)));
+        assert_eq!(format_for(Path::new("DSC_0001.NEF")), Some("Nef"));
+        assert_eq!(format_for(Path::new("photo.jpg")), None);
+        assert!(read(b"not a raw file", "Nef").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_raf() -> Result<(), Error> {
+        let preview = jpeg_with_exif(&[field(exif::Tag::Make, ascii("FUJIFILM"))]);
+        let mut raf = RAF_MAGIC.to_vec();
+        raf.resize(100, 0);
+        raf[84..88].copy_from_slice(&100u32.to_be_bytes());
+        raf[88..92].copy_from_slice(&(preview.len() as u32).to_be_bytes());
+        raf.extend_from_slice(&preview);
+
+        let raw = read(&raf, "Raf")?;
+        assert_eq!(raw.preview.as_deref(), Some(&preview[..]));
+        assert_eq!(raw.dimensions, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_baseline_jpeg() {
+        assert!(is_baseline_jpeg(&[0xFF, 0xD8, 0xFF, 0xC0, 0x00, 0x02]));
+        // Lossless, as used for the sensor data in CR2 and DNG
+        assert!(!is_baseline_jpeg(&[0xFF, 0xD8, 0xFF, 0xC3, 0x00, 0x02]));
+        assert!(!is_baseline_jpeg(b"II*\0"));
+    }
+}
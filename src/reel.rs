@@ -0,0 +1,302 @@
+//! Highlight reels: a short video of a trip, one of its events or a year,
+//! cut from the catalog's photos and clips by ffmpeg. The best-liked
+//! moments go in (the catalog's own ratings and favorites, Lightroom
+//! ratings and picks, Apple and Google Photos favorites, the people Google Photos found in them, the actions tagged in
+//! clips), one from each stretch of the time covered so the reel tells the
+//! whole story, in the order they happened, with a music track if one is
+//! set.
+//!
+//! Photos are shown for `photo_secs` each and videos cut to `clip_secs`
+//! from a third of the way in, each fading in and out, then joined and
+//! given the music, which fades out at the end.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::Error;
+use rusqlite::{params_from_iter, Connection};
+use crate::config::{Config, ReelConfig};
+use crate::error::{CliError, ErrorKind};
+use crate::{jobs, video, SearchFilter};
+
+/// Seconds of fade at the start and end of each shot
+const FADE_SECS: f64 = 0.5;
+/// Seconds the music fades out over at the end
+const MUSIC_FADE_SECS: f64 = 3.0;
+
+/// A photo or video that could go in.
+#[derive(Debug, Clone, PartialEq)]
+struct Shot {
+    path: String,
+    /// Videos' length; `None` for photos
+    duration_secs: Option<f64>,
+    score: u32,
+}
+
+impl Shot {
+    /// Seconds of the reel it takes up.
+    fn secs(&self, config: &ReelConfig) -> f64 {
+        match self.duration_secs {
+            Some(duration) => duration.min(config.clip_secs),
+            None => config.photo_secs,
+        }
+    }
+}
+
+/// How much a photo stands out, from what's been said of it in the catalog
+/// (as `catalog` fields) and elsewhere: `None` if it was rejected or hidden. Everything else scores at
+/// least 1, so a catalog with none of this still makes a reel.
+fn score(external: &[(String, String, String)]) -> Option<u32> {
+    let mut score = 1;
+    let (mut people, mut actions) = (0, 0);
+    for (source, field, value) in external {
+        match (source.as_str(), field.as_str(), value.as_str()) {
+            ("catalog" | "lightroom", "pick", "rejected") | ("apple_photos", "hidden", "true") => return None,
+            ("lightroom", "pick", "picked") => score += 2,
+            ("catalog" | "lightroom", "rating", rating) => score += rating.parse::<u32>().unwrap_or_default().min(5),
+            ("catalog" | "apple_photos" | "takeout", "favorite", "true") => score += 3,
+            ("takeout", "person", _) => people += 1,
+            (crate::actions::SOURCE, "tag", _) => actions += 1,
+            _ => {}
+        }
+    }
+    Some(score + u32::min(people, 3) + u32::min(actions, 3))
+}
+
+/// The photos and videos matching `filter` (and in `event` of its trip, if
+/// given) that weren't rejected, in the order they were taken.
+fn candidates(conn: &Connection, filter: &SearchFilter, event: Option<usize>) -> Result<Vec<Shot>, Error> {
+    let (mut clause, mut params) = crate::filter_clause(filter);
+    if let (Some(trip), Some(event)) = (filter.trip, event) {
+        clause.push_str(if clause.is_empty() { " WHERE " } else { " AND " });
+        clause.push_str("id IN (SELECT image_id FROM trip_images WHERE trip_id = ? AND event = ?)");
+        params.push(Box::new(trip));
+        params.push(Box::new(event as i64));
+    }
+    let rows: Vec<(i64, String, Option<f64>, u8, bool, bool)> = conn
+        .prepare(&format!("SELECT id, path, duration_secs, rating, favorite, rejected FROM images{} ORDER BY creation_date, path", clause))?
+        .query_map(params_from_iter(params), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?)))?
+        .collect::<Result<_, _>>()?;
+    let mut external = conn.prepare("SELECT source, field, value FROM external_metadata WHERE image_id = ?1")?;
+    let mut shots = Vec::new();
+    for (id, path, duration_secs, rating, favorite, rejected) in rows {
+        let mut fields: Vec<(String, String, String)> = external
+            .query_map([id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+        let catalog = |field: &str, value: String| (String::from("catalog"), field.to_string(), value);
+        fields.push(catalog("rating", rating.to_string()));
+        if favorite {
+            fields.push(catalog("favorite", String::from("true")));
+        }
+        if rejected {
+            fields.push(catalog("pick", String::from("rejected")));
+        }
+        if let Some(score) = score(&fields) {
+            shots.push(Shot { path, duration_secs: duration_secs.filter(|d| *d > 0.0), score });
+        }
+    }
+    Ok(shots)
+}
+
+/// The shots of the reel: the best of each equal stretch of `shots`, so
+/// bursts give one frame and every part of the day is there, then the
+/// least liked left out until it fits in `config.seconds`.
+fn pick(shots: &[Shot], config: &ReelConfig) -> Vec<Shot> {
+    let slots = ((config.seconds / config.photo_secs.min(config.clip_secs)).floor() as usize).clamp(1, shots.len().max(1));
+    let mut picked: Vec<(usize, &Shot)> = (0..slots)
+        .filter_map(|slot| {
+            let stretch = shots.get(slot * shots.len() / slots..(slot + 1) * shots.len() / slots)?;
+            // The earliest of the best
+            let best = stretch.iter().map(|shot| shot.score).max()?;
+            stretch.iter().position(|shot| shot.score == best).map(|i| (slot * shots.len() / slots + i, &stretch[i]))
+        })
+        .collect();
+    while picked.iter().map(|(_, shot)| shot.secs(config)).sum::<f64>() > config.seconds && picked.len() > 1 {
+        // The last of the least liked
+        let worst = picked.iter().map(|(_, shot)| shot.score).min().unwrap_or_default();
+        let i = picked.iter().rposition(|(_, shot)| shot.score == worst).unwrap_or_default();
+        picked.remove(i);
+    }
+    picked.sort_by_key(|(i, _)| *i);
+    picked.into_iter().map(|(_, shot)| shot.clone()).collect()
+}
+
+/// The filters fitting each shot into the frame, at the reel's frame
+/// rate, fading in and out over `secs`.
+fn filters(secs: f64, config: &ReelConfig) -> String {
+    let (w, h) = (config.width, config.height);
+    format!(
+        "scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,setsar=1,fps={fps},format=yuv420p,\
+         fade=t=in:d={fade},fade=t=out:st={out:.3}:d={fade}",
+        fps = config.fps,
+        fade = FADE_SECS,
+        out = (secs - FADE_SECS).max(0.0),
+    )
+}
+
+/// Encode `shot` as the `index`th part of the reel, in `dir`.
+fn encode(shot: &Shot, index: usize, dir: &Path, config: &Config) -> Result<PathBuf, Error> {
+    let secs = shot.secs(&config.reel);
+    let part = dir.join(format!("{:04}.mp4", index));
+    let length = format!("{:.3}", secs);
+    let vf = filters(secs, &config.reel);
+    let output = part.to_string_lossy().into_owned();
+    let encoding = ["-vf", &vf, "-an", "-c:v", "libx264", "-preset", "veryfast", "-crf", "20", &output];
+    match shot.duration_secs {
+        Some(duration) => {
+            // From a third of the way in, as for the analyzed frame, unless that runs past the end
+            let start = format!("{:.3}", (duration / 3.0).min(duration - secs).max(0.0));
+            let mut args = vec!["-v", "error", "-y", "-ss", &start, "-t", &length, "-i", &shot.path];
+            args.extend(encoding);
+            video::run_tool(&config.video.ffmpeg, &args, "video.ffmpeg")?;
+        }
+        None => {
+            // Upright, and decoded whatever the format, as it's shown everywhere else
+            let edge = config.reel.width.max(config.reel.height);
+            let still = dir.join(format!("{:04}.jpg", index));
+            fs::write(&still, jobs::derivative(Path::new(&shot.path), config, edge)?)?;
+            let still = still.to_string_lossy().into_owned();
+            let fps = config.reel.fps.to_string();
+            let mut args = vec!["-v", "error", "-y", "-loop", "1", "-framerate", &fps, "-t", &length, "-i", &still];
+            args.extend(encoding);
+            video::run_tool(&config.video.ffmpeg, &args, "video.ffmpeg")?;
+        }
+    }
+    Ok(part)
+}
+
+/// What the reel is of, for messages.
+pub struct Selection<'a> {
+    pub filter: &'a SearchFilter,
+    pub event: Option<usize>,
+    pub name: String,
+}
+
+/// Cut the reel of `selection` to `out`, an MP4, and say how many shots
+/// and seconds it came to.
+pub fn make(conn: &Connection, selection: &Selection, config: &Config, out: &Path) -> Result<(usize, f64), Error> {
+    let shots = pick(&candidates(conn, selection.filter, selection.event)?, &config.reel);
+    if shots.is_empty() {
+        let message = format!("{} has no photos or videos for a reel", selection.name);
+        return Err(CliError::new(ErrorKind::NothingToDo, message).into());
+    }
+    if let Some(music) = &config.reel.music {
+        if !music.exists() {
+            return Err(CliError::new(ErrorKind::Config, format!("reel.music is {}, which doesn't exist", music.display())).into());
+        }
+    }
+
+    let dir = tempfile::tempdir()?;
+    let mut list = String::new();
+    for (index, shot) in shots.iter().enumerate() {
+        println!("{}/{}: {}", index + 1, shots.len(), shot.path);
+        let part = encode(shot, index, dir.path(), config)?;
+        let _ = writeln!(list, "file '{}'", part.to_string_lossy().replace('\'', "'\\''"));
+    }
+    let list_path = dir.path().join("parts.txt");
+    fs::write(&list_path, list)?;
+
+    let secs: f64 = shots.iter().map(|shot| shot.secs(&config.reel)).sum();
+    let list_path = list_path.to_string_lossy().into_owned();
+    let length = format!("{:.3}", secs);
+    let output = out.to_string_lossy().into_owned();
+    let mut args = vec!["-v", "error", "-y", "-f", "concat", "-safe", "0", "-i", &list_path];
+    let music = config.reel.music.as_ref().map(|music| music.to_string_lossy().into_owned());
+    let fade = format!("afade=t=out:st={:.3}:d={}", (secs - MUSIC_FADE_SECS).max(0.0), MUSIC_FADE_SECS);
+    if let Some(music) = &music {
+        args.extend(["-stream_loop", "-1", "-i", music, "-map", "0:v", "-map", "1:a", "-af", &fade, "-c:a", "aac", "-b:a", "192k"]);
+    }
+    args.extend(["-c:v", "copy", "-t", &length, "-movflags", "+faststart", &output]);
+    video::run_tool(&config.video.ffmpeg, &args, "video.ffmpeg")?;
+    Ok((shots.len(), secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn external(fields: &[(&str, &str, &str)]) -> Vec<(String, String, String)> {
+        fields.iter().map(|(source, field, value)| (source.to_string(), field.to_string(), value.to_string())).collect()
+    }
+
+    fn shot(path: &str, score: u32) -> Shot {
+        Shot { path: path.to_string(), duration_secs: None, score }
+    }
+
+    #[test]
+    fn test_score() {
+        assert_eq!(score(&[]), Some(1));
+        assert_eq!(score(&external(&[("lightroom", "rating", "4"), ("lightroom", "pick", "picked")])), Some(7));
+        assert_eq!(score(&external(&[("takeout", "favorite", "true"), ("takeout", "person", "Ana"), ("action", "tag", "diving")])), Some(6));
+        assert_eq!(score(&external(&[("lightroom", "rating", "5"), ("lightroom", "pick", "rejected")])), None);
+        assert_eq!(score(&external(&[("apple_photos", "hidden", "true")])), None);
+        assert_eq!(score(&external(&[("catalog", "rating", "3"), ("catalog", "favorite", "true")])), Some(7));
+        assert_eq!(score(&external(&[("catalog", "rating", "0"), ("catalog", "pick", "rejected")])), None);
+    }
+
+    #[test]
+    fn test_pick() {
+        let config = ReelConfig { seconds: 9.0, ..ReelConfig::default() };
+        // Two to a stretch, so the burst a1, a2 gives one
+        let shots = [shot("a1", 1), shot("a2", 4), shot("b", 1), shot("c", 2), shot("d", 1), shot("e", 1)];
+        let paths = |picked: Vec<Shot>| picked.into_iter().map(|shot| shot.path).collect::<Vec<_>>();
+        assert_eq!(paths(pick(&shots, &config)), vec!["a2", "c", "d"]);
+
+        // A clip takes less than its length, and the least liked make room for it
+        let mut with_clip = shots.to_vec();
+        with_clip[3] = Shot { duration_secs: Some(120.0), ..shot("clip", 3) };
+        let picked = pick(&with_clip, &config);
+        assert_eq!(paths(picked.clone()), vec!["a2", "clip"]);
+        assert!(picked.iter().map(|shot| shot.secs(&config)).sum::<f64>() <= 9.0);
+
+        assert_eq!(paths(pick(&shots[..1], &config)), vec!["a1"]);
+        assert!(pick(&[], &config).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_make() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let photo = dir.path().join("beach.jpg");
+        fs::write(&photo, crate::test_support::jpeg_with_exif(&[]))?;
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, creation_date, duration_secs) VALUES
+                (?1, 'beach.jpg', 1, '2023-07-14 10:00:00', NULL),
+                ('/videos/dive.mp4', 'dive.mp4', 1, '2023-07-14 11:00:00', 30.0),
+                ('/photos/winter.jpg', 'winter.jpg', 1, '2022-12-24 18:00:00', NULL)",
+            [photo.to_string_lossy()],
+        )?;
+
+        // Records each run, and writes the file named last
+        let log = dir.path().join("ffmpeg.log");
+        let ffmpeg = crate::test_support::fake_tool(dir.path(), &format!(
+            "echo \"$*\" >> {}\nfor last; do :; done\necho video > \"$last\"",
+            log.display(),
+        ))?;
+        let music = dir.path().join("song.mp3");
+        fs::write(&music, b"music")?;
+        let mut config = Config::default();
+        config.video.ffmpeg = ffmpeg;
+        config.reel.music = Some(music);
+
+        let out = dir.path().join("2023.mp4");
+        let filter = SearchFilter { date: Some(String::from("2023")), ..SearchFilter::default() };
+        let selection = Selection { filter: &filter, event: None, name: String::from("2023") };
+        assert_eq!(make(&conn, &selection, &config, &out)?, (2, 7.0));
+        assert!(out.exists());
+        let runs = fs::read_to_string(&log)?;
+        let runs: Vec<&str> = runs.lines().collect();
+        assert_eq!(runs.len(), 3);
+        assert!(runs[0].contains("-loop 1") && runs[0].contains("0000.jpg"));
+        // From 10 s into the 30 s clip
+        assert!(runs[1].contains("-ss 10.000 -t 4.000 -i /videos/dive.mp4"));
+        assert!(runs[2].contains("-f concat") && runs[2].contains("song.mp3") && runs[2].contains("afade=t=out:st=4.000"));
+
+        let filter = SearchFilter { date: Some(String::from("2021")), ..SearchFilter::default() };
+        let nothing = Selection { filter: &filter, event: None, name: String::from("2021") };
+        assert!(make(&conn, &nothing, &config, &out).is_err());
+        Ok(())
+    }
+}
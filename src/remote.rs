@@ -0,0 +1,232 @@
+//! Mirrors: the catalog and a thumbnail of each photo, without the
+//! originals, copied to an rclone remote, so a small server elsewhere (a
+//! VPS, say) can run `serve` for browsing while the originals stay at
+//! home. `mirror` puts together the mirror in `staging` — a snapshot of
+//! the catalog and `thumbnails/{content hash}.jpg` — and has rclone sync
+//! it to `destination`; only new thumbnails are made each time.
+//!
+//! On the server, thumbnails come from the mirror. Anything that needs an
+//! original (a bigger thumbnail, a video) fetches it from `originals`, an
+//! rclone remote reaching the machine they're on, into `cache`.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::{bail, Error};
+use rusqlite::Connection;
+use serde::Deserialize;
+use crate::config::Config;
+use crate::error::{CliError, ErrorKind};
+use crate::jobs;
+
+/// Where thumbnails are, next to the catalog.
+pub const THUMBNAILS: &str = "thumbnails";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteConfig {
+    pub rclone: String,
+    /// Where `mirror` copies to, as rclone names it, e.g. "vps:photos".
+    pub destination: Option<String>,
+    /// Where the mirror is put together before it's copied.
+    pub staging: PathBuf,
+    /// Longest edge of the thumbnails in the mirror.
+    pub thumbnail_edge: u32,
+    /// On the server: an rclone remote the catalogued paths of originals
+    /// can be fetched under, e.g. "nas:" or "nas:/volume1".
+    pub originals: Option<String>,
+    /// On the server: where fetched originals are kept.
+    pub cache: PathBuf,
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        RemoteConfig {
+            rclone: String::from("rclone"),
+            destination: None,
+            staging: PathBuf::from("mirror"),
+            thumbnail_edge: 400,
+            originals: None,
+            cache: PathBuf::from("originals"),
+        }
+    }
+}
+
+/// What `mirror` did.
+#[derive(Debug, Default, PartialEq)]
+pub struct Mirrored {
+    /// Thumbnails made this time
+    pub thumbnails: usize,
+    /// Photos no thumbnail could be made of
+    pub failed: usize,
+}
+
+/// Put together the mirror of the catalog `conn` has open and sync it to
+/// `destination` (`remote.destination` if not given).
+pub fn mirror(conn: &Connection, config: &Config, destination: Option<&str>) -> Result<Mirrored, Error> {
+    let remote = &config.remote;
+    let Some(destination) = destination.or(remote.destination.as_deref()) else {
+        return Err(CliError::new(ErrorKind::Config, "no remote to mirror to; pass --to or set remote.destination").into());
+    };
+    let thumbnails = remote.staging.join(THUMBNAILS);
+    fs::create_dir_all(&thumbnails)?;
+
+    let mut mirrored = Mirrored::default();
+    let mut hashes = HashSet::new();
+    let mut stmt = conn.prepare("SELECT path, content_hash FROM images WHERE content_hash IS NOT NULL ORDER BY id")?;
+    let images: Vec<(String, String)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<_, _>>()?;
+    for (path, hash) in images {
+        let thumbnail = thumbnails.join(format!("{}.jpg", hash));
+        if !hashes.insert(hash) || thumbnail.exists() {
+            continue;
+        }
+        match jobs::derivative(Path::new(&path), config, remote.thumbnail_edge) {
+            Ok(data) if image::guess_format(&data).ok() == Some(image::ImageFormat::Jpeg) => {
+                fs::write(&thumbnail, data)?;
+                mirrored.thumbnails += 1;
+            }
+            Ok(_) => {
+                tracing::warn!("Can't make a thumbnail of {}", path);
+                mirrored.failed += 1;
+            }
+            Err(e) => {
+                tracing::warn!("Can't make a thumbnail of {}: {}", path, e);
+                mirrored.failed += 1;
+            }
+        }
+    }
+    // Photos no longer catalogued
+    for entry in fs::read_dir(&thumbnails)? {
+        let path = entry?.path();
+        if path.file_stem().is_some_and(|stem| !hashes.contains(stem.to_string_lossy().as_ref())) {
+            fs::remove_file(path)?;
+        }
+    }
+
+    let snapshot = remote.staging.join(crate::CATALOG_PATH);
+    if snapshot.exists() {
+        fs::remove_file(&snapshot)?;
+    }
+    conn.execute("VACUUM INTO ?1", [snapshot.to_string_lossy()])?;
+    rclone(&remote.rclone, &["sync", &remote.staging.to_string_lossy(), destination])?;
+    Ok(mirrored)
+}
+
+/// The thumbnail of the photo with `hash` in the mirror around `catalog`,
+/// if it is one.
+#[cfg(feature = "server")]
+pub fn thumbnail(catalog: &Path, hash: &str) -> Option<PathBuf> {
+    let path = catalog.parent()?.join(THUMBNAILS).join(format!("{}.jpg", hash));
+    path.is_file().then_some(path)
+}
+
+/// The original catalogued at `path`: there, or else fetched from
+/// `remote.originals` (once).
+#[cfg(feature = "server")]
+pub fn original(path: &Path, config: &Config) -> Result<PathBuf, Error> {
+    if path.exists() {
+        return Ok(path.to_path_buf());
+    }
+    let Some(originals) = &config.remote.originals else {
+        bail!("{} isn't on this machine, and remote.originals isn't set", path.display());
+    };
+    let mut cached = config.remote.cache.clone();
+    cached.extend(path.components().filter(|component| matches!(component, std::path::Component::Normal(_))));
+    if !cached.exists() {
+        let source = format!("{}{}", originals.trim_end_matches('/'), path.to_string_lossy());
+        if let Some(parent) = cached.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        rclone(&config.remote.rclone, &["copyto", &source, &cached.to_string_lossy()])?;
+    }
+    Ok(cached)
+}
+
+fn rclone(program: &str, args: &[&str]) -> Result<(), Error> {
+    let output = match Command::new(program).args(args).output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => bail!("{} not found; install rclone or set remote.rclone", program),
+        Err(e) => return Err(e.into()),
+    };
+    if !output.status.success() {
+        bail!("{} {} failed: {}", program, args[0], String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use rusqlite::params;
+    use crate::test_support::fake_tool;
+
+    /// rclone for local paths, with remotes as directories: `sync` and
+    /// `copyto` of "remote:path" copy from or to the directory "remote".
+    fn fake_rclone(dir: &Path) -> Result<String, Error> {
+        let body = format!(
+            r#"cd "{}"; command="$1"; from=$(echo "$2" | tr -d :); to=$(echo "$3" | tr -d :)
+if [ "$command" = sync ]; then rm -rf "$to"; cp -r "$from" "$to"; else cp "$from" "$to"; fi"#,
+            dir.display(),
+        );
+        fake_tool(dir, &body)
+    }
+
+    #[test]
+    fn test_mirror() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let conn = crate::open_catalog(&dir.path().join("catalog.db"))?;
+        let photo = dir.path().join("beach.png");
+        image::DynamicImage::new_rgb8(800, 400).save(&photo)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, content_hash, description) VALUES
+                 (?1, 'beach.png', 1, 'abc', 'Sand'), ('/gone.jpg', 'gone.jpg', 1, 'def', NULL)",
+            params![photo.to_string_lossy()],
+        )?;
+        let mut config = Config::default();
+        config.remote.rclone = fake_rclone(dir.path())?;
+        config.remote.staging = dir.path().join("staging");
+        fs::create_dir_all(config.remote.staging.join(THUMBNAILS))?;
+        fs::write(config.remote.staging.join(THUMBNAILS).join("old.jpg"), "")?;
+
+        let error = mirror(&conn, &config, None).unwrap_err();
+        assert_eq!(crate::error::classify(&error), ErrorKind::Config);
+        assert_eq!(mirror(&conn, &config, Some("vps"))?, Mirrored { thumbnails: 1, failed: 1 });
+
+        let mirrored = dir.path().join("vps");
+        let thumbnail = image::open(mirrored.join(THUMBNAILS).join("abc.jpg"))?;
+        assert_eq!((thumbnail.width(), thumbnail.height()), (400, 200));
+        assert!(!mirrored.join(THUMBNAILS).join("old.jpg").exists());
+        let snapshot = Connection::open(mirrored.join(crate::CATALOG_PATH))?;
+        let description: String = snapshot.query_row("SELECT description FROM images WHERE content_hash = 'abc'", [], |row| row.get(0))?;
+        assert_eq!(description, "Sand");
+
+        // Only what's new the second time
+        assert_eq!(mirror(&conn, &config, Some("vps"))?, Mirrored { thumbnails: 0, failed: 1 });
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_original() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let nas = dir.path().join("nas");
+        fs::create_dir_all(nas.join("volume1"))?;
+        fs::write(nas.join("volume1/tram.jpg"), "tram")?;
+        let mut config = Config::default();
+        config.remote.rclone = fake_rclone(dir.path())?;
+        config.remote.cache = dir.path().join("cache");
+
+        let here = dir.path().join("nas/volume1/tram.jpg");
+        assert_eq!(original(&here, &config)?, here);
+        assert!(original(Path::new("/volume1/tram.jpg"), &config).is_err());
+        config.remote.originals = Some(String::from("nas:"));
+        let fetched = original(Path::new("/volume1/tram.jpg"), &config)?;
+        assert_eq!(fetched, dir.path().join("cache/volume1/tram.jpg"));
+        assert_eq!(fs::read_to_string(&fetched)?, "tram");
+        // Kept for next time
+        fs::remove_file(nas.join("volume1/tram.jpg"))?;
+        assert_eq!(original(Path::new("/volume1/tram.jpg"), &config)?, fetched);
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+//! `organize --into`: renaming and moving originals into folders and names
+//! made from what the catalog knows of them, e.g.
+//! `{YYYY}/{MM}/{YYYY}-{MM}-{DD}_{hh}{mm}{ss}_{camera}` for
+//! `2023/07/2023-07-14_183005_X100V.jpg`. Only photos with a capture time
+//! can be placed; the rest are left where they are.
+//!
+//! Nothing is ever overwritten: a name already taken, on disk or by
+//! another photo being moved, gets `_2`, `_3` and so on, and files are
+//! moved in a way that fails rather than replace one that turns up
+//! meanwhile. Each photo's row is updated in the same transaction as its
+//! file is moved, so the catalog never points at where a file isn't.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use anyhow::Error;
+use chrono::NaiveDateTime;
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use crate::error::{CliError, ErrorKind};
+use crate::{xmp, SearchFilter};
+
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+/// What a pattern can have in braces.
+const FIELDS: &[&str] = &["YYYY", "YY", "MM", "DD", "hh", "mm", "ss", "camera", "make", "model", "name", "ext"];
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrganizeConfig {
+    /// Where `organize --into` puts each photo, below that directory. The
+    /// original's extension is added unless the pattern has `{ext}`.
+    pub pattern: String,
+}
+
+impl Default for OrganizeConfig {
+    fn default() -> Self {
+        OrganizeConfig { pattern: String::from("{YYYY}/{MM}/{YYYY}-{MM}-{DD}_{hh}{mm}{ss}_{camera}") }
+    }
+}
+
+/// A pattern for paths, checked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern(String);
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Pattern, Error> {
+        let invalid = |message: String| -> Error { CliError::new(ErrorKind::Config, format!("organize pattern {}: {}", pattern, message)).into() };
+        let mut rest = pattern;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').ok_or_else(|| invalid(String::from("a { isn't closed")))?;
+            let field = &rest[start + 1..start + end];
+            if !FIELDS.contains(&field) {
+                return Err(invalid(format!("{{{}}} isn't one of {{{}}}", field, FIELDS.join("}, {"))));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        let path = Path::new(pattern);
+        if pattern.trim().is_empty() || !path.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(invalid(String::from("it has to be a relative path, with no `..`")));
+        }
+        Ok(Pattern(pattern.to_string()))
+    }
+
+    /// The relative path for a photo taken at `taken` with `camera` (make
+    /// and model), first named `original`.
+    fn render(&self, taken: &NaiveDateTime, camera: (Option<&str>, Option<&str>), original: &Path) -> PathBuf {
+        let stem = original.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let ext = original.extension().map(|ext| ext.to_string_lossy().to_lowercase()).unwrap_or_default();
+        let (make, model) = camera;
+        let value = |field: &str| -> String {
+            match field {
+                "YYYY" => taken.format("%Y").to_string(),
+                "YY" => taken.format("%y").to_string(),
+                "MM" => taken.format("%m").to_string(),
+                "DD" => taken.format("%d").to_string(),
+                "hh" => taken.format("%H").to_string(),
+                "mm" => taken.format("%M").to_string(),
+                "ss" => taken.format("%S").to_string(),
+                "camera" => component(model.or(make).unwrap_or("unknown")),
+                "make" => component(make.unwrap_or("unknown")),
+                "model" => component(model.unwrap_or("unknown")),
+                "name" => component(&stem),
+                _ => ext.clone(),
+            }
+        };
+        let mut rendered = String::new();
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').unwrap_or_default();
+            rendered.push_str(&rest[..start]);
+            rendered.push_str(&value(&rest[start + 1..end]));
+            rest = &rest[end + 1..];
+        }
+        rendered.push_str(rest);
+        if !self.0.contains("{ext}") && !ext.is_empty() {
+            rendered.push('.');
+            rendered.push_str(&ext);
+        }
+        PathBuf::from(rendered)
+    }
+}
+
+/// `value` as one safe path component: no separators or characters
+/// Windows refuses, and spaces as dashes.
+fn component(value: &str) -> String {
+    let cleaned: String = value
+        .trim()
+        .chars()
+        .map(|c| if c.is_whitespace() || c.is_control() || "/\\:*?\"<>|".contains(c) { '-' } else { c })
+        .collect();
+    let cleaned = cleaned.trim_matches(|c| c == '.' || c == '-');
+    if cleaned.is_empty() { String::from("unknown") } else { cleaned.to_string() }
+}
+
+/// A photo to move, and where.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rename {
+    pub id: i64,
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// What `organize --into` would do: the moves, and the photos left alone
+/// with why.
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub renames: Vec<Rename>,
+    pub skipped: Vec<(String, &'static str)>,
+}
+
+/// Where each photo matching `filter` goes under `into` by `pattern`.
+pub fn plan(conn: &Connection, filter: &SearchFilter, pattern: &Pattern, into: &Path) -> Result<Plan, Error> {
+    let (clause, params) = crate::filter_clause(filter);
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, path, creation_date, camera_make, camera_model FROM images{} ORDER BY creation_date, path",
+        clause,
+    ))?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?, row.get::<_, Option<String>>(3)?, row.get::<_, Option<String>>(4)?))
+    })?.collect::<Result<Vec<_>, _>>()?;
+
+    let mut plan = Plan::default();
+    // Destinations claimed so far, as case-insensitive file systems see them
+    let mut taken = HashSet::new();
+    for (id, path, date, make, model) in rows {
+        let from = PathBuf::from(&path);
+        if !from.is_file() {
+            plan.skipped.push((path, "not there"));
+            continue;
+        }
+        let Some(taken_at) = date.as_deref().and_then(|date| NaiveDateTime::parse_from_str(date, DATE_FORMAT).ok()) else {
+            plan.skipped.push((path, "no capture time"));
+            continue;
+        };
+        let wanted = into.join(pattern.render(&taken_at, (make.as_deref(), model.as_deref()), &from));
+        let to = free_name(&wanted, &from, &mut taken);
+        if to == from {
+            plan.skipped.push((path, "already there"));
+            continue;
+        }
+        plan.renames.push(Rename { id, from, to });
+    }
+    Ok(plan)
+}
+
+/// `wanted`, or the first of `wanted_2`, `wanted_3`... that nothing else
+/// has, on disk or in `taken`. A photo can keep its own name.
+fn free_name(wanted: &Path, from: &Path, taken: &mut HashSet<String>) -> PathBuf {
+    let stem = wanted.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let extension = wanted.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    let mut candidate = wanted.to_path_buf();
+    let mut copy = 1;
+    loop {
+        let free = candidate == from || !candidate.exists();
+        if free && taken.insert(candidate.to_string_lossy().to_lowercase()) {
+            return candidate;
+        }
+        copy += 1;
+        candidate = wanted.with_file_name(format!("{}_{}{}", stem, copy, extension));
+    }
+}
+
+/// Make `renames`, each with its XMP sidecar, updating the catalog as each
+/// file moves. Gives how many were moved.
+pub fn apply(conn: &Connection, renames: &[Rename]) -> Result<usize, Error> {
+    let mut moved = 0;
+    for rename in renames {
+        let (from, to) = (&rename.from, &rename.to);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let sidecar = xmp::find_sidecar(from);
+        let tx = conn.unchecked_transaction()?;
+        let file_name = to.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        tx.execute("UPDATE images SET path = ?1, file_name = ?2 WHERE id = ?3", params![to.to_string_lossy(), file_name, rename.id])?;
+        if let Err(e) = move_new(from, to) {
+            // Dropping the transaction leaves the row as it was
+            tracing::warn!("Not moving {}: {}", from.display(), e);
+            continue;
+        }
+        if let Err(e) = tx.commit() {
+            move_new(to, from)?;
+            return Err(e.into());
+        }
+        if let Some(sidecar) = sidecar {
+            let to_sidecar = sidecar_for(&sidecar, from, to);
+            if let Err(e) = move_new(&sidecar, &to_sidecar) {
+                tracing::warn!("Not moving {} with its photo: {}", sidecar.display(), e);
+            }
+        }
+        println!("Moved {} to {}", from.display(), to.display());
+        moved += 1;
+    }
+    if moved == 0 && !renames.is_empty() {
+        return Err(CliError::new(ErrorKind::PartialFailure, "no files could be moved").into());
+    }
+    Ok(moved)
+}
+
+/// Where `sidecar` of `from` goes with it to `to`, named the same way:
+/// `photo.jpg.xmp` or `photo.xmp`.
+fn sidecar_for(sidecar: &Path, from: &Path, to: &Path) -> PathBuf {
+    let name = sidecar.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let from_name = from.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    if name.len() > from_name.len() && name.starts_with(&from_name) {
+        PathBuf::from(format!("{}{}", to.to_string_lossy(), &name[from_name.len()..]))
+    } else {
+        to.with_extension(sidecar.extension().unwrap_or_default())
+    }
+}
+
+/// Move `from` to `to`, failing if `to` exists rather than replacing it:
+/// a hard link then an unlink, or across file systems a copy to a new
+/// file, keeping the modification time.
+fn move_new(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::hard_link(from, to) {
+        Ok(()) => return fs::remove_file(from),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(e),
+        Err(_) => {}
+    }
+    let mut source = File::open(from)?;
+    let modified = source.metadata()?.modified()?;
+    let mut target = OpenOptions::new().write(true).create_new(true).open(to)?;
+    if let Err(e) = io::copy(&mut source, &mut target).and_then(|_| target.set_modified(modified)) {
+        let _ = fs::remove_file(to);
+        return Err(e);
+    }
+    fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern() -> Result<(), Error> {
+        let pattern = Pattern::parse(&OrganizeConfig::default().pattern)?;
+        let taken = NaiveDateTime::parse_from_str("2023-07-14 18:30:05", DATE_FORMAT)?;
+        let rendered = pattern.render(&taken, (Some("FUJIFILM"), Some("X100V")), Path::new("/import/DSCF0042.JPG"));
+        assert_eq!(rendered, Path::new("2023/07/2023-07-14_183005_X100V.jpg"));
+        let by_name = Pattern::parse("{YY}/{make} {model}/{name}.{ext}")?;
+        assert_eq!(by_name.render(&taken, (None, Some("iPhone 14/Pro")), Path::new("/a/IMG 1.HEIC")), Path::new("23/unknown iPhone-14-Pro/IMG-1.heic"));
+
+        assert!(Pattern::parse("{YYYY}/{month}").is_err());
+        assert!(Pattern::parse("{YYYY/x").is_err());
+        assert!(Pattern::parse("/photos/{YYYY}").is_err());
+        assert!(Pattern::parse("../{YYYY}").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_organize() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let import = dir.path().join("import");
+        fs::create_dir(&import)?;
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        let files = [
+            ("a.jpg", Some("2023-07-14 18:30:05"), "X100V"),
+            ("b.jpg", Some("2023-07-14 18:30:05"), "X100V"),
+            ("c.jpg", None, "X100V"),
+            ("d.jpg", Some("2023-07-15 09:00:00"), "X100V"),
+        ];
+        for (name, date, camera) in files {
+            fs::write(import.join(name), name)?;
+            conn.execute(
+                "INSERT INTO images (path, file_name, file_size, creation_date, camera_model) VALUES (?1, ?2, 1, ?3, ?4)",
+                params![import.join(name).to_string_lossy(), name, date, camera],
+            )?;
+        }
+        fs::write(import.join("a.jpg.xmp"), "<x:xmpmeta/>")?;
+        let sorted = dir.path().join("sorted");
+        // Something already at d's destination stays
+        fs::create_dir_all(sorted.join("2023/07"))?;
+        fs::write(sorted.join("2023/07/2023-07-15_090000_X100V.jpg"), "someone else")?;
+
+        let pattern = Pattern::parse(&OrganizeConfig::default().pattern)?;
+        let plan = plan(&conn, &SearchFilter::default(), &pattern, &sorted)?;
+        let names: Vec<String> = plan.renames.iter().map(|rename| rename.to.strip_prefix(&sorted).unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, vec!["2023/07/2023-07-14_183005_X100V.jpg", "2023/07/2023-07-14_183005_X100V_2.jpg", "2023/07/2023-07-15_090000_X100V_2.jpg"]);
+        assert_eq!(plan.skipped, vec![(import.join("c.jpg").to_string_lossy().into_owned(), "no capture time")]);
+        // Only planned so far
+        assert!(import.join("a.jpg").exists());
+
+        assert_eq!(apply(&conn, &plan.renames)?, 3);
+        assert_eq!(fs::read_to_string(&plan.renames[1].to)?, "b.jpg");
+        assert!(plan.renames[0].to.with_file_name("2023-07-14_183005_X100V.jpg.xmp").is_file());
+        assert_eq!(fs::read_to_string(sorted.join("2023/07/2023-07-15_090000_X100V.jpg"))?, "someone else");
+        let (path, file_name): (String, String) = conn.query_row("SELECT path, file_name FROM images WHERE id = 2", [], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        assert_eq!((PathBuf::from(path), file_name.as_str()), (plan.renames[1].to.clone(), "2023-07-14_183005_X100V_2.jpg"));
+
+        // Run again, everything's in place
+        let again = super::plan(&conn, &SearchFilter::default(), &pattern, &sorted)?;
+        assert!(again.renames.is_empty());
+        assert_eq!(again.skipped.len(), 4);
+
+        assert!(move_new(&plan.renames[0].to, &plan.renames[1].to).is_err());
+        assert!(plan.renames[0].to.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sidecar_for() {
+        let (from, to) = (Path::new("/a/IMG_1.jpg"), Path::new("/b/2023.jpg"));
+        assert_eq!(sidecar_for(Path::new("/a/IMG_1.jpg.xmp"), from, to), Path::new("/b/2023.jpg.xmp"));
+        assert_eq!(sidecar_for(Path::new("/a/IMG_1.XMP"), from, to), Path::new("/b/2023.XMP"));
+    }
+}
@@ -0,0 +1,198 @@
+//! What a scan did, for whatever runs it unattended: `scan --report` (or
+//! `[scan] report`) writes it as JSON when the scan ends, however it ends.
+//! `--max-failures` is how many failed images a scan can have before it's
+//! a failure itself, as a count or a share of the images it tried; by
+//! default any one is.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+use anyhow::{Context, Error};
+use serde::Serialize;
+use crate::error;
+
+/// How many of a scan's images may fail before the scan does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold {
+    Count(usize),
+    /// Of the images tried
+    Percent(f64),
+}
+
+impl Threshold {
+    /// Whether `failed` of `tried` images is more than it allows.
+    pub fn exceeded(self, failed: usize, tried: usize) -> bool {
+        match self {
+            Threshold::Count(count) => failed > count,
+            Threshold::Percent(percent) => failed as f64 > tried as f64 * percent / 100.0,
+        }
+    }
+}
+
+impl Default for Threshold {
+    fn default() -> Self {
+        Threshold::Count(0)
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Threshold::Count(count) => write!(f, "{}", count),
+            Threshold::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+/// A threshold like "10" or "2.5%".
+pub fn parse_threshold(threshold: &str) -> Result<Threshold, String> {
+    let invalid = || format!("invalid failure threshold {:?}; it's a count like \"10\" or a percentage like \"2%\"", threshold);
+    let trimmed = threshold.trim();
+    match trimmed.strip_suffix('%') {
+        Some(percent) => match percent.trim().parse::<f64>() {
+            Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(Threshold::Percent(percent)),
+            _ => Err(invalid()),
+        },
+        None => trimmed.parse().map(Threshold::Count).map_err(|_| invalid()),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    #[serde(skip)]
+    clock: Instant,
+    pub started_at: String,
+    pub finished_at: String,
+    pub duration_secs: f64,
+    /// Supported files found, the moved and skipped ones included
+    pub discovered: usize,
+    pub processed: usize,
+    /// Catalogued before under another path
+    pub moved: usize,
+    /// Saved already by the scan being resumed
+    pub skipped: usize,
+    pub failed: usize,
+    /// Images processed a second
+    pub throughput: f64,
+    pub failures: Vec<Failure>,
+    /// Each folder or photo scanned, and why its scan didn't finish
+    pub targets: Vec<Target>,
+    pub exit_code: u8,
+    /// As the summary ending standard error has it
+    pub error: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Failure {
+    pub path: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Target {
+    pub path: String,
+    pub error: Option<String>,
+}
+
+impl Report {
+    pub fn start() -> Report {
+        Report {
+            clock: Instant::now(),
+            started_at: chrono::Utc::now().to_rfc3339(),
+            finished_at: String::new(),
+            duration_secs: 0.0,
+            discovered: 0,
+            processed: 0,
+            moved: 0,
+            skipped: 0,
+            failed: 0,
+            throughput: 0.0,
+            failures: Vec::new(),
+            targets: Vec::new(),
+            exit_code: 0,
+            error: None,
+        }
+    }
+
+    /// Count `path` as failed, for `reason`.
+    pub fn fail(&mut self, path: &Path, reason: &Error) {
+        self.failed += 1;
+        self.failures.push(Failure { path: path.display().to_string(), reason: format!("{:#}", reason) });
+    }
+
+    /// How the scan of the folder or photo at `path` ended.
+    pub fn target(&mut self, path: &Path, error: Option<&Error>) {
+        let error = error.map(|e| format!("{:#}", e));
+        self.targets.push(Target { path: path.display().to_string(), error });
+    }
+
+    /// Close the report with how the whole of the scan ended.
+    pub fn finish(&mut self, result: &Result<(), Error>) {
+        self.finished_at = chrono::Utc::now().to_rfc3339();
+        self.duration_secs = self.clock.elapsed().as_secs_f64();
+        self.throughput = match self.duration_secs {
+            secs if secs > 0.0 => self.processed as f64 / secs,
+            _ => 0.0,
+        };
+        self.exit_code = result.as_ref().err().map_or(0, |e| error::classify(e).exit_code());
+        self.error = result.as_ref().err().map(|e| error::summary(e)["error"].clone());
+    }
+
+    /// Write the report to `path`, atomically, so a watcher never reads
+    /// half of one.
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let partial = path.with_extension("part");
+        fs::write(&partial, serde_json::to_vec_pretty(self)?)
+            .and_then(|_| fs::rename(&partial, path))
+            .with_context(|| format!("writing the scan report to {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{CliError, ErrorKind};
+
+    #[test]
+    fn test_parse_threshold() {
+        assert_eq!(parse_threshold("10"), Ok(Threshold::Count(10)));
+        assert_eq!(parse_threshold(" 2.5% "), Ok(Threshold::Percent(2.5)));
+        assert!(parse_threshold("-1").is_err());
+        assert!(parse_threshold("150%").is_err());
+        assert!(parse_threshold("a few").is_err());
+        assert_eq!(parse_threshold(&Threshold::Percent(2.5).to_string()), Ok(Threshold::Percent(2.5)));
+    }
+
+    #[test]
+    fn test_exceeded() {
+        assert!(!Threshold::default().exceeded(0, 100));
+        assert!(Threshold::default().exceeded(1, 100));
+        assert!(!Threshold::Count(3).exceeded(3, 100));
+        assert!(Threshold::Count(3).exceeded(4, 100));
+        assert!(!Threshold::Percent(2.0).exceeded(2, 100));
+        assert!(Threshold::Percent(2.0).exceeded(3, 100));
+    }
+
+    #[test]
+    fn test_report() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let mut report = Report::start();
+        report.discovered = 3;
+        report.processed = 2;
+        report.fail(Path::new("/photos/b.jpg"), &anyhow::anyhow!("not an image"));
+        let result = Err(CliError::new(ErrorKind::PartialFailure, "1 of 3 images failed").into());
+        report.target(Path::new("/photos"), result.as_ref().err());
+        report.finish(&result);
+        report.write(&dir.path().join("report.json"))?;
+
+        let written: serde_json::Value = serde_json::from_slice(&fs::read(dir.path().join("report.json"))?)?;
+        assert_eq!(written["failed"], 1);
+        assert_eq!(written["failures"][0]["reason"], "not an image");
+        assert_eq!(written["targets"][0]["error"], "1 of 3 images failed");
+        assert_eq!(written["exit_code"], 5);
+        assert_eq!(written["error"]["kind"], "partial_failure");
+        assert!(written.get("clock").is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+//! Scan sessions, so a scan that didn't finish (Ctrl-C, `--max-duration`,
+//! the power going, a lost analyzer) can be picked up with `scan --resume`
+//! instead of analyzing everything again. A session is one folder's scan:
+//! each file is recorded in it as it's saved to the catalog, in the same
+//! transaction, so a file is never counted done without its row or the
+//! other way round. A scan that finishes closes its session; one resumed
+//! skips the files its session has and retries the ones that failed.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use anyhow::Error;
+use rusqlite::{params, Connection, OptionalExtension};
+
+pub struct Session {
+    pub id: i64,
+    /// Files saved by the scans of this session so far
+    done: HashSet<String>,
+}
+
+fn key(path: &Path) -> String {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()).to_string_lossy().into_owned()
+}
+
+/// The session for a scan of `dir` by `operation_id`: the last one that
+/// didn't finish if `resume` (printing what it had), else a new one in
+/// place of any that didn't.
+pub fn open(conn: &Connection, dir: &Path, operation_id: i64, resume: bool) -> Result<Session, Error> {
+    let dir = key(dir);
+    let unfinished: Option<(i64, String)> = conn.query_row(
+        "SELECT id, started_at FROM scan_sessions WHERE dir = ?1 AND finished_at IS NULL ORDER BY id DESC LIMIT 1",
+        [&dir],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+    match (unfinished, resume) {
+        (Some((id, started_at)), true) => {
+            conn.execute("UPDATE scan_sessions SET operation_id = ?1 WHERE id = ?2", params![operation_id, id])?;
+            let done: HashSet<String> = conn.prepare("SELECT path FROM scan_files WHERE session_id = ?1")?
+                .query_map([id], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            tracing::info!("Resuming the scan started {}, {} files in", started_at, done.len());
+            return Ok(Session { id, done });
+        }
+        (None, true) => tracing::info!("No unfinished scan of {} to resume; scanning all of it", dir),
+        _ => {}
+    }
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM scan_files WHERE session_id IN (SELECT id FROM scan_sessions WHERE dir = ?1 AND finished_at IS NULL)", [&dir])?;
+    tx.execute("DELETE FROM scan_sessions WHERE dir = ?1 AND finished_at IS NULL", [&dir])?;
+    tx.execute(
+        "INSERT INTO scan_sessions (dir, operation_id, started_at) VALUES (?1, ?2, ?3)",
+        params![dir, operation_id, chrono::Utc::now().to_rfc3339()],
+    )?;
+    let id = tx.last_insert_rowid();
+    tx.commit()?;
+    Ok(Session { id, done: HashSet::new() })
+}
+
+/// The folders whose last scan didn't finish, the oldest first.
+pub fn interrupted(conn: &Connection) -> Result<Vec<PathBuf>, Error> {
+    let dirs = conn.prepare("SELECT dir FROM scan_sessions WHERE finished_at IS NULL GROUP BY dir ORDER BY MIN(id)")?
+        .query_map([], |row| Ok(PathBuf::from(row.get::<_, String>(0)?)))?
+        .collect::<Result<_, _>>()?;
+    Ok(dirs)
+}
+
+impl Session {
+    /// Whether an earlier scan of this session saved `path`.
+    pub fn is_done(&self, path: &Path) -> bool {
+        self.done.contains(&key(path))
+    }
+
+    /// Count `path` as saved, in whatever transaction saved it.
+    pub fn record(&self, conn: &Connection, path: &Path) -> Result<(), Error> {
+        conn.prepare_cached("INSERT OR IGNORE INTO scan_files (session_id, path) VALUES (?1, ?2)")?
+            .execute(params![self.id, key(path)])?;
+        Ok(())
+    }
+
+    /// Close the session, leaving nothing to resume; its files' list goes.
+    pub fn finish(&self, conn: &Connection) -> Result<(), Error> {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute("UPDATE scan_sessions SET finished_at = ?1 WHERE id = ?2", params![chrono::Utc::now().to_rfc3339(), self.id])?;
+        tx.execute("DELETE FROM scan_files WHERE session_id = ?1", [self.id])?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        let operation = crate::progress::start(&conn, "scan", crate::cancel::CancelToken::new())?;
+        let (photos, usb) = (Path::new("/photos"), Path::new("/media/usb"));
+
+        let session = open(&conn, photos, operation.id, false)?;
+        session.record(&conn, &photos.join("a.jpg"))?;
+        session.record(&conn, &photos.join("a.jpg"))?;
+        open(&conn, usb, operation.id, false)?.finish(&conn)?;
+        assert_eq!(interrupted(&conn)?, vec![photos.to_path_buf()]);
+
+        let resumed = open(&conn, photos, operation.id, true)?;
+        assert_eq!(resumed.id, session.id);
+        assert!(resumed.is_done(&photos.join("a.jpg")) && !resumed.is_done(&photos.join("b.jpg")));
+        // Nothing to resume for a finished one, and starting over forgets
+        // what was done
+        assert!(open(&conn, usb, operation.id, true)?.done.is_empty());
+        let again = open(&conn, photos, operation.id, false)?;
+        assert!(!again.is_done(&photos.join("a.jpg")));
+        let sessions: i64 = conn.query_row("SELECT COUNT(*) FROM scan_sessions WHERE dir = '/photos'", [], |row| row.get(0))?;
+        assert_eq!(sessions, 1);
+
+        again.finish(&conn)?;
+        assert_eq!(interrupted(&conn)?, vec![usb.to_path_buf()]);
+        Ok(())
+    }
+}
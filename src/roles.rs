@@ -0,0 +1,143 @@
+//! Who may do what through the API, for a catalog server several people
+//! share. Each of the `[[users]]` has a role and a token, and requests say
+//! whose they are with `Authorization: Bearer <token>`, or Basic with the
+//! user's name and token (which browsers ask for themselves). Viewers can
+//! search and look; curators can also edit captions; admins can also start
+//! and cancel scans and exports. With no users configured, the API is open
+//! to anyone, as admin.
+
+#[cfg(feature = "server")]
+use anyhow::Error;
+#[cfg(feature = "server")]
+use base64::Engine;
+use serde::Deserialize;
+#[cfg(feature = "server")]
+use crate::error::{CliError, ErrorKind};
+#[cfg(feature = "server")]
+use crate::hash;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Curator,
+    Admin,
+}
+
+#[cfg(feature = "server")]
+impl Role {
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Curator => "curator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// Read by `serve` only, but accepted by every build, so one configuration
+/// file does for all.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    /// Environment variable holding the user's token.
+    pub token_env: String,
+}
+
+/// The users, with their tokens read from the environment.
+#[cfg(feature = "server")]
+pub struct Users(Vec<(String, Role, String)>);
+
+/// Why a request was turned away.
+#[cfg(feature = "server")]
+#[derive(Debug, PartialEq)]
+pub enum Denied {
+    /// No token, or not one of the users'
+    Unknown,
+    /// A user whose role isn't enough
+    Forbidden { name: String, role: Role },
+}
+
+#[cfg(feature = "server")]
+impl Users {
+    pub fn load(users: &[User]) -> Result<Users, Error> {
+        let mut loaded = Vec::new();
+        for user in users {
+            let token = std::env::var(&user.token_env).ok().filter(|token| !token.is_empty()).ok_or_else(|| {
+                CliError::new(ErrorKind::Config, format!("environment variable {} (the token of {}) is not set", user.token_env, user.name))
+            })?;
+            loaded.push((user.name.clone(), user.role, token));
+        }
+        Ok(Users(loaded))
+    }
+
+    /// Whether a request with `authorization` (its Authorization header)
+    /// may do what takes `needed`, and the name of the user making it if
+    /// there are users.
+    pub fn authorize(&self, authorization: Option<&str>, needed: Role) -> Result<Option<String>, Denied> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        let (name, token) = authorization.and_then(credentials).ok_or(Denied::Unknown)?;
+        // Compared by hash, so how long the comparison takes says
+        // nothing about the token
+        let token = hash::of_bytes(token.as_bytes());
+        let (user, role, _) = self.0.iter()
+            .find(|(user, _, known)| hash::of_bytes(known.as_bytes()) == token && name.as_ref().is_none_or(|name| name == user))
+            .ok_or(Denied::Unknown)?;
+        match *role >= needed {
+            true => Ok(Some(user.clone())),
+            false => Err(Denied::Forbidden { name: user.clone(), role: *role }),
+        }
+    }
+}
+
+/// The user name, if given, and token in an Authorization header.
+#[cfg(feature = "server")]
+fn credentials(authorization: &str) -> Option<(Option<String>, String)> {
+    let (scheme, value) = authorization.trim().split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return Some((None, value.trim().to_string()));
+    }
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64::engine::general_purpose::STANDARD.decode(value.trim()).ok()?).ok()?;
+    let (name, token) = decoded.split_once(':')?;
+    Some((Some(name.to_string()), token.to_string()))
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorize() {
+        let open = Users(Vec::new());
+        assert_eq!(open.authorize(None, Role::Admin), Ok(None));
+
+        let users = Users(vec![
+            (String::from("ana"), Role::Admin, String::from("s3cret")),
+            (String::from("rui"), Role::Viewer, String::from("hunter2")),
+        ]);
+        assert_eq!(users.authorize(Some("Bearer s3cret"), Role::Admin), Ok(Some(String::from("ana"))));
+        assert_eq!(users.authorize(Some("Bearer hunter2"), Role::Viewer), Ok(Some(String::from("rui"))));
+        assert_eq!(
+            users.authorize(Some("Bearer hunter2"), Role::Curator),
+            Err(Denied::Forbidden { name: String::from("rui"), role: Role::Viewer }),
+        );
+        assert_eq!(users.authorize(None, Role::Viewer), Err(Denied::Unknown));
+        assert_eq!(users.authorize(Some("Bearer guess"), Role::Viewer), Err(Denied::Unknown));
+
+        let basic = |credentials: &str| format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials));
+        assert_eq!(users.authorize(Some(&basic("ana:s3cret")), Role::Admin), Ok(Some(String::from("ana"))));
+        // Someone else's name with the token
+        assert_eq!(users.authorize(Some(&basic("rui:s3cret")), Role::Viewer), Err(Denied::Unknown));
+
+        let error = Users::load(&[User { name: String::from("ana"), role: Role::Admin, token_env: String::from("PHOTOCATALOGER_TEST_UNSET") }]);
+        assert!(error.is_err_and(|error| crate::error::classify(&error) == ErrorKind::Config));
+    }
+}
@@ -0,0 +1,143 @@
+//! Scenes of videos. A long family video is many moments, and one frame
+//! a third of the way in says little about the cake at 02:13, so with
+//! `video.scenes` on, videos are split where the picture cuts (by ffmpeg's
+//! scene detection) and a frame from the middle of each scene is captioned.
+//! Scenes are kept with their times and indexed for full-text search, so
+//! `search --scene "birthday cake"` says where in the video to look.
+
+use std::path::Path;
+use anyhow::Error;
+use rusqlite::{params, Connection};
+use crate::analyzer::AnalyzerPool;
+use crate::cancel::CancelToken;
+use crate::config::VideoConfig;
+use crate::{notes, video};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scene {
+    pub start_secs: f64,
+    pub end_secs: f64,
+    pub description: String,
+    pub keywords: String,
+}
+
+/// The spans between `cuts` over a video of `duration` seconds, with the
+/// short ones joined to a neighbor and no more than `config.max_scenes`.
+fn spans(cuts: &[f64], duration: f64, config: &VideoConfig) -> Vec<(f64, f64)> {
+    let mut bounds = vec![0.0];
+    bounds.extend(cuts.iter().copied().filter(|cut| *cut > 0.0 && *cut < duration));
+    bounds.push(duration);
+    let mut spans: Vec<(f64, f64)> = bounds.windows(2).map(|pair| (pair[0], pair[1])).collect();
+    let length = |span: &(f64, f64)| span.1 - span.0;
+    while spans.len() > 1 {
+        let (shortest, span) = spans.iter().enumerate()
+            .min_by(|a, b| length(a.1).total_cmp(&length(b.1)))
+            .map(|(i, span)| (i, *span))
+            .unwrap_or_default();
+        if length(&span) >= config.min_scene_secs && spans.len() <= config.max_scenes.max(1) {
+            break;
+        }
+        // Into whichever neighbor is shorter
+        let into = match (shortest.checked_sub(1), spans.get(shortest + 1)) {
+            (Some(before), Some(after)) if length(after) < length(&spans[before]) => shortest + 1,
+            (Some(before), _) => before,
+            (None, _) => shortest + 1,
+        };
+        let joined = (spans[shortest.min(into)].0, spans[shortest.max(into)].1);
+        spans[shortest.min(into)] = joined;
+        spans.remove(shortest.max(into));
+    }
+    spans
+}
+
+/// Caption a frame from each scene of the video at `path`. Videos of a
+/// single scene have none: the video's own caption covers them.
+pub fn caption(path: &Path, duration: Option<f64>, config: &VideoConfig, analyzer: &AnalyzerPool, cancel: &CancelToken) -> Result<Vec<Scene>, Error> {
+    let Some(duration) = duration else { return Ok(Vec::new()) };
+    let spans = spans(&video::scene_cuts(path, config)?, duration, config);
+    if spans.len() < 2 {
+        return Ok(Vec::new());
+    }
+    let mut scenes = Vec::new();
+    for (start_secs, end_secs) in spans {
+        let frame = video::frame_at(path, (start_secs + end_secs) / 2.0, config)?;
+        tracing::debug!("Scene at {}:", timestamp(start_secs));
+        let analysis = analyzer.analyze_blocking(&frame, None, cancel)?;
+        scenes.push(Scene { start_secs, end_secs, description: analysis.description, keywords: analysis.keywords });
+    }
+    Ok(scenes)
+}
+
+pub fn save(conn: &Connection, image_id: i64, scenes: &[Scene]) -> rusqlite::Result<()> {
+    let mut insert = conn.prepare_cached("INSERT INTO scenes (image_id, start_secs, end_secs, description, keywords) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+    for scene in scenes {
+        insert.execute(params![image_id, scene.start_secs, scene.end_secs, scene.description, scene.keywords])?;
+    }
+    Ok(())
+}
+
+/// The scenes of the video at `path` with all of `words` in their
+/// description or keywords, in order.
+pub fn matching(conn: &Connection, path: &str, words: &str) -> Result<Vec<Scene>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT start_secs, end_secs, description, keywords FROM scenes
+         WHERE image_id IN (SELECT id FROM images WHERE path = ?1)
+           AND id IN (SELECT rowid FROM scenes_fts WHERE scenes_fts MATCH ?2)
+         ORDER BY start_secs",
+    )?;
+    let scenes = stmt.query_map(params![path, notes::match_query(words)], |row| {
+        Ok(Scene { start_secs: row.get(0)?, end_secs: row.get(1)?, description: row.get(2)?, keywords: row.get(3)? })
+    })?;
+    Ok(scenes.collect::<Result<_, _>>()?)
+}
+
+/// "02:13", or "1:02:13" an hour or more in.
+pub fn timestamp(secs: f64) -> String {
+    let secs = secs as u64;
+    match secs / 3600 {
+        0 => format!("{:02}:{:02}", secs / 60, secs % 60),
+        hours => format!("{}:{:02}:{:02}", hours, secs / 60 % 60, secs % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spans() {
+        let config = VideoConfig::default();
+        assert_eq!(spans(&[], 30.0, &config), vec![(0.0, 30.0)]);
+        // The half-second flash at 10 joins the shorter scene next to it
+        assert_eq!(spans(&[10.0, 10.5, 40.0], 60.0, &config), vec![(0.0, 10.5), (10.5, 40.0), (40.0, 60.0)]);
+        let few = VideoConfig { max_scenes: 2, ..VideoConfig::default() };
+        assert_eq!(spans(&[10.0, 30.0, 45.0], 60.0, &few), vec![(0.0, 30.0), (30.0, 60.0)]);
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(133.4), "02:13");
+        assert_eq!(timestamp(3733.0), "1:02:13");
+    }
+
+    #[test]
+    fn test_search_scenes() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute("INSERT INTO images (path, file_name, file_size) VALUES ('/party.mp4', 'party.mp4', 1), ('/beach.mp4', 'beach.mp4', 1)", [])?;
+        let scene = |start_secs, description: &str, keywords: &str| Scene {
+            start_secs,
+            end_secs: start_secs + 30.0,
+            description: description.to_string(),
+            keywords: keywords.to_string(),
+        };
+        save(&conn, 1, &[scene(0.0, "Children arriving at a party", "party, children"), scene(133.0, "A girl blowing out candles", "birthday cake, candles")])?;
+        save(&conn, 2, &[scene(0.0, "Waves on a beach", "beach, sea"), scene(30.0, "A birthday picnic", "picnic")])?;
+
+        let found = matching(&conn, "/party.mp4", "birthday cake")?;
+        assert_eq!(found.iter().map(|scene| scene.start_secs).collect::<Vec<_>>(), vec![133.0]);
+        let filter = crate::SearchFilter { scene: Some(String::from("birthday")), ..crate::SearchFilter::default() };
+        assert_eq!(crate::search_images(&conn, &filter)?, vec!["/beach.mp4", "/party.mp4"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+//! Processing windows: the times of day background work may run, written as
+//! "01:00-06:00". A window may wrap past midnight ("22:00-06:00").
+
+use std::fmt;
+use chrono::{NaiveTime, Timelike};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Window, String> {
+        let invalid = || format!("invalid processing window {:?}, expected e.g. \"01:00-06:00\"", s);
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|_| invalid());
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start == end {
+            return Err(invalid());
+        }
+        Ok(Window { start, end })
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start.format("%H:%M"), self.end.format("%H:%M"))
+    }
+}
+
+/// Whether work may run at `time`; no windows means any time.
+pub fn is_open(windows: &[Window], time: NaiveTime) -> bool {
+    windows.is_empty() || windows.iter().any(|w| w.contains(time))
+}
+
+/// When the next window opens after `time`, for telling the user.
+pub fn next_opening(windows: &[Window], time: NaiveTime) -> Option<NaiveTime> {
+    let seconds = |t: NaiveTime| t.num_seconds_from_midnight() as i64;
+    windows.iter()
+        .map(|w| w.start)
+        .min_by_key(|start| (seconds(*start) - seconds(time)).rem_euclid(86400))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_windows() {
+        let night = Window::try_from(String::from("01:00-06:00")).unwrap();
+        let evening = Window::try_from(String::from("22:30 - 00:30")).unwrap();
+        assert!(night.contains(at(1, 0)) && night.contains(at(5, 59)));
+        assert!(!night.contains(at(6, 0)) && !night.contains(at(12, 0)));
+        assert!(evening.contains(at(23, 0)) && evening.contains(at(0, 15)));
+        assert!(!evening.contains(at(0, 30)));
+        assert_eq!(evening.to_string(), "22:30-00:30");
+
+        let windows = [night, evening];
+        assert!(is_open(&[], at(12, 0)));
+        assert!(!is_open(&windows, at(12, 0)));
+        assert_eq!(next_opening(&windows, at(12, 0)), Some(at(22, 30)));
+        assert_eq!(next_opening(&windows, at(0, 45)), Some(at(1, 0)));
+
+        for bad in ["01:00", "1am-6am", "06:00-06:00"] {
+            assert!(Window::try_from(bad.to_string()).is_err());
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Sequences: bursts and runs of photos of one moment. A single frame of
+//! one can be hard to make sense of (a hand, a blurred candle), so when
+//! `[analyzer.sequence]` asks for it the photos taken just before and after
+//! go along to the model, which then describes the frame as part of the
+//! sequence.
+//!
+//! Neighbors are the photos next to one in its folder, by name, as long as
+//! each was taken within `gap_secs` of the one before it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Error};
+use chrono::{DateTime, Local, NaiveDateTime};
+use exif::{In, Reader, Tag};
+use crate::analyzer::Neighbors;
+use crate::config::SequenceConfig;
+
+/// More than this and the model loses track of which photo it's describing.
+pub const MAX_FRAMES: usize = 4;
+
+pub fn validate(config: &SequenceConfig) -> Result<(), Error> {
+    if config.frames > MAX_FRAMES {
+        bail!("analyzer.sequence.frames can be at most {}", MAX_FRAMES);
+    }
+    Ok(())
+}
+
+/// Only formats every model takes as they are go along; the photo itself
+/// can be anything the scanner converts.
+fn is_frame(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .is_some_and(|ext| matches!(ext.as_str(), "jpg" | "jpeg" | "png"))
+}
+
+/// When the photo was taken, by its EXIF date or else its modification time.
+fn taken(path: &Path) -> Option<NaiveDateTime> {
+    let exif = fs::File::open(path).ok()
+        .and_then(|file| Reader::new().read_from_container(&mut std::io::BufReader::new(file)).ok());
+    let date = exif.as_ref()
+        .and_then(|exif| exif.get_field(Tag::DateTimeOriginal, In::PRIMARY))
+        .and_then(|field| NaiveDateTime::parse_from_str(&field.display_value().to_string(), "%Y-%m-%d %H:%M:%S").ok());
+    date.or_else(|| {
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok()?;
+        Some(DateTime::<Local>::from(modified).naive_local())
+    })
+}
+
+/// The photos on one side of a sequence, nearest first: as long as each is
+/// within `gap_secs` of the last.
+fn run<'a>(photos: impl Iterator<Item = &'a PathBuf>, start: NaiveDateTime, config: &SequenceConfig) -> Vec<&'a PathBuf> {
+    let mut last = start;
+    let mut run = Vec::new();
+    for photo in photos.take(config.frames) {
+        let Some(time) = taken(photo) else { break };
+        if (time - last).num_seconds().unsigned_abs() > config.gap_secs {
+            break;
+        }
+        last = time;
+        run.push(photo);
+    }
+    run
+}
+
+/// Up to `frames` photos of the same sequence as `path`, split between
+/// those before and after it.
+pub fn neighbors(path: &Path, config: &SequenceConfig) -> Neighbors {
+    let none = Neighbors::default();
+    if config.frames == 0 {
+        return none;
+    }
+    let Some(dir) = path.parent() else { return none };
+    let Ok(entries) = fs::read_dir(dir) else { return none };
+    let mut photos: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|photo| photo == path || (photo.is_file() && is_frame(photo)))
+        .collect();
+    photos.sort();
+    let (Some(index), Some(start)) = (photos.iter().position(|photo| photo == path), taken(path)) else { return none };
+
+    let mut before = run(photos[..index].iter().rev(), start, config);
+    let mut after = run(photos[index + 1..].iter(), start, config);
+    // Share the frames between the two sides, nearest first
+    let mut kept = (0, 0);
+    while kept.0 + kept.1 < config.frames && (kept.0 < before.len() || kept.1 < after.len()) {
+        if kept.1 < after.len() && (kept.1 < kept.0 || kept.0 == before.len()) {
+            kept.1 += 1;
+        } else {
+            kept.0 += 1;
+        }
+    }
+    before.truncate(kept.0);
+    before.reverse();
+    after.truncate(kept.1);
+    let read = |photos: Vec<&PathBuf>| photos.into_iter().filter_map(|photo| fs::read(photo).ok()).collect();
+    Neighbors { before: read(before), after: read(after) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_neighbors() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let start = SystemTime::now() - Duration::from_secs(3600);
+        // A burst of five two seconds apart, then another photo a minute later
+        for (i, offset) in [0, 2, 4, 6, 8, 68].into_iter().enumerate() {
+            let photo = dir.path().join(format!("IMG_{}.jpg", i));
+            fs::write(&photo, [i as u8])?;
+            fs::File::options().write(true).open(&photo)?.set_modified(start + Duration::from_secs(offset))?;
+        }
+        let photo = |i: usize| dir.path().join(format!("IMG_{}.jpg", i));
+        let config = |frames| SequenceConfig { frames, gap_secs: 10 };
+
+        assert_eq!(neighbors(&photo(2), &config(0)), Neighbors::default());
+        assert_eq!(neighbors(&photo(2), &config(2)), Neighbors { before: vec![vec![1]], after: vec![vec![3]] });
+        assert_eq!(neighbors(&photo(2), &config(4)), Neighbors { before: vec![vec![0], vec![1]], after: vec![vec![3], vec![4]] });
+        // The first of the burst gets the ones after it, and the burst
+        // ends before the last photo
+        assert_eq!(neighbors(&photo(0), &config(3)), Neighbors { before: vec![], after: vec![vec![1], vec![2], vec![3]] });
+        assert_eq!(neighbors(&photo(4), &config(4)), Neighbors { before: vec![vec![0], vec![1], vec![2], vec![3]], after: vec![] });
+        assert_eq!(neighbors(&photo(5), &config(4)), Neighbors::default());
+
+        assert!(validate(&config(4)).is_ok());
+        assert!(validate(&config(5)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,1340 @@
+//! The HTTP API. Scans and exports started here run in the background as
+//! tracked operations (see `progress`), so clients poll `/jobs/{id}` for
+//! progress instead of waiting on the request, or follow
+//! `/jobs/{id}/events`; so are ZIPs of many originals, fetched from
+//! `/downloads/{id}` once made (see `downloads`). The catalog itself is
+//! read through `/images`, or `/graphql` (see `graphql`).
+//!
+//! Other catalogs can be attached (`[[web.catalogs]]`, `serve --attach`),
+//! so an archive too big to merge stays a catalog of its own and is still
+//! searched with this one: each one's images are under `/catalogs/{name}`,
+//! and `/images` lists all of theirs together, each with its catalog's
+//! name and the URL it's at.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use anyhow::Error;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Path, Query, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use futures_util::Stream;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use crate::cancel::{self, CancelToken};
+use crate::config::Config;
+use crate::error::{self, CliError, ErrorKind};
+use crate::progress::{self, Status};
+use crate::roles::{Denied, Role, Users};
+use crate::edits::Editor;
+use crate::bandwidth::Bandwidth;
+use crate::{audit, corrections, culling, downloads, duplicates, edits, export, fields, graphql, histogram, jobs, notes, portable, preferences, priority, privacy, query, remote, scenes, share, tags, tiering, video, web};
+
+/// How often `/jobs/{id}/events` looks for progress.
+const EVENT_INTERVAL: Duration = Duration::from_millis(500);
+/// Images listed when the request doesn't say.
+const DEFAULT_LIMIT: usize = 100;
+/// Longest edge of thumbnails when the request doesn't say, and at most.
+const THUMBNAIL_EDGE: u32 = 400;
+const MAX_THUMBNAIL_EDGE: u32 = 2048;
+/// Bytes of a file sent at a time.
+const CHUNK: usize = 64 << 10;
+
+struct AppState {
+    catalog: PathBuf,
+    config: Arc<Config>,
+    local_only: bool,
+    users: Users,
+    bandwidth: Bandwidth,
+    /// Held while a video is transcoded, one at a time
+    transcoding: tokio::sync::Mutex<()>,
+    graphql: graphql::Schema,
+    /// Claimed while making what the web page is waiting on (see `priority`)
+    lane: priority::Lane,
+    /// Which photos are on network shares, whose thumbnails are kept
+    shares: share::Shares,
+    /// The threads of operations started here, waited for on shutdown
+    background: std::sync::Mutex<Vec<thread::JoinHandle<()>>>,
+    /// Where its images are served, below `/images`
+    prefix: String,
+    /// Its name, when there are others attached to list with it
+    name: Option<String>,
+    /// The catalogs attached to this one
+    attached: Vec<Arc<AppState>>,
+}
+
+/// Serve the API on `listen` until the process is stopped.
+pub fn serve(catalog: PathBuf, config: Config, local_only: bool, listen: SocketAddr) -> Result<(), Error> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let state = app_state(catalog, config, local_only)?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(listen).await?;
+        println!("Listening on http://{}", listener.local_addr()?);
+        // Ctrl-C lets the requests being answered finish
+        axum::serve(listener, router(state.clone()).into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async { cancel::shutdown().cancelled().await })
+            .await?;
+        Ok::<_, Error>(())
+    })?;
+    // and the operations running stop at their next safe point
+    let background = std::mem::take(&mut *state.background.lock().unwrap());
+    if background.iter().any(|thread| !thread.is_finished()) {
+        println!("Waiting for the operations running to stop");
+    }
+    for thread in background {
+        let _ = thread.join();
+    }
+    Ok(())
+}
+
+/// The state for serving `catalog`, with the catalogs `[[web.catalogs]]`
+/// attaches to it.
+fn app_state(catalog: PathBuf, config: Config, local_only: bool) -> Result<Arc<AppState>, Error> {
+    let config = Arc::new(config);
+    let mut names = vec![config.web.name.as_str()];
+    let mut attached = Vec::new();
+    for other in &config.web.catalogs {
+        let name = other.name.as_str();
+        let invalid = |message: String| -> Error { CliError::new(ErrorKind::Config, message).into() };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(invalid(format!("catalog names are letters, digits, - and _, not {:?}", name)));
+        }
+        if names.contains(&name) {
+            return Err(invalid(format!("there's already a catalog named {}", name)));
+        }
+        // Opening one that isn't there would make it, empty
+        if !other.path.is_file() {
+            return Err(invalid(format!("there's no catalog at {} to attach as {}", other.path.display(), name)));
+        }
+        names.push(name);
+        let prefix = format!("/catalogs/{}", name);
+        attached.push(catalog_state(other.path.clone(), config.clone(), local_only, prefix, Some(other.name.clone()), Vec::new())?);
+    }
+    let name = (!attached.is_empty()).then(|| config.web.name.clone());
+    catalog_state(catalog, config.clone(), local_only, String::new(), name, attached)
+}
+
+fn catalog_state(
+    catalog: PathBuf,
+    config: Arc<Config>,
+    local_only: bool,
+    prefix: String,
+    name: Option<String>,
+    attached: Vec<Arc<AppState>>,
+) -> Result<Arc<AppState>, Error> {
+    let graphql = graphql::schema(catalog.clone());
+    let users = Users::load(&config.users)?;
+    let bandwidth = Bandwidth::new(&config.bandwidth)?;
+    let lane = priority::Lane::new(&catalog);
+    let background = std::sync::Mutex::new(Vec::new());
+    let transcoding = tokio::sync::Mutex::new(());
+    let shares = share::Shares::new(&config.shares);
+    Ok(Arc::new(AppState { catalog, config, local_only, users, bandwidth, transcoding, graphql, lane, shares, background, prefix, name, attached }))
+}
+
+fn router(state: Arc<AppState>) -> Router {
+    let page = web::page(&state.config.web, state.local_only);
+    let router = Router::new()
+        // Asking browsers to say how fast their connection is
+        .route("/", get(move || {
+            let page = page.clone();
+            async move { ([("accept-ch", "ECT")], Html(page)) }
+        }))
+        .route("/theme.css", get(theme))
+        .route("/preferences", get(get_preferences).put(set_preferences))
+        .route("/jobs", get(list_jobs).post(start_job))
+        .route("/jobs/{id}", get(get_job))
+        .route("/jobs/{id}/cancel", post(cancel_job))
+        .route("/jobs/{id}/events", get(job_events))
+        .merge(image_routes())
+        .route("/graphql", post(graphql_query))
+        .route("/downloads", post(start_download))
+        .route("/downloads/{id}", get(get_download));
+    let router = state.attached.iter().fold(router, |router, other| {
+        router.nest_service(&other.prefix, image_routes().with_state(other.clone()))
+    });
+    router
+        .layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state)
+}
+
+/// What's served for each catalog.
+fn image_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/images", get(list_images))
+        .route("/images/{id}", get(get_image).patch(edit_image))
+        .route("/images/{id}/thumbnail", get(thumbnail))
+        .route("/images/{id}/histogram", get(image_histogram))
+        .route("/images/{id}/video", get(video_preview))
+        .route("/images/{id}/diff/{other}", get(diff))
+}
+
+impl AppState {
+    /// Run `operation` on a thread of its own, which shutdown waits for.
+    fn spawn(&self, operation: impl FnOnce() + Send + 'static) {
+        let mut background = self.background.lock().unwrap();
+        background.retain(|thread| !thread.is_finished());
+        background.push(thread::spawn(operation));
+    }
+}
+
+/// Who's making a request, for the audit log.
+#[derive(Clone)]
+struct Actor(String);
+
+/// Turn away requests from anyone whose role doesn't allow them: reading
+/// (and keeping one's own preferences and downloads) takes a viewer,
+/// editing a curator, and starting or cancelling other operations an admin.
+async fn authorize(State(state): State<Arc<AppState>>, mut request: Request, next: Next) -> Response {
+    let needed = match (request.method(), request.uri().path()) {
+        (&Method::GET | &Method::HEAD, _) | (&Method::POST, "/graphql") | (&Method::PUT, "/preferences") | (&Method::POST, "/downloads") => Role::Viewer,
+        (&Method::PATCH, _) => Role::Curator,
+        _ => Role::Admin,
+    };
+    let authorization = request.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+    match state.users.authorize(authorization, needed) {
+        Ok(name) => {
+            request.extensions_mut().insert(Actor(name.unwrap_or_else(|| String::from(audit::ANONYMOUS))));
+            next.run(request).await
+        }
+        Err(Denied::Unknown) => {
+            let error = ApiError(StatusCode::UNAUTHORIZED, CliError::new(ErrorKind::Config, "sign in with a user's name and token").into());
+            ([(header::WWW_AUTHENTICATE, "Basic realm=\"PhotoCataloger\"")], error).into_response()
+        }
+        Err(Denied::Forbidden { name, role }) => {
+            let message = format!("{} is a {}; this takes a {}", name, role.name(), needed.name());
+            ApiError(StatusCode::FORBIDDEN, CliError::new(ErrorKind::Config, message).into()).into_response()
+        }
+    }
+}
+
+/// An error response: the same JSON summary the CLI prints on failure.
+struct ApiError(StatusCode, Error);
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> ApiError {
+        let status = match error::classify(&error) {
+            ErrorKind::Config => StatusCode::BAD_REQUEST,
+            ErrorKind::NothingToDo => StatusCode::NOT_FOUND,
+            ErrorKind::BackendUnreachable => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError(status, error)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(error::summary(&self.1))).into_response()
+    }
+}
+
+/// Run a catalog query off the async workers.
+async fn with_catalog<T: Send + 'static>(
+    state: &AppState,
+    f: impl FnOnce(&Connection) -> Result<T, Error> + Send + 'static,
+) -> Result<T, ApiError> {
+    let catalog = state.catalog.clone();
+    let result = tokio::task::spawn_blocking(move || f(&crate::open_catalog(&catalog)?))
+        .await
+        .map_err(Error::from)?;
+    Ok(result?)
+}
+
+fn not_found(id: i64) -> ApiError {
+    Error::from(CliError::new(ErrorKind::NothingToDo, format!("no operation {}", id))).into()
+}
+
+fn no_image(id: i64) -> ApiError {
+    Error::from(CliError::new(ErrorKind::NothingToDo, format!("no image {}", id))).into()
+}
+
+/// The accent color and stylesheet of `[web]`, after the page's own.
+async fn theme(State(state): State<Arc<AppState>>) -> Result<Response, ApiError> {
+    let web = &state.config.web;
+    let mut css = String::new();
+    if let Some(accent) = &web.accent {
+        css.push_str(&format!(":root {{ --accent: {}; }}\n", accent));
+    }
+    if let Some(stylesheet) = &web.stylesheet {
+        css.push_str(&tokio::fs::read_to_string(stylesheet).await.map_err(Error::from)?);
+    }
+    Ok(([(header::CONTENT_TYPE, "text/css")], css).into_response())
+}
+
+async fn get_preferences(
+    State(state): State<Arc<AppState>>,
+    Extension(Actor(actor)): Extension<Actor>,
+) -> Result<Json<BTreeMap<String, String>>, ApiError> {
+    Ok(Json(with_catalog(&state, move |conn| preferences::get(conn, &actor)).await?))
+}
+
+/// Set the preferences given, leaving the rest.
+async fn set_preferences(
+    State(state): State<Arc<AppState>>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Json(changes): Json<BTreeMap<String, String>>,
+) -> Result<Json<BTreeMap<String, String>>, ApiError> {
+    preferences::check(&changes)?;
+    let preferences = with_catalog(&state, move |conn| {
+        let before = preferences::get(conn, &actor)?;
+        preferences::set(conn, &actor, &changes)?;
+        let after = preferences::get(conn, &actor)?;
+        audit::record(conn, &actor, "preferences", &actor, Some(&json!(before)), Some(&json!(after)))?;
+        Ok(after)
+    }).await?;
+    Ok(Json(preferences))
+}
+
+async fn list_jobs(State(state): State<Arc<AppState>>) -> Result<Json<Vec<Status>>, ApiError> {
+    Ok(Json(with_catalog(&state, |conn| progress::list(conn, 50)).await?))
+}
+
+async fn get_job(State(state): State<Arc<AppState>>, Path(id): Path<i64>) -> Result<Json<Status>, ApiError> {
+    match with_catalog(&state, move |conn| progress::get(conn, id)).await? {
+        Some(status) => Ok(Json(status)),
+        None => Err(not_found(id)),
+    }
+}
+
+async fn cancel_job(
+    State(state): State<Arc<AppState>>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(id): Path<i64>,
+) -> Result<Response, ApiError> {
+    let (cancelled, status) = with_catalog(&state, move |conn| {
+        let cancelled = progress::request_cancel(conn, id)?;
+        if cancelled {
+            audit::record(conn, &actor, "cancel", &format!("operation {}", id), None, None)?;
+        }
+        Ok((cancelled, progress::get(conn, id)?))
+    }).await?;
+    match status {
+        Some(status) if cancelled => Ok((StatusCode::ACCEPTED, Json(status)).into_response()),
+        // Already finished; nothing to cancel
+        Some(status) => Ok((StatusCode::CONFLICT, Json(status)).into_response()),
+        None => Err(not_found(id)),
+    }
+}
+
+/// The operation's status each time it changes, as `status` events, until
+/// it finishes.
+async fn job_events(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let first = with_catalog(&state, move |conn| progress::get(conn, id)).await?.ok_or_else(|| not_found(id))?;
+    let events = futures_util::stream::unfold((state, Some(first), None), move |(state, mut next, last)| async move {
+        // Once it's over there's nothing more to say
+        if last.as_ref().is_some_and(|last: &Status| last.state != "running") {
+            return None;
+        }
+        loop {
+            let status = match next.take() {
+                Some(status) => status,
+                None => {
+                    tokio::time::sleep(EVENT_INTERVAL).await;
+                    with_catalog(&state, move |conn| progress::get(conn, id)).await.ok().flatten()?
+                }
+            };
+            if last.as_ref() == Some(&status) {
+                continue;
+            }
+            let event = Event::default().event("status").json_data(&status).unwrap_or_default();
+            return Some((Ok(event), (state, None, Some(status))));
+        }
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Deserialize)]
+struct ImagesQuery {
+    /// `key:value` terms, as `--query` takes them
+    q: Option<String>,
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+    /// One of `preferences::Sort::NAMES`; by path if not given
+    sort: Option<String>,
+}
+
+/// A page of the images matching the query, in the order asked for, from
+/// the attached catalogs as well as this one.
+async fn list_images(State(state): State<Arc<AppState>>, Query(params): Query<ImagesQuery>) -> Result<Json<Vec<Value>>, ApiError> {
+    let terms = query::parse(params.q.as_deref().unwrap_or_default()).map_err(|e| Error::from(CliError::new(ErrorKind::Config, e)))?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    let sort = params.sort.as_deref().map(preferences::Sort::parse).transpose()?.unwrap_or(preferences::Sort::Path);
+    let federated = !state.attached.is_empty();
+    // The page is somewhere in the first offset + limit of each catalog
+    let (each_limit, each_offset) = if federated { (params.offset + limit, 0) } else { (limit, params.offset) };
+    let catalogs: Vec<&Arc<AppState>> = std::iter::once(&state).chain(&state.attached).collect();
+    let mut images = Vec::new();
+    for (index, catalog) in catalogs.iter().enumerate() {
+        let filter = query::filter(&terms);
+        let (prefix, name) = (catalog.prefix.clone(), catalog.name.clone());
+        let listed = with_catalog(catalog, move |conn| images_page(conn, &filter, sort, each_limit, each_offset, &prefix, name.as_deref())).await?;
+        images.extend(listed.into_iter().map(|image| (index, image)));
+    }
+    if federated {
+        // A stable sort, so ties keep to the catalogs' order
+        images.sort_by(|(_, a), (_, b)| sort.compare(a, b));
+        images = images.into_iter().skip(params.offset).take(limit).collect();
+    }
+    for (index, catalog) in catalogs.iter().enumerate() {
+        let ids: Vec<i64> = images.iter().filter(|(from, _)| *from == index).filter_map(|(_, image)| image["id"].as_i64()).collect();
+        if !ids.is_empty() {
+            with_catalog(catalog, move |conn| Ok(tiering::record(conn, &ids)?)).await?;
+        }
+    }
+    Ok(Json(images.into_iter().map(|(_, image)| image).collect()))
+}
+
+/// A page of one catalog's images, each with the URL it's at below
+/// `prefix` and, if given, the catalog's name.
+fn images_page(
+    conn: &Connection,
+    filter: &crate::SearchFilter,
+    sort: preferences::Sort,
+    limit: usize,
+    offset: usize,
+    prefix: &str,
+    catalog: Option<&str>,
+) -> Result<Vec<Value>, Error> {
+    let (clause, mut query_params) = crate::filter_clause(filter);
+    query_params.push(Box::new(limit as i64));
+    query_params.push(Box::new(offset as i64));
+    let images = conn
+        .prepare(&format!(
+            "SELECT id, path, file_name, format, creation_date, description, {}, rating, favorite, rejected FROM images{} ORDER BY {} LIMIT ? OFFSET ?",
+            tags::KEYWORDS, clause, sort.order_by(),
+        ))?
+        .query_map(params_from_iter(query_params), |row| {
+            let id = row.get::<_, i64>(0)?;
+            let mut image = json!({
+                "id": id,
+                "url": format!("{}/images/{}", prefix, id),
+                "path": row.get::<_, String>(1)?,
+                "file_name": row.get::<_, String>(2)?,
+                "format": row.get::<_, Option<String>>(3)?,
+                "creation_date": row.get::<_, Option<String>>(4)?,
+                "description": row.get::<_, Option<String>>(5)?,
+                "keywords": row.get::<_, Option<String>>(6)?,
+                "rating": row.get::<_, u8>(7)?,
+                "favorite": row.get::<_, bool>(8)?,
+                "rejected": row.get::<_, bool>(9)?,
+            });
+            if let Some(catalog) = catalog {
+                image["catalog"] = Value::from(catalog);
+            }
+            Ok(image)
+        })?
+        .collect::<Result<Vec<Value>, _>>()?;
+    Ok(images)
+}
+
+/// Everything the catalog has on one image: its columns, the metadata
+/// imported alongside, and its notes and scenes.
+async fn get_image(State(state): State<Arc<AppState>>, Path(id): Path<i64>) -> Result<Json<Value>, ApiError> {
+    let image = with_catalog(&state, move |conn| {
+        let mut stmt = conn.prepare(&format!("SELECT *, {} AS keywords FROM images WHERE id = ?1", tags::KEYWORDS))?;
+        let columns: Vec<String> = stmt.column_names().into_iter().map(str::to_string).collect();
+        let Some(mut image) = stmt.query_row([id], |row| {
+            let mut image = Map::new();
+            for (i, column) in columns.iter().enumerate() {
+                image.insert(column.clone(), portable::to_json(row.get_ref(i)?));
+            }
+            Ok(image)
+        }).optional()? else {
+            return Ok(None);
+        };
+        tiering::record(conn, &[id])?;
+        let external: Vec<Value> = conn
+            .prepare("SELECT source, field, value FROM external_metadata WHERE image_id = ?1 ORDER BY rowid")?
+            .query_map([id], |row| Ok(json!({ "source": row.get::<_, String>(0)?, "field": row.get::<_, String>(1)?, "value": row.get::<_, String>(2)? })))?
+            .collect::<Result<_, _>>()?;
+        let path = image["path"].as_str().unwrap_or_default().to_string();
+        let notes: Vec<Value> = notes::list(conn, Some(&path))?.into_iter()
+            .map(|note| json!({ "id": note.id, "audio": note.audio, "text": note.text, "created_at": note.created_at }))
+            .collect();
+        let scenes: Vec<Value> = conn
+            .prepare("SELECT start_secs, end_secs, description, keywords FROM scenes WHERE image_id = ?1 ORDER BY start_secs")?
+            .query_map([id], |row| {
+                let start: f64 = row.get(0)?;
+                Ok(json!({ "start_secs": start, "end_secs": row.get::<_, f64>(1)?, "timestamp": scenes::timestamp(start), "description": row.get::<_, String>(2)?, "keywords": row.get::<_, String>(3)? }))
+            })?
+            .collect::<Result<_, _>>()?;
+        image.insert(String::from("external"), Value::from(external));
+        image.insert(String::from("notes"), Value::from(notes));
+        image.insert(String::from("scenes"), Value::from(scenes));
+        let fields: Map<String, Value> = fields::of(conn, id)?.into_iter().map(|(key, value)| (key, Value::from(value))).collect();
+        image.insert(String::from("fields"), Value::Object(fields));
+        // As true and false rather than the columns' 1 and 0
+        let culled = culling::get(conn, id)?;
+        image.insert(String::from("favorite"), Value::from(culled.favorite));
+        image.insert(String::from("rejected"), Value::from(culled.rejected));
+        Ok(Some(Value::Object(image)))
+    }).await?;
+    image.map(Json).ok_or_else(|| no_image(id))
+}
+
+#[derive(Deserialize)]
+struct EditRequest {
+    description: Option<String>,
+    keywords: Option<String>,
+    /// The version of the image the edit was made to, as `/images/{id}`
+    /// gave it; the edit is logged as a conflict if it's been edited since
+    version: Option<i64>,
+    rating: Option<u8>,
+    favorite: Option<bool>,
+    rejected: Option<bool>,
+}
+
+/// Correct an image's description or keywords, as `correct` does, or
+/// rate or flag it, as `rate`, `favorite` and `reject` do.
+async fn edit_image(
+    State(state): State<Arc<AppState>>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(id): Path<i64>,
+    Json(request): Json<EditRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let edited = with_catalog(&state, move |conn| {
+        let row: Option<(String, i64)> = conn.query_row("SELECT path, version FROM images WHERE id = ?1", [id], |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+        let Some((path, version)) = row else { return Ok(None) };
+        let marks = [request.rating.map(culling::Mark::Rating), request.favorite.map(culling::Mark::Favorite), request.rejected.map(culling::Mark::Rejected)];
+        if marks.iter().any(Option::is_some) {
+            let before = culling::get(conn, id)?;
+            for mark in marks.into_iter().flatten() {
+                culling::mark(conn, &[id], mark)?;
+            }
+            let after = culling::get(conn, id)?;
+            audit::record(conn, &actor, "cull", &path, Some(&json!(before)), Some(&json!(after)))?;
+        }
+        if request.description.is_none() && request.keywords.is_none() {
+            return Ok(Some(edits::Edited { version, conflict: false }));
+        }
+        let editor = Editor { source: "web", seen: request.version };
+        let edited = corrections::correct(conn, &path, request.description.as_deref(), request.keywords.as_deref(), &editor)?;
+        let (before, after) = conn.query_row(
+            "SELECT previous_description, previous_keywords, description, keywords FROM edits WHERE image_id = ?1 AND version = ?2",
+            params![id, edited.version],
+            |row| {
+                let before = json!({ "description": row.get::<_, Option<String>>(0)?, "keywords": row.get::<_, Option<String>>(1)? });
+                let after = json!({ "description": row.get::<_, Option<String>>(2)?, "keywords": row.get::<_, Option<String>>(3)? });
+                Ok((before, after))
+            },
+        )?;
+        audit::record(conn, &actor, "edit", &path, Some(&before), Some(&after))?;
+        Ok(Some(edited))
+    }).await?;
+    let edited = edited.ok_or_else(|| no_image(id))?;
+    Ok(Json(json!({ "version": edited.version, "conflict": edited.conflict })))
+}
+
+#[derive(Deserialize)]
+struct ThumbnailQuery {
+    /// Longest edge, in pixels
+    size: Option<u32>,
+}
+
+/// An upright JPEG of the image, as the analyzer would be sent it.
+async fn thumbnail(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+    Query(params): Query<ThumbnailQuery>,
+) -> Result<Response, ApiError> {
+    let hint = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let limits = state.bandwidth.limits(client.ip(), hint("save-data"), hint("ect"));
+    let edge = params.size.unwrap_or(THUMBNAIL_EDGE).clamp(16, MAX_THUMBNAIL_EDGE);
+    let edge = limits.map_or(edge, |limits| edge.min(limits.max_edge));
+    let _claim = state.lane.claim();
+    let app = state.clone();
+    let data = with_catalog(&state, move |conn| {
+        let row: Option<(String, Option<String>)> = conn
+            .query_row("SELECT path, content_hash FROM images WHERE id = ?1", [id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?;
+        let Some((path, hash)) = row else { return Ok(None) };
+        let path = std::path::Path::new(&path);
+        // On a mirror, a thumbnail of the size asked for or bigger is made
+        // already; for anything else the original is fetched, just the once
+        // for each size if it's on a share
+        let mirrored = hash.as_deref().and_then(|hash| remote::thumbnail(&app.catalog, hash));
+        let make = || jobs::derivative(&remote::original(path, &app.config)?, &app.config, edge);
+        match mirrored {
+            Some(thumbnail) if !path.exists() && edge <= app.config.remote.thumbnail_edge => {
+                let img = image::open(thumbnail)?.thumbnail(edge, edge);
+                let mut jpeg = Vec::new();
+                img.to_rgb8().write_to(&mut std::io::Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)?;
+                Ok(Some(jpeg))
+            }
+            _ => match hash.filter(|_| app.shares.root(path).is_some()) {
+                Some(hash) => Ok(Some(share::cached(&app.config.shares, &hash, edge, make)?)),
+                None => Ok(Some(make()?)),
+            },
+        }
+    }).await?;
+    let mut data = data.ok_or_else(|| no_image(id))?;
+    if image::guess_format(&data).ok() != Some(image::ImageFormat::Jpeg) {
+        return Err(Error::msg(format!("can't make a JPEG of image {}", id)).into());
+    }
+    if let Some(limits) = limits {
+        let img = image::load_from_memory(&data).map_err(Error::from)?;
+        data = Vec::new();
+        img.to_rgb8().write_to(&mut std::io::Cursor::new(&mut data), image::ImageOutputFormat::Jpeg(limits.jpeg_quality)).map_err(Error::from)?;
+    }
+    Ok(([(header::CONTENT_TYPE, "image/jpeg"), (header::VARY, "Save-Data, ECT")], data).into_response())
+}
+
+/// How many pixels have each level of red, green, blue and luminance,
+/// worked out the first time it's asked for (or after the file changes).
+async fn image_histogram(State(state): State<Arc<AppState>>, Path(id): Path<i64>) -> Result<Json<histogram::Histogram>, ApiError> {
+    let _claim = state.lane.claim();
+    let app = state.clone();
+    let histogram = with_catalog(&state, move |conn| {
+        let row: Option<(String, Option<String>)> = conn
+            .query_row("SELECT path, content_hash FROM images WHERE id = ?1", [id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?;
+        let Some((path, hash)) = row else { return Ok(None) };
+        if let Some(histogram) = histogram::cached(conn, id, hash.as_deref())? {
+            return Ok(Some(histogram));
+        }
+        let path = std::path::Path::new(&path);
+        // On a mirror, the thumbnail does
+        let mirrored = hash.as_deref().and_then(|hash| remote::thumbnail(&app.catalog, hash));
+        let img = match mirrored {
+            Some(thumbnail) if !path.exists() => image::open(thumbnail)?,
+            _ => image::load_from_memory(&jobs::derivative(&remote::original(path, &app.config)?, &app.config, histogram::EDGE)?)?,
+        };
+        let histogram = histogram::compute(&img.thumbnail(histogram::EDGE, histogram::EDGE).to_rgb8());
+        histogram::store(conn, id, hash.as_deref(), &histogram)?;
+        Ok(Some(histogram))
+    }).await?;
+    histogram.map(Json).ok_or_else(|| no_image(id))
+}
+
+/// A PNG heatmap of where the image and `other` differ, over the part they
+/// share once lined up (see `duplicates`), for choosing between copies.
+/// `X-Difference` has how different they are overall, from 0 to 1.
+async fn diff(
+    State(state): State<Arc<AppState>>,
+    Path((id, other)): Path<(i64, i64)>,
+    Query(params): Query<ThumbnailQuery>,
+) -> Result<Response, ApiError> {
+    let edge = params.size.unwrap_or(THUMBNAIL_EDGE).clamp(16, MAX_THUMBNAIL_EDGE);
+    let _claim = state.lane.claim();
+    let app = state.clone();
+    let rendered = with_catalog(&state, move |conn| {
+        let path = |id: i64| -> Result<Option<PathBuf>, Error> {
+            let path: Option<String> = conn.query_row("SELECT path FROM images WHERE id = ?1", [id], |row| row.get(0)).optional()?;
+            Ok(path.map(PathBuf::from))
+        };
+        let Some(a) = path(id)? else { return Ok(Err(id)) };
+        let Some(b) = path(other)? else { return Ok(Err(other)) };
+        let load_edge = edge.max(duplicates::COMPARE_EDGE);
+        let (first, second) = (duplicates::load(&a, &app.config, load_edge)?, duplicates::load(&b, &app.config, load_edge)?);
+        let alignment = duplicates::compare(&first, &second);
+        let mut png = Vec::new();
+        duplicates::heatmap(&first, &second, &alignment, edge).write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)?;
+        Ok(Ok((png, alignment.difference)))
+    }).await?;
+    let (png, difference) = rendered.map_err(no_image)?;
+    Ok(([(header::CONTENT_TYPE, String::from("image/png")), (header::HeaderName::from_static("x-difference"), format!("{:.4}", difference))], png).into_response())
+}
+
+/// The first and last byte a `Range` header asks for, if it's one range
+/// within a file of `len` bytes.
+fn byte_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (len.checked_sub(suffix.parse().ok()?)?, len.checked_sub(1)?),
+        (start, "") => (start.parse().ok()?, len.checked_sub(1)?),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(len.checked_sub(1)?)),
+    };
+    (start <= end).then_some((start, end))
+}
+
+/// The file at `path`, or the part of it `headers` ask for, so that
+/// browsers can seek in videos without downloading them whole.
+async fn serve_file(path: &std::path::Path, content_type: &str, headers: &HeaderMap) -> Result<Response, ApiError> {
+    let mut file = tokio::fs::File::open(path).await.map_err(Error::from)?;
+    let len = file.metadata().await.map_err(Error::from)?.len();
+    let range = headers.get(header::RANGE).and_then(|range| range.to_str().ok()).and_then(|range| byte_range(range, len));
+    let (start, end) = range.unwrap_or((0, len.saturating_sub(1)));
+    let length = if len == 0 { 0 } else { end - start + 1 };
+    file.seek(SeekFrom::Start(start)).await.map_err(Error::from)?;
+
+    let chunks = futures_util::stream::unfold(Some(file.take(length)), |reader| async move {
+        let mut reader = reader?;
+        let mut chunk = vec![0; CHUNK];
+        match reader.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(n) => {
+                chunk.truncate(n);
+                Some((Ok(chunk), Some(reader)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_LENGTH, length);
+    if range.is_some() {
+        response = response.status(StatusCode::PARTIAL_CONTENT).header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+    }
+    Ok(response.body(Body::from_stream(chunks)).map_err(Error::from)?)
+}
+
+/// The video, as it is if browsers can play it and otherwise as a copy
+/// they can, made the first time it's asked for (or after the video
+/// changes) and kept in `video.preview_cache`.
+async fn video_preview(State(state): State<Arc<AppState>>, Path(id): Path<i64>, headers: HeaderMap) -> Result<Response, ApiError> {
+    let found = with_catalog(&state, move |conn| {
+        let row = conn.query_row("SELECT path, format, video_codec FROM images WHERE id = ?1", [id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, Option<String>>(2)?))
+        });
+        Ok(row.optional()?)
+    }).await?;
+    let Some((path, format, codec)) = found else { return Err(no_image(id)) };
+    if !video::is_video(std::path::Path::new(&path)) {
+        return Err(Error::from(CliError::new(ErrorKind::NothingToDo, format!("image {} isn't a video", id))).into());
+    }
+    let app = state.clone();
+    let path = tokio::task::spawn_blocking(move || remote::original(std::path::Path::new(&path), &app.config))
+        .await
+        .map_err(Error::from)??;
+    if video::plays_in_browsers(format.as_deref(), codec.as_deref()) {
+        return serve_file(&path, "video/mp4", &headers).await;
+    }
+
+    let preview = PathBuf::from(&state.config.video.preview_cache).join(format!("{}.mp4", id));
+    let is_fresh = |preview: &std::path::Path| -> bool {
+        let modified = |path: &std::path::Path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        matches!((modified(preview), modified(&path)), (Some(preview), Some(original)) if preview >= original)
+    };
+    if !is_fresh(&preview) {
+        let _claim = state.lane.claim();
+        let _transcoding = state.transcoding.lock().await;
+        // Made while we waited, perhaps
+        if !is_fresh(&preview) {
+            let (app, source, target) = (state.clone(), path.clone(), preview.clone());
+            tokio::task::spawn_blocking(move || -> Result<(), Error> {
+                std::fs::create_dir_all(&app.config.video.preview_cache)?;
+                println!("Transcoding {} for browsers", source.display());
+                video::transcode_preview(&source, &target, &app.config.video)
+            }).await.map_err(Error::from)??;
+        }
+    }
+    serve_file(&preview, "video/mp4", &headers).await
+}
+
+async fn graphql_query(State(state): State<Arc<AppState>>, Json(request): Json<async_graphql::Request>) -> Json<async_graphql::Response> {
+    Json(state.graphql.execute(request).await)
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+enum StartRequest {
+    Scan {
+        dir: PathBuf,
+        #[serde(default = "default_analyze")]
+        analyze: bool,
+        /// Pick up the folder's last scan where it stopped
+        #[serde(default)]
+        resume: bool,
+    },
+    ExportXmpSidecars,
+}
+
+fn default_analyze() -> bool {
+    true
+}
+
+async fn start_job(
+    State(state): State<Arc<AppState>>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Json(request): Json<StartRequest>,
+) -> Result<(StatusCode, Json<Status>), ApiError> {
+    if matches!(request, StartRequest::Scan { analyze: true, .. }) {
+        privacy::enforce(&state.config, state.local_only)?;
+    }
+    let kind = match request {
+        StartRequest::Scan { .. } => "scan",
+        StartRequest::ExportXmpSidecars => "export",
+    };
+    let started = serde_json::to_value(&request).map_err(Error::from)?;
+    let (id, status) = with_catalog(&state, move |conn| {
+        let operation = progress::start(conn, kind, CancelToken::new())?;
+        audit::record(conn, &actor, kind, &format!("operation {}", operation.id), None, Some(&started))?;
+        Ok((operation.id, progress::get(conn, operation.id)?))
+    }).await?;
+
+    // The operation owns its own connection and runs to completion (or
+    // cancellation) whatever happens to this request
+    let app = state.clone();
+    state.spawn(move || {
+        let run = || -> Result<(), Error> {
+            let conn = crate::open_catalog(&app.catalog)?;
+            let operation = progress::Operation { id, cancel: cancel::shutdown().child() };
+            let result = match request {
+                StartRequest::Scan { dir, analyze, resume } => crate::scan(&conn, &operation, &app.config, Some(dir), analyze, resume, &mut crate::report::Report::start()),
+                StartRequest::ExportXmpSidecars => export::export_xmp_sidecars(&conn, &operation, app.config.captions.template.as_ref(), None).map(|_| ()),
+            };
+            operation.finish(&conn, &result)
+        };
+        if let Err(e) = run() {
+            tracing::error!("Error recording operation {}: {}", id, e);
+        }
+    });
+
+    let status = status.ok_or_else(|| not_found(id))?;
+    Ok((StatusCode::ACCEPTED, Json(status)))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DownloadRequest {
+    /// `key:value` terms, as `--query` takes them
+    query: Option<String>,
+    #[serde(default)]
+    ids: Vec<i64>,
+}
+
+/// Start making a ZIP of the originals of the images asked for, by id,
+/// query or both, for the one asking to fetch once it's made.
+async fn start_download(
+    State(state): State<Arc<AppState>>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Json(request): Json<DownloadRequest>,
+) -> Result<(StatusCode, Json<Status>), ApiError> {
+    let filter = match &request.query {
+        Some(q) => Some(query::filter(&query::parse(q).map_err(|e| Error::from(CliError::new(ErrorKind::Config, e)))?)),
+        None => None,
+    };
+    let dir = PathBuf::from(&state.config.downloads.dir);
+    let keep_hours = state.config.downloads.keep_hours;
+    let (ids, id, status) = with_catalog(&state, move |conn| {
+        downloads::expire(conn, &dir, keep_hours)?;
+        let mut ids = request.ids;
+        if let Some(filter) = &filter {
+            ids.extend(crate::selected_ids(conn, &[], Some(filter))?);
+        }
+        let ids = crate::first_occurrences(ids);
+        if ids.is_empty() {
+            return Err(CliError::new(ErrorKind::NothingToDo, "nothing to download: no photos match").into());
+        }
+        let operation = progress::start(conn, downloads::KIND, CancelToken::new())?;
+        downloads::record(conn, operation.id, &actor, ids.len())?;
+        audit::record(conn, &actor, downloads::KIND, &format!("operation {}", operation.id), None, Some(&json!({ "images": ids.len() })))?;
+        Ok((ids, operation.id, progress::get(conn, operation.id)?))
+    }).await?;
+
+    // Made by a thread of its own, like the operations of `/jobs`
+    let app = state.clone();
+    state.spawn(move || {
+        let run = || -> Result<(), Error> {
+            let conn = crate::open_catalog(&app.catalog)?;
+            let operation = progress::Operation { id, cancel: cancel::shutdown().child() };
+            let result = downloads::make(&conn, &operation, &ids, std::path::Path::new(&app.config.downloads.dir), &app.config);
+            operation.finish(&conn, &result)
+        };
+        if let Err(e) = run() {
+            tracing::error!("Error recording download {}: {}", id, e);
+        }
+    });
+
+    let status = status.ok_or_else(|| not_found(id))?;
+    Ok((StatusCode::ACCEPTED, Json(status)))
+}
+
+/// The ZIP of download `id`, once it's made, to the one who asked for it.
+async fn get_download(
+    State(state): State<Arc<AppState>>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let dir = PathBuf::from(&state.config.downloads.dir);
+    let keep_hours = state.config.downloads.keep_hours;
+    let path = with_catalog(&state, move |conn| {
+        downloads::expire(conn, &dir, keep_hours)?;
+        downloads::ready(conn, &dir, id, &actor)
+    }).await?;
+    let mut response = serve_file(&path, "application/zip", &headers).await?;
+    let disposition = HeaderValue::from_str(&format!("attachment; filename=\"photos-{}.zip\"", id)).map_err(Error::from)?;
+    response.headers_mut().insert(header::CONTENT_DISPOSITION, disposition);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    async fn spawn_server(catalog: PathBuf) -> Result<String, Error> {
+        spawn_server_with(catalog, Config::default()).await
+    }
+
+    async fn spawn_server_with(catalog: PathBuf, config: Config) -> Result<String, Error> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let server = axum::serve(listener, router(app_state(catalog, config, false)?).into_make_service_with_connect_info::<SocketAddr>());
+        tokio::spawn(async move { server.await });
+        Ok(url)
+    }
+
+    #[tokio::test]
+    async fn test_job_endpoints() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let catalog = dir.path().join("catalog.db");
+        let operation = progress::start(&crate::open_catalog(&catalog)?, "scan", CancelToken::new())?;
+        let url = spawn_server(catalog).await?;
+        let client = reqwest::Client::new();
+
+        let status: serde_json::Value = client.get(format!("{}/jobs/{}", url, operation.id)).send().await?.json().await?;
+        assert_eq!(status["state"], "running");
+        assert_eq!(status["done"], 0);
+
+        let response = client.post(format!("{}/jobs/{}/cancel", url, operation.id)).send().await?;
+        assert_eq!(response.status(), StatusCode::ACCEPTED.as_u16());
+        let status: serde_json::Value = response.json().await?;
+        assert_eq!(status["cancel_requested"], true);
+
+        let response = client.get(format!("{}/jobs/{}", url, operation.id + 1)).send().await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND.as_u16());
+        let body: serde_json::Value = response.json().await?;
+        assert_eq!(body["error"]["kind"], "nothing_to_do");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_image_endpoints() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let photo = dir.path().join("beach.png");
+        image::DynamicImage::new_rgb8(800, 400).save(&photo)?;
+        let catalog = dir.path().join("catalog.db");
+        let conn = crate::open_catalog(&catalog)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, description) VALUES (?1, 'beach.png', 1, 'Sand and sea'), ('/cat.jpg', 'cat.jpg', 1, NULL)",
+            [photo.to_string_lossy()],
+        )?;
+        crate::test_support::set_keywords(&conn, &[(&photo.to_string_lossy(), "beach, sea"), ("/cat.jpg", "cat")]);
+        conn.execute("INSERT INTO external_metadata (image_id, source, field, value) VALUES (1, 'xmp', 'rating', '4')", [])?;
+        let url = spawn_server(catalog).await?;
+        let client = reqwest::Client::new();
+
+        let page = client.get(&url).send().await?.text().await?;
+        assert!(page.contains("/images?"));
+        let images: Vec<serde_json::Value> = client.get(format!("{}/images", url)).query(&[("q", "keyword:beach")]).send().await?.json().await?;
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0]["description"], "Sand and sea");
+        let images: Vec<serde_json::Value> = client.get(format!("{}/images?limit=1&offset=1", url)).send().await?.json().await?;
+        assert_eq!(images[0]["path"], photo.to_string_lossy().as_ref());
+        let response = client.get(format!("{}/images", url)).query(&[("q", "colour:red")]).send().await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST.as_u16());
+
+        let image: serde_json::Value = client.get(format!("{}/images/1", url)).send().await?.json().await?;
+        assert_eq!(image["keywords"], "beach, sea");
+        assert_eq!(image["external"][0]["value"], "4");
+        assert_eq!(image["notes"], serde_json::json!([]));
+        assert_eq!(client.get(format!("{}/images/9", url)).send().await?.status(), StatusCode::NOT_FOUND.as_u16());
+
+        let response = client.post(format!("{}/graphql", url))
+            .json(&serde_json::json!({ "query": "query ($id: Int!) { image(id: $id) { description tags { name } } }", "variables": { "id": 1 } }))
+            .send().await?;
+        let body: serde_json::Value = response.json().await?;
+        assert_eq!(body["data"]["image"], serde_json::json!({ "description": "Sand and sea", "tags": [{ "name": "beach" }, { "name": "sea" }] }));
+
+        let response = client.get(format!("{}/images/1/thumbnail?size=200", url)).send().await?;
+        assert_eq!(response.headers()["content-type"], "image/jpeg");
+        let thumbnail = image::load_from_memory(&response.bytes().await?)?;
+        assert_eq!((thumbnail.width(), thumbnail.height()), (200, 100));
+
+        let histogram: serde_json::Value = client.get(format!("{}/images/1/histogram", url)).send().await?.json().await?;
+        let luminance: Vec<u64> = serde_json::from_value(histogram["luminance"].clone())?;
+        assert_eq!((luminance.len(), luminance[0], luminance.iter().sum::<u64>()), (256, 512 * 256, 512 * 256));
+        let cached: i64 = conn.query_row("SELECT COUNT(*) FROM histograms WHERE image_id = 1", [], |row| row.get(0))?;
+        assert_eq!(cached, 1);
+        assert_eq!(client.get(format!("{}/images/9/histogram", url)).send().await?.status(), StatusCode::NOT_FOUND.as_u16());
+        // A mirror's, with the original elsewhere
+        std::fs::create_dir(dir.path().join(remote::THUMBNAILS))?;
+        image::DynamicImage::new_rgb8(400, 300).save(dir.path().join(remote::THUMBNAILS).join("cat.jpg"))?;
+        crate::open_catalog(&dir.path().join("catalog.db"))?.execute("UPDATE images SET content_hash = 'cat' WHERE id = 2", [])?;
+        let response = client.get(format!("{}/images/2/thumbnail?size=200", url)).send().await?;
+        let thumbnail = image::load_from_memory(&response.bytes().await?)?;
+        assert_eq!((thumbnail.width(), thumbnail.height()), (200, 150));
+
+        let response = client.get(format!("{}/images/1/diff/1?size=100", url)).send().await?;
+        assert_eq!(response.headers()["content-type"], "image/png");
+        assert_eq!(response.headers()["x-difference"], "0.0000");
+        let heatmap = image::load_from_memory(&response.bytes().await?)?;
+        assert_eq!((heatmap.width(), heatmap.height()), (100, 50));
+        assert_eq!(client.get(format!("{}/images/1/diff/9", url)).send().await?.status(), StatusCode::NOT_FOUND.as_u16());
+
+        let edit = serde_json::json!({ "keywords": "beach, sand", "version": image["version"] });
+        let edited: serde_json::Value = client.patch(format!("{}/images/1", url)).json(&edit).send().await?.json().await?;
+        assert_eq!(edited, serde_json::json!({ "version": 1, "conflict": false }));
+        // Again, from a page loaded before the first
+        let edited: serde_json::Value = client.patch(format!("{}/images/1", url)).json(&edit).send().await?.json().await?;
+        assert_eq!(edited, serde_json::json!({ "version": 2, "conflict": true }));
+        let image: serde_json::Value = client.get(format!("{}/images/1", url)).send().await?.json().await?;
+        assert_eq!((&image["keywords"], &image["description"]), (&serde_json::json!("beach, sand"), &serde_json::json!("Sand and sea")));
+        assert_eq!(client.patch(format!("{}/images/9", url)).json(&edit).send().await?.status(), StatusCode::NOT_FOUND.as_u16());
+
+        // Rating and flagging leave the caption and its version alone
+        let cull = serde_json::json!({ "rating": 4, "favorite": true });
+        let edited: serde_json::Value = client.patch(format!("{}/images/1", url)).json(&cull).send().await?.json().await?;
+        assert_eq!(edited, serde_json::json!({ "version": 2, "conflict": false }));
+        let image: serde_json::Value = client.get(format!("{}/images/1", url)).send().await?.json().await?;
+        assert_eq!((&image["rating"], &image["favorite"], &image["rejected"]), (&serde_json::json!(4), &serde_json::json!(true), &serde_json::json!(false)));
+        let response = client.patch(format!("{}/images/1", url)).json(&serde_json::json!({ "rating": 6 })).send().await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST.as_u16());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_attached_catalogs() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let (current, archive) = (dir.path().join("current.db"), dir.path().join("archive.db"));
+        crate::open_catalog(&current)?.execute_batch(
+            "INSERT INTO images (path, file_name, file_size, creation_date) VALUES
+                 ('/2024/a.jpg', 'a.jpg', 1, '2024-06-01'), ('/2024/b.jpg', 'b.jpg', 1, '2024-02-01')",
+        )?;
+        crate::open_catalog(&archive)?.execute_batch(
+            "INSERT INTO images (path, file_name, file_size, creation_date, rating) VALUES
+                 ('/2009/c.jpg', 'c.jpg', 1, '2009-08-01', 5), ('/2009/scan.jpg', 'scan.jpg', 1, NULL, 0)",
+        )?;
+        let mut config = Config::default();
+        config.web.name = String::from("current");
+        config.web.catalogs.push(crate::config::AttachedCatalog { name: String::from("archive"), path: archive.clone() });
+        let url = spawn_server_with(current.clone(), config).await?;
+        let client = reqwest::Client::new();
+
+        // One timeline, pages cutting across the catalogs
+        let images: Vec<serde_json::Value> = client.get(format!("{}/images?sort=newest", url)).send().await?.json().await?;
+        let listed: Vec<(&str, &str)> = images.iter().map(|image| (image["catalog"].as_str().unwrap(), image["file_name"].as_str().unwrap())).collect();
+        assert_eq!(listed, [("current", "a.jpg"), ("current", "b.jpg"), ("archive", "c.jpg"), ("archive", "scan.jpg")]);
+        assert_eq!(images[2]["url"], "/catalogs/archive/images/1");
+        let page: Vec<serde_json::Value> = client.get(format!("{}/images?sort=newest&limit=2&offset=1", url)).send().await?.json().await?;
+        assert_eq!((&page[0]["file_name"], &page[1]["file_name"]), (&serde_json::json!("b.jpg"), &serde_json::json!("c.jpg")));
+        let rated: Vec<serde_json::Value> = client.get(format!("{}/images", url)).query(&[("q", "rating:5")]).send().await?.json().await?;
+        assert_eq!(rated.len(), 1);
+
+        // Each read and edited where it is
+        let image: serde_json::Value = client.get(format!("{}/catalogs/archive/images/1", url)).send().await?.json().await?;
+        assert_eq!(image["file_name"], "c.jpg");
+        client.patch(format!("{}/catalogs/archive/images/2", url)).json(&serde_json::json!({ "favorite": true })).send().await?.error_for_status()?;
+        let favorite: i64 = crate::open_catalog(&archive)?.query_row("SELECT favorite FROM images WHERE id = 2", [], |row| row.get(0))?;
+        assert_eq!(favorite, 1);
+        assert_eq!(client.get(format!("{}/catalogs/elsewhere/images/1", url)).send().await?.status(), StatusCode::NOT_FOUND.as_u16());
+
+        // Only catalogs there are, each called something else
+        let attach = |name: &str, path: &std::path::Path| {
+            let mut config = Config::default();
+            config.web.catalogs.push(crate::config::AttachedCatalog { name: String::from(name), path: path.to_path_buf() });
+            app_state(current.clone(), config, false).map(|_| ())
+        };
+        assert!(attach("old", &dir.path().join("missing.db")).is_err());
+        assert!(!dir.path().join("missing.db").exists());
+        assert!(attach("main", &archive).is_err());
+        assert!(attach("old photos", &archive).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_roles() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let catalog = dir.path().join("catalog.db");
+        let conn = crate::open_catalog(&catalog)?;
+        conn.execute("INSERT INTO images (path, file_name, file_size) VALUES ('/cat.jpg', 'cat.jpg', 1)", [])?;
+        let operation = progress::start(&conn, "scan", CancelToken::new())?;
+        let mut config = Config::default();
+        for (name, role, token) in [("ana", Role::Admin, "ana-token"), ("rui", Role::Curator, "rui-token"), ("avo", Role::Viewer, "avo-token")] {
+            let token_env = format!("PHOTOCATALOGER_TEST_TOKEN_{}", name.to_uppercase());
+            std::env::set_var(&token_env, token);
+            config.users.push(crate::roles::User { name: String::from(name), role, token_env });
+        }
+        let url = spawn_server_with(catalog, config).await?;
+        let client = reqwest::Client::new();
+        let images = format!("{}/images", url);
+        let cancel = format!("{}/jobs/{}/cancel", url, operation.id);
+        let edit = serde_json::json!({ "keywords": "cat" });
+
+        let response = client.get(&images).send().await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED.as_u16());
+        assert_eq!(response.headers()["www-authenticate"], "Basic realm=\"PhotoCataloger\"");
+        assert_eq!(client.get(&images).bearer_auth("guess").send().await?.status(), StatusCode::UNAUTHORIZED.as_u16());
+        assert_eq!(client.get(&images).bearer_auth("avo-token").send().await?.status(), StatusCode::OK.as_u16());
+
+        let response = client.patch(format!("{}/1", images)).bearer_auth("avo-token").json(&edit).send().await?;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN.as_u16());
+        let body: serde_json::Value = response.json().await?;
+        assert_eq!(body["error"]["message"], "avo is a viewer; this takes a curator");
+        let response = client.patch(format!("{}/1", images)).basic_auth("rui", Some("rui-token")).json(&edit).send().await?;
+        assert_eq!(response.status(), StatusCode::OK.as_u16());
+
+        assert_eq!(client.post(&cancel).bearer_auth("rui-token").send().await?.status(), StatusCode::FORBIDDEN.as_u16());
+        assert_eq!(client.post(&cancel).bearer_auth("ana-token").send().await?.status(), StatusCode::ACCEPTED.as_u16());
+
+        // Everyone keeps their own preferences
+        let theme = serde_json::json!({ "theme": "dark" });
+        let response = client.put(format!("{}/preferences", url)).bearer_auth("avo-token").json(&theme).send().await?;
+        assert_eq!(response.status(), StatusCode::OK.as_u16());
+        let preferences: serde_json::Value = client.get(format!("{}/preferences", url)).bearer_auth("rui-token").send().await?.json().await?;
+        assert_eq!(preferences, serde_json::json!({}));
+
+        // Only what was done, by whom
+        let entries = audit::list(&conn, None, 10)?;
+        let logged: Vec<(&str, &str, &str)> = entries.iter().map(|entry| (entry.actor.as_str(), entry.action.as_str(), entry.target.as_str())).collect();
+        assert_eq!(logged, vec![("rui", "edit", "/cat.jpg"), ("ana", "cancel", "operation 1"), ("avo", "preferences", "avo")]);
+        assert_eq!(entries[0].before.as_deref(), Some(r#"{"description":null,"keywords":null}"#));
+        assert_eq!(entries[0].after.as_deref(), Some(r#"{"description":"","keywords":"cat"}"#));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let photo = dir.path().join("beach.png");
+        image::DynamicImage::new_rgb8(800, 400).save(&photo)?;
+        let catalog = dir.path().join("catalog.db");
+        crate::open_catalog(&catalog)?.execute("INSERT INTO images (path, file_name, file_size) VALUES (?1, 'beach.png', 1)", [photo.to_string_lossy()])?;
+        // As if the tests ran from far away
+        let mut config = Config::default();
+        config.bandwidth.full_quality.clear();
+        config.bandwidth.remote.max_edge = 300;
+        config.bandwidth.slow.max_edge = 100;
+        let url = spawn_server_with(catalog, config).await?;
+        let client = reqwest::Client::new();
+        let edges = |data: &[u8]| -> Result<(u32, u32), Error> {
+            let thumbnail = image::load_from_memory(data)?;
+            Ok((thumbnail.width(), thumbnail.height()))
+        };
+
+        let response = client.get(format!("{}/images/1/thumbnail?size=800", url)).send().await?;
+        assert_eq!(response.headers()["vary"], "Save-Data, ECT");
+        assert_eq!(edges(&response.bytes().await?)?, (300, 150));
+        let response = client.get(format!("{}/images/1/thumbnail?size=800", url)).header("save-data", "on").send().await?;
+        assert_eq!(edges(&response.bytes().await?)?, (100, 50));
+        let response = client.get(format!("{}/images/1/thumbnail?size=80", url)).header("ect", "2g").send().await?;
+        assert_eq!(edges(&response.bytes().await?)?, (80, 40));
+        assert_eq!(client.get(&url).send().await?.headers()["accept-ch"], "ECT");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_theme() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let stylesheet = dir.path().join("family.css");
+        std::fs::write(&stylesheet, ":root { --surface: #fdf6e3; }")?;
+        let mut config = Config::default();
+        config.web.accent = Some(String::from("#c0392b"));
+        config.web.stylesheet = Some(stylesheet);
+        config.web.map_tiles = String::from("https://tiles.example/{z}/{x}/{y}.png?key=a&b");
+        // No sending where photos were taken elsewhere when local-only
+        assert!(web::page(&config.web, true).contains(r#"<meta name="map-tiles" content="">"#));
+        let conn = crate::open_catalog(&dir.path().join("catalog.db"))?;
+        conn.execute_batch(
+            "INSERT INTO images (path, file_name, file_size, creation_date, rating) VALUES
+                 ('/a.jpg', 'a.jpg', 1, '2023-07-01 10:00:00', 2), ('/b.jpg', 'b.jpg', 1, '2023-07-02 10:00:00', 0), ('/c.jpg', 'c.jpg', 1, NULL, 5);",
+        )?;
+        let url = spawn_server_with(dir.path().join("catalog.db"), config).await?;
+        let client = reqwest::Client::new();
+
+        let page = client.get(&url).send().await?.text().await?;
+        assert!(page.contains(r#"href="/theme.css""#) && page.contains(r#"<dialog id="layout""#));
+        assert!(page.contains(r#"<meta name="map-tiles" content="https://tiles.example/{z}/{x}/{y}.png?key=a&amp;b">"#));
+        let response = client.get(format!("{}/theme.css", url)).send().await?;
+        assert_eq!(response.headers()["content-type"], "text/css");
+        assert_eq!(response.text().await?, ":root { --accent: #c0392b; }\n:root { --surface: #fdf6e3; }");
+
+        let preferences = format!("{}/preferences", url);
+        let saved: serde_json::Value = client.put(&preferences).json(&serde_json::json!({ "theme": "dark" })).send().await?.json().await?;
+        assert_eq!(saved, serde_json::json!({ "theme": "dark" }));
+        let saved: serde_json::Value = client.get(&preferences).send().await?.json().await?;
+        assert_eq!(saved, serde_json::json!({ "theme": "dark" }));
+        let huge = serde_json::json!({ "theme": "x".repeat(preferences::MAX_LENGTH + 1) });
+        assert_eq!(client.put(&preferences).json(&huge).send().await?.status(), StatusCode::BAD_REQUEST.as_u16());
+
+        // A layout, and the grid in the order it asks for
+        let layout = serde_json::json!({ "sort": "newest", "density": "small", "facets": "date,album", "landing": "album:Lisbon" });
+        let saved: serde_json::Value = client.put(&preferences).json(&layout).send().await?.json().await?;
+        assert_eq!((&saved["theme"], &saved["sort"]), (&serde_json::json!("dark"), &serde_json::json!("newest")));
+        let response = client.put(&preferences).json(&serde_json::json!({ "sort": "size" })).send().await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST.as_u16());
+        let order = |sort: &'static str| {
+            let client = client.clone();
+            let url = url.clone();
+            async move {
+                let images: Vec<serde_json::Value> = client.get(format!("{}/images?sort={}", url, sort)).send().await?.json().await?;
+                Ok::<_, Error>(images.iter().map(|image| image["path"].as_str().unwrap_or_default().to_string()).collect::<Vec<_>>())
+            }
+        };
+        assert_eq!(order("newest").await?, vec!["/b.jpg", "/a.jpg", "/c.jpg"]);
+        assert_eq!(order("rating").await?, vec!["/c.jpg", "/a.jpg", "/b.jpg"]);
+        assert_eq!(client.get(format!("{}/images?sort=size", url)).send().await?.status(), StatusCode::BAD_REQUEST.as_u16());
+        Ok(())
+    }
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(byte_range("bytes=0-", 10), Some((0, 9)));
+        assert_eq!(byte_range("bytes=2-5", 10), Some((2, 5)));
+        assert_eq!(byte_range("bytes=8-20", 10), Some((8, 9)));
+        assert_eq!(byte_range("bytes=-3", 10), Some((7, 9)));
+        assert_eq!(byte_range("bytes=12-", 10), None);
+        assert_eq!(byte_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(byte_range("bytes=0-", 0), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_video_preview() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let (playable, avi) = (dir.path().join("clip.mp4"), dir.path().join("old.avi"));
+        std::fs::write(&playable, b"0123456789")?;
+        std::fs::write(&avi, b"RIFF")?;
+        let catalog = dir.path().join("catalog.db");
+        crate::open_catalog(&catalog)?.execute(
+            "INSERT INTO images (path, file_name, file_size, format, video_codec) VALUES
+                (?1, 'clip.mp4', 10, 'Mp4', 'h264'), (?2, 'old.avi', 4, 'Avi', 'mpeg4'), ('/photo.jpg', 'photo.jpg', 1, 'Jpeg', NULL)",
+            [playable.to_string_lossy(), avi.to_string_lossy()],
+        )?;
+        let mut config = Config::default();
+        let runs = dir.path().join("runs");
+        config.video.ffmpeg = crate::test_support::fake_tool(dir.path(), &format!("for last; do :; done; printf transcoded > \"$last\"; echo >> '{}'", runs.display()))?;
+        config.video.preview_cache = dir.path().join("previews").to_string_lossy().into_owned();
+        let url = spawn_server_with(catalog, config).await?;
+        let client = reqwest::Client::new();
+
+        let response = client.get(format!("{}/images/1/video", url)).header("range", "bytes=2-5").send().await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT.as_u16());
+        assert_eq!(response.headers()["content-range"], "bytes 2-5/10");
+        assert_eq!(response.text().await?, "2345");
+
+        // Transcoded once, then served from the cache
+        for _ in 0..2 {
+            let response = client.get(format!("{}/images/2/video", url)).send().await?;
+            assert_eq!(response.headers()["content-type"], "video/mp4");
+            assert_eq!(response.text().await?, "transcoded");
+        }
+        assert_eq!(std::fs::read_to_string(&runs)?.lines().count(), 1);
+
+        assert_eq!(client.get(format!("{}/images/3/video", url)).send().await?.status(), StatusCode::NOT_FOUND.as_u16());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_job_events() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let catalog = dir.path().join("catalog.db");
+        let operation = progress::start(&crate::open_catalog(&catalog)?, "scan", CancelToken::new())?;
+        let url = spawn_server(catalog.clone()).await?;
+
+        let finish = tokio::task::spawn_blocking(move || -> Result<(), Error> {
+            let conn = crate::open_catalog(&catalog)?;
+            operation.set_total(&conn, 1)?;
+            thread::sleep(Duration::from_millis(600));
+            operation.advance(&conn, true)?;
+            operation.finish(&conn, &Ok(()))
+        });
+        // The stream ends with the operation
+        let body = reqwest::get(format!("{}/jobs/1/events", url)).await?.text().await?;
+        finish.await??;
+        let states: Vec<serde_json::Value> = body.lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(states.first().map(|status| &status["state"]), Some(&serde_json::json!("running")));
+        assert_eq!(states.last().map(|status| &status["state"]), Some(&serde_json::json!("completed")));
+        assert!(body.contains("event: status"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_start_scan() -> Result<(), Error> {
+        let dir = tempdir()?;
+        image::DynamicImage::new_rgb8(8, 6).save(dir.path().join("photo.png"))?;
+        let url = spawn_server(dir.path().join("catalog.db")).await?;
+        let client = reqwest::Client::new();
+
+        let response = client.post(format!("{}/jobs", url))
+            .json(&serde_json::json!({ "kind": "scan", "dir": dir.path(), "analyze": false }))
+            .send().await?;
+        assert_eq!(response.status(), StatusCode::ACCEPTED.as_u16());
+        let id = response.json::<serde_json::Value>().await?["id"].as_i64().unwrap();
+
+        let mut status = serde_json::Value::Null;
+        for _ in 0..100 {
+            status = client.get(format!("{}/jobs/{}", url, id)).send().await?.json().await?;
+            if status["state"] != "running" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(status["state"], "completed");
+        assert_eq!((status["total"].as_u64(), status["done"].as_u64()), (Some(1), Some(1)));
+        assert_eq!(status["percent"], 100.0);
+
+        let jobs: Vec<serde_json::Value> = client.get(format!("{}/jobs", url)).send().await?.json().await?;
+        assert_eq!(jobs.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_downloads() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let catalog = dir.path().join("catalog.db");
+        let conn = crate::open_catalog(&catalog)?;
+        for (name, camera) in [("a.jpg", "X100V"), ("b.jpg", "X100V"), ("c.jpg", "iPhone")] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, name)?;
+            conn.execute("INSERT INTO images (path, file_name, file_size, camera_model) VALUES (?1, ?2, 5, ?3)", params![path.to_string_lossy(), name, camera])?;
+        }
+        let mut config = Config::default();
+        config.downloads.dir = dir.path().join("downloads").to_string_lossy().into_owned();
+        let url = spawn_server_with(catalog, config).await?;
+        let client = reqwest::Client::new();
+        let downloads = format!("{}/downloads", url);
+
+        let response = client.post(&downloads).json(&serde_json::json!({ "query": "camera:X100V", "ids": [1, 3] })).send().await?;
+        assert_eq!(response.status(), StatusCode::ACCEPTED.as_u16());
+        let status: serde_json::Value = response.json().await?;
+        let id = status["id"].as_i64().unwrap();
+        assert_eq!(status["kind"], "download");
+
+        let mut status = serde_json::Value::Null;
+        for _ in 0..100 {
+            status = client.get(format!("{}/jobs/{}", url, id)).send().await?.json().await?;
+            if status["state"] != "running" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!((status["state"].as_str(), status["done"].as_u64()), (Some("completed"), Some(3)));
+        let response = client.get(format!("{}/{}", downloads, id)).send().await?;
+        assert_eq!(response.status(), StatusCode::OK.as_u16());
+        assert_eq!(response.headers()["content-type"], "application/zip");
+        assert_eq!(response.headers()["content-disposition"], format!("attachment; filename=\"photos-{}.zip\"", id));
+        assert_eq!(&response.bytes().await?[..4], b"PK\x03\x04");
+
+        assert_eq!(client.get(format!("{}/{}", downloads, id + 1)).send().await?.status(), StatusCode::NOT_FOUND.as_u16());
+        let response = client.post(&downloads).json(&serde_json::json!({ "query": "camera:Leica" })).send().await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND.as_u16());
+        Ok(())
+    }
+}
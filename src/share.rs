@@ -0,0 +1,270 @@
+//! Photos on network shares (SMB, NFS and the like) are read where they
+//! are: the bytes of each come over once, for hashing and analysis
+//! together, and nothing keeps a copy of an original. What's made from
+//! them for the web page is kept locally, in `[shares] cache`, so browsing
+//! doesn't fetch them again. A share that hiccups is retried a few times;
+//! one that stays away is offline rather than emptied, so its photos
+//! aren't taken for deleted or moved while it's gone.
+//!
+//! Shares are the folders `[shares] paths` lists, and on Linux whatever is
+//! mounted with a network filesystem.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::Deserialize;
+
+/// Filesystems in `/proc/mounts` that are shares.
+const NETWORK_FILESYSTEMS: &[&str] = &["cifs", "smb3", "smbfs", "nfs", "nfs4", "afs", "9p", "ceph", "fuse.sshfs", "fuse.rclone", "davfs"];
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShareConfig {
+    /// Folders on shares, besides the mounts found.
+    pub paths: Vec<PathBuf>,
+    /// How many times a read failing the way a dropped share does is tried
+    /// again.
+    pub retries: u32,
+    /// How long before the first retry, doubling for each after it.
+    pub retry_delay_ms: u64,
+    /// Where the thumbnails of photos on shares are kept.
+    pub cache: PathBuf,
+}
+
+impl Default for ShareConfig {
+    fn default() -> Self {
+        ShareConfig { paths: Vec::new(), retries: 3, retry_delay_ms: 500, cache: PathBuf::from("share-cache") }
+    }
+}
+
+/// The shares, and which of them have been found not to answer.
+#[derive(Debug)]
+pub struct Shares {
+    /// The innermost first
+    roots: Vec<PathBuf>,
+    retries: u32,
+    delay: Duration,
+    reachable: HashMap<PathBuf, bool>,
+}
+
+impl Shares {
+    pub fn new(config: &ShareConfig) -> Shares {
+        let mut roots: Vec<PathBuf> = config.paths.iter().map(|path| std::path::absolute(path).unwrap_or_else(|_| path.clone())).collect();
+        roots.extend(mounts());
+        roots.sort_by_key(|root| std::cmp::Reverse(root.components().count()));
+        roots.dedup();
+        Shares { roots, retries: config.retries, delay: Duration::from_millis(config.retry_delay_ms), reachable: HashMap::new() }
+    }
+
+    /// The share `path` is on, if it's on one.
+    pub fn root(&self, path: &Path) -> Option<&Path> {
+        self.roots.iter().find(|root| path.starts_with(root)).map(PathBuf::as_path)
+    }
+
+    /// The share `path` is on, if that share isn't answering. Each share is
+    /// only asked once, until `forget`.
+    pub fn offline(&mut self, path: &Path) -> Option<PathBuf> {
+        let root = self.root(path)?.to_path_buf();
+        let reachable = match self.reachable.get(&root) {
+            Some(reachable) => *reachable,
+            None => {
+                let reachable = self.answers(&root);
+                self.reachable.insert(root.clone(), reachable);
+                reachable
+            }
+        };
+        (!reachable).then_some(root)
+    }
+
+    /// Whether the file catalogued at `path` is gone: not there, and not on
+    /// a share that's offline.
+    pub fn gone(&mut self, path: &Path) -> bool {
+        !path.exists() && self.offline(path).is_none()
+    }
+
+    /// Ask the share `path` is on again next time.
+    pub fn forget(&mut self, path: &Path) {
+        if let Some(root) = self.root(path).map(Path::to_path_buf) {
+            self.reachable.remove(&root);
+        }
+    }
+
+    /// The shares found offline so far.
+    pub fn unreachable(&self) -> Vec<&Path> {
+        let mut roots: Vec<&Path> = self.reachable.iter().filter(|(_, reachable)| !**reachable).map(|(root, _)| root.as_path()).collect();
+        roots.sort();
+        roots
+    }
+
+    /// Whether the share at `root` lists anything: a share that isn't
+    /// mounted is an empty folder, or none.
+    fn answers(&self, root: &Path) -> bool {
+        retrying(self.retries, self.delay, || fs::read_dir(root).map(|mut entries| entries.next().is_some())).unwrap_or(false)
+    }
+}
+
+/// The mount points of network filesystems, from `/proc/mounts`.
+fn mounts() -> Vec<PathBuf> {
+    let Ok(mounts) = fs::read_to_string("/proc/mounts") else { return Vec::new() };
+    mounts.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (point, filesystem) = (fields.nth(1)?, fields.next()?);
+            NETWORK_FILESYSTEMS.contains(&filesystem).then(|| PathBuf::from(unescape(point)))
+        })
+        .collect()
+}
+
+/// A mount point as `/proc/mounts` writes it, with spaces and the like as
+/// octal escapes.
+pub fn unescape(field: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = field;
+    while let Some(at) = rest.find('\\') {
+        unescaped.push_str(&rest[..at]);
+        match rest.get(at + 1..at + 4).and_then(|octal| u8::from_str_radix(octal, 8).ok()) {
+            Some(byte) => {
+                unescaped.push(char::from(byte));
+                rest = &rest[at + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[at + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Whether `e` is how a share that's briefly away fails, worth trying again.
+pub fn is_transient(e: &io::Error) -> bool {
+    // EIO, which is what a dropped SMB or NFS connection mostly gives
+    let io_error = cfg!(unix) && e.raw_os_error() == Some(5);
+    io_error || matches!(
+        e.kind(),
+        ErrorKind::TimedOut | ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::ResourceBusy
+            | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::NotConnected | ErrorKind::BrokenPipe
+            | ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable | ErrorKind::NetworkDown | ErrorKind::StaleNetworkFileHandle
+    )
+}
+
+fn retrying<T>(retries: u32, delay: Duration, mut attempt: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = delay;
+    for _ in 0..retries {
+        match attempt() {
+            Err(e) if is_transient(&e) => {
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    attempt()
+}
+
+/// The bytes of the file at `path`, read again if the share it's on
+/// hiccups.
+pub fn read(path: &Path, config: &ShareConfig) -> io::Result<Vec<u8>> {
+    retrying(config.retries, Duration::from_millis(config.retry_delay_ms), || fs::read(path))
+}
+
+/// The JPEG `make` gives of the photo with `hash` at `edge` pixels, made
+/// once and kept in `config.cache`.
+#[cfg(feature = "server")]
+pub fn cached(config: &ShareConfig, hash: &str, edge: u32, make: impl FnOnce() -> Result<Vec<u8>, anyhow::Error>) -> Result<Vec<u8>, anyhow::Error> {
+    let path = config.cache.join(format!("{}-{}.jpg", hash, edge));
+    if let Ok(data) = fs::read(&path) {
+        return Ok(data);
+    }
+    let data = make()?;
+    // Only ever a copy, so not being able to keep one is no error
+    if fs::create_dir_all(&config.cache).is_ok() {
+        let partial = path.with_extension("part");
+        if fs::write(&partial, &data).is_ok() {
+            let _ = fs::rename(&partial, &path);
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use anyhow::Error;
+
+    fn shares(paths: &[&Path]) -> Shares {
+        Shares::new(&ShareConfig { paths: paths.iter().map(|path| path.to_path_buf()).collect(), retry_delay_ms: 1, ..ShareConfig::default() })
+    }
+
+    #[test]
+    fn test_offline() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let (nas, usb) = (dir.path().join("nas"), dir.path().join("usb"));
+        fs::create_dir_all(nas.join("2023"))?;
+        fs::write(nas.join("2023/a.jpg"), b"a")?;
+        // A mount point with nothing mounted on it
+        fs::create_dir(&usb)?;
+        let mut shares = shares(&[&nas, &usb]);
+
+        assert_eq!(shares.root(&nas.join("2023/a.jpg")), Some(nas.as_path()));
+        assert_eq!(shares.root(&dir.path().join("local.jpg")), None);
+        assert_eq!(shares.offline(&nas.join("2023/a.jpg")), None);
+        assert_eq!(shares.offline(&usb.join("b.jpg")), Some(usb.clone()));
+        // Gone from a share that answers, but not from one that doesn't
+        assert!(shares.gone(&nas.join("2023/deleted.jpg")));
+        assert!(!shares.gone(&usb.join("b.jpg")));
+        assert!(shares.gone(&dir.path().join("local.jpg")));
+        assert_eq!(shares.unreachable(), vec![usb.as_path()]);
+
+        // Asked again once forgotten
+        fs::write(usb.join("b.jpg"), b"b")?;
+        assert!(shares.offline(&usb.join("b.jpg")).is_some());
+        shares.forget(&usb.join("b.jpg"));
+        assert_eq!(shares.offline(&usb.join("b.jpg")), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retrying() {
+        let attempts = Cell::new(0);
+        let flaky = || {
+            attempts.set(attempts.get() + 1);
+            match attempts.get() {
+                1 | 2 => Err(io::Error::from(ErrorKind::TimedOut)),
+                _ => Ok(attempts.get()),
+            }
+        };
+        assert_eq!(retrying(3, Duration::from_millis(1), flaky).ok(), Some(3));
+        attempts.set(0);
+        assert!(retrying(1, Duration::from_millis(1), flaky).is_err());
+        // Not worth trying again
+        attempts.set(0);
+        assert!(retrying(3, Duration::from_millis(1), || {
+            attempts.set(attempts.get() + 1);
+            Err::<(), _>(io::Error::from(ErrorKind::NotFound))
+        }).is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape(r"/mnt/family\040photos"), "/mnt/family photos");
+        assert_eq!(unescape(r"/mnt/odd\x"), r"/mnt/odd\x");
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_cached() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let config = ShareConfig { cache: dir.path().join("cache"), ..ShareConfig::default() };
+        assert_eq!(cached(&config, "abc", 400, || Ok(b"jpeg".to_vec()))?, b"jpeg");
+        // Not made again
+        assert_eq!(cached(&config, "abc", 400, || anyhow::bail!("read the original again"))?, b"jpeg");
+        assert!(cached(&config, "abc", 1600, || anyhow::bail!("not made yet")).is_err());
+        Ok(())
+    }
+}
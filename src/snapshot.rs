@@ -0,0 +1,251 @@
+//! Scanning from a snapshot (`scan --snapshot`, `[scan] snapshot`), so a
+//! long scan sees a folder as it was when it started however much is
+//! added, edited or moved in it meanwhile. The snapshot is of the
+//! filesystem the folder is on: of the subvolume mounted on Btrfs, of the
+//! dataset on ZFS, a local Time Machine snapshot mounted read-only on
+//! APFS. It's removed when the scan ends, however it ends. Files are read
+//! from it but catalogued under their own paths.
+//!
+//! Anywhere else, or where taking one fails (it mostly takes root), the
+//! folder is scanned as it is, with a warning.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::{anyhow, bail, Error};
+
+/// What snapshots are named with, which scans skip by default so the
+/// daemon doesn't catalog one while it's there
+const PREFIX: &str = ".PhotoCataloger-snapshot-";
+
+/// A snapshot of the folder `live`, until dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+    kind: Kind,
+    /// The folder scanned, as it was given
+    live: PathBuf,
+    /// The same folder in the snapshot
+    view: PathBuf,
+}
+
+#[derive(Debug)]
+enum Kind {
+    /// The snapshot's subvolume
+    Btrfs(PathBuf),
+    /// dataset@name
+    Zfs(String),
+    /// The snapshot's date, as `tmutil` names it, and where it's mounted
+    Apfs(String, PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Mount {
+    point: PathBuf,
+    /// The device, or the ZFS dataset
+    source: String,
+    filesystem: String,
+}
+
+impl Snapshot {
+    /// A snapshot of the filesystem `dir` is on, if one can be taken.
+    pub fn take(dir: &Path) -> Option<Snapshot> {
+        let taken = fs::canonicalize(dir).map_err(Error::from).and_then(|canonical| {
+            let mounts = mounts();
+            let mount = mount_of(&canonical, &mounts).ok_or_else(|| anyhow!("couldn't tell which filesystem it's on"))?;
+            let relative = canonical.strip_prefix(&mount.point)?;
+            let name = format!("{}{}-{}", PREFIX, chrono::Utc::now().format("%Y%m%dT%H%M%S"), std::process::id());
+            let (kind, root) = match mount.filesystem.as_str() {
+                "btrfs" => btrfs(mount, &name)?,
+                "zfs" => zfs(mount, &name)?,
+                "apfs" => apfs(mount, &name)?,
+                other => bail!("it's on {}, which has no snapshots to take", other),
+            };
+            let snapshot = Snapshot { kind, live: dir.to_path_buf(), view: root.join(relative) };
+            // A Btrfs subvolume inside the one snapshotted is left out of it,
+            // as an empty folder
+            if is_empty(&snapshot.view) && !is_empty(dir) {
+                bail!("it's on a subvolume or dataset of its own that isn't mounted on its own");
+            }
+            Ok(snapshot)
+        });
+        match taken {
+            Ok(snapshot) => {
+                tracing::info!("Scanning {} from a snapshot, at {}", dir.display(), snapshot.view.display());
+                Some(snapshot)
+            }
+            Err(e) => {
+                tracing::warn!("Not snapshotting {}: {:#}; scanning it as it is", dir.display(), e);
+                None
+            }
+        }
+    }
+
+    /// The folder in the snapshot, to be scanned in place of the live one.
+    pub fn view(&self) -> &Path {
+        &self.view
+    }
+
+    /// The live path of `path` in the snapshot.
+    pub fn live(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.view).map_or_else(|_| path.to_path_buf(), |relative| self.live.join(relative))
+    }
+
+    /// Where in the snapshot the live `path` is.
+    pub fn inside(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.live).map_or_else(|_| path.to_path_buf(), |relative| self.view.join(relative))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let removed = match &self.kind {
+            Kind::Btrfs(subvolume) => run("btrfs", &["subvolume", "delete", &subvolume.to_string_lossy()]).map(drop),
+            Kind::Zfs(snapshot) => run("zfs", &["destroy", snapshot]).map(drop),
+            Kind::Apfs(date, mount) => run("umount", &[&mount.to_string_lossy()])
+                .and_then(|_| Ok(fs::remove_dir(mount)?))
+                .and_then(|_| run("tmutil", &["deletelocalsnapshots", date]).map(drop)),
+        };
+        if let Err(e) = removed {
+            tracing::error!("Couldn't remove the snapshot scanned ({:?}): {:#}", self.kind, e);
+        }
+    }
+}
+
+fn btrfs(mount: &Mount, name: &str) -> Result<(Kind, PathBuf), Error> {
+    let subvolume = mount.point.join(name);
+    run("btrfs", &["subvolume", "snapshot", "-r", &mount.point.to_string_lossy(), &subvolume.to_string_lossy()])?;
+    Ok((Kind::Btrfs(subvolume.clone()), subvolume))
+}
+
+fn zfs(mount: &Mount, name: &str) -> Result<(Kind, PathBuf), Error> {
+    // ZFS snapshot names can't start with a dot
+    let name = name.trim_start_matches('.');
+    let snapshot = format!("{}@{}", mount.source, name);
+    run("zfs", &["snapshot", &snapshot])?;
+    Ok((Kind::Zfs(snapshot), mount.point.join(".zfs/snapshot").join(name)))
+}
+
+fn apfs(mount: &Mount, name: &str) -> Result<(Kind, PathBuf), Error> {
+    let created = run("tmutil", &["localsnapshot"])?;
+    let date = snapshot_date(&created).ok_or_else(|| anyhow!("tmutil didn't say which snapshot it made: {}", created.trim()))?;
+    let at = std::env::temp_dir().join(name);
+    fs::create_dir_all(&at)?;
+    let snapshot = format!("com.apple.TimeMachine.{}.local", date);
+    if let Err(e) = run("mount_apfs", &["-o", "rdonly,nobrowse", "-s", &snapshot, &mount.point.to_string_lossy(), &at.to_string_lossy()]) {
+        let _ = fs::remove_dir(&at);
+        let _ = run("tmutil", &["deletelocalsnapshots", &date]);
+        return Err(e);
+    }
+    Ok((Kind::Apfs(date, at.clone()), at))
+}
+
+/// The date `tmutil localsnapshot` named the snapshot it made by.
+fn snapshot_date(output: &str) -> Option<String> {
+    output.lines()
+        .find_map(|line| line.split_once("with date:"))
+        .map(|(_, date)| date.trim().to_string())
+        .filter(|date| !date.is_empty())
+}
+
+fn is_empty(dir: &Path) -> bool {
+    fs::read_dir(dir).map_or(true, |mut entries| entries.next().is_none())
+}
+
+/// What's mounted: `/proc/mounts` on Linux, `mount` elsewhere.
+fn mounts() -> Vec<Mount> {
+    match fs::read_to_string("/proc/mounts") {
+        Ok(mounts) => proc_mounts(&mounts),
+        Err(_) => run("mount", &[]).map(|mounts| bsd_mounts(&mounts)).unwrap_or_default(),
+    }
+}
+
+/// `/proc/mounts`: "source point filesystem options 0 0".
+fn proc_mounts(mounts: &str) -> Vec<Mount> {
+    mounts.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (source, point, filesystem) = (fields.next()?, fields.next()?, fields.next()?);
+            let unescape = crate::share::unescape;
+            Some(Mount { point: PathBuf::from(unescape(point)), source: unescape(source), filesystem: filesystem.to_string() })
+        })
+        .collect()
+}
+
+/// macOS's and the BSDs' `mount`: "source on point (filesystem, options)".
+fn bsd_mounts(mounts: &str) -> Vec<Mount> {
+    mounts.lines()
+        .filter_map(|line| {
+            let (source, rest) = line.split_once(" on ")?;
+            let (point, options) = rest.rsplit_once(" (")?;
+            let filesystem = options.split([',', ')']).next()?.trim();
+            Some(Mount { point: PathBuf::from(point), source: source.to_string(), filesystem: filesystem.to_string() })
+        })
+        .collect()
+}
+
+/// The innermost of `mounts` that `path` is on.
+fn mount_of<'a>(path: &Path, mounts: &'a [Mount]) -> Option<&'a Mount> {
+    mounts.iter()
+        .filter(|mount| path.starts_with(&mount.point))
+        .max_by_key(|mount| mount.point.components().count())
+}
+
+fn run(program: &str, args: &[&str]) -> Result<String, Error> {
+    let output = match Command::new(program).args(args).output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => bail!("{} not found", program),
+        Err(e) => return Err(e.into()),
+    };
+    if !output.status.success() {
+        bail!("{} {} failed: {}", program, args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mounts() {
+        let linux = proc_mounts(
+            "/dev/sda2 / ext4 rw,relatime 0 0\n\
+             /dev/sdb1 /home btrfs rw,subvol=/@home 0 0\n\
+             tank/photos /mnt/family\\040photos zfs rw,xattr 0 0\n",
+        );
+        let mount = |path: &str| mount_of(Path::new(path), &linux).map(|mount| (mount.filesystem.as_str(), mount.source.as_str()));
+        assert_eq!(mount("/home/ana/Pictures"), Some(("btrfs", "/dev/sdb1")));
+        assert_eq!(mount("/mnt/family photos/2023"), Some(("zfs", "tank/photos")));
+        assert_eq!(mount("/homes"), Some(("ext4", "/dev/sda2")));
+
+        let mac = bsd_mounts(
+            "/dev/disk3s1s1 on / (apfs, sealed, local, read-only, journaled)\n\
+             /dev/disk3s5 on /System/Volumes/Data (apfs, local, journaled, nobrowse)\n\
+             //ana@nas/photos on /Volumes/photos (smbfs, nodev, nosuid, mounted by ana)\n",
+        );
+        let data = mount_of(Path::new("/System/Volumes/Data/Users/ana/Pictures"), &mac).unwrap();
+        assert_eq!((data.filesystem.as_str(), data.point.as_path()), ("apfs", Path::new("/System/Volumes/Data")));
+        assert_eq!(mount_of(Path::new("/Volumes/photos/2023"), &mac).unwrap().filesystem, "smbfs");
+    }
+
+    #[test]
+    fn test_snapshot_date() {
+        assert_eq!(snapshot_date("Created local snapshot with date: 2024-05-04-020000\n").as_deref(), Some("2024-05-04-020000"));
+        assert_eq!(snapshot_date("NOTE: snapshots are disabled\n"), None);
+    }
+
+    #[test]
+    fn test_paths() {
+        let snapshot = Snapshot {
+            kind: Kind::Btrfs(PathBuf::from("/nonexistent")),
+            live: PathBuf::from("photos"),
+            view: PathBuf::from("/home/.PhotoCataloger-snapshot-1/ana/photos"),
+        };
+        let inside = Path::new("/home/.PhotoCataloger-snapshot-1/ana/photos/2023/a.jpg");
+        assert_eq!(snapshot.live(inside), Path::new("photos/2023/a.jpg"));
+        assert_eq!(snapshot.inside(Path::new("photos/2023/a.jpg")), inside);
+        assert_eq!(snapshot.live(Path::new("/elsewhere/b.jpg")), Path::new("/elsewhere/b.jpg"));
+        // There's nothing to remove
+        std::mem::forget(snapshot);
+    }
+}
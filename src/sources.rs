@@ -0,0 +1,247 @@
+//! Sources: the folders a catalog is scanned from, each with a name, so
+//! one catalog can span an internal disk, an external drive and a NAS
+//! mount, and `scan nas` rescans just the one. A folder becomes a source
+//! the first time it's scanned, named after its last component unless
+//! `[[sources]]` in the configuration names it, and the photos under it
+//! belong to it; under sources inside other sources, to the innermost.
+
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use anyhow::Error;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Deserialize;
+use crate::error::{CliError, ErrorKind};
+
+/// A source as `[[sources]]` configures it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub id: i64,
+    pub name: String,
+    pub path: PathBuf,
+    /// When a scan of it last finished, RFC 3339
+    pub scanned_at: Option<String>,
+    /// How many photos are under it
+    pub images: i64,
+    /// Since when it's been on a share that isn't answering, RFC 3339
+    pub offline_since: Option<String>,
+}
+
+const COLUMNS: &str = "id, name, path, scanned_at, (SELECT COUNT(*) FROM images WHERE source_id = sources.id), offline_since";
+
+fn from_row(row: &Row) -> rusqlite::Result<Source> {
+    Ok(Source {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        path: PathBuf::from(row.get::<_, String>(2)?),
+        scanned_at: row.get(3)?,
+        images: row.get(4)?,
+        offline_since: row.get(5)?,
+    })
+}
+
+pub fn list(conn: &Connection) -> Result<Vec<Source>, Error> {
+    let sources = conn.prepare(&format!("SELECT {} FROM sources ORDER BY name", COLUMNS))?
+        .query_map([], from_row)?
+        .collect::<Result<_, _>>()?;
+    Ok(sources)
+}
+
+/// Source `name`, whatever its case.
+pub fn find(conn: &Connection, name: &str) -> Result<Source, Error> {
+    conn.query_row(&format!("SELECT {} FROM sources WHERE name = ?1", COLUMNS), [name.trim()], from_row)
+        .optional()?
+        .ok_or_else(|| CliError::new(ErrorKind::NothingToDo, format!("no source named {}", name.trim())).into())
+}
+
+fn at(conn: &Connection, path: &Path) -> Result<Option<Source>, Error> {
+    Ok(conn.query_row(&format!("SELECT {} FROM sources WHERE path = ?1", COLUMNS), [path.to_string_lossy()], from_row).optional()?)
+}
+
+/// Make the sources `[[sources]]` lists, or rename those already made for
+/// their folders.
+pub fn configure(conn: &Connection, configured: &[SourceConfig]) -> Result<(), Error> {
+    for source in configured {
+        let path = std::path::absolute(&source.path)?;
+        let name = source.name.trim();
+        match at(conn, &path)? {
+            Some(known) if known.name == name => {}
+            Some(known) => rename(conn, &known.name, name)?,
+            None => {
+                add(conn, name, &path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The source `target` names, or the one for the folder at `target`,
+/// made if there isn't one yet. A name wins over a folder of that name in
+/// the working directory, which `./name` scans instead.
+pub fn resolve(conn: &Connection, target: &Path) -> Result<Source, Error> {
+    if target.components().count() == 1 {
+        if let Ok(source) = find(conn, &target.to_string_lossy()) {
+            return Ok(source);
+        }
+    }
+    let path = std::path::absolute(target)?;
+    if let Some(source) = at(conn, &path)? {
+        return Ok(source);
+    }
+    if !path.is_dir() {
+        let message = format!("{} is neither a source nor a folder", target.display());
+        return Err(CliError::new(ErrorKind::NothingToDo, message).into());
+    }
+    let stem = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| String::from("root"));
+    let mut name = stem.clone();
+    let mut copy = 1;
+    while find(conn, &name).is_ok() {
+        copy += 1;
+        name = format!("{}-{}", stem, copy);
+    }
+    add(conn, &name, &path)?;
+    tracing::info!("Added source {} for {}", name, path.display());
+    at(conn, &path)?.ok_or_else(|| anyhow::anyhow!("source {} went missing", name))
+}
+
+fn add(conn: &Connection, name: &str, path: &Path) -> Result<(), Error> {
+    if name.is_empty() {
+        return Err(CliError::new(ErrorKind::Config, "a source needs a name").into());
+    }
+    let added = conn.execute(
+        "INSERT INTO sources (name, path, added_at) VALUES (?1, ?2, ?3) ON CONFLICT (name) DO NOTHING",
+        params![name, path.to_string_lossy(), chrono::Utc::now().to_rfc3339()],
+    )?;
+    if added == 0 {
+        let message = format!("there's already a source named {}, for {}", name, find(conn, name)?.path.display());
+        return Err(CliError::new(ErrorKind::Config, message).into());
+    }
+    Ok(())
+}
+
+pub fn rename(conn: &Connection, name: &str, new_name: &str) -> Result<(), Error> {
+    let source = find(conn, name)?;
+    let new_name = new_name.trim();
+    if new_name.is_empty() {
+        return Err(CliError::new(ErrorKind::Config, "a source needs a name").into());
+    }
+    if find(conn, new_name).is_ok_and(|other| other.id != source.id) {
+        return Err(CliError::new(ErrorKind::Config, format!("there's already a source named {}", new_name)).into());
+    }
+    conn.execute("UPDATE sources SET name = ?1 WHERE id = ?2", params![new_name, source.id])?;
+    Ok(())
+}
+
+/// Forget source `name`, leaving its photos in the catalog without one
+/// until a scan of a folder they're in. Gives how many it had.
+pub fn remove(conn: &Connection, name: &str) -> Result<usize, Error> {
+    let tx = conn.unchecked_transaction()?;
+    let source = find(&tx, name)?;
+    let unlinked = tx.execute("UPDATE images SET source_id = NULL WHERE source_id = ?1", [source.id])?;
+    tx.execute("DELETE FROM sources WHERE id = ?1", [source.id])?;
+    tx.commit()?;
+    Ok(unlinked)
+}
+
+/// Record that `source` was just scanned, so it's there.
+pub fn scanned(conn: &Connection, source: &Source) -> Result<(), Error> {
+    conn.execute("UPDATE sources SET scanned_at = ?1, offline_since = NULL WHERE id = ?2", params![chrono::Utc::now().to_rfc3339(), source.id])?;
+    assign(conn)
+}
+
+/// Record that `source` isn't there, unless it's known not to be already.
+pub fn offline(conn: &Connection, source: &Source) -> Result<(), Error> {
+    conn.execute("UPDATE sources SET offline_since = ?1 WHERE id = ?2 AND offline_since IS NULL", params![chrono::Utc::now().to_rfc3339(), source.id])?;
+    Ok(())
+}
+
+/// Give every photo under a source's folder that source, the innermost
+/// where they nest.
+pub fn assign(conn: &Connection) -> Result<(), Error> {
+    let tx = conn.unchecked_transaction()?;
+    // Outermost first, so the ones inside them have the last word
+    let sources: Vec<(i64, String)> = tx.prepare("SELECT id, path FROM sources ORDER BY length(path)")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    let mut stmt = tx.prepare(
+        "UPDATE images SET source_id = ?1
+         WHERE (path = ?2 OR substr(path, 1, length(?3)) = ?3) AND source_id IS NOT ?1",
+    )?;
+    for (id, path) in &sources {
+        let prefix = match path.ends_with(MAIN_SEPARATOR) {
+            true => path.clone(),
+            false => format!("{}{}", path, MAIN_SEPARATOR),
+        };
+        stmt.execute(params![id, path, prefix])?;
+    }
+    drop(stmt);
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(conn: &Connection, source: &str) -> Vec<String> {
+        conn.prepare("SELECT path FROM images WHERE source_id = (SELECT id FROM sources WHERE name = ?1) ORDER BY path").unwrap()
+            .query_map([source], |row| row.get(0)).unwrap()
+            .collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_sources() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().display();
+        for folder in ["media/usb", "backup/usb", "mnt/nas/2023"] {
+            std::fs::create_dir_all(dir.path().join(folder))?;
+        }
+        conn.execute_batch(&format!(
+            "INSERT INTO images (path, file_name, file_size) VALUES
+                 ('{root}/mnt/nas/a.jpg', 'a.jpg', 1), ('{root}/mnt/nas/2023/b.jpg', 'b.jpg', 1),
+                 ('{root}/mnt/nas-old/c.jpg', 'c.jpg', 1), ('{root}/media/usb/DCIM/d.jpg', 'd.jpg', 1)",
+        ))?;
+        configure(&conn, &[SourceConfig { name: String::from("nas"), path: dir.path().join("mnt/nas") }])?;
+        let usb = resolve(&conn, &dir.path().join("media/usb"))?;
+        assert_eq!(usb.name, "usb");
+        // Found again by folder or name, and another folder called the same
+        // gets a name of its own; what's neither isn't made one
+        assert_eq!(resolve(&conn, &dir.path().join("media/usb"))?.id, usb.id);
+        assert_eq!(resolve(&conn, Path::new("USB"))?.id, usb.id);
+        assert_eq!(resolve(&conn, &dir.path().join("backup/usb"))?.name, "usb-2");
+        assert!(resolve(&conn, &dir.path().join("media/cdrom")).is_err());
+        let inner = resolve(&conn, &dir.path().join("mnt/nas/2023"))?;
+
+        offline(&conn, &find(&conn, "nas")?)?;
+        let since = find(&conn, "nas")?.offline_since;
+        assert!(since.is_some());
+        // Since it first went
+        offline(&conn, &find(&conn, "nas")?)?;
+        assert_eq!(find(&conn, "nas")?.offline_since, since);
+        scanned(&conn, &find(&conn, "nas")?)?;
+        // Not /mnt/nas-old, and 2023 has its own
+        assert_eq!(paths(&conn, "nas"), vec![format!("{root}/mnt/nas/a.jpg")]);
+        assert_eq!(paths(&conn, &inner.name), vec![format!("{root}/mnt/nas/2023/b.jpg")]);
+        assert_eq!(paths(&conn, "usb"), vec![format!("{root}/media/usb/DCIM/d.jpg")]);
+        assert!(find(&conn, "nas")?.scanned_at.is_some() && find(&conn, "nas")?.offline_since.is_none());
+
+        // Renamed by the configuration, which can't take another's name
+        configure(&conn, &[SourceConfig { name: String::from("synology"), path: dir.path().join("mnt/nas") }])?;
+        assert_eq!(find(&conn, "synology")?.images, 1);
+        assert!(configure(&conn, &[SourceConfig { name: String::from("usb"), path: PathBuf::from("/elsewhere") }]).is_err());
+        assert!(rename(&conn, "synology", "usb-2").is_err());
+
+        assert_eq!(remove(&conn, "usb")?, 1);
+        assert!(find(&conn, "usb").is_err());
+        let names: Vec<String> = list(&conn)?.into_iter().map(|source| source.name).collect();
+        assert_eq!(names, vec!["2023", "synology", "usb-2"]);
+        Ok(())
+    }
+}
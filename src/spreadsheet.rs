@@ -0,0 +1,403 @@
+//! Curation done in a spreadsheet, merged back in: `import csv` reads a CSV
+//! file (as `export --csv` writes them, or any other with a header row)
+//! whose rows are photos, matched by path or by content hash, and merges
+//! what its columns say into them. It takes keywords (added to those the
+//! photo has), a rating, favorite and rejected, a corrected capture time,
+//! and fields of the user's own as `field:client` columns. Empty cells
+//! leave things as they are, and columns it doesn't know are skipped.
+//!
+//! Every change is worked out before any is made, so a bad cell anywhere
+//! stops the import with nothing changed, and `--dry-run` shows the
+//! changes without making them.
+
+use std::collections::BTreeMap;
+use anyhow::{bail, Error};
+use chrono::NaiveDateTime;
+use rusqlite::{params, Connection};
+use crate::edits::{self, Caption, Editor};
+use crate::error::{CliError, ErrorKind};
+use crate::{dates, fields, keywords, query, tags};
+
+/// How rows are matched to photos.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchOn {
+    /// By the path a photo was cataloged under, in a `path` column
+    Path,
+    /// By SHA-256, in a `content_hash` (or `hash`) column, for files
+    /// moved since or cataloged elsewhere; copies all match
+    Hash,
+}
+
+impl MatchOn {
+    pub const ALL: [MatchOn; 2] = [MatchOn::Path, MatchOn::Hash];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MatchOn::Path => "path",
+            MatchOn::Hash => "hash",
+        }
+    }
+
+    /// For `--match-on`'s value parser.
+    pub fn parse(name: &str) -> Result<MatchOn, String> {
+        MatchOn::ALL.into_iter().find(|on| on.name() == name.trim().to_lowercase()).ok_or_else(|| {
+            let names: Vec<&str> = MatchOn::ALL.iter().map(|on| on.name()).collect();
+            format!("can't match on {:?}; photos are matched on {}", name, names.join(" or "))
+        })
+    }
+
+    fn columns(self) -> &'static [&'static str] {
+        match self {
+            MatchOn::Path => &["path"],
+            MatchOn::Hash => &["content_hash", "hash"],
+        }
+    }
+}
+
+/// What a column sets.
+#[derive(Debug, Clone, PartialEq)]
+enum Column {
+    Keywords,
+    Rating,
+    Favorite,
+    Rejected,
+    Date,
+    Field(String),
+}
+
+impl Column {
+    fn of(header: &str) -> Option<Column> {
+        let header = header.trim();
+        if let Some(key) = header.get(..6).filter(|prefix| prefix.eq_ignore_ascii_case("field:")).map(|_| &header[6..]) {
+            return Some(Column::Field(key.trim().to_string()));
+        }
+        Some(match header.to_lowercase().as_str() {
+            "keywords" | "tags" => Column::Keywords,
+            "rating" => Column::Rating,
+            "favorite" => Column::Favorite,
+            "rejected" => Column::Rejected,
+            "creation_date" | "date" => Column::Date,
+            _ => return None,
+        })
+    }
+}
+
+/// What a photo has that a spreadsheet can change.
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    keywords: String,
+    rating: u8,
+    favorite: bool,
+    rejected: bool,
+    date: Option<String>,
+    /// By key, as it's kept
+    fields: BTreeMap<String, String>,
+}
+
+impl State {
+    fn load(conn: &Connection, id: i64) -> Result<State, Error> {
+        let state = conn.query_row(
+            &format!("SELECT {}, rating, favorite, rejected, creation_date FROM images WHERE id = ?1", tags::KEYWORDS),
+            [id],
+            |row| Ok(State {
+                keywords: row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                rating: row.get(1)?,
+                favorite: row.get(2)?,
+                rejected: row.get(3)?,
+                date: row.get(4)?,
+                fields: BTreeMap::new(),
+            }),
+        )?;
+        Ok(State { fields: fields::of(conn, id)?.into_iter().collect(), ..state })
+    }
+
+    /// Merge the cell `value` of a `column` column in.
+    fn merge(&mut self, conn: &Connection, column: &Column, value: &str) -> Result<(), Error> {
+        match column {
+            Column::Keywords => {
+                let mut merged = keywords::split(&self.keywords);
+                for keyword in keywords::split(value) {
+                    if !merged.iter().any(|kept| kept.eq_ignore_ascii_case(&keyword)) {
+                        merged.push(keyword);
+                    }
+                }
+                self.keywords = merged.join(", ");
+            }
+            Column::Rating => self.rating = match value.trim() {
+                "0" => 0,
+                stars => query::parse_rating(stars).map_err(Error::msg)?,
+            },
+            Column::Favorite => self.favorite = query::parse_yes_no(value).map_err(Error::msg)?,
+            Column::Rejected => self.rejected = query::parse_yes_no(value).map_err(Error::msg)?,
+            Column::Date => self.date = Some(parse_date(value)?),
+            Column::Field(key) => {
+                let (key, value) = fields::normalize(conn, key, value)?;
+                // A key new to the catalog, spelled as it first was
+                let key = self.fields.keys().find(|kept| kept.eq_ignore_ascii_case(&key)).cloned().unwrap_or(key);
+                self.fields.insert(key, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// How `self` became `after`, as "what", before and after.
+    fn diff(&self, after: &State) -> Vec<(String, String, String)> {
+        let yes_no = |flag: bool| String::from(if flag { "yes" } else { "no" });
+        let mut diff = Vec::new();
+        let mut change = |what: &str, before: String, after: String| {
+            if before != after {
+                diff.push((what.to_string(), before, after));
+            }
+        };
+        change("keywords", self.keywords.clone(), after.keywords.clone());
+        change("rating", self.rating.to_string(), after.rating.to_string());
+        change("favorite", yes_no(self.favorite), yes_no(after.favorite));
+        change("rejected", yes_no(self.rejected), yes_no(after.rejected));
+        change("creation_date", self.date.clone().unwrap_or_default(), after.date.clone().unwrap_or_default());
+        for (key, value) in &after.fields {
+            change(&format!("field:{}", key), self.fields.get(key).cloned().unwrap_or_default(), value.clone());
+        }
+        diff
+    }
+}
+
+/// A capture time as the catalog keeps them, from how spreadsheets tend to
+/// write them: "2023-07-14 18:30:00", with a T, as EXIF has it, or without
+/// the seconds.
+fn parse_date(value: &str) -> Result<String, Error> {
+    let value = value.trim();
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y:%m:%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .map(|date| date.format(dates::CATALOG_FORMAT).to_string())
+        .ok_or_else(|| anyhow::anyhow!("invalid capture time {:?} (expected e.g. 2023-07-14 18:30:00)", value))
+}
+
+/// One thing the import changes, or would.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub path: String,
+    /// The column, e.g. "rating" or "field:client"
+    pub what: String,
+    pub before: String,
+    pub after: String,
+}
+
+/// The outcome of `merge`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Merged {
+    /// By photo, in the order they came
+    pub changes: Vec<Change>,
+    /// Photos that changed, or would
+    pub photos: usize,
+    /// The paths or hashes of rows no photo matched
+    pub unmatched: Vec<String>,
+    /// Columns it doesn't know
+    pub ignored_columns: Vec<String>,
+}
+
+/// Merge the CSV `text` into the photos its rows match on `match_on`, or
+/// with `dry_run`, only say what that would change.
+pub fn merge(conn: &Connection, text: &str, match_on: MatchOn, dry_run: bool) -> Result<Merged, Error> {
+    let mut rows = records(text)?.into_iter();
+    let Some(header) = rows.next() else { bail!("the CSV file is empty") };
+    let key = header.iter().position(|name| match_on.columns().contains(&name.trim().to_lowercase().as_str())).ok_or_else(|| {
+        let message = format!("no {} column to match photos on", match_on.columns().join(" or "));
+        CliError::new(ErrorKind::Config, message)
+    })?;
+    let mut merged = Merged::default();
+    let mut columns = Vec::new();
+    for (i, name) in header.iter().enumerate().filter(|(i, _)| *i != key) {
+        match Column::of(name) {
+            Some(column) => columns.push((i, column)),
+            None => merged.ignored_columns.push(name.trim().to_string()),
+        }
+    }
+
+    // Photos as they are and as the rows leave them, a row at a time so a
+    // photo on several rows gets all of them
+    let mut states: Vec<(i64, String, State, State)> = Vec::new();
+    let lookup = match match_on {
+        MatchOn::Path => "SELECT id, path FROM images WHERE path = ?1",
+        MatchOn::Hash => "SELECT id, path FROM images WHERE content_hash = ?1 ORDER BY id",
+    };
+    let mut stmt = conn.prepare(lookup)?;
+    for (number, row) in rows.enumerate() {
+        let Some(wanted) = row.get(key).map(|cell| cell.trim()).filter(|cell| !cell.is_empty()) else { continue };
+        let wanted = match match_on {
+            MatchOn::Path => wanted.to_string(),
+            MatchOn::Hash => wanted.to_lowercase(),
+        };
+        let photos: Vec<(i64, String)> = stmt.query_map([&wanted], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<_, _>>()?;
+        if photos.is_empty() {
+            merged.unmatched.push(wanted);
+            continue;
+        }
+        for (id, path) in photos {
+            let at = match states.iter().position(|(known, ..)| *known == id) {
+                Some(at) => at,
+                None => {
+                    let state = State::load(conn, id)?;
+                    states.push((id, path, state.clone(), state));
+                    states.len() - 1
+                }
+            };
+            for (i, column) in &columns {
+                let Some(value) = row.get(*i).map(|cell| cell.trim()).filter(|cell| !cell.is_empty()) else { continue };
+                states[at].3.merge(conn, column, value).map_err(|e| {
+                    // The header being row 1
+                    let message = format!("row {}, column {}: {:#}", number + 2, header[*i].trim(), e);
+                    CliError::new(ErrorKind::Config, message)
+                })?;
+            }
+        }
+    }
+    drop(stmt);
+
+    let tx = conn.unchecked_transaction()?;
+    let editor = Editor { source: "import", seen: None };
+    for (id, path, before, after) in &states {
+        let diff = before.diff(after);
+        if diff.is_empty() {
+            continue;
+        }
+        merged.photos += 1;
+        merged.changes.extend(diff.into_iter().map(|(what, before, after)| Change { path: path.clone(), what, before, after }));
+        if dry_run {
+            continue;
+        }
+        if after.keywords != before.keywords {
+            let (version, description): (i64, Option<String>) =
+                tx.query_row("SELECT version, description FROM images WHERE id = ?1", [id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            tags::set(&tx, *id, &after.keywords)?;
+            let previous = Caption { description: description.clone(), keywords: Some(before.keywords.clone()) };
+            edits::log(&tx, *id, &editor, version, &previous, &Caption { description, keywords: Some(after.keywords.clone()) })?;
+        }
+        tx.execute(
+            "UPDATE images SET rating = ?1, favorite = ?2, rejected = ?3, creation_date = ?4 WHERE id = ?5",
+            params![after.rating, after.favorite, after.rejected, after.date, id],
+        )?;
+        for (key, value) in after.fields.iter().filter(|(key, value)| before.fields.get(*key) != Some(*value)) {
+            fields::write(&tx, &[*id], key, value, None)?;
+        }
+    }
+    tx.commit()?;
+    Ok(merged)
+}
+
+/// The records of a CSV file (RFC 4180, though with LF line ends too), a
+/// list of fields each, blank lines left out.
+fn records(text: &str) -> Result<Vec<Vec<String>>, Error> {
+    // As Excel starts UTF-8 files
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let (mut records, mut record, mut field) = (Vec::new(), Vec::new(), String::new());
+    let (mut quoted, mut line) = (false, 1);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => record.push(std::mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                line += 1;
+                record.push(std::mem::take(&mut field));
+                let record = std::mem::take(&mut record);
+                if record.iter().any(|field| !field.is_empty()) {
+                    records.push(record);
+                }
+            }
+            (_, c) => {
+                line += usize::from(c == '\n');
+                field.push(c);
+            }
+        }
+    }
+    if quoted {
+        bail!("a quoted field in the CSV file isn't closed (by line {})", line);
+    }
+    record.push(field);
+    if record.iter().any(|field| !field.is_empty()) {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records() -> Result<(), Error> {
+        let text = "\u{feff}path,keywords\r\n/a.jpg,\"tram, night\"\r\n\r\n\"/b \"\"1\"\".jpg\",\"line one\nline two\"\n/c.jpg,";
+        assert_eq!(records(text)?, vec![
+            vec!["path", "keywords"],
+            vec!["/a.jpg", "tram, night"],
+            vec!["/b \"1\".jpg", "line one\nline two"],
+            vec!["/c.jpg", ""],
+        ]);
+        assert!(records("path\n\"/a.jpg").is_err());
+        assert_eq!(parse_date("2023-07-14T18:30")?, "2023-07-14 18:30:00");
+        assert_eq!(parse_date("2023:07:14 18:30:05")?, "2023-07-14 18:30:05");
+        assert!(parse_date("14/07/2023").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute_batch(
+            "INSERT INTO images (id, path, file_name, file_size, content_hash, rating, creation_date) VALUES
+                 (1, '/a.jpg', 'a.jpg', 1, 'aaa', 3, '2023-07-14 10:00:00'),
+                 (2, '/b.jpg', 'b.jpg', 1, 'bbb', 0, NULL),
+                 (3, '/copy/b.jpg', 'b.jpg', 1, 'bbb', 0, NULL);",
+        )?;
+        crate::test_support::set_keywords(&conn, &[("/a.jpg", "tram")]);
+        fields::set(&conn, &[1], "invoice", "980", Some(fields::Kind::Number))?;
+        let csv = "path,file_name,Keywords,rating,favorite,creation_date,field:Client,field:invoice\r\n\
+                   /a.jpg,a.jpg,\"Tram, night\",5,yes,2023-07-14 11:00:00,Smith,\r\n\
+                   /b.jpg,b.jpg,,,,,,1042\r\n\
+                   /gone.jpg,,,,,,,\r\n";
+
+        let planned = merge(&conn, csv, MatchOn::Path, true)?;
+        let what: Vec<(&str, &str, &str, &str)> = planned.changes.iter()
+            .map(|change| (change.path.as_str(), change.what.as_str(), change.before.as_str(), change.after.as_str()))
+            .collect();
+        assert_eq!(what, vec![
+            ("/a.jpg", "keywords", "tram", "tram, night"),
+            ("/a.jpg", "rating", "3", "5"),
+            ("/a.jpg", "favorite", "no", "yes"),
+            ("/a.jpg", "creation_date", "2023-07-14 10:00:00", "2023-07-14 11:00:00"),
+            ("/a.jpg", "field:Client", "", "Smith"),
+            ("/b.jpg", "field:invoice", "", "1042"),
+        ]);
+        assert_eq!((planned.photos, planned.unmatched.clone(), planned.ignored_columns.clone()), (2, vec![String::from("/gone.jpg")], vec![String::from("file_name")]));
+        assert_eq!(State::load(&conn, 1)?.rating, 3);
+
+        assert_eq!(merge(&conn, csv, MatchOn::Path, false)?, planned);
+        let a = State::load(&conn, 1)?;
+        assert_eq!((a.keywords.as_str(), a.rating, a.favorite, a.date.as_deref()), ("tram, night", 5, true, Some("2023-07-14 11:00:00")));
+        assert_eq!(fields::of(&conn, 1)?, vec![(String::from("Client"), String::from("Smith")), (String::from("invoice"), String::from("980"))]);
+        let source: String = conn.query_row("SELECT source FROM edits WHERE image_id = 1", [], |row| row.get(0))?;
+        assert_eq!(source, "import");
+        // Again, there's nothing left to change
+        assert!(merge(&conn, csv, MatchOn::Path, false)?.changes.is_empty());
+
+        // By hash, every copy
+        let by_hash = merge(&conn, "content_hash,rejected\nBBB,yes\n", MatchOn::Hash, false)?;
+        assert_eq!(by_hash.photos, 2);
+        assert!(State::load(&conn, 3)?.rejected);
+
+        // A bad cell changes nothing
+        let error = merge(&conn, "path,rating,field:invoice\n/a.jpg,1,\n/b.jpg,2,lots\n", MatchOn::Path, false).unwrap_err();
+        assert_eq!(error.to_string(), "row 3, column field:invoice: field invoice: \"lots\" isn't a number");
+        assert_eq!(State::load(&conn, 1)?.rating, 5);
+        assert!(merge(&conn, "file_name,rating\na.jpg,1\n", MatchOn::Path, false).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! What's in the catalog: how many photos and how much space, broken down
+//! by when they were taken, format, camera and folder, the keywords used
+//! most, and how many lack a capture time, camera, location or AI
+//! description. Files `prune --archive` flagged as gone aren't counted.
+
+use std::collections::HashMap;
+use std::path::Path;
+use anyhow::Error;
+use rusqlite::Connection;
+use serde::Serialize;
+
+/// Photos and bytes under one name: a year, a format, a folder...
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Count {
+    pub name: String,
+    pub images: i64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeywordCount {
+    pub keyword: String,
+    pub images: i64,
+}
+
+/// How many photos lack each.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Missing {
+    pub capture_date: i64,
+    pub camera: i64,
+    pub location: i64,
+    pub description: i64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    pub images: i64,
+    pub bytes: u64,
+    pub by_year: Vec<Count>,
+    pub by_month: Vec<Count>,
+    /// The most used, as many as asked for
+    pub by_format: Vec<Count>,
+    pub by_camera: Vec<Count>,
+    pub by_folder: Vec<Count>,
+    pub top_keywords: Vec<KeywordCount>,
+    pub missing: Missing,
+}
+
+/// Gather the stats, keeping the `top` formats, cameras, folders and
+/// keywords.
+pub fn gather(conn: &Connection, top: usize) -> Result<Stats, Error> {
+    let (images, bytes) = conn.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(file_size), 0) FROM images WHERE archived_at IS NULL", [], |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let missing = conn.query_row(
+        "SELECT COALESCE(SUM(creation_date IS NULL), 0), COALESCE(SUM(camera_make IS NULL AND camera_model IS NULL), 0),
+                COALESCE(SUM(latitude IS NULL), 0), COALESCE(SUM(description IS NULL), 0)
+         FROM images WHERE archived_at IS NULL",
+        [],
+        |row| Ok(Missing { capture_date: row.get(0)?, camera: row.get(1)?, location: row.get(2)?, description: row.get(3)? }),
+    )?;
+
+    let mut folders: HashMap<String, (i64, u64)> = HashMap::new();
+    let mut stmt = conn.prepare("SELECT path, file_size FROM images WHERE archived_at IS NULL")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let path: String = row.get(0)?;
+        let folder = Path::new(&path).parent().map(|parent| parent.to_string_lossy().into_owned()).unwrap_or_default();
+        let entry = folders.entry(folder).or_default();
+        entry.0 += 1;
+        entry.1 += row.get::<_, u64>(1)?;
+    }
+    let mut by_folder: Vec<Count> = folders.into_iter().map(|(name, (images, bytes))| Count { name, images, bytes }).collect();
+    by_folder.sort_by(|a, b| b.images.cmp(&a.images).then_with(|| a.name.cmp(&b.name)));
+    by_folder.truncate(top);
+
+    let top_keywords = keyword_counts(conn)?.into_iter().take(top).map(|(keyword, images)| KeywordCount { keyword, images }).collect();
+    Ok(Stats {
+        images,
+        bytes,
+        by_year: group(conn, "substr(creation_date, 1, 4)", false, None)?,
+        by_month: group(conn, "substr(creation_date, 1, 7)", false, None)?,
+        by_format: group(conn, "format", true, Some(top))?,
+        by_camera: group(conn, "camera_model", true, Some(top))?,
+        by_folder,
+        top_keywords,
+        missing,
+    })
+}
+
+/// Photos and bytes by `expr`, those it's NULL for as "unknown": most
+/// first (up to `limit`) if `by_count`, else in order.
+fn group(conn: &Connection, expr: &str, by_count: bool, limit: Option<usize>) -> Result<Vec<Count>, Error> {
+    let order = if by_count { "2 DESC, 1" } else { "1" };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT COALESCE({}, 'unknown'), COUNT(*), COALESCE(SUM(file_size), 0) FROM images WHERE archived_at IS NULL
+         GROUP BY 1 ORDER BY {} LIMIT {}",
+        expr, order, limit.map_or(-1, |limit| limit as i64),
+    ))?;
+    let counts = stmt.query_map([], |row| Ok(Count { name: row.get(0)?, images: row.get(1)?, bytes: row.get(2)? }))?
+        .collect::<Result<_, _>>()?;
+    Ok(counts)
+}
+
+/// Every keyword in the catalog, AI-generated or embedded, with how many
+/// photos have it, most used first.
+pub fn keyword_counts(conn: &Connection) -> Result<Vec<(String, i64)>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT keyword, COUNT(DISTINCT image_id) FROM (
+             SELECT tags.name AS keyword, image_tags.image_id FROM image_tags JOIN tags ON tags.id = image_tags.tag_id
+             UNION ALL SELECT value, image_id FROM external_metadata WHERE field = 'keyword'
+         )
+         GROUP BY keyword ORDER BY 2 DESC, 1",
+    )?;
+    let counts = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect::<Result<_, _>>()?;
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gather() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute_batch(
+            "INSERT INTO images (path, file_name, file_size, format, creation_date, camera_model, latitude, description, archived_at) VALUES
+                 ('/photos/2023/a.jpg', 'a.jpg', 100, 'Jpeg', '2023-07-01 10:00:00', 'X100V', 38.7, 'Sand', NULL),
+                 ('/photos/2023/b.jpg', 'b.jpg', 200, 'Jpeg', '2023-08-02 10:00:00', 'X100V', NULL, NULL, NULL),
+                 ('/photos/scans/c.tif', 'c.tif', 1000, 'Tiff', NULL, NULL, NULL, NULL, NULL),
+                 ('/photos/gone.jpg', 'gone.jpg', 5000, 'Jpeg', '2020-01-01 10:00:00', 'X100V', NULL, NULL, '2024-01-01');
+             INSERT INTO external_metadata (image_id, source, field, value) VALUES (3, 'iptc', 'keyword', 'grandma');",
+        )?;
+        crate::test_support::set_keywords(&conn, &[("/photos/2023/a.jpg", "beach, sea"), ("/photos/2023/b.jpg", "beach")]);
+
+        let stats = gather(&conn, 1)?;
+        assert_eq!((stats.images, stats.bytes), (3, 1300));
+        let names = |counts: &[Count]| counts.iter().map(|count| (count.name.clone(), count.images)).collect::<Vec<_>>();
+        assert_eq!(names(&stats.by_year), vec![(String::from("2023"), 2), (String::from("unknown"), 1)]);
+        assert_eq!(names(&stats.by_month)[..2], [(String::from("2023-07"), 1), (String::from("2023-08"), 1)]);
+        assert_eq!(names(&stats.by_format), vec![(String::from("Jpeg"), 2)]);
+        assert_eq!(stats.by_format[0].bytes, 300);
+        assert_eq!(names(&stats.by_camera), vec![(String::from("X100V"), 2)]);
+        assert_eq!(names(&stats.by_folder), vec![(String::from("/photos/2023"), 2)]);
+        assert_eq!(stats.top_keywords, vec![KeywordCount { keyword: String::from("beach"), images: 2 }]);
+        assert_eq!(stats.missing, Missing { capture_date: 1, camera: 1, location: 2, description: 2 });
+
+        assert_eq!(keyword_counts(&conn)?, vec![(String::from("beach"), 2), (String::from("grandma"), 1), (String::from("sea"), 1)]);
+        Ok(())
+    }
+}
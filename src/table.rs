@@ -0,0 +1,186 @@
+//! Tables for the commands that list things (`search`, `tag list`, `album
+//! list`, `audit show`): a header and aligned columns, numbers on the
+//! right. `--columns` picks which and in what order, `--sort` orders the
+//! rows by one, `--limit` keeps the first few and `--no-header` leaves
+//! the header out, for scripts: `search --columns path --no-header` is
+//! one path per line.
+
+use std::cmp::Ordering;
+use anyhow::Error;
+use clap::Args;
+use crate::error::{CliError, ErrorKind};
+use crate::tiering;
+
+#[derive(Args, Debug, Default)]
+pub struct TableArgs {
+    /// Which columns to show, in order, e.g. "path,date,rating"
+    #[arg(long, value_delimiter = ',')]
+    pub columns: Vec<String>,
+    /// Sort the rows by this column; "-rating" for the highest first
+    #[arg(long, value_name = "COLUMN")]
+    pub sort: Option<String>,
+    /// Only the first this many rows
+    #[arg(long)]
+    pub limit: Option<usize>,
+    /// Leave out the header
+    #[arg(long)]
+    pub no_header: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Cell {
+    Text(String),
+    Number(i64),
+    /// Bytes, shown as e.g. "1.5 MB"
+    Size(u64),
+    Empty,
+}
+
+impl Cell {
+    fn show(&self) -> String {
+        match self {
+            Cell::Text(text) => text.clone(),
+            Cell::Number(number) => number.to_string(),
+            Cell::Size(bytes) => tiering::size(*bytes),
+            Cell::Empty => String::new(),
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, Cell::Number(_) | Cell::Size(_))
+    }
+}
+
+impl From<Option<String>> for Cell {
+    fn from(text: Option<String>) -> Cell {
+        match text {
+            Some(text) if !text.is_empty() => Cell::Text(text),
+            _ => Cell::Empty,
+        }
+    }
+}
+
+/// Rows with a cell for each of `columns`, before `TableArgs` pick from
+/// them.
+pub struct Table {
+    columns: &'static [&'static str],
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    pub fn new(columns: &'static [&'static str]) -> Table {
+        Table { columns, rows: Vec::new() }
+    }
+
+    pub fn push(&mut self, row: Vec<Cell>) {
+        debug_assert_eq!(row.len(), self.columns.len());
+        self.rows.push(row);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The table as `args` asks for it, with the columns in `default`
+    /// unless it names others. Rows sort with empty cells last either way.
+    pub fn render(mut self, args: &TableArgs, default: &[&str]) -> Result<String, Error> {
+        let names: Vec<&str> = match args.columns.is_empty() {
+            true => default.to_vec(),
+            false => args.columns.iter().map(|name| name.trim()).collect(),
+        };
+        let shown = names.iter().map(|name| self.column(name)).collect::<Result<Vec<_>, _>>()?;
+        if let Some(sort) = &args.sort {
+            let (name, descending) = match sort.strip_prefix('-') {
+                Some(name) => (name, true),
+                None => (sort.as_str(), false),
+            };
+            let column = self.column(name)?;
+            self.rows.sort_by(|a, b| match (&a[column], &b[column]) {
+                (Cell::Empty, Cell::Empty) => Ordering::Equal,
+                (Cell::Empty, _) => Ordering::Greater,
+                (_, Cell::Empty) => Ordering::Less,
+                (a, b) if descending => b.cmp(a),
+                (a, b) => a.cmp(b),
+            });
+        }
+        self.rows.truncate(args.limit.unwrap_or(usize::MAX));
+
+        let header: Vec<String> = shown.iter().map(|&column| heading(self.columns[column])).collect();
+        let cells: Vec<Vec<String>> = self.rows.iter().map(|row| shown.iter().map(|&column| row[column].show()).collect()).collect();
+        let lines = (!args.no_header).then_some(&header).into_iter().chain(&cells);
+        let widths: Vec<usize> = (0..shown.len())
+            .map(|i| lines.clone().map(|line| line[i].chars().count()).max().unwrap_or_default())
+            .collect();
+        let right: Vec<bool> = shown.iter().map(|&column| self.rows.iter().any(|row| row[column].is_numeric())).collect();
+
+        let mut out = String::new();
+        for line in lines {
+            let mut text = String::new();
+            for (i, cell) in line.iter().enumerate() {
+                if i > 0 {
+                    text.push_str("  ");
+                }
+                let padding = " ".repeat(widths[i] - cell.chars().count());
+                match right[i] {
+                    true => text.push_str(&(padding + cell)),
+                    false => text.push_str(&(cell.clone() + &padding)),
+                }
+            }
+            out.push_str(text.trim_end());
+            out.push('\n');
+        }
+        Ok(out)
+    }
+
+    pub fn print(self, args: &TableArgs, default: &[&str]) -> Result<(), Error> {
+        print!("{}", self.render(args, default)?);
+        Ok(())
+    }
+
+    fn column(&self, name: &str) -> Result<usize, Error> {
+        self.columns.iter().position(|column| column.eq_ignore_ascii_case(name)).ok_or_else(|| {
+            let message = format!("no column {:?}; there are {}", name, self.columns.join(", "));
+            CliError::new(ErrorKind::Config, message).into()
+        })
+    }
+}
+
+/// "place" as a header, "Place".
+fn heading(column: &str) -> String {
+    let mut chars = column.chars();
+    chars.next().map(|first| first.to_uppercase().chain(chars).collect()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> Table {
+        let mut table = Table::new(&["path", "rating", "size", "place"]);
+        table.push(vec![Cell::Text(String::from("/b.jpg")), Cell::Number(4), Cell::Size(2048), Cell::Empty]);
+        table.push(vec![Cell::Text(String::from("/a.jpg")), Cell::Empty, Cell::Size(10), Cell::Text(String::from("Lisbon"))]);
+        table.push(vec![Cell::Text(String::from("/c.jpg")), Cell::Number(5), Cell::Size(3), Cell::Text(String::from("Porto"))]);
+        table
+    }
+
+    #[test]
+    fn test_render() -> Result<(), Error> {
+        let args = TableArgs::default();
+        assert_eq!(
+            table().render(&args, &["path", "rating", "place"])?,
+            "Path    Rating  Place\n/b.jpg       4\n/a.jpg          Lisbon\n/c.jpg       5  Porto\n",
+        );
+
+        let args = TableArgs { columns: vec![String::from("Path"), String::from("size")], sort: Some(String::from("-rating")), ..TableArgs::default() };
+        assert_eq!(table().render(&args, &[])?, "Path        Size\n/c.jpg   3 bytes\n/b.jpg    2.0 KB\n/a.jpg  10 bytes\n");
+
+        let args = TableArgs { columns: vec![String::from("path")], sort: Some(String::from("path")), limit: Some(2), no_header: true };
+        assert_eq!(table().render(&args, &[])?, "/a.jpg\n/b.jpg\n");
+
+        let args = TableArgs { columns: vec![String::from("camera")], ..TableArgs::default() };
+        assert!(table().render(&args, &[]).is_err());
+        let args = TableArgs { sort: Some(String::from("-camera")), ..TableArgs::default() };
+        assert!(table().render(&args, &["path"]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+//! The catalog's keywords: one row per keyword in `tags`, whatever its
+//! case, and an `image_tags` row per photo it's on, in the order it was
+//! written. Photos' keywords are still read and written as one list,
+//! "beach, sea", split as `keywords::split` does.
+//!
+//! `tag` changes them in bulk: adding one to the photos a query matches,
+//! or removing, renaming or merging one wherever it's used, e.g. `dog`
+//! into `dogs` across the catalog. Each photo changed is an edit like any
+//! other (see `edits`), so it counts up the photo's version and is logged
+//! with the keywords it had. Only the catalog's keywords change; those
+//! imported from the files' own metadata stay as the files have them.
+
+use anyhow::Error;
+use rusqlite::{params, Connection};
+use crate::edits::{self, Caption, Editor};
+use crate::{keywords, SearchFilter};
+
+/// An `images` row's keywords as a list, or NULL if it has none, for
+/// selecting alongside its columns: `SELECT path, {KEYWORDS} FROM images`.
+pub const KEYWORDS: &str = "(SELECT group_concat(name, ', ') FROM (
+    SELECT tags.name FROM image_tags JOIN tags ON tags.id = image_tags.tag_id
+    WHERE image_tags.image_id = images.id ORDER BY image_tags.position))";
+
+/// Replace image `id`'s keywords with those in the list `keywords`. A
+/// keyword no photo has any more goes.
+pub fn set(conn: &Connection, id: i64, keywords: &str) -> rusqlite::Result<()> {
+    let previous: Vec<i64> = conn.prepare_cached("SELECT tag_id FROM image_tags WHERE image_id = ?1")?
+        .query_map([id], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    conn.prepare_cached("DELETE FROM image_tags WHERE image_id = ?1")?.execute([id])?;
+    // Keeping the spelling the keyword was first given
+    let mut tag = conn.prepare_cached("INSERT INTO tags (name) VALUES (?1) ON CONFLICT (name) DO UPDATE SET name = name RETURNING id")?;
+    let mut link = conn.prepare_cached("INSERT INTO image_tags (image_id, tag_id, position) VALUES (?1, ?2, ?3)")?;
+    for (position, name) in keywords::split(keywords).iter().enumerate() {
+        let tag_id: i64 = tag.query_row([name], |row| row.get(0))?;
+        link.execute(params![id, tag_id, position])?;
+    }
+    let mut unused = conn.prepare_cached("DELETE FROM tags WHERE id = ?1 AND NOT EXISTS (SELECT 1 FROM image_tags WHERE tag_id = ?1)")?;
+    for tag_id in previous {
+        unused.execute([tag_id])?;
+    }
+    Ok(())
+}
+
+/// Drop the keywords no photo has, e.g. after removing photos.
+pub fn drop_unused(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.execute("DELETE FROM tags WHERE NOT EXISTS (SELECT 1 FROM image_tags WHERE tag_id = tags.id)", [])
+}
+
+/// A keyword and how many photos have it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Usage {
+    pub name: String,
+    pub images: i64,
+}
+
+/// Every keyword, by name.
+pub fn list(conn: &Connection) -> rusqlite::Result<Vec<Usage>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, COUNT(image_tags.image_id) FROM tags JOIN image_tags ON image_tags.tag_id = tags.id GROUP BY tags.id ORDER BY name",
+    )?;
+    let usages = stmt.query_map([], |row| Ok(Usage { name: row.get(0)?, images: row.get(1)? }))?.collect();
+    usages
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Add(String),
+    Remove(String),
+    /// Every one of `from` becomes `to`; more than one merges them.
+    Rename { from: Vec<String>, to: String },
+}
+
+impl Change {
+    /// The keyword list with the change made, or `None` if it has nothing
+    /// to change.
+    fn apply(&self, keywords: &str) -> Option<String> {
+        let before = keywords::split(keywords);
+        let is = |keyword: &str, tag: &str| keyword.eq_ignore_ascii_case(tag.trim());
+        let after: Vec<String> = match self {
+            Change::Add(tag) if before.iter().any(|keyword| is(keyword, tag)) => return None,
+            Change::Add(tag) => before.iter().cloned().chain([tag.trim().to_string()]).collect(),
+            Change::Remove(tag) => before.iter().filter(|keyword| !is(keyword, tag)).cloned().collect(),
+            Change::Rename { from, to } => {
+                let mut after: Vec<String> = Vec::new();
+                for keyword in &before {
+                    let keyword = match from.iter().any(|tag| is(keyword, tag)) {
+                        true => to.trim(),
+                        false => keyword,
+                    };
+                    if !after.iter().any(|kept| is(kept, keyword)) {
+                        after.push(keyword.to_string());
+                    }
+                }
+                after
+            }
+        };
+        (after != before).then(|| after.join(", "))
+    }
+}
+
+/// A photo whose keywords changed, or would.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retagged {
+    pub path: String,
+    pub before: String,
+    pub after: String,
+}
+
+/// Make `change` to the photos matching `filter`, or with `dry_run`, only
+/// say what it would change.
+pub fn retag(conn: &Connection, filter: &SearchFilter, change: &Change, dry_run: bool) -> Result<Vec<Retagged>, Error> {
+    let tx = conn.unchecked_transaction()?;
+    let (clause, params) = crate::filter_clause(filter);
+    let mut stmt = tx.prepare(&format!("SELECT id, path, version, description, {} FROM images{} ORDER BY path", KEYWORDS, clause))?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?, row.get::<_, Option<String>>(3)?, row.get::<_, Option<String>>(4)?))
+    })?.collect::<Result<Vec<_>, _>>()?;
+    drop(stmt);
+
+    let editor = Editor { source: "tag", seen: None };
+    let mut retagged = Vec::new();
+    for (id, path, version, description, keywords) in rows {
+        let before = keywords.unwrap_or_default();
+        let Some(after) = change.apply(&before) else { continue };
+        if !dry_run {
+            set(&tx, id, &after)?;
+            let previous = Caption { description: description.clone(), keywords: Some(before.clone()) };
+            edits::log(&tx, id, &editor, version, &previous, &Caption { description, keywords: Some(after.clone()) })?;
+        }
+        retagged.push(Retagged { path, before, after });
+    }
+    tx.commit()?;
+    Ok(retagged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let rename = |from: &[&str], to: &str| Change::Rename { from: from.iter().map(|tag| tag.to_string()).collect(), to: to.to_string() };
+        assert_eq!(Change::Add(String::from("Lisbon")).apply("tram, city"), Some(String::from("tram, city, Lisbon")));
+        assert_eq!(Change::Add(String::from("lisbon")).apply("Lisbon"), None);
+        assert_eq!(Change::Add(String::from("tram")).apply(""), Some(String::from("tram")));
+        assert_eq!(Change::Remove(String::from("City")).apply("tram, city"), Some(String::from("tram")));
+        assert_eq!(Change::Remove(String::from("boat")).apply("tram, city"), None);
+        assert_eq!(rename(&["dog"], "dogs").apply("beach, Dog, sea"), Some(String::from("beach, dogs, sea")));
+        assert_eq!(rename(&["dog"], "dogs").apply("hotdog"), None);
+        // Merging into a keyword already there leaves one of it
+        assert_eq!(rename(&["puppy", "dog"], "dogs").apply("dogs, puppy, dog"), Some(String::from("dogs")));
+    }
+
+    #[test]
+    fn test_retag() -> Result<(), Error> {
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute_batch(
+            "INSERT INTO images (path, file_name, file_size, camera_model, description) VALUES
+                 ('/a.jpg', 'a.jpg', 1, 'X100V', 'A dog on the beach'),
+                 ('/b.jpg', 'b.jpg', 1, 'iPhone', NULL),
+                 ('/c.jpg', 'c.jpg', 1, 'X100V', NULL);",
+        )?;
+        crate::test_support::set_keywords(&conn, &[("/a.jpg", "dog, beach"), ("/b.jpg", "dog")]);
+        let keywords = |path: &str| -> rusqlite::Result<Option<String>> {
+            conn.query_row(&format!("SELECT {} FROM images WHERE path = ?1", KEYWORDS), [path], |row| row.get(0))
+        };
+
+        let rename = Change::Rename { from: vec![String::from("dog")], to: String::from("dogs") };
+        let planned = retag(&conn, &SearchFilter::default(), &rename, true)?;
+        assert_eq!(planned.iter().map(|retagged| retagged.path.as_str()).collect::<Vec<_>>(), vec!["/a.jpg", "/b.jpg"]);
+        assert_eq!(keywords("/a.jpg")?.as_deref(), Some("dog, beach"));
+
+        assert_eq!(retag(&conn, &SearchFilter::default(), &rename, false)?, planned);
+        assert_eq!(keywords("/a.jpg")?.as_deref(), Some("dogs, beach"));
+        let (version, source, previous): (i64, String, String) = conn.query_row(
+            "SELECT images.version, source, previous_keywords FROM images JOIN edits ON edits.image_id = images.id WHERE path = '/b.jpg'",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        assert_eq!((version, source.as_str(), previous.as_str()), (1, "tag", "dog"));
+
+        let x100v = SearchFilter { camera: Some(String::from("X100V")), ..SearchFilter::default() };
+        assert_eq!(retag(&conn, &x100v, &Change::Add(String::from("fuji")), false)?.len(), 2);
+        assert_eq!(keywords("/c.jpg")?.as_deref(), Some("fuji"));
+        assert_eq!(keywords("/b.jpg")?.as_deref(), Some("dogs"));
+        let tags: Vec<String> = conn.prepare("SELECT name FROM tags ORDER BY id")?.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+        assert_eq!(tags, vec!["beach", "dogs", "fuji"]);
+        let usages: Vec<(String, i64)> = list(&conn)?.into_iter().map(|usage| (usage.name, usage.images)).collect();
+        assert_eq!(usages, vec![(String::from("beach"), 1), (String::from("dogs"), 2), (String::from("fuji"), 2)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! Google Photos Takeout sidecars: next to each photo in a Takeout archive
+//! is a JSON file with what Google Photos knew about it. Often that's more
+//! than the photo itself says, since Google strips location from some
+//! downloads and people add descriptions and name faces in the app.
+//!
+//! The description, the people and whether the photo was a favorite are
+//! kept as metadata from the source `takeout`; the time and place fill in
+//! for the photo's own when it has none.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::Error;
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use crate::{geocode, ExternalMetadata, ImageMetadata};
+
+const SOURCE: &str = "takeout";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Sidecar {
+    description: String,
+    photo_taken_time: Option<Timestamp>,
+    geo_data: Option<GeoData>,
+    geo_data_exif: Option<GeoData>,
+    people: Vec<Person>,
+    favorited: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Timestamp {
+    /// Seconds since the epoch, as a string
+    timestamp: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeoData {
+    latitude: f64,
+    longitude: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Person {
+    name: String,
+}
+
+impl GeoData {
+    /// Takeout writes 0, 0 for "no location".
+    fn coordinates(&self) -> Option<(f64, f64)> {
+        (self.latitude != 0.0 || self.longitude != 0.0).then_some((self.latitude, self.longitude))
+    }
+}
+
+/// The sidecar for `image`: `photo.jpg.json`, or in newer archives
+/// `photo.jpg.supplemental-metadata.json`, either of which Takeout cuts
+/// short when the name gets long. Copies numbered by Takeout, like
+/// `photo(1).jpg`, have theirs at `photo.jpg(1).json`.
+pub fn find_sidecar(image: &Path) -> Option<PathBuf> {
+    let name = image.file_name()?.to_string_lossy().into_owned();
+    let dir = image.parent()?;
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((&name, ""));
+    // "photo(1).jpg" → "photo.jpg(1).json"
+    if let Some((base, number)) = stem.strip_suffix(')').and_then(|stem| stem.rsplit_once('(')) {
+        let numbered = dir.join(format!("{}.{}({}).json", base, extension, number));
+        if numbered.is_file() {
+            return Some(numbered);
+        }
+    }
+    let full = format!("{}.supplemental-metadata", name);
+    for candidate in [format!("{}.json", name), format!("{}.json", full)] {
+        if dir.join(&candidate).is_file() {
+            return Some(dir.join(candidate));
+        }
+    }
+    // Shortened names are a start of the full one
+    fs::read_dir(dir).ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .find(|file| file.strip_suffix(".json").is_some_and(|start| start.len() > name.len() && full.starts_with(start)))
+        .map(|file| dir.join(file))
+}
+
+/// Merge what the Takeout sidecar of the photo says into its metadata, if
+/// it has one.
+pub fn apply(metadata: &mut ImageMetadata) -> Result<(), Error> {
+    let Some(sidecar) = find_sidecar(Path::new(&metadata.path)) else { return Ok(()) };
+    let sidecar: Sidecar = serde_json::from_str(&fs::read_to_string(&sidecar)?)
+        .map_err(|e| anyhow::anyhow!("reading {}: {}", sidecar.display(), e))?;
+
+    let mut entry = |field: &str, value: &str| metadata.external.push(ExternalMetadata {
+        source: SOURCE.to_string(),
+        field: field.to_string(),
+        value: value.to_string(),
+    });
+    if !sidecar.description.trim().is_empty() {
+        entry("caption", sidecar.description.trim());
+    }
+    for person in &sidecar.people {
+        entry("person", &person.name);
+    }
+    if sidecar.favorited {
+        entry("favorite", "true");
+    }
+
+    if metadata.creation_date.is_none() {
+        // In local time, like the dates cameras write
+        metadata.creation_date = sidecar.photo_taken_time
+            .and_then(|time| time.timestamp.parse().ok())
+            .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+            .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string());
+    }
+    if metadata.gps.is_none() {
+        // Where the photo says it was taken before where Google thinks
+        metadata.gps = [sidecar.geo_data_exif, sidecar.geo_data].iter().flatten().find_map(GeoData::coordinates);
+        metadata.place = metadata.gps.and_then(|(latitude, longitude)| geocode::reverse_geocode(latitude, longitude));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_takeout_sidecars() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let photo = dir.path().join("IMG_20230714_184207.jpg");
+        fs::write(&photo, b"")?;
+        assert_eq!(find_sidecar(&photo), None);
+
+        let sidecar = dir.path().join("IMG_20230714_184207.jpg.supplemental-met.json");
+        fs::write(&sidecar, r#"{
+            "title": "IMG_20230714_184207.jpg",
+            "description": "Tram 28 ",
+            "photoTakenTime": { "timestamp": "1689360127", "formatted": "Jul 14, 2023, 6:42:07 PM UTC" },
+            "geoData": { "latitude": 38.7111, "longitude": -9.1302, "altitude": 0.0 },
+            "geoDataExif": { "latitude": 0.0, "longitude": 0.0, "altitude": 0.0 },
+            "people": [{ "name": "Ana" }, { "name": "Rui" }],
+            "favorited": true,
+            "url": "https://photos.google.com/photo/x"
+        }"#)?;
+        assert_eq!(find_sidecar(&photo), Some(sidecar));
+        let copy = dir.path().join("IMG_20230714_184207(1).jpg");
+        fs::write(dir.path().join("IMG_20230714_184207.jpg(1).json"), "{}")?;
+        assert_eq!(find_sidecar(&copy), Some(dir.path().join("IMG_20230714_184207.jpg(1).json")));
+
+        let mut metadata = ImageMetadata { path: photo.to_string_lossy().into_owned(), ..ImageMetadata::default() };
+        apply(&mut metadata)?;
+        let fields: Vec<(&str, &str)> = metadata.external.iter().map(|m| (m.field.as_str(), m.value.as_str())).collect();
+        assert_eq!(fields, vec![("caption", "Tram 28"), ("person", "Ana"), ("person", "Rui"), ("favorite", "true")]);
+        assert_eq!(metadata.gps, Some((38.7111, -9.1302)));
+        let expected = DateTime::from_timestamp(1689360127, 0).unwrap().with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string();
+        assert_eq!(metadata.creation_date, Some(expected));
+
+        // The photo's own date and place win
+        let mut metadata = ImageMetadata {
+            path: photo.to_string_lossy().into_owned(),
+            creation_date: Some(String::from("2023-07-14 19:42:07")),
+            gps: Some((38.0, -9.0)),
+            ..ImageMetadata::default()
+        };
+        apply(&mut metadata)?;
+        assert_eq!((metadata.creation_date.as_deref(), metadata.gps), (Some("2023-07-14 19:42:07"), Some((38.0, -9.0))));
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+//! Fixtures shared by the unit tests of several modules.
+
+use std::io::Cursor;
+use exif::{Field, In, Tag, Value};
+use image::ImageFormat;
+use mockito::{Server, ServerGuard};
+use rusqlite::Connection;
+use crate::analyzer::AnalyzerPool;
+use crate::config::{AnalyzerConfig, HostConfig};
+
+pub const MOCK_RESPONSE: &str = r#"{
+    "model": "llava",
+    "response": "A colorful sunset over mountains\n\nKeywords: sunset, mountains, nature, landscape, evening, colorful"
+}"#;
+
+/// A mock Ollama server that answers every analysis request, plus a pool
+/// pointed at it.
+pub fn mock_ollama() -> (ServerGuard, AnalyzerPool) {
+    let mut server = Server::new();
+    server.mock("POST", "/api/generate")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(MOCK_RESPONSE)
+        .create();
+    let analyzer = AnalyzerPool::new(&AnalyzerConfig {
+        hosts: vec![HostConfig::ollama(&server.url())],
+        ..Default::default()
+    }).unwrap();
+    (server, analyzer)
+}
+
+pub fn field(tag: Tag, value: Value) -> Field {
+    Field { tag, ifd_num: In::PRIMARY, value }
+}
+
+pub fn ascii(s: &str) -> Value {
+    Value::Ascii(vec![s.as_bytes().to_vec()])
+}
+
+pub fn rational(num: u32, denom: u32) -> Value {
+    Value::Rational(vec![exif::Rational::from((num, denom))])
+}
+
+/// The given fields encoded as a big-endian TIFF/EXIF block.
+pub fn exif_tiff(fields: &[Field]) -> Vec<u8> {
+    let mut tiff = Cursor::new(Vec::new());
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    writer.write(&mut tiff, false).unwrap();
+    tiff.into_inner()
+}
+
+/// A small valid JPEG carrying the given EXIF fields in an APP1 segment
+/// (none if there are no fields).
+pub fn jpeg_with_exif(fields: &[Field]) -> Vec<u8> {
+    let mut jpeg = Vec::new();
+    image::DynamicImage::new_rgb8(8, 6)
+        .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+        .unwrap();
+    if fields.is_empty() {
+        return jpeg;
+    }
+
+    let tiff = exif_tiff(fields);
+    let mut app1 = vec![0xFF, 0xE1];
+    app1.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+    app1.extend_from_slice(b"Exif\0\0");
+    app1.extend_from_slice(&tiff);
+    jpeg.splice(2..2, app1);
+    jpeg
+}
+
+/// A minimal TIFF-based RAW file: the given EXIF fields and sensor size in
+/// IFD0, and `preview` referenced from IFD1 the way cameras embed theirs.
+#[cfg(feature = "raw")]
+pub fn raw_with_preview(fields: &[Field], preview: &[u8], (width, height): (u32, u32)) -> Vec<u8> {
+    let size = [
+        field(Tag::ImageWidth, Value::Long(vec![width])),
+        field(Tag::ImageLength, Value::Long(vec![height])),
+    ];
+    let mut tiff = Cursor::new(Vec::new());
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields.iter().chain(&size) {
+        writer.push_field(field);
+    }
+    writer.set_jpeg(preview, In::THUMBNAIL);
+    writer.write(&mut tiff, true).unwrap();
+    tiff.into_inner()
+}
+
+/// A 1x1 lossless WebP in the extended format, carrying `xmp` in its XMP
+/// chunk.
+pub fn webp_with_xmp(xmp: &str) -> Vec<u8> {
+    const VP8L: &[u8] = &[0x2F, 0, 0, 0, 0x10, 0x07, 0x10, 0x11, 0x11, 0x88, 0x88, 0xFE, 0x07];
+    let chunk = |kind: &[u8; 4], contents: &[u8]| {
+        let mut chunk = [&kind[..], &(contents.len() as u32).to_le_bytes(), contents].concat();
+        if contents.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    };
+    // Flags (XMP present), then the canvas size minus one
+    let vp8x = [0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let body = [&b"WEBP"[..], &chunk(b"VP8X", &vp8x), &chunk(b"VP8L", VP8L), &chunk(b"XMP ", xmp.as_bytes())].concat();
+    [&b"RIFF"[..], &(body.len() as u32).to_le_bytes(), &body].concat()
+}
+
+/// A TIFF document of 16-bit RGB pages, each `(width, height, thumbnail)`;
+/// thumbnails are marked as reduced-resolution copies.
+pub fn multi_page_tiff(pages: &[(u32, u32, bool)]) -> Vec<u8> {
+    use ::tiff::encoder::{colortype::RGB16, TiffEncoder};
+    use ::tiff::tags::Tag as TiffTag;
+
+    let mut tiff = Cursor::new(Vec::new());
+    let mut encoder = TiffEncoder::new(&mut tiff).unwrap();
+    for &(width, height, thumbnail) in pages {
+        let samples: Vec<u16> = (0..width * height * 3).map(|i| (i * 257) as u16).collect();
+        let mut page = encoder.new_image::<RGB16>(width, height).unwrap();
+        if thumbnail {
+            page.encoder().write_tag(TiffTag::NewSubfileType, 1u32).unwrap();
+        }
+        page.write_data(&samples).unwrap();
+    }
+    tiff.into_inner()
+}
+
+/// An MP4 with one HEVC video track (after a stereo AAC track), created
+/// 2024-05-01 18:30:00 UTC and optionally carrying an ISO 6709 location.
+/// The `moov` box comes last, after the media data, as cameras write it.
+#[cfg(feature = "video")]
+pub fn mp4(duration_secs: f64, (width, height): (u32, u32), location: Option<&str>) -> Vec<u8> {
+    let make_box = |kind: &[u8; 4], contents: &[u8]| {
+        [&((contents.len() + 8) as u32).to_be_bytes()[..], kind, contents].concat()
+    };
+    const TIMESCALE: u32 = 600;
+    let mvhd = [
+        &[0; 4][..],
+        &3_797_433_000u32.to_be_bytes(),
+        &[0; 4],
+        &TIMESCALE.to_be_bytes(),
+        &((duration_secs * TIMESCALE as f64) as u32).to_be_bytes(),
+        &[0; 80],
+    ].concat();
+    let hdlr = |handler: &[u8; 4]| make_box(b"hdlr", &[&[0; 8][..], handler, &[0; 13]].concat());
+    let stsd = |entry: &[u8]| make_box(b"stsd", &[&[0, 0, 0, 0, 0, 0, 0, 1][..], entry].concat());
+    let mp4a = make_box(b"mp4a", &[&[0; 16][..], &2u16.to_be_bytes(), &[0; 10]].concat());
+    let minf = make_box(b"minf", &make_box(b"stbl", &stsd(&mp4a)));
+    let audio = make_box(b"trak", &[make_box(b"tkhd", &[0; 84]), make_box(b"mdia", &[hdlr(b"soun"), minf].concat())].concat());
+    let tkhd = [&[0; 76][..], &(width << 16).to_be_bytes(), &(height << 16).to_be_bytes()].concat();
+    let minf = make_box(b"minf", &make_box(b"stbl", &stsd(&make_box(b"hvc1", &[0; 78]))));
+    let video = make_box(b"trak", &[make_box(b"tkhd", &tkhd), make_box(b"mdia", &[hdlr(b"vide"), minf].concat())].concat());
+    let udta = location.map(|location| {
+        let xyz = [&(location.len() as u16).to_be_bytes()[..], &[0x15, 0xC7], location.as_bytes()].concat();
+        make_box(b"udta", &make_box(b"\xA9xyz", &xyz))
+    }).unwrap_or_default();
+    let moov = make_box(b"moov", &[make_box(b"mvhd", &mvhd), audio, video, udta].concat());
+    [make_box(b"ftyp", b"qt  \0\0\0\0qt  "), make_box(b"mdat", &[0; 4096]), moov].concat()
+}
+
+/// Apple's Display P3 ICC profile, as far as `icc` reads it: the D50
+/// primaries, sRGB's curve and the name.
+pub fn display_p3_profile() -> Vec<u8> {
+    let fixed = |v: f64| ((v * 65536.0).round() as i32).to_be_bytes();
+    let xyz = |[x, y, z]: [f64; 3]| [&b"XYZ \0\0\0\0"[..], &fixed(x), &fixed(y), &fixed(z)].concat();
+    let curve = [&b"para\0\0\0\0\0\x03\0\0"[..], &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045].map(fixed).concat()].concat();
+    let name = b"Display P3\0";
+    let desc = [&b"desc\0\0\0\0"[..], &(name.len() as u32).to_be_bytes(), name].concat();
+    let tags: [(&[u8; 4], Vec<u8>); 5] = [
+        (b"rXYZ", xyz([0.515102, 0.241196, -0.001053])),
+        (b"gXYZ", xyz([0.291965, 0.692246, 0.041876])),
+        (b"bXYZ", xyz([0.157153, 0.066574, 0.784265])),
+        (b"rTRC", curve),
+        (b"desc", desc),
+    ];
+    // The three curves are one tag, as in Apple's profile
+    let entries: Vec<(&[u8; 4], usize)> = tags.iter().enumerate().map(|(i, (signature, _))| (*signature, i))
+        .chain([(b"gTRC", 3), (b"bTRC", 3)])
+        .collect();
+    let mut offsets = Vec::new();
+    let mut data = vec![0; 132 + entries.len() * 12];
+    for (_, contents) in &tags {
+        offsets.push((data.len(), contents.len()));
+        data.extend_from_slice(contents);
+    }
+    data[16..20].copy_from_slice(b"RGB ");
+    data[20..24].copy_from_slice(b"XYZ ");
+    data[36..40].copy_from_slice(b"acsp");
+    data[128..132].copy_from_slice(&(entries.len() as u32).to_be_bytes());
+    for (i, (signature, tag)) in entries.iter().enumerate() {
+        let (offset, len) = offsets[*tag];
+        let entry = [&signature[..], &(offset as u32).to_be_bytes(), &(len as u32).to_be_bytes()].concat();
+        data[132 + i * 12..144 + i * 12].copy_from_slice(&entry);
+    }
+    let len = data.len() as u32;
+    data[..4].copy_from_slice(&len.to_be_bytes());
+    data
+}
+
+/// An executable shell script in `dir` running `body`, to stand in for an
+/// external tool; returns its path.
+#[cfg(unix)]
+pub fn fake_tool(dir: &std::path::Path, body: &str) -> Result<String, anyhow::Error> {
+    use std::os::unix::fs::PermissionsExt;
+    let path = dir.join("fake-tool");
+    std::fs::write(&path, format!("#!/bin/sh\n{}\n", body))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// A 4x3 animated GIF with a frame of each color, `delay` hundredths of a
+/// second apart, repeating `repeat` times (0 for forever) if given.
+pub fn animated_gif(colors: &[[u8; 3]], delay: u16, repeat: Option<u16>) -> Vec<u8> {
+    use image::codecs::gif::{GifEncoder, Repeat};
+    use image::{Delay, Frame, Rgba, RgbaImage};
+
+    let mut gif = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut gif);
+        if let Some(repeat) = repeat {
+            encoder.set_repeat(if repeat == 0 { Repeat::Infinite } else { Repeat::Finite(repeat) }).unwrap();
+        }
+        for &[r, g, b] in colors {
+            let buffer = RgbaImage::from_pixel(4, 3, Rgba([r, g, b, 255]));
+            encoder.encode_frame(Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(delay as u32 * 10, 1))).unwrap();
+        }
+    }
+    gif
+}
+
+/// A 4x3 APNG with a frame of each color, half a second apiece, playing
+/// `plays` times (0 for forever).
+pub fn animated_png(colors: &[[u8; 3]], plays: u32) -> Vec<u8> {
+    let mut apng = Vec::new();
+    let mut encoder = png::Encoder::new(&mut apng, 4, 3);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_animated(colors.len() as u32, plays).unwrap();
+    encoder.set_frame_delay(1, 2).unwrap();
+    let mut writer = encoder.write_header().unwrap();
+    for color in colors {
+        writer.write_image_data(&color.repeat(12)).unwrap();
+    }
+    writer.finish().unwrap();
+    apng
+}
+
+/// Give the catalogued photos at these paths keywords, as analyzing them
+/// would.
+pub fn set_keywords(conn: &Connection, keywords: &[(&str, &str)]) {
+    for (path, list) in keywords {
+        let id: i64 = conn.query_row("SELECT id FROM images WHERE path = ?1", [path], |row| row.get(0)).unwrap();
+        crate::tags::set(conn, id, list).unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+//! Storage tiering: which files could go to cold storage and which could be
+//! deleted, going by how they're used. Every search and every image looked
+//! at through the API counts as an access (kept in `image_access`), so
+//! photos nobody has gone looking for in `unused_days` stand out; those
+//! that are also rated low or are huge are worth archiving. Exact copies of
+//! another catalogued file, and photos rejected (in the catalog or in
+//! Lightroom), are worth deleting; of a set of copies the most used is
+//! kept.
+//!
+//! `organize --archive-to` carries the recommendations out, moving files
+//! (with their XMP sidecars) rather than deleting anything: those to
+//! delete go under `to-delete/`, to look over first.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use anyhow::Error;
+use rusqlite::{params, params_from_iter, Connection};
+use serde::Deserialize;
+use crate::error::{CliError, ErrorKind};
+use crate::{xmp, SearchFilter};
+
+/// Where deletion candidates go, under the archive.
+pub const TO_DELETE: &str = "to-delete";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TieringConfig {
+    /// Photos rated this or lower (in the catalog, XMP or Lightroom) count
+    /// as low rated.
+    pub low_rating: u32,
+    /// Files of this many megabytes or more count as huge.
+    pub huge_mb: u64,
+    /// Photos not searched for or looked at in this many days count as
+    /// unused. Accesses are counted from when the catalog started keeping
+    /// them, so nothing is unused until it has for this long.
+    pub unused_days: u32,
+}
+
+impl Default for TieringConfig {
+    fn default() -> Self {
+        TieringConfig { low_rating: 2, huge_mb: 50, unused_days: 365 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Move to cold storage
+    Archive,
+    Delete,
+}
+
+/// A file that could be moved off the main library.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub id: i64,
+    pub path: String,
+    pub file_size: u64,
+    pub action: Action,
+    /// Why, e.g. "rated 1" or "a copy of /photos/a.jpg"
+    pub reasons: Vec<String>,
+}
+
+/// Count an access to each of `ids`.
+pub fn record(conn: &Connection, ids: &[i64]) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO image_access (image_id, hits, last_accessed) VALUES (?1, 1, datetime('now'))
+         ON CONFLICT (image_id) DO UPDATE SET hits = hits + 1, last_accessed = excluded.last_accessed",
+    )?;
+    for id in ids {
+        stmt.execute([id])?;
+    }
+    Ok(())
+}
+
+/// Count an access to each image matching `filter`.
+pub fn record_matches(conn: &Connection, filter: &SearchFilter) -> rusqlite::Result<()> {
+    let (clause, params) = crate::filter_clause(filter);
+    let ids: Vec<i64> = conn.prepare(&format!("SELECT id FROM images{}", clause))?
+        .query_map(params_from_iter(params), |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    record(conn, &ids)
+}
+
+/// What could be archived or deleted, in catalog order.
+pub fn recommend(conn: &Connection, config: &TieringConfig) -> Result<Vec<Candidate>, Error> {
+    let since = format!("-{} days", config.unused_days);
+    let mut stmt = conn.prepare(
+        "SELECT i.id, i.path, i.file_size, i.content_hash, COALESCE(a.hits, 0),
+                -- The catalog's own rating, when it has one, over those imported
+                COALESCE(NULLIF(i.rating, 0), (SELECT MAX(CAST(value AS INTEGER)) FROM external_metadata e
+                 WHERE e.image_id = i.id AND e.source IN ('xmp', 'lightroom') AND e.field = 'rating')),
+                i.rejected OR EXISTS(SELECT 1 FROM external_metadata e
+                       WHERE e.image_id = i.id AND e.source = 'lightroom' AND e.field = 'pick' AND e.value = 'rejected'),
+                (SELECT applied_at FROM schema_version WHERE version = 2) <= datetime('now', ?1)
+                    AND (a.last_accessed IS NULL OR a.last_accessed < datetime('now', ?1)),
+                a.last_accessed IS NULL
+         FROM images i LEFT JOIN image_access a ON a.image_id = i.id
+         ORDER BY i.id",
+    )?;
+    struct Row {
+        id: i64,
+        path: String,
+        file_size: u64,
+        content_hash: Option<String>,
+        hits: i64,
+        rating: Option<u32>,
+        rejected: bool,
+        unused: bool,
+        never: bool,
+    }
+    let rows: Vec<Row> = stmt.query_map([&since], |row| {
+        Ok(Row {
+            id: row.get(0)?,
+            path: row.get(1)?,
+            file_size: row.get(2)?,
+            content_hash: row.get(3)?,
+            hits: row.get(4)?,
+            rating: row.get(5)?,
+            rejected: row.get(6)?,
+            unused: row.get::<_, Option<bool>>(7)?.unwrap_or(false),
+            never: row.get(8)?,
+        })
+    })?.collect::<Result<_, _>>()?;
+
+    // The copy of each file that's kept: the most used, then the first
+    // catalogued
+    let mut kept: HashMap<&str, &Row> = HashMap::new();
+    for row in &rows {
+        if let Some(hash) = &row.content_hash {
+            let best = kept.entry(hash).or_insert(row);
+            if row.hits > best.hits {
+                *best = row;
+            }
+        }
+    }
+
+    let mut candidates = Vec::new();
+    for row in &rows {
+        let (mut delete, mut archive) = (Vec::new(), Vec::new());
+        if let Some(original) = row.content_hash.as_deref().and_then(|hash| kept.get(hash)).filter(|kept| kept.id != row.id) {
+            delete.push(format!("a copy of {}", original.path));
+        }
+        if row.rejected {
+            delete.push(String::from("rejected in Lightroom"));
+        }
+        if let Some(rating) = row.rating.filter(|rating| *rating <= config.low_rating) {
+            archive.push(format!("rated {}", rating));
+        }
+        if row.file_size >= config.huge_mb << 20 {
+            archive.push(format!("huge ({})", size(row.file_size)));
+        }
+        let action = if !delete.is_empty() {
+            Action::Delete
+        } else if row.unused && !archive.is_empty() {
+            Action::Archive
+        } else {
+            continue;
+        };
+        if row.unused {
+            let unused = if row.never { "never searched for" } else { "not searched for" };
+            archive.insert(0, format!("{} in {} days", unused, config.unused_days));
+        }
+        candidates.push(Candidate {
+            id: row.id,
+            path: row.path.clone(),
+            file_size: row.file_size,
+            action,
+            reasons: delete.into_iter().chain(archive).collect(),
+        });
+    }
+    Ok(candidates)
+}
+
+/// Where `candidate` goes under `archive`: the same path, below it.
+pub fn destination(archive: &Path, candidate: &Candidate) -> PathBuf {
+    let mut destination = archive.to_path_buf();
+    if candidate.action == Action::Delete {
+        destination.push(TO_DELETE);
+    }
+    destination.extend(Path::new(&candidate.path).components().filter(|component| matches!(component, Component::Normal(_))));
+    destination
+}
+
+/// Move each of `candidates` under `archive` and catalog it there. A file
+/// already at the destination is left where it is. Returns how many were
+/// moved and the bytes they take; with `dry_run`, how many would be, and
+/// nothing is touched.
+pub fn archive(conn: &Connection, candidates: &[Candidate], archive: &Path, dry_run: bool) -> Result<(usize, u64), Error> {
+    let (mut moved, mut bytes) = (0, 0);
+    for candidate in candidates {
+        let (source, destination) = (Path::new(&candidate.path), destination(archive, candidate));
+        if destination.exists() {
+            tracing::warn!("Not moving {}: {} already exists", source.display(), destination.display());
+            continue;
+        }
+        if dry_run {
+            println!("Would move {} to {}", source.display(), destination.display());
+            moved += 1;
+            bytes += candidate.file_size;
+            continue;
+        }
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        move_file(source, &destination)?;
+        if let Some(sidecar) = xmp::find_sidecar(source) {
+            let name = sidecar.file_name().ok_or_else(|| Error::msg("sidecar without a name"))?;
+            move_file(&sidecar, &destination.with_file_name(name))?;
+        }
+        conn.execute("UPDATE images SET path = ?1 WHERE id = ?2", params![destination.to_string_lossy(), candidate.id])?;
+        println!("Moved {} to {}", source.display(), destination.display());
+        moved += 1;
+        bytes += candidate.file_size;
+    }
+    if moved == 0 && !candidates.is_empty() {
+        return Err(CliError::new(ErrorKind::PartialFailure, "no files could be moved").into());
+    }
+    Ok((moved, bytes))
+}
+
+/// Rename, or copy and delete across file systems (cold storage is often
+/// another disk).
+fn move_file(source: &Path, destination: &Path) -> Result<(), Error> {
+    if fs::rename(source, destination).is_err() {
+        fs::copy(source, destination)?;
+        fs::remove_file(source)?;
+    }
+    Ok(())
+}
+
+/// `bytes` for people, e.g. "1.5 GB".
+pub fn size(bytes: u64) -> String {
+    let units = ["bytes", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} bytes", bytes) } else { format!("{:.1} {}", value, units[unit]) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recommend_and_archive() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let library = dir.path().join("library");
+        fs::create_dir(&library)?;
+        let conn = crate::open_catalog(&dir.path().join("catalog.db"))?;
+        let mut paths = Vec::new();
+        for (name, size, hash) in [("a.jpg", 1000, "aaa"), ("copy-of-a.jpg", 1000, "aaa"), ("dull.jpg", 2000, "bbb"), ("pano.tif", 60 << 20, "ccc"), ("fine.jpg", 500, "ddd")] {
+            let path = library.join(name);
+            fs::write(&path, name)?;
+            conn.execute(
+                "INSERT INTO images (path, file_name, file_size, content_hash) VALUES (?1, ?2, ?3, ?4)",
+                params![path.to_string_lossy(), name, size, hash],
+            )?;
+            paths.push(path);
+        }
+        fs::write(library.join("dull.jpg.xmp"), "<x:xmpmeta/>")?;
+        conn.execute_batch(
+            "INSERT INTO external_metadata (image_id, source, field, value) VALUES (3, 'xmp', 'rating', '1'), (5, 'lightroom', 'rating', '1')",
+        )?;
+        // The copy is the one people look at
+        record(&conn, &[2, 2])?;
+        // Accesses just started being kept: nothing is unused yet
+        let config = TieringConfig::default();
+        let candidates = recommend(&conn, &config)?;
+        assert_eq!(candidates.len(), 1);
+        assert_eq!((candidates[0].id, candidates[0].action), (1, Action::Delete));
+        assert_eq!(candidates[0].reasons, vec![format!("a copy of {}", paths[1].display())]);
+
+        // A year and a half on, with "fine.jpg" searched for last month
+        conn.execute_batch(
+            "UPDATE schema_version SET applied_at = datetime('now', '-540 days') WHERE version = 2;
+             UPDATE image_access SET last_accessed = datetime('now', '-400 days');
+             INSERT INTO image_access (image_id, hits, last_accessed) VALUES (5, 3, datetime('now', '-30 days'));",
+        )?;
+        let candidates = recommend(&conn, &config)?;
+        let summary: Vec<(i64, Action)> = candidates.iter().map(|candidate| (candidate.id, candidate.action)).collect();
+        assert_eq!(summary, vec![(1, Action::Delete), (3, Action::Archive), (4, Action::Archive)]);
+        assert_eq!(candidates[1].reasons, vec!["never searched for in 365 days", "rated 1"]);
+        assert_eq!(candidates[2].reasons, vec!["never searched for in 365 days", "huge (60.0 MB)"]);
+
+        let cold = dir.path().join("cold");
+        fs::create_dir_all(destination(&cold, &candidates[2]).parent().unwrap())?;
+        fs::write(destination(&cold, &candidates[2]), "already there")?;
+        assert_eq!(archive(&conn, &candidates, &cold, true)?, (2, 3000));
+        assert!(paths[2].exists() && !cold.join(TO_DELETE).exists());
+        assert_eq!(archive(&conn, &candidates, &cold, false)?, (2, 3000));
+        let moved = destination(&cold, &candidates[1]);
+        assert_eq!(fs::read_to_string(&moved)?, "dull.jpg");
+        assert!(moved.with_file_name("dull.jpg.xmp").is_file() && !paths[2].exists());
+        assert!(destination(&cold, &candidates[0]).starts_with(cold.join(TO_DELETE)));
+        let path: String = conn.query_row("SELECT path FROM images WHERE id = 3", [], |row| row.get(0))?;
+        assert_eq!(path, moved.to_string_lossy());
+        // Left alone, as something was in the way
+        assert!(paths[3].exists());
+
+        // Rejected in the catalog
+        conn.execute("UPDATE images SET rejected = 1 WHERE id = 5", [])?;
+        let rejected = recommend(&conn, &config)?.into_iter().find(|candidate| candidate.id == 5).map(|candidate| candidate.action);
+        assert_eq!(rejected, Some(Action::Delete));
+
+        conn.execute_batch("DELETE FROM image_access")?;
+        crate::tags::set(&conn, 5, "tram")?;
+        let filter = SearchFilter { keyword: Some(String::from("tram")), ..SearchFilter::default() };
+        record_matches(&conn, &filter)?;
+        let accessed: Vec<i64> = conn.prepare("SELECT image_id FROM image_access")?.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+        assert_eq!(accessed, vec![5]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_size() {
+        assert_eq!(size(512), "512 bytes");
+        assert_eq!(size(1536), "1.5 KB");
+        assert_eq!(size(3 << 30), "3.0 GB");
+    }
+}
@@ -0,0 +1,203 @@
+//! TIFF files: scans, often 16 bits per sample and sometimes a document of
+//! many pages, and the container underneath the TIFF-based RAW formats. The
+//! IFD walker here serves both; decoding is left to the image crate, which
+//! handles 16-bit samples, and analysis gets the first page as JPEG.
+
+use std::path::Path;
+use anyhow::{bail, Error};
+
+/// Guards against IFD loops in corrupt files
+const MAX_IFDS: usize = 64;
+/// NewSubfileType flag for a reduced-resolution copy (a thumbnail)
+const REDUCED_RESOLUTION: u32 = 1;
+const EXIF_IFD_POINTER: u16 = 0x8769;
+
+pub fn is_tiff(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("tif") || ext.eq_ignore_ascii_case("tiff"))
+}
+
+/// The bits of a TIFF IFD that matter for cataloging.
+#[derive(Default)]
+pub struct Ifd {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// (offset, length) of JPEG data referenced by this IFD
+    pub jpeg: Option<(u32, u32)>,
+    subfile_type: u32,
+    /// In the main IFD chain rather than hanging off another IFD
+    main: bool,
+}
+
+impl Ifd {
+    /// Whether this is a page of the document, rather than a thumbnail or
+    /// a SubIFD.
+    pub fn is_page(&self) -> bool {
+        self.main && self.subfile_type & REDUCED_RESOLUTION == 0
+    }
+}
+
+pub struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Tiff<'a>, Error> {
+        let little_endian = match data.get(..4) {
+            Some(b"II*\0") => true,
+            Some(b"MM\0*") => false,
+            _ => bail!("not a TIFF file"),
+        };
+        Ok(Tiff { data, little_endian })
+    }
+
+    fn u16(&self, at: usize) -> Option<u16> {
+        let bytes = self.data.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let bytes = self.data.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    }
+
+    /// The values of a SHORT or LONG entry, wherever they are stored.
+    fn values(&self, entry: usize) -> Vec<u32> {
+        let (Some(kind), Some(count)) = (self.u16(entry + 2), self.u32(entry + 4)) else { return Vec::new() };
+        let size = match kind {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return Vec::new(),
+        };
+        let count = count as usize;
+        let start = if size * count <= 4 {
+            entry + 8
+        } else {
+            match self.u32(entry + 8) {
+                Some(offset) => offset as usize,
+                None => return Vec::new(),
+            }
+        };
+        (0..count.min(1024))
+            .map_while(|i| if size == 2 { self.u16(start + i * 2).map(u32::from) } else { self.u32(start + i * 4) })
+            .collect()
+    }
+
+    /// Every IFD reachable from the header: the main chain, in order, then
+    /// SubIFDs, where NEF and DNG keep their previews.
+    pub fn ifds(&self) -> Vec<Ifd> {
+        let mut pending: Vec<(u32, bool)> = self.u32(4).map(|first| (first, true)).into_iter().collect();
+        let mut seen = Vec::new();
+        let mut ifds = Vec::new();
+        while let Some((offset, main)) = pending.pop() {
+            if offset == 0 || seen.contains(&offset) || seen.len() >= MAX_IFDS {
+                continue;
+            }
+            seen.push(offset);
+            let offset = offset as usize;
+            let Some(count) = self.u16(offset) else { continue };
+
+            let mut ifd = Ifd { main, ..Ifd::default() };
+            let (mut compression, mut strips, mut strip_lengths) = (None, Vec::new(), Vec::new());
+            let (mut jpeg_offset, mut jpeg_length) = (None, None);
+            for i in 0..count as usize {
+                let entry = offset + 2 + i * 12;
+                let Some(tag) = self.u16(entry) else { break };
+                let first = || self.values(entry).first().copied();
+                match tag {
+                    0x00FE => ifd.subfile_type = first().unwrap_or_default(),
+                    0x0100 => ifd.width = first(),
+                    0x0101 => ifd.height = first(),
+                    0x0103 => compression = first(),
+                    0x0111 => strips = self.values(entry),
+                    0x0117 => strip_lengths = self.values(entry),
+                    0x014A => pending.extend(self.values(entry).into_iter().rev().map(|sub| (sub, false))),
+                    0x0201 => jpeg_offset = first(),
+                    0x0202 => jpeg_length = first(),
+                    _ => {}
+                }
+            }
+            ifd.jpeg = match (jpeg_offset, jpeg_length) {
+                (Some(offset), Some(len)) => Some((offset, len)),
+                // A JPEG stored as a single strip (CR2, DNG previews)
+                _ if matches!(compression, Some(6 | 7)) && strips.len() == 1 && strip_lengths.len() == 1 => {
+                    Some((strips[0], strip_lengths[0]))
+                }
+                _ => None,
+            };
+            ifds.push(ifd);
+
+            // Pushed last so the rest of the chain comes before the SubIFDs
+            if let Some(next) = self.u32(offset + 2 + count as usize * 12) {
+                pending.push((next, main));
+            }
+        }
+        ifds
+    }
+}
+
+impl Tiff<'_> {
+    /// Where the ASCII values of `tags` in IFD0 and the Exif IFD are stored,
+    /// as (tag, offset, length), for patching them in place.
+    pub fn ascii_locations(&self, tags: &[u16]) -> Vec<(u16, usize, usize)> {
+        let mut locations = Vec::new();
+        let Some(ifd0) = self.u32(4) else { return locations };
+        let exif = self.find_ascii(ifd0 as usize, tags, &mut locations);
+        if let Some(exif) = exif.filter(|&exif| exif != ifd0) {
+            self.find_ascii(exif as usize, tags, &mut locations);
+        }
+        locations
+    }
+
+    /// Add the locations of `tags` in the IFD at `offset`, returning where
+    /// it points to an Exif IFD, if it does.
+    fn find_ascii(&self, offset: usize, tags: &[u16], locations: &mut Vec<(u16, usize, usize)>) -> Option<u32> {
+        let mut exif = None;
+        for i in 0..self.u16(offset)? as usize {
+            let entry = offset + 2 + i * 12;
+            let (Some(tag), Some(kind), Some(len)) = (self.u16(entry), self.u16(entry + 2), self.u32(entry + 4)) else { break };
+            if tag == EXIF_IFD_POINTER {
+                exif = self.values(entry).first().copied();
+            }
+            if kind == 2 && tags.contains(&tag) {
+                let len = len as usize;
+                let at = if len <= 4 { Some(entry + 8) } else { self.u32(entry + 8).map(|at| at as usize) };
+                if let Some(at) = at.filter(|&at| at + len <= self.data.len()) {
+                    locations.push((tag, at, len));
+                }
+            }
+        }
+        exif
+    }
+}
+
+/// The number of pages and the size of the first, for a TIFF document.
+pub fn pages(data: &[u8]) -> Option<(u32, Option<(u32, u32)>)> {
+    let ifds = Tiff::new(data).ok()?.ifds();
+    let mut pages = ifds.iter().filter(|ifd| ifd.is_page()).peekable();
+    let first = pages.peek().and_then(|page| Some((page.width?, page.height?)));
+    Some((pages.count() as u32, first))
+}
+
+/// The first page as JPEG, for the analyzer.
+pub fn to_jpeg(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let img = image::load_from_memory_with_format(data, image::ImageFormat::Tiff)?;
+    crate::encode_jpeg(&img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_multi_page() -> Result<(), Error> {
+        let scan = multi_page_tiff(&[(40, 30, false), (10, 8, true), (40, 30, false)]);
+        assert_eq!(pages(&scan), Some((2, Some((40, 30)))));
+        assert_eq!(image::load_from_memory(&to_jpeg(&scan)?)?.width(), 40);
+        assert_eq!(pages(b"not a tiff"), None);
+        assert!(is_tiff(Path::new("scan.TIF")));
+        Ok(())
+    }
+}
@@ -0,0 +1,486 @@
+//! Trips: stretches of time spent away from home, found from where photos
+//! were taken. Home is a geofence named in `[trips] home`; a trip is a run
+//! of geotagged photos outside it that lasts long enough and gets far
+//! enough away, and ends at the first photo back home (or a long gap with no
+//! photos). Photos without GPS join the trip they were taken during.
+//!
+//! Within a trip, photos are grouped into events wherever there's a long
+//! enough pause between them. Trips are stored in `trips` and
+//! `trip_images`, and found afresh each time `trips detect` runs.
+//!
+//! The route of a trip (or one of its events) is the line through its
+//! geotagged photos in the order they were taken, written out as GeoJSON
+//! for any map to draw, with the distance covered each day.
+
+use std::fmt::Write as _;
+use anyhow::Error;
+use chrono::{NaiveDate, NaiveDateTime, TimeDelta};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use crate::error::{CliError, ErrorKind};
+use crate::geocode;
+use crate::geofence::Geofence;
+
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+/// Geotagged photos needed to draw a route
+const MIN_ROUTE_POINTS: usize = 3;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TripConfig {
+    /// Name of the geofence that is home.
+    pub home: Option<String>,
+    /// Hours between the first and last photo away for it to be a trip.
+    pub min_hours: u64,
+    /// How far from home (in km) a trip has to get.
+    pub min_distance_km: f64,
+    /// Days without photos after which a trip is taken to have ended.
+    pub max_gap_days: u64,
+    /// Hours without photos that separate one event from the next.
+    pub event_gap_hours: u64,
+}
+
+impl Default for TripConfig {
+    fn default() -> Self {
+        TripConfig { home: None, min_hours: 24, min_distance_km: 50.0, max_gap_days: 3, event_gap_hours: 3 }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Photo {
+    id: i64,
+    date: NaiveDateTime,
+    gps: Option<(f64, f64)>,
+    city: Option<String>,
+    country: Option<String>,
+}
+
+/// A detected trip, before it's stored.
+#[derive(Debug, PartialEq)]
+pub struct Trip {
+    pub name: String,
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    /// Along the photos, in order
+    pub distance_km: f64,
+    /// In the order they were visited
+    pub countries: Vec<String>,
+    /// Photo ids in each event, by time
+    pub events: Vec<Vec<i64>>,
+}
+
+impl Trip {
+    pub fn photo_count(&self) -> usize {
+        self.events.iter().map(Vec::len).sum()
+    }
+}
+
+/// The home geofence named by the config.
+fn home<'a>(config: &TripConfig, fences: &'a [Geofence]) -> Result<&'a Geofence, Error> {
+    let Some(name) = &config.home else {
+        let message = "set [trips] home to the name of the geofence that is home";
+        return Err(CliError::new(ErrorKind::Config, message).into());
+    };
+    fences.iter().find(|fence| fence.name.eq_ignore_ascii_case(name)).ok_or_else(|| {
+        CliError::new(ErrorKind::Config, format!("[trips] home is {:?}, but there's no geofence by that name", name)).into()
+    })
+}
+
+fn load(conn: &Connection) -> Result<Vec<Photo>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, creation_date, latitude, longitude, city, country FROM images
+         WHERE creation_date IS NOT NULL ORDER BY creation_date, id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<f64>>(2)?.zip(row.get::<_, Option<f64>>(3)?),
+            row.get::<_, Option<String>>(4)?,
+            row.get::<_, Option<String>>(5)?,
+        ))
+    })?;
+    let mut photos = Vec::new();
+    for row in rows {
+        let (id, date, gps, city, country) = row?;
+        // Dates without a time can't be placed on the timeline
+        if let Ok(date) = NaiveDateTime::parse_from_str(&date, DATE_FORMAT) {
+            photos.push(Photo { id, date, gps, city, country });
+        }
+    }
+    Ok(photos)
+}
+
+/// Find the trips in the catalog.
+pub fn detect(conn: &Connection, config: &TripConfig, fences: &[Geofence]) -> Result<Vec<Trip>, Error> {
+    let home = home(config, fences)?;
+    let photos = load(conn)?;
+    let max_gap = TimeDelta::days(config.max_gap_days as i64);
+
+    // Runs of geotagged photos away from home
+    let mut runs: Vec<Vec<&Photo>> = Vec::new();
+    let mut current: Vec<&Photo> = Vec::new();
+    for photo in photos.iter().filter(|photo| photo.gps.is_some()) {
+        let gap = current.last().is_some_and(|last| photo.date - last.date > max_gap);
+        let at_home = home.contains(photo.gps.unwrap());
+        if (gap || at_home) && !current.is_empty() {
+            runs.push(std::mem::take(&mut current));
+        }
+        if !at_home {
+            current.push(photo);
+        }
+    }
+    if !current.is_empty() {
+        runs.push(current);
+    }
+
+    let far_enough = |run: &[&Photo]| run.iter().any(|photo| {
+        let (latitude, longitude) = photo.gps.unwrap();
+        geocode::distance_km(home.latitude, home.longitude, latitude, longitude) >= config.min_distance_km
+    });
+    let mut trips = Vec::new();
+    for run in runs {
+        let (start, end) = (run[0].date, run[run.len() - 1].date);
+        if end - start < TimeDelta::hours(config.min_hours as i64) || !far_enough(&run) {
+            continue;
+        }
+        let members: Vec<&Photo> = photos.iter()
+            .filter(|photo| start <= photo.date && photo.date <= end)
+            .filter(|photo| photo.gps.is_none_or(|gps| !home.contains(gps)))
+            .collect();
+        trips.push(trip(&run, &members, config));
+    }
+    Ok(trips)
+}
+
+fn trip(run: &[&Photo], members: &[&Photo], config: &TripConfig) -> Trip {
+    let points: Vec<(f64, f64)> = run.iter().map(|photo| photo.gps.unwrap()).collect();
+    let distance_km = points.windows(2).map(|pair| leg_km(pair[0], pair[1])).sum();
+    let mut countries: Vec<String> = Vec::new();
+    for country in members.iter().filter_map(|photo| photo.country.as_ref()) {
+        if !countries.contains(country) {
+            countries.push(country.clone());
+        }
+    }
+
+    let mut events: Vec<Vec<i64>> = Vec::new();
+    let mut last: Option<NaiveDateTime> = None;
+    for photo in members {
+        match (events.last_mut(), last) {
+            (Some(event), Some(last)) if photo.date - last <= TimeDelta::hours(config.event_gap_hours as i64) => event.push(photo.id),
+            _ => events.push(vec![photo.id]),
+        }
+        last = Some(photo.date);
+    }
+
+    Trip {
+        name: name(members, run[0].date),
+        start: run[0].date,
+        end: run[run.len() - 1].date,
+        distance_km,
+        countries,
+        events,
+    }
+}
+
+fn leg_km((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    geocode::distance_km(lat1, lon1, lat2, lon2)
+}
+
+/// "Lisbon, July 2023": the place with the most photos, and when.
+fn name(members: &[&Photo], start: NaiveDateTime) -> String {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for place in members.iter().filter_map(|photo| photo.city.as_deref().or(photo.country.as_deref())) {
+        match counts.iter_mut().find(|(p, _)| *p == place) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((place, 1)),
+        }
+    }
+    // The first visited wins ties
+    let place = counts.iter().rev().max_by_key(|(_, count)| *count).map_or("Away", |(place, _)| place);
+    format!("{}, {}", place, start.format("%B %Y"))
+}
+
+/// Replace the stored trips with `trips`.
+pub fn save(conn: &Connection, trips: &[Trip]) -> Result<(), Error> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM trip_images", [])?;
+    tx.execute("DELETE FROM trips", [])?;
+    for trip in trips {
+        tx.execute(
+            "INSERT INTO trips (name, start_date, end_date, distance_km, countries, event_count)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                trip.name,
+                trip.start.format(DATE_FORMAT).to_string(),
+                trip.end.format(DATE_FORMAT).to_string(),
+                trip.distance_km,
+                trip.countries.join(", "),
+                trip.events.len(),
+            ],
+        )?;
+        let trip_id = tx.last_insert_rowid();
+        for (event, ids) in trip.events.iter().enumerate() {
+            for id in ids {
+                tx.execute(
+                    "INSERT INTO trip_images (trip_id, image_id, event) VALUES (?1, ?2, ?3)",
+                    params![trip_id, id, event + 1],
+                )?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// A stored trip, as listed.
+#[derive(Debug)]
+pub struct Summary {
+    pub id: i64,
+    pub name: String,
+    pub start: String,
+    pub end: String,
+    pub distance_km: f64,
+    pub countries: String,
+    pub events: usize,
+    pub photos: usize,
+}
+
+pub fn list(conn: &Connection) -> Result<Vec<Summary>, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, start_date, end_date, distance_km, countries, event_count,
+                (SELECT COUNT(*) FROM trip_images WHERE trip_id = trips.id)
+         FROM trips ORDER BY start_date",
+    )?;
+    let trips = stmt.query_map([], summary)?.collect::<Result<Vec<_>, _>>()?;
+    Ok(trips)
+}
+
+fn summary(row: &rusqlite::Row) -> rusqlite::Result<Summary> {
+    Ok(Summary {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        start: row.get(2)?,
+        end: row.get(3)?,
+        distance_km: row.get(4)?,
+        countries: row.get(5)?,
+        events: row.get(6)?,
+        photos: row.get(7)?,
+    })
+}
+
+/// A Markdown report of trip `id`: its span, distance and countries, then
+/// each event with its time, places and photos.
+pub fn report(conn: &Connection, id: i64) -> Result<String, Error> {
+    let trip = conn.query_row(
+        "SELECT id, name, start_date, end_date, distance_km, countries, event_count,
+                (SELECT COUNT(*) FROM trip_images WHERE trip_id = trips.id)
+         FROM trips WHERE id = ?1",
+        [id],
+        summary,
+    ).optional()?.ok_or_else(|| CliError::new(ErrorKind::NothingToDo, format!("no trip {}; `trips detect` finds them", id)))?;
+
+    let mut out = format!("# {}\n\n", trip.name);
+    let _ = writeln!(out, "- From {} to {}", trip.start, trip.end);
+    let _ = writeln!(out, "- {:.0} km traveled", trip.distance_km);
+    if !trip.countries.is_empty() {
+        let _ = writeln!(out, "- Countries: {}", trip.countries);
+    }
+    let _ = writeln!(out, "- {} photos in {} events", trip.photos, trip.events);
+    if let Ok(route) = route(conn, id, None) {
+        out.push_str("\n## Distance by day\n\n");
+        for (day, km) in &route.days {
+            let _ = writeln!(out, "- {}: {:.0} km", day, km);
+        }
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT event, images.file_name, images.creation_date, images.city, images.country, images.description
+         FROM trip_images JOIN images ON images.id = trip_images.image_id
+         WHERE trip_id = ?1 ORDER BY event, images.creation_date, images.id",
+    )?;
+    let rows = stmt.query_map([id], |row| {
+        Ok((
+            row.get::<_, usize>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<String>>(2)?,
+            row.get::<_, Option<String>>(3)?.or(row.get::<_, Option<String>>(4)?),
+            row.get::<_, Option<String>>(5)?,
+        ))
+    })?.collect::<Result<Vec<_>, _>>()?;
+    let mut events: Vec<(usize, Vec<_>)> = Vec::new();
+    for (event, file_name, date, place, description) in rows {
+        match events.last_mut() {
+            Some((last, photos)) if *last == event => photos.push((file_name, date, place, description)),
+            _ => events.push((event, vec![(file_name, date, place, description)])),
+        }
+    }
+    for (event, photos) in events {
+        let first = photos[0].1.clone().unwrap_or_default();
+        let last = photos[photos.len() - 1].1.clone().unwrap_or_default();
+        let mut places: Vec<&str> = Vec::new();
+        for place in photos.iter().filter_map(|photo| photo.2.as_deref()) {
+            if !places.contains(&place) {
+                places.push(place);
+            }
+        }
+        let _ = write!(out, "\n## Event {}: {} to {}", event, first, last);
+        if !places.is_empty() {
+            let _ = write!(out, ", {}", places.join(", "));
+        }
+        out.push_str("\n\n");
+        for (file_name, date, _, description) in &photos {
+            let _ = write!(out, "- {} {}", date.as_deref().unwrap_or(""), file_name);
+            if let Some(description) = description {
+                let _ = write!(out, ": {}", description);
+            }
+            out.push('\n');
+        }
+    }
+    Ok(out)
+}
+
+/// The line through a trip's geotagged photos.
+#[derive(Debug)]
+pub struct Route {
+    /// Time and `(latitude, longitude)` of each photo, in order
+    pub points: Vec<(NaiveDateTime, (f64, f64))>,
+    /// Distance covered each day; a leg counts for the day it ends
+    pub days: Vec<(NaiveDate, f64)>,
+}
+
+impl Route {
+    pub fn distance_km(&self) -> f64 {
+        self.days.iter().map(|(_, km)| km).sum()
+    }
+
+    /// A GeoJSON feature collection: the route as a line, and each photo as
+    /// a point with its time.
+    pub fn geojson(&self, name: &str) -> serde_json::Value {
+        // GeoJSON has longitude first
+        let coordinates: Vec<[f64; 2]> = self.points.iter().map(|(_, (lat, lon))| [*lon, *lat]).collect();
+        let days: serde_json::Map<String, serde_json::Value> = self.days.iter()
+            .map(|(day, km)| (day.to_string(), serde_json::json!((km * 10.0).round() / 10.0)))
+            .collect();
+        let mut features = vec![serde_json::json!({
+            "type": "Feature",
+            "geometry": { "type": "LineString", "coordinates": coordinates },
+            "properties": { "name": name, "distance_km": (self.distance_km() * 10.0).round() / 10.0, "distance_km_by_day": days },
+        })];
+        for (time, (lat, lon)) in &self.points {
+            features.push(serde_json::json!({
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": [lon, lat] },
+                "properties": { "time": time.format(DATE_FORMAT).to_string() },
+            }));
+        }
+        serde_json::json!({ "type": "FeatureCollection", "features": features })
+    }
+}
+
+/// The route of trip `id`, or of just one of its events.
+pub fn route(conn: &Connection, id: i64, event: Option<usize>) -> Result<Route, Error> {
+    let mut stmt = conn.prepare(
+        "SELECT images.creation_date, images.latitude, images.longitude
+         FROM trip_images JOIN images ON images.id = trip_images.image_id
+         WHERE trip_id = ?1 AND (?2 IS NULL OR event = ?2)
+           AND images.latitude IS NOT NULL AND images.longitude IS NOT NULL
+         ORDER BY images.creation_date, images.id",
+    )?;
+    let rows = stmt.query_map(params![id, event], |row| Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?, row.get::<_, f64>(2)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let points: Vec<(NaiveDateTime, (f64, f64))> = rows.into_iter()
+        .filter_map(|(date, lat, lon)| Some((NaiveDateTime::parse_from_str(&date, DATE_FORMAT).ok()?, (lat, lon))))
+        .collect();
+    if points.len() < MIN_ROUTE_POINTS {
+        let what = match event {
+            Some(event) => format!("event {} of trip {}", event, id),
+            None => format!("trip {}", id),
+        };
+        let message = format!("{} has {} geotagged photos; a route needs {}", what, points.len(), MIN_ROUTE_POINTS);
+        return Err(CliError::new(ErrorKind::NothingToDo, message).into());
+    }
+
+    let mut days: Vec<(NaiveDate, f64)> = vec![(points[0].0.date(), 0.0)];
+    for pair in points.windows(2) {
+        let ((_, from), (time, to)) = (pair[0], pair[1]);
+        let km = leg_km(from, to);
+        match days.last_mut() {
+            Some((day, total)) if *day == time.date() => *total += km,
+            _ => days.push((time.date(), km)),
+        }
+    }
+    Ok(Route { points, days })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn insert(conn: &Connection, name: &str, date: &str, gps: Option<(f64, f64)>, city: Option<&str>, country: Option<&str>) -> Result<(), Error> {
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, creation_date, latitude, longitude, city, country)
+             VALUES (?1, ?1, 0, ?2, ?3, ?4, ?5, ?6)",
+            params![name, date, gps.map(|(lat, _)| lat), gps.map(|(_, lon)| lon), city, country],
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect() -> Result<(), Error> {
+        let config = Config::parse(r#"
+            [trips]
+            home = "home"
+
+            [[geofences]]
+            name = "Home"
+            latitude = 38.71
+            longitude = -9.14
+        "#)?;
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        let (home, porto, vigo) = ((38.71, -9.14), (41.15, -8.61), (42.24, -8.72));
+        insert(&conn, "home1.jpg", "2023-07-01 09:00:00", Some(home), Some("Lisbon"), Some("Portugal"))?;
+        insert(&conn, "porto1.jpg", "2023-07-02 10:00:00", Some(porto), Some("Porto"), Some("Portugal"))?;
+        insert(&conn, "porto2.jpg", "2023-07-02 11:00:00", None, None, None)?;
+        insert(&conn, "porto3.jpg", "2023-07-02 18:00:00", Some(porto), Some("Porto"), Some("Portugal"))?;
+        insert(&conn, "vigo1.jpg", "2023-07-03 12:00:00", Some(vigo), Some("Vigo"), Some("Spain"))?;
+        insert(&conn, "home2.jpg", "2023-07-04 20:00:00", Some(home), Some("Lisbon"), Some("Portugal"))?;
+        // Out for the afternoon: not a trip
+        insert(&conn, "beach.jpg", "2023-07-08 15:00:00", Some((38.69, -9.42)), Some("Cascais"), Some("Portugal"))?;
+        insert(&conn, "beach2.jpg", "2023-07-08 17:00:00", Some((38.69, -9.42)), Some("Cascais"), Some("Portugal"))?;
+
+        let trips = detect(&conn, &config.trips, &config.geofences)?;
+        assert_eq!(trips.len(), 1);
+        let trip = &trips[0];
+        assert_eq!(trip.name, "Porto, July 2023");
+        assert_eq!(trip.countries, vec!["Portugal", "Spain"]);
+        assert_eq!(trip.photo_count(), 4);
+        // Porto in the morning, Porto in the evening, Vigo
+        assert_eq!(trip.events.len(), 3);
+        assert!((121.0..123.0).contains(&trip.distance_km), "{}", trip.distance_km);
+
+        save(&conn, &trips)?;
+        save(&conn, &trips)?;
+        let listed = list(&conn)?;
+        assert_eq!((listed.len(), listed[0].photos), (1, 4));
+        let report = report(&conn, listed[0].id)?;
+        assert!(report.starts_with("# Porto, July 2023\n"));
+        assert!(report.contains("## Event 3: 2023-07-03 12:00:00 to 2023-07-03 12:00:00, Vigo"));
+        assert!(report.contains("- 2023-07-02: 0 km\n- 2023-07-03: 122 km\n"));
+
+        let route = route(&conn, listed[0].id, None)?;
+        assert_eq!(route.points.len(), 3);
+        let geojson = route.geojson("Porto");
+        assert_eq!(geojson["features"][0]["geometry"]["coordinates"][0], serde_json::json!([-8.61, 41.15]));
+        let day = geojson["features"][0]["properties"]["distance_km_by_day"]["2023-07-03"].as_f64().unwrap();
+        assert!((day - route.distance_km()).abs() < 0.1 && (121.0..123.0).contains(&day), "{}", day);
+        // The first event has just one geotagged photo
+        assert!(super::route(&conn, listed[0].id, Some(1)).is_err());
+
+        let unset = TripConfig::default();
+        assert!(detect(&conn, &unset, &config.geofences).is_err());
+        Ok(())
+    }
+}
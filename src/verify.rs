@@ -0,0 +1,156 @@
+//! Checking the catalog against the files, e.g. after a disk swap or a
+//! restore from backup: every catalogued path should still be there, the
+//! same size, and (for the files re-hashed) with the same content hash.
+//! Files that are gone can be looked for under other directories, by hash,
+//! to tell the moved from the lost. Nothing is changed; `scan` of where
+//! the moved files are now catalogs them there.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use anyhow::Error;
+use rusqlite::Connection;
+use walkdir::WalkDir;
+use crate::hash;
+
+/// Which files to re-hash. Hashing reads the whole file, so on a big
+/// catalog a sample is the quick check and `All` the thorough one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rehash {
+    None,
+    /// This many files, picked at random
+    Sample(usize),
+    All,
+}
+
+/// What `verify` found.
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    /// Files looked at
+    pub checked: usize,
+    /// Of which re-hashed
+    pub hashed: usize,
+    /// Catalogued paths with no file, and none found elsewhere
+    pub missing: Vec<String>,
+    /// Catalogued paths with no file, and where the file is now
+    pub moved: Vec<(String, PathBuf)>,
+    /// Files whose size or content changed since they were catalogued
+    pub modified: Vec<String>,
+}
+
+impl Report {
+    pub fn problems(&self) -> usize {
+        self.missing.len() + self.moved.len() + self.modified.len()
+    }
+}
+
+/// Check every catalogued file, re-hashing as `rehash` says, and look for
+/// the missing ones under `search`.
+pub fn verify(conn: &Connection, rehash: Rehash, search: &[PathBuf]) -> Result<Report, Error> {
+    // In random order when sampling, so the sample is the first few found
+    let order = match rehash {
+        Rehash::Sample(_) => "RANDOM()",
+        _ => "path",
+    };
+    let mut stmt = conn.prepare(&format!("SELECT path, file_size, content_hash FROM images ORDER BY {}", order))?;
+    let rows: Vec<(String, u64, Option<String>)> = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<_, _>>()?;
+
+    let mut report = Report::default();
+    // The missing files with a hash, by size, to look for
+    let mut lost: HashMap<u64, Vec<(String, String)>> = HashMap::new();
+    for (path, size, content_hash) in rows {
+        report.checked += 1;
+        let Ok(metadata) = Path::new(&path).metadata() else {
+            match content_hash {
+                Some(content_hash) if !search.is_empty() => lost.entry(size).or_default().push((path, content_hash)),
+                _ => report.missing.push(path),
+            }
+            continue;
+        };
+        if metadata.len() != size {
+            report.modified.push(path);
+            continue;
+        }
+        let Some(content_hash) = content_hash else { continue };
+        let due = match rehash {
+            Rehash::None => false,
+            Rehash::Sample(n) => report.hashed < n,
+            Rehash::All => true,
+        };
+        if due {
+            report.hashed += 1;
+            if hash::of_file(Path::new(&path))? != content_hash {
+                report.modified.push(path);
+            }
+        }
+    }
+
+    if !lost.is_empty() {
+        let catalogued: HashSet<String> = conn.prepare("SELECT path FROM images")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        let files = search.iter()
+            .flat_map(|dir| WalkDir::new(dir).into_iter().filter_map(|entry| entry.ok()))
+            .filter(|entry| entry.file_type().is_file() && crate::is_supported(entry.path()));
+        for entry in files {
+            if lost.values().all(Vec::is_empty) {
+                break;
+            }
+            let Some(candidates) = entry.metadata().ok().and_then(|metadata| lost.get_mut(&metadata.len())) else { continue };
+            if candidates.is_empty() || catalogued.contains(entry.path().to_string_lossy().as_ref()) {
+                continue;
+            }
+            let content_hash = hash::of_file(entry.path())?;
+            if let Some(i) = candidates.iter().position(|(_, missing)| *missing == content_hash) {
+                let (path, _) = candidates.remove(i);
+                report.moved.push((path, entry.into_path()));
+            }
+        }
+        report.missing.extend(lost.into_values().flatten().map(|(path, _)| path));
+    }
+    report.missing.sort();
+    report.moved.sort();
+    report.modified.sort();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use rusqlite::params;
+
+    #[test]
+    fn test_verify() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        fs::create_dir(dir.path().join("new disk"))?;
+        fs::write(dir.path().join("intact.jpg"), "beach")?;
+        fs::write(dir.path().join("new disk/moved.jpg"), "tram")?;
+        // Edited in place, keeping the size, and cropped
+        fs::write(dir.path().join("retouched.jpg"), "CAT")?;
+        fs::write(dir.path().join("cropped.jpg"), "do")?;
+        let mut insert = conn.prepare("INSERT INTO images (path, file_name, file_size, content_hash) VALUES (?1, ?2, ?3, ?4)")?;
+        for (name, content) in [("intact.jpg", "beach"), ("moved.jpg", "tram"), ("lost.jpg", "boat"), ("retouched.jpg", "cat"), ("cropped.jpg", "dog")] {
+            insert.execute(params![path(name), name, content.len(), hash::of_bytes(content.as_bytes())])?;
+        }
+
+        let report = verify(&conn, Rehash::None, &[])?;
+        assert_eq!((report.checked, report.hashed), (5, 0));
+        assert_eq!(report.missing, vec![path("lost.jpg"), path("moved.jpg")]);
+        assert_eq!(report.modified, vec![path("cropped.jpg")]);
+
+        let report = verify(&conn, Rehash::All, &[dir.path().join("new disk")])?;
+        assert_eq!(report.hashed, 2);
+        assert_eq!(report.missing, vec![path("lost.jpg")]);
+        assert_eq!(report.moved, vec![(path("moved.jpg"), dir.path().join("new disk/moved.jpg"))]);
+        assert_eq!(report.modified, vec![path("cropped.jpg"), path("retouched.jpg")]);
+        assert_eq!(report.problems(), 4);
+
+        assert_eq!(verify(&conn, Rehash::Sample(1), &[])?.hashed, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,482 @@
+//! Videos: MP4, QuickTime and AVI. MP4 and QuickTime metadata is read here
+//! from the `moov` box alone, so a long video isn't loaded for a few hundred
+//! bytes of it; AVI files, and MP4s we can't make sense of, go to `ffprobe`.
+//! What gets analyzed is a representative frame, grabbed by ffmpeg a third
+//! of the way in (past fades and the camera being raised).
+
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::process::{Command, Output};
+use anyhow::{bail, Error};
+use chrono::{DateTime, Utc};
+use crate::config::VideoConfig;
+use crate::isobmff::{be, boxes, find_box};
+use crate::ExternalMetadata;
+
+/// Extensions handled here, with the format name recorded for each
+#[cfg(feature = "video")]
+pub const FORMATS: &[(&str, &str)] = &[
+    ("mp4", "Mp4"),
+    ("m4v", "Mp4"),
+    ("mov", "QuickTime"),
+    ("avi", "Avi"),
+];
+
+/// Seconds from the MP4 epoch (1904) to the Unix one
+const MP4_EPOCH_OFFSET: i64 = 2_082_844_800;
+/// A `moov` bigger than this is not something we want in memory
+const MAX_MOOV: u64 = 64 << 20;
+/// The `external_metadata` source a video's sound is described under.
+pub const AUDIO_SOURCE: &str = "audio";
+/// Integrated loudness below which a clip counts as silent. Room tone
+/// measures around -45 LUFS; digital silence as -70.
+const SILENCE_LUFS: f64 = -55.0;
+
+#[cfg(feature = "video")]
+pub fn format_for(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_string_lossy().to_lowercase();
+    FORMATS.iter().find(|(e, _)| *e == ext).map(|(_, format)| *format)
+}
+
+/// Builds without the `video` feature catalog no videos.
+#[cfg(not(feature = "video"))]
+pub fn format_for(_path: &Path) -> Option<&'static str> {
+    None
+}
+
+pub fn is_video(path: &Path) -> bool {
+    format_for(path).is_some()
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Probe {
+    pub duration_secs: Option<f64>,
+    pub dimensions: Option<(u32, u32)>,
+    /// As ffprobe names it: "h264", "hevc", "prores", ...
+    pub codec: Option<String>,
+    /// In UTC, which is how the containers keep it, formatted as EXIF
+    /// dates are: "2024-05-01 18:30:00"
+    pub creation_date: Option<String>,
+    pub gps: Option<(f64, f64)>,
+    /// `None` for videos without sound
+    pub audio: Option<Audio>,
+}
+
+/// The first audio track of a video.
+#[derive(Debug, Default, PartialEq)]
+pub struct Audio {
+    /// As ffprobe names it: "aac", "pcm_s16le", ...
+    pub codec: Option<String>,
+    pub channels: Option<u32>,
+}
+
+/// Metadata of the video at `path`, natively for MP4 and QuickTime and
+/// through ffprobe otherwise.
+pub fn probe(path: &Path, config: &VideoConfig) -> Result<Probe, Error> {
+    if format_for(path) != Some("Avi") {
+        if let Some(moov) = read_moov(&mut File::open(path)?)? {
+            let probe = parse_moov(&moov);
+            if probe.duration_secs.is_some() {
+                return Ok(probe);
+            }
+        }
+    }
+    ffprobe(path, config)
+}
+
+/// A JPEG of the frame `duration_secs / 3` in (the first one if the
+/// duration isn't known).
+pub fn frame(path: &Path, duration_secs: Option<f64>, config: &VideoConfig) -> Result<Vec<u8>, Error> {
+    frame_at(path, duration_secs.unwrap_or_default() / 3.0, config)
+}
+
+/// A JPEG of the frame `secs` in.
+pub fn frame_at(path: &Path, secs: f64, config: &VideoConfig) -> Result<Vec<u8>, Error> {
+    let at = format!("{:.3}", secs);
+    let input = path.to_string_lossy();
+    let args = ["-v", "error", "-ss", &at, "-i", &input, "-frames:v", "1", "-f", "image2pipe", "-c:v", "mjpeg", "-q:v", "3", "-"];
+    let output = tool_output(&config.ffmpeg, &args, "video.ffmpeg")?;
+    if output.is_empty() {
+        bail!("{} extracted no frame", config.ffmpeg);
+    }
+    Ok(output)
+}
+
+/// Whether browsers play videos in `format` encoded with `codec` (as
+/// cataloged) without help. Chrome and Firefox don't do HEVC or ProRes,
+/// and nothing plays AVI.
+#[cfg(feature = "server")]
+pub fn plays_in_browsers(format: Option<&str>, codec: Option<&str>) -> bool {
+    matches!(format, Some("Mp4" | "QuickTime")) && matches!(codec, Some("h264" | "av1" | "vp9"))
+}
+
+/// Write a copy of the video at `path` to `out` that browsers can play:
+/// H.264 and AAC in an MP4, no taller than `config.preview_height`, with
+/// its index up front so playback starts while it downloads.
+#[cfg(feature = "server")]
+pub fn transcode_preview(path: &Path, out: &Path, config: &VideoConfig) -> Result<(), Error> {
+    // Written aside first, so a half-done copy is never served
+    let partial = out.with_extension("part.mp4");
+    let input = path.to_string_lossy();
+    let scale = format!("scale=-2:'min({},ih)'", config.preview_height);
+    let output = partial.to_string_lossy();
+    let args = [
+        "-v", "error", "-y", "-i", &input, "-vf", &scale,
+        "-c:v", "libx264", "-preset", "veryfast", "-crf", "23", "-pix_fmt", "yuv420p",
+        "-c:a", "aac", "-b:a", "128k", "-movflags", "+faststart", &output,
+    ];
+    run_tool(&config.ffmpeg, &args, "video.ffmpeg")?;
+    std::fs::rename(&partial, out)?;
+    Ok(())
+}
+
+/// The times, in seconds, at which the picture changes more than
+/// `config.scene_threshold` from one frame to the next (0 to 1, as ffmpeg's
+/// scene score).
+pub fn scene_cuts(path: &Path, config: &VideoConfig) -> Result<Vec<f64>, Error> {
+    let input = path.to_string_lossy();
+    let filter = format!("select='gt(scene,{})',showinfo", config.scene_threshold);
+    let args = ["-hide_banner", "-nostats", "-i", &input, "-an", "-vf", &filter, "-f", "null", "-"];
+    // showinfo reports each selected frame on stderr
+    let output = run_tool(&config.ffmpeg, &args, "video.ffmpeg")?;
+    Ok(parse_showinfo(&String::from_utf8_lossy(&output.stderr)))
+}
+
+/// The integrated loudness of the video's sound, in LUFS, by ffmpeg's
+/// EBU R128 meter.
+pub fn loudness(path: &Path, config: &VideoConfig) -> Result<Option<f64>, Error> {
+    let input = path.to_string_lossy();
+    let args = ["-hide_banner", "-nostats", "-i", &input, "-vn", "-af", "ebur128", "-f", "null", "-"];
+    let output = run_tool(&config.ffmpeg, &args, "video.ffmpeg")?;
+    Ok(parse_ebur128(&String::from_utf8_lossy(&output.stderr)))
+}
+
+/// The `I:` of the summary ending the meter's log.
+fn parse_ebur128(log: &str) -> Option<f64> {
+    log.lines().rev()
+        .filter_map(|line| line.trim().strip_prefix("I:"))
+        .find_map(|rest| rest.trim().strip_suffix("LUFS")?.trim().parse().ok())
+}
+
+/// What's known of a video's sound, as metadata: its codec, channels and
+/// loudness, and whether it's silent (as videos without sound are).
+pub fn audio_metadata(path: &Path, audio: Option<&Audio>, config: &VideoConfig) -> Vec<ExternalMetadata> {
+    let entry = |field: &str, value: String| ExternalMetadata { source: AUDIO_SOURCE.to_string(), field: field.to_string(), value };
+    let Some(audio) = audio else {
+        return vec![entry("silent", String::from("true"))];
+    };
+    let mut metadata = Vec::new();
+    metadata.extend(audio.codec.clone().map(|codec| entry("codec", codec)));
+    metadata.extend(audio.channels.map(|channels| entry("channels", channels.to_string())));
+    if config.loudness {
+        match loudness(path, config) {
+            Ok(Some(lufs)) => {
+                metadata.push(entry("loudness_lufs", format!("{:.1}", lufs)));
+                metadata.push(entry("silent", (lufs < SILENCE_LUFS).to_string()));
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Can't measure the loudness of {}: {}", path.display(), e),
+        }
+    }
+    metadata
+}
+
+fn parse_showinfo(log: &str) -> Vec<f64> {
+    log.lines()
+        .filter(|line| line.contains("Parsed_showinfo"))
+        .filter_map(|line| line.split_once("pts_time:"))
+        .filter_map(|(_, rest)| rest.split_whitespace().next()?.parse().ok())
+        .collect()
+}
+
+fn ffprobe(path: &Path, config: &VideoConfig) -> Result<Probe, Error> {
+    let input = path.to_string_lossy();
+    let args = ["-v", "error", "-print_format", "json", "-show_format", "-show_streams", &input];
+    let output = tool_output(&config.ffprobe, &args, "video.ffprobe")?;
+    Ok(parse_ffprobe(&serde_json::from_slice(&output)?))
+}
+
+/// Stdout of `program`, which must succeed.
+fn tool_output(program: &str, args: &[&str], setting: &str) -> Result<Vec<u8>, Error> {
+    Ok(run_tool(program, args, setting)?.stdout)
+}
+
+pub fn run_tool(program: &str, args: &[&str], setting: &str) -> Result<Output, Error> {
+    let output = match Command::new(program).args(args).output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == ErrorKind::NotFound => bail!("{} not found; install ffmpeg or set {}", program, setting),
+        Err(e) => return Err(e.into()),
+    };
+    if !output.status.success() {
+        bail!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(output)
+}
+
+/// The contents of the top-level `moov` box, skipping over the (possibly
+/// huge) boxes around it without reading them.
+fn read_moov(file: &mut File) -> Result<Option<Vec<u8>>, Error> {
+    let len = file.metadata()?.len();
+    let mut pos = 0;
+    while pos + 8 <= len {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut header = [0; 16];
+        file.read_exact(&mut header[..8])?;
+        let (size, header_len) = match be(&header[..4]) {
+            0 => (len - pos, 8),
+            1 => {
+                file.read_exact(&mut header[8..])?;
+                (u64::from_be_bytes(header[8..].try_into()?), 16)
+            }
+            size => (size as u64, 8),
+        };
+        if size < header_len {
+            bail!("corrupt box at offset {}", pos);
+        }
+        if &header[4..8] == b"moov" {
+            let contents_len = size - header_len;
+            if contents_len > MAX_MOOV {
+                bail!("moov box too large ({} bytes)", contents_len);
+            }
+            let mut moov = vec![0; contents_len as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(Some(moov));
+        }
+        pos += size;
+    }
+    Ok(None)
+}
+
+/// The type and contents of a track's first sample entry; the type is the
+/// codec.
+fn sample_entry(trak: &[u8]) -> Option<(&[u8; 4], &[u8])> {
+    find_box(trak, b"mdia")
+        .and_then(|mdia| find_box(mdia, b"minf"))
+        .and_then(|minf| find_box(minf, b"stbl"))
+        .and_then(|stbl| find_box(stbl, b"stsd"))
+        .and_then(|stsd| boxes(stsd.get(8..)?).next())
+}
+
+fn parse_moov(moov: &[u8]) -> Probe {
+    let mut probe = Probe::default();
+    if let Some(mvhd) = find_box(moov, b"mvhd") {
+        let fields = match mvhd.first() {
+            Some(0) => mvhd.get(4..8).zip(mvhd.get(12..16)).zip(mvhd.get(16..20)),
+            Some(1) => mvhd.get(4..12).zip(mvhd.get(20..24)).zip(mvhd.get(24..32)),
+            _ => None,
+        };
+        if let Some(((created, timescale), duration)) = fields {
+            let (timescale, duration) = (be(timescale), be64(duration));
+            probe.duration_secs = (timescale > 0).then(|| duration as f64 / timescale as f64);
+            probe.creation_date = match be64(created) as i64 {
+                0 => None,
+                secs => format_date(DateTime::from_timestamp(secs - MP4_EPOCH_OFFSET, 0)),
+            };
+        }
+    }
+
+    let track = |handler: &[u8]| boxes(moov)
+        .filter(|(kind, _)| *kind == b"trak")
+        .map(|(_, trak)| trak)
+        .find(|trak| {
+            find_box(trak, b"mdia").and_then(|mdia| find_box(mdia, b"hdlr")).and_then(|hdlr| hdlr.get(8..12)) == Some(handler)
+        });
+    if let Some(trak) = track(b"vide") {
+        // Width and height end the track header, in 16.16 fixed point
+        probe.dimensions = find_box(trak, b"tkhd")
+            .and_then(|tkhd| tkhd.get(tkhd.len().checked_sub(8)?..))
+            .map(|size| (be(&size[..4]) >> 16, be(&size[4..]) >> 16))
+            .filter(|&(width, height)| width > 0 && height > 0);
+        probe.codec = sample_entry(trak).map(|(kind, _)| codec_name(kind));
+    }
+    if let Some(trak) = track(b"soun") {
+        let entry = sample_entry(trak);
+        probe.audio = Some(Audio {
+            codec: entry.map(|(kind, _)| codec_name(kind)),
+            // After the reserved bytes, data reference index and version
+            channels: entry.and_then(|(_, entry)| entry.get(16..18)).map(be).filter(|channels| *channels > 0),
+        });
+    }
+
+    // `©xyz`: a 16-bit length and language, then an ISO 6709 location
+    probe.gps = find_box(moov, b"udta")
+        .and_then(|udta| find_box(udta, b"\xA9xyz"))
+        .and_then(|xyz| std::str::from_utf8(xyz.get(4..4 + be(xyz.get(..2)?) as usize)?).ok())
+        .and_then(parse_iso6709);
+    probe
+}
+
+fn be64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |value, &b| value << 8 | b as u64)
+}
+
+fn format_date(date: Option<DateTime<Utc>>) -> Option<String> {
+    date.map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+/// ffprobe's name for a sample entry type.
+fn codec_name(fourcc: &[u8; 4]) -> String {
+    match fourcc {
+        b"avc1" | b"avc3" => "h264",
+        b"hvc1" | b"hev1" => "hevc",
+        b"av01" => "av1",
+        b"vp09" => "vp9",
+        b"mp4v" => "mpeg4",
+        b"mp4a" => "aac",
+        b"sowt" | b"lpcm" => "pcm_s16le",
+        b"twos" => "pcm_s16be",
+        b"alac" => "alac",
+        b"ac-3" => "ac3",
+        b"jpeg" | b"mjpa" => "mjpeg",
+        b"apch" | b"apcn" | b"apcs" | b"apco" | b"ap4h" | b"ap4x" => "prores",
+        other => return String::from_utf8_lossy(other).trim().to_string(),
+    }.to_string()
+}
+
+/// The parts of `ffprobe -print_format json -show_format -show_streams`
+/// output we keep.
+fn parse_ffprobe(json: &serde_json::Value) -> Probe {
+    let format = &json["format"];
+    // Cover art shows up as a video stream too
+    let video = json["streams"].as_array().and_then(|streams| {
+        streams.iter().find(|s| s["codec_type"] == "video" && s["disposition"]["attached_pic"] != 1)
+    });
+    let tag = |name: &str| {
+        format["tags"][name].as_str().or_else(|| video.and_then(|v| v["tags"][name].as_str()))
+    };
+    let dimension = |name: &str| video.and_then(|v| v[name].as_u64()).and_then(|n| u32::try_from(n).ok());
+    let audio = json["streams"].as_array().and_then(|streams| streams.iter().find(|s| s["codec_type"] == "audio"));
+    Probe {
+        duration_secs: format["duration"].as_str().and_then(|d| d.parse().ok()),
+        dimensions: dimension("width").zip(dimension("height")),
+        codec: video.and_then(|v| v["codec_name"].as_str()).map(String::from),
+        creation_date: tag("creation_time").and_then(|time| {
+            format_date(DateTime::parse_from_rfc3339(time).ok().map(|date| date.with_timezone(&Utc)))
+        }),
+        gps: tag("location").or_else(|| tag("com.apple.quicktime.location.ISO6709")).and_then(parse_iso6709),
+        audio: audio.map(|audio| Audio {
+            codec: audio["codec_name"].as_str().map(String::from),
+            channels: audio["channels"].as_u64().and_then(|n| u32::try_from(n).ok()),
+        }),
+    }
+}
+
+/// Latitude and longitude from an ISO 6709 string such as
+/// "+38.7139-009.1394+052.000/".
+fn parse_iso6709(s: &str) -> Option<(f64, f64)> {
+    let s = s.trim().trim_end_matches('/');
+    let starts: Vec<usize> = s.match_indices(['+', '-']).map(|(i, _)| i).collect();
+    if starts.first() != Some(&0) || starts.len() < 2 {
+        return None;
+    }
+    let end = starts.get(2).copied().unwrap_or(s.len());
+    let latitude: f64 = s[..starts[1]].parse().ok()?;
+    let longitude: f64 = s[starts[1]..end].parse().ok()?;
+    (latitude.abs() <= 90.0 && longitude.abs() <= 180.0).then_some((latitude, longitude))
+}
+
+#[cfg(all(test, feature = "video"))]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_read_moov() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("IMG_0001.MOV");
+        std::fs::write(&path, mp4(12.5, (1920, 1080), Some("+38.7139-009.1394+052.000/")))?;
+        assert!(is_video(&path));
+        assert_eq!(format_for(Path::new("clip.m4v")), Some("Mp4"));
+
+        let moov = read_moov(&mut File::open(&path)?)?.expect("a moov box");
+        assert_eq!(parse_moov(&moov), Probe {
+            duration_secs: Some(12.5),
+            dimensions: Some((1920, 1080)),
+            codec: Some(String::from("hevc")),
+            creation_date: Some(String::from("2024-05-01 18:30:00")),
+            gps: Some((38.7139, -9.1394)),
+            audio: Some(Audio { codec: Some(String::from("aac")), channels: Some(2) }),
+        });
+        // MP4 and QuickTime don't need ffprobe
+        let missing = VideoConfig { ffprobe: String::from("no-such-ffprobe"), ..VideoConfig::default() };
+        assert_eq!(probe(&path, &missing)?.codec.as_deref(), Some("hevc"));
+
+        std::fs::write(&path, b"\0\0\0\x10ftypqt  \0\0\0\0")?;
+        assert_eq!(read_moov(&mut File::open(&path)?)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_ffprobe() {
+        let json = serde_json::json!({
+            "streams": [
+                { "codec_type": "audio", "codec_name": "pcm_s16le", "channels": 1 },
+                { "codec_type": "video", "codec_name": "mjpeg", "width": 160, "height": 120, "disposition": { "attached_pic": 1 } },
+                { "codec_type": "video", "codec_name": "mpeg4", "width": 640, "height": 480, "disposition": { "attached_pic": 0 } },
+            ],
+            "format": {
+                "duration": "95.040000",
+                "tags": { "creation_time": "2009-07-14T09:05:00.000000Z", "location": "+51.5007-000.1246/" },
+            },
+        });
+        assert_eq!(parse_ffprobe(&json), Probe {
+            duration_secs: Some(95.04),
+            dimensions: Some((640, 480)),
+            codec: Some(String::from("mpeg4")),
+            creation_date: Some(String::from("2009-07-14 09:05:00")),
+            gps: Some((51.5007, -0.1246)),
+            audio: Some(Audio { codec: Some(String::from("pcm_s16le")), channels: Some(1) }),
+        });
+        assert_eq!(parse_ffprobe(&serde_json::json!({})), Probe::default());
+        assert_eq!(parse_iso6709("garbage"), None);
+        assert_eq!(parse_iso6709("+95.0+010.0/"), None);
+    }
+
+    #[test]
+    fn test_parse_showinfo() {
+        let log = "Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'party.mp4':
+[Parsed_showinfo_1 @ 0x600] config in time_base: 1/15360, frame_rate: 30/1
+[Parsed_showinfo_1 @ 0x600] n:   0 pts: 196608 pts_time:12.8    duration:    512 pos: 4745 fmt:yuv420p
+[Parsed_showinfo_1 @ 0x600] n:   1 pts:2044928 pts_time:133.133 duration:    512 pos: 9312 fmt:yuv420p
+";
+        assert_eq!(parse_showinfo(log), vec![12.8, 133.133]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_frame_with_configured_ffmpeg() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let config = VideoConfig { ffmpeg: fake_tool(dir.path(), "printf frame")?, ..VideoConfig::default() };
+        assert_eq!(frame(Path::new("clip.mp4"), Some(9.0), &config)?, b"frame");
+
+        let missing = VideoConfig { ffmpeg: String::from("no-such-ffmpeg"), ..VideoConfig::default() };
+        assert!(frame(Path::new("clip.mp4"), None, &missing).is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_audio_metadata() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let summary = |lufs: &str| format!("printf '[Parsed_ebur128_0 @ 0x6] t: 9.9 M: -71.2 S: -70.4 I: -70.0 LUFS\\n  Integrated loudness:\\n    I:         {} LUFS\\n    Threshold: -70.0 LUFS\\n' >&2", lufs);
+        let stereo = Audio { codec: Some(String::from("aac")), channels: Some(2) };
+        let fields = |metadata: Vec<ExternalMetadata>| -> Vec<(String, String)> {
+            metadata.into_iter().map(|entry| (entry.field, entry.value)).collect()
+        };
+
+        let config = VideoConfig { ffmpeg: fake_tool(dir.path(), &summary("-23.4"))?, ..VideoConfig::default() };
+        assert_eq!(fields(audio_metadata(Path::new("party.mp4"), Some(&stereo), &config)), [
+            ("codec", "aac"), ("channels", "2"), ("loudness_lufs", "-23.4"), ("silent", "false"),
+        ].map(|(field, value)| (field.to_string(), value.to_string())));
+
+        let config = VideoConfig { ffmpeg: fake_tool(dir.path(), &summary("-70.0"))?, ..VideoConfig::default() };
+        assert_eq!(fields(audio_metadata(Path::new("hum.mp4"), Some(&stereo), &config)).last(), Some(&(String::from("silent"), String::from("true"))));
+        // No sound at all is silent too
+        assert_eq!(fields(audio_metadata(Path::new("timelapse.mp4"), None, &config)), [(String::from("silent"), String::from("true"))]);
+        // Nothing said of loudness unless it was measured
+        let unmeasured = VideoConfig { loudness: false, ..config };
+        assert_eq!(audio_metadata(Path::new("party.mp4"), Some(&stereo), &unmeasured).len(), 2);
+        Ok(())
+    }
+}
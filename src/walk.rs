@@ -0,0 +1,442 @@
+//! Which files under a folder a scan looks at. Folders matching an
+//! `exclude` pattern aren't gone into and files matching one are skipped;
+//! given `include` patterns, only files matching one of them are looked
+//! at. A `.photoignore` file excludes more from the folder it's in, one
+//! pattern a line (`#` starts a comment), like a simple `.gitignore`.
+//!
+//! Patterns are globs, whatever the case: `*` and `?` within a name, `**`
+//! across folders, `[abc]`, `[a-z]` and `[!a]` for one character. Without
+//! a `/` a pattern matches a file or folder name anywhere (`@eaDir`,
+//! `*.tmp`); with one it matches the path from the folder scanned, or the
+//! one the ignore file is in (`2019/phone-backup`, `**/cache/*.jpg`). A
+//! trailing `/` matches only folders.
+//!
+//! `max_depth` stops a scan going more than that many folders down, 0
+//! being only the folder's own files, and `min_size` and `max_size` skip
+//! files smaller or bigger than a size like "50KB" or "2GB".
+//!
+//! Symlinks are only followed with `follow_symlinks`, and a link back to a
+//! folder above it is reported and not gone into. A file reached more than
+//! once, through links or under hard-linked names, is only found the first
+//! time.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::Error;
+use serde::Deserialize;
+use walkdir::WalkDir;
+use crate::error::{CliError, ErrorKind};
+
+pub const IGNORE_FILE: &str = ".photoignore";
+
+/// NAS thumbnail folders, desktop thumbnail caches, recycle bins and the
+/// snapshots of `scan --snapshot`.
+const DEFAULT_EXCLUDE: &[&str] = &["@eaDir", ".@__thumb", ".thumbnails", "#recycle", "$RECYCLE.BIN", ".Trash-*", ".PhotoCataloger-snapshot-*"];
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanConfig {
+    pub exclude: Vec<String>,
+    /// Empty for every supported file.
+    pub include: Vec<String>,
+    pub follow_symlinks: bool,
+    pub max_depth: Option<usize>,
+    /// Sizes as `parse_size` reads them
+    pub min_size: Option<String>,
+    pub max_size: Option<String>,
+    /// Where each scan writes a JSON report of what it did
+    pub report: Option<PathBuf>,
+    /// Failed images a scan may have before it fails, as
+    /// `report::parse_threshold` reads them
+    pub max_failures: Option<String>,
+    /// Scan from a snapshot of the filesystem, where it can take one
+    pub snapshot: bool,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig { exclude: DEFAULT_EXCLUDE.iter().map(|pattern| pattern.to_string()).collect(), include: Vec::new(), follow_symlinks: false, max_depth: None, min_size: None, max_size: None, report: None, max_failures: None, snapshot: false }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    /// One glob per path component; `None` for `**`.
+    parts: Vec<Option<Vec<Token>>>,
+    dirs_only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Char(char),
+    Any,
+    Star,
+    /// Ranges, and whether it's negated
+    Set(Vec<(char, char)>, bool),
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Pattern, Error> {
+        let invalid = |why: &str| CliError::new(ErrorKind::Config, format!("invalid pattern {:?}: {}", pattern, why));
+        let trimmed = pattern.trim();
+        let dirs_only = trimmed.ends_with('/');
+        let trimmed = trimmed.trim_end_matches('/');
+        if trimmed.is_empty() {
+            return Err(invalid("it's empty").into());
+        }
+        let anchored = trimmed.contains('/');
+        let mut parts = Vec::new();
+        if !anchored {
+            parts.push(None);
+        }
+        for component in trimmed.trim_start_matches('/').split('/').filter(|component| !component.is_empty()) {
+            if component == "**" {
+                parts.push(None);
+                continue;
+            }
+            let mut tokens = Vec::new();
+            let mut chars = component.chars().map(|c| c.to_lowercase().next().unwrap_or(c));
+            while let Some(c) = chars.next() {
+                tokens.push(match c {
+                    '*' => Token::Star,
+                    '?' => Token::Any,
+                    '[' => {
+                        let mut set: Vec<char> = Vec::new();
+                        let mut closed = false;
+                        for c in chars.by_ref() {
+                            if c == ']' && !set.is_empty() {
+                                closed = true;
+                                break;
+                            }
+                            set.push(c);
+                        }
+                        if !closed {
+                            return Err(invalid("a [ isn't closed").into());
+                        }
+                        let negated = matches!(set.first(), Some('!' | '^'));
+                        let set = &set[negated as usize..];
+                        let mut ranges = Vec::new();
+                        let mut i = 0;
+                        while i < set.len() {
+                            match set.get(i + 1..i + 3) {
+                                Some(['-', end]) => {
+                                    ranges.push((set[i], *end));
+                                    i += 3;
+                                }
+                                _ => {
+                                    ranges.push((set[i], set[i]));
+                                    i += 1;
+                                }
+                            }
+                        }
+                        Token::Set(ranges, negated)
+                    }
+                    c => Token::Char(c),
+                });
+            }
+            parts.push(Some(tokens));
+        }
+        Ok(Pattern { parts, dirs_only })
+    }
+
+    /// Whether the file or folder at `path`, relative to where the pattern
+    /// applies from, matches.
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dirs_only && !is_dir {
+            return false;
+        }
+        let names: Vec<String> = path.components().map(|component| component.as_os_str().to_string_lossy().to_lowercase()).collect();
+        let names: Vec<Vec<char>> = names.iter().map(|name| name.chars().collect()).collect();
+        matches_parts(&self.parts, &names)
+    }
+}
+
+fn matches_parts(parts: &[Option<Vec<Token>>], names: &[Vec<char>]) -> bool {
+    match parts.split_first() {
+        None => names.is_empty(),
+        Some((None, rest)) => (0..=names.len()).any(|skip| matches_parts(rest, &names[skip..])),
+        Some((Some(tokens), rest)) => match names.split_first() {
+            Some((name, names)) => matches_name(tokens, name) && matches_parts(rest, names),
+            None => false,
+        },
+    }
+}
+
+fn matches_name(tokens: &[Token], name: &[char]) -> bool {
+    match tokens.split_first() {
+        None => name.is_empty(),
+        Some((Token::Star, rest)) => (0..=name.len()).any(|skip| matches_name(rest, &name[skip..])),
+        Some((token, rest)) => match name.split_first() {
+            Some((c, name)) => {
+                let matched = match token {
+                    Token::Char(expected) => c == expected,
+                    Token::Any => true,
+                    Token::Set(ranges, negated) => ranges.iter().any(|(from, to)| (from..=to).contains(&c)) != *negated,
+                    Token::Star => unreachable!("matched above"),
+                };
+                matched && matches_name(rest, name)
+            }
+            None => false,
+        },
+    }
+}
+
+/// The configured patterns, ready to walk folders with.
+pub struct Filter {
+    exclude: Vec<Pattern>,
+    include: Vec<Pattern>,
+    follow_symlinks: bool,
+    max_depth: Option<usize>,
+    /// Smallest and biggest, in bytes
+    sizes: (u64, u64),
+}
+
+/// What a file is however it's reached: its device and inode where there
+/// are such, else the path its links lead to.
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(not(unix))]
+type FileId = PathBuf;
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(path: &Path) -> Option<FileId> {
+    fs::canonicalize(path).ok()
+}
+
+impl Filter {
+    pub fn new(config: &ScanConfig) -> Result<Filter, Error> {
+        let parse = |patterns: &[String]| patterns.iter().map(|pattern| Pattern::parse(pattern)).collect::<Result<Vec<_>, _>>();
+        let size = |size: &Option<String>| size.as_deref().map(parse_size).transpose().map_err(|e| CliError::new(ErrorKind::Config, e));
+        let sizes = (size(&config.min_size)?.unwrap_or(0), size(&config.max_size)?.unwrap_or(u64::MAX));
+        Ok(Filter {
+            exclude: parse(&config.exclude)?,
+            include: parse(&config.include)?,
+            follow_symlinks: config.follow_symlinks,
+            max_depth: config.max_depth,
+            sizes,
+        })
+    }
+
+    /// Every file under `root` the patterns and ignore files let through,
+    /// in the order they're found.
+    pub fn files<'a>(&'a self, root: &'a Path) -> impl Iterator<Item = PathBuf> + 'a {
+        // The ignore files of the folders above the entry being looked at,
+        // with how deep each folder is
+        let ignored: RefCell<Vec<(usize, PathBuf, Vec<Pattern>)>> = RefCell::new(Vec::new());
+        // Where each file was first found
+        let mut found: HashMap<FileId, PathBuf> = HashMap::new();
+        WalkDir::new(root)
+            .follow_links(self.follow_symlinks)
+            .max_depth(self.max_depth.map_or(usize::MAX, |depth| depth.saturating_add(1)))
+            .into_iter()
+            .filter_entry(move |entry| {
+                let mut ignored = ignored.borrow_mut();
+                ignored.retain(|(depth, _, _)| *depth < entry.depth());
+                let is_dir = entry.file_type().is_dir();
+                if entry.depth() > 0 {
+                    let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+                    let excluded = self.exclude.iter().any(|pattern| pattern.matches(relative, is_dir))
+                        || ignored.iter().any(|(_, dir, patterns)| {
+                            let relative = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+                            patterns.iter().any(|pattern| pattern.matches(relative, is_dir))
+                        });
+                    if excluded {
+                        return false;
+                    }
+                }
+                if is_dir {
+                    let patterns = read_ignore_file(&entry.path().join(IGNORE_FILE));
+                    if !patterns.is_empty() {
+                        ignored.push((entry.depth(), entry.path().to_path_buf(), patterns));
+                    }
+                }
+                true
+            })
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    if let (Some(path), Some(ancestor)) = (e.path(), e.loop_ancestor()) {
+                        tracing::warn!("Skipping {}: it links back to {}", path.display(), ancestor.display());
+                    }
+                    None
+                }
+            })
+            .filter(|entry| entry.file_type().is_file())
+            .filter(move |entry| {
+                let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+                self.include.is_empty() || self.include.iter().any(|pattern| pattern.matches(relative, false))
+            })
+            .filter(move |entry| match entry.metadata() {
+                Ok(metadata) => (self.sizes.0..=self.sizes.1).contains(&metadata.len()),
+                Err(_) => true,
+            })
+            .filter(move |entry| {
+                let Some(id) = file_id(entry.path()) else {
+                    return true;
+                };
+                match found.get(&id) {
+                    Some(first) => {
+                        tracing::warn!("Skipping {}: it's the same file as {}", entry.path().display(), first.display());
+                        false
+                    }
+                    None => {
+                        found.insert(id, entry.path().to_path_buf());
+                        true
+                    }
+                }
+            })
+            .map(|entry| entry.into_path())
+    }
+}
+
+/// A size in bytes from e.g. "50KB", "1.5 MB" or "2g", in units of 1024
+/// as `tiering::size` shows them; a plain number is bytes.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid size {:?}; sizes are like \"50KB\" or \"2GB\"", size);
+    let trimmed = size.trim();
+    let split = trimmed.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let unit = unit.trim().to_lowercase();
+    let power = match unit.trim_end_matches("ib").trim_end_matches('b') {
+        "" => 0,
+        "k" => 1,
+        "m" => 2,
+        "g" => 3,
+        "t" => 4,
+        _ => return Err(invalid()),
+    };
+    Ok((number * 1024f64.powi(power)).round() as u64)
+}
+
+/// The patterns in the ignore file at `path`, none if there isn't one.
+/// Bad lines are reported and skipped rather than stopping the scan.
+fn read_ignore_file(path: &Path) -> Vec<Pattern> {
+    let Ok(text) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match Pattern::parse(line) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                tracing::warn!("Skipping a line of {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_pattern() -> Result<(), Error> {
+        let matches = |pattern: &str, path: &str, is_dir: bool| Pattern::parse(pattern).map(|pattern| pattern.matches(Path::new(path), is_dir));
+        assert!(matches("@eaDir", "2023/@eaDir", true)?);
+        assert!(matches("*.tmp", "a/b/photo.TMP", false)?);
+        assert!(!matches("*.tmp", "a/b/photo.jpg", false)?);
+        assert!(matches("IMG_00??.jpg", "IMG_0042.JPG", false)?);
+        assert!(matches("IMG_[0-4]*", "IMG_4200.jpg", false)?);
+        assert!(!matches("IMG_[!0-4]*", "IMG_4200.jpg", false)?);
+        // A path is anchored to the root
+        assert!(matches("2019/backup", "2019/backup", true)?);
+        assert!(!matches("2019/backup", "old/2019/backup", true)?);
+        assert!(matches("**/cache/*.jpg", "a/b/cache/x.jpg", false)?);
+        assert!(matches("2023/**", "2023/07/x.jpg", false)?);
+        assert!(matches("/2023/", "2023", true)?);
+        assert!(!matches("cache/", "cache", false)?);
+        assert!(Pattern::parse("[abc").is_err());
+        assert!(Pattern::parse(" / ").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("50KB"), Ok(50 << 10));
+        assert_eq!(parse_size("1.5 MB"), Ok(3 << 19));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        assert_eq!(parse_size("4GiB"), Ok(4 << 30));
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("5 parsecs").is_err());
+    }
+
+    #[test]
+    fn test_files() -> Result<(), Error> {
+        let dir = tempdir()?;
+        for path in ["a.jpg", "@eaDir/a.jpg", "2023/b.jpg", "2023/b.xmp", "2023/cache/c.jpg", "2024/d.jpg", "2024/old/e.jpg"] {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, "")?;
+        }
+        fs::write(dir.path().join("2024").join(IGNORE_FILE), "# not these\nold\n[broken\n")?;
+        let found = |config: &ScanConfig| -> Result<Vec<String>, Error> {
+            let filter = Filter::new(config)?;
+            let mut found: Vec<String> = filter.files(dir.path())
+                .map(|path| path.strip_prefix(dir.path()).unwrap().to_string_lossy().replace('\\', "/"))
+                .filter(|path| !path.ends_with(IGNORE_FILE))
+                .collect();
+            found.sort();
+            Ok(found)
+        };
+
+        assert_eq!(found(&ScanConfig::default())?, vec!["2023/b.jpg", "2023/b.xmp", "2023/cache/c.jpg", "2024/d.jpg", "a.jpg"]);
+        let config = ScanConfig { exclude: vec![String::from("cache/"), String::from("*.xmp")], include: vec![String::from("2023/**")], ..ScanConfig::default() };
+        assert_eq!(found(&config)?, vec!["2023/b.jpg"]);
+        let config = ScanConfig { exclude: Vec::new(), include: vec![String::from("*.JPG")], ..ScanConfig::default() };
+        assert_eq!(found(&config)?, vec!["2023/b.jpg", "2023/cache/c.jpg", "2024/d.jpg", "@eaDir/a.jpg", "a.jpg"]);
+        let config = ScanConfig { max_depth: Some(1), ..ScanConfig::default() };
+        assert_eq!(found(&config)?, vec!["2023/b.jpg", "2023/b.xmp", "2024/d.jpg", "a.jpg"]);
+        fs::write(dir.path().join("2023/b.jpg"), [0; 2048])?;
+        fs::write(dir.path().join("a.jpg"), [0; 100])?;
+        let config = ScanConfig { min_size: Some(String::from("50")), max_size: Some(String::from("1KB")), ..ScanConfig::default() };
+        assert_eq!(found(&config)?, vec!["a.jpg"]);
+        let config = ScanConfig { min_size: Some(String::from("lots")), ..ScanConfig::default() };
+        assert!(Filter::new(&config).is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks() -> Result<(), Error> {
+        use std::os::unix::fs::symlink;
+        let dir = tempdir()?;
+        let outside = tempdir()?;
+        fs::create_dir_all(dir.path().join("2023"))?;
+        fs::write(dir.path().join("2023/a.jpg"), "")?;
+        fs::write(outside.path().join("b.jpg"), "")?;
+        symlink(outside.path(), dir.path().join("shared"))?;
+        // Hard-linked, and linked back to the folder above
+        fs::hard_link(dir.path().join("2023/a.jpg"), dir.path().join("2023/copy.jpg"))?;
+        symlink(dir.path(), dir.path().join("2023/loop"))?;
+        let found = |follow_symlinks: bool| -> Result<Vec<String>, Error> {
+            let filter = Filter::new(&ScanConfig { follow_symlinks, ..ScanConfig::default() })?;
+            let mut found: Vec<String> = filter.files(dir.path())
+                .map(|path| path.strip_prefix(dir.path()).unwrap().to_string_lossy().into_owned())
+                .collect();
+            found.sort();
+            Ok(found)
+        };
+
+        let files = found(false)?;
+        assert_eq!(files.len(), 1);
+        assert!(files[0] == "2023/a.jpg" || files[0] == "2023/copy.jpg");
+        let files = found(true)?;
+        assert_eq!(files.len(), 2);
+        assert!(files.contains(&String::from("shared/b.jpg")));
+        Ok(())
+    }
+}
@@ -0,0 +1,654 @@
+//! The browser UI `serve` has at `/`: a thumbnail grid with a search box and
+//! filters, and a photo's details with its EXIF and AI description, a map
+//! of where it was taken and its histogram. It's one page working off the
+//! HTTP API, so there's nothing to build or install and it shows what a
+//! frontend of one's own can do.
+//!
+//! Colors are CSS variables, light or dark as the viewer picks (or as
+//! their system is), with `/theme.css` after them for the accent color and
+//! stylesheet of `[web]`, so a gallery can be branded without touching the
+//! page.
+//!
+//! Everything can be done from the keyboard (`?` lists the shortcuts), and
+//! photos' AI descriptions are their alt text, so the gallery reads well
+//! with a screen reader.
+//!
+//! Its layout (the grid's order and size, the search fields shown and the
+//! view it opens with) is kept per user with the theme, as `preferences`.
+
+use crate::config::WebConfig;
+use crate::gallery::escape;
+
+/// The page, with the map tiles `config` has (none in local-only mode,
+/// since the browser would be sending where photos were taken to them).
+pub fn page(config: &WebConfig, local_only: bool) -> String {
+    let tiles = if local_only { "" } else { config.map_tiles.as_str() };
+    INDEX
+        .replace(r#"<meta name="map-tiles" content="">"#, &format!(r#"<meta name="map-tiles" content="{}">"#, escape(tiles)))
+        .replace(r#"<meta name="map-attribution" content="">"#, &format!(r#"<meta name="map-attribution" content="{}">"#, escape(&config.map_attribution)))
+}
+
+const INDEX: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>PhotoCataloger</title>
+<meta name="map-tiles" content="">
+<meta name="map-attribution" content="">
+<style>
+:root { color-scheme: light; --background: #fff; --text: #111; --muted: #666; --surface: #eee; --accent: #2563eb; }
+:root[data-theme="dark"] { color-scheme: dark; --background: #121212; --text: #e8e8e8; --muted: #9a9a9a; --surface: #2a2a2a; }
+@media (prefers-color-scheme: dark) {
+  :root:not([data-theme="light"]) { color-scheme: dark; --background: #121212; --text: #e8e8e8; --muted: #9a9a9a; --surface: #2a2a2a; }
+}
+body { font-family: system-ui, sans-serif; margin: 0 auto; max-width: 1400px; padding: 1rem; background: var(--background); color: var(--text); accent-color: var(--accent); }
+:focus-visible { outline: 2px solid var(--accent); }
+form > button { background: var(--accent); color: #fff; border: none; border-radius: 4px; padding: 0.4rem 0.8rem; font-size: 1rem; }
+form { display: flex; gap: 0.5rem; flex-wrap: wrap; align-items: center; margin-bottom: 1rem; }
+form input { font-size: 1rem; padding: 0.4rem; }
+#q { flex: 1; min-width: 12rem; }
+.grid { display: grid; grid-template-columns: repeat(auto-fill, minmax(var(--cell, 200px), 1fr)); gap: 0.5rem; }
+.grid[data-density="small"] { --cell: 120px; }
+.grid[data-density="large"] { --cell: 320px; }
+.grid button { margin: 0; padding: 0; border: none; background: none; cursor: pointer; text-align: left; font: inherit; }
+.grid img { width: 100%; aspect-ratio: 1; object-fit: cover; display: block; background: var(--surface); }
+.grid span { display: block; font-size: 0.8rem; overflow: hidden; white-space: nowrap; text-overflow: ellipsis; }
+.grid .rejected img { opacity: 0.35; }
+.grid button { position: relative; }
+.grid .catalog { position: absolute; top: 0.3rem; left: 0.3rem; padding: 0 0.3rem; border-radius: 3px; font-size: 0.75rem; background: var(--surface); opacity: 0.9; }
+#more { display: block; margin: 1rem auto; }
+#status { color: var(--muted); }
+dialog { max-width: min(1200px, 95vw); max-height: 95vh; background: var(--background); color: var(--text); }
+dialog img, dialog video { max-width: 100%; max-height: 70vh; display: block; margin: 0 auto; }
+dl { display: grid; grid-template-columns: max-content 1fr; gap: 0.2rem 1rem; }
+dt { font-weight: bold; }
+.panels { display: flex; flex-wrap: wrap; gap: 1rem 2rem; align-items: flex-start; }
+#details { flex: 1; min-width: 18rem; }
+#details h3 { font-size: 1rem; margin: 0.8rem 0 0.3rem; color: var(--muted); }
+figure { margin: 0 0 1rem; }
+figcaption { font-size: 0.8rem; color: var(--muted); margin-top: 0.2rem; }
+#map { position: relative; width: 300px; height: 200px; overflow: hidden; background: var(--surface); }
+#map img { position: absolute; width: 256px; height: 256px; max-width: none; max-height: none; margin: 0; }
+#map .pin { position: absolute; left: 144px; top: 94px; width: 12px; height: 12px; box-sizing: border-box; border-radius: 50%; border: 2px solid #fff; background: var(--accent); }
+#histogram { width: 300px; height: 120px; display: block; background: var(--surface); }
+.skip { position: absolute; left: -999px; }
+.skip:focus { left: 1rem; top: 1rem; z-index: 1; background: var(--background); padding: 0.4rem; }
+kbd { border: 1px solid var(--muted); border-radius: 3px; padding: 0 0.3rem; font: inherit; }
+#tags { margin: 1rem 0; }
+#tags input { flex: 1; }
+#saved, #layout-saved { color: var(--muted); }
+fieldset { border: 1px solid var(--surface); margin: 0 0 0.8rem; }
+</style>
+<link rel="stylesheet" href="/theme.css">
+</head>
+<body>
+<a class="skip" href="#grid">Skip to the photos</a>
+<form id="search" role="search">
+<input id="q" type="search" placeholder="Keyword, or a query like camera:X100V date:2023" aria-label="Search" aria-keyshortcuts="/">
+<input id="place" placeholder="Place" aria-label="Place" size="12">
+<input id="camera" placeholder="Camera" aria-label="Camera" size="12">
+<input id="date" placeholder="2023-07" aria-label="Date" size="8">
+<input id="album" placeholder="Album" aria-label="Album" size="12" hidden>
+<select id="rating" aria-label="Rating" hidden>
+<option value="">Any rating</option>
+<option value="1">★ or more</option>
+<option value="2">★★ or more</option>
+<option value="3">★★★ or more</option>
+<option value="4">★★★★ or more</option>
+<option value="5">★★★★★</option>
+</select>
+<select id="sort" aria-label="Order">
+<option value="path">By folder</option>
+<option value="newest">Newest first</option>
+<option value="oldest">Oldest first</option>
+<option value="rating">Best rated first</option>
+</select>
+<button>Search</button>
+<span id="status" role="status"></span>
+<button type="button" id="help" aria-keyshortcuts="?">Shortcuts</button>
+<button type="button" id="layout-button">Layout</button>
+<button type="button" id="download">Download</button>
+<select id="theme" aria-label="Theme">
+<option value="system">System theme</option>
+<option value="light">Light</option>
+<option value="dark">Dark</option>
+</select>
+</form>
+<main class="grid" id="grid" aria-label="Photos" tabindex="-1"></main>
+<button id="more" hidden>More photos</button>
+<dialog id="photo" aria-labelledby="title" aria-describedby="description">
+<form method="dialog"><button>Close</button></form>
+<h2 id="title"></h2>
+<img id="preview" alt="">
+<video id="player" controls preload="metadata" hidden></video>
+<p id="description"></p>
+<form id="tags">
+<label for="keywords">Keywords</label>
+<input id="keywords" aria-keyshortcuts="t">
+<button>Save</button>
+<span id="saved" role="status"></span>
+</form>
+<div class="panels">
+<div id="details" role="group" aria-label="Details"></div>
+<div>
+<figure id="location" hidden>
+<div id="map" role="img"></div>
+<figcaption><a id="map-link" target="_blank" rel="noopener">Open the map</a> <span id="attribution"></span></figcaption>
+</figure>
+<figure id="levels" hidden>
+<canvas id="histogram" width="256" height="100" role="img" aria-label="Histogram of red, green, blue and luminance"></canvas>
+<figcaption>Red, green, blue and luminance</figcaption>
+</figure>
+</div>
+</div>
+</dialog>
+<dialog id="shortcuts" aria-labelledby="shortcuts-title">
+<h2 id="shortcuts-title">Keyboard shortcuts</h2>
+<dl>
+<dt><kbd>j</kbd> / <kbd>k</kbd></dt><dd>Next and previous photo, in the grid or open</dd>
+<dt><kbd>Enter</kbd></dt><dd>Open the photo</dd>
+<dt><kbd>t</kbd></dt><dd>Edit its keywords</dd>
+<dt><kbd>1</kbd>–<kbd>5</kbd>, <kbd>0</kbd></dt><dd>Rate it, or take its rating away</dd>
+<dt><kbd>f</kbd></dt><dd>Mark it as a favorite, or not</dd>
+<dt><kbd>x</kbd></dt><dd>Reject it, or keep it after all</dd>
+<dt><kbd>/</kbd></dt><dd>Search</dd>
+<dt><kbd>Esc</kbd></dt><dd>Close</dd>
+<dt><kbd>?</kbd></dt><dd>This list</dd>
+</dl>
+<form method="dialog"><button>Close</button></form>
+</dialog>
+<dialog id="layout" aria-labelledby="layout-title">
+<h2 id="layout-title">Layout</h2>
+<form id="layout-form">
+<fieldset>
+<legend>Photo size</legend>
+<label><input type="radio" name="density" value="small"> Small</label>
+<label><input type="radio" name="density" value="medium"> Medium</label>
+<label><input type="radio" name="density" value="large"> Large</label>
+</fieldset>
+<fieldset>
+<legend>Search by</legend>
+<label><input type="checkbox" name="facets" value="place"> Place</label>
+<label><input type="checkbox" name="facets" value="camera"> Camera</label>
+<label><input type="checkbox" name="facets" value="date"> Date</label>
+<label><input type="checkbox" name="facets" value="album"> Album</label>
+<label><input type="checkbox" name="facets" value="rating"> Rating</label>
+</fieldset>
+<fieldset>
+<legend>Open with</legend>
+<input id="landing" placeholder="A query, such as album:Lisbon or favorite:yes" aria-label="Query to open with" size="40">
+<button type="button" id="use-search">This search</button>
+</fieldset>
+<button>Save</button>
+<button type="button" id="layout-close">Cancel</button>
+<span id="layout-saved" role="status"></span>
+</form>
+</dialog>
+<script>
+const PAGE = 100;
+// The search fields there can be, and those shown until someone picks
+const FACETS = ['place', 'camera', 'date', 'album', 'rating'];
+const DEFAULT_FACETS = ['place', 'camera', 'date'];
+const grid = document.getElementById('grid');
+const more = document.getElementById('more');
+const status = document.getElementById('status');
+const photo = document.getElementById('photo');
+let query = '';
+let offset = 0;
+// The photos in the grid, in order, and which is open
+let images = [];
+let current = -1;
+let shown = null;
+
+function value(id) { return document.getElementById(id).value.trim(); }
+
+function isVideo(image) { return ['Mp4', 'QuickTime', 'Avi'].includes(image.format); }
+
+// What a screen reader says for a photo: its AI description, when it has one
+function altText(image) {
+  return image.description || (isVideo(image) ? 'Video ' : 'Photo ') + image.file_name;
+}
+
+// Words typed on their own are a keyword; anything else is a query
+function currentQuery() {
+  const q = value('q');
+  const terms = q && !/^\w+:/.test(q) ? ['keyword:' + q] : (q ? [q] : []);
+  for (const key of FACETS) {
+    if (!document.getElementById(key).hidden && value(key)) terms.push(key + ':' + value(key));
+  }
+  return terms.join(' ');
+}
+
+async function load() {
+  const params = new URLSearchParams({ q: query, limit: PAGE, offset, sort: value('sort') });
+  const response = await fetch('/images?' + params);
+  const body = await response.json();
+  if (!response.ok) {
+    status.textContent = body.error.message;
+    return;
+  }
+  for (const image of body) {
+    const index = images.push(image) - 1;
+    const button = document.createElement('button');
+    const img = document.createElement('img');
+    img.src = image.url + '/thumbnail';
+    img.alt = altText(image);
+    img.loading = 'lazy';
+    button.append(img, document.createElement('span'));
+    // Which catalog it's from, when the server shows several
+    if (image.catalog) {
+      const badge = document.createElement('small');
+      badge.className = 'catalog';
+      badge.textContent = image.catalog;
+      button.append(badge);
+    }
+    button.addEventListener('click', () => show(index));
+    grid.append(button);
+    label(index);
+  }
+  offset += body.length;
+  more.hidden = body.length < PAGE;
+  status.textContent = offset + (offset === 1 ? ' photo' : ' photos') + (more.hidden ? '' : ' so far');
+}
+
+function stars(rating) { return '★'.repeat(rating) + '☆'.repeat(5 - rating); }
+
+// A grid photo's caption: when it was taken, with its rating and flags
+function label(index) {
+  const image = images[index];
+  const button = grid.children[index];
+  const marks = [image.rating ? '★'.repeat(image.rating) : '', image.favorite ? '♥' : '', image.rejected ? 'Rejected' : ''];
+  button.querySelector('span').textContent = [image.creation_date || image.file_name, ...marks].filter(m => m).join(' ');
+  button.classList.toggle('rejected', Boolean(image.rejected));
+}
+
+function bytes(n) {
+  const units = ['bytes', 'KB', 'MB', 'GB'];
+  let unit = 0;
+  while (n >= 1000 && unit < units.length - 1) { n /= 1000; unit++; }
+  return (unit ? n.toFixed(1) : n) + ' ' + units[unit];
+}
+
+function coordinates(i) {
+  const part = (degrees, positive, negative) => Math.abs(degrees).toFixed(5) + '° ' + (degrees < 0 ? negative : positive);
+  return part(i.latitude, 'N', 'S') + ', ' + part(i.longitude, 'E', 'W');
+}
+
+// The details, under headings, leaving out what a photo doesn't have
+const DETAILS = [
+  ['Photo', [
+    ['Taken', i => i.creation_date],
+    ['Size', i => i.width && i.width + ' × ' + i.height],
+    ['Length', i => i.duration_secs && Math.round(i.duration_secs) + ' s'],
+    ['Format', i => i.format],
+    ['File size', i => i.file_size && bytes(i.file_size)],
+  ]],
+  ['Camera', [
+    ['Camera', i => [i.camera_make, i.camera_model].filter(p => p).join(' ')],
+    ['Lens', i => i.lens_model],
+    ['Serial number', i => i.camera_serial],
+  ]],
+  ['Exposure', [
+    ['Focal length', i => i.focal_length && i.focal_length + ' mm'],
+    ['Aperture', i => i.aperture && 'f/' + i.aperture],
+    ['Shutter', i => i.exposure_time && (i.exposure_time < 1 ? '1/' + Math.round(1 / i.exposure_time) : i.exposure_time) + ' s'],
+    ['ISO', i => i.iso],
+    ['Flash', i => i.flash_fired === null ? null : (i.flash_fired ? 'Fired' : 'Off')],
+  ]],
+  ['Location', [
+    ['Place', i => [i.city, i.region, i.country].filter(p => p).join(', ')],
+    ['Coordinates', i => i.latitude !== null && i.longitude !== null && coordinates(i)],
+  ]],
+  ['Catalog', [
+    ['Rating', i => i.rating ? stars(i.rating) : null],
+    ['Favorite', i => i.favorite ? 'Yes' : null],
+    ['Rejected', i => i.rejected ? 'Yes' : null],
+    ['Keywords', i => i.keywords],
+    ['File', i => i.path],
+  ]],
+];
+
+// A few map tiles around where it was taken, with a pin there
+const mapTiles = document.querySelector('meta[name="map-tiles"]').content;
+const MAP_ZOOM = 14;
+const MAP_WIDTH = 300;
+const MAP_HEIGHT = 200;
+function showMap(image) {
+  const location = document.getElementById('location');
+  location.hidden = image.latitude === null || image.longitude === null;
+  if (location.hidden) return;
+  const [latitude, longitude] = [image.latitude, image.longitude];
+  document.getElementById('map-link').href = 'https://www.openstreetmap.org/?mlat=' + latitude + '&mlon=' + longitude + '#map=' + MAP_ZOOM + '/' + latitude + '/' + longitude;
+  document.getElementById('attribution').textContent = mapTiles ? document.querySelector('meta[name="map-attribution"]').content : '';
+  const map = document.getElementById('map');
+  map.replaceChildren();
+  map.hidden = !mapTiles;
+  if (!mapTiles) return;
+  map.setAttribute('aria-label', 'Map of where it was taken, ' + coordinates(image));
+  // Web Mercator, in tiles
+  const n = 2 ** MAP_ZOOM;
+  const x = (longitude + 180) / 360 * n;
+  const radians = latitude * Math.PI / 180;
+  const y = (1 - Math.log(Math.tan(radians) + 1 / Math.cos(radians)) / Math.PI) / 2 * n;
+  for (let dx = -1; dx <= 1; dx++) {
+    for (let dy = -1; dy <= 1; dy++) {
+      const [tx, ty] = [Math.floor(x) + dx, Math.floor(y) + dy];
+      if (ty < 0 || ty >= n) continue;
+      const tile = document.createElement('img');
+      tile.alt = '';
+      tile.src = mapTiles.replace('{z}', MAP_ZOOM).replace('{x}', (tx % n + n) % n).replace('{y}', ty);
+      tile.style.left = Math.round((tx - x) * 256 + MAP_WIDTH / 2) + 'px';
+      tile.style.top = Math.round((ty - y) * 256 + MAP_HEIGHT / 2) + 'px';
+      map.append(tile);
+    }
+  }
+  const pin = document.createElement('span');
+  pin.className = 'pin';
+  map.append(pin);
+}
+
+// Worked out by the server the first time, so it's drawn once it comes
+async function showHistogram(image) {
+  const levels = document.getElementById('levels');
+  levels.hidden = true;
+  if (isVideo(image)) return;
+  const response = await fetch(image.url + '/histogram');
+  // Moved on to another photo meanwhile
+  if (!response.ok || shown !== image) return;
+  const histogram = await response.json();
+  const canvas = document.getElementById('histogram');
+  const context = canvas.getContext('2d');
+  context.clearRect(0, 0, canvas.width, canvas.height);
+  // Scaled to the highest level short of pure black and white, where
+  // clipped pixels pile up
+  const channels = ['luminance', 'red', 'green', 'blue'];
+  const peak = Math.max(1, ...channels.flatMap(channel => histogram[channel].slice(1, -1)));
+  const draw = (counts, color, filled) => {
+    context.beginPath();
+    context.moveTo(0, canvas.height);
+    counts.forEach((count, level) => context.lineTo(level, canvas.height * (1 - Math.min(1, count / peak))));
+    context.lineTo(counts.length - 1, canvas.height);
+    if (filled) {
+      context.fillStyle = color;
+      context.fill();
+    } else {
+      context.strokeStyle = color;
+      context.stroke();
+    }
+  };
+  draw(histogram.luminance, getComputedStyle(document.documentElement).getPropertyValue('--muted'), true);
+  draw(histogram.red, '#e53935');
+  draw(histogram.green, '#43a047');
+  draw(histogram.blue, '#1e88e5');
+  levels.hidden = false;
+}
+
+async function show(index) {
+  const url = images[index].url;
+  const image = await (await fetch(url)).json();
+  image.url = url;
+  current = index;
+  shown = image;
+  const preview = document.getElementById('preview');
+  const player = document.getElementById('player');
+  // Videos the browser can't play come transcoded, so the first view waits
+  const video = isVideo(image);
+  preview.hidden = video;
+  player.hidden = !video;
+  if (video) {
+    player.poster = url + '/thumbnail?size=1600';
+    player.src = url + '/video';
+    player.setAttribute('aria-label', altText(image));
+  } else {
+    player.removeAttribute('src');
+    preview.src = url + '/thumbnail?size=1600';
+    preview.alt = altText(image);
+  }
+  const catalog = images[index].catalog;
+  document.getElementById('title').textContent = image.file_name + (catalog ? ' (' + catalog + ')' : '');
+  document.getElementById('description').textContent = image.description || '';
+  document.getElementById('keywords').value = image.keywords || '';
+  document.getElementById('saved').textContent = '';
+  showDetails(image);
+  showMap(image);
+  showHistogram(image);
+  if (!photo.open) photo.showModal();
+}
+
+function showDetails(image) {
+  const details = document.getElementById('details');
+  details.replaceChildren();
+  for (const [heading, fields] of DETAILS) {
+    const list = document.createElement('dl');
+    for (const [label, get] of fields) {
+      const text = get(image);
+      if (text === null || text === undefined || text === '' || text === false) continue;
+      const dt = document.createElement('dt');
+      dt.textContent = label;
+      const dd = document.createElement('dd');
+      dd.textContent = text;
+      list.append(dt, dd);
+    }
+    if (!list.children.length) continue;
+    const h3 = document.createElement('h3');
+    h3.textContent = heading;
+    details.append(h3, list);
+  }
+}
+
+// Rate or flag the open photo, or the one picked in the grid; `change`
+// makes the edit from what the photo has now
+async function cull(change, said) {
+  const index = photo.open ? current : [...grid.children].indexOf(document.activeElement);
+  if (index < 0) return;
+  const image = images[index];
+  const edit = change(image);
+  // Inside the dialog while it's open, since the page behind it is inert
+  const announce = photo.open ? document.getElementById('saved') : status;
+  const response = await fetch(image.url, {
+    method: 'PATCH',
+    headers: { 'content-type': 'application/json' },
+    body: JSON.stringify(edit),
+  });
+  const body = await response.json();
+  if (!response.ok) {
+    announce.textContent = body.error.message;
+    return;
+  }
+  Object.assign(image, edit);
+  label(index);
+  if (photo.open && shown.url === image.url) {
+    Object.assign(shown, edit);
+    showDetails(shown);
+  }
+  announce.textContent = said(image);
+}
+
+// The next or previous photo, loading more of the grid past its end
+async function step(by) {
+  const buttons = grid.children;
+  const from = photo.open ? current : [...buttons].indexOf(document.activeElement);
+  const to = from < 0 ? 0 : from + by;
+  if (to >= images.length && !more.hidden) await load();
+  if (to < 0 || to >= images.length) return;
+  if (photo.open) show(to);
+  else buttons[to].focus();
+}
+
+document.getElementById('tags').addEventListener('submit', async event => {
+  event.preventDefault();
+  const saved = document.getElementById('saved');
+  const response = await fetch(shown.url, {
+    method: 'PATCH',
+    headers: { 'content-type': 'application/json' },
+    body: JSON.stringify({ keywords: value('keywords'), version: shown.version }),
+  });
+  const body = await response.json();
+  if (!response.ok) {
+    saved.textContent = body.error.message;
+    return;
+  }
+  shown.version = body.version;
+  shown.keywords = images[current].keywords = value('keywords');
+  saved.textContent = body.conflict ? 'Saved, over an edit made meanwhile' : 'Saved';
+});
+
+document.addEventListener('keydown', event => {
+  if (event.ctrlKey || event.metaKey || event.altKey) return;
+  // Typing in a field
+  if (event.target.closest('input, select, textarea')) return;
+  switch (event.key) {
+    case 'j': step(1); break;
+    case 'k': step(-1); break;
+    case 't':
+      if (!photo.open) {
+        const index = [...grid.children].indexOf(document.activeElement);
+        if (index < 0) return;
+        show(index).then(() => document.getElementById('keywords').focus());
+      } else {
+        document.getElementById('keywords').focus();
+      }
+      break;
+    case '0': case '1': case '2': case '3': case '4': case '5': {
+      const rating = Number(event.key);
+      cull(() => ({ rating }), () => rating ? 'Rated ' + rating + (rating === 1 ? ' star' : ' stars') : 'Rating taken away');
+      break;
+    }
+    case 'f': cull(image => ({ favorite: !image.favorite }), image => image.favorite ? 'Marked as a favorite' : 'No longer a favorite'); break;
+    case 'x': cull(image => ({ rejected: !image.rejected }), image => image.rejected ? 'Rejected' : 'Kept'); break;
+    case '/':
+      if (photo.open) return;
+      document.getElementById('q').focus();
+      break;
+    case '?': document.getElementById('shortcuts').showModal(); break;
+    default: return;
+  }
+  event.preventDefault();
+});
+document.getElementById('help').addEventListener('click', () => document.getElementById('shortcuts').showModal());
+
+function search() {
+  query = currentQuery();
+  offset = 0;
+  images = [];
+  grid.replaceChildren();
+  return load();
+}
+
+document.getElementById('search').addEventListener('submit', event => {
+  event.preventDefault();
+  search();
+});
+photo.addEventListener('close', () => {
+  document.getElementById('player').pause();
+  // Back where j and k left off
+  grid.children[current]?.focus();
+});
+more.addEventListener('click', load);
+
+// The photos searched for, as a ZIP the server makes in the background;
+// the page follows it being made and offers it once it's ready
+document.getElementById('download').addEventListener('click', async () => {
+  const response = await fetch('/downloads', { method: 'POST', headers: { 'content-type': 'application/json' }, body: JSON.stringify({ query }) });
+  const body = await response.json();
+  if (!response.ok) {
+    status.textContent = body.error.message;
+    return;
+  }
+  const events = new EventSource('/jobs/' + body.id + '/events');
+  events.addEventListener('status', event => {
+    const job = JSON.parse(event.data);
+    if (job.state === 'running') {
+      status.textContent = 'Zipping ' + (job.total === null ? '' : job.total + ' photos ') + (job.percent === null ? '' : Math.round(job.percent) + '%');
+      return;
+    }
+    events.close();
+    if (job.state !== 'completed') {
+      status.textContent = 'The download was ' + job.state + (job.message ? ': ' + job.message : '');
+      return;
+    }
+    const link = document.createElement('a');
+    link.href = '/downloads/' + job.id;
+    link.textContent = 'Download ready (' + job.done + (job.done === 1 ? ' photo)' : ' photos)');
+    status.replaceChildren(link);
+  });
+});
+
+// The theme is kept per user, on the server
+const theme = document.getElementById('theme');
+const systemDark = window.matchMedia('(prefers-color-scheme: dark)');
+function applyTheme(name) {
+  theme.value = name;
+  // Always set, so branding stylesheets only need to match data-theme
+  document.documentElement.dataset.theme = name === 'system' ? (systemDark.matches ? 'dark' : 'light') : name;
+}
+systemDark.addEventListener('change', () => applyTheme(theme.value));
+theme.addEventListener('change', () => {
+  applyTheme(theme.value);
+  savePreferences({ theme: theme.value });
+});
+
+function savePreferences(changes) {
+  return fetch('/preferences', { method: 'PUT', headers: { 'content-type': 'application/json' }, body: JSON.stringify(changes) });
+}
+
+// So is the layout: the grid's order and size, the search fields shown,
+// and the query it opens with
+const sort = document.getElementById('sort');
+const layout = document.getElementById('layout');
+const layoutForm = document.getElementById('layout-form');
+let landing = '';
+function shownFacets(preferences) {
+  return preferences.facets === undefined ? DEFAULT_FACETS : preferences.facets.split(',').map(f => f.trim()).filter(f => f);
+}
+function applyLayout(preferences) {
+  sort.value = preferences.sort || 'path';
+  grid.dataset.density = preferences.density || 'medium';
+  const facets = shownFacets(preferences);
+  for (const facet of FACETS) document.getElementById(facet).hidden = !facets.includes(facet);
+  landing = preferences.landing || '';
+}
+sort.addEventListener('change', () => {
+  savePreferences({ sort: sort.value });
+  search();
+});
+document.getElementById('layout-button').addEventListener('click', () => {
+  layoutForm.elements.density.value = grid.dataset.density;
+  for (const box of layoutForm.elements.facets) box.checked = !document.getElementById(box.value).hidden;
+  document.getElementById('landing').value = landing;
+  document.getElementById('layout-saved').textContent = '';
+  layout.showModal();
+});
+document.getElementById('use-search').addEventListener('click', () => {
+  document.getElementById('landing').value = currentQuery();
+});
+document.getElementById('layout-close').addEventListener('click', () => layout.close());
+layoutForm.addEventListener('submit', async event => {
+  event.preventDefault();
+  const changes = {
+    density: layoutForm.elements.density.value || 'medium',
+    facets: [...layoutForm.elements.facets].filter(box => box.checked).map(box => box.value).join(','),
+    landing: value('landing'),
+  };
+  const response = await savePreferences(changes);
+  const body = await response.json();
+  if (!response.ok) {
+    document.getElementById('layout-saved').textContent = body.error.message;
+    return;
+  }
+  applyLayout(body);
+  layout.close();
+});
+
+// The theme and layout come first, then the photos they open with
+fetch('/preferences').then(response => response.ok ? response.json() : {}, () => ({})).then(preferences => {
+  applyTheme(preferences.theme || 'system');
+  applyLayout(preferences);
+  document.getElementById('q').value = landing;
+  search();
+});
+</script>
+</body>
+</html>
+"##;
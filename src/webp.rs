@@ -0,0 +1,75 @@
+//! WebP images. The RIFF container is walked here for dimensions and the
+//! XMP chunk; the EXIF reader and the image decoder handle the rest. Not
+//! every analyzer accepts WebP, so analysis gets a JPEG conversion.
+
+use std::path::Path;
+use anyhow::Error;
+
+pub fn is_webp(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("webp"))
+}
+
+/// The chunks of a WebP file, as (FourCC, contents).
+fn chunks(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut rest = match (data.get(..4), data.get(8..12)) {
+        (Some(b"RIFF"), Some(b"WEBP")) => &data[12..],
+        _ => &[][..],
+    };
+    std::iter::from_fn(move || {
+        let kind = rest.get(..4)?;
+        let len = u32::from_le_bytes(rest.get(4..8)?.try_into().ok()?) as usize;
+        let contents = rest.get(8..8 + len)?;
+        // Chunks are padded to an even length
+        rest = rest.get(8 + len + len % 2..).unwrap_or_default();
+        Some((kind, contents))
+    })
+}
+
+pub fn chunk<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    chunks(data).find(|(k, _)| k == kind).map(|(_, contents)| contents)
+}
+
+/// Canvas size, from whichever header the file has: VP8X (extended),
+/// VP8L (lossless) or VP8 (lossy).
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let le24 = |b: &[u8]| b.iter().rev().fold(0, |value, &byte| value << 8 | byte as u32);
+    let (kind, contents) = chunks(data).next()?;
+    match kind {
+        b"VP8X" => Some((le24(contents.get(4..7)?) + 1, le24(contents.get(7..10)?) + 1)),
+        b"VP8L" if contents.first() == Some(&0x2F) => {
+            let bits = u32::from_le_bytes(contents.get(1..5)?.try_into().ok()?);
+            Some(((bits & 0x3FFF) + 1, (bits >> 14 & 0x3FFF) + 1))
+        }
+        b"VP8 " if contents.get(3..6) == Some(&[0x9D, 0x01, 0x2A]) => {
+            let size = |at: usize| Some(u16::from_le_bytes(contents.get(at..at + 2)?.try_into().ok()?) as u32 & 0x3FFF);
+            Some((size(6)?, size(8)?))
+        }
+        _ => None,
+    }
+}
+
+/// Decode and re-encode as JPEG for the analyzer.
+pub fn to_jpeg(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let img = image::load_from_memory_with_format(data, image::ImageFormat::WebP)?;
+    crate::encode_jpeg(&img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_webp() -> Result<(), Error> {
+        let webp = webp_with_xmp("<x:xmpmeta/>");
+        assert_eq!(dimensions(&webp), Some((1, 1)));
+        assert_eq!(chunk(&webp, b"XMP "), Some(&b"<x:xmpmeta/>"[..]));
+        assert_eq!(chunk(&webp, b"EXIF"), None);
+        assert_eq!(image::load_from_memory(&to_jpeg(&webp)?)?.width(), 1);
+
+        assert_eq!(dimensions(b"RIFF\0\0\0\0WEBP"), None);
+        assert_eq!(dimensions(b"not a webp file"), None);
+        assert!(is_webp(Path::new("IMG_1.WEBP")));
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+//! Embedding descriptions and keywords into the JPEG files themselves, for
+//! people who want self-describing files rather than sidecars.
+//!
+//! Both places other software looks are written: an XMP packet (APP1) and
+//! the IPTC block in the Photoshop resources (APP13). Existing values win
+//! over ours and keywords are merged, as for sidecars. EXIF is left alone:
+//! it has no keyword field, and rewriting it risks losing maker notes.
+
+use std::fs;
+use std::path::Path;
+use anyhow::Error;
+use rusqlite::{params, Connection};
+use crate::caption::{self, Template};
+use crate::jpeg::{self, Segment, APP1, APP13, XMP_SIGNATURE};
+use crate::progress::Operation;
+use crate::{hash, iptc, keywords, tags, xmp};
+
+/// The JPEG with the description and keywords embedded.
+pub fn embed(jpeg: &[u8], description: Option<&str>, keywords: &[String]) -> Result<Vec<u8>, Error> {
+    let (mut segments, image_data) = jpeg::split(jpeg)?;
+
+    let packet = match segments.iter().find(|s| s.is_xmp()) {
+        Some(segment) => {
+            let existing = std::str::from_utf8(&segment.payload[XMP_SIGNATURE.len()..])?;
+            xmp::merge_sidecar(existing, description, keywords, &[])?
+        }
+        None => xmp::render_sidecar(description, keywords, &[]),
+    };
+    jpeg::upsert(&mut segments, APP1, Segment::is_xmp, [XMP_SIGNATURE, packet.as_bytes()].concat())?;
+
+    let resources = segments.iter()
+        .find(|s| s.is_photoshop())
+        .map(|s| s.payload[iptc::PHOTOSHOP_SIGNATURE.len()..].to_vec())
+        .unwrap_or_default();
+    let existing_iim = iptc::photoshop_resource(&resources, iptc::IPTC_RESOURCE_ID).unwrap_or_default();
+    let iim = iptc::merge(existing_iim, description, keywords);
+    let resources = iptc::replace_resource(&resources, iptc::IPTC_RESOURCE_ID, &iim);
+    jpeg::upsert(&mut segments, APP13, Segment::is_photoshop, [iptc::PHOTOSHOP_SIGNATURE, &resources].concat())?;
+    Ok(jpeg::join(&segments, image_data))
+}
+
+/// Embed the AI description (formatted by `template`, if given) and
+/// keywords into every analyzed JPEG in the catalog, optionally keeping a
+/// copy of each original as `photo.jpg.bak`. Returns how many files were
+/// written and how many were skipped.
+pub fn writeback_catalog(conn: &Connection, operation: &Operation, template: Option<&Template>, backup: bool) -> Result<(usize, usize), Error> {
+    let pending = pending(conn, template)?;
+    operation.set_total(conn, pending.len())?;
+
+    let (mut written, mut skipped) = (0, 0);
+    for Pending { id, path, description, keywords } in pending {
+        operation.checkpoint(conn)?;
+        let result = writeback_file(Path::new(&path), description.as_deref(), &keywords, backup);
+        match &result {
+            Ok((size, content_hash)) => {
+                // Keep the catalog in step with the file we just rewrote
+                conn.execute(
+                    "UPDATE images SET file_size = ?1, content_hash = ?2 WHERE id = ?3",
+                    params![*size as i64, content_hash, id],
+                )?;
+                written += 1;
+            }
+            Err(e) => {
+                tracing::warn!("Skipping {}: {}", path, e);
+                skipped += 1;
+            }
+        }
+        operation.advance(conn, result.is_ok())?;
+    }
+    Ok((written, skipped))
+}
+
+/// What `writeback_catalog` would do, printed file by file: each file is
+/// read and the result made but not written. Returns how many files would
+/// be written and how many skipped.
+pub fn rehearse(conn: &Connection, template: Option<&Template>, backup: bool) -> Result<(usize, usize), Error> {
+    let (mut written, mut skipped) = (0, 0);
+    for Pending { path, description, keywords, .. } in pending(conn, template)? {
+        match fs::read(&path).map_err(Error::from).and_then(|original| embed(&original, description.as_deref(), &keywords)) {
+            Ok(_) => {
+                println!("{}: {:?}, keywords {}", path, description.as_deref().unwrap_or(""), keywords.join(", "));
+                if backup && !Path::new(&format!("{}.bak", path)).exists() {
+                    println!("  keeping the original as {}.bak", path);
+                }
+                written += 1;
+            }
+            Err(e) => {
+                tracing::warn!("Skipping {}: {}", path, e);
+                skipped += 1;
+            }
+        }
+    }
+    Ok((written, skipped))
+}
+
+/// An analyzed JPEG, with the description and keywords it would get.
+struct Pending {
+    id: i64,
+    path: String,
+    description: Option<String>,
+    keywords: Vec<String>,
+}
+
+fn pending(conn: &Connection, template: Option<&Template>) -> Result<Vec<Pending>, Error> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, path, description, {0} FROM images
+         WHERE (description IS NOT NULL OR {0} IS NOT NULL) AND format = 'Jpeg'
+         ORDER BY path",
+        tags::KEYWORDS,
+    ))?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?, row.get::<_, Option<String>>(3)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    rows.into_iter()
+        .map(|(id, path, description, keyword_list)| {
+            let keywords = keyword_list.as_deref().map(keywords::split).unwrap_or_default();
+            Ok(Pending { id, path, description: caption::caption(conn, id, template, description)?, keywords })
+        })
+        .collect()
+}
+
+/// Rewrite one file in place, returning its new size and content hash.
+fn writeback_file(path: &Path, description: Option<&str>, keywords: &[String], backup: bool) -> Result<(usize, String), Error> {
+    let original = fs::read(path)?;
+    let updated = embed(&original, description, keywords)?;
+    if backup {
+        let backup = format!("{}.bak", path.display());
+        // An earlier backup is closer to the original; keep it
+        if !Path::new(&backup).exists() {
+            fs::write(&backup, &original)?;
+        }
+    }
+    let temp = format!("{}.tmp", path.display());
+    fs::write(&temp, &updated)?;
+    fs::rename(&temp, path)?;
+    Ok((updated.len(), hash::of_bytes(&updated)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+    use crate::test_support::*;
+
+    #[test]
+    fn test_embed() -> Result<(), Error> {
+        let jpeg = jpeg_with_exif(&[field(exif::Tag::Make, ascii("FUJIFILM"))]);
+        let keywords = vec![String::from("tram"), String::from("Lisbon")];
+        let embedded = embed(&jpeg, Some("A yellow tram"), &keywords)?;
+
+        // The image and its EXIF survive
+        assert_eq!(image::load_from_memory(&embedded)?.width(), 8);
+        let exif = exif::Reader::new().read_from_container(&mut Cursor::new(&embedded))?;
+        assert!(exif.get_field(exif::Tag::Make, exif::In::PRIMARY).is_some());
+
+        let data = iptc::read(&embedded, None);
+        assert_eq!(data.caption.as_deref(), Some("A yellow tram"));
+        assert_eq!(data.keywords, keywords);
+
+        // Doing it again merges rather than duplicating
+        let again = embed(&embedded, Some("Different"), &[String::from("yellow")])?;
+        let (segments, _) = jpeg::split(&again)?;
+        assert_eq!(segments.iter().filter(|s| s.marker == APP13).count(), 1);
+        let packet = segments.iter().find(|s| s.payload.starts_with(XMP_SIGNATURE)).unwrap();
+        let data = xmp::parse(std::str::from_utf8(&packet.payload[XMP_SIGNATURE.len()..])?)?;
+        assert_eq!(data.description.as_deref(), Some("A yellow tram"));
+        assert_eq!(data.keywords, vec!["tram", "Lisbon", "yellow"]);
+
+        assert!(embed(b"not a jpeg", None, &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_writeback_catalog() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let photo = dir.path().join("tram.jpg");
+        let jpeg = jpeg_with_exif(&[field(exif::Tag::Make, ascii("FUJIFILM"))]);
+        fs::write(&photo, &jpeg)?;
+
+        let conn = Connection::open_in_memory()?;
+        crate::init_database(&conn)?;
+        conn.execute(
+            "INSERT INTO images (path, file_name, file_size, content_hash, format, description)
+             VALUES (?1, 'tram.jpg', ?2, ?3, 'Jpeg', 'A yellow tram')",
+            params![photo.to_string_lossy(), jpeg.len() as i64, hash::of_bytes(&jpeg)],
+        )?;
+        crate::test_support::set_keywords(&conn, &[(&photo.to_string_lossy(), "tram, yellow")]);
+
+        assert_eq!(rehearse(&conn, None, true)?, (1, 0));
+        assert_eq!(fs::read(&photo)?, jpeg);
+        assert!(!dir.path().join("tram.jpg.bak").exists());
+
+        let operation = crate::progress::start(&conn, "writeback", crate::cancel::CancelToken::new())?;
+        assert_eq!(writeback_catalog(&conn, &operation, None, true)?, (1, 0));
+        assert_eq!(fs::read(dir.path().join("tram.jpg.bak"))?, jpeg);
+        let updated = fs::read(&photo)?;
+        assert_eq!(iptc::read(&updated, None).keywords, vec!["tram", "yellow"]);
+        let size: i64 = conn.query_row("SELECT file_size FROM images", [], |row| row.get(0))?;
+        assert_eq!(size, updated.len() as i64);
+        // Nor does the stored hash go stale
+        let report = crate::verify::verify(&conn, crate::verify::Rehash::All, &[])?;
+        assert_eq!((report.hashed, report.modified.len()), (1, 0));
+        Ok(())
+    }
+}
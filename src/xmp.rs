@@ -0,0 +1,463 @@
+//! XMP packets, as written to `photo.jpg.xmp` / `photo.xmp` sidecars by
+//! Lightroom, darktable, digiKam and friends, or embedded in the image.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::Error;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::name::{Namespace, ResolveResult};
+use quick_xml::{NsReader, Writer};
+use crate::ExternalMetadata;
+
+const SOURCE: &str = "xmp";
+
+const NS_RDF: &[u8] = b"http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const NS_DC: &[u8] = b"http://purl.org/dc/elements/1.1/";
+const NS_XMP: &[u8] = b"http://ns.adobe.com/xap/1.0/";
+const NS_LIGHTROOM: &[u8] = b"http://ns.adobe.com/lightroom/1.0/";
+const NS_DIGIKAM: &[u8] = b"http://www.digikam.org/ns/1.0/";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct XmpData {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// -1 (rejected) to 5
+    pub rating: Option<i32>,
+    pub keywords: Vec<String>,
+    /// Keyword paths with `|` between levels, e.g. "Places|Portugal|Lisbon"
+    pub hierarchical_keywords: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Property {
+    Title,
+    Description,
+    Rating,
+    Subject,
+    HierarchicalSubject,
+    DigikamTags,
+}
+
+impl XmpData {
+    fn add(&mut self, property: Property, text: &str) {
+        let text = text.trim();
+        if text.is_empty() {
+            return;
+        }
+        match property {
+            // Language alternatives: keep the first (normally x-default)
+            Property::Title => { self.title.get_or_insert_with(|| text.to_string()); }
+            Property::Description => { self.description.get_or_insert_with(|| text.to_string()); }
+            Property::Rating => self.rating = text.parse::<f64>().ok().map(|r| r.round() as i32),
+            Property::Subject => self.keywords.push(text.to_string()),
+            Property::HierarchicalSubject => self.add_path(text.to_string()),
+            Property::DigikamTags => self.add_path(text.replace('/', "|")),
+        }
+    }
+
+    /// Lightroom's and digiKam's lists usually hold the same paths; keep one.
+    fn add_path(&mut self, path: String) {
+        if !self.hierarchical_keywords.contains(&path) {
+            self.hierarchical_keywords.push(path);
+        }
+    }
+
+    /// Flatten into catalog rows tagged with their XMP provenance.
+    pub fn into_external(self) -> Vec<ExternalMetadata> {
+        let single = [
+            ("title", self.title),
+            ("caption", self.description),
+            ("rating", self.rating.map(|r| r.to_string())),
+        ];
+        single.into_iter()
+            .filter_map(|(field, value)| Some((field, value?)))
+            .chain(self.keywords.into_iter().map(|k| ("keyword", k)))
+            .chain(self.hierarchical_keywords.into_iter().map(|k| ("hierarchical_keyword", k)))
+            .map(|(field, value)| ExternalMetadata {
+                source: SOURCE.to_string(),
+                field: field.to_string(),
+                value,
+            })
+            .collect()
+    }
+}
+
+/// The sidecar for `image`, if there is one. Both the darktable style
+/// (`photo.jpg.xmp`) and the Lightroom style (`photo.xmp`) are recognised.
+pub fn find_sidecar(image: &Path) -> Option<PathBuf> {
+    let full = image.as_os_str().to_string_lossy();
+    [
+        PathBuf::from(format!("{}.xmp", full)),
+        PathBuf::from(format!("{}.XMP", full)),
+        image.with_extension("xmp"),
+        image.with_extension("XMP"),
+    ]
+    .into_iter()
+    .find(|candidate| candidate != image && candidate.is_file())
+}
+
+/// Read and parse the sidecar for `image`. A sidecar that can't be parsed is
+/// reported and otherwise ignored.
+pub fn read_sidecar(image: &Path) -> Option<XmpData> {
+    let sidecar = find_sidecar(image)?;
+    match fs::read_to_string(&sidecar).map_err(Error::from).and_then(|xml| parse(&xml)) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            tracing::error!("Error reading sidecar {}: {}", sidecar.display(), e);
+            None
+        }
+    }
+}
+
+/// Parse a packet embedded in `image`. One that can't be parsed is reported
+/// and otherwise ignored, as for sidecars.
+pub fn read_embedded(image: &Path, packet: &[u8]) -> Option<XmpData> {
+    match std::str::from_utf8(packet).map_err(Error::from).and_then(parse) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            tracing::error!("Error reading the XMP in {}: {}", image.display(), e);
+            None
+        }
+    }
+}
+
+pub fn parse(xml: &str) -> Result<XmpData, Error> {
+    let mut reader = NsReader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut data = XmpData::default();
+    // The property each open element contributes text to, if any
+    let mut stack: Vec<Option<Property>> = Vec::new();
+    loop {
+        match reader.read_resolved_event()? {
+            (ns, Event::Start(e)) => {
+                let local = e.local_name();
+                let property = match namespace(&ns) {
+                    // rdf:Alt/Bag/Seq/li carry their parent property's values
+                    Some(NS_RDF) if local.as_ref() != b"Description" => stack.last().copied().flatten(),
+                    Some(NS_RDF) => {
+                        read_attributes(&reader, &e, &mut data)?;
+                        None
+                    }
+                    ns => property(ns, local.as_ref()),
+                };
+                stack.push(property);
+            }
+            (ns, Event::Empty(e))
+                if namespace(&ns) == Some(NS_RDF) && e.local_name().as_ref() == b"Description" =>
+            {
+                read_attributes(&reader, &e, &mut data)?;
+            }
+            (_, Event::Text(text)) => {
+                if let Some(Some(property)) = stack.last() {
+                    data.add(*property, &text.unescape()?);
+                }
+            }
+            (_, Event::End(_)) => {
+                stack.pop();
+            }
+            (_, Event::Eof) => break,
+            _ => {}
+        }
+    }
+
+    // Hierarchical-only tools still mean the leaf as a plain keyword
+    for path in &data.hierarchical_keywords {
+        let leaf = path.rsplit('|').next().unwrap_or(path).trim();
+        if !leaf.is_empty() && !data.keywords.iter().any(|k| k.eq_ignore_ascii_case(leaf)) {
+            data.keywords.push(leaf.to_string());
+        }
+    }
+    Ok(data)
+}
+
+/// A fresh sidecar holding a description and keywords, and keyword paths
+/// (with `|` between levels) for tools that organise tags in a tree.
+pub fn render_sidecar(description: Option<&str>, keywords: &[String], hierarchical: &[String]) -> String {
+    format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>
+<x:xmpmeta xmlns:x=\"adobe:ns:meta/\" x:xmptk=\"{} {}\">
+ <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">
+  <rdf:Description rdf:about=\"\"
+    xmlns:dc=\"http://purl.org/dc/elements/1.1/\">{}
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end=\"w\"?>
+",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        properties(description, keywords, hierarchical, false),
+    )
+}
+
+/// dc:description and dc:subject elements, optionally declaring the dc
+/// namespace themselves for insertion into a document that may lack it.
+/// Keyword paths go to both lr:hierarchicalSubject and digiKam:TagsList,
+/// which always declare theirs.
+fn properties(description: Option<&str>, keywords: &[String], hierarchical: &[String], declare_ns: bool) -> String {
+    let ns = if declare_ns { " xmlns:dc=\"http://purl.org/dc/elements/1.1/\"" } else { "" };
+    let mut xml = String::new();
+    if let Some(description) = description {
+        xml.push_str(&format!(
+            "\n   <dc:description{}>\n    <rdf:Alt>\n     <rdf:li xml:lang=\"x-default\">{}</rdf:li>\n    </rdf:Alt>\n   </dc:description>",
+            ns, escape(description),
+        ));
+    }
+    let mut list = |element: &str, ns: &str, container: &str, items: &mut dyn Iterator<Item = String>| {
+        xml.push_str(&format!("\n   <{}{}>\n    <rdf:{}>", element, ns, container));
+        for item in items {
+            xml.push_str(&format!("\n     <rdf:li>{}</rdf:li>", escape(item.as_str())));
+        }
+        xml.push_str(&format!("\n    </rdf:{}>\n   </{}>", container, element));
+    };
+    if !keywords.is_empty() {
+        list("dc:subject", ns, "Bag", &mut keywords.iter().cloned());
+    }
+    if !hierarchical.is_empty() {
+        list("lr:hierarchicalSubject", " xmlns:lr=\"http://ns.adobe.com/lightroom/1.0/\"", "Bag", &mut hierarchical.iter().cloned());
+        list("digiKam:TagsList", " xmlns:digiKam=\"http://www.digikam.org/ns/1.0/\"", "Seq", &mut hierarchical.iter().map(|path| path.replace('|', "/")));
+    }
+    xml
+}
+
+/// Add a description and keywords to an existing sidecar without disturbing
+/// anything else in it (darktable keeps its edit history there, for one).
+/// Keywords (and keyword paths, if there are any) are merged with those
+/// already present, and a description that is already there is kept.
+pub fn merge_sidecar(existing: &str, description: Option<&str>, keywords: &[String], hierarchical: &[String]) -> Result<String, Error> {
+    let current = parse(existing)?;
+    let merge = |current: &[String], new: &[String]| {
+        let mut merged: Vec<String> = Vec::new();
+        for keyword in current.iter().chain(new) {
+            if !merged.iter().any(|k| k.eq_ignore_ascii_case(keyword)) {
+                merged.push(keyword.clone());
+            }
+        }
+        merged
+    };
+    let merged = merge(&current.keywords, keywords);
+    // Existing keyword paths are only rewritten when there are new ones
+    let merged_paths = if hierarchical.is_empty() { Vec::new() } else { merge(&current.hierarchical_keywords, hierarchical) };
+    let description = if current.description.is_some() { None } else { description };
+    let fragment = properties(description, &merged, &merged_paths, true);
+    let replaced = |ns: Option<&[u8]>, local: &[u8]| match (ns, local) {
+        (Some(NS_DC), b"subject") => true,
+        (Some(NS_LIGHTROOM), b"hierarchicalSubject") | (Some(NS_DIGIKAM), b"TagsList") => !hierarchical.is_empty(),
+        _ => false,
+    };
+
+    let mut reader = NsReader::from_str(existing);
+    let mut writer = Writer::new(Vec::new());
+    let mut skip_depth = 0;
+    let mut injected = false;
+    loop {
+        let (ns, event) = reader.read_resolved_event()?;
+        if skip_depth > 0 {
+            match event {
+                Event::Start(_) => skip_depth += 1,
+                Event::End(_) => skip_depth -= 1,
+                _ => {}
+            }
+            continue;
+        }
+        match event {
+            Event::Eof => break,
+            // The old keyword lists are replaced by the merged ones
+            Event::Start(e) if replaced(namespace(&ns), e.local_name().as_ref()) => {
+                skip_depth = 1;
+            }
+            Event::Empty(e) if replaced(namespace(&ns), e.local_name().as_ref()) => {}
+            Event::End(e) if !injected && is_description(&reader, e.name()) => {
+                writer.write_event(Event::Text(BytesText::from_escaped(fragment.as_str())))?;
+                writer.write_event(Event::Text(BytesText::from_escaped("\n  ")))?;
+                writer.write_event(Event::End(e))?;
+                injected = true;
+            }
+            Event::Empty(e) if !injected && is_description(&reader, e.name()) => {
+                let end = BytesEnd::new(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+                writer.write_event(Event::Start(e))?;
+                writer.write_event(Event::Text(BytesText::from_escaped(fragment.as_str())))?;
+                writer.write_event(Event::Text(BytesText::from_escaped("\n  ")))?;
+                writer.write_event(Event::End(end))?;
+                injected = true;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+
+    if !injected {
+        return Ok(render_sidecar(description, &merged, &merged_paths));
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+fn is_description(reader: &NsReader<&[u8]>, name: quick_xml::name::QName) -> bool {
+    let (ns, local) = reader.resolve_element(name);
+    namespace(&ns) == Some(NS_RDF) && local.as_ref() == b"Description"
+}
+
+/// Write (or merge into) the sidecar for `image`. An existing sidecar in
+/// either naming style is updated in place; otherwise `photo.jpg.xmp` is
+/// created. Returns the sidecar's path.
+pub fn write_sidecar(image: &Path, description: Option<&str>, keywords: &[String], hierarchical: &[String]) -> Result<PathBuf, Error> {
+    let (sidecar, xml) = match find_sidecar(image) {
+        Some(sidecar) => {
+            let existing = fs::read_to_string(&sidecar)?;
+            let xml = merge_sidecar(&existing, description, keywords, hierarchical)?;
+            (sidecar, xml)
+        }
+        None => {
+            let sidecar = PathBuf::from(format!("{}.xmp", image.as_os_str().to_string_lossy()));
+            (sidecar, render_sidecar(description, keywords, hierarchical))
+        }
+    };
+
+    // Write next to the target and rename, so a crash never leaves a
+    // truncated sidecar behind
+    let temp = sidecar.with_extension("xmp.tmp");
+    fs::write(&temp, xml)?;
+    fs::rename(&temp, &sidecar)?;
+    Ok(sidecar)
+}
+
+fn namespace<'a>(ns: &'a ResolveResult) -> Option<&'a [u8]> {
+    match ns {
+        ResolveResult::Bound(Namespace(ns)) => Some(ns),
+        _ => None,
+    }
+}
+
+fn property(ns: Option<&[u8]>, local: &[u8]) -> Option<Property> {
+    match (ns?, local) {
+        (NS_DC, b"title") => Some(Property::Title),
+        (NS_DC, b"description") => Some(Property::Description),
+        (NS_DC, b"subject") => Some(Property::Subject),
+        (NS_XMP, b"Rating") => Some(Property::Rating),
+        (NS_LIGHTROOM, b"hierarchicalSubject") => Some(Property::HierarchicalSubject),
+        (NS_DIGIKAM, b"TagsList") => Some(Property::DigikamTags),
+        _ => None,
+    }
+}
+
+/// Simple properties may also be written as attributes of rdf:Description,
+/// which is how darktable stores the rating.
+fn read_attributes(reader: &NsReader<&[u8]>, element: &BytesStart, data: &mut XmpData) -> Result<(), Error> {
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        let (ns, local) = reader.resolve_attribute(attribute.key);
+        if let Some(property) = property(namespace(&ns), local.as_ref()) {
+            data.add(property, &attribute.unescape_value()?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const LIGHTROOM_SIDECAR: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:lr="http://ns.adobe.com/lightroom/1.0/"
+   xmp:Rating="4">
+   <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Tram 28</rdf:li></rdf:Alt></dc:title>
+   <dc:description><rdf:Alt><rdf:li xml:lang="x-default">Yellow tram &amp; hill</rdf:li></rdf:Alt></dc:description>
+   <dc:subject><rdf:Bag><rdf:li>tram</rdf:li></rdf:Bag></dc:subject>
+   <lr:hierarchicalSubject>
+    <rdf:Bag>
+     <rdf:li>Places|Portugal|Lisbon</rdf:li>
+     <rdf:li>Transport|tram</rdf:li>
+    </rdf:Bag>
+   </lr:hierarchicalSubject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+    #[test]
+    fn test_parse_lightroom_sidecar() -> Result<(), Error> {
+        let data = parse(LIGHTROOM_SIDECAR)?;
+        assert_eq!(data.title.as_deref(), Some("Tram 28"));
+        assert_eq!(data.description.as_deref(), Some("Yellow tram & hill"));
+        assert_eq!(data.rating, Some(4));
+        assert_eq!(data.keywords, vec!["tram", "Lisbon"]);
+        assert_eq!(data.hierarchical_keywords, vec!["Places|Portugal|Lisbon", "Transport|tram"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_digikam_tags_and_element_rating() -> Result<(), Error> {
+        let data = parse(r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+            <rdf:Description xmlns:digiKam="http://www.digikam.org/ns/1.0/" xmlns:xmp="http://ns.adobe.com/xap/1.0/">
+              <xmp:Rating>2</xmp:Rating>
+              <digiKam:TagsList><rdf:Seq><rdf:li>People/Family/Ana</rdf:li></rdf:Seq></digiKam:TagsList>
+            </rdf:Description></rdf:RDF></x:xmpmeta>"#)?;
+        assert_eq!(data.rating, Some(2));
+        assert_eq!(data.hierarchical_keywords, vec!["People|Family|Ana"]);
+        assert_eq!(data.keywords, vec!["Ana"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_sidecar_roundtrip() -> Result<(), Error> {
+        let keywords = vec![String::from("tram"), String::from("Lisbon & hills")];
+        let xml = render_sidecar(Some("A <yellow> tram"), &keywords, &[]);
+        let data = parse(&xml)?;
+        assert_eq!(data.description.as_deref(), Some("A <yellow> tram"));
+        assert_eq!(data.keywords, keywords);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_sidecar_keeps_existing_data() -> Result<(), Error> {
+        let darktable = LIGHTROOM_SIDECAR.replace(
+            "</rdf:Description>",
+            "<darktable:history xmlns:darktable=\"http://darktable.sf.net/\"><rdf:Seq/></darktable:history></rdf:Description>",
+        );
+        let keywords = vec![String::from("Tram"), String::from("yellow")];
+        let merged = merge_sidecar(&darktable, Some("AI description"), &keywords, &[])?;
+
+        assert!(merged.contains("darktable:history"));
+        let data = parse(&merged)?;
+        assert_eq!(data.description.as_deref(), Some("Yellow tram & hill"));
+        assert_eq!(data.rating, Some(4));
+        assert_eq!(data.keywords, vec!["tram", "Lisbon", "yellow"]);
+        assert_eq!(data.hierarchical_keywords.len(), 2);
+
+        // Self-closing description with attributes only
+        let minimal = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="1"/></rdf:RDF></x:xmpmeta>"#;
+        let data = parse(&merge_sidecar(minimal, Some("New"), &keywords, &[])?)?;
+        assert_eq!((data.rating, data.description.as_deref()), (Some(1), Some("New")));
+        assert_eq!(data.keywords, keywords);
+
+        // New keyword paths join the old in both lists
+        let paths = vec![String::from("AI|tram"), String::from("Places|Portugal|Lisbon")];
+        let merged = merge_sidecar(LIGHTROOM_SIDECAR, None, &keywords, &paths)?;
+        assert!(merged.contains("<rdf:li>AI/tram</rdf:li>"));
+        assert_eq!(parse(&merged)?.hierarchical_keywords, vec![
+            "Places|Portugal|Lisbon", "Transport|tram", "AI|tram",
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_sidecar() -> Result<(), Error> {
+        let dir = tempdir()?;
+        let image = dir.path().join("photo.jpg");
+        fs::write(&image, b"")?;
+        assert_eq!(find_sidecar(&image), None);
+
+        fs::write(dir.path().join("photo.xmp"), LIGHTROOM_SIDECAR)?;
+        assert_eq!(find_sidecar(&image), Some(dir.path().join("photo.xmp")));
+        fs::write(dir.path().join("photo.jpg.xmp"), LIGHTROOM_SIDECAR)?;
+        assert_eq!(find_sidecar(&image), Some(dir.path().join("photo.jpg.xmp")));
+
+        assert_eq!(read_sidecar(&image).and_then(|d| d.title).as_deref(), Some("Tram 28"));
+        Ok(())
+    }
+}
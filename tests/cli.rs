@@ -0,0 +1,27 @@
+//! The binary as scripts call it: what goes to standard output, where a
+//! pipe expects nothing but the data asked for.
+
+use std::process::Command;
+
+#[test]
+fn test_scan_csv_to_stdout() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::tempdir()?;
+    for name in ["a.png", "b.png"] {
+        image::DynamicImage::new_rgb8(8, 6).save(dir.path().join(name))?;
+    }
+    let output = Command::new(env!("CARGO_BIN_EXE_PhotoCataloger"))
+        .args(["--db", ":memory:", "scan", "--no-analyze", "--csv", "-"])
+        .arg(dir.path())
+        .env_remove("RUST_LOG")
+        .output()?;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3, "{}", stdout);
+    assert!(lines[0].starts_with("id,path,"), "{}", lines[0]);
+    assert!(lines[1..].iter().all(|line| line.contains(".png")), "{}", stdout);
+    // The progress is still there, on standard error
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Successfully processed 2 images"));
+    Ok(())
+}